@@ -0,0 +1,276 @@
+// sqew admin UI: everything here goes through the JSON API.
+"use strict";
+
+const REFRESH_MS = 2000;
+// Samples kept per queue for the graphs: five minutes at REFRESH_MS
+const HISTORY = 150;
+
+const history = new Map();
+let selected = null;
+let queues = [];
+
+const $ = (id) => document.getElementById(id);
+
+// Servers started with API keys answer 401 until one is sent; ask for it
+// once and keep it for the session
+const KEY_STORAGE = "sqew-api-key";
+
+// Version of the JSON API the UI was written against
+const API_PREFIX = "/v1";
+
+async function api(method, path, body, retried) {
+  const opts = { method, headers: {} };
+  if (body !== undefined) {
+    opts.headers["content-type"] = "application/json";
+    opts.body = JSON.stringify(body);
+  }
+  const key = sessionStorage.getItem(KEY_STORAGE);
+  if (key) opts.headers["authorization"] = `Bearer ${key}`;
+  const resp = await fetch(API_PREFIX + path, opts);
+  if (resp.status === 401 && !retried) {
+    const entered = prompt("API key");
+    if (entered) {
+      sessionStorage.setItem(KEY_STORAGE, entered);
+      return api(method, path, body, true);
+    }
+  }
+  const text = await resp.text();
+  if (!resp.ok) {
+    throw new Error(`${method} ${path}: ${resp.status} ${text}`);
+  }
+  return text ? JSON.parse(text) : null;
+}
+
+const queuePath = (name) => `/queues/${encodeURIComponent(name)}`;
+
+function cell(row, text, className) {
+  const td = document.createElement("td");
+  td.textContent = text;
+  if (className) td.className = className;
+  row.appendChild(td);
+  return td;
+}
+
+function showError(e) {
+  $("status").textContent = e.message;
+}
+
+// Record a stats sample, dropping the oldest beyond HISTORY
+function record(name, stats) {
+  const samples = history.get(name) || [];
+  samples.push({ t: Date.now(), ...stats });
+  if (samples.length > HISTORY) samples.shift();
+  history.set(name, samples);
+}
+
+// Per-second rates of the `enqueued` and `acked` counters between samples
+function rates(samples) {
+  const out = [];
+  for (let i = 1; i < samples.length; i++) {
+    const a = samples[i - 1];
+    const b = samples[i];
+    const secs = Math.max((b.t - a.t) / 1000, 0.001);
+    out.push({
+      enqueued: Math.max(b.enqueued - a.enqueued, 0) / secs,
+      acked: Math.max(b.acked - a.acked, 0) / secs,
+    });
+  }
+  return out;
+}
+
+// Draw one polyline per series into an SVG, scaled to the largest value
+function plot(svg, points, series) {
+  const ns = "http://www.w3.org/2000/svg";
+  const [, , width, height] = svg.getAttribute("viewBox").split(" ").map(Number);
+  svg.replaceChildren();
+  const max = Math.max(1, ...points.flatMap((p) => series.map((s) => p[s])));
+  const step = width / Math.max(HISTORY - 1, 1);
+  const offset = width - step * (points.length - 1);
+  for (const s of series) {
+    const line = document.createElementNS(ns, "polyline");
+    line.setAttribute("stroke", `var(--${s})`);
+    line.setAttribute(
+      "points",
+      points
+        .map((p, i) => {
+          const y = height - (p[s] / max) * (height - 4) - 2;
+          return `${(offset + i * step).toFixed(1)},${y.toFixed(1)}`;
+        })
+        .join(" "),
+    );
+    svg.appendChild(line);
+  }
+}
+
+function renderQueues() {
+  const rows = $("queue-rows");
+  rows.replaceChildren();
+  $("no-queues").hidden = queues.length > 0;
+  for (const q of queues) {
+    const samples = history.get(q.name) || [];
+    const s = samples[samples.length - 1] || {};
+    const tr = document.createElement("tr");
+    if (q.name === selected) tr.className = "selected";
+    const name = cell(tr, q.name);
+    if (q.paused) {
+      const badge = document.createElement("span");
+      badge.className = "badge";
+      badge.textContent = "paused";
+      name.append(" ", badge);
+    }
+    cell(tr, s.ready ?? "");
+    cell(tr, s.leased ?? "");
+    cell(tr, s.delayed ?? "");
+    cell(tr, s.dlq ?? "");
+    const spark = document.createElementNS("http://www.w3.org/2000/svg", "svg");
+    spark.setAttribute("class", "spark");
+    spark.setAttribute("viewBox", "0 0 120 24");
+    spark.setAttribute("preserveAspectRatio", "none");
+    plot(spark, samples, ["ready"]);
+    cell(tr, "").appendChild(spark);
+    cell(tr, "").textContent = "›";
+    tr.addEventListener("click", () => select(q.name));
+    rows.appendChild(tr);
+  }
+}
+
+function renderDetail() {
+  const q = queues.find((q) => q.name === selected);
+  $("detail").hidden = !q;
+  if (!q) return;
+  $("detail-name").textContent = q.name;
+  $("pause").textContent = q.paused ? "Resume" : "Pause";
+  const samples = history.get(q.name) || [];
+  plot($("depth-chart"), samples, ["ready", "leased", "dlq"]);
+  plot($("rate-chart"), rates(samples), ["enqueued", "acked"]);
+  const s = samples[samples.length - 1] || {};
+  const list = $("detail-stats");
+  list.replaceChildren();
+  for (const key of [
+    "ready", "leased", "delayed", "dlq", "expired", "enqueued", "acked",
+    "oldest_ready_age_ms", "avg_ack_ms",
+  ]) {
+    const div = document.createElement("div");
+    const dt = document.createElement("dt");
+    const dd = document.createElement("dd");
+    dt.textContent = key;
+    dd.textContent = s[key] ?? "-";
+    div.append(dt, dd);
+    list.appendChild(div);
+  }
+}
+
+async function refresh() {
+  try {
+    queues = await api("GET", "/queues");
+    await Promise.all(
+      queues.map(async (q) => {
+        record(q.name, await api("GET", `${queuePath(q.name)}/stats`));
+      }),
+    );
+    const names = new Set(queues.map((q) => q.name));
+    for (const name of history.keys()) {
+      if (!names.has(name)) history.delete(name);
+    }
+    $("status").textContent = `updated ${new Date().toLocaleTimeString()}`;
+  } catch (e) {
+    showError(e);
+  }
+  renderQueues();
+  renderDetail();
+}
+
+function select(name) {
+  selected = name;
+  $("message-rows").replaceChildren();
+  renderQueues();
+  renderDetail();
+  peek();
+  loadDeadLetters();
+}
+
+async function peek() {
+  const limit = Math.max(1, Number($("peek-limit").value) || 20);
+  try {
+    const msgs = await api("GET", `${queuePath(selected)}/messages?limit=${limit}`);
+    const rows = $("message-rows");
+    rows.replaceChildren();
+    for (const m of msgs) {
+      const tr = document.createElement("tr");
+      cell(tr, m.id);
+      cell(tr, m.priority);
+      cell(tr, m.attempts);
+      cell(tr, new Date(m.available_at).toLocaleString());
+      cell(tr, JSON.stringify(m.payload), "payload");
+      rows.appendChild(tr);
+    }
+  } catch (e) {
+    showError(e);
+  }
+}
+
+async function loadDeadLetters() {
+  try {
+    const msgs = await api("GET", `${queuePath(selected)}/dlq?limit=100`);
+    const rows = $("dlq-rows");
+    rows.replaceChildren();
+    for (const m of msgs) {
+      const tr = document.createElement("tr");
+      const box = document.createElement("input");
+      box.type = "checkbox";
+      box.value = m.id;
+      cell(tr, "").appendChild(box);
+      cell(tr, m.id);
+      cell(tr, m.attempts);
+      cell(tr, m.dead_at ? new Date(m.dead_at).toLocaleString() : "");
+      cell(tr, JSON.stringify(m.payload), "payload");
+      rows.appendChild(tr);
+    }
+  } catch (e) {
+    showError(e);
+  }
+}
+
+async function redrive(ids) {
+  try {
+    const body = ids === undefined ? undefined : { ids };
+    const res = await api("POST", `${queuePath(selected)}/dlq/redrive`, body);
+    $("status").textContent = `redrove ${res.redriven} messages`;
+  } catch (e) {
+    showError(e);
+  }
+  await Promise.all([loadDeadLetters(), peek(), refresh()]);
+}
+
+$("peek").addEventListener("click", peek);
+$("dlq-refresh").addEventListener("click", loadDeadLetters);
+$("redrive-all").addEventListener("click", () => redrive());
+$("redrive-selected").addEventListener("click", () => {
+  const ids = [...document.querySelectorAll("#dlq-rows input:checked")].map(
+    (box) => Number(box.value),
+  );
+  if (ids.length) redrive(ids);
+});
+$("pause").addEventListener("click", async () => {
+  const q = queues.find((q) => q.name === selected);
+  if (!q) return;
+  try {
+    await api("PATCH", queuePath(q.name), { paused: !q.paused });
+  } catch (e) {
+    showError(e);
+  }
+  await refresh();
+});
+$("purge").addEventListener("click", async () => {
+  if (!confirm(`Delete all live messages in '${selected}'?`)) return;
+  try {
+    const res = await api("DELETE", `${queuePath(selected)}/messages`);
+    $("status").textContent = `purged ${res.deleted} messages`;
+  } catch (e) {
+    showError(e);
+  }
+  await Promise.all([peek(), refresh()]);
+});
+
+refresh();
+setInterval(refresh, REFRESH_MS);
@@ -0,0 +1,179 @@
+//! Audit log of administrative actions.
+//!
+//! Creating, cloning, deleting, purging, pausing and resuming queues,
+//! moving, importing and replaying messages, adding and removing consumer
+//! groups and alarms, granting and revoking API keys, redriving or purging
+//! dead letters, backups and admin jobs are recorded with who took them,
+//! when, and with which parameters, whether they came through the HTTP API
+//! or the CLI. The log is append-only: the database refuses to update or
+//! delete its entries.
+
+use crate::db::{self, AuditFilter, Db};
+use crate::error::Result;
+use crate::models::AuditEntry;
+use crate::output::OutputFormat;
+use crate::queue::{self, Config};
+use clap::Subcommand;
+
+/// Entries listed when no limit is given
+pub const DEFAULT_AUDIT_LIMIT: i64 = 100;
+
+/// Who takes an administrative action, as recorded in the audit log
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Actor(pub String);
+
+impl Actor {
+    /// A client presenting the managed API key named `name`
+    pub fn key(name: &str) -> Self {
+        Actor(format!("key:{name}"))
+    }
+
+    /// A client presenting one of the keys configured with `--api-key`
+    pub fn configured_key() -> Self {
+        Actor("key".into())
+    }
+
+    /// A client of a server that requires no API key
+    pub fn anonymous() -> Self {
+        Actor("anonymous".into())
+    }
+
+    /// The user running the CLI, from `$USER` (`$USERNAME` on Windows)
+    pub fn cli() -> Self {
+        let user = std::env::var("USER")
+            .or_else(|_| std::env::var("USERNAME"))
+            .unwrap_or_else(|_| "unknown".into());
+        Actor(format!("cli:{user}"))
+    }
+}
+
+impl std::fmt::Display for Actor {
+    fn fmt(
+        &self,
+        f: &mut std::fmt::Formatter<'_>,
+    ) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// Append `action` by `actor` on `queue` to the audit log. The action has
+/// already happened, so a failure to record it is logged rather than
+/// returned.
+pub async fn record(
+    db: &Db,
+    actor: &Actor,
+    action: &str,
+    queue: Option<&str>,
+    params: serde_json::Value,
+) {
+    let entry = AuditEntry {
+        id: 0,
+        at: db::now_ms(),
+        actor: actor.0.clone(),
+        action: action.to_string(),
+        queue: queue.map(str::to_string),
+        params: (!params.is_null()).then_some(params),
+    };
+    if let Err(e) = db.insert_audit_entry(&entry).await {
+        tracing::warn!(
+            error = %e,
+            action,
+            actor = %actor,
+            "failed to record audit log entry"
+        );
+    }
+}
+
+/// Up to `limit` audit log entries matching `filter`, newest first
+pub async fn list_entries(
+    db: &Db,
+    filter: &AuditFilter,
+    limit: i64,
+) -> Result<Vec<AuditEntry>> {
+    Ok(db.list_audit_entries(filter, limit).await?)
+}
+
+/// Build the [`AuditFilter`] of the `list` options; `since` is a window
+/// such as `24h` (see [`queue::parse_window`]) reaching back from `now`
+pub fn audit_filter(
+    action: Option<String>,
+    queue: Option<String>,
+    actor: Option<String>,
+    since: Option<&str>,
+    now: i64,
+) -> Result<AuditFilter> {
+    Ok(AuditFilter {
+        action,
+        queue,
+        actor,
+        since: since
+            .map(|w| queue::parse_window(w).map(|ms| now - ms))
+            .transpose()?,
+    })
+}
+
+/// Audit log CLI subcommands
+#[derive(Subcommand, Debug)]
+pub enum AuditCommands {
+    /// List recorded administrative actions, newest first
+    List {
+        /// Only this action, e.g. `queue.purge`
+        #[arg(long)]
+        action: Option<String>,
+        /// Only actions on this queue
+        #[arg(long)]
+        queue: Option<String>,
+        /// Only actions by this actor, e.g. `key:deploy` or `cli:alice`
+        #[arg(long)]
+        actor: Option<String>,
+        /// Only actions within this window, e.g. `24h` or `7d`
+        #[arg(long)]
+        since: Option<String>,
+        /// Maximum number of entries
+        #[arg(long, default_value_t = DEFAULT_AUDIT_LIMIT)]
+        limit: i64,
+    },
+}
+
+/// Execute an audit log command
+pub async fn run_audit_command(
+    cmd: AuditCommands,
+    cfg: &Config,
+    output: OutputFormat,
+) -> anyhow::Result<()> {
+    use anyhow::Context;
+    let db = queue::init_pool(cfg).await?;
+    match cmd {
+        AuditCommands::List { action, queue, actor, since, limit } => {
+            let filter = audit_filter(
+                action,
+                queue,
+                actor,
+                since.as_deref(),
+                db::now_ms(),
+            )?;
+            let entries = list_entries(&db, &filter, limit)
+                .await
+                .context("Error listing audit log")?;
+            if output.is_structured() {
+                output.print(&entries)?;
+            } else if entries.is_empty() {
+                println!("No audit log entries found");
+            } else {
+                for e in entries {
+                    println!(
+                        "[{}] at={} actor={} action={} queue={}{}",
+                        e.id,
+                        e.at,
+                        e.actor,
+                        e.action,
+                        e.queue.as_deref().unwrap_or("-"),
+                        e.params
+                            .map_or(String::new(), |p| format!(" params={p}"))
+                    );
+                }
+            }
+        }
+    }
+    Ok(())
+}
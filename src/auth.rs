@@ -0,0 +1,382 @@
+//! API keys with roles.
+//!
+//! Keys created with `sqew auth grant` are stored as SHA-256 hashes, each
+//! with a [`Role`] and optionally a queue-name pattern limiting it to some
+//! queues. The server and the Redis listener look the presented key up and
+//! check the [`Grant`] against what each request does.
+
+use crate::audit::{self, Actor};
+use crate::db::{self, Db};
+use crate::error::{Result, SqewError};
+use crate::models::ApiKey;
+use crate::output::OutputFormat;
+use crate::queue::{self, Config};
+use clap::Subcommand;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+/// What an API key may do
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum Role {
+    /// Everything, including creating, purging and deleting queues
+    Admin,
+    /// Enqueue messages and read queues
+    Producer,
+    /// Poll, ack and nack messages and read queues
+    Consumer,
+    /// Read queues, stats and messages without changing them
+    ReadOnly,
+}
+
+impl Role {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Role::Admin => "admin",
+            Role::Producer => "producer",
+            Role::Consumer => "consumer",
+            Role::ReadOnly => "read-only",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Role> {
+        [Role::Admin, Role::Producer, Role::Consumer, Role::ReadOnly]
+            .into_iter()
+            .find(|r| r.as_str() == s)
+    }
+}
+
+/// What a request does, checked against the caller's [`Role`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Permission {
+    /// Look at queues and messages
+    Read,
+    /// Enqueue messages
+    Produce,
+    /// Lease, ack and nack messages
+    Consume,
+    /// Anything else: queue settings, purges, deletes, server admin
+    Admin,
+}
+
+/// The role and queue scope of an authenticated caller
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Grant {
+    pub role: Role,
+    /// `*` glob over queue names; `None` covers every queue
+    pub queue_pattern: Option<String>,
+}
+
+impl Grant {
+    /// Unrestricted access, as given by `--api-key` keys and open servers
+    pub fn admin() -> Self {
+        Grant { role: Role::Admin, queue_pattern: None }
+    }
+
+    /// Whether the grant allows `perm` on `queue`. Without a queue only the
+    /// role is checked.
+    pub fn allows(
+        &self,
+        perm: Permission,
+        queue: Option<&str>,
+    ) -> bool {
+        let role_ok = match self.role {
+            Role::Admin => true,
+            Role::Producer => {
+                matches!(perm, Permission::Read | Permission::Produce)
+            }
+            Role::Consumer => {
+                matches!(perm, Permission::Read | Permission::Consume)
+            }
+            Role::ReadOnly => perm == Permission::Read,
+        };
+        role_ok && queue.is_none_or(|q| self.covers(q))
+    }
+
+    /// Whether the grant's queue pattern matches `queue`
+    pub fn covers(
+        &self,
+        queue: &str,
+    ) -> bool {
+        self.queue_pattern.as_deref().is_none_or(|p| glob_match(p, queue))
+    }
+}
+
+// Match `text` against `pattern`, where `*` matches any run of characters
+fn glob_match(
+    pattern: &str,
+    text: &str,
+) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = text.strip_prefix(first) else {
+        return false;
+    };
+    let parts: Vec<&str> = parts.collect();
+    let Some((last, middle)) = parts.split_last() else {
+        // No `*`: the whole text must match
+        return rest.is_empty();
+    };
+    for part in middle {
+        match rest.find(part) {
+            Some(i) => rest = &rest[i + part.len()..],
+            None => return false,
+        }
+    }
+    rest.len() >= last.len() && rest.ends_with(last)
+}
+
+/// Hex-encoded SHA-256 of an API key, as stored
+pub fn hash_key(key: &str) -> String {
+    Sha256::digest(key.as_bytes()).iter().map(|b| format!("{b:02x}")).collect()
+}
+
+// A new random key: a recognizable prefix and 32 random bytes in hex
+fn generate_key() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    let hex: String = bytes.iter().map(|b| format!("{b:02x}")).collect();
+    format!("sqew_{hex}")
+}
+
+/// Store a key named `name` with `role`, generating the key when none is
+/// given. Returns the stored row and the key, which is not kept in clear.
+#[tracing::instrument(level = "debug", skip_all, fields(name = %name))]
+pub async fn grant_key(
+    db: &Db,
+    name: &str,
+    role: Role,
+    queue_pattern: Option<&str>,
+    key: Option<&str>,
+) -> Result<(ApiKey, String)> {
+    if name.trim().is_empty() {
+        return Err(SqewError::Invalid(
+            "API key name: must not be empty".into(),
+        ));
+    }
+    if queue_pattern.is_some_and(|p| p.trim().is_empty()) {
+        return Err(SqewError::Invalid(
+            "queue pattern: must not be empty".into(),
+        ));
+    }
+    if key.is_some_and(|k| k.len() < 16) {
+        return Err(SqewError::Invalid(
+            "API key: must be at least 16 characters".into(),
+        ));
+    }
+    if db.list_api_keys().await?.iter().any(|k| k.name == name) {
+        return Err(SqewError::Invalid(format!(
+            "API key name '{}': already in use",
+            name
+        )));
+    }
+    let key = key.map_or_else(generate_key, str::to_string);
+    let mut row = ApiKey {
+        id: 0,
+        name: name.to_string(),
+        key_hash: hash_key(&key),
+        role: role.as_str().to_string(),
+        queue_pattern: queue_pattern.map(str::to_string),
+        created_at: db::now_ms(),
+    };
+    row.id = db.create_api_key(&row).await?;
+    Ok((row, key))
+}
+
+/// List the stored API keys (without the keys themselves)
+pub async fn list_keys(db: &Db) -> Result<Vec<ApiKey>> {
+    Ok(db.list_api_keys().await?)
+}
+
+/// Delete the key named `name`. Returns whether one existed.
+pub async fn revoke_key(
+    db: &Db,
+    name: &str,
+) -> Result<bool> {
+    Ok(db.delete_api_key(name).await?)
+}
+
+/// API key CLI subcommands
+#[derive(Subcommand, Debug)]
+pub enum AuthCommands {
+    /// Store a new API key with a role; prints the key, which is not kept
+    Grant {
+        /// Name the key is listed and revoked by
+        name: String,
+        /// What the key may do
+        #[arg(long, value_enum)]
+        role: Role,
+        /// Limit the key to queues matching this pattern (`*` matches any
+        /// characters), e.g. `orders-*`
+        #[arg(long = "queue")]
+        queue_pattern: Option<String>,
+        /// Use this key instead of generating one
+        #[arg(long)]
+        key: Option<String>,
+    },
+    /// List stored API keys
+    List,
+    /// Delete a stored API key
+    Revoke {
+        /// Key name
+        name: String,
+    },
+}
+
+/// Execute an API key command
+pub async fn run_auth_command(
+    cmd: AuthCommands,
+    cfg: &Config,
+    output: OutputFormat,
+) -> anyhow::Result<()> {
+    use anyhow::Context;
+    let db = queue::init_pool(cfg).await?;
+    let structured = output.is_structured();
+    match cmd {
+        AuthCommands::Grant { name, role, queue_pattern, key } => {
+            let (row, key) = grant_key(
+                &db,
+                &name,
+                role,
+                queue_pattern.as_deref(),
+                key.as_deref(),
+            )
+            .await
+            .context("Error granting API key")?;
+            audit::record(
+                &db,
+                &Actor::cli(),
+                "key.grant",
+                None,
+                serde_json::json!({
+                    "name": row.name,
+                    "role": row.role,
+                    "queue_pattern": row.queue_pattern,
+                }),
+            )
+            .await;
+            if structured {
+                output.print(&serde_json::json!({
+                    "key": row,
+                    "secret": key,
+                }))?;
+            } else {
+                println!(
+                    "Granted '{}' role {} on {}",
+                    row.name,
+                    row.role,
+                    row.queue_pattern.as_deref().unwrap_or("all queues")
+                );
+                println!("Key (shown once): {}", key);
+            }
+        }
+        AuthCommands::List => {
+            let keys =
+                list_keys(&db).await.context("Error listing API keys")?;
+            if structured {
+                output.print(&keys)?;
+            } else if keys.is_empty() {
+                println!("No API keys found");
+            } else {
+                for k in keys {
+                    println!(
+                        "{} role={} queues={} created_at={}",
+                        k.name,
+                        k.role,
+                        k.queue_pattern.as_deref().unwrap_or("*"),
+                        k.created_at
+                    );
+                }
+            }
+        }
+        AuthCommands::Revoke { name } => {
+            let revoked = revoke_key(&db, &name)
+                .await
+                .context("Error revoking API key")?;
+            if revoked {
+                audit::record(
+                    &db,
+                    &Actor::cli(),
+                    "key.revoke",
+                    None,
+                    serde_json::json!({ "name": name }),
+                )
+                .await;
+            }
+            if structured {
+                output.print(
+                    &serde_json::json!({ "name": name, "revoked": revoked }),
+                )?;
+            } else if revoked {
+                println!("Revoked API key '{}'", name);
+            } else {
+                eprintln!("API key '{}' not found", name);
+            }
+            if !revoked {
+                std::process::exit(1);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// How long the server trusts its copy of the stored keys; grants and
+/// revocations take effect within this time
+pub const KEY_CACHE_TTL: Duration = Duration::from_secs(2);
+
+/// Names and grants of the stored API keys, by key hash
+pub type KeyGrants = HashMap<String, (String, Grant)>;
+
+/// The stored API keys, reloaded from the database once stale
+#[derive(Debug, Default)]
+pub struct KeyCache {
+    loaded: Mutex<Option<(Instant, Arc<KeyGrants>)>>,
+}
+
+impl KeyCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The current keys. If reloading fails the previous copy is kept; only
+    /// a failed first load is an error.
+    pub async fn keys(
+        &self,
+        db: &Db,
+    ) -> sqlx::Result<Arc<KeyGrants>> {
+        let mut loaded = self.loaded.lock().await;
+        if let Some((at, keys)) = loaded.as_ref()
+            && at.elapsed() < KEY_CACHE_TTL
+        {
+            return Ok(keys.clone());
+        }
+        let keys: Arc<KeyGrants> = match db.list_api_keys().await {
+            Ok(rows) => Arc::new(
+                rows.into_iter()
+                    .filter_map(|k| {
+                        let role = Role::parse(&k.role)?;
+                        let grant =
+                            Grant { role, queue_pattern: k.queue_pattern };
+                        Some((k.key_hash, (k.name, grant)))
+                    })
+                    .collect(),
+            ),
+            Err(e) => match loaded.as_mut() {
+                Some((_, keys)) => {
+                    tracing::warn!(error = %e, "failed to reload API keys");
+                    keys.clone()
+                }
+                None => return Err(e),
+            },
+        };
+        *loaded = Some((Instant::now(), keys.clone()));
+        Ok(keys)
+    }
+}
@@ -0,0 +1,407 @@
+use crate::client::{ClientError, PollRequest, SqewClient};
+use crate::db::Db;
+use crate::error::SqewError;
+use crate::models::Message;
+use crate::output::OutputFormat;
+use crate::queue::{self, Config};
+use anyhow::{Context, Result};
+use clap::Args;
+use serde::Serialize;
+use serde_json::{Value, json};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::task::JoinSet;
+
+/// How long a consumer waits before polling an empty queue again
+const IDLE_POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+/// Retries of one operation after lock contention before giving up
+const MAX_LOCK_RETRIES: u32 = 50;
+
+/// Options for `sqew bench`
+#[derive(Args, Debug, Clone)]
+pub struct BenchOptions {
+    /// Queue to benchmark; it is deleted and recreated first
+    #[arg(long, default_value = "bench")]
+    pub queue: String,
+    /// Total number of messages to enqueue and consume
+    #[arg(long, default_value_t = 10_000)]
+    pub messages: usize,
+    /// Number of concurrent producers
+    #[arg(long, default_value_t = 4)]
+    pub producers: usize,
+    /// Number of concurrent consumers
+    #[arg(long, default_value_t = 4)]
+    pub consumers: usize,
+    /// Messages leased per poll
+    #[arg(long, default_value_t = 32)]
+    pub batch: i64,
+    /// Size of the padding added to each payload, in bytes
+    #[arg(long, default_value_t = 128)]
+    pub payload_bytes: usize,
+    /// Lease length in milliseconds for polled messages
+    #[arg(long, default_value_t = 30_000)]
+    pub visibility_ms: i64,
+    /// Benchmark a running server at this URL instead of the local database
+    #[arg(long)]
+    pub server: Option<String>,
+    /// API key for a `--server` that requires one
+    #[arg(long, env = "SQEW_API_KEY", hide_env_values = true)]
+    pub api_key: Option<String>,
+    /// Enqueue every message before starting the consumers, to measure
+    /// draining a backlog without producers competing for the database
+    #[arg(long)]
+    pub prefill: bool,
+}
+
+/// What a benchmark runs against
+#[derive(Clone)]
+pub enum BenchTarget {
+    /// The storage backend, in process
+    Local(Db),
+    /// A remote server over HTTP
+    Remote(SqewClient),
+}
+
+/// Results of a benchmark run. Latencies are in milliseconds.
+#[derive(Debug, Clone, Serialize)]
+pub struct BenchReport {
+    pub messages: usize,
+    pub producers: usize,
+    pub consumers: usize,
+    /// Time from the first enqueue until the last ack
+    pub elapsed_ms: f64,
+    /// Messages enqueued per second while producers were running
+    pub enqueue_per_sec: f64,
+    /// Messages enqueued and acked per second over the whole run
+    pub throughput_per_sec: f64,
+    /// Messages acked per second from the consumers' start until the last ack
+    pub consume_per_sec: f64,
+    pub enqueue_p50_ms: f64,
+    pub enqueue_p99_ms: f64,
+    /// Time from enqueue (`created_at`) until the message was acked
+    pub end_to_end_p50_ms: f64,
+    pub end_to_end_p99_ms: f64,
+    /// Time taken by polls that leased at least one message
+    pub poll_p50_ms: f64,
+    pub poll_p99_ms: f64,
+    /// Operations retried after lock contention (SQLite busy, Postgres
+    /// serialization failures, or a 5xx from a remote server)
+    pub lock_retries: u64,
+}
+
+impl BenchTarget {
+    async fn recreate_queue(
+        &self,
+        name: &str,
+    ) -> Result<()> {
+        match self {
+            BenchTarget::Local(db) => {
+                queue::delete_queue(db, name).await?;
+                queue::create_queue(db, name, 5).await?;
+            }
+            BenchTarget::Remote(client) => {
+                match client.delete_queue(name).await {
+                    Ok(()) | Err(ClientError::NotFound(_)) => {}
+                    Err(e) => return Err(e.into()),
+                }
+                client.create_queue(name, 5).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn enqueue(
+        &self,
+        name: &str,
+        payload: &Value,
+    ) -> Result<()> {
+        match self {
+            BenchTarget::Local(db) => {
+                queue::enqueue_message(db, name, payload, 0).await?;
+            }
+            BenchTarget::Remote(client) => {
+                client.enqueue(name, payload).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn poll(
+        &self,
+        name: &str,
+        batch: i64,
+        visibility_ms: i64,
+    ) -> Result<Vec<Message>> {
+        match self {
+            BenchTarget::Local(db) => {
+                Ok(queue::poll_messages(db, name, batch, visibility_ms).await?)
+            }
+            BenchTarget::Remote(client) => {
+                let req = PollRequest {
+                    batch: Some(batch),
+                    visibility_ms: Some(visibility_ms),
+                    ..PollRequest::default()
+                };
+                Ok(client.poll(name, &req).await?)
+            }
+        }
+    }
+
+    async fn ack(
+        &self,
+        ids: &[i64],
+        lease_token: &str,
+    ) -> Result<u64> {
+        match self {
+            BenchTarget::Local(db) => {
+                Ok(queue::ack_messages(db, ids, lease_token).await?)
+            }
+            BenchTarget::Remote(client) => {
+                Ok(client.ack(ids, lease_token).await?)
+            }
+        }
+    }
+}
+
+/// Run the benchmark and print its report
+pub async fn run_bench_command(
+    opts: BenchOptions,
+    cfg: &Config,
+    output: OutputFormat,
+) -> Result<()> {
+    let target = match &opts.server {
+        Some(url) => {
+            let client = SqewClient::new(url.as_str());
+            BenchTarget::Remote(match &opts.api_key {
+                Some(key) => client.with_api_key(key),
+                None => client,
+            })
+        }
+        None => BenchTarget::Local(queue::init_pool(cfg).await?),
+    };
+    let report = run_bench(target, &opts).await?;
+    if output.is_structured() {
+        return output.print(&report);
+    }
+    println!(
+        "Messages:        {} ({} producers, {} consumers)",
+        report.messages, report.producers, report.consumers
+    );
+    println!("Elapsed:         {:.1} ms", report.elapsed_ms);
+    println!(
+        "Throughput:      {:.0} msg/s (enqueue {:.0} msg/s, consume {:.0} msg/s)",
+        report.throughput_per_sec,
+        report.enqueue_per_sec,
+        report.consume_per_sec
+    );
+    println!(
+        "Enqueue latency: p50 {:.2} ms, p99 {:.2} ms",
+        report.enqueue_p50_ms, report.enqueue_p99_ms
+    );
+    println!(
+        "End-to-end:      p50 {:.0} ms, p99 {:.0} ms",
+        report.end_to_end_p50_ms, report.end_to_end_p99_ms
+    );
+    println!(
+        "Poll latency:    p50 {:.2} ms, p99 {:.2} ms",
+        report.poll_p50_ms, report.poll_p99_ms
+    );
+    println!("Lock retries:    {}", report.lock_retries);
+    Ok(())
+}
+
+/// Recreate `opts.queue`, then enqueue `opts.messages` messages from
+/// `opts.producers` tasks while `opts.consumers` tasks poll and ack them.
+/// With `opts.prefill` the consumers start once every message is enqueued.
+pub async fn run_bench(
+    target: BenchTarget,
+    opts: &BenchOptions,
+) -> Result<BenchReport> {
+    target
+        .recreate_queue(&opts.queue)
+        .await
+        .context("Failed to prepare bench queue")?;
+    let target = Arc::new(target);
+    let retries = Arc::new(AtomicU64::new(0));
+    let acked = Arc::new(AtomicU64::new(0));
+    let total = opts.messages as u64;
+    let producers = opts.producers.max(1);
+    let start = Instant::now();
+
+    let spawn_consumers = || {
+        let mut tasks = JoinSet::new();
+        for _ in 0..opts.consumers.max(1) {
+            let target = target.clone();
+            let opts = opts.clone();
+            let retries = retries.clone();
+            let acked = acked.clone();
+            tasks.spawn(async move {
+                consume(&target, &opts, &retries, &acked, total).await
+            });
+        }
+        tasks
+    };
+    let mut consumer_tasks =
+        if opts.prefill { JoinSet::new() } else { spawn_consumers() };
+    let mut producer_tasks = JoinSet::new();
+    for p in 0..producers {
+        let target = target.clone();
+        let opts = opts.clone();
+        let retries = retries.clone();
+        producer_tasks.spawn(async move {
+            let pad = "x".repeat(opts.payload_bytes);
+            let mut latencies = Vec::new();
+            for seq in (p..opts.messages).step_by(producers) {
+                let payload = json!({ "seq": seq, "pad": pad });
+                let t = Instant::now();
+                with_lock_retry(&retries, || {
+                    target.enqueue(&opts.queue, &payload)
+                })
+                .await?;
+                latencies.push(ms(t.elapsed()));
+            }
+            anyhow::Ok(latencies)
+        });
+    }
+
+    let mut enqueue_latencies = Vec::with_capacity(opts.messages);
+    while let Some(res) = producer_tasks.join_next().await {
+        enqueue_latencies.extend(res.context("Producer task panicked")??);
+    }
+    let enqueue_elapsed = start.elapsed();
+    let consume_start = if opts.prefill {
+        consumer_tasks = spawn_consumers();
+        Instant::now()
+    } else {
+        start
+    };
+    let mut e2e_latencies = Vec::with_capacity(opts.messages);
+    let mut poll_latencies = Vec::new();
+    while let Some(res) = consumer_tasks.join_next().await {
+        let (e2e, polls) = res.context("Consumer task panicked")??;
+        e2e_latencies.extend(e2e);
+        poll_latencies.extend(polls);
+    }
+    let elapsed = start.elapsed();
+
+    enqueue_latencies.sort_by(f64::total_cmp);
+    e2e_latencies.sort_by(f64::total_cmp);
+    poll_latencies.sort_by(f64::total_cmp);
+    Ok(BenchReport {
+        messages: opts.messages,
+        producers,
+        consumers: opts.consumers.max(1),
+        elapsed_ms: ms(elapsed),
+        enqueue_per_sec: per_sec(opts.messages, enqueue_elapsed),
+        throughput_per_sec: per_sec(opts.messages, elapsed),
+        consume_per_sec: per_sec(opts.messages, consume_start.elapsed()),
+        enqueue_p50_ms: percentile(&enqueue_latencies, 50.0),
+        enqueue_p99_ms: percentile(&enqueue_latencies, 99.0),
+        end_to_end_p50_ms: percentile(&e2e_latencies, 50.0),
+        end_to_end_p99_ms: percentile(&e2e_latencies, 99.0),
+        poll_p50_ms: percentile(&poll_latencies, 50.0),
+        poll_p99_ms: percentile(&poll_latencies, 99.0),
+        lock_retries: retries.load(Ordering::Relaxed),
+    })
+}
+
+// Poll and ack until `total` messages have been acked across all consumers,
+// returning the end-to-end latency of each message this consumer acked and
+// the latency of each of its polls that leased something
+async fn consume(
+    target: &BenchTarget,
+    opts: &BenchOptions,
+    retries: &AtomicU64,
+    acked: &AtomicU64,
+    total: u64,
+) -> Result<(Vec<f64>, Vec<f64>)> {
+    let mut latencies = Vec::new();
+    let mut poll_latencies = Vec::new();
+    while acked.load(Ordering::Relaxed) < total {
+        let t = Instant::now();
+        let msgs = with_lock_retry(retries, || {
+            target.poll(&opts.queue, opts.batch, opts.visibility_ms)
+        })
+        .await?;
+        let Some(token) = msgs.first().and_then(|m| m.lease_token.clone())
+        else {
+            tokio::time::sleep(IDLE_POLL_INTERVAL).await;
+            continue;
+        };
+        poll_latencies.push(ms(t.elapsed()));
+        let ids: Vec<i64> = msgs.iter().map(|m| m.id).collect();
+        let n = with_lock_retry(retries, || target.ack(&ids, &token)).await?;
+        let now =
+            SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as i64;
+        if n == ids.len() as u64 {
+            latencies.extend(msgs.iter().map(|m| (now - m.created_at) as f64));
+        }
+        acked.fetch_add(n, Ordering::Relaxed);
+    }
+    Ok((latencies, poll_latencies))
+}
+
+// Run `op`, retrying with a short growing backoff while it fails on lock
+// contention; each retry is counted in `retries`
+async fn with_lock_retry<T, F, Fut>(
+    retries: &AtomicU64,
+    mut op: F,
+) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let mut attempt = 0;
+    loop {
+        match op().await {
+            Err(e) if attempt < MAX_LOCK_RETRIES && is_lock_error(&e) => {
+                attempt += 1;
+                retries.fetch_add(1, Ordering::Relaxed);
+                let backoff = Duration::from_millis(u64::from(attempt.min(20)));
+                tokio::time::sleep(backoff).await;
+            }
+            res => return res,
+        }
+    }
+}
+
+// Whether an error is transient lock contention worth retrying
+fn is_lock_error(e: &anyhow::Error) -> bool {
+    match e.downcast_ref() {
+        Some(ClientError::Unavailable { .. }) => return true,
+        Some(ClientError::Server { status, .. }) => {
+            return status.is_server_error();
+        }
+        _ => {}
+    }
+    e.chain().any(|cause| {
+        cause
+            .downcast_ref::<SqewError>()
+            .is_some_and(|e| e.retry_after().is_some())
+    })
+}
+
+// Nearest-rank percentile of sorted samples; 0 when there are none
+fn percentile(
+    sorted: &[f64],
+    pct: f64,
+) -> f64 {
+    if sorted.is_empty() {
+        return 0.0;
+    }
+    let rank = (pct / 100.0 * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+fn ms(d: Duration) -> f64 {
+    d.as_secs_f64() * 1000.0
+}
+
+fn per_sec(
+    n: usize,
+    d: Duration,
+) -> f64 {
+    if d.is_zero() { 0.0 } else { n as f64 / d.as_secs_f64() }
+}
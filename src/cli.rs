@@ -1,11 +1,80 @@
-use crate::queue::{self, MessageCommands, QueueCommands};
+use crate::audit::{self, AuditCommands};
+use crate::auth::{self, AuthCommands};
+use crate::bench::{self, BenchOptions};
+use crate::config::ConfigFile;
+use crate::db::{self, Keyring};
+use crate::output::OutputFormat;
+use crate::queue::{
+    self, Config, DbCommands, JobCommands, MessageCommands, QueueCommands,
+};
 use crate::server;
+use crate::worker::{self, WorkerOptions};
+use axum::http::HeaderValue;
 use clap::{Parser, Subcommand};
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
 
 /// Sqew CLI interface
 #[derive(Parser, Debug)]
 #[command(name = "sqew", about = "Sqew CLI tool")]
 pub struct Cli {
+    /// Read settings from this TOML file; flags and environment variables
+    /// override it
+    #[arg(long, global = true, env = "SQEW_CONFIG")]
+    pub config: Option<PathBuf>,
+    /// Lay this profile of the config file (`[profiles.<name>]`) over its
+    /// other settings, e.g. `dev` or `prod`
+    #[arg(long, global = true, env = "SQEW_PROFILE")]
+    pub profile: Option<String>,
+    /// Path to the SQLite database file (default: ./sqew.db)
+    #[arg(long, global = true, env = "SQEW_DB_PATH")]
+    pub db: Option<PathBuf>,
+    /// Keep the SQLite database in memory; it is discarded on exit
+    #[arg(long, global = true, conflicts_with = "db")]
+    pub memory: bool,
+    /// Database URL (`postgres://...` or `sqlite://<path>`); overrides --db
+    #[arg(long, global = true, env = "SQEW_DATABASE_URL")]
+    pub database_url: Option<String>,
+    /// Maximum number of pooled database connections (default: 32)
+    #[arg(long, global = true, env = "SQEW_POOL_SIZE")]
+    pub pool_size: Option<u32>,
+    /// How long a query waits for a free pooled connection before failing
+    /// (default: 30000)
+    #[arg(long, global = true, env = "SQEW_ACQUIRE_TIMEOUT_MS")]
+    pub acquire_timeout_ms: Option<u64>,
+    /// Prepared statements cached per database connection (default: 100)
+    #[arg(long, global = true, env = "SQEW_STATEMENT_CACHE_SIZE")]
+    pub statement_cache_size: Option<usize>,
+    /// SQLite: serve peeks, searches and stats from a separate read-only
+    /// pool of this many connections; 0 shares the main pool (default: 0)
+    #[arg(long, global = true, env = "SQEW_READ_POOL_SIZE")]
+    pub read_pool_size: Option<u32>,
+    /// SQLite stores payloads larger than this many bytes zstd-compressed;
+    /// 0 disables compression (default: 4096)
+    #[arg(long, global = true, env = "SQEW_COMPRESS_THRESHOLD")]
+    pub compress_threshold: Option<usize>,
+    /// Encrypt SQLite payloads at rest with AES-256-GCM: comma-separated
+    /// keys of 64 hex digits, the first active and the rest retired
+    #[arg(
+        long,
+        global = true,
+        env = "SQEW_ENCRYPTION_KEY",
+        hide_env_values = true
+    )]
+    pub encryption_key: Option<String>,
+    /// Read the encryption keys from this file (one per line) instead
+    #[arg(
+        long,
+        global = true,
+        env = "SQEW_ENCRYPTION_KEYFILE",
+        conflicts_with = "encryption_key"
+    )]
+    pub encryption_keyfile: Option<PathBuf>,
+    /// Output format for queue, message, db, job and bench commands
+    #[arg(long, global = true, value_enum, default_value_t = OutputFormat::Table)]
+    pub output: OutputFormat,
     #[command(subcommand)]
     pub command: Commands,
 }
@@ -14,9 +83,59 @@ pub struct Cli {
 pub enum Commands {
     /// Run the HTTP server
     Serve {
-        /// Port to listen on
-        #[arg(short, long, default_value_t = 8888)]
-        port: u16,
+        /// Address to listen on (default: 127.0.0.1)
+        #[arg(long, env = "SQEW_BIND")]
+        bind: Option<IpAddr>,
+        /// Port to listen on (default: 8888)
+        #[arg(short, long, env = "SQEW_PORT")]
+        port: Option<u16>,
+        /// On shutdown (Ctrl+C or SIGTERM), how long in-flight requests may
+        /// take to finish before they are dropped (default: 30000)
+        #[arg(long)]
+        drain_timeout_ms: Option<u64>,
+        /// Also accept Redis protocol clients (LPUSH/RPOP/BRPOP/LLEN mapped
+        /// onto queues) on this port
+        #[arg(long, env = "SQEW_REDIS_PORT")]
+        redis_port: Option<u16>,
+        /// Reject enqueues whose JSON payload is larger than this many bytes,
+        /// whatever the queue allows
+        #[arg(long, env = "SQEW_MAX_PAYLOAD_BYTES")]
+        max_payload_bytes: Option<usize>,
+        /// Require clients to present one of these keys (comma-separated),
+        /// as a bearer token over HTTP or with AUTH over the Redis protocol
+        #[arg(
+            long = "api-key",
+            env = "SQEW_API_KEYS",
+            value_delimiter = ',',
+            hide_env_values = true
+        )]
+        api_keys: Vec<String>,
+        /// Inject faults into a fraction of API requests to test consumers:
+        /// delays, 503s and redelivered polls, e.g. p=0.05 or
+        /// p=0.2,delay_ms=500,faults=unavailable+redeliver
+        #[arg(long, env = "SQEW_CHAOS")]
+        chaos: Option<server::ChaosConfig>,
+        /// Let browsers call the API from these origins (comma-separated),
+        /// e.g. https://dash.example.com, or * for any
+        #[arg(
+            long = "cors-origin",
+            env = "SQEW_CORS_ORIGINS",
+            value_delimiter = ',',
+            value_parser = server::parse_cors_origin
+        )]
+        cors_origins: Vec<HeaderValue>,
+        /// Reject request bodies larger than this many bytes with 413
+        /// (default: 2 MiB)
+        #[arg(long, env = "SQEW_MAX_BODY_BYTES")]
+        max_body_bytes: Option<usize>,
+        /// Answer requests taking longer than this with 408, long polls
+        /// included (default: no limit)
+        #[arg(long, env = "SQEW_REQUEST_TIMEOUT_MS")]
+        request_timeout_ms: Option<u64>,
+        /// Repair what the startup recovery scan finds, such as leases a
+        /// crash stranded, instead of only logging it
+        #[arg(long)]
+        repair_on_start: bool,
     },
     /// Queue management commands
     #[command(subcommand)]
@@ -24,14 +143,167 @@ pub enum Commands {
     /// Message commands
     #[command(subcommand)]
     Message(MessageCommands),
+    /// Database maintenance commands
+    #[command(subcommand)]
+    Db(DbCommands),
+    /// Follow and cancel the admin jobs `sqew serve` runs in the background
+    #[command(subcommand)]
+    Job(JobCommands),
+    /// Manage API keys and their roles
+    #[command(subcommand)]
+    Auth(AuthCommands),
+    /// List the administrative actions recorded in the audit log
+    #[command(subcommand)]
+    Audit(AuditCommands),
+    /// Run a shell command for each message in a queue (payload on stdin);
+    /// acks on exit code 0 and nacks otherwise
+    Worker(WorkerOptions),
+    /// Load-test a queue with concurrent producers and consumers and report
+    /// throughput and latency
+    Bench(BenchOptions),
 }
 
 impl Cli {
     pub async fn run(self) -> anyhow::Result<()> {
+        let file = match (&self.config, &self.profile) {
+            (Some(path), profile) => {
+                ConfigFile::load_profile(path, profile.as_deref())?
+            }
+            (None, Some(profile)) => anyhow::bail!(
+                "Profile '{profile}' needs a config file; pass --config or set SQEW_CONFIG"
+            ),
+            (None, None) => ConfigFile::default(),
+        };
+        let cfg = self.queue_config(&file)?;
         match self.command {
-            Commands::Serve { port } => server::run_server(port).await,
-            Commands::Queue(cmd) => queue::run_queue_command(cmd).await,
-            Commands::Message(cmd) => queue::run_message_command(cmd).await,
+            Commands::Serve {
+                bind,
+                port,
+                drain_timeout_ms,
+                redis_port,
+                max_payload_bytes,
+                api_keys,
+                chaos,
+                cors_origins,
+                max_body_bytes,
+                request_timeout_ms,
+                repair_on_start,
+            } => {
+                let defaults = server::ServeOptions::default();
+                let server = file.server;
+                let drain_timeout_ms =
+                    drain_timeout_ms.or(server.drain_timeout_ms);
+                let opts = server::ServeOptions {
+                    bind: bind.or(server.bind).unwrap_or(defaults.bind),
+                    port: port.or(server.port).unwrap_or(defaults.port),
+                    redis_port: redis_port.or(server.redis_port),
+                    drain_timeout: drain_timeout_ms
+                        .map(Duration::from_millis)
+                        .unwrap_or(defaults.drain_timeout),
+                    max_payload_bytes: max_payload_bytes
+                        .or(server.max_payload_bytes),
+                    api_keys: if api_keys.is_empty() {
+                        server.api_keys
+                    } else {
+                        api_keys
+                    },
+                    tasks: file.tasks.intervals()?,
+                    mqtt: file.mqtt,
+                    chaos: chaos.or(server.chaos),
+                    cors_origins: if cors_origins.is_empty() {
+                        server.cors_origins
+                    } else {
+                        cors_origins
+                    },
+                    max_body_bytes: max_body_bytes.or(server.max_body_bytes),
+                    request_timeout: request_timeout_ms
+                        .or(server.request_timeout_ms)
+                        .map(Duration::from_millis),
+                    auto_compact: file.auto_compact,
+                    repair_on_start: repair_on_start || server.repair_on_start,
+                };
+                server::run_server(&opts, &cfg).await
+            }
+            Commands::Queue(cmd) => {
+                queue::run_queue_command(cmd, &cfg, self.output).await
+            }
+            Commands::Message(cmd) => {
+                queue::run_message_command(cmd, &cfg, self.output).await
+            }
+            Commands::Db(cmd) => {
+                queue::run_db_command(cmd, &cfg, self.output).await
+            }
+            Commands::Job(cmd) => {
+                queue::run_job_command(cmd, &cfg, self.output).await
+            }
+            Commands::Auth(cmd) => {
+                auth::run_auth_command(cmd, &cfg, self.output).await
+            }
+            Commands::Audit(cmd) => {
+                audit::run_audit_command(cmd, &cfg, self.output).await
+            }
+            Commands::Worker(opts) => {
+                worker::run_worker_command(opts, &cfg).await
+            }
+            Commands::Bench(opts) => {
+                bench::run_bench_command(opts, &cfg, self.output).await
+            }
         }
     }
+
+    /// Build the database configuration from global flags, falling back to
+    /// the config file and then to defaults
+    fn queue_config(
+        &self,
+        file: &ConfigFile,
+    ) -> anyhow::Result<Config> {
+        let default = Config::default();
+        let db = &file.database;
+        let encryption_keys = self
+            .encryption_key
+            .as_deref()
+            .map(Keyring::parse)
+            .transpose()?
+            .map(Arc::new);
+        Ok(Config {
+            db_path: if self.memory {
+                PathBuf::from(db::sqlite::MEMORY_PATH)
+            } else {
+                self.db.clone().or(db.path.clone()).unwrap_or(default.db_path)
+            },
+            // A database chosen on the command line beats the file's URL
+            database_url: self.database_url.clone().or_else(|| {
+                (self.db.is_none() && !self.memory)
+                    .then(|| db.url.clone())
+                    .flatten()
+            }),
+            pool_size: self
+                .pool_size
+                .or(db.pool_size)
+                .unwrap_or(default.pool_size),
+            acquire_timeout: self
+                .acquire_timeout_ms
+                .or(db.acquire_timeout_ms)
+                .map_or(default.acquire_timeout, Duration::from_millis),
+            statement_cache_size: self
+                .statement_cache_size
+                .or(db.statement_cache_size)
+                .unwrap_or(default.statement_cache_size),
+            read_pool_size: self
+                .read_pool_size
+                .or(db.read_pool_size)
+                .unwrap_or(default.read_pool_size),
+            compress_threshold: self
+                .compress_threshold
+                .or(db.compress_threshold)
+                .unwrap_or(default.compress_threshold),
+            encryption_keyfile: self
+                .encryption_keyfile
+                .clone()
+                .or(db.encryption_keyfile.clone()),
+            encryption_keys,
+            queue_defaults: file.queue_defaults.clone().unwrap_or_default(),
+            ..default
+        })
+    }
 }
@@ -0,0 +1,426 @@
+use crate::models::{AdminJob, Headers, Message, Queue, QueueSchema};
+use crate::queue::AdminJobKind;
+use crate::server::version::{API_PREFIX, API_VERSION, API_VERSION_HEADER};
+use reqwest::{Method, RequestBuilder, StatusCode};
+use serde::de::DeserializeOwned;
+use serde_json::{Value, json};
+use std::time::Duration;
+
+/// Errors returned by [`SqewClient`], mapped from HTTP status codes
+#[derive(Debug, thiserror::Error)]
+pub enum ClientError {
+    /// 404: the queue or message does not exist
+    #[error("not found: {0}")]
+    NotFound(String),
+    /// 409: queue already exists, or a lease token was mismatched/expired
+    #[error("conflict: {0}")]
+    Conflict(String),
+    /// 400/422: the request was rejected as invalid
+    #[error("bad request: {0}")]
+    BadRequest(String),
+    /// 401: the server requires an API key and none or a wrong one was sent
+    #[error("unauthorized: {0}")]
+    Unauthorized(String),
+    /// 403: the API key's role or queue pattern does not allow the request
+    #[error("forbidden: {0}")]
+    Forbidden(String),
+    /// 503: the server cannot take the request now, as while its database
+    /// is busy; it may be sent again after `retry_after`
+    #[error("unavailable: {message}")]
+    Unavailable { retry_after: Option<Duration>, message: String },
+    /// Any other non-success status
+    #[error("server error {status}: {message}")]
+    Server { status: StatusCode, message: String },
+    /// Transport or decoding failure
+    #[error(transparent)]
+    Http(#[from] reqwest::Error),
+}
+
+pub type Result<T> = std::result::Result<T, ClientError>;
+
+/// Options for [`SqewClient::enqueue_with`]
+#[derive(Debug, Clone, Default)]
+pub struct EnqueueRequest {
+    pub delay_ms: Option<i64>,
+    /// Deliver at this RFC 3339 time, with a UTC offset, instead of after
+    /// `delay_ms`
+    pub deliver_at: Option<String>,
+    pub priority: Option<i32>,
+    pub ttl_ms: Option<i64>,
+    /// Repeats of this key within the queue's dedup window return the original
+    pub dedup_key: Option<String>,
+    /// FIFO group; messages in a group are delivered one at a time, in order
+    pub group_id: Option<String>,
+    /// Routing metadata returned with the message
+    pub headers: Option<Headers>,
+    /// Correlates the message in the server's logs; generated when omitted
+    pub trace_id: Option<String>,
+    /// Tenant key: polls of a fair queue take turns between keys
+    pub fair_key: Option<String>,
+    /// Polls of a `sort_key` queue take lower keys first
+    pub sort_key: Option<f64>,
+    /// Queue to send a receipt to once the message is acked or
+    /// dead-lettered
+    pub reply_to: Option<String>,
+}
+
+/// Options for [`SqewClient::poll`]
+#[derive(Debug, Clone, Default)]
+pub struct PollRequest {
+    pub batch: Option<i64>,
+    pub visibility_ms: Option<i64>,
+    /// Long-poll: wait up to this long for messages on an empty queue
+    pub wait_ms: Option<i64>,
+    /// Consumer group to lease for; required on queues with groups
+    pub group: Option<String>,
+    /// Names the consumer holding the leases
+    pub consumer: Option<String>,
+}
+
+/// Outcome of a nack
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+pub struct NackOutcome {
+    pub requeued: u64,
+    pub dead_lettered: u64,
+}
+
+/// Async client for a remote sqew server's HTTP API, speaking version
+/// [`API_VERSION`] under its `/v1` paths
+#[derive(Debug, Clone)]
+pub struct SqewClient {
+    base_url: String,
+    http: reqwest::Client,
+    api_key: Option<String>,
+}
+
+impl SqewClient {
+    /// Create a client for the server at `base_url`, e.g. `http://127.0.0.1:8888`
+    pub fn new(base_url: impl Into<String>) -> Self {
+        Self::with_http_client(base_url, reqwest::Client::new())
+    }
+
+    /// Create a client reusing a preconfigured `reqwest::Client`
+    pub fn with_http_client(
+        base_url: impl Into<String>,
+        http: reqwest::Client,
+    ) -> Self {
+        let base_url = base_url.into().trim_end_matches('/').to_string();
+        Self { base_url, http, api_key: None }
+    }
+
+    /// Authenticate every request with `key`, for servers started with API
+    /// keys
+    pub fn with_api_key(
+        mut self,
+        key: impl Into<String>,
+    ) -> Self {
+        self.api_key = Some(key.into());
+        self
+    }
+
+    pub async fn list_queues(&self) -> Result<Vec<Queue>> {
+        self.send(self.request(Method::GET, "/queues")).await
+    }
+
+    pub async fn create_queue(
+        &self,
+        name: &str,
+        max_attempts: i32,
+    ) -> Result<Queue> {
+        let body = json!({ "name": name, "max_attempts": max_attempts });
+        self.send(self.request(Method::POST, "/queues").json(&body)).await
+    }
+
+    pub async fn get_queue(
+        &self,
+        name: &str,
+    ) -> Result<Queue> {
+        let path = format!("/queues/{name}");
+        self.send(self.request(Method::GET, &path)).await
+    }
+
+    pub async fn delete_queue(
+        &self,
+        name: &str,
+    ) -> Result<()> {
+        let path = format!("/queues/{name}");
+        let resp = self.request(Method::DELETE, &path).send().await?;
+        check(resp).await.map(|_| ())
+    }
+
+    /// Move a queue to the trash, from which [`SqewClient::restore_queue`]
+    /// brings it back until the server purges it
+    pub async fn trash_queue(
+        &self,
+        name: &str,
+    ) -> Result<()> {
+        let path = format!("/queues/{name}?soft=true");
+        let resp = self.request(Method::DELETE, &path).send().await?;
+        check(resp).await.map(|_| ())
+    }
+
+    pub async fn restore_queue(
+        &self,
+        name: &str,
+    ) -> Result<Queue> {
+        let path = format!("/queues/{name}/restore");
+        self.send(self.request(Method::POST, &path)).await
+    }
+
+    /// A queue's payload schema: the current version, or `version`
+    pub async fn schema(
+        &self,
+        name: &str,
+        version: Option<i64>,
+    ) -> Result<QueueSchema> {
+        let path = match version {
+            Some(v) => format!("/queues/{name}/schema?version={v}"),
+            None => format!("/queues/{name}/schema"),
+        };
+        self.send(self.request(Method::GET, &path)).await
+    }
+
+    pub async fn stats(
+        &self,
+        name: &str,
+    ) -> Result<Value> {
+        let path = format!("/queues/{name}/stats");
+        self.send(self.request(Method::GET, &path)).await
+    }
+
+    /// Enqueue a JSON payload with default options
+    pub async fn enqueue(
+        &self,
+        queue: &str,
+        payload: &Value,
+    ) -> Result<Message> {
+        self.enqueue_with(queue, payload, &EnqueueRequest::default()).await
+    }
+
+    pub async fn enqueue_with(
+        &self,
+        queue: &str,
+        payload: &Value,
+        opts: &EnqueueRequest,
+    ) -> Result<Message> {
+        let path = format!("/queues/{queue}/messages");
+        let body = json!({
+            "payload": payload,
+            "delay_ms": opts.delay_ms,
+            "deliver_at": opts.deliver_at,
+            "priority": opts.priority,
+            "ttl_ms": opts.ttl_ms,
+            "dedup_key": opts.dedup_key,
+            "group_id": opts.group_id,
+            "headers": opts.headers,
+            "trace_id": opts.trace_id,
+            "fair_key": opts.fair_key,
+            "sort_key": opts.sort_key,
+            "reply_to": opts.reply_to,
+        });
+        self.send(self.request(Method::POST, &path).json(&body)).await
+    }
+
+    /// Enqueue `bytes` of the media type `content_type` as they are, e.g.
+    /// protobuf or msgpack; JSON types are enqueued as JSON
+    pub async fn enqueue_bytes(
+        &self,
+        queue: &str,
+        bytes: Vec<u8>,
+        content_type: &str,
+    ) -> Result<Message> {
+        let path = format!("/queues/{queue}/messages");
+        let req = self
+            .request(Method::POST, &path)
+            .header(reqwest::header::CONTENT_TYPE, content_type)
+            .body(bytes);
+        self.send(req).await
+    }
+
+    /// A message's payload as `(content type, bytes)`, binary payloads
+    /// decoded
+    pub async fn payload(
+        &self,
+        queue: &str,
+        id: i64,
+    ) -> Result<(String, Vec<u8>)> {
+        let path = format!("/queues/{queue}/messages/{id}/payload");
+        let resp =
+            check(self.request(Method::GET, &path).send().await?).await?;
+        let content_type = resp
+            .headers()
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or("application/octet-stream")
+            .to_string();
+        Ok((content_type, resp.bytes().await?.to_vec()))
+    }
+
+    /// Peek messages without leasing
+    pub async fn peek(
+        &self,
+        queue: &str,
+        limit: i64,
+    ) -> Result<Vec<Message>> {
+        let path = format!("/queues/{queue}/messages?limit={limit}");
+        self.send(self.request(Method::GET, &path)).await
+    }
+
+    /// Lease messages; each carries the `lease_token` needed to ack/nack
+    pub async fn poll(
+        &self,
+        queue: &str,
+        opts: &PollRequest,
+    ) -> Result<Vec<Message>> {
+        let path = format!("/queues/{queue}/messages/poll");
+        let body = json!({
+            "batch": opts.batch,
+            "visibility_ms": opts.visibility_ms,
+            "wait_ms": opts.wait_ms,
+            "group": opts.group,
+            "consumer": opts.consumer,
+        });
+        self.send(self.request(Method::POST, &path).json(&body)).await
+    }
+
+    /// Ack leased messages; returns how many were deleted
+    pub async fn ack(
+        &self,
+        ids: &[i64],
+        lease_token: &str,
+    ) -> Result<u64> {
+        let body = json!({ "ids": ids, "lease_token": lease_token });
+        let v: Value = self
+            .send(self.request(Method::POST, "/messages/ack").json(&body))
+            .await?;
+        Ok(v["acked"].as_u64().unwrap_or(0))
+    }
+
+    /// Nack leased messages, making them visible again after `delay_ms`
+    pub async fn nack(
+        &self,
+        ids: &[i64],
+        lease_token: &str,
+        delay_ms: i64,
+    ) -> Result<NackOutcome> {
+        let body = json!({
+            "ids": ids,
+            "lease_token": lease_token,
+            "delay_ms": delay_ms,
+        });
+        self.send(self.request(Method::POST, "/messages/nack").json(&body))
+            .await
+    }
+
+    /// Nack leased messages, each visible again after its own delay:
+    /// `nacks` pairs message ids with delays in milliseconds
+    pub async fn nack_with_delays(
+        &self,
+        nacks: &[(i64, i64)],
+        lease_token: &str,
+    ) -> Result<NackOutcome> {
+        let delays: Vec<Value> = nacks
+            .iter()
+            .map(|&(id, delay_ms)| json!({ "id": id, "delay_ms": delay_ms }))
+            .collect();
+        let body = json!({ "lease_token": lease_token, "delays": delays });
+        self.send(self.request(Method::POST, "/messages/nack").json(&body))
+            .await
+    }
+
+    /// Extend the lease on a message (heartbeat)
+    pub async fn extend(
+        &self,
+        id: i64,
+        lease_token: &str,
+        extra_ms: i64,
+    ) -> Result<()> {
+        let path = format!("/messages/{id}/extend");
+        let body = json!({ "lease_token": lease_token, "extra_ms": extra_ms });
+        let _: Value =
+            self.send(self.request(Method::POST, &path).json(&body)).await?;
+        Ok(())
+    }
+
+    /// Queue an admin job for the server to run in the background.
+    /// `queue` is required by all kinds but `compact`, `path` (a file on
+    /// the server) by `export`.
+    pub async fn start_job(
+        &self,
+        kind: AdminJobKind,
+        queue: Option<&str>,
+        path: Option<&str>,
+    ) -> Result<AdminJob> {
+        let body = json!({ "kind": kind, "queue": queue, "path": path });
+        self.send(self.request(Method::POST, "/admin/jobs").json(&body)).await
+    }
+
+    /// An admin job's status and progress
+    pub async fn job(
+        &self,
+        id: i64,
+    ) -> Result<AdminJob> {
+        let path = format!("/admin/jobs/{id}");
+        self.send(self.request(Method::GET, &path)).await
+    }
+
+    /// Cancel a pending admin job, or ask a running one to stop
+    pub async fn cancel_job(
+        &self,
+        id: i64,
+    ) -> Result<AdminJob> {
+        let path = format!("/admin/jobs/{id}/cancel");
+        self.send(self.request(Method::POST, &path)).await
+    }
+
+    fn request(
+        &self,
+        method: Method,
+        path: &str,
+    ) -> RequestBuilder {
+        let url = format!("{}{}{}", self.base_url, API_PREFIX, path);
+        let req = self
+            .http
+            .request(method, url)
+            .header(API_VERSION_HEADER, API_VERSION);
+        match &self.api_key {
+            Some(key) => req.bearer_auth(key),
+            None => req,
+        }
+    }
+
+    async fn send<T: DeserializeOwned>(
+        &self,
+        req: RequestBuilder,
+    ) -> Result<T> {
+        let resp = check(req.send().await?).await?;
+        Ok(resp.json().await?)
+    }
+}
+
+// Turn non-success responses into typed errors carrying the server message
+async fn check(resp: reqwest::Response) -> Result<reqwest::Response> {
+    let status = resp.status();
+    if status.is_success() {
+        return Ok(resp);
+    }
+    let retry_after = resp
+        .headers()
+        .get(reqwest::header::RETRY_AFTER)
+        .and_then(|v| v.to_str().ok()?.parse().ok())
+        .map(Duration::from_secs);
+    let message = resp.text().await.unwrap_or_default();
+    Err(match status {
+        StatusCode::NOT_FOUND => ClientError::NotFound(message),
+        StatusCode::CONFLICT => ClientError::Conflict(message),
+        StatusCode::BAD_REQUEST | StatusCode::UNPROCESSABLE_ENTITY => {
+            ClientError::BadRequest(message)
+        }
+        StatusCode::UNAUTHORIZED => ClientError::Unauthorized(message),
+        StatusCode::FORBIDDEN => ClientError::Forbidden(message),
+        StatusCode::SERVICE_UNAVAILABLE => {
+            ClientError::Unavailable { retry_after, message }
+        }
+        _ => ClientError::Server { status, message },
+    })
+}
@@ -0,0 +1,298 @@
+//! The `sqew.toml` configuration file, loaded with the global `--config`
+//! flag. Every setting is optional: command line flags and their environment
+//! variables override the file, and the file overrides built-in defaults.
+//! Relative paths are resolved against the file's directory.
+//!
+//! Named profiles under `[profiles.<name>]`, selected with `--profile` or
+//! `SQEW_PROFILE`, override any of the sections below, so one file serves
+//! several environments: the profile's tables are laid over the rest key by
+//! key, and its other values, lists included, replace the file's.
+//!
+//! ```toml
+//! [database]
+//! path = "/var/lib/sqew/sqew.db"
+//! pool_size = 16
+//! read_pool_size = 4
+//!
+//! [server]
+//! bind = "0.0.0.0"
+//! port = 8888
+//! api_keys = ["s3cret"]
+//! cors_origins = ["https://dash.example.com"]
+//!
+//! [tasks]
+//! lease_reap_ms = 500
+//!
+//! [queue_defaults]
+//! max_attempts = 10
+//! retention_days = 7
+//!
+//! [auto_compact]
+//! window = "* 2-4 * * *"
+//!
+//! [mqtt]
+//! host = "broker.local"
+//!
+//! [[mqtt.subscribe]]
+//! topic = "sensors/+/reading"
+//! queue = "readings"
+//!
+//! [profiles.dev.database]
+//! path = "dev.db"
+//!
+//! [profiles.prod.server]
+//! bind = "10.0.0.5"
+//! api_keys = ["pr0d-s3cret"]
+//!
+//! [profiles.prod.queue_defaults]
+//! max_attempts = 20
+//! ```
+
+use crate::mqtt::MqttConfig;
+use crate::queue::{AutoCompactConfig, QueueOptions};
+use crate::server::{ChaosConfig, TaskIntervals, parse_cors_origin};
+use anyhow::{Context, Result, anyhow, bail};
+use axum::http::HeaderValue;
+use serde::Deserialize;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Contents of a configuration file
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConfigFile {
+    pub database: DatabaseSection,
+    pub server: ServerSection,
+    pub tasks: TasksSection,
+    /// Settings of queues created without explicit ones, by `queue add`, the
+    /// HTTP API or a Redis `LPUSH`
+    pub queue_defaults: Option<QueueOptions>,
+    /// MQTT broker bridged by `sqew serve`
+    pub mqtt: Option<MqttConfig>,
+    /// When `sqew serve` compacts the database by itself
+    pub auto_compact: Option<AutoCompactConfig>,
+}
+
+/// `[database]`: where the queues live
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseSection {
+    /// SQLite database file
+    pub path: Option<PathBuf>,
+    /// Connection URL, overriding `path`
+    pub url: Option<String>,
+    pub pool_size: Option<u32>,
+    pub acquire_timeout_ms: Option<u64>,
+    pub statement_cache_size: Option<usize>,
+    /// Connections of the read-only pool for peeks and stats (SQLite)
+    pub read_pool_size: Option<u32>,
+    pub compress_threshold: Option<usize>,
+    /// File holding the payload encryption keys
+    pub encryption_keyfile: Option<PathBuf>,
+}
+
+/// `[server]`: settings of `sqew serve`
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerSection {
+    pub bind: Option<IpAddr>,
+    pub port: Option<u16>,
+    pub redis_port: Option<u16>,
+    pub drain_timeout_ms: Option<u64>,
+    pub max_payload_bytes: Option<usize>,
+    /// Keys clients must present; an empty list leaves the API open
+    pub api_keys: Vec<String>,
+    /// Fault injection spec, as for `serve --chaos`
+    #[serde(default, deserialize_with = "parse_chaos")]
+    pub chaos: Option<ChaosConfig>,
+    /// Origins browsers may call the API from, as for `serve --cors-origin`
+    #[serde(default, deserialize_with = "parse_cors_origins")]
+    pub cors_origins: Vec<HeaderValue>,
+    pub max_body_bytes: Option<usize>,
+    pub request_timeout_ms: Option<u64>,
+    /// Repair what the startup recovery scan finds, as for
+    /// `serve --repair-on-start`
+    pub repair_on_start: bool,
+}
+
+/// `[tasks]`: how often the server's background tasks run, in milliseconds
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TasksSection {
+    pub expiry_sweep_ms: Option<u64>,
+    pub lease_reap_ms: Option<u64>,
+    pub alarm_eval_ms: Option<u64>,
+    pub archive_purge_ms: Option<u64>,
+    pub schedule_tick_ms: Option<u64>,
+    pub stats_snapshot_ms: Option<u64>,
+    pub push_tick_ms: Option<u64>,
+    pub trash_purge_ms: Option<u64>,
+    pub admin_jobs_ms: Option<u64>,
+    pub auto_compact_ms: Option<u64>,
+}
+
+// Parse `[server] chaos` from its spec string
+fn parse_chaos<'de, D: serde::Deserializer<'de>>(
+    d: D
+) -> std::result::Result<Option<ChaosConfig>, D::Error> {
+    let spec = String::deserialize(d)?;
+    spec.parse().map(Some).map_err(serde::de::Error::custom)
+}
+
+// Parse `[server] cors_origins`, each as for `serve --cors-origin`
+fn parse_cors_origins<'de, D: serde::Deserializer<'de>>(
+    d: D
+) -> std::result::Result<Vec<HeaderValue>, D::Error> {
+    Vec::<String>::deserialize(d)?
+        .iter()
+        .map(|o| parse_cors_origin(o).map_err(serde::de::Error::custom))
+        .collect()
+}
+
+impl ConfigFile {
+    /// Read and parse a configuration file
+    pub fn load(path: &Path) -> Result<Self> {
+        Self::load_profile(path, None)
+    }
+
+    /// Read and parse a configuration file, with the settings of the named
+    /// profile laid over the rest
+    pub fn load_profile(
+        path: &Path,
+        profile: Option<&str>,
+    ) -> Result<Self> {
+        let text = std::fs::read_to_string(path).with_context(|| {
+            format!("Failed to read config file {}", path.display())
+        })?;
+        let invalid = || format!("Invalid config file {}", path.display());
+        let mut table: toml::Table =
+            toml::from_str(&text).with_context(invalid)?;
+        let profiles = match table.remove("profiles") {
+            None => toml::Table::new(),
+            Some(toml::Value::Table(profiles)) => profiles,
+            Some(_) => bail!("{}: [profiles] must be a table", invalid()),
+        };
+        if let Some(name) = profile {
+            let Some(toml::Value::Table(overrides)) = profiles.get(name) else {
+                let known: Vec<&str> =
+                    profiles.keys().map(String::as_str).collect();
+                bail!(
+                    "Unknown profile '{name}' in config file {}; it defines: {}",
+                    path.display(),
+                    if known.is_empty() {
+                        "none".into()
+                    } else {
+                        known.join(", ")
+                    }
+                );
+            };
+            overlay(&mut table, overrides);
+        }
+        let mut file: ConfigFile = toml::Value::Table(table)
+            .try_into()
+            .with_context(|| match profile {
+                Some(name) => format!("{} (profile '{name}')", invalid()),
+                None => invalid(),
+            })?;
+        file.tasks.intervals()?;
+        if let Some(mqtt) = &file.mqtt {
+            mqtt.validate()?;
+        }
+        if let Some(auto) = &file.auto_compact {
+            auto.validate().context("Invalid [auto_compact] window")?;
+        }
+        let dir = path.parent().unwrap_or(Path::new("."));
+        let db = &mut file.database;
+        for p in
+            [&mut db.path, &mut db.encryption_keyfile].into_iter().flatten()
+        {
+            if p.is_relative() {
+                *p = dir.join(&*p);
+            }
+        }
+        Ok(file)
+    }
+}
+
+// Lay a profile's settings over the file's: tables merge key by key, and
+// any other value replaces the one underneath
+fn overlay(
+    base: &mut toml::Table,
+    overrides: &toml::Table,
+) {
+    for (key, value) in overrides {
+        match (base.get_mut(key), value) {
+            (Some(toml::Value::Table(below)), toml::Value::Table(above)) => {
+                overlay(below, above)
+            }
+            _ => {
+                base.insert(key.clone(), value.clone());
+            }
+        }
+    }
+}
+
+impl TasksSection {
+    /// The configured intervals, with defaults for those left out
+    pub fn intervals(&self) -> Result<TaskIntervals> {
+        let default = TaskIntervals::default();
+        let pick = |name: &str, ms: Option<u64>, default: Duration| match ms {
+            None => Ok(default),
+            Some(0) => Err(anyhow!("Invalid [tasks] {name}: must be positive")),
+            Some(ms) => Ok(Duration::from_millis(ms)),
+        };
+        Ok(TaskIntervals {
+            expiry_sweep: pick(
+                "expiry_sweep_ms",
+                self.expiry_sweep_ms,
+                default.expiry_sweep,
+            )?,
+            lease_reap: pick(
+                "lease_reap_ms",
+                self.lease_reap_ms,
+                default.lease_reap,
+            )?,
+            alarm_eval: pick(
+                "alarm_eval_ms",
+                self.alarm_eval_ms,
+                default.alarm_eval,
+            )?,
+            archive_purge: pick(
+                "archive_purge_ms",
+                self.archive_purge_ms,
+                default.archive_purge,
+            )?,
+            schedule_tick: pick(
+                "schedule_tick_ms",
+                self.schedule_tick_ms,
+                default.schedule_tick,
+            )?,
+            stats_snapshot: pick(
+                "stats_snapshot_ms",
+                self.stats_snapshot_ms,
+                default.stats_snapshot,
+            )?,
+            push_tick: pick(
+                "push_tick_ms",
+                self.push_tick_ms,
+                default.push_tick,
+            )?,
+            trash_purge: pick(
+                "trash_purge_ms",
+                self.trash_purge_ms,
+                default.trash_purge,
+            )?,
+            admin_jobs: pick(
+                "admin_jobs_ms",
+                self.admin_jobs_ms,
+                default.admin_jobs,
+            )?,
+            auto_compact: pick(
+                "auto_compact_ms",
+                self.auto_compact_ms,
+                default.auto_compact,
+            )?,
+        })
+    }
+}
@@ -0,0 +1,243 @@
+use crate::db::Db;
+use crate::models::Message;
+use crate::queue;
+use anyhow::{Context, Result};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::task::JoinSet;
+
+/// How long an idle consumer waits before polling an empty queue again
+const IDLE_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Processes messages from a queue with a handler, leasing, renewing and
+/// settling them: a handler returning `Ok` acks its message, an error (or
+/// exceeding [`Consumer::max_runtime`]) nacks it, keeping the error as the
+/// message's `last_error`.
+///
+/// ```no_run
+/// # async fn example(db: sqew::db::Db) -> anyhow::Result<()> {
+/// use sqew::consumer::Consumer;
+///
+/// Consumer::new(&db, "jobs", |msg| async move {
+///     println!("{}", msg.payload);
+///     Ok(())
+/// })
+/// .concurrency(4)
+/// .run(async {
+///     let _ = tokio::signal::ctrl_c().await;
+/// })
+/// .await
+/// # }
+/// ```
+pub struct Consumer<F> {
+    db: Db,
+    queue: String,
+    handler: Arc<F>,
+    concurrency: usize,
+    visibility_ms: Option<i64>,
+    max_runtime: Option<Duration>,
+    retry_delay_ms: i64,
+    poll_interval: Duration,
+}
+
+impl<F, Fut> Consumer<F>
+where
+    F: Fn(Message) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<()>> + Send,
+{
+    /// A consumer of `queue` running `handler` for one message at a time
+    pub fn new(
+        db: &Db,
+        queue: impl Into<String>,
+        handler: F,
+    ) -> Self {
+        Self {
+            db: db.clone(),
+            queue: queue.into(),
+            handler: Arc::new(handler),
+            concurrency: 1,
+            visibility_ms: None,
+            max_runtime: None,
+            retry_delay_ms: 1000,
+            poll_interval: IDLE_POLL_INTERVAL,
+        }
+    }
+
+    /// Number of messages handled in parallel (default 1)
+    pub fn concurrency(
+        mut self,
+        n: usize,
+    ) -> Self {
+        self.concurrency = n.max(1);
+        self
+    }
+
+    /// Lease length in milliseconds, renewed at half this interval while
+    /// the handler runs (default: the queue's default visibility)
+    pub fn visibility_ms(
+        mut self,
+        ms: i64,
+    ) -> Self {
+        self.visibility_ms = Some(ms);
+        self
+    }
+
+    /// Abandon (and nack) a handler running longer than this
+    pub fn max_runtime(
+        mut self,
+        limit: Duration,
+    ) -> Self {
+        self.max_runtime = Some(limit);
+        self
+    }
+
+    /// Delay before a failed message becomes visible again (default 1000)
+    pub fn retry_delay_ms(
+        mut self,
+        ms: i64,
+    ) -> Self {
+        self.retry_delay_ms = ms;
+        self
+    }
+
+    /// How long to wait before polling an empty queue again (default 500ms)
+    pub fn poll_interval(
+        mut self,
+        interval: Duration,
+    ) -> Self {
+        self.poll_interval = interval;
+        self
+    }
+
+    /// Process messages until `shutdown` resolves. Messages already leased
+    /// when shutdown is requested are handled to completion.
+    pub async fn run(
+        self,
+        shutdown: impl Future<Output = ()>,
+    ) -> Result<()> {
+        // Fail fast on an unknown queue rather than polling it forever
+        let q = queue::show_queue(&self.db, &self.queue).await?;
+        let visibility_ms =
+            self.visibility_ms.unwrap_or(q.default_visibility_ms).max(1);
+        let this = Arc::new(self);
+        let (stop_tx, stop_rx) = watch::channel(false);
+        let mut tasks = JoinSet::new();
+        for _ in 0..this.concurrency {
+            let this = this.clone();
+            let stop = stop_rx.clone();
+            tasks.spawn(async move { this.consume(visibility_ms, stop).await });
+        }
+        shutdown.await;
+        let _ = stop_tx.send(true);
+        while let Some(res) = tasks.join_next().await {
+            res.context("Consumer task panicked")?;
+        }
+        Ok(())
+    }
+
+    // Poll one message at a time and handle it until stopped
+    async fn consume(
+        &self,
+        visibility_ms: i64,
+        mut stop: watch::Receiver<bool>,
+    ) {
+        while !*stop.borrow() {
+            let polled =
+                queue::poll_messages(&self.db, &self.queue, 1, visibility_ms)
+                    .await;
+            match polled {
+                Ok(mut msgs) if !msgs.is_empty() => {
+                    let msg = msgs.remove(0);
+                    let id = msg.id;
+                    if let Err(e) = self.process(visibility_ms, msg).await {
+                        tracing::warn!("Message {id} failed to settle: {e:#}");
+                    }
+                    continue;
+                }
+                Ok(_) => {}
+                Err(e) => tracing::warn!("Poll failed: {e:#}"),
+            }
+            tokio::select! {
+                _ = tokio::time::sleep(self.poll_interval) => {}
+                _ = stop.changed() => {}
+            }
+        }
+    }
+
+    // Run the handler for one leased message, then ack or nack it
+    #[tracing::instrument(
+        skip_all,
+        fields(
+            queue = %self.queue,
+            message_id = msg.id,
+            attempt = msg.attempts + 1,
+            trace_id = msg.trace_id.as_deref(),
+        )
+    )]
+    async fn process(
+        &self,
+        visibility_ms: i64,
+        msg: Message,
+    ) -> Result<()> {
+        let id = msg.id;
+        let token = msg.lease_token.clone().unwrap_or_default();
+        let handled = self.handle(visibility_ms, msg, &token).await;
+        if let Err(e) = handled {
+            // Keep the error on the message so dead letters show why
+            let reason = format!("{e:#}");
+            tracing::warn!("Message {id}: {reason}");
+            let (_, dead) = queue::nack_messages_with_reason(
+                &self.db,
+                &[(id, self.retry_delay_ms)],
+                &token,
+                Some(&reason),
+            )
+            .await?;
+            if dead > 0 {
+                tracing::warn!("Message {id} dead-lettered");
+            }
+        } else {
+            let n = queue::ack_messages(&self.db, &[id], &token).await?;
+            if n == 0 {
+                tracing::warn!("Message {id} lease was lost before ack");
+            }
+        }
+        Ok(())
+    }
+
+    // Await the handler within `max_runtime`, renewing the lease meanwhile
+    async fn handle(
+        &self,
+        visibility_ms: i64,
+        msg: Message,
+        token: &str,
+    ) -> Result<()> {
+        let id = msg.id;
+        let handler = (self.handler)(msg);
+        tokio::pin!(handler);
+        let deadline = async {
+            match self.max_runtime {
+                Some(limit) => tokio::time::sleep(limit).await,
+                None => std::future::pending().await,
+            }
+        };
+        tokio::pin!(deadline);
+        let heartbeat =
+            Duration::from_millis((visibility_ms / 2).max(1) as u64);
+        let mut ticker = tokio::time::interval(heartbeat);
+        ticker.tick().await;
+        loop {
+            tokio::select! {
+                res = &mut handler => return res,
+                _ = &mut deadline => anyhow::bail!("exceeded max runtime"),
+                _ = ticker.tick() => {
+                    let extra = heartbeat.as_millis() as i64;
+                    if let Err(e) = queue::extend_visibility(&self.db, &[id], token, extra).await {
+                        tracing::warn!("Message {id}: lease renewal failed: {e:#}");
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,148 @@
+//! AES-256-GCM encryption of stored payloads.
+//!
+//! A [`Keyring`] holds the active key, which encrypts new payloads, and the
+//! retired keys still needed to read payloads written before a rotation.
+//! Each key is known by its id, a key check value (the first four bytes of
+//! the key's AES encryption of a zero block, in hex), which is stored with
+//! every encrypted payload so the right key can be found after a rotation.
+
+use aes_gcm::aead::{Aead, AeadCore, OsRng};
+use aes_gcm::aes::Aes256;
+use aes_gcm::aes::cipher::BlockEncrypt;
+use aes_gcm::{Aes256Gcm, Key, KeyInit, Nonce};
+use anyhow::{Context, anyhow};
+use std::fmt;
+use std::path::Path;
+
+// Bytes of the random nonce stored in front of each ciphertext
+const NONCE_LEN: usize = 12;
+
+struct PayloadKey {
+    id: String,
+    cipher: Aes256Gcm,
+}
+
+/// Payload encryption keys: the first is active, the rest are retired
+pub struct Keyring {
+    keys: Vec<PayloadKey>,
+}
+
+impl Keyring {
+    /// Parse 256-bit keys written as 64 hex digits, separated by commas or
+    /// newlines. The first key is active; blank lines and `#` comments are
+    /// skipped.
+    pub fn parse(spec: &str) -> anyhow::Result<Self> {
+        let mut keys: Vec<PayloadKey> = Vec::new();
+        let entries = spec
+            .lines()
+            .map(|line| line.split('#').next().unwrap_or_default())
+            .flat_map(|line| line.split(','))
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty());
+        for (n, entry) in entries.enumerate() {
+            let bytes = decode_hex(entry).ok_or_else(|| {
+                anyhow!(
+                    "Invalid encryption key {}: expected 64 hex digits",
+                    n + 1
+                )
+            })?;
+            let key = PayloadKey::new(&bytes);
+            if keys.iter().any(|k| k.id == key.id) {
+                return Err(anyhow!("Duplicate encryption key {}", key.id));
+            }
+            keys.push(key);
+        }
+        if keys.is_empty() {
+            return Err(anyhow!("No encryption key given"));
+        }
+        Ok(Keyring { keys })
+    }
+
+    /// Read keys from a file in the format of [`Keyring::parse`]
+    pub fn read(path: &Path) -> anyhow::Result<Self> {
+        let spec = std::fs::read_to_string(path).with_context(|| {
+            format!("Failed to read encryption keyfile {}", path.display())
+        })?;
+        Self::parse(&spec)
+    }
+
+    /// Id of the key that encrypts new payloads
+    pub fn active_id(&self) -> &str {
+        &self.keys[0].id
+    }
+
+    // Encrypt with the active key under a fresh random nonce, returning the
+    // nonce followed by the ciphertext
+    pub(crate) fn encrypt(
+        &self,
+        plain: &[u8],
+    ) -> sqlx::Result<Vec<u8>> {
+        let cipher = &self.keys[0].cipher;
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let sealed = cipher.encrypt(&nonce, plain).map_err(|_| {
+            sqlx::Error::Encode("payload encryption failed".into())
+        })?;
+        let mut out = Vec::with_capacity(NONCE_LEN + sealed.len());
+        out.extend_from_slice(&nonce);
+        out.extend_from_slice(&sealed);
+        Ok(out)
+    }
+
+    // Decrypt what `encrypt` produced under the key with id `key_id`
+    pub(crate) fn decrypt(
+        &self,
+        key_id: &str,
+        bytes: &[u8],
+    ) -> sqlx::Result<Vec<u8>> {
+        let key =
+            self.keys.iter().find(|k| k.id == key_id).ok_or_else(|| {
+                sqlx::Error::Decode(
+                    format!("payload encrypted with unknown key '{key_id}'")
+                        .into(),
+                )
+            })?;
+        if bytes.len() < NONCE_LEN {
+            return Err(sqlx::Error::Decode(
+                "encrypted payload truncated".into(),
+            ));
+        }
+        let (nonce, sealed) = bytes.split_at(NONCE_LEN);
+        key.cipher.decrypt(Nonce::from_slice(nonce), sealed).map_err(|_| {
+            sqlx::Error::Decode(
+                format!("payload failed to decrypt with key '{key_id}'").into(),
+            )
+        })
+    }
+}
+
+impl PayloadKey {
+    fn new(bytes: &[u8; 32]) -> Self {
+        let mut block = [0u8; 16].into();
+        Aes256::new(bytes.into()).encrypt_block(&mut block);
+        let id = block[..4].iter().map(|b| format!("{b:02x}")).collect();
+        let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(bytes));
+        PayloadKey { id, cipher }
+    }
+}
+
+// Keys never appear in debug output, only their ids
+impl fmt::Debug for Keyring {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+    ) -> fmt::Result {
+        let ids: Vec<&str> = self.keys.iter().map(|k| k.id.as_str()).collect();
+        f.debug_struct("Keyring").field("keys", &ids).finish()
+    }
+}
+
+fn decode_hex(s: &str) -> Option<[u8; 32]> {
+    if s.len() != 64 || !s.is_ascii() {
+        return None;
+    }
+    let mut out = [0u8; 32];
+    for (i, byte) in out.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&s[2 * i..2 * i + 2], 16).ok()?;
+    }
+    Some(out)
+}
@@ -1,406 +1,1553 @@
-use crate::models::{Message, Queue};
-use anyhow::Context;
-use sqlx::{Executor, Sqlite, SqlitePool, Transaction};
-use sqlx::sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions};
-use std::str::FromStr;
+use crate::models::{
+    AdminJob, Alarm, ApiKey, ArchivedMessage, AuditEntry, ConsumerGroup,
+    InFlightMessage, Message, MessageAttempt, PushConfig, PushDelivery, Queue,
+    QueueSchema, Schedule, StatsSample,
+};
+use async_trait::async_trait;
 use std::path::Path;
-use std::{env, fs};
-// Embedded initial SQL schema for bootstrapping a new database
-const INIT_SQL: &str = r#"
--- Initial schema for Sqew message queue
-CREATE TABLE queue (
-  id            INTEGER PRIMARY KEY,
-  name          TEXT UNIQUE NOT NULL,
-  max_attempts  INTEGER NOT NULL DEFAULT 5
-);
-
-CREATE TABLE message (
-  id               INTEGER PRIMARY KEY,
-  queue_id         INTEGER NOT NULL REFERENCES queue(id) ON DELETE CASCADE,
-  payload          TEXT NOT NULL,
-  attempts         INTEGER NOT NULL DEFAULT 0,
-  available_at     INTEGER NOT NULL,
-  created_at       INTEGER NOT NULL
-);
-
-CREATE INDEX ix_msg_visible ON message(queue_id, available_at);
-"#;
-
-pub async fn get_queue_by_name(
-    pool: &SqlitePool,
-    name: &str,
-) -> sqlx::Result<Option<Queue>> {
-    sqlx::query_as::<_, Queue>(
-        "SELECT id, name, max_attempts FROM queue WHERE name = ?",
-    )
-    .bind(name)
-    .fetch_optional(pool)
-    .await
-}
-
-pub async fn create_queue(
-    pool: &SqlitePool,
-    name: &str,
-    max_attempts: i32,
-) -> sqlx::Result<i64> {
-    let rec =
-        sqlx::query("INSERT INTO queue (name, max_attempts) VALUES (?, ?)")
-            .bind(name)
-            .bind(max_attempts)
-            .execute(pool)
-            .await?;
-    Ok(rec.last_insert_rowid())
-}
-
-pub async fn enqueue_message(
-    pool: &SqlitePool,
-    msg: &Message,
-) -> sqlx::Result<i64> {
-    let rec = sqlx::query(
-        "INSERT INTO message (queue_id, payload, attempts, available_at, created_at) VALUES (?, ?, ?, ?, ?)",
-    )
-    .bind(msg.queue_id)
-    .bind(&msg.payload)
-    .bind(msg.attempts)
-    .bind(msg.available_at)
-    .bind(msg.created_at)
-    .execute(pool)
-    .await?;
-    Ok(rec.last_insert_rowid())
-}
-
-pub async fn get_message_by_id(
-    pool: &SqlitePool,
-    id: i64,
-) -> sqlx::Result<Option<Message>> {
-    sqlx::query_as::<_, Message>(
-        "SELECT id, queue_id, payload, attempts, available_at, created_at FROM message WHERE id = ?",
-    )
-    .bind(id)
-    .fetch_optional(pool)
-    .await
-}
-/// Delete messages by IDs (ack)
-pub async fn ack_messages(
-    pool: &SqlitePool,
-    ids: &[i64],
-) -> sqlx::Result<u64> {
-    if ids.is_empty() {
-        return Ok(0);
+use std::sync::Arc;
+use std::time::Duration;
+
+pub mod crypto;
+pub mod postgres;
+pub mod sqlite;
+
+pub use crypto::Keyring;
+pub use postgres::PgStorage;
+pub use sqlite::SqliteStorage;
+
+/// Default number of pooled database connections
+pub const DEFAULT_POOL_SIZE: u32 = 32;
+
+/// Default time a query waits for a free pooled connection
+pub const DEFAULT_ACQUIRE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Default number of prepared statements cached per connection
+pub const DEFAULT_STATEMENT_CACHE_SIZE: usize = 100;
+
+/// Connection pool settings
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PoolOptions {
+    /// Maximum number of pooled connections
+    pub max_connections: u32,
+    /// How long a query waits for a free connection before failing
+    pub acquire_timeout: Duration,
+    /// Prepared statements cached per connection
+    pub statement_cache_size: usize,
+    /// SQLite only: connections of a separate read-only pool serving peeks,
+    /// searches and stats, so they do not queue behind writers for a
+    /// connection; 0 runs them on the main pool
+    pub read_connections: u32,
+}
+
+impl Default for PoolOptions {
+    fn default() -> Self {
+        PoolOptions {
+            max_connections: DEFAULT_POOL_SIZE,
+            acquire_timeout: DEFAULT_ACQUIRE_TIMEOUT,
+            statement_cache_size: DEFAULT_STATEMENT_CACHE_SIZE,
+            read_connections: 0,
+        }
     }
-    let placeholders =
-        std::iter::repeat_n("?", ids.len()).collect::<Vec<_>>().join(",");
-    let sql = format!("DELETE FROM message WHERE id IN ({})", placeholders);
-    let mut q = sqlx::query(&sql);
-    for id in ids {
-        q = q.bind(id);
+}
+
+/// Default per-queue window for enqueue deduplication (5 minutes)
+pub const DEFAULT_DEDUP_WINDOW_MS: i64 = 300_000;
+
+/// Default lease length for polls that give no visibility timeout (30 seconds)
+pub const DEFAULT_VISIBILITY_MS: i64 = 30_000;
+
+/// Default size in bytes above which SQLite stores payloads zstd-compressed
+pub const DEFAULT_COMPRESS_THRESHOLD: usize = 4096;
+
+// Milliseconds per day, for retention periods given in days
+const DAY_MS: i64 = 86_400_000;
+
+// Condition on a `message` row: its queue has consumer groups and every
+// group that should receive the message has acked or dead-lettered it, so it
+// can be deleted
+const DONE_BY_ALL_GROUPS: &str = "EXISTS (
+       SELECT 1 FROM consumer_group cg WHERE cg.queue_id = message.queue_id)
+     AND NOT EXISTS (
+       SELECT 1 FROM consumer_group cg
+       WHERE cg.queue_id = message.queue_id AND cg.start_id < message.id
+         AND NOT EXISTS (
+           SELECT 1 FROM group_delivery gd
+           WHERE gd.consumer_group_id = cg.id AND gd.message_id = message.id
+             AND (gd.acked_at IS NOT NULL OR gd.dead_at IS NOT NULL)))";
+
+// Deliveries a rate-limited queue's token bucket allows at `now`, given the
+// balance `tokens` stored at `updated_at`. The bucket refills at `rate` per
+// second, holds one second's worth of deliveries (at least one) and starts
+// full.
+fn rate_tokens(
+    rate: f64,
+    tokens: Option<f64>,
+    updated_at: Option<i64>,
+    now: i64,
+) -> f64 {
+    let capacity = rate.max(1.0);
+    match (tokens, updated_at) {
+        (Some(tokens), Some(at)) => {
+            let refill = (now - at).max(0) as f64 / 1000.0 * rate;
+            (tokens + refill).min(capacity)
+        }
+        _ => capacity,
     }
-    let res = q.execute(pool).await?;
-    Ok(res.rows_affected())
 }
-/// List all queues
-pub async fn list_queues(pool: &SqlitePool) -> sqlx::Result<Vec<Queue>> {
-    sqlx::query_as::<_, Queue>(
-        "SELECT id, name, max_attempts FROM queue ORDER BY id",
-    )
-    .fetch_all(pool)
-    .await
-}
-
-/// Delete a queue by name, returning how many rows were affected
-pub async fn delete_queue_by_name(
-    pool: &SqlitePool,
-    name: &str,
-) -> sqlx::Result<u64> {
-    let res = sqlx::query("DELETE FROM queue WHERE name = ?")
-        .bind(name)
-        .execute(pool)
-        .await?;
-    Ok(res.rows_affected())
-}
-
-// Add more helper functions for poll, ack, nack, batch operations, DLQ, etc.
-/// Purge all messages in the given queue
-pub async fn purge_messages_by_queue(
-    pool: &SqlitePool,
-    queue_name: &str,
-) -> sqlx::Result<u64> {
-    // Delete messages matching the queue name
-    let res =
-        sqlx::query("DELETE FROM message WHERE queue_id = (SELECT id FROM queue WHERE name = ?)")
-            .bind(queue_name)
-            .execute(pool)
-            .await?;
-    Ok(res.rows_affected())
-}
-
-/// Peek (list) messages in a queue without leasing
-pub async fn peek_messages(
-    pool: &SqlitePool,
-    queue_name: &str,
-    limit: i64,
-) -> sqlx::Result<Vec<Message>> {
-    let msgs = sqlx::query_as::<_, Message>(
-        "SELECT id, queue_id, payload, attempts, available_at, created_at
-         FROM message
-         WHERE queue_id = (SELECT id FROM queue WHERE name = ?)
-         ORDER BY available_at, id
-         LIMIT ?",
-    )
-    .bind(queue_name)
-    .bind(limit)
-    .fetch_all(pool)
-    .await?;
-    Ok(msgs)
-}
-
-/// Poll (lease) up to `limit` messages: select ready, set available_at forward, return messages.
-pub async fn poll_messages(
-    pool: &SqlitePool,
-    queue_name: &str,
-    limit: i64,
-    visibility_ms: i64,
-) -> sqlx::Result<Vec<Message>> {
-    // Retry loop to mitigate SQLITE_BUSY/SQLITE_BUSY_SNAPSHOT under contention
-    let mut attempt = 0u32;
-    loop {
-        let res: sqlx::Result<Vec<Message>> = async {
-            let mut tx: Transaction<'_, Sqlite> = pool.begin().await?;
-            let now = std::time::SystemTime::now()
-                .duration_since(std::time::UNIX_EPOCH)
-                .unwrap()
-                .as_millis() as i64;
-            let ids: Vec<i64> = sqlx::query_scalar(
-                "SELECT m.id
-                 FROM message m
-                 WHERE m.queue_id = (SELECT id FROM queue WHERE name = ?)
-                   AND m.available_at <= ?
-                 ORDER BY m.available_at, m.id
-                 LIMIT ?",
-            )
-            .bind(queue_name)
-            .bind(now)
-            .bind(limit)
-            .fetch_all(&mut *tx)
-            .await?;
-
-            if ids.is_empty() {
-                tx.commit().await?;
-                return Ok(Vec::new());
-            }
 
-            let new_available = now + visibility_ms.max(0);
-            let placeholders =
-                std::iter::repeat_n("?", ids.len()).collect::<Vec<_>>().join(",");
-            let update_sql = format!(
-                "UPDATE message SET available_at = ? WHERE id IN ({})",
-                placeholders
-            );
-            let mut uq = sqlx::query(&update_sql).bind(new_available);
-            for id in &ids {
-                uq = uq.bind(id);
-            }
-            uq.execute(&mut *tx).await?;
+// A nacked message with its queue's backoff settings:
+// (id, attempts, base_ms, multiplier, max_ms, jitter)
+type BackoffRow = (i64, i32, i64, f64, Option<i64>, f64);
 
-            let select_sql = format!(
-                "SELECT id, queue_id, payload, attempts, available_at, created_at
-                 FROM message WHERE id IN ({}) ORDER BY available_at, id",
-                placeholders
-            );
-            let mut sq = sqlx::query_as::<_, Message>(&select_sql);
-            for id in &ids {
-                sq = sq.bind(id);
+// Ready messages up to which `sample_messages` shuffles them all; past it,
+// the sample is drawn by random id instead
+const SAMPLE_SHUFFLE_MAX: i64 = 10_000;
+
+// Random ids in `lo..=hi` from which `sample_messages` takes the next ready
+// message: three per message wanted, as some land on the same one
+fn sample_pivots(
+    lo: i64,
+    hi: i64,
+    n: i64,
+) -> Vec<i64> {
+    use rand::Rng;
+    let mut rng = rand::thread_rng();
+    (0..n * 3).map(|_| rng.gen_range(lo..=hi.max(lo))).collect()
+}
+
+// Fair keys a poll of a fair queue probes for ready messages at most, so a
+// queue with a great many tenants does not cost one query per tenant a poll
+const FAIR_MAX_KEYS: i64 = 1_000;
+
+// How a poll picks among a queue's ready messages
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum PollOrder {
+    // In the queue's `ordering`
+    Ready(ReadyOrder),
+    // Only the oldest live message of each FIFO group, in enqueue order
+    StrictFifo,
+    // One message from each fair key in turn, see `FairLanes`, each key's
+    // messages in the queue's `ordering`
+    Fair(ReadyOrder),
+}
+
+impl PollOrder {
+    // Strict FIFO wins over fair polling and `ordering`, as it already fixes
+    // the order
+    fn of(
+        strict_fifo: bool,
+        fair: bool,
+        ordering: &str,
+    ) -> Self {
+        let ready = ReadyOrder::of(ordering);
+        match (strict_fifo, fair) {
+            (true, _) => PollOrder::StrictFifo,
+            (false, true) => PollOrder::Fair(ready),
+            (false, false) => PollOrder::Ready(ready),
+        }
+    }
+
+    // The order of ready messages, which strict FIFO polls lease by id
+    fn ready(self) -> ReadyOrder {
+        match self {
+            PollOrder::Ready(ready) | PollOrder::Fair(ready) => ready,
+            PollOrder::StrictFifo => ReadyOrder::Fifo,
+        }
+    }
+}
+
+// The order a queue's `ordering` setting (`fifo`, `priority` or `sort_key`)
+// polls and peeks its ready messages in
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum ReadyOrder {
+    // Oldest first, regardless of priority
+    Fifo,
+    // Highest priority first, then oldest
+    Priority,
+    // Smallest sort key first, messages without one last, then oldest
+    SortKey,
+}
+
+impl ReadyOrder {
+    fn of(ordering: &str) -> Self {
+        match ordering {
+            "fifo" => ReadyOrder::Fifo,
+            "sort_key" => ReadyOrder::SortKey,
+            _ => ReadyOrder::Priority,
+        }
+    }
+
+    // ORDER BY terms over messages aliased `m`
+    fn sql(self) -> &'static str {
+        match self {
+            ReadyOrder::Fifo => "m.available_at, m.id",
+            ReadyOrder::Priority => "m.priority DESC, m.available_at, m.id",
+            ReadyOrder::SortKey => {
+                "m.sort_key NULLS LAST, m.available_at, m.id"
             }
-            let messages = sq.fetch_all(&mut *tx).await?;
-            tx.commit().await?;
-            Ok(messages)
         }
-        .await;
-
-        match res {
-            Ok(v) => return Ok(v),
-            Err(e) => {
-                let msg = format!("{e}");
-                if msg.contains("database is locked") && attempt < 200 {
-                    // brief backoff then retry
-                    let delay_ms = 5 * (attempt + 1).min(50);
-                    tokio::time::sleep(std::time::Duration::from_millis(delay_ms as u64)).await;
-                    attempt += 1;
-                    continue;
+    }
+
+    // ORDER BY terms of a consumer group's poll, whose messages become
+    // available per group, so they fall back on enqueue order
+    fn group_sql(self) -> &'static str {
+        match self {
+            ReadyOrder::Fifo => "m.id",
+            ReadyOrder::Priority => "m.priority DESC, m.id",
+            ReadyOrder::SortKey => "m.sort_key NULLS LAST, m.id",
+        }
+    }
+
+    // Put leased messages, which `UPDATE ... RETURNING` yields in no
+    // particular order, back in this order. Their `available_at` is already
+    // the lease expiry, so enqueue order stands in for age.
+    fn sort<T>(
+        self,
+        rows: &mut [T],
+        msg: impl Fn(&T) -> &Message,
+    ) {
+        rows.sort_by(|a, b| {
+            let (a, b) = (msg(a), msg(b));
+            let by = match self {
+                ReadyOrder::Fifo => std::cmp::Ordering::Equal,
+                ReadyOrder::Priority => b.priority.cmp(&a.priority),
+                ReadyOrder::SortKey => match (a.sort_key, b.sort_key) {
+                    (Some(x), Some(y)) => x.total_cmp(&y),
+                    (x, y) => y.is_some().cmp(&x.is_some()),
+                },
+            };
+            by.then(a.id.cmp(&b.id))
+        });
+    }
+}
+
+// Ready message ids of the fair keys a fair poll visited, in turn order.
+// Unkeyed messages take their turn as the key `""`, ahead of every other key.
+struct FairLanes {
+    lanes: Vec<(String, Vec<i64>)>,
+    // Keys whose ready messages were all fetched
+    drained: Vec<bool>,
+}
+
+// A fair key a poll can fetch more ready ids from: its lane index, the key
+// and how many ids the lane already has
+type OpenLane = (usize, String, usize);
+
+impl FairLanes {
+    fn new() -> Self {
+        FairLanes { lanes: Vec::new(), drained: Vec::new() }
+    }
+
+    // Record the first ready messages of `key`, fetched up to `asked`
+    fn push(
+        &mut self,
+        key: String,
+        ids: Vec<i64>,
+        asked: usize,
+    ) {
+        if !ids.is_empty() {
+            self.drained.push(ids.len() < asked);
+            self.lanes.push((key, ids));
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.lanes.len()
+    }
+
+    // How many more ids to fetch from each undrained key to fill `limit`,
+    // with the keys still to fetch from
+    fn refill(
+        &self,
+        limit: usize,
+    ) -> Option<(usize, Vec<OpenLane>)> {
+        let have: usize = self.lanes.iter().map(|(_, ids)| ids.len()).sum();
+        let open: Vec<_> = self
+            .lanes
+            .iter()
+            .enumerate()
+            .filter(|&(i, _)| !self.drained[i])
+            .map(|(i, (key, ids))| (i, key.clone(), ids.len()))
+            .collect();
+        if have >= limit || open.is_empty() {
+            return None;
+        }
+        Some(((limit - have).div_ceil(open.len()), open))
+    }
+
+    // Add ids fetched by `refill` to lane `i`
+    fn extend(
+        &mut self,
+        i: usize,
+        ids: Vec<i64>,
+        asked: usize,
+    ) {
+        self.drained[i] = ids.len() < asked;
+        self.lanes[i].1.extend(ids);
+    }
+
+    // Take one id from each key in turn until `limit` are taken. Returns the
+    // ids in lease order and the key that got the last one, which the next
+    // poll starts after.
+    fn interleave(
+        &self,
+        limit: usize,
+    ) -> (Vec<i64>, Option<String>) {
+        let mut picked = Vec::with_capacity(limit);
+        let mut last = None;
+        for round in 0.. {
+            let mut any = false;
+            for (key, ids) in &self.lanes {
+                if picked.len() == limit {
+                    return (picked, last);
+                }
+                if let Some(&id) = ids.get(round) {
+                    picked.push(id);
+                    last = Some(key.clone());
+                    any = true;
                 }
-                return Err(e);
+            }
+            if !any {
+                break;
             }
         }
+        (picked, last)
     }
 }
 
-/// Count ready messages (available and not leased or lease expired)
-pub async fn count_ready_messages(
-    pool: &SqlitePool,
-    queue_id: i64,
-    now_ms: i64,
-) -> sqlx::Result<i64> {
-    let count: i64 = sqlx::query_scalar(
-        "SELECT COUNT(*) FROM message
-         WHERE queue_id = ?
-           AND available_at <= ?",
+// A message whose lease was reaped, as returned by the backends'
+// `reap_leases`: its id, queue name, trace id, lease expirations so far,
+// whether it was dead-lettered and whether that was a quarantine
+type ReapedRow = (i64, String, Option<String>, i32, bool, bool);
+
+// `last_error` of a message quarantined after `n` lease expirations, as SQL
+// concatenating the count (an expression) into the reason
+fn quarantine_reason_sql(n: &str) -> String {
+    format!(
+        "'quarantined: lease expired ' || ({n}) || ' times without an ack or nack'"
     )
-    .bind(queue_id)
-    .bind(now_ms)
-    .fetch_one(pool)
-    .await?;
-    Ok(count)
-}
-
-/// Count queued messages in a queue
-pub async fn count_queued_messages_by_queue(
-    pool: &SqlitePool,
-    queue_id: i64,
-) -> sqlx::Result<i64> {
-    let count: i64 =
-        sqlx::query_scalar("SELECT COUNT(*) FROM message WHERE queue_id = ?")
-            .bind(queue_id)
-            .fetch_one(pool)
-            .await?;
-    Ok(count)
-}
-
-/// Run VACUUM to compact the database
-pub async fn compact_db(pool: &SqlitePool) -> sqlx::Result<()> {
-    sqlx::query("VACUUM").execute(pool).await?;
-    Ok(())
-}
-// The initial schema is embedded via the migrations directory SQL
-
-/// Initialize the SQLite connection pool.
-pub async fn init_pool() -> anyhow::Result<SqlitePool> {
-    let current_dir =
-        env::current_dir().context("Failed to get current directory")?;
-    let db_file = current_dir.join("sqew.db");
-    init_pool_at(&db_file).await
-}
-
-/// Initialize the SQLite connection pool at a specific path.
-pub async fn init_pool_at(path: &Path) -> anyhow::Result<SqlitePool> {
-    let db_url = format!("sqlite://{}", path.to_string_lossy());
-    // Configure SQLite for better concurrency under load
-    let connect_opts = SqliteConnectOptions::from_str(&db_url)
-        .context("Invalid SQLite URL")?
-        .journal_mode(SqliteJournalMode::Wal)
-        .busy_timeout(std::time::Duration::from_secs(5))
-        .synchronous(sqlx::sqlite::SqliteSynchronous::Normal);
-    let pool = SqlitePoolOptions::new()
-        .max_connections(32)
-        .connect_with(connect_opts)
-        .await
-        .context("Failed to connect to the database")?;
-    // Set WAL autocheckpoint to a reasonable value
-    sqlx::query("PRAGMA wal_autocheckpoint = 1000;")
-        .execute(&pool)
-        .await
-        .ok();
-    Ok(pool)
-}
-
-/// Create the database file (if missing) and run initial migrations.
-pub async fn create_db_if_needed() -> anyhow::Result<()> {
-    let current_dir =
-        env::current_dir().context("Failed to get current directory")?;
-    let db_file = current_dir.join("sqew.db");
-    create_db_if_needed_at(&db_file, false).await
-}
-
-/// Create the database file at the given path (if missing) and run initial schema.
-/// If `force_recreate` is true, delete any existing file first.
-pub async fn create_db_if_needed_at(
-    path: &Path,
-    force_recreate: bool,
-) -> anyhow::Result<()> {
-    let mut is_new = false;
-    if force_recreate {
-        if path.exists() {
-            fs::remove_file(path).with_context(|| {
-                format!("Failed to delete DB at {}", path.display())
-            })?;
+}
+
+// Report the messages of `reaped` that were quarantined; returns their ids
+fn report_quarantined(reaped: &[ReapedRow]) -> Vec<i64> {
+    let mut ids = Vec::new();
+    for (id, queue, trace_id, expirations, _, quarantined) in reaped {
+        if *quarantined {
+            tracing::warn!(
+                message_id = id,
+                queue = %queue,
+                trace_id = trace_id.as_deref().unwrap_or_default(),
+                lease_expirations = expirations,
+                "quarantined message: its lease kept expiring without an ack or nack"
+            );
+            ids.push(*id);
         }
-        is_new = true;
     }
-    if !path.exists() {
-        fs::File::create(path).with_context(|| {
-            format!("Failed to create DB file at {}", path.display())
-        })?;
-        is_new = true;
+    ids
+}
+
+// Retry delay after a message's `attempts`-th failed delivery under a queue's
+// exponential backoff: `base * multiplier^(attempts - 1)`, capped at `max_ms`,
+// with up to a `jitter` fraction of it randomly removed
+fn backoff_delay(
+    attempts: i32,
+    base_ms: i64,
+    multiplier: f64,
+    max_ms: Option<i64>,
+    jitter: f64,
+) -> i64 {
+    use rand::Rng;
+    let exp = (attempts - 1).clamp(0, 1_000);
+    let mut delay = base_ms.max(0) as f64 * multiplier.max(1.0).powi(exp);
+    if let Some(max) = max_ms {
+        delay = delay.min(max.max(0) as f64);
+    }
+    // Keep far-future delays from overflowing `available_at`
+    delay = delay.min((i64::MAX / 4) as f64);
+    let jitter = jitter.clamp(0.0, 1.0);
+    if jitter > 0.0 {
+        delay *= 1.0 - jitter * rand::thread_rng().r#gen::<f64>();
     }
-    if is_new {
-        let db_url = format!("sqlite://{}", path.to_string_lossy());
-        let pool = SqlitePool::connect(&db_url)
-            .await
-            .context("Failed to connect to the database for initialization")?;
-        pool.execute(INIT_SQL)
-            .await
-            .context("Failed to execute initial database schema")?;
+    delay as i64
+}
+
+/// Filters and paging for [`Storage::peek_messages`]; the default lists from
+/// the head of the queue
+#[derive(Debug, Clone, Default)]
+pub struct PeekFilter {
+    /// Skip this many matching messages
+    pub offset: i64,
+    /// Cursor: only messages with a greater id, listed in id order
+    pub after_id: Option<i64>,
+    /// Only messages created at or after this time
+    pub created_after: Option<i64>,
+    /// Only messages created before this time
+    pub created_before: Option<i64>,
+    /// Only messages whose header `key` equals `value`
+    pub header: Option<(String, String)>,
+    /// Only messages whose raw payload contains this substring
+    pub payload_contains: Option<String>,
+    /// Only messages whose payload value at a JSON path (e.g. `$.user.id`)
+    /// equals the given text
+    pub json_path: Option<(String, String)>,
+    /// Only messages in this delivery state
+    pub state: MessageState,
+}
+
+/// Messages of a queue to delete in bulk with
+/// [`Storage::remove_matching_messages`]; every given condition must hold
+#[derive(Debug, Clone, Default)]
+pub struct RemoveFilter {
+    /// Only messages whose payload value at a JSON path (e.g. `$.type`)
+    /// equals the given text
+    pub json_path: Option<(String, String)>,
+    /// Only messages created before this time
+    pub created_before: Option<i64>,
+}
+
+/// Audit log entries to list with [`Storage::list_audit_entries`]; every
+/// given condition must hold
+#[derive(Debug, Clone, Default)]
+pub struct AuditFilter {
+    pub action: Option<String>,
+    pub queue: Option<String>,
+    pub actor: Option<String>,
+    /// Only entries recorded at or after this time
+    pub since: Option<i64>,
+}
+
+/// Where a live message stands in delivery, as told apart by its lease
+/// token and `available_at`: a message is leased while a lease token is set
+/// and the lease has not run out, and delayed while it is invisible without
+/// one (scheduled, or backing off after a nack)
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Eq,
+    serde::Serialize,
+    serde::Deserialize,
+    clap::ValueEnum,
+    utoipa::ToSchema,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum MessageState {
+    /// Visible to the next poll, including leases that ran out and have not
+    /// been reaped yet
+    Ready,
+    /// Not visible yet and not leased
+    Delayed,
+    /// Held by a consumer under an unexpired lease
+    Leased,
+    /// Any of the above
+    #[default]
+    All,
+}
+
+impl MessageState {
+    // WHERE condition over messages aliased `m`, as of `now_ms`
+    fn sql(
+        self,
+        now_ms: i64,
+    ) -> String {
+        match self {
+            MessageState::Ready => format!("m.available_at <= {now_ms}"),
+            MessageState::Delayed => {
+                format!("m.lease_token IS NULL AND m.available_at > {now_ms}")
+            }
+            MessageState::Leased => format!(
+                "m.lease_token IS NOT NULL AND m.available_at > {now_ms}"
+            ),
+            MessageState::All => "1 = 1".to_string(),
+        }
+    }
+}
+
+/// Per-queue aggregates reported by [`Storage::queue_metrics`]
+#[derive(Debug, Clone, Default, sqlx::FromRow)]
+pub struct QueueMetrics {
+    /// Messages under an unexpired lease
+    pub leased: i64,
+    /// Live messages not yet visible (delayed or backing off after a nack)
+    pub delayed: i64,
+    /// Creation time of the oldest ready message, if any
+    pub oldest_ready_at: Option<i64>,
+    /// Messages ever enqueued into the queue
+    pub enqueued: i64,
+    /// Messages ever acked (each consumer group's ack counts)
+    pub acked: i64,
+    /// Sum of the enqueue-to-ack times of the acked messages
+    pub ack_latency_ms_total: i64,
+}
+
+/// Findings of [`Storage::doctor`]. With `fixed` set, the counts are of the
+/// problems that were found and then repaired.
+#[derive(Debug, Clone, Default, serde::Serialize)]
+pub struct DoctorReport {
+    /// Problems reported by the backend's own integrity check; these cannot
+    /// be fixed by sqew
+    pub integrity_errors: Vec<String>,
+    /// Messages, archived messages, schedules, alarms, push configs and
+    /// deliveries, consumer groups and group deliveries referencing a queue (or
+    /// group or message) that no longer exists
+    pub orphaned_rows: u64,
+    /// Leases held on dead letters, and expired leases not yet reaped
+    pub stuck_leases: u64,
+    /// Messages created in the future or before the epoch, or made visible,
+    /// dead-lettered or expiring before they were created
+    pub impossible_timestamps: u64,
+    /// Queues whose live or dead message counters disagree with their rows
+    pub drifted_counters: u64,
+    pub fixed: bool,
+}
+
+impl DoctorReport {
+    /// Total number of problems found
+    pub fn problems(&self) -> u64 {
+        self.integrity_errors.len() as u64
+            + self.orphaned_rows
+            + self.stuck_leases
+            + self.impossible_timestamps
+            + self.drifted_counters
+    }
+}
+
+/// How far ahead a lease must be held, without an open attempt recording
+/// it, for [`Storage::recovery_scan`] to report it stranded (one hour)
+pub const STRANDED_LEASE_MS: i64 = 3_600_000;
+
+/// Size from which [`Storage::recovery_scan`] reports SQLite's write-ahead
+/// log oversized (256 MiB)
+pub const WAL_OVERSIZED_BYTES: i64 = 256 * 1024 * 1024;
+
+/// Anomalies a crash may have left behind, found by
+/// [`Storage::recovery_scan`] when the server starts. With `repaired` set,
+/// they were found and then repaired where possible.
+#[derive(Debug, Clone, Default, serde::Serialize)]
+pub struct RecoveryReport {
+    /// Leases held more than [`STRANDED_LEASE_MS`] ahead that no open
+    /// attempt records, as when a server stopped between leasing messages
+    /// and logging their delivery
+    pub stranded_leases: u64,
+    /// Recorded schema versions with gaps, or newer than this build knows;
+    /// these cannot be repaired by sqew
+    pub schema_problems: Vec<String>,
+    /// Size of SQLite's write-ahead log; `None` without one
+    pub wal_bytes: Option<i64>,
+    /// Whether the log reached [`WAL_OVERSIZED_BYTES`], as it does when
+    /// checkpoints stop keeping up
+    pub wal_oversized: bool,
+    pub repaired: bool,
+}
+
+impl RecoveryReport {
+    /// Total number of anomalies found
+    pub fn anomalies(&self) -> u64 {
+        self.stranded_leases
+            + self.schema_problems.len() as u64
+            + u64::from(self.wal_oversized)
+    }
+}
+
+// Problems with the schema versions recorded in `versions` (ascending):
+// versions skipped on the way to the highest, and versions beyond `latest`
+fn schema_problems(
+    versions: &[i64],
+    latest: i64,
+) -> Vec<String> {
+    let mut problems = Vec::new();
+    let mut expected = 1;
+    for &version in versions {
+        if version > expected {
+            problems.push(format!(
+                "schema versions {expected} to {} are not recorded",
+                version - 1
+            ));
+        }
+        if version > latest {
+            problems.push(format!(
+                "schema version {version} is newer than this build's {latest}"
+            ));
+        }
+        expected = version + 1;
+    }
+    problems
+}
+
+/// Storage use reported by [`Storage::db_status`]
+#[derive(Debug, Clone, Default, serde::Serialize, utoipa::ToSchema)]
+pub struct DbStatus {
+    /// Size of the database, in bytes
+    pub file_bytes: i64,
+    /// Size of SQLite's write-ahead log; `None` without one
+    pub wal_bytes: Option<i64>,
+    pub page_size: i64,
+    pub page_count: i64,
+    /// Pages no longer in use, which compacting returns to the filesystem
+    pub free_pages: i64,
+    /// Bytes taken by each table with its indexes, largest first
+    pub tables: Vec<TableUsage>,
+    /// Rows of each queue and an estimate of the bytes they take, by name
+    pub queues: Vec<QueueUsage>,
+    /// Whether enough space is free for compacting to be worthwhile
+    pub compact_recommended: bool,
+    /// Automatic compaction of a server configured with `[auto_compact]`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub auto_compact: Option<AutoCompactStatus>,
+}
+
+/// Settings and last run of a server's automatic compaction
+#[derive(Debug, Clone, serde::Serialize, utoipa::ToSchema)]
+pub struct AutoCompactStatus {
+    /// Cron expression (UTC) of the minutes compaction may start in
+    pub window: String,
+    /// `incremental` or `full`
+    pub mode: String,
+    /// Free pages from which the database is compacted
+    pub min_free_pages: i64,
+    /// Whether the current minute is in the window
+    pub in_window: bool,
+    pub last_run: Option<CompactRun>,
+}
+
+/// An automatic compaction
+#[derive(Debug, Clone, PartialEq, serde::Serialize, utoipa::ToSchema)]
+pub struct CompactRun {
+    /// `incremental` or `full`
+    pub mode: String,
+    /// When it started, in milliseconds since the Unix epoch
+    pub started_at: i64,
+    pub duration_ms: i64,
+    pub free_pages_before: i64,
+    pub free_pages_after: i64,
+}
+
+/// Bytes taken by a table and its indexes
+#[derive(Debug, Clone, serde::Serialize, sqlx::FromRow, utoipa::ToSchema)]
+pub struct TableUsage {
+    pub name: String,
+    pub bytes: i64,
+}
+
+/// Rows held by a queue
+#[derive(Debug, Clone, serde::Serialize, sqlx::FromRow, utoipa::ToSchema)]
+pub struct QueueUsage {
+    pub name: String,
+    /// Live messages
+    pub messages: i64,
+    pub dead_letters: i64,
+    /// Acked messages kept in the archive
+    pub archived: i64,
+    /// The queue's share, by rows, of the message and archive tables
+    #[sqlx(default)]
+    pub estimated_bytes: i64,
+}
+
+// Rows of each queue for `db_status`; the message counts come from the
+// queue row's counters
+const QUEUE_USAGE_SQL: &str = "SELECT q.name, q.message_count AS messages,
+       q.dead_count AS dead_letters,
+       (SELECT COUNT(*) FROM message_archive a WHERE a.queue_id = q.id)
+         AS archived
+     FROM queue q ORDER BY q.name";
+
+// Tables checked for orphaned rows by `doctor`, with the condition selecting
+// them, in the order they are deleted
+const ORPHAN_CHECKS: &[(&str, &str)] = &[
+    ("message", "queue_id NOT IN (SELECT id FROM queue)"),
+    ("message_archive", "queue_id NOT IN (SELECT id FROM queue)"),
+    ("schedule", "queue_id NOT IN (SELECT id FROM queue)"),
+    ("alarm", "queue_id NOT IN (SELECT id FROM queue)"),
+    ("push_config", "queue_id NOT IN (SELECT id FROM queue)"),
+    ("push_delivery", "queue_id NOT IN (SELECT id FROM queue)"),
+    ("message_attempt", "queue_id NOT IN (SELECT id FROM queue)"),
+    ("consumer_group", "queue_id NOT IN (SELECT id FROM queue)"),
+    (
+        "group_delivery",
+        "consumer_group_id NOT IN (SELECT id FROM consumer_group)
+         OR message_id NOT IN (SELECT id FROM message)",
+    ),
+];
+
+// Move timestamps a message cannot have had before its creation time up to
+// it, for `doctor --fix`
+const CLAMP_TIMESTAMPS_SQL: &str = "UPDATE message SET
+       available_at = CASE WHEN available_at < created_at
+                           THEN created_at ELSE available_at END,
+       dead_at = CASE WHEN dead_at < created_at THEN created_at ELSE dead_at END,
+       expires_at = CASE WHEN expires_at < created_at
+                         THEN created_at ELSE expires_at END
+     WHERE available_at < created_at OR dead_at < created_at
+        OR expires_at < created_at";
+
+// Queues whose message counters disagree with their rows, for `doctor`
+const DRIFTED_COUNTERS: &str = "message_count <> (
+         SELECT COUNT(*) FROM message
+         WHERE message.queue_id = queue.id AND dead_at IS NULL)
+     OR dead_count <> (
+         SELECT COUNT(*) FROM message
+         WHERE message.queue_id = queue.id AND dead_at IS NOT NULL)";
+
+// Recompute every queue's message counters from its rows, for
+// `doctor --fix`
+const RECOUNT_SQL: &str = "UPDATE queue SET
+       message_count = (SELECT COUNT(*) FROM message
+                        WHERE message.queue_id = queue.id AND dead_at IS NULL),
+       dead_count = (SELECT COUNT(*) FROM message
+                     WHERE message.queue_id = queue.id
+                       AND dead_at IS NOT NULL)";
+
+/// Shared handle to the configured storage backend
+pub type Db = Arc<dyn Storage>;
+
+/// How long a caller refused with [`SqewError::Busy`](crate::error::SqewError::Busy)
+/// is asked to wait before trying again
+pub const BUSY_RETRY_AFTER: Duration = Duration::from_secs(1);
+
+// Times `retry_busy` runs an operation again after lock contention
+const BUSY_RETRIES: u32 = 20;
+
+// Longest pause between two tries of `retry_busy`, before jitter
+const BUSY_BACKOFF_MAX_MS: u64 = 200;
+
+/// Whether a database error is transient lock contention: SQLite's
+/// `SQLITE_BUSY` or `SQLITE_LOCKED` (and their extended codes), or a
+/// Postgres serialization failure, deadlock or lock timeout. The operation
+/// failed as a whole and may be tried again.
+pub fn is_busy(e: &sqlx::Error) -> bool {
+    let sqlx::Error::Database(db) = e else {
+        return false;
+    };
+    match db.code().as_deref() {
+        Some("40001" | "40P01" | "55P03") => true,
+        Some(code) => {
+            code.parse::<i32>().is_ok_and(|c| matches!(c & 0xff, 5 | 6))
+        }
+        None => false,
     }
-    Ok(())
 }
 
-/// Nack: increment attempts, set available_at forward; drop if attempts >= max_attempts.
-pub async fn nack_messages(
-    pool: &SqlitePool,
-    ids: &[i64],
-    delay_ms: i64,
-) -> sqlx::Result<(u64, u64)> {
-    if ids.is_empty() {
-        return Ok((0, 0));
+// Run `op`, trying it again with a growing, jittered pause while it fails
+// with lock contention, up to `BUSY_RETRIES` times. Only for operations
+// that are safe to repeat: a failed try must have changed nothing, and one
+// that did take effect must not be taken twice.
+pub(crate) async fn retry_busy<T, F, Fut>(mut op: F) -> sqlx::Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = sqlx::Result<T>>,
+{
+    use rand::Rng;
+    let mut attempt = 0;
+    loop {
+        match op().await {
+            Err(e) if attempt < BUSY_RETRIES && is_busy(&e) => {
+                attempt += 1;
+                let ceiling = (5u64 << attempt.min(6)).min(BUSY_BACKOFF_MAX_MS);
+                let pause = rand::thread_rng().gen_range(ceiling / 2..=ceiling);
+                tokio::time::sleep(Duration::from_millis(pause)).await;
+            }
+            res => return res,
+        }
     }
-    let mut tx: Transaction<'_, Sqlite> = pool.begin().await?;
-    let now = std::time::SystemTime::now()
+}
+
+// Current wall-clock time in milliseconds since the Unix epoch
+pub(crate) fn now_ms() -> i64 {
+    std::time::SystemTime::now()
         .duration_since(std::time::UNIX_EPOCH)
         .unwrap()
-        .as_millis() as i64;
-    let new_available = now + delay_ms.max(0);
-    let placeholders =
-        std::iter::repeat("?").take(ids.len()).collect::<Vec<_>>().join(",");
-
-    // Update attempts and visibility
-    let update_sql = format!(
-        "UPDATE message SET attempts = attempts + 1, available_at = ? WHERE id IN ({})",
-        placeholders
-    );
-    let mut uq = sqlx::query(&update_sql).bind(new_available);
-    for id in ids {
-        uq = uq.bind(id);
-    }
-    let updated = uq.execute(&mut *tx).await?.rows_affected();
-
-    // Drop messages exceeding max_attempts
-    let delete_sql = format!(
-        "DELETE FROM message
-         WHERE id IN (
-            SELECT m.id FROM message m
-            JOIN queue q ON q.id = m.queue_id
-            WHERE m.id IN ({}) AND m.attempts >= q.max_attempts
-         )",
-        placeholders
+        .as_millis() as i64
+}
+
+// A public message id for a message created at `created_at` (ms): a
+// version 7 UUID, so ids sort by creation time
+pub(crate) fn new_public_id(created_at: i64) -> String {
+    use uuid::{NoContext, Timestamp, Uuid};
+    let ms = created_at.max(0) as u64;
+    let ts = Timestamp::from_unix(
+        NoContext,
+        ms / 1000,
+        (ms % 1000) as u32 * 1_000_000,
     );
-    let mut dq = sqlx::query(&delete_sql);
-    for id in ids {
-        dq = dq.bind(id);
+    Uuid::new_v7(ts).to_string()
+}
+
+/// Persistence operations behind the queue service, implemented for SQLite
+/// ([`SqliteStorage`]) and Postgres ([`PgStorage`]).
+///
+/// Timestamps are milliseconds since the Unix epoch. A message is leased while
+/// it carries a `lease_token` and its `available_at` lies in the future.
+#[async_trait]
+pub trait Storage: Send + Sync {
+    /// Current schema version; opening a backend migrates it to the latest
+    async fn schema_version(&self) -> sqlx::Result<i64>;
+
+    /// Schema version this build migrates to
+    fn latest_schema_version(&self) -> i64;
+
+    /// The SQLite storage behind this backend, for embedding applications
+    /// that share the database file; `None` for other backends
+    fn as_sqlite(&self) -> Option<&SqliteStorage> {
+        None
     }
-    let dropped = dq.execute(&mut *tx).await?.rows_affected();
-
-    tx.commit().await?;
-    let requeued = updated.saturating_sub(dropped);
-    Ok((requeued, dropped))
-}
-
-/// Remove a message by ID
-pub async fn remove_message_by_id(
-    pool: &SqlitePool,
-    id: i64,
-) -> sqlx::Result<u64> {
-    let res = sqlx::query("DELETE FROM message WHERE id = ?")
-        .bind(id)
-        .execute(pool)
-        .await?;
-    Ok(res.rows_affected())
+
+    async fn get_queue_by_name(
+        &self,
+        name: &str,
+    ) -> sqlx::Result<Option<Queue>>;
+
+    /// Insert a queue row; the `id` field is ignored
+    async fn create_queue(
+        &self,
+        q: &Queue,
+    ) -> sqlx::Result<i64>;
+
+    /// List all queues
+    async fn list_queues(&self) -> sqlx::Result<Vec<Queue>>;
+
+    /// List the queues whose names match `pattern`, where `*` matches any
+    /// run of characters and everything else only itself
+    async fn list_queues_matching(
+        &self,
+        pattern: &str,
+    ) -> sqlx::Result<Vec<Queue>>;
+
+    /// Overwrite a queue's settings by `id`; the name cannot change.
+    /// Returns how many rows were affected.
+    async fn update_queue(
+        &self,
+        q: &Queue,
+    ) -> sqlx::Result<u64>;
+
+    /// In one transaction, create queue `target` with the settings of
+    /// `source` (unpaused) and, with `with_messages`, copies of the source's
+    /// live messages. Copies keep their payloads, attempts, priorities and
+    /// timestamps, but leased ones are copied unleased and visible. Returns
+    /// the new queue's id and how many messages were copied, or `None` if
+    /// `source` does not exist.
+    async fn clone_queue(
+        &self,
+        source: &str,
+        target: &str,
+        with_messages: bool,
+    ) -> sqlx::Result<Option<(i64, u64)>>;
+
+    /// Delete a queue by name, returning how many rows were affected
+    async fn delete_queue_by_name(
+        &self,
+        name: &str,
+    ) -> sqlx::Result<u64>;
+
+    /// Move a live queue to the trash as of `now`, hiding it from lookups
+    /// and listings; returns how many rows were affected
+    async fn trash_queue(
+        &self,
+        name: &str,
+        now: i64,
+    ) -> sqlx::Result<u64>;
+
+    /// Take a queue back out of the trash; returns how many rows were
+    /// affected
+    async fn restore_queue(
+        &self,
+        name: &str,
+    ) -> sqlx::Result<u64>;
+
+    /// The queues in the trash, by deletion time
+    async fn list_trashed_queues(&self) -> sqlx::Result<Vec<Queue>>;
+
+    /// Delete, with their messages, the queues trashed at or before
+    /// `deleted_before`; returns their names
+    async fn purge_trashed_queues(
+        &self,
+        deleted_before: i64,
+    ) -> sqlx::Result<Vec<String>>;
+
+    /// Insert a message row; the `id` field is ignored
+    async fn enqueue_message(
+        &self,
+        msg: &Message,
+    ) -> sqlx::Result<i64>;
+
+    /// Enqueue a message carrying a `dedup_key`. If a message with the same
+    /// key was enqueued into the queue within `window_ms` and still exists,
+    /// nothing is inserted and `(existing_id, false)` is returned; otherwise
+    /// the key is released from any older message and `(new_id, true)` is
+    /// returned.
+    async fn enqueue_message_dedup(
+        &self,
+        msg: &Message,
+        window_ms: i64,
+    ) -> sqlx::Result<(i64, bool)>;
+
+    async fn get_message_by_id(
+        &self,
+        id: i64,
+    ) -> sqlx::Result<Option<Message>>;
+
+    /// The integer ids of the messages with the given public ids, as
+    /// `(public_id, id)` pairs; unknown public ids are left out
+    async fn find_message_ids(
+        &self,
+        public_ids: &[String],
+    ) -> sqlx::Result<Vec<(String, i64)>>;
+
+    /// Every message of a queue with an id above `after_id`, including leased
+    /// and dead-lettered ones, in id order. Used to export a queue page by
+    /// page.
+    async fn export_messages(
+        &self,
+        queue_name: &str,
+        after_id: i64,
+        limit: i64,
+    ) -> sqlx::Result<Vec<Message>>;
+
+    /// Insert exported messages as-is (attempts, timestamps, dead-letter
+    /// state and headers) in one transaction. Messages whose dedup key is
+    /// already held in their queue are skipped; returns how many were
+    /// inserted.
+    async fn import_messages(
+        &self,
+        msgs: &[Message],
+    ) -> sqlx::Result<u64>;
+
+    /// Delete messages by IDs (ack). Only messages still leased under
+    /// `lease_token` are deleted; mismatched or expired leases are skipped.
+    /// Messages of queues with `retention_days` set are moved to the archive.
+    /// Returns the IDs deleted.
+    async fn ack_messages(
+        &self,
+        ids: &[i64],
+        lease_token: &str,
+    ) -> sqlx::Result<Vec<i64>>;
+
+    /// End the leases of messages still held under `lease_token` now, as if
+    /// their visibility timeout had passed: the next poll delivers them again
+    /// and acks under `lease_token` fail. Returns how many leases ended.
+    async fn expire_leases(
+        &self,
+        ids: &[i64],
+        lease_token: &str,
+    ) -> sqlx::Result<u64>;
+
+    /// Extend the lease of messages still held under `lease_token` by
+    /// `extra_ms`. Expired leases cannot be extended; returns how many leases
+    /// were extended.
+    async fn extend_visibility(
+        &self,
+        ids: &[i64],
+        lease_token: &str,
+        extra_ms: i64,
+    ) -> sqlx::Result<u64>;
+
+    /// Purge up to `limit` live messages of the given queue in one
+    /// transaction; dead letters are kept. Returns how many were deleted.
+    async fn purge_messages_by_queue(
+        &self,
+        queue_name: &str,
+        limit: i64,
+    ) -> sqlx::Result<u64>;
+
+    /// Delete up to `limit` live messages of the given queue matching
+    /// `filter` in one transaction; dead letters are kept. Returns how many
+    /// were deleted.
+    async fn remove_matching_messages(
+        &self,
+        queue_name: &str,
+        filter: &RemoveFilter,
+        limit: i64,
+    ) -> sqlx::Result<u64>;
+
+    /// Peek (list) unexpired messages in a queue without leasing, in delivery
+    /// order (highest priority first, then oldest), narrowed by `filter`
+    async fn peek_messages(
+        &self,
+        queue_name: &str,
+        limit: i64,
+        filter: &PeekFilter,
+    ) -> sqlx::Result<Vec<Message>>;
+
+    /// Search every message in a queue (leased, delayed and dead-lettered
+    /// included) whose payload has a value at JSON `path`, equal to `value`
+    /// when given, paging in id order after `after_id`
+    async fn search_messages(
+        &self,
+        queue_name: &str,
+        path: &str,
+        value: Option<&str>,
+        after_id: Option<i64>,
+        limit: i64,
+    ) -> sqlx::Result<Vec<Message>>;
+
+    /// Poll (lease) up to `limit` messages: select ready (highest priority
+    /// first), set available_at forward and stamp a fresh lease token, return
+    /// messages. A grouped message is only eligible while it is the oldest
+    /// live message of its group. Queues with consumer groups are only
+    /// consumed through [`Storage::poll_group_messages`], so this returns
+    /// nothing for them.
+    async fn poll_messages(
+        &self,
+        queue_name: &str,
+        limit: i64,
+        visibility_ms: i64,
+    ) -> sqlx::Result<Vec<Message>>;
+
+    /// A random sample of up to `n` ready messages of a queue, in no
+    /// particular order. Small queues are shuffled whole; larger ones take
+    /// the first ready message at or after random ids, which favours
+    /// messages that follow gaps in the ids.
+    async fn sample_messages(
+        &self,
+        queue_id: i64,
+        n: i64,
+        now_ms: i64,
+    ) -> sqlx::Result<Vec<Message>>;
+
+    /// Count ready messages (available, unexpired, and not leased or lease
+    /// expired)
+    async fn count_ready_messages(
+        &self,
+        queue_id: i64,
+        now_ms: i64,
+    ) -> sqlx::Result<i64>;
+
+    /// Creation time of the oldest live (not dead-lettered, unexpired)
+    /// message in a queue, leased or not
+    async fn oldest_message_created_at(
+        &self,
+        queue_id: i64,
+        now_ms: i64,
+    ) -> sqlx::Result<Option<i64>>;
+
+    /// Count queued messages in a queue
+    async fn count_queued_messages_by_queue(
+        &self,
+        queue_id: i64,
+    ) -> sqlx::Result<i64>;
+
+    /// Delete live messages whose TTL passed before `now_ms`, adding them to
+    /// each queue's `expired_count`. Messages under an active lease are left
+    /// for their consumer; returns how many were deleted.
+    async fn expire_messages(
+        &self,
+        now_ms: i64,
+    ) -> sqlx::Result<u64>;
+
+    /// Check the database for corruption and inconsistent rows left by crashes
+    /// or manual edits, repairing what can be repaired when `fix` is set:
+    /// orphaned rows are deleted, stuck leases released (expired ones are
+    /// reaped as usual) and impossible timestamps clamped.
+    async fn doctor(
+        &self,
+        fix: bool,
+        now_ms: i64,
+    ) -> sqlx::Result<DoctorReport>;
+
+    /// Look for anomalies a crash may have left: stranded leases, gaps in
+    /// the schema versions and an oversized write-ahead log. With `repair`,
+    /// stranded leases are released and the log checkpointed.
+    async fn recovery_scan(
+        &self,
+        repair: bool,
+        now_ms: i64,
+    ) -> sqlx::Result<RecoveryReport>;
+
+    /// Report the size of the database, the space free in it and the rows
+    /// held by each queue. `estimated_bytes` and `compact_recommended` are
+    /// left for the caller to fill in.
+    async fn db_status(&self) -> sqlx::Result<DbStatus>;
+
+    /// Release leases that expired before `now_ms` without an ack or nack,
+    /// counting each as a failed attempt: the message is requeued, or
+    /// dead-lettered once attempts reach the queue's `max_attempts` or is
+    /// quarantined (dead-lettered with a warning) once its lease expirations
+    /// reach the queue's `max_lease_expirations`.
+    /// Consumer group deliveries are reaped per group. Polls reap their own
+    /// queue first; returns `(requeued, dead_lettered)`.
+    async fn reap_expired_leases(
+        &self,
+        now_ms: i64,
+    ) -> sqlx::Result<(u64, u64)>;
+
+    /// Total messages in a queue removed because their TTL passed
+    async fn count_expired_messages(
+        &self,
+        queue_id: i64,
+    ) -> sqlx::Result<i64>;
+
+    /// Leased and delayed counts, the oldest ready message and the enqueue
+    /// and ack counters of a queue
+    async fn queue_metrics(
+        &self,
+        queue_id: i64,
+        now_ms: i64,
+    ) -> sqlx::Result<QueueMetrics>;
+
+    /// Count dead-lettered messages in a queue
+    async fn count_dead_messages(
+        &self,
+        queue_id: i64,
+    ) -> sqlx::Result<i64>;
+
+    /// Reclaim free space (VACUUM)
+    async fn compact(&self) -> sqlx::Result<()>;
+
+    /// Return free pages to the filesystem without rebuilding the database
+    /// where the backend can. A SQLite database not yet set up for
+    /// incremental vacuum is switched to it, which takes one full VACUUM.
+    async fn compact_incremental(&self) -> sqlx::Result<()>;
+
+    /// Compress stored payloads (live and archived) that exceed the
+    /// backend's compression threshold but are still stored plain, e.g.
+    /// because they were written before compression was enabled. Returns
+    /// how many were compressed; backends that compress on their own return 0.
+    async fn recompress_payloads(&self) -> sqlx::Result<u64>;
+
+    /// Re-encrypt stored payloads (live and archived) that are not yet
+    /// encrypted with the active encryption key: plain ones, and those
+    /// written under a retired key. Returns how many were rewritten. Fails
+    /// when no encryption key is configured.
+    async fn rotate_payload_key(&self) -> sqlx::Result<u64>;
+
+    /// Write a consistent snapshot of the whole database to a new file at
+    /// `path` while it stays online. Only SQLite supports this.
+    async fn backup(
+        &self,
+        path: &Path,
+    ) -> sqlx::Result<()>;
+
+    /// Nack: increment attempts, set available_at forward; dead-letter if
+    /// attempts >= max_attempts. `nacks` pairs each message id with the delay
+    /// before it becomes visible again. Only messages still leased under
+    /// `lease_token` are affected. Messages of queues with backoff configured
+    /// are delayed by the queue's backoff for their attempt count instead of
+    /// the requested delay. A `reason` (the consumer's error) is kept as
+    /// the messages' `last_error`. Returns the IDs `(requeued,
+    /// dead_lettered)`.
+    async fn nack_messages(
+        &self,
+        nacks: &[(i64, i64)],
+        lease_token: &str,
+        reason: Option<&str>,
+    ) -> sqlx::Result<(Vec<i64>, Vec<i64>)>;
+
+    /// Remove a message by ID
+    async fn remove_message_by_id(
+        &self,
+        id: i64,
+    ) -> sqlx::Result<u64>;
+
+    /// List dead-lettered messages in a queue, oldest first
+    async fn list_dead_letters(
+        &self,
+        queue_name: &str,
+        limit: i64,
+    ) -> sqlx::Result<Vec<Message>>;
+
+    /// Redrive dead letters back into their queue with attempts reset.
+    /// An empty `ids` slice redrives every dead letter in the queue.
+    async fn redrive_dead_letters(
+        &self,
+        queue_name: &str,
+        ids: &[i64],
+    ) -> sqlx::Result<u64>;
+
+    /// Move messages by IDs into `target_queue` in one statement, optionally
+    /// only those currently in `from_queue`. Moved messages are live and
+    /// visible immediately (dead letters are revived) and lose their dedup
+    /// key; `reset_attempts` zeroes their attempt counts. Messages under an
+    /// active lease are skipped. Returns how many were moved.
+    async fn move_messages(
+        &self,
+        ids: &[i64],
+        target_queue: &str,
+        from_queue: Option<&str>,
+        reset_attempts: bool,
+    ) -> sqlx::Result<u64>;
+
+    /// Insert a consumer group; the `id` field is ignored and `start_id` is
+    /// set to the newest message ID, so the group receives messages enqueued
+    /// from now on
+    async fn create_consumer_group(
+        &self,
+        g: &ConsumerGroup,
+    ) -> sqlx::Result<i64>;
+
+    /// List the consumer groups of a queue
+    async fn list_consumer_groups(
+        &self,
+        queue_name: &str,
+    ) -> sqlx::Result<Vec<ConsumerGroup>>;
+
+    /// Delete a consumer group and its delivery state, then delete messages
+    /// the remaining groups have all finished with. Returns how many groups
+    /// were deleted.
+    async fn delete_consumer_group(
+        &self,
+        queue_name: &str,
+        group: &str,
+    ) -> sqlx::Result<u64>;
+
+    /// Poll (lease) up to `limit` messages for a consumer group: messages
+    /// after the group's cursor that the group has not acked or
+    /// dead-lettered and does not hold leased. Returned messages carry the
+    /// group's lease token and attempt count.
+    async fn poll_group_messages(
+        &self,
+        queue_name: &str,
+        group: &str,
+        limit: i64,
+        visibility_ms: i64,
+    ) -> sqlx::Result<Vec<Message>>;
+
+    /// Ack messages leased to a consumer group under `lease_token`. A
+    /// message is deleted once every group has acked or dead-lettered it.
+    /// Returns the IDs of the messages acked.
+    async fn ack_group_messages(
+        &self,
+        ids: &[i64],
+        lease_token: &str,
+    ) -> sqlx::Result<Vec<i64>>;
+
+    /// Nack messages leased to a consumer group under `lease_token`:
+    /// increment the group's attempts and make each visible to the group
+    /// again after its delay in `nacks` (`(id, delay_ms)` pairs), or
+    /// dead-letter them for the group once attempts reach the queue's
+    /// `max_attempts`. Returns the IDs `(requeued, dead_lettered)`.
+    async fn nack_group_messages(
+        &self,
+        nacks: &[(i64, i64)],
+        lease_token: &str,
+    ) -> sqlx::Result<(Vec<i64>, Vec<i64>)>;
+
+    /// Extend consumer group leases still held under `lease_token` by
+    /// `extra_ms`, returning how many were extended
+    async fn extend_group_visibility(
+        &self,
+        ids: &[i64],
+        lease_token: &str,
+        extra_ms: i64,
+    ) -> sqlx::Result<u64>;
+
+    /// Delete all dead-lettered messages in a queue
+    async fn purge_dead_letters(
+        &self,
+        queue_name: &str,
+    ) -> sqlx::Result<u64>;
+
+    /// List archived (acked) messages in a queue, most recently acked first
+    async fn list_archived_messages(
+        &self,
+        queue_name: &str,
+        limit: i64,
+    ) -> sqlx::Result<Vec<ArchivedMessage>>;
+
+    /// Re-enqueue a queue's archived messages acked in `[from_ms, to_ms)`
+    /// (either bound optional) whose raw payload contains
+    /// `payload_contains`, as new messages visible now with no attempts.
+    /// The archive keeps its copies. Returns how many were enqueued.
+    async fn replay_archived_messages(
+        &self,
+        queue_name: &str,
+        from_ms: Option<i64>,
+        to_ms: Option<i64>,
+        payload_contains: Option<&str>,
+    ) -> sqlx::Result<u64>;
+
+    /// Delete archived messages whose retention ended before `now_ms`
+    async fn purge_archived_messages(
+        &self,
+        now_ms: i64,
+    ) -> sqlx::Result<u64>;
+
+    /// Snapshot every queue's depth and counters as of `now_ms`, returning
+    /// how many queues were recorded
+    async fn record_stats_history(
+        &self,
+        now_ms: i64,
+    ) -> sqlx::Result<u64>;
+
+    /// Snapshots of a queue recorded at or after `since_ms`, oldest first
+    async fn list_stats_history(
+        &self,
+        queue_name: &str,
+        since_ms: i64,
+    ) -> sqlx::Result<Vec<StatsSample>>;
+
+    /// Delete snapshots recorded before `before_ms`
+    async fn purge_stats_history(
+        &self,
+        before_ms: i64,
+    ) -> sqlx::Result<u64>;
+
+    /// Insert a schedule row; the `id` field is ignored
+    async fn create_schedule(
+        &self,
+        s: &Schedule,
+    ) -> sqlx::Result<i64>;
+
+    /// List schedules, optionally restricted to one queue
+    async fn list_schedules(
+        &self,
+        queue_name: Option<&str>,
+    ) -> sqlx::Result<Vec<Schedule>>;
+
+    /// Delete a schedule by id. Returns true if a schedule was deleted
+    async fn delete_schedule(
+        &self,
+        id: i64,
+    ) -> sqlx::Result<bool>;
+
+    /// Schedules whose next run time has been reached
+    async fn due_schedules(
+        &self,
+        now_ms: i64,
+    ) -> sqlx::Result<Vec<Schedule>>;
+
+    /// Fire a due schedule: advance it from `prev_run_at` to `next_run_at`
+    /// and enqueue `msg` in one transaction. Returns false without enqueueing
+    /// if the schedule was already advanced (e.g. by another server).
+    async fn fire_schedule(
+        &self,
+        id: i64,
+        prev_run_at: i64,
+        next_run_at: i64,
+        msg: &Message,
+    ) -> sqlx::Result<bool>;
+
+    /// Insert an alarm row; the `id` field is ignored
+    async fn create_alarm(
+        &self,
+        a: &Alarm,
+    ) -> sqlx::Result<i64>;
+
+    /// List alarms, optionally restricted to one queue
+    async fn list_alarms(
+        &self,
+        queue_name: Option<&str>,
+    ) -> sqlx::Result<Vec<Alarm>>;
+
+    /// Delete an alarm by id. Returns true if an alarm was deleted
+    async fn delete_alarm(
+        &self,
+        id: i64,
+    ) -> sqlx::Result<bool>;
+
+    /// Record the evaluation state of an alarm
+    async fn set_alarm_state(
+        &self,
+        id: i64,
+        breached_since: Option<i64>,
+        firing: bool,
+    ) -> sqlx::Result<()>;
+
+    /// Insert an API key row; the `id` field is ignored
+    async fn create_api_key(
+        &self,
+        key: &ApiKey,
+    ) -> sqlx::Result<i64>;
+
+    /// List API keys by name
+    async fn list_api_keys(&self) -> sqlx::Result<Vec<ApiKey>>;
+
+    /// Delete an API key by name. Returns true if a key was deleted
+    async fn delete_api_key(
+        &self,
+        name: &str,
+    ) -> sqlx::Result<bool>;
+
+    /// Insert or replace the push config of a queue, keeping its
+    /// `created_at` when replacing. Returns the stored row.
+    async fn set_push_config(
+        &self,
+        cfg: &PushConfig,
+    ) -> sqlx::Result<PushConfig>;
+
+    async fn get_push_config(
+        &self,
+        queue_id: i64,
+    ) -> sqlx::Result<Option<PushConfig>>;
+
+    /// Push configs of every queue
+    async fn list_push_configs(&self) -> sqlx::Result<Vec<PushConfig>>;
+
+    /// Delete the push config of a queue. Returns true if one was deleted
+    async fn delete_push_config(
+        &self,
+        queue_id: i64,
+    ) -> sqlx::Result<bool>;
+
+    /// Insert push delivery log rows; the `id` fields are ignored
+    async fn record_push_deliveries(
+        &self,
+        log: &[PushDelivery],
+    ) -> sqlx::Result<()>;
+
+    /// Up to `limit` push deliveries of a queue, newest first
+    async fn list_push_deliveries(
+        &self,
+        queue_id: i64,
+        limit: i64,
+    ) -> sqlx::Result<Vec<PushDelivery>>;
+
+    /// Delete push deliveries logged before `before_ms`
+    async fn purge_push_deliveries(
+        &self,
+        before_ms: i64,
+    ) -> sqlx::Result<u64>;
+
+    /// Log the deliveries of messages just leased by a poll. Earlier
+    /// attempts of the messages still unsettled are marked `lease_expired`.
+    async fn record_attempts(
+        &self,
+        leased: &[Message],
+        consumer: Option<&str>,
+        now_ms: i64,
+    ) -> sqlx::Result<()>;
+
+    /// Record the outcome (and the consumer's note) of the attempts that
+    /// leased messages `ids` under `lease_token`
+    async fn settle_attempts(
+        &self,
+        ids: &[i64],
+        lease_token: &str,
+        outcome: &str,
+        note: Option<&str>,
+        now_ms: i64,
+    ) -> sqlx::Result<()>;
+
+    /// The logged attempts of a message, oldest first
+    async fn list_attempts(
+        &self,
+        message_id: i64,
+    ) -> sqlx::Result<Vec<MessageAttempt>>;
+
+    /// Delete attempts leased before `before_ms`
+    async fn purge_attempts(
+        &self,
+        before_ms: i64,
+    ) -> sqlx::Result<u64>;
+
+    /// Messages of a queue leased by a plain poll at `now_ms`, with the
+    /// consumer and lease time from their current attempt; soonest lease
+    /// expiry first
+    async fn list_in_flight(
+        &self,
+        queue_name: &str,
+        now_ms: i64,
+        limit: i64,
+    ) -> sqlx::Result<Vec<InFlightMessage>>;
+
+    /// Insert a pending admin job from its `kind`, `queue`, `path`,
+    /// `batch_size` and `created_at`; returns the stored row
+    async fn create_admin_job(
+        &self,
+        job: &AdminJob,
+    ) -> sqlx::Result<AdminJob>;
+
+    async fn get_admin_job(
+        &self,
+        id: i64,
+    ) -> sqlx::Result<Option<AdminJob>>;
+
+    /// Up to `limit` admin jobs, newest first
+    async fn list_admin_jobs(
+        &self,
+        limit: i64,
+    ) -> sqlx::Result<Vec<AdminJob>>;
+
+    /// Mark the oldest pending admin job running as of `now` and return it
+    async fn claim_admin_job(
+        &self,
+        now: i64,
+    ) -> sqlx::Result<Option<AdminJob>>;
+
+    /// Record how far a running admin job has got; returns whether it has
+    /// been asked to cancel
+    async fn set_admin_job_progress(
+        &self,
+        id: i64,
+        progress: i64,
+    ) -> sqlx::Result<bool>;
+
+    /// Mark an admin job `done`, `failed` or `canceled` as of `now`
+    async fn finish_admin_job(
+        &self,
+        id: i64,
+        status: &str,
+        error: Option<&str>,
+        now: i64,
+    ) -> sqlx::Result<()>;
+
+    /// Cancel a pending admin job as of `now`, or ask a running one to stop
+    /// at its next batch; finished jobs are left as they are. Returns the
+    /// job as it now stands.
+    async fn cancel_admin_job(
+        &self,
+        id: i64,
+        now: i64,
+    ) -> sqlx::Result<Option<AdminJob>>;
+
+    /// Mark the admin jobs still running as failed with `error` as of
+    /// `now`; returns how many
+    async fn fail_running_admin_jobs(
+        &self,
+        error: &str,
+        now: i64,
+    ) -> sqlx::Result<u64>;
+
+    /// Delete admin jobs that finished before `before_ms`
+    async fn purge_admin_jobs(
+        &self,
+        before_ms: i64,
+    ) -> sqlx::Result<u64>;
+
+    /// Append an entry to the audit log from its `at`, `actor`, `action`,
+    /// `queue` and `params`
+    async fn insert_audit_entry(
+        &self,
+        entry: &AuditEntry,
+    ) -> sqlx::Result<()>;
+
+    /// Up to `limit` audit log entries matching `filter`, newest first
+    async fn list_audit_entries(
+        &self,
+        filter: &AuditFilter,
+        limit: i64,
+    ) -> sqlx::Result<Vec<AuditEntry>>;
+
+    /// Record `schema` (`None` once removed) as the next version of a
+    /// queue's payload schema as of `now`, bumping the queue's
+    /// `payload_schema_version`; returns the new version
+    async fn record_queue_schema(
+        &self,
+        queue_id: i64,
+        schema: Option<&serde_json::Value>,
+        now: i64,
+    ) -> sqlx::Result<i64>;
+
+    /// In one transaction, overwrite a queue's settings as `update_queue`
+    /// does and record its new payload schema as the next version as of
+    /// `now`; returns the new version
+    async fn update_queue_and_schema(
+        &self,
+        q: &Queue,
+        now: i64,
+    ) -> sqlx::Result<i64>;
+
+    /// A version of a queue's payload schema; the latest for `None`
+    async fn get_queue_schema(
+        &self,
+        queue_id: i64,
+        version: Option<i64>,
+    ) -> sqlx::Result<Option<QueueSchema>>;
+
+    /// Every recorded version of a queue's payload schema, newest first
+    async fn list_queue_schemas(
+        &self,
+        queue_id: i64,
+    ) -> sqlx::Result<Vec<QueueSchema>>;
 }
@@ -0,0 +1,3223 @@
+use super::{
+    AuditFilter, BackoffRow, CLAMP_TIMESTAMPS_SQL, DAY_MS, DONE_BY_ALL_GROUPS,
+    DRIFTED_COUNTERS, DbStatus, DoctorReport, FAIR_MAX_KEYS, FairLanes,
+    ORPHAN_CHECKS, PeekFilter, PollOrder, PoolOptions, QUEUE_USAGE_SQL,
+    QueueMetrics, RECOUNT_SQL, ReadyOrder, ReapedRow, RecoveryReport,
+    RemoveFilter, SAMPLE_SHUFFLE_MAX, STRANDED_LEASE_MS, Storage,
+    backoff_delay, now_ms, quarantine_reason_sql, rate_tokens,
+    report_quarantined, sample_pivots, schema_problems,
+};
+use crate::models::{
+    AdminJob, Alarm, ApiKey, ArchivedMessage, AuditEntry, ConsumerGroup,
+    InFlightMessage, Message, MessageAttempt, PushConfig, PushDelivery, Queue,
+    QueueSchema, Schedule, StatsSample,
+};
+use anyhow::Context;
+use async_trait::async_trait;
+use sqlx::postgres::{PgConnectOptions, PgPool, PgPoolOptions};
+use sqlx::types::Json;
+use sqlx::{Executor, PgConnection, Postgres, Transaction};
+use std::path::Path;
+use std::str::FromStr;
+
+// Versioned schema migrations: applying `MIGRATIONS[i]` brings the schema to
+// version `i + 1`. Released migrations must never change; append new ones.
+const MIGRATIONS: &[&str] = &[
+    // 1: initial schema, mirroring SQLite schema version 2
+    r#"
+CREATE TABLE queue (
+  id            BIGSERIAL PRIMARY KEY,
+  name          TEXT UNIQUE NOT NULL,
+  max_attempts  INTEGER NOT NULL DEFAULT 5,
+  expired_count BIGINT NOT NULL DEFAULT 0,
+  dedup_window_ms BIGINT NOT NULL DEFAULT 300000,
+  retention_days INTEGER,
+  backoff_base_ms BIGINT,
+  backoff_multiplier DOUBLE PRECISION NOT NULL DEFAULT 2.0,
+  backoff_max_ms  BIGINT,
+  backoff_jitter  DOUBLE PRECISION NOT NULL DEFAULT 0.0
+);
+
+CREATE TABLE message (
+  id               BIGSERIAL PRIMARY KEY,
+  queue_id         BIGINT NOT NULL REFERENCES queue(id) ON DELETE CASCADE,
+  payload          TEXT NOT NULL,
+  attempts         INTEGER NOT NULL DEFAULT 0,
+  available_at     BIGINT NOT NULL,
+  created_at       BIGINT NOT NULL,
+  dead_at          BIGINT,
+  lease_token      TEXT,
+  priority         INTEGER NOT NULL DEFAULT 0,
+  expires_at       BIGINT,
+  dedup_key        TEXT,
+  group_id         TEXT
+);
+
+CREATE INDEX ix_msg_visible ON message(queue_id, available_at);
+CREATE INDEX ix_msg_priority ON message(queue_id, priority DESC, available_at);
+CREATE INDEX ix_msg_expires ON message(expires_at) WHERE expires_at IS NOT NULL;
+CREATE UNIQUE INDEX ux_msg_dedup ON message(queue_id, dedup_key) WHERE dedup_key IS NOT NULL;
+CREATE INDEX ix_msg_dead ON message(queue_id, dead_at);
+CREATE INDEX ix_msg_group ON message(queue_id, group_id, id) WHERE group_id IS NOT NULL;
+
+CREATE TABLE schedule (
+  id               BIGSERIAL PRIMARY KEY,
+  queue_id         BIGINT NOT NULL REFERENCES queue(id) ON DELETE CASCADE,
+  cron             TEXT NOT NULL,
+  payload          TEXT NOT NULL,
+  next_run_at      BIGINT NOT NULL,
+  created_at       BIGINT NOT NULL
+);
+
+CREATE INDEX ix_schedule_due ON schedule(next_run_at);
+
+CREATE TABLE message_archive (
+  id               BIGSERIAL PRIMARY KEY,
+  message_id       BIGINT NOT NULL,
+  queue_id         BIGINT NOT NULL REFERENCES queue(id) ON DELETE CASCADE,
+  payload          TEXT NOT NULL,
+  attempts         INTEGER NOT NULL,
+  priority         INTEGER NOT NULL,
+  group_id         TEXT,
+  created_at       BIGINT NOT NULL,
+  acked_at         BIGINT NOT NULL,
+  purge_at         BIGINT NOT NULL
+);
+
+CREATE INDEX ix_archive_queue ON message_archive(queue_id, acked_at);
+CREATE INDEX ix_archive_purge ON message_archive(purge_at);
+"#,
+    // 2: message headers (JSON object)
+    "ALTER TABLE message ADD COLUMN headers JSONB;",
+    // 3: per-queue defaults for omitted visibility timeouts and delays
+    r#"
+ALTER TABLE queue ADD COLUMN default_visibility_ms BIGINT NOT NULL DEFAULT 30000;
+ALTER TABLE queue ADD COLUMN default_delay_ms BIGINT NOT NULL DEFAULT 0;
+"#,
+    // 4: consumer groups and their per-message delivery state
+    r#"
+CREATE TABLE consumer_group (
+  id               BIGSERIAL PRIMARY KEY,
+  queue_id         BIGINT NOT NULL REFERENCES queue(id) ON DELETE CASCADE,
+  name             TEXT NOT NULL,
+  start_id         BIGINT NOT NULL,
+  created_at       BIGINT NOT NULL,
+  UNIQUE (queue_id, name)
+);
+
+CREATE TABLE group_delivery (
+  consumer_group_id BIGINT NOT NULL REFERENCES consumer_group(id) ON DELETE CASCADE,
+  message_id        BIGINT NOT NULL REFERENCES message(id) ON DELETE CASCADE,
+  attempts          INTEGER NOT NULL DEFAULT 0,
+  available_at      BIGINT NOT NULL,
+  lease_token       TEXT,
+  acked_at          BIGINT,
+  dead_at           BIGINT,
+  PRIMARY KEY (consumer_group_id, message_id)
+);
+
+CREATE INDEX ix_delivery_message ON group_delivery(message_id);
+CREATE INDEX ix_delivery_lease ON group_delivery(lease_token) WHERE lease_token IS NOT NULL;
+"#,
+    // 5: per-queue delivery rate limit and its token bucket
+    r#"
+ALTER TABLE queue ADD COLUMN max_deliveries_per_second DOUBLE PRECISION;
+ALTER TABLE queue ADD COLUMN rate_tokens DOUBLE PRECISION;
+ALTER TABLE queue ADD COLUMN rate_updated_at BIGINT;
+"#,
+    // 6: trace id correlating a message's lifecycle in logs
+    r#"
+ALTER TABLE message ADD COLUMN trace_id TEXT;
+"#,
+    // 7: queue depth and age alarms delivered to webhooks
+    r#"
+CREATE TABLE alarm (
+  id               BIGSERIAL PRIMARY KEY,
+  queue_id         BIGINT NOT NULL REFERENCES queue(id) ON DELETE CASCADE,
+  metric           TEXT NOT NULL,
+  threshold        BIGINT NOT NULL,
+  for_ms           BIGINT NOT NULL,
+  webhook_url      TEXT NOT NULL,
+  breached_since   BIGINT,
+  firing           BOOLEAN NOT NULL DEFAULT FALSE,
+  created_at       BIGINT NOT NULL
+);
+"#,
+    // 8: enqueue and ack counters for stats
+    r#"
+ALTER TABLE queue ADD COLUMN enqueued_count BIGINT NOT NULL DEFAULT 0;
+ALTER TABLE queue ADD COLUMN acked_count BIGINT NOT NULL DEFAULT 0;
+ALTER TABLE queue ADD COLUMN ack_latency_ms_total BIGINT NOT NULL DEFAULT 0;
+UPDATE queue SET enqueued_count = (
+  SELECT COUNT(*) FROM message WHERE message.queue_id = queue.id
+);
+CREATE OR REPLACE FUNCTION count_enqueued() RETURNS trigger AS $$
+BEGIN
+  UPDATE queue SET enqueued_count = enqueued_count + 1 WHERE id = NEW.queue_id;
+  RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+CREATE TRIGGER message_enqueued AFTER INSERT ON message
+  FOR EACH ROW EXECUTE FUNCTION count_enqueued();
+"#,
+    // 9: per-queue payload size limit and JSON Schema
+    r#"
+ALTER TABLE queue ADD COLUMN max_payload_bytes BIGINT;
+ALTER TABLE queue ADD COLUMN payload_schema JSONB;
+"#,
+    // 10: paused queues
+    r#"
+ALTER TABLE queue ADD COLUMN paused BOOLEAN NOT NULL DEFAULT FALSE;
+"#,
+    // 11: strict FIFO queues
+    r#"
+ALTER TABLE queue ADD COLUMN strict_fifo BOOLEAN NOT NULL DEFAULT FALSE;
+"#,
+    // 12: per-minute snapshots of queue depth and counters
+    r#"
+CREATE TABLE queue_stats_history (
+  id               BIGSERIAL PRIMARY KEY,
+  queue_id         BIGINT NOT NULL REFERENCES queue(id) ON DELETE CASCADE,
+  recorded_at      BIGINT NOT NULL,
+  ready            BIGINT NOT NULL,
+  leased           BIGINT NOT NULL,
+  delayed          BIGINT NOT NULL,
+  dlq              BIGINT NOT NULL,
+  enqueued         BIGINT NOT NULL,
+  acked            BIGINT NOT NULL
+);
+
+CREATE INDEX ix_stats_history_queue ON queue_stats_history(queue_id, recorded_at);
+CREATE INDEX ix_stats_history_recorded ON queue_stats_history(recorded_at);
+"#,
+    // 13: push delivery of messages to HTTP endpoints, and its delivery log
+    r#"
+CREATE TABLE push_config (
+  queue_id         BIGINT PRIMARY KEY REFERENCES queue(id) ON DELETE CASCADE,
+  url              TEXT NOT NULL,
+  concurrency      INTEGER NOT NULL,
+  timeout_ms       BIGINT NOT NULL,
+  backoff_ms       BIGINT NOT NULL,
+  created_at       BIGINT NOT NULL,
+  updated_at       BIGINT NOT NULL
+);
+
+CREATE TABLE push_delivery (
+  id               BIGSERIAL PRIMARY KEY,
+  queue_id         BIGINT NOT NULL REFERENCES queue(id) ON DELETE CASCADE,
+  message_id       BIGINT NOT NULL,
+  attempt          INTEGER NOT NULL,
+  status_code      INTEGER,
+  error            TEXT,
+  outcome          TEXT NOT NULL,
+  duration_ms      BIGINT NOT NULL,
+  delivered_at     BIGINT NOT NULL
+);
+
+CREATE INDEX ix_push_delivery_queue ON push_delivery(queue_id, id);
+CREATE INDEX ix_push_delivery_delivered ON push_delivery(delivered_at);
+"#,
+    // 14: per-queue cap on unacked messages
+    r#"
+ALTER TABLE queue ADD COLUMN max_depth BIGINT;
+"#,
+    // 15: log of each message delivery and how it ended
+    r#"
+CREATE TABLE message_attempt (
+  id               BIGSERIAL PRIMARY KEY,
+  message_id       BIGINT NOT NULL,
+  queue_id         BIGINT NOT NULL REFERENCES queue(id) ON DELETE CASCADE,
+  attempt          INTEGER NOT NULL,
+  lease_token      TEXT NOT NULL,
+  leased_at        BIGINT NOT NULL,
+  consumer         TEXT,
+  outcome          TEXT,
+  note             TEXT,
+  settled_at       BIGINT
+);
+
+CREATE INDEX ix_attempt_message ON message_attempt(message_id, id);
+CREATE INDEX ix_attempt_leased ON message_attempt(leased_at);
+"#,
+    // 16: why a message was last nacked
+    r#"
+ALTER TABLE message ADD COLUMN last_error TEXT;
+"#,
+    // 17: live and dead message counters, kept by a trigger
+    r#"
+ALTER TABLE queue ADD COLUMN message_count BIGINT NOT NULL DEFAULT 0;
+ALTER TABLE queue ADD COLUMN dead_count BIGINT NOT NULL DEFAULT 0;
+UPDATE queue SET
+  message_count = (SELECT COUNT(*) FROM message
+                   WHERE message.queue_id = queue.id AND dead_at IS NULL),
+  dead_count = (SELECT COUNT(*) FROM message
+                WHERE message.queue_id = queue.id AND dead_at IS NOT NULL);
+CREATE OR REPLACE FUNCTION count_messages() RETURNS trigger AS $$
+BEGIN
+  IF TG_OP IN ('DELETE', 'UPDATE') THEN
+    UPDATE queue
+    SET message_count = message_count - (OLD.dead_at IS NULL)::INT,
+        dead_count = dead_count - (OLD.dead_at IS NOT NULL)::INT
+    WHERE id = OLD.queue_id;
+  END IF;
+  IF TG_OP IN ('INSERT', 'UPDATE') THEN
+    UPDATE queue
+    SET message_count = message_count + (NEW.dead_at IS NULL)::INT,
+        dead_count = dead_count + (NEW.dead_at IS NOT NULL)::INT
+    WHERE id = NEW.queue_id;
+  END IF;
+  RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+CREATE TRIGGER message_counted AFTER INSERT OR DELETE ON message
+  FOR EACH ROW EXECUTE FUNCTION count_messages();
+CREATE TRIGGER message_recounted AFTER UPDATE OF queue_id, dead_at ON message
+  FOR EACH ROW
+  WHEN (OLD.queue_id IS DISTINCT FROM NEW.queue_id
+        OR (OLD.dead_at IS NULL) IS DISTINCT FROM (NEW.dead_at IS NULL))
+  EXECUTE FUNCTION count_messages();
+"#,
+    // 18: API keys managed from the CLI, stored as SHA-256 hashes
+    r#"
+CREATE TABLE api_key (
+  id               BIGSERIAL PRIMARY KEY,
+  name             TEXT NOT NULL UNIQUE,
+  key_hash         TEXT NOT NULL UNIQUE,
+  role             TEXT NOT NULL,
+  queue_pattern    TEXT,
+  created_at       BIGINT NOT NULL
+);
+"#,
+    // 19: fair polling across the messages' tenant keys
+    r#"
+ALTER TABLE message ADD COLUMN fair_key TEXT;
+ALTER TABLE queue ADD COLUMN fair BOOLEAN NOT NULL DEFAULT FALSE;
+ALTER TABLE queue ADD COLUMN fair_cursor TEXT;
+CREATE INDEX ix_msg_fair ON message(queue_id, fair_key, priority DESC, available_at) WHERE fair_key IS NOT NULL;
+"#,
+    // 20: quarantine of messages whose leases keep expiring
+    r#"
+ALTER TABLE message ADD COLUMN lease_expirations INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE queue ADD COLUMN max_lease_expirations INTEGER;
+"#,
+    // 21: soft-deleted queues kept in the trash until purged
+    r#"
+ALTER TABLE queue ADD COLUMN deleted_at BIGINT;
+"#,
+    // 22: background admin jobs
+    r#"
+CREATE TABLE admin_job (
+  id               BIGSERIAL PRIMARY KEY,
+  kind             TEXT NOT NULL,
+  queue            TEXT,
+  path             TEXT,
+  batch_size       BIGINT NOT NULL,
+  status           TEXT NOT NULL,
+  progress         BIGINT NOT NULL DEFAULT 0,
+  error            TEXT,
+  cancel_requested BOOLEAN NOT NULL DEFAULT FALSE,
+  created_at       BIGINT NOT NULL,
+  started_at       BIGINT,
+  finished_at      BIGINT
+);
+CREATE INDEX ix_admin_job_status ON admin_job(status, id);
+"#,
+    // 23: ordering ready messages by FIFO, priority or a sort key
+    r#"
+ALTER TABLE message ADD COLUMN sort_key DOUBLE PRECISION;
+ALTER TABLE queue ADD COLUMN ordering TEXT NOT NULL DEFAULT 'priority';
+CREATE INDEX ix_msg_sort_key ON message(queue_id, sort_key, available_at) WHERE sort_key IS NOT NULL;
+"#,
+    // 24: delivery receipts sent to a reply queue
+    r#"
+ALTER TABLE message ADD COLUMN reply_to TEXT;
+"#,
+    // 25: public message ids, time-ordered UUIDs (version 7) from the
+    // creation time. Enqueues set them; the trigger covers rows copied by
+    // SQL, such as receipts, clones and replays, and existing rows.
+    r#"
+CREATE OR REPLACE FUNCTION new_public_id(created_at BIGINT) RETURNS TEXT AS $$
+DECLARE
+  r TEXT := gen_random_uuid()::text;
+BEGIN
+  RETURN lpad(to_hex(created_at >> 16), 8, '0') || '-'
+      || lpad(to_hex(created_at & 65535), 4, '0') || '-7'
+      || substr(r, 16, 3) || '-' || substr(r, 20);
+END;
+$$ LANGUAGE plpgsql;
+CREATE OR REPLACE FUNCTION set_public_id() RETURNS trigger AS $$
+BEGIN
+  IF NEW.public_id IS NULL THEN
+    NEW.public_id := new_public_id(NEW.created_at);
+  END IF;
+  RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+ALTER TABLE message ADD COLUMN public_id TEXT;
+UPDATE message SET public_id = new_public_id(created_at);
+CREATE UNIQUE INDEX ux_msg_public_id ON message(public_id);
+CREATE TRIGGER message_public_id BEFORE INSERT ON message
+  FOR EACH ROW EXECUTE FUNCTION set_public_id();
+"#,
+    // 26: append-only audit log of administrative actions
+    r#"
+CREATE TABLE audit_log (
+  id               BIGSERIAL PRIMARY KEY,
+  at               BIGINT NOT NULL,
+  actor            TEXT NOT NULL,
+  action           TEXT NOT NULL,
+  queue            TEXT,
+  params           JSONB
+);
+CREATE INDEX ix_audit_log_at ON audit_log(at);
+CREATE OR REPLACE FUNCTION audit_log_append_only() RETURNS trigger AS $$
+BEGIN
+  RAISE EXCEPTION 'audit_log is append-only';
+END;
+$$ LANGUAGE plpgsql;
+CREATE TRIGGER audit_log_append_only BEFORE UPDATE OR DELETE ON audit_log
+  FOR EACH ROW EXECUTE FUNCTION audit_log_append_only();
+"#,
+    // 27: versioned payload schemas, and tagging payloads that break them
+    r#"
+ALTER TABLE queue ADD COLUMN schema_mode TEXT NOT NULL DEFAULT 'reject';
+ALTER TABLE queue ADD COLUMN payload_schema_version BIGINT NOT NULL DEFAULT 0;
+CREATE TABLE queue_schema (
+  queue_id         BIGINT NOT NULL REFERENCES queue(id) ON DELETE CASCADE,
+  version          BIGINT NOT NULL,
+  payload_schema   JSONB,
+  created_at       BIGINT NOT NULL,
+  PRIMARY KEY (queue_id, version)
+);
+INSERT INTO queue_schema (queue_id, version, payload_schema, created_at)
+  SELECT id, 1, payload_schema, (EXTRACT(EPOCH FROM now()) * 1000)::BIGINT
+  FROM queue WHERE payload_schema IS NOT NULL;
+UPDATE queue SET payload_schema_version = 1 WHERE payload_schema IS NOT NULL;
+"#,
+];
+
+// Tables dropped (in dependency order) when recreating the schema
+const DROP_SQL: &str = "DROP TABLE IF EXISTS schema_version, queue_schema, audit_log, admin_job, api_key, message_attempt, push_delivery, push_config, queue_stats_history, alarm, group_delivery, consumer_group, message_archive, schedule, message, queue CASCADE";
+
+const QUEUE_COLUMNS: &str = "id, name, max_attempts, dedup_window_ms, \
+                             retention_days, backoff_base_ms, \
+                             backoff_multiplier, backoff_max_ms, \
+                             backoff_jitter, default_visibility_ms, \
+                             default_delay_ms, max_deliveries_per_second, \
+                             max_payload_bytes, payload_schema, \
+                             schema_mode, payload_schema_version, paused, \
+                             strict_fifo, fair, ordering, max_depth, \
+                             max_lease_expirations, deleted_at";
+
+// Columns selected whenever a full `Message` row is loaded. The lease token
+// is only handed out by poll, so other reads never expose it.
+const MESSAGE_COLUMNS: &str = "id, queue_id, payload, attempts, available_at, \
+                               created_at, dead_at, NULL::TEXT AS lease_token, \
+                               priority, expires_at, dedup_key, group_id, \
+                               headers, trace_id, last_error, fair_key, \
+                               lease_expirations, sort_key, reply_to, \
+                               public_id";
+const LEASED_MESSAGE_COLUMNS: &str = "id, queue_id, payload, attempts, \
+                                      available_at, created_at, dead_at, \
+                                      lease_token, priority, expires_at, \
+                                      dedup_key, group_id, headers, \
+                                      trace_id, last_error, fair_key, \
+                                      lease_expirations, sort_key, reply_to, \
+                                      public_id";
+
+// Columns of a message leased to a consumer group, from `message m` joined
+// with its `group_delivery gd` row
+const GROUP_MESSAGE_COLUMNS: &str = "m.id, m.queue_id, gd.attempts, \
+                                     gd.available_at, m.created_at, \
+                                     gd.dead_at, gd.lease_token, m.priority, \
+                                     m.expires_at, m.dedup_key, m.group_id, \
+                                     m.headers, m.trace_id, m.fair_key, \
+                                     m.sort_key, m.reply_to, m.public_id, \
+                                     m.payload";
+
+const SCHEDULE_COLUMNS: &str =
+    "id, queue_id, cron, payload, next_run_at, created_at";
+
+const ALARM_COLUMNS: &str = "id, queue_id, metric, threshold, for_ms, \
+                             webhook_url, breached_since, firing, created_at";
+const ADMIN_JOB_COLUMNS: &str = "id, kind, queue, path, batch_size, status, \
+                                 progress, error, cancel_requested, \
+                                 created_at, started_at, finished_at";
+const AUDIT_COLUMNS: &str = "id, at, actor, action, queue, params";
+const PUSH_CONFIG_COLUMNS: &str = "queue_id, url, concurrency, timeout_ms, \
+                                   backoff_ms, created_at, updated_at";
+const PUSH_DELIVERY_COLUMNS: &str = "id, queue_id, message_id, attempt, \
+                                     status_code, error, outcome, \
+                                     duration_ms, delivered_at";
+const ATTEMPT_COLUMNS: &str = "id, message_id, queue_id, attempt, leased_at, \
+                               consumer, outcome, note, settled_at";
+
+/// Postgres storage. Pollers lease rows with `FOR UPDATE SKIP LOCKED`, so
+/// concurrent consumers never block on or double-lease the same message.
+#[derive(Clone)]
+pub struct PgStorage {
+    pool: PgPool,
+}
+
+impl PgStorage {
+    /// Wrap an existing pool
+    pub fn new(pool: PgPool) -> Self {
+        PgStorage { pool }
+    }
+
+    /// Connect to `url` with a pool set up as `opts` says and create the
+    /// schema if it is missing. With `force_recreate`, existing sqew tables
+    /// are dropped first. `opts.read_connections` is ignored.
+    pub async fn connect(
+        url: &str,
+        force_recreate: bool,
+        opts: &PoolOptions,
+    ) -> anyhow::Result<Self> {
+        let connect_opts = PgConnectOptions::from_str(url)
+            .context("Invalid Postgres URL")?
+            .statement_cache_capacity(opts.statement_cache_size);
+        let pool = PgPoolOptions::new()
+            .max_connections(opts.max_connections.max(1))
+            .acquire_timeout(opts.acquire_timeout)
+            .connect_with(connect_opts)
+            .await
+            .context("Failed to connect to Postgres")?;
+        if force_recreate {
+            sqlx::raw_sql(DROP_SQL)
+                .execute(&pool)
+                .await
+                .context("Failed to drop existing schema")?;
+        }
+        migrate(&pool).await.context("Failed to migrate database schema")?;
+        Ok(Self::new(pool))
+    }
+
+    /// The underlying connection pool
+    pub fn pool(&self) -> &PgPool {
+        &self.pool
+    }
+}
+
+// Ready messages of queue `q` at `$1`: its live message counter less the
+// live messages not yet visible and the expired ones not yet swept. Both
+// are range scans over messages in flight, never the whole backlog.
+const READY_COUNT: &str = "(q.message_count
+   - (SELECT COUNT(*) FROM message
+      WHERE queue_id = q.id AND available_at > $1 AND dead_at IS NULL)
+   - (SELECT COUNT(*) FROM message
+      WHERE expires_at <= $1 AND queue_id = q.id
+        AND available_at <= $1 AND dead_at IS NULL))";
+
+// Arbitrary key for the advisory lock serialising concurrent migrations
+const MIGRATION_LOCK_KEY: i64 = 0x7371_6577;
+
+/// Apply pending schema migrations, returning the versions applied.
+/// Databases created before schema versioning are treated as version 1.
+pub async fn migrate(pool: &PgPool) -> sqlx::Result<Vec<i64>> {
+    let mut tx = pool.begin().await?;
+    sqlx::query("SELECT pg_advisory_xact_lock($1)")
+        .bind(MIGRATION_LOCK_KEY)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS schema_version (
+           version    BIGINT PRIMARY KEY,
+           applied_at BIGINT NOT NULL
+         )",
+    )
+    .execute(&mut *tx)
+    .await?;
+    let mut current: i64 = sqlx::query_scalar(
+        "SELECT COALESCE(MAX(version), 0) FROM schema_version",
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+    if current == 0 {
+        let legacy: bool =
+            sqlx::query_scalar("SELECT to_regclass('queue') IS NOT NULL")
+                .fetch_one(&mut *tx)
+                .await?;
+        if legacy {
+            current = 1;
+            record_version(&mut tx, current).await?;
+        }
+    }
+    let mut applied = Vec::new();
+    for (i, sql) in MIGRATIONS.iter().enumerate().skip(current as usize) {
+        let version = i as i64 + 1;
+        tx.execute(*sql).await?;
+        record_version(&mut tx, version).await?;
+        applied.push(version);
+    }
+    tx.commit().await?;
+    Ok(applied)
+}
+
+// Record that the schema reached `version`
+async fn record_version(
+    tx: &mut Transaction<'_, Postgres>,
+    version: i64,
+) -> sqlx::Result<()> {
+    sqlx::query(
+        "INSERT INTO schema_version (version, applied_at) VALUES ($1, $2)",
+    )
+    .bind(version)
+    .bind(now_ms())
+    .execute(&mut **tx)
+    .await?;
+    Ok(())
+}
+
+// Insert a message row on the given connection, returning its id
+async fn insert_message(
+    conn: &mut PgConnection,
+    msg: &Message,
+) -> sqlx::Result<i64> {
+    sqlx::query_scalar(
+        "INSERT INTO message (queue_id, payload, attempts, available_at, created_at, dead_at, priority, expires_at, dedup_key, group_id, headers, trace_id, fair_key, sort_key, reply_to, public_id)
+         VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16)
+         RETURNING id",
+    )
+    .bind(msg.queue_id)
+    .bind(&msg.payload)
+    .bind(msg.attempts)
+    .bind(msg.available_at)
+    .bind(msg.created_at)
+    .bind(msg.dead_at)
+    .bind(msg.priority)
+    .bind(msg.expires_at)
+    .bind(&msg.dedup_key)
+    .bind(&msg.group_id)
+    .bind(msg.headers.as_ref().map(Json))
+    .bind(&msg.trace_id)
+    .bind(&msg.fair_key)
+    .bind(msg.sort_key)
+    .bind(&msg.reply_to)
+    .bind((!msg.public_id.is_empty()).then_some(&msg.public_id))
+    .fetch_one(&mut *conn)
+    .await
+}
+
+// Split `(id, delay_ms)` nacks into the arrays bound for `unnest`, clamping
+// delays at zero
+fn split_nacks(nacks: &[(i64, i64)]) -> (Vec<i64>, Vec<i64>) {
+    nacks.iter().map(|&(id, delay_ms)| (id, delay_ms.max(0))).unzip()
+}
+
+// Lock message rows for the rest of the transaction, so concurrent acks of
+// the same message by different consumer groups see each other's writes
+// before deciding whether to delete it
+async fn lock_messages(
+    conn: &mut PgConnection,
+    ids: &[i64],
+) -> sqlx::Result<()> {
+    sqlx::query(
+        "SELECT id FROM message WHERE id = ANY($1) ORDER BY id FOR UPDATE",
+    )
+    .bind(ids)
+    .execute(&mut *conn)
+    .await?;
+    Ok(())
+}
+
+// Add the acks of messages `ids`, still present, to their queues' ack
+// counters
+async fn record_acks(
+    conn: &mut PgConnection,
+    ids: &[i64],
+    now: i64,
+) -> sqlx::Result<()> {
+    if ids.is_empty() {
+        return Ok(());
+    }
+    sqlx::query(
+        "UPDATE queue SET acked_count = acked_count + c.n,
+                          ack_latency_ms_total = ack_latency_ms_total + c.ms
+         FROM (SELECT queue_id, COUNT(*) AS n, SUM($1 - created_at)::BIGINT AS ms
+               FROM message WHERE id = ANY($2)
+               GROUP BY queue_id) AS c
+         WHERE queue.id = c.queue_id",
+    )
+    .bind(now)
+    .bind(ids)
+    .execute(&mut *conn)
+    .await?;
+    Ok(())
+}
+
+// Enqueue a receipt that the messages `ids`, still present, were
+// dead-lettered into the reply queue each names. Acks send theirs from
+// `ack_messages`.
+async fn send_dead_receipts(
+    conn: &mut PgConnection,
+    ids: &[i64],
+    now: i64,
+) -> sqlx::Result<()> {
+    if ids.is_empty() {
+        return Ok(());
+    }
+    sqlx::query(
+        "INSERT INTO message (queue_id, payload, attempts, available_at, created_at, trace_id)
+         SELECT rq.id,
+                json_build_object('message_id', m.id, 'queue', q.name,
+                                  'outcome', 'dead', 'attempts', m.attempts,
+                                  'enqueued_at', m.created_at,
+                                  'settled_at', $1::BIGINT)::TEXT,
+                0, $1, $1, m.trace_id
+         FROM message m JOIN queue q ON q.id = m.queue_id
+         JOIN queue rq ON rq.name = m.reply_to AND rq.deleted_at IS NULL
+         WHERE m.id = ANY($2)
+         ORDER BY m.id",
+    )
+    .bind(now)
+    .bind(ids)
+    .execute(&mut *conn)
+    .await?;
+    Ok(())
+}
+
+// Delete those of `ids` that every consumer group of their queue has acked or
+// dead-lettered
+async fn delete_finished(
+    conn: &mut PgConnection,
+    ids: &[i64],
+) -> sqlx::Result<()> {
+    if ids.is_empty() {
+        return Ok(());
+    }
+    let sql = format!(
+        "DELETE FROM message WHERE id = ANY($1) AND {DONE_BY_ALL_GROUPS}"
+    );
+    sqlx::query(&sql).bind(ids).execute(&mut *conn).await?;
+    Ok(())
+}
+
+// Cap a poll's `limit` by the queue's delivery rate limit; a paused queue
+// leases nothing. Returns the capped limit, the bucket's balance to charge
+// leased messages against (`None` when the queue is not rate-limited) and
+// how the poll orders the queue's messages. The queue row of a rate-limited
+// queue stays locked until the transaction ends, so concurrent polls take
+// turns.
+async fn rate_limit(
+    conn: &mut PgConnection,
+    queue_name: &str,
+    limit: i64,
+    now: i64,
+) -> sqlx::Result<(i64, Option<f64>, PollOrder)> {
+    let row: Option<(bool, Option<f64>, bool, bool, String)> = sqlx::query_as(
+        "SELECT paused OR deleted_at IS NOT NULL, max_deliveries_per_second,
+                strict_fifo, fair, ordering
+         FROM queue WHERE name = $1",
+    )
+    .bind(queue_name)
+    .fetch_optional(&mut *conn)
+    .await?;
+    let (rate, order) = match row {
+        Some((true, _, strict, fair, ordering)) => {
+            return Ok((0, None, PollOrder::of(strict, fair, &ordering)));
+        }
+        Some((false, Some(rate), strict, fair, ordering)) => {
+            (rate, PollOrder::of(strict, fair, &ordering))
+        }
+        Some((false, None, strict, fair, ordering)) => {
+            return Ok((limit, None, PollOrder::of(strict, fair, &ordering)));
+        }
+        None => {
+            return Ok((limit, None, PollOrder::Ready(ReadyOrder::Priority)));
+        }
+    };
+    let (tokens, updated_at): (Option<f64>, Option<i64>) = sqlx::query_as(
+        "SELECT rate_tokens, rate_updated_at FROM queue WHERE name = $1
+         FOR UPDATE",
+    )
+    .bind(queue_name)
+    .fetch_one(&mut *conn)
+    .await?;
+    let tokens = rate_tokens(rate, tokens, updated_at, now);
+    Ok((limit.min(tokens.floor() as i64), Some(tokens), order))
+}
+
+// How ready messages of `queue_name` are ordered; unknown queues fall back
+// to priority order
+async fn ready_order(
+    pool: &PgPool,
+    queue_name: &str,
+) -> sqlx::Result<ReadyOrder> {
+    let ordering: Option<String> =
+        sqlx::query_scalar("SELECT ordering FROM queue WHERE name = $1")
+            .bind(queue_name)
+            .fetch_optional(pool)
+            .await?;
+    Ok(ReadyOrder::of(ordering.as_deref().unwrap_or_default()))
+}
+
+// Ids of up to `limit` ready messages of a fair queue, one from each fair key
+// in turn, starting with the key after the one that got the last message of
+// the previous poll. Returns them in lease order with the key that got the
+// last one. The picked rows stay locked until the transaction ends.
+async fn fair_poll_ids(
+    conn: &mut PgConnection,
+    queue_name: &str,
+    limit: i64,
+    now: i64,
+    order: ReadyOrder,
+) -> sqlx::Result<(Vec<i64>, Option<String>)> {
+    let Some((queue_id, cursor)): Option<(i64, Option<String>)> =
+        sqlx::query_as("SELECT id, fair_cursor FROM queue WHERE name = $1")
+            .bind(queue_name)
+            .fetch_optional(&mut *conn)
+            .await?
+    else {
+        return Ok((Vec::new(), None));
+    };
+    // Keys after the cursor come first, then the rest from the start
+    let keys: Vec<String> = sqlx::query_scalar(
+        "SELECT k FROM (
+           SELECT DISTINCT COALESCE(fair_key, '') AS k FROM message
+           WHERE queue_id = $1 AND dead_at IS NULL AND available_at <= $2
+             AND (expires_at IS NULL OR expires_at > $2)) keys
+         ORDER BY $3::TEXT IS NOT NULL AND k <= $3, k
+         LIMIT $4",
+    )
+    .bind(queue_id)
+    .bind(now)
+    .bind(&cursor)
+    .bind(FAIR_MAX_KEYS)
+    .fetch_all(&mut *conn)
+    .await?;
+    let limit = limit.max(0) as usize;
+    let mut lanes = FairLanes::new();
+    for key in keys {
+        if lanes.len() == limit {
+            break;
+        }
+        let ids =
+            fair_ready_ids(conn, queue_id, &key, 0, 1, now, order).await?;
+        lanes.push(key, ids, 1);
+    }
+    while let Some((per, open)) = lanes.refill(limit) {
+        for (i, key, have) in open {
+            let ids =
+                fair_ready_ids(conn, queue_id, &key, have, per, now, order)
+                    .await?;
+            lanes.extend(i, ids, per);
+        }
+    }
+    Ok(lanes.interleave(limit))
+}
+
+// Ids of ready messages with fair key `key` (`""` for unkeyed ones) in the
+// order a poll leases them, skipping the first `skip`. Rows locked by a
+// concurrent poll are skipped rather than waited on.
+async fn fair_ready_ids(
+    conn: &mut PgConnection,
+    queue_id: i64,
+    key: &str,
+    skip: usize,
+    take: usize,
+    now: i64,
+    order: ReadyOrder,
+) -> sqlx::Result<Vec<i64>> {
+    let order = order.sql();
+    let keyed = if key.is_empty() {
+        "m.fair_key IS NULL AND $5 = ''"
+    } else {
+        "m.fair_key = $5"
+    };
+    let sql = format!(
+        "SELECT m.id FROM message m
+         WHERE m.queue_id = $1
+           AND {keyed}
+           AND m.dead_at IS NULL
+           AND m.available_at <= $2
+           AND (m.expires_at IS NULL OR m.expires_at > $2)
+           AND NOT EXISTS (
+             SELECT 1 FROM consumer_group cg WHERE cg.queue_id = m.queue_id)
+           AND (m.group_id IS NULL OR m.id = (
+             SELECT MIN(g.id) FROM message g
+             WHERE g.queue_id = m.queue_id
+               AND g.group_id = m.group_id
+               AND g.dead_at IS NULL
+               AND (g.expires_at IS NULL OR g.expires_at > $2)))
+         ORDER BY {order}
+         LIMIT $3 OFFSET $4
+         FOR UPDATE SKIP LOCKED"
+    );
+    sqlx::query_scalar(&sql)
+        .bind(queue_id)
+        .bind(now)
+        .bind(take as i64)
+        .bind(skip as i64)
+        .bind(key)
+        .fetch_all(&mut *conn)
+        .await
+}
+
+// Charge `leased` deliveries to a rate-limited queue's token bucket
+async fn spend_tokens(
+    conn: &mut PgConnection,
+    queue_name: &str,
+    tokens: f64,
+    leased: u64,
+    now: i64,
+) -> sqlx::Result<()> {
+    sqlx::query(
+        "UPDATE queue SET rate_tokens = $1, rate_updated_at = $2 WHERE name = $3",
+    )
+    .bind(tokens - leased as f64)
+    .bind(now)
+    .bind(queue_name)
+    .execute(&mut *conn)
+    .await?;
+    Ok(())
+}
+
+// Release leases (of one queue, or all) that expired by `now`, counting each
+// as a failed attempt: the message is requeued, or dead-lettered once it
+// reaches its queue's max_attempts. Consumer group deliveries are reaped the
+// same way. Returns `(requeued, dead_lettered)`.
+async fn reap_leases(
+    conn: &mut PgConnection,
+    queue_name: Option<&str>,
+    now: i64,
+) -> sqlx::Result<(u64, u64)> {
+    // A message is quarantined once its leases expired the queue's
+    // max_lease_expirations times
+    let quarantine = "message.lease_expirations + 1 >= q.max_lease_expirations";
+    let reason = quarantine_reason_sql("message.lease_expirations + 1");
+    let sql = format!(
+        "UPDATE message
+         SET attempts = message.attempts + 1, lease_token = NULL,
+             lease_expirations = message.lease_expirations + 1,
+             dead_at = CASE WHEN message.attempts + 1 >= q.max_attempts
+               OR {quarantine}
+               THEN $1 END,
+             last_error = CASE WHEN {quarantine}
+               THEN {reason} ELSE message.last_error END
+         FROM queue q
+         WHERE q.id = message.queue_id
+           AND message.lease_token IS NOT NULL
+           AND message.available_at <= $1
+           AND message.dead_at IS NULL
+           AND ($2::TEXT IS NULL OR q.name = $2)
+         RETURNING message.id, q.name, message.trace_id,
+                   message.lease_expirations, message.dead_at IS NOT NULL,
+                   COALESCE(
+                     message.lease_expirations >= q.max_lease_expirations,
+                     FALSE)"
+    );
+    let reaped: Vec<ReapedRow> = sqlx::query_as(&sql)
+        .bind(now)
+        .bind(queue_name)
+        .fetch_all(&mut *conn)
+        .await?;
+    let quarantined = report_quarantined(&reaped);
+    if !quarantined.is_empty() {
+        sqlx::query(
+            "UPDATE message_attempt SET outcome = 'quarantined', settled_at = $1
+             WHERE outcome IS NULL AND message_id = ANY($2)",
+        )
+        .bind(now)
+        .bind(&quarantined)
+        .execute(&mut *conn)
+        .await?;
+    }
+    let dead_messages: Vec<i64> =
+        reaped.iter().filter(|r| r.4).map(|r| r.0).collect();
+    send_dead_receipts(conn, &dead_messages, now).await?;
+    let deliveries: Vec<(i64, bool)> = sqlx::query_as(
+        "UPDATE group_delivery
+         SET attempts = attempts + 1, lease_token = NULL,
+             dead_at = CASE WHEN group_delivery.attempts + 1 >= (
+               SELECT q.max_attempts FROM consumer_group cg
+               JOIN queue q ON q.id = cg.queue_id
+               WHERE cg.id = group_delivery.consumer_group_id)
+               THEN $1 END
+         WHERE lease_token IS NOT NULL AND available_at <= $1
+           AND acked_at IS NULL AND dead_at IS NULL
+           AND ($2::TEXT IS NULL OR consumer_group_id IN (
+             SELECT cg.id FROM consumer_group cg
+             JOIN queue q ON q.id = cg.queue_id WHERE q.name = $2))
+         RETURNING message_id, dead_at IS NOT NULL",
+    )
+    .bind(now)
+    .bind(queue_name)
+    .fetch_all(&mut *conn)
+    .await?;
+    let dead: Vec<i64> = deliveries
+        .iter()
+        .filter(|&&(_, dead)| dead)
+        .map(|&(id, _)| id)
+        .collect();
+    lock_messages(conn, &dead).await?;
+    delete_finished(conn, &dead).await?;
+    let dead_count =
+        (reaped.iter().filter(|r| r.4).count() + dead.len()) as u64;
+    let total = (reaped.len() + deliveries.len()) as u64;
+    Ok((total - dead_count, dead_count))
+}
+
+#[async_trait]
+impl Storage for PgStorage {
+    async fn schema_version(&self) -> sqlx::Result<i64> {
+        sqlx::query_scalar(
+            "SELECT COALESCE(MAX(version), 0) FROM schema_version",
+        )
+        .fetch_one(&self.pool)
+        .await
+    }
+
+    fn latest_schema_version(&self) -> i64 {
+        MIGRATIONS.len() as i64
+    }
+
+    async fn get_queue_by_name(
+        &self,
+        name: &str,
+    ) -> sqlx::Result<Option<Queue>> {
+        let sql = format!(
+            "SELECT {QUEUE_COLUMNS} FROM queue
+             WHERE name = $1 AND deleted_at IS NULL"
+        );
+        sqlx::query_as::<_, Queue>(&sql)
+            .bind(name)
+            .fetch_optional(&self.pool)
+            .await
+    }
+
+    async fn create_queue(
+        &self,
+        q: &Queue,
+    ) -> sqlx::Result<i64> {
+        sqlx::query_scalar(
+            "INSERT INTO queue (name, max_attempts, dedup_window_ms, retention_days, backoff_base_ms, backoff_multiplier, backoff_max_ms, backoff_jitter, default_visibility_ms, default_delay_ms, max_deliveries_per_second, max_payload_bytes, payload_schema, strict_fifo, fair, max_depth, max_lease_expirations, ordering, schema_mode)
+             VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17, $18, $19) RETURNING id",
+        )
+        .bind(&q.name)
+        .bind(q.max_attempts)
+        .bind(q.dedup_window_ms)
+        .bind(q.retention_days)
+        .bind(q.backoff_base_ms)
+        .bind(q.backoff_multiplier)
+        .bind(q.backoff_max_ms)
+        .bind(q.backoff_jitter)
+        .bind(q.default_visibility_ms)
+        .bind(q.default_delay_ms)
+        .bind(q.max_deliveries_per_second)
+        .bind(q.max_payload_bytes)
+        .bind(q.payload_schema.as_ref().map(Json))
+        .bind(q.strict_fifo)
+        .bind(q.fair)
+        .bind(q.max_depth)
+        .bind(q.max_lease_expirations)
+        .bind(&q.ordering)
+        .bind(&q.schema_mode)
+        .fetch_one(&self.pool)
+        .await
+    }
+
+    async fn list_queues(&self) -> sqlx::Result<Vec<Queue>> {
+        let sql = format!(
+            "SELECT {QUEUE_COLUMNS} FROM queue WHERE deleted_at IS NULL
+             ORDER BY id"
+        );
+        sqlx::query_as::<_, Queue>(&sql).fetch_all(&self.pool).await
+    }
+
+    async fn list_queues_matching(
+        &self,
+        pattern: &str,
+    ) -> sqlx::Result<Vec<Queue>> {
+        // `%`, `_` and the escape character itself are escaped to stay
+        // literal
+        let like = pattern
+            .replace('\\', "\\\\")
+            .replace('%', "\\%")
+            .replace('_', "\\_")
+            .replace('*', "%");
+        let sql = format!(
+            "SELECT {QUEUE_COLUMNS} FROM queue
+             WHERE deleted_at IS NULL AND name LIKE $1 ESCAPE '\\'
+             ORDER BY id"
+        );
+        sqlx::query_as::<_, Queue>(&sql).bind(like).fetch_all(&self.pool).await
+    }
+
+    async fn update_queue(
+        &self,
+        q: &Queue,
+    ) -> sqlx::Result<u64> {
+        let mut conn = self.pool.acquire().await?;
+        update_queue_row(&mut conn, q).await
+    }
+
+    async fn clone_queue(
+        &self,
+        source: &str,
+        target: &str,
+        with_messages: bool,
+    ) -> sqlx::Result<Option<(i64, u64)>> {
+        let mut tx = self.pool.begin().await?;
+        let ids: Option<(i64, i64)> = sqlx::query_as(
+            "WITH src AS (SELECT * FROM queue WHERE name = $2)
+             INSERT INTO queue (name, max_attempts, dedup_window_ms, retention_days, backoff_base_ms, backoff_multiplier, backoff_max_ms, backoff_jitter, default_visibility_ms, default_delay_ms, max_deliveries_per_second, max_payload_bytes, payload_schema, strict_fifo, fair, max_depth, max_lease_expirations, ordering, schema_mode)
+             SELECT $1, max_attempts, dedup_window_ms, retention_days, backoff_base_ms, backoff_multiplier, backoff_max_ms, backoff_jitter, default_visibility_ms, default_delay_ms, max_deliveries_per_second, max_payload_bytes, payload_schema, strict_fifo, fair, max_depth, max_lease_expirations, ordering, schema_mode
+             FROM src
+             RETURNING id, (SELECT id FROM src)",
+        )
+        .bind(target)
+        .bind(source)
+        .fetch_optional(&mut *tx)
+        .await?;
+        let Some((id, source_id)) = ids else {
+            return Ok(None);
+        };
+        let mut copied = 0;
+        if with_messages {
+            copied = sqlx::query(
+                "INSERT INTO message (queue_id, payload, attempts, available_at, created_at, priority, expires_at, dedup_key, group_id, headers, trace_id, fair_key, lease_expirations, sort_key, reply_to)
+                 SELECT $1, payload, attempts,
+                        CASE WHEN lease_token IS NULL THEN available_at
+                             ELSE LEAST(available_at, $2) END,
+                        created_at, priority, expires_at, dedup_key, group_id,
+                        headers, trace_id, fair_key, lease_expirations, sort_key,
+                        reply_to
+                 FROM message
+                 WHERE queue_id = $3 AND dead_at IS NULL
+                 ORDER BY id",
+            )
+            .bind(id)
+            .bind(now_ms())
+            .bind(source_id)
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+        }
+        tx.commit().await?;
+        Ok(Some((id, copied)))
+    }
+
+    async fn delete_queue_by_name(
+        &self,
+        name: &str,
+    ) -> sqlx::Result<u64> {
+        let res = sqlx::query("DELETE FROM queue WHERE name = $1")
+            .bind(name)
+            .execute(&self.pool)
+            .await?;
+        Ok(res.rows_affected())
+    }
+
+    async fn trash_queue(
+        &self,
+        name: &str,
+        now: i64,
+    ) -> sqlx::Result<u64> {
+        let res = sqlx::query(
+            "UPDATE queue SET deleted_at = $1
+             WHERE name = $2 AND deleted_at IS NULL",
+        )
+        .bind(now)
+        .bind(name)
+        .execute(&self.pool)
+        .await?;
+        Ok(res.rows_affected())
+    }
+
+    async fn restore_queue(
+        &self,
+        name: &str,
+    ) -> sqlx::Result<u64> {
+        let res = sqlx::query(
+            "UPDATE queue SET deleted_at = NULL
+             WHERE name = $1 AND deleted_at IS NOT NULL",
+        )
+        .bind(name)
+        .execute(&self.pool)
+        .await?;
+        Ok(res.rows_affected())
+    }
+
+    async fn list_trashed_queues(&self) -> sqlx::Result<Vec<Queue>> {
+        let sql = format!(
+            "SELECT {QUEUE_COLUMNS} FROM queue WHERE deleted_at IS NOT NULL
+             ORDER BY deleted_at, id"
+        );
+        sqlx::query_as::<_, Queue>(&sql).fetch_all(&self.pool).await
+    }
+
+    async fn purge_trashed_queues(
+        &self,
+        deleted_before: i64,
+    ) -> sqlx::Result<Vec<String>> {
+        sqlx::query_scalar(
+            "DELETE FROM queue WHERE deleted_at <= $1 RETURNING name",
+        )
+        .bind(deleted_before)
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    async fn enqueue_message(
+        &self,
+        msg: &Message,
+    ) -> sqlx::Result<i64> {
+        let mut conn = self.pool.acquire().await?;
+        insert_message(&mut conn, msg).await
+    }
+
+    async fn enqueue_message_dedup(
+        &self,
+        msg: &Message,
+        window_ms: i64,
+    ) -> sqlx::Result<(i64, bool)> {
+        let mut attempt = 0;
+        loop {
+            let mut tx: Transaction<'_, Postgres> = self.pool.begin().await?;
+            // Release the key from messages outside the window
+            sqlx::query(
+                "UPDATE message SET dedup_key = NULL
+                 WHERE queue_id = $1 AND dedup_key = $2 AND created_at <= $3",
+            )
+            .bind(msg.queue_id)
+            .bind(&msg.dedup_key)
+            .bind(msg.created_at - window_ms.max(0))
+            .execute(&mut *tx)
+            .await?;
+            let existing: Option<i64> = sqlx::query_scalar(
+                "SELECT id FROM message WHERE queue_id = $1 AND dedup_key = $2",
+            )
+            .bind(msg.queue_id)
+            .bind(&msg.dedup_key)
+            .fetch_optional(&mut *tx)
+            .await?;
+            if let Some(id) = existing {
+                tx.commit().await?;
+                return Ok((id, false));
+            }
+            match insert_message(&mut tx, msg).await {
+                Ok(id) => {
+                    tx.commit().await?;
+                    return Ok((id, true));
+                }
+                // A concurrent producer inserted the same key first; look again
+                Err(sqlx::Error::Database(e))
+                    if e.is_unique_violation() && attempt == 0 =>
+                {
+                    tx.rollback().await?;
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    async fn get_message_by_id(
+        &self,
+        id: i64,
+    ) -> sqlx::Result<Option<Message>> {
+        let sql =
+            format!("SELECT {MESSAGE_COLUMNS} FROM message WHERE id = $1");
+        sqlx::query_as::<_, Message>(&sql)
+            .bind(id)
+            .fetch_optional(&self.pool)
+            .await
+    }
+
+    async fn find_message_ids(
+        &self,
+        public_ids: &[String],
+    ) -> sqlx::Result<Vec<(String, i64)>> {
+        sqlx::query_as(
+            "SELECT public_id, id FROM message WHERE public_id = ANY($1)",
+        )
+        .bind(public_ids)
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    async fn export_messages(
+        &self,
+        queue_name: &str,
+        after_id: i64,
+        limit: i64,
+    ) -> sqlx::Result<Vec<Message>> {
+        let sql = format!(
+            "SELECT {MESSAGE_COLUMNS}
+             FROM message
+             WHERE queue_id = (SELECT id FROM queue WHERE name = $1) AND id > $2
+             ORDER BY id
+             LIMIT $3"
+        );
+        sqlx::query_as::<_, Message>(&sql)
+            .bind(queue_name)
+            .bind(after_id)
+            .bind(limit)
+            .fetch_all(&self.pool)
+            .await
+    }
+
+    async fn import_messages(
+        &self,
+        msgs: &[Message],
+    ) -> sqlx::Result<u64> {
+        let mut tx: Transaction<'_, Postgres> = self.pool.begin().await?;
+        let mut imported = 0;
+        for msg in msgs {
+            if msg.dedup_key.is_some() {
+                let held: Option<i64> = sqlx::query_scalar(
+                    "SELECT id FROM message WHERE queue_id = $1 AND dedup_key = $2",
+                )
+                .bind(msg.queue_id)
+                .bind(&msg.dedup_key)
+                .fetch_optional(&mut *tx)
+                .await?;
+                if held.is_some() {
+                    continue;
+                }
+            }
+            insert_message(&mut tx, msg).await?;
+            imported += 1;
+        }
+        tx.commit().await?;
+        Ok(imported)
+    }
+
+    async fn ack_messages(
+        &self,
+        ids: &[i64],
+        lease_token: &str,
+    ) -> sqlx::Result<Vec<i64>> {
+        if ids.is_empty() {
+            return Ok(Vec::new());
+        }
+        // Delete, archive (for queues with retention), count and send
+        // receipts in one statement
+        let sql = format!(
+            "WITH acked AS (
+               DELETE FROM message
+               WHERE id = ANY($1) AND lease_token = $2 AND available_at > $3
+               RETURNING id, queue_id, payload, attempts, priority, group_id, created_at,
+                         trace_id, reply_to
+             ), archived AS (
+               INSERT INTO message_archive (message_id, queue_id, payload, attempts, priority, group_id, created_at, acked_at, purge_at)
+               SELECT a.id, a.queue_id, a.payload, a.attempts, a.priority, a.group_id, a.created_at, $3, $3 + q.retention_days * {DAY_MS}::BIGINT
+               FROM acked a JOIN queue q ON q.id = a.queue_id
+               WHERE q.retention_days IS NOT NULL
+             ), counted AS (
+               UPDATE queue SET acked_count = acked_count + c.n,
+                                ack_latency_ms_total = ack_latency_ms_total + c.ms
+               FROM (SELECT queue_id, COUNT(*) AS n, SUM($3 - created_at)::BIGINT AS ms
+                     FROM acked GROUP BY queue_id) AS c
+               WHERE queue.id = c.queue_id
+             ), receipts AS (
+               INSERT INTO message (queue_id, payload, attempts, available_at, created_at, trace_id)
+               SELECT rq.id,
+                      json_build_object('message_id', a.id, 'queue', q.name,
+                                        'outcome', 'acked', 'attempts', a.attempts + 1,
+                                        'enqueued_at', a.created_at, 'settled_at', $3)::TEXT,
+                      0, $3, $3, a.trace_id
+               FROM acked a JOIN queue q ON q.id = a.queue_id
+               JOIN queue rq ON rq.name = a.reply_to AND rq.deleted_at IS NULL
+               ORDER BY a.id
+             )
+             SELECT id FROM acked"
+        );
+        sqlx::query_scalar(&sql)
+            .bind(ids)
+            .bind(lease_token)
+            .bind(now_ms())
+            .fetch_all(&self.pool)
+            .await
+    }
+
+    async fn expire_leases(
+        &self,
+        ids: &[i64],
+        lease_token: &str,
+    ) -> sqlx::Result<u64> {
+        if ids.is_empty() {
+            return Ok(0);
+        }
+        let res = sqlx::query(
+            "UPDATE message SET available_at = $1
+             WHERE id = ANY($2) AND lease_token = $3 AND available_at > $1",
+        )
+        .bind(now_ms())
+        .bind(ids)
+        .bind(lease_token)
+        .execute(&self.pool)
+        .await?;
+        Ok(res.rows_affected())
+    }
+
+    async fn extend_visibility(
+        &self,
+        ids: &[i64],
+        lease_token: &str,
+        extra_ms: i64,
+    ) -> sqlx::Result<u64> {
+        if ids.is_empty() {
+            return Ok(0);
+        }
+        let res = sqlx::query(
+            "UPDATE message SET available_at = available_at + $1
+             WHERE id = ANY($2) AND lease_token = $3 AND available_at > $4",
+        )
+        .bind(extra_ms.max(0))
+        .bind(ids)
+        .bind(lease_token)
+        .bind(now_ms())
+        .execute(&self.pool)
+        .await?;
+        Ok(res.rows_affected())
+    }
+
+    async fn purge_messages_by_queue(
+        &self,
+        queue_name: &str,
+        limit: i64,
+    ) -> sqlx::Result<u64> {
+        let res = sqlx::query(
+            "DELETE FROM message WHERE id IN (
+               SELECT id FROM message
+               WHERE queue_id = (SELECT id FROM queue WHERE name = $1)
+                 AND dead_at IS NULL
+               LIMIT $2)",
+        )
+        .bind(queue_name)
+        .bind(limit)
+        .execute(&self.pool)
+        .await?;
+        Ok(res.rows_affected())
+    }
+
+    async fn remove_matching_messages(
+        &self,
+        queue_name: &str,
+        filter: &RemoveFilter,
+        limit: i64,
+    ) -> sqlx::Result<u64> {
+        let (path, path_value) = filter.json_path.clone().unzip();
+        let res = sqlx::query(
+            "DELETE FROM message WHERE id IN (
+               SELECT id FROM message
+               WHERE queue_id = (SELECT id FROM queue WHERE name = $1)
+                 AND dead_at IS NULL
+                 AND ($2::BIGINT IS NULL OR created_at < $2)
+                 AND ($3::TEXT IS NULL
+                      OR jsonb_path_query_first(payload::jsonb, $3::jsonpath)
+                         #>> '{}' = $4)
+               LIMIT $5)",
+        )
+        .bind(queue_name)
+        .bind(filter.created_before)
+        .bind(path)
+        .bind(path_value)
+        .bind(limit)
+        .execute(&self.pool)
+        .await?;
+        Ok(res.rows_affected())
+    }
+
+    async fn peek_messages(
+        &self,
+        queue_name: &str,
+        limit: i64,
+        filter: &PeekFilter,
+    ) -> sqlx::Result<Vec<Message>> {
+        // A cursor (after_id) pages in id order instead of delivery order
+        let order = match filter.after_id {
+            Some(_) => "m.id",
+            None => ready_order(&self.pool, queue_name).await?.sql(),
+        };
+        let now = now_ms();
+        let state = filter.state.sql(now);
+        let sql = format!(
+            "SELECT {MESSAGE_COLUMNS}
+             FROM message m
+             WHERE queue_id = (SELECT id FROM queue WHERE name = $1)
+               AND dead_at IS NULL
+               AND (expires_at IS NULL OR expires_at > $2)
+               AND {state}
+               AND ($5::BIGINT IS NULL OR id > $5)
+               AND ($6::BIGINT IS NULL OR created_at >= $6)
+               AND ($7::BIGINT IS NULL OR created_at < $7)
+               AND ($8::TEXT IS NULL OR headers ->> $8 = $9)
+               AND ($10::TEXT IS NULL OR strpos(payload, $10) > 0)
+               AND ($11::TEXT IS NULL
+                    OR jsonb_path_query_first(payload::jsonb, $11::jsonpath)
+                       #>> '{{}}' = $12)
+             ORDER BY {order}
+             LIMIT $3 OFFSET $4"
+        );
+        let (key, value) = filter.header.clone().unzip();
+        let (path, path_value) = filter.json_path.clone().unzip();
+        sqlx::query_as::<_, Message>(&sql)
+            .bind(queue_name)
+            .bind(now)
+            .bind(limit)
+            .bind(filter.offset.max(0))
+            .bind(filter.after_id)
+            .bind(filter.created_after)
+            .bind(filter.created_before)
+            .bind(key)
+            .bind(value)
+            .bind(&filter.payload_contains)
+            .bind(path)
+            .bind(path_value)
+            .fetch_all(&self.pool)
+            .await
+    }
+
+    async fn search_messages(
+        &self,
+        queue_name: &str,
+        path: &str,
+        value: Option<&str>,
+        after_id: Option<i64>,
+        limit: i64,
+    ) -> sqlx::Result<Vec<Message>> {
+        let sql = format!(
+            "SELECT {MESSAGE_COLUMNS}
+             FROM message
+             WHERE queue_id = (SELECT id FROM queue WHERE name = $1)
+               AND ($2::BIGINT IS NULL OR id > $2)
+               AND CASE WHEN $4::TEXT IS NULL
+                        THEN jsonb_path_exists(payload::jsonb, $3::jsonpath)
+                        ELSE jsonb_path_query_first(payload::jsonb, $3::jsonpath)
+                             #>> '{{}}' = $4 END
+             ORDER BY id
+             LIMIT $5"
+        );
+        sqlx::query_as::<_, Message>(&sql)
+            .bind(queue_name)
+            .bind(after_id)
+            .bind(path)
+            .bind(value)
+            .bind(limit)
+            .fetch_all(&self.pool)
+            .await
+    }
+
+    async fn poll_messages(
+        &self,
+        queue_name: &str,
+        limit: i64,
+        visibility_ms: i64,
+    ) -> sqlx::Result<Vec<Message>> {
+        let now = now_ms();
+        let lease_token = uuid::Uuid::new_v4().to_string();
+        // Count expired leases as attempts before they are handed out again,
+        // so a consumer that keeps crashing cannot retry forever
+        let mut tx = self.pool.begin().await?;
+        reap_leases(&mut tx, Some(queue_name), now).await?;
+        tx.commit().await?;
+        let mut tx = self.pool.begin().await?;
+        let (limit, bucket, order) =
+            rate_limit(&mut tx, queue_name, limit, now).await?;
+        if let PollOrder::Fair(ready) = order {
+            let (ids, last) =
+                fair_poll_ids(&mut tx, queue_name, limit, now, ready).await?;
+            let sql = format!(
+                "UPDATE message SET available_at = $1, lease_token = $2
+                 WHERE id = ANY($3)
+                 RETURNING {LEASED_MESSAGE_COLUMNS}"
+            );
+            let mut messages = sqlx::query_as::<_, Message>(&sql)
+                .bind(now + visibility_ms.max(0))
+                .bind(&lease_token)
+                .bind(&ids)
+                .fetch_all(&mut *tx)
+                .await?;
+            if let Some(key) = last {
+                sqlx::query(
+                    "UPDATE queue SET fair_cursor = $1 WHERE name = $2",
+                )
+                .bind(key)
+                .bind(queue_name)
+                .execute(&mut *tx)
+                .await?;
+            }
+            if let Some(tokens) = bucket {
+                let leased = messages.len() as u64;
+                spend_tokens(&mut tx, queue_name, tokens, leased, now).await?;
+            }
+            tx.commit().await?;
+            messages.sort_by_key(|m| ids.iter().position(|&id| id == m.id));
+            return Ok(messages);
+        }
+        let strict = order == PollOrder::StrictFifo;
+        let ready = order.ready();
+        // Rows locked by a concurrent poll are skipped rather than waited on.
+        // Strict FIFO queues only offer the oldest live message of each
+        // group (ungrouped messages forming one group), in enqueue order.
+        let eligible = if strict {
+            "m.id IN (
+                   SELECT MIN(h.id) FROM message h
+                   WHERE h.queue_id = (SELECT id FROM queue WHERE name = $1)
+                     AND h.dead_at IS NULL
+                     AND (h.expires_at IS NULL OR h.expires_at > $2)
+                   GROUP BY h.group_id)"
+        } else {
+            "(m.group_id IS NULL OR m.id = (
+                   SELECT MIN(g.id) FROM message g
+                   WHERE g.queue_id = m.queue_id
+                     AND g.group_id = m.group_id
+                     AND g.dead_at IS NULL
+                     AND (g.expires_at IS NULL OR g.expires_at > $2)))"
+        };
+        let order = if strict { "m.id" } else { ready.sql() };
+        let sql = format!(
+            "WITH picked AS (
+               SELECT m.id AS picked_id
+               FROM message m
+               WHERE m.queue_id = (SELECT id FROM queue WHERE name = $1)
+                 AND m.dead_at IS NULL
+                 AND m.available_at <= $2
+                 AND (m.expires_at IS NULL OR m.expires_at > $2)
+                 AND NOT EXISTS (
+                   SELECT 1 FROM consumer_group cg
+                   WHERE cg.queue_id = m.queue_id)
+                 AND {eligible}
+               ORDER BY {order}
+               LIMIT $3
+               FOR UPDATE SKIP LOCKED
+             )
+             UPDATE message SET available_at = $4, lease_token = $5
+             FROM picked WHERE id = picked_id
+             RETURNING {LEASED_MESSAGE_COLUMNS}"
+        );
+        let mut messages = sqlx::query_as::<_, Message>(&sql)
+            .bind(queue_name)
+            .bind(now)
+            .bind(limit)
+            .bind(now + visibility_ms.max(0))
+            .bind(&lease_token)
+            .fetch_all(&mut *tx)
+            .await?;
+        if let Some(tokens) = bucket {
+            let leased = messages.len() as u64;
+            spend_tokens(&mut tx, queue_name, tokens, leased, now).await?;
+        }
+        tx.commit().await?;
+        // RETURNING has no defined order; match the SQLite backend
+        ready.sort(&mut messages, |m| m);
+        Ok(messages)
+    }
+
+    async fn count_ready_messages(
+        &self,
+        queue_id: i64,
+        now_ms: i64,
+    ) -> sqlx::Result<i64> {
+        let sql = format!("SELECT {READY_COUNT} FROM queue q WHERE q.id = $2");
+        let count: Option<i64> = sqlx::query_scalar(&sql)
+            .bind(now_ms)
+            .bind(queue_id)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(count.unwrap_or(0))
+    }
+
+    async fn sample_messages(
+        &self,
+        queue_id: i64,
+        n: i64,
+        now_ms: i64,
+    ) -> sqlx::Result<Vec<Message>> {
+        const READY: &str = "queue_id = $1 AND dead_at IS NULL
+               AND available_at <= $2
+               AND (expires_at IS NULL OR expires_at > $2)";
+        let ready = self.count_ready_messages(queue_id, now_ms).await?;
+        if ready <= SAMPLE_SHUFFLE_MAX {
+            let sql = format!(
+                "SELECT {MESSAGE_COLUMNS} FROM message WHERE {READY}
+                 ORDER BY random() LIMIT $3"
+            );
+            return sqlx::query_as::<_, Message>(&sql)
+                .bind(queue_id)
+                .bind(now_ms)
+                .bind(n)
+                .fetch_all(&self.pool)
+                .await;
+        }
+        // Too many to shuffle: walk the primary key from random ids instead
+        let range: (Option<i64>, Option<i64>) = sqlx::query_as(
+            "SELECT
+               (SELECT MIN(id) FROM message
+                WHERE queue_id = $1 AND dead_at IS NULL),
+               (SELECT MAX(id) FROM message
+                WHERE queue_id = $1 AND dead_at IS NULL)",
+        )
+        .bind(queue_id)
+        .fetch_one(&self.pool)
+        .await?;
+        let (Some(lo), Some(hi)) = range else {
+            return Ok(Vec::new());
+        };
+        let sql = format!(
+            "SELECT {MESSAGE_COLUMNS} FROM message
+             WHERE {READY} AND id >= $3
+             ORDER BY id LIMIT 1"
+        );
+        let mut sample: Vec<Message> = Vec::new();
+        for pivot in sample_pivots(lo, hi, n) {
+            if sample.len() as i64 >= n {
+                break;
+            }
+            let msg = sqlx::query_as::<_, Message>(&sql)
+                .bind(queue_id)
+                .bind(now_ms)
+                .bind(pivot)
+                .fetch_optional(&self.pool)
+                .await?;
+            if let Some(msg) = msg
+                && !sample.iter().any(|m| m.id == msg.id)
+            {
+                sample.push(msg);
+            }
+        }
+        Ok(sample)
+    }
+
+    async fn oldest_message_created_at(
+        &self,
+        queue_id: i64,
+        now_ms: i64,
+    ) -> sqlx::Result<Option<i64>> {
+        sqlx::query_scalar(
+            "SELECT MIN(created_at) FROM message
+             WHERE queue_id = $1
+               AND dead_at IS NULL
+               AND (expires_at IS NULL OR expires_at > $2)",
+        )
+        .bind(queue_id)
+        .bind(now_ms)
+        .fetch_one(&self.pool)
+        .await
+    }
+
+    async fn count_queued_messages_by_queue(
+        &self,
+        queue_id: i64,
+    ) -> sqlx::Result<i64> {
+        let count: Option<i64> =
+            sqlx::query_scalar("SELECT message_count FROM queue WHERE id = $1")
+                .bind(queue_id)
+                .fetch_optional(&self.pool)
+                .await?;
+        Ok(count.unwrap_or(0))
+    }
+
+    async fn expire_messages(
+        &self,
+        now_ms: i64,
+    ) -> sqlx::Result<u64> {
+        const EXPIRED: &str = "dead_at IS NULL
+               AND expires_at IS NOT NULL AND expires_at <= $1
+               AND NOT (lease_token IS NOT NULL AND available_at > $1)";
+        let mut tx: Transaction<'_, Postgres> = self.pool.begin().await?;
+        let delete_sql = format!(
+            "WITH gone AS (DELETE FROM message WHERE {EXPIRED} RETURNING queue_id),
+                  counts AS (SELECT queue_id, COUNT(*) AS n FROM gone GROUP BY queue_id)
+             UPDATE queue SET expired_count = expired_count + counts.n
+             FROM counts WHERE queue.id = counts.queue_id
+             RETURNING counts.n"
+        );
+        let counts: Vec<i64> = sqlx::query_scalar(&delete_sql)
+            .bind(now_ms)
+            .fetch_all(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(counts.iter().sum::<i64>() as u64)
+    }
+
+    async fn doctor(
+        &self,
+        fix: bool,
+        now_ms: i64,
+    ) -> sqlx::Result<DoctorReport> {
+        const STUCK: &str = "lease_token IS NOT NULL
+               AND (dead_at IS NOT NULL OR available_at <= $1)";
+        const IMPOSSIBLE: &str = "created_at < 0 OR created_at > $1
+               OR available_at < created_at OR dead_at < created_at
+               OR expires_at < created_at";
+        // Postgres enforces its constraints itself and has no built-in
+        // integrity check to run here
+        let mut report = DoctorReport { fixed: fix, ..DoctorReport::default() };
+        let mut tx: Transaction<'_, Postgres> = self.pool.begin().await?;
+        for (table, cond) in ORPHAN_CHECKS {
+            let sql = format!("SELECT COUNT(*) FROM {table} WHERE {cond}");
+            let n: i64 = sqlx::query_scalar(&sql).fetch_one(&mut *tx).await?;
+            report.orphaned_rows += n as u64;
+        }
+        let sql = format!("SELECT COUNT(*) FROM message WHERE {STUCK}");
+        let n: i64 =
+            sqlx::query_scalar(&sql).bind(now_ms).fetch_one(&mut *tx).await?;
+        report.stuck_leases = n as u64;
+        let sql = format!("SELECT COUNT(*) FROM message WHERE {IMPOSSIBLE}");
+        let n: i64 =
+            sqlx::query_scalar(&sql).bind(now_ms).fetch_one(&mut *tx).await?;
+        report.impossible_timestamps = n as u64;
+        let sql =
+            format!("SELECT COUNT(*) FROM queue WHERE {DRIFTED_COUNTERS}");
+        let n: i64 = sqlx::query_scalar(&sql).fetch_one(&mut *tx).await?;
+        report.drifted_counters = n as u64;
+
+        if fix {
+            for (table, cond) in ORPHAN_CHECKS {
+                let sql = format!("DELETE FROM {table} WHERE {cond}");
+                sqlx::query(&sql).execute(&mut *tx).await?;
+            }
+            sqlx::query(
+                "UPDATE message SET lease_token = NULL
+                 WHERE lease_token IS NOT NULL AND dead_at IS NOT NULL",
+            )
+            .execute(&mut *tx)
+            .await?;
+            reap_leases(&mut tx, None, now_ms).await?;
+            sqlx::query(
+                "UPDATE message SET created_at = $1
+                 WHERE created_at < 0 OR created_at > $1",
+            )
+            .bind(now_ms)
+            .execute(&mut *tx)
+            .await?;
+            sqlx::query(CLAMP_TIMESTAMPS_SQL).execute(&mut *tx).await?;
+            sqlx::query(RECOUNT_SQL).execute(&mut *tx).await?;
+        }
+        tx.commit().await?;
+        Ok(report)
+    }
+
+    async fn recovery_scan(
+        &self,
+        repair: bool,
+        now_ms: i64,
+    ) -> sqlx::Result<RecoveryReport> {
+        const STRANDED: &str = "lease_token IS NOT NULL AND dead_at IS NULL
+               AND available_at > $1
+               AND NOT EXISTS (
+                 SELECT 1 FROM message_attempt a
+                 WHERE a.message_id = message.id
+                   AND a.lease_token = message.lease_token
+                   AND a.outcome IS NULL)";
+        let horizon = now_ms + STRANDED_LEASE_MS;
+        let sql = format!("SELECT COUNT(*) FROM message WHERE {STRANDED}");
+        let stranded: i64 = sqlx::query_scalar(&sql)
+            .bind(horizon)
+            .fetch_one(&self.pool)
+            .await?;
+        let versions: Vec<i64> = sqlx::query_scalar(
+            "SELECT version FROM schema_version ORDER BY version",
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        // Postgres manages its own write-ahead log
+        let report = RecoveryReport {
+            stranded_leases: stranded as u64,
+            schema_problems: schema_problems(
+                &versions,
+                self.latest_schema_version(),
+            ),
+            repaired: repair,
+            ..RecoveryReport::default()
+        };
+        if repair {
+            let sql = format!(
+                "UPDATE message SET lease_token = NULL, available_at = $2
+                 WHERE {STRANDED}"
+            );
+            sqlx::query(&sql)
+                .bind(horizon)
+                .bind(now_ms)
+                .execute(&self.pool)
+                .await?;
+        }
+        Ok(report)
+    }
+
+    async fn reap_expired_leases(
+        &self,
+        now_ms: i64,
+    ) -> sqlx::Result<(u64, u64)> {
+        let mut tx: Transaction<'_, Postgres> = self.pool.begin().await?;
+        let reaped = reap_leases(&mut tx, None, now_ms).await?;
+        tx.commit().await?;
+        Ok(reaped)
+    }
+
+    async fn count_expired_messages(
+        &self,
+        queue_id: i64,
+    ) -> sqlx::Result<i64> {
+        sqlx::query_scalar("SELECT expired_count FROM queue WHERE id = $1")
+            .bind(queue_id)
+            .fetch_one(&self.pool)
+            .await
+    }
+
+    async fn queue_metrics(
+        &self,
+        queue_id: i64,
+        now_ms: i64,
+    ) -> sqlx::Result<QueueMetrics> {
+        sqlx::query_as::<_, QueueMetrics>(
+            "SELECT
+               (SELECT COUNT(*) FROM message
+                WHERE queue_id = $1 AND dead_at IS NULL
+                  AND lease_token IS NOT NULL AND available_at > $2) AS leased,
+               (SELECT COUNT(*) FROM message
+                WHERE queue_id = $1 AND dead_at IS NULL
+                  AND lease_token IS NULL AND available_at > $2
+                  AND (expires_at IS NULL OR expires_at > $2)) AS delayed,
+               (SELECT MIN(created_at) FROM message
+                WHERE queue_id = $1 AND dead_at IS NULL AND available_at <= $2
+                  AND (expires_at IS NULL OR expires_at > $2)) AS oldest_ready_at,
+               enqueued_count AS enqueued,
+               acked_count AS acked,
+               ack_latency_ms_total
+             FROM queue WHERE id = $1",
+        )
+        .bind(queue_id)
+        .bind(now_ms)
+        .fetch_one(&self.pool)
+        .await
+    }
+
+    async fn count_dead_messages(
+        &self,
+        queue_id: i64,
+    ) -> sqlx::Result<i64> {
+        let count: Option<i64> =
+            sqlx::query_scalar("SELECT dead_count FROM queue WHERE id = $1")
+                .bind(queue_id)
+                .fetch_optional(&self.pool)
+                .await?;
+        Ok(count.unwrap_or(0))
+    }
+
+    async fn compact(&self) -> sqlx::Result<()> {
+        sqlx::query("VACUUM").execute(&self.pool).await?;
+        Ok(())
+    }
+
+    async fn compact_incremental(&self) -> sqlx::Result<()> {
+        // Plain VACUUM already works without locking tables out
+        self.compact().await
+    }
+
+    async fn recompress_payloads(&self) -> sqlx::Result<u64> {
+        // TOAST already compresses large payload values
+        Ok(0)
+    }
+
+    async fn rotate_payload_key(&self) -> sqlx::Result<u64> {
+        Err(sqlx::Error::Configuration(
+            "payload encryption requires the SQLite backend".into(),
+        ))
+    }
+
+    async fn db_status(&self) -> sqlx::Result<DbStatus> {
+        let file_bytes: i64 =
+            sqlx::query_scalar("SELECT pg_database_size(current_database())")
+                .fetch_one(&self.pool)
+                .await?;
+        let page_size: i64 =
+            sqlx::query_scalar("SELECT current_setting('block_size')::BIGINT")
+                .fetch_one(&self.pool)
+                .await?;
+        // Sizes include indexes and TOAST; autovacuum reuses dead space
+        // itself, so no free pages are reported
+        let tables = sqlx::query_as(
+            "SELECT c.relname::TEXT AS name,
+                    pg_total_relation_size(c.oid) AS bytes
+             FROM pg_class c JOIN pg_namespace n ON n.oid = c.relnamespace
+             WHERE c.relkind = 'r' AND n.nspname = current_schema()
+             ORDER BY bytes DESC, name",
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        let queues =
+            sqlx::query_as(QUEUE_USAGE_SQL).fetch_all(&self.pool).await?;
+        Ok(DbStatus {
+            file_bytes,
+            wal_bytes: None,
+            page_size,
+            page_count: file_bytes / page_size,
+            free_pages: 0,
+            tables,
+            queues,
+            compact_recommended: false,
+            auto_compact: None,
+        })
+    }
+
+    async fn backup(
+        &self,
+        _path: &Path,
+    ) -> sqlx::Result<()> {
+        Err(sqlx::Error::Configuration(
+            "Postgres databases cannot be backed up by sqew; use pg_dump"
+                .into(),
+        ))
+    }
+
+    async fn nack_messages(
+        &self,
+        nacks: &[(i64, i64)],
+        lease_token: &str,
+        reason: Option<&str>,
+    ) -> sqlx::Result<(Vec<i64>, Vec<i64>)> {
+        if nacks.is_empty() {
+            return Ok((Vec::new(), Vec::new()));
+        }
+        let now = now_ms();
+        let (ids, delays) = split_nacks(nacks);
+        let mut tx: Transaction<'_, Postgres> = self.pool.begin().await?;
+        // Requeue only messages whose lease is held by the caller
+        let ids: Vec<i64> = sqlx::query_scalar(
+            "UPDATE message m
+             SET attempts = m.attempts + 1, available_at = $1 + n.delay_ms,
+                 lease_token = NULL, last_error = COALESCE($5, m.last_error)
+             FROM (SELECT id, MAX(delay_ms) AS delay_ms
+                   FROM unnest($2::BIGINT[], $3::BIGINT[]) AS n(id, delay_ms)
+                   GROUP BY id) n
+             WHERE m.id = n.id AND m.dead_at IS NULL
+               AND m.lease_token = $4 AND m.available_at > $1
+             RETURNING m.id",
+        )
+        .bind(now)
+        .bind(&ids)
+        .bind(&delays)
+        .bind(lease_token)
+        .bind(reason)
+        .fetch_all(&mut *tx)
+        .await?;
+        if ids.is_empty() {
+            tx.commit().await?;
+            return Ok((Vec::new(), Vec::new()));
+        }
+        // Queues with backoff configured override the requested delay
+        let rows: Vec<BackoffRow> = sqlx::query_as(
+            "SELECT m.id, m.attempts, q.backoff_base_ms, q.backoff_multiplier,
+                    q.backoff_max_ms, q.backoff_jitter
+             FROM message m JOIN queue q ON q.id = m.queue_id
+             WHERE m.id = ANY($1) AND q.backoff_base_ms IS NOT NULL",
+        )
+        .bind(&ids)
+        .fetch_all(&mut *tx)
+        .await?;
+        for (id, attempts, base, multiplier, max, jitter) in rows {
+            let delay = backoff_delay(attempts, base, multiplier, max, jitter);
+            sqlx::query("UPDATE message SET available_at = $1 WHERE id = $2")
+                .bind(now + delay)
+                .bind(id)
+                .execute(&mut *tx)
+                .await?;
+        }
+        // Move messages exceeding max_attempts to the dead-letter queue
+        let dead: Vec<i64> = sqlx::query_scalar(
+            "UPDATE message m SET dead_at = $1
+             FROM queue q
+             WHERE q.id = m.queue_id AND m.id = ANY($2)
+               AND m.dead_at IS NULL AND m.attempts >= q.max_attempts
+             RETURNING m.id",
+        )
+        .bind(now)
+        .bind(&ids)
+        .fetch_all(&mut *tx)
+        .await?;
+        send_dead_receipts(&mut tx, &dead, now).await?;
+        tx.commit().await?;
+        let requeued =
+            ids.into_iter().filter(|id| !dead.contains(id)).collect();
+        Ok((requeued, dead))
+    }
+
+    async fn remove_message_by_id(
+        &self,
+        id: i64,
+    ) -> sqlx::Result<u64> {
+        let res = sqlx::query("DELETE FROM message WHERE id = $1")
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(res.rows_affected())
+    }
+
+    async fn list_dead_letters(
+        &self,
+        queue_name: &str,
+        limit: i64,
+    ) -> sqlx::Result<Vec<Message>> {
+        let sql = format!(
+            "SELECT {MESSAGE_COLUMNS}
+             FROM message
+             WHERE queue_id = (SELECT id FROM queue WHERE name = $1)
+               AND dead_at IS NOT NULL
+             ORDER BY dead_at, id
+             LIMIT $2"
+        );
+        sqlx::query_as::<_, Message>(&sql)
+            .bind(queue_name)
+            .bind(limit)
+            .fetch_all(&self.pool)
+            .await
+    }
+
+    async fn redrive_dead_letters(
+        &self,
+        queue_name: &str,
+        ids: &[i64],
+    ) -> sqlx::Result<u64> {
+        // An empty id list means every dead letter in the queue
+        let res = sqlx::query(
+            "UPDATE message SET dead_at = NULL, attempts = 0, available_at = $1,
+                                lease_token = NULL, lease_expirations = 0
+             WHERE queue_id = (SELECT id FROM queue WHERE name = $2)
+               AND dead_at IS NOT NULL
+               AND (cardinality($3::BIGINT[]) = 0 OR id = ANY($3))",
+        )
+        .bind(now_ms())
+        .bind(queue_name)
+        .bind(ids)
+        .execute(&self.pool)
+        .await?;
+        Ok(res.rows_affected())
+    }
+
+    async fn move_messages(
+        &self,
+        ids: &[i64],
+        target_queue: &str,
+        from_queue: Option<&str>,
+        reset_attempts: bool,
+    ) -> sqlx::Result<u64> {
+        let now = now_ms();
+        let res = sqlx::query(
+            "UPDATE message
+             SET queue_id = (SELECT id FROM queue WHERE name = $1),
+                 attempts = CASE WHEN $2 THEN 0 ELSE attempts END,
+                 lease_expirations =
+                   CASE WHEN $2 THEN 0 ELSE lease_expirations END,
+                 dead_at = NULL, lease_token = NULL, dedup_key = NULL,
+                 available_at = $3
+             WHERE id = ANY($4)
+               AND (dead_at IS NOT NULL OR lease_token IS NULL
+                    OR available_at <= $3)
+               AND ($5::TEXT IS NULL
+                    OR queue_id = (SELECT id FROM queue WHERE name = $5))",
+        )
+        .bind(target_queue)
+        .bind(reset_attempts)
+        .bind(now)
+        .bind(ids)
+        .bind(from_queue)
+        .execute(&self.pool)
+        .await?;
+        Ok(res.rows_affected())
+    }
+
+    async fn create_consumer_group(
+        &self,
+        g: &ConsumerGroup,
+    ) -> sqlx::Result<i64> {
+        sqlx::query_scalar(
+            "INSERT INTO consumer_group (queue_id, name, start_id, created_at)
+             VALUES ($1, $2, (SELECT COALESCE(MAX(id), 0) FROM message), $3)
+             RETURNING id",
+        )
+        .bind(g.queue_id)
+        .bind(&g.name)
+        .bind(g.created_at)
+        .fetch_one(&self.pool)
+        .await
+    }
+
+    async fn list_consumer_groups(
+        &self,
+        queue_name: &str,
+    ) -> sqlx::Result<Vec<ConsumerGroup>> {
+        sqlx::query_as::<_, ConsumerGroup>(
+            "SELECT id, queue_id, name, start_id, created_at
+             FROM consumer_group
+             WHERE queue_id = (SELECT id FROM queue WHERE name = $1)
+             ORDER BY id",
+        )
+        .bind(queue_name)
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    async fn delete_consumer_group(
+        &self,
+        queue_name: &str,
+        group: &str,
+    ) -> sqlx::Result<u64> {
+        let mut tx = self.pool.begin().await?;
+        let res = sqlx::query(
+            "DELETE FROM consumer_group
+             WHERE queue_id = (SELECT id FROM queue WHERE name = $1)
+               AND name = $2",
+        )
+        .bind(queue_name)
+        .bind(group)
+        .execute(&mut *tx)
+        .await?;
+        let sql = format!(
+            "DELETE FROM message
+             WHERE queue_id = (SELECT id FROM queue WHERE name = $1)
+               AND {DONE_BY_ALL_GROUPS}"
+        );
+        sqlx::query(&sql).bind(queue_name).execute(&mut *tx).await?;
+        tx.commit().await?;
+        Ok(res.rows_affected())
+    }
+
+    async fn poll_group_messages(
+        &self,
+        queue_name: &str,
+        group: &str,
+        limit: i64,
+        visibility_ms: i64,
+    ) -> sqlx::Result<Vec<Message>> {
+        let now = now_ms();
+        let lease_token = uuid::Uuid::new_v4().to_string();
+        let mut tx = self.pool.begin().await?;
+        reap_leases(&mut tx, Some(queue_name), now).await?;
+        let (limit, bucket, order) =
+            rate_limit(&mut tx, queue_name, limit, now).await?;
+        let order = order.ready().group_sql();
+        // Concurrent polls of the same group may pick the same messages; the
+        // conflict guard lets only one of them take each lease
+        let sql = format!(
+            "INSERT INTO group_delivery
+               (consumer_group_id, message_id, attempts, available_at, lease_token)
+             SELECT cg.id, m.id, 0, $1, $2
+             FROM consumer_group cg
+             JOIN message m ON m.queue_id = cg.queue_id AND m.id > cg.start_id
+             LEFT JOIN group_delivery gd
+               ON gd.consumer_group_id = cg.id AND gd.message_id = m.id
+             WHERE cg.queue_id = (SELECT id FROM queue WHERE name = $3)
+               AND cg.name = $4
+               AND m.dead_at IS NULL
+               AND m.available_at <= $5
+               AND (m.expires_at IS NULL OR m.expires_at > $5)
+               AND (gd.message_id IS NULL
+                    OR (gd.acked_at IS NULL AND gd.dead_at IS NULL
+                        AND gd.available_at <= $5))
+             ORDER BY {order}
+             LIMIT $6
+             ON CONFLICT (consumer_group_id, message_id) DO UPDATE
+             SET available_at = excluded.available_at,
+                 lease_token = excluded.lease_token
+             WHERE group_delivery.acked_at IS NULL
+               AND group_delivery.dead_at IS NULL
+               AND group_delivery.available_at <= $5"
+        );
+        let leased = sqlx::query(&sql)
+            .bind(now + visibility_ms.max(0))
+            .bind(&lease_token)
+            .bind(queue_name)
+            .bind(group)
+            .bind(now)
+            .bind(limit)
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+        if let Some(tokens) = bucket {
+            spend_tokens(&mut tx, queue_name, tokens, leased, now).await?;
+        }
+        let sql = format!(
+            "SELECT {GROUP_MESSAGE_COLUMNS}
+             FROM group_delivery gd JOIN message m ON m.id = gd.message_id
+             WHERE gd.lease_token = $1
+             ORDER BY {order}"
+        );
+        let messages = sqlx::query_as::<_, Message>(&sql)
+            .bind(&lease_token)
+            .fetch_all(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(messages)
+    }
+
+    async fn ack_group_messages(
+        &self,
+        ids: &[i64],
+        lease_token: &str,
+    ) -> sqlx::Result<Vec<i64>> {
+        if ids.is_empty() {
+            return Ok(Vec::new());
+        }
+        let now = now_ms();
+        let mut tx = self.pool.begin().await?;
+        lock_messages(&mut tx, ids).await?;
+        let acked: Vec<i64> = sqlx::query_scalar(
+            "UPDATE group_delivery SET acked_at = $1, lease_token = NULL
+             WHERE message_id = ANY($2) AND lease_token = $3
+               AND available_at > $1
+             RETURNING message_id",
+        )
+        .bind(now)
+        .bind(ids)
+        .bind(lease_token)
+        .fetch_all(&mut *tx)
+        .await?;
+        record_acks(&mut tx, &acked, now).await?;
+        delete_finished(&mut tx, &acked).await?;
+        tx.commit().await?;
+        Ok(acked)
+    }
+
+    async fn nack_group_messages(
+        &self,
+        nacks: &[(i64, i64)],
+        lease_token: &str,
+    ) -> sqlx::Result<(Vec<i64>, Vec<i64>)> {
+        if nacks.is_empty() {
+            return Ok((Vec::new(), Vec::new()));
+        }
+        let now = now_ms();
+        let (ids, delays) = split_nacks(nacks);
+        let mut tx = self.pool.begin().await?;
+        lock_messages(&mut tx, &ids).await?;
+        let nacked: Vec<(i64, i64)> = sqlx::query_as(
+            "UPDATE group_delivery gd
+             SET attempts = gd.attempts + 1, available_at = $1 + n.delay_ms,
+                 lease_token = NULL
+             FROM (SELECT id, MAX(delay_ms) AS delay_ms
+                   FROM unnest($2::BIGINT[], $3::BIGINT[]) AS n(id, delay_ms)
+                   GROUP BY id) n
+             WHERE gd.message_id = n.id AND gd.lease_token = $4
+               AND gd.available_at > $1
+             RETURNING gd.consumer_group_id, gd.message_id",
+        )
+        .bind(now)
+        .bind(&ids)
+        .bind(&delays)
+        .bind(lease_token)
+        .fetch_all(&mut *tx)
+        .await?;
+        // A lease token belongs to a single group
+        let Some(&(group_id, _)) = nacked.first() else {
+            tx.commit().await?;
+            return Ok((Vec::new(), Vec::new()));
+        };
+        let nacked_ids: Vec<i64> = nacked.iter().map(|&(_, id)| id).collect();
+        let dead: Vec<i64> = sqlx::query_scalar(
+            "UPDATE group_delivery SET dead_at = $1
+             WHERE consumer_group_id = $2 AND message_id = ANY($3)
+               AND attempts >= (
+                 SELECT q.max_attempts FROM consumer_group cg
+                 JOIN queue q ON q.id = cg.queue_id
+                 WHERE cg.id = group_delivery.consumer_group_id)
+             RETURNING message_id",
+        )
+        .bind(now)
+        .bind(group_id)
+        .bind(&nacked_ids)
+        .fetch_all(&mut *tx)
+        .await?;
+        delete_finished(&mut tx, &dead).await?;
+        tx.commit().await?;
+        let requeued = nacked
+            .into_iter()
+            .map(|(_, id)| id)
+            .filter(|id| !dead.contains(id))
+            .collect();
+        Ok((requeued, dead))
+    }
+
+    async fn extend_group_visibility(
+        &self,
+        ids: &[i64],
+        lease_token: &str,
+        extra_ms: i64,
+    ) -> sqlx::Result<u64> {
+        if ids.is_empty() {
+            return Ok(0);
+        }
+        let res = sqlx::query(
+            "UPDATE group_delivery SET available_at = available_at + $1
+             WHERE message_id = ANY($2) AND lease_token = $3
+               AND available_at > $4",
+        )
+        .bind(extra_ms.max(0))
+        .bind(ids)
+        .bind(lease_token)
+        .bind(now_ms())
+        .execute(&self.pool)
+        .await?;
+        Ok(res.rows_affected())
+    }
+
+    async fn purge_dead_letters(
+        &self,
+        queue_name: &str,
+    ) -> sqlx::Result<u64> {
+        let res = sqlx::query(
+            "DELETE FROM message
+             WHERE queue_id = (SELECT id FROM queue WHERE name = $1)
+               AND dead_at IS NOT NULL",
+        )
+        .bind(queue_name)
+        .execute(&self.pool)
+        .await?;
+        Ok(res.rows_affected())
+    }
+
+    async fn list_archived_messages(
+        &self,
+        queue_name: &str,
+        limit: i64,
+    ) -> sqlx::Result<Vec<ArchivedMessage>> {
+        sqlx::query_as::<_, ArchivedMessage>(
+            "SELECT message_id, queue_id, payload, attempts, priority, group_id, created_at, acked_at, purge_at
+             FROM message_archive
+             WHERE queue_id = (SELECT id FROM queue WHERE name = $1)
+             ORDER BY acked_at DESC, id DESC
+             LIMIT $2",
+        )
+        .bind(queue_name)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    async fn replay_archived_messages(
+        &self,
+        queue_name: &str,
+        from_ms: Option<i64>,
+        to_ms: Option<i64>,
+        payload_contains: Option<&str>,
+    ) -> sqlx::Result<u64> {
+        let res = sqlx::query(
+            "INSERT INTO message (queue_id, payload, attempts, available_at, created_at, priority, group_id)
+             SELECT queue_id, payload, 0, $1, $1, priority, group_id
+             FROM message_archive
+             WHERE queue_id = (SELECT id FROM queue WHERE name = $2)
+               AND ($3::BIGINT IS NULL OR acked_at >= $3)
+               AND ($4::BIGINT IS NULL OR acked_at < $4)
+               AND ($5::TEXT IS NULL OR strpos(payload, $5) > 0)
+             ORDER BY id",
+        )
+        .bind(now_ms())
+        .bind(queue_name)
+        .bind(from_ms)
+        .bind(to_ms)
+        .bind(payload_contains)
+        .execute(&self.pool)
+        .await?;
+        Ok(res.rows_affected())
+    }
+
+    async fn purge_archived_messages(
+        &self,
+        now_ms: i64,
+    ) -> sqlx::Result<u64> {
+        let res =
+            sqlx::query("DELETE FROM message_archive WHERE purge_at <= $1")
+                .bind(now_ms)
+                .execute(&self.pool)
+                .await?;
+        Ok(res.rows_affected())
+    }
+
+    async fn record_stats_history(
+        &self,
+        now_ms: i64,
+    ) -> sqlx::Result<u64> {
+        // The counts match those of `queue_metrics` and the stats endpoint
+        let sql = format!(
+            "INSERT INTO queue_stats_history (queue_id, recorded_at, ready, leased, delayed, dlq, enqueued, acked)
+             SELECT q.id, $1, {READY_COUNT},
+               (SELECT COUNT(*) FROM message
+                WHERE queue_id = q.id AND dead_at IS NULL
+                  AND lease_token IS NOT NULL AND available_at > $1),
+               (SELECT COUNT(*) FROM message
+                WHERE queue_id = q.id AND dead_at IS NULL
+                  AND lease_token IS NULL AND available_at > $1
+                  AND (expires_at IS NULL OR expires_at > $1)),
+               q.dead_count, q.enqueued_count, q.acked_count
+             FROM queue q",
+        );
+        let res = sqlx::query(&sql).bind(now_ms).execute(&self.pool).await?;
+        Ok(res.rows_affected())
+    }
+
+    async fn list_stats_history(
+        &self,
+        queue_name: &str,
+        since_ms: i64,
+    ) -> sqlx::Result<Vec<StatsSample>> {
+        sqlx::query_as(
+            "SELECT recorded_at, ready, leased, delayed, dlq, enqueued, acked
+             FROM queue_stats_history
+             WHERE queue_id = (SELECT id FROM queue WHERE name = $1)
+               AND recorded_at >= $2
+             ORDER BY recorded_at, id",
+        )
+        .bind(queue_name)
+        .bind(since_ms)
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    async fn purge_stats_history(
+        &self,
+        before_ms: i64,
+    ) -> sqlx::Result<u64> {
+        let res = sqlx::query(
+            "DELETE FROM queue_stats_history WHERE recorded_at < $1",
+        )
+        .bind(before_ms)
+        .execute(&self.pool)
+        .await?;
+        Ok(res.rows_affected())
+    }
+
+    async fn create_schedule(
+        &self,
+        s: &Schedule,
+    ) -> sqlx::Result<i64> {
+        sqlx::query_scalar(
+            "INSERT INTO schedule (queue_id, cron, payload, next_run_at, created_at)
+             VALUES ($1, $2, $3, $4, $5) RETURNING id",
+        )
+        .bind(s.queue_id)
+        .bind(&s.cron)
+        .bind(&s.payload)
+        .bind(s.next_run_at)
+        .bind(s.created_at)
+        .fetch_one(&self.pool)
+        .await
+    }
+
+    async fn list_schedules(
+        &self,
+        queue_name: Option<&str>,
+    ) -> sqlx::Result<Vec<Schedule>> {
+        let sql = format!(
+            "SELECT {SCHEDULE_COLUMNS}
+             FROM schedule
+             WHERE $1::TEXT IS NULL
+                OR queue_id = (SELECT id FROM queue WHERE name = $1)
+             ORDER BY id"
+        );
+        sqlx::query_as::<_, Schedule>(&sql)
+            .bind(queue_name)
+            .fetch_all(&self.pool)
+            .await
+    }
+
+    async fn delete_schedule(
+        &self,
+        id: i64,
+    ) -> sqlx::Result<bool> {
+        let res = sqlx::query("DELETE FROM schedule WHERE id = $1")
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(res.rows_affected() > 0)
+    }
+
+    async fn due_schedules(
+        &self,
+        now_ms: i64,
+    ) -> sqlx::Result<Vec<Schedule>> {
+        let sql = format!(
+            "SELECT {SCHEDULE_COLUMNS}
+             FROM schedule
+             WHERE next_run_at <= $1
+             ORDER BY next_run_at, id"
+        );
+        sqlx::query_as::<_, Schedule>(&sql)
+            .bind(now_ms)
+            .fetch_all(&self.pool)
+            .await
+    }
+
+    async fn fire_schedule(
+        &self,
+        id: i64,
+        prev_run_at: i64,
+        next_run_at: i64,
+        msg: &Message,
+    ) -> sqlx::Result<bool> {
+        let mut tx: Transaction<'_, Postgres> = self.pool.begin().await?;
+        let res = sqlx::query(
+            "UPDATE schedule SET next_run_at = $1
+             WHERE id = $2 AND next_run_at = $3",
+        )
+        .bind(next_run_at)
+        .bind(id)
+        .bind(prev_run_at)
+        .execute(&mut *tx)
+        .await?;
+        if res.rows_affected() == 0 {
+            tx.rollback().await?;
+            return Ok(false);
+        }
+        insert_message(&mut tx, msg).await?;
+        tx.commit().await?;
+        Ok(true)
+    }
+
+    async fn create_alarm(
+        &self,
+        a: &Alarm,
+    ) -> sqlx::Result<i64> {
+        sqlx::query_scalar(
+            "INSERT INTO alarm (queue_id, metric, threshold, for_ms, webhook_url, created_at)
+             VALUES ($1, $2, $3, $4, $5, $6) RETURNING id",
+        )
+        .bind(a.queue_id)
+        .bind(&a.metric)
+        .bind(a.threshold)
+        .bind(a.for_ms)
+        .bind(&a.webhook_url)
+        .bind(a.created_at)
+        .fetch_one(&self.pool)
+        .await
+    }
+
+    async fn list_alarms(
+        &self,
+        queue_name: Option<&str>,
+    ) -> sqlx::Result<Vec<Alarm>> {
+        let sql = format!(
+            "SELECT {ALARM_COLUMNS}
+             FROM alarm
+             WHERE $1::TEXT IS NULL
+                OR queue_id = (SELECT id FROM queue WHERE name = $1)
+             ORDER BY id"
+        );
+        sqlx::query_as::<_, Alarm>(&sql)
+            .bind(queue_name)
+            .fetch_all(&self.pool)
+            .await
+    }
+
+    async fn delete_alarm(
+        &self,
+        id: i64,
+    ) -> sqlx::Result<bool> {
+        let res = sqlx::query("DELETE FROM alarm WHERE id = $1")
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(res.rows_affected() > 0)
+    }
+
+    async fn set_alarm_state(
+        &self,
+        id: i64,
+        breached_since: Option<i64>,
+        firing: bool,
+    ) -> sqlx::Result<()> {
+        sqlx::query(
+            "UPDATE alarm SET breached_since = $1, firing = $2 WHERE id = $3",
+        )
+        .bind(breached_since)
+        .bind(firing)
+        .bind(id)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn create_api_key(
+        &self,
+        key: &ApiKey,
+    ) -> sqlx::Result<i64> {
+        let sql = "INSERT INTO api_key (name, key_hash, role, queue_pattern, created_at) VALUES ($1, $2, $3, $4, $5) RETURNING id";
+        sqlx::query_scalar(sql)
+            .bind(&key.name)
+            .bind(&key.key_hash)
+            .bind(&key.role)
+            .bind(&key.queue_pattern)
+            .bind(key.created_at)
+            .fetch_one(&self.pool)
+            .await
+    }
+
+    async fn list_api_keys(&self) -> sqlx::Result<Vec<ApiKey>> {
+        sqlx::query_as::<_, ApiKey>(
+            "SELECT id, name, key_hash, role, queue_pattern, created_at FROM api_key ORDER BY name",
+        )
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    async fn delete_api_key(
+        &self,
+        name: &str,
+    ) -> sqlx::Result<bool> {
+        let res = sqlx::query("DELETE FROM api_key WHERE name = $1")
+            .bind(name)
+            .execute(&self.pool)
+            .await?;
+        Ok(res.rows_affected() > 0)
+    }
+
+    async fn set_push_config(
+        &self,
+        cfg: &PushConfig,
+    ) -> sqlx::Result<PushConfig> {
+        let sql = format!(
+            "INSERT INTO push_config ({PUSH_CONFIG_COLUMNS})
+             VALUES ($1, $2, $3, $4, $5, $6, $7)
+             ON CONFLICT (queue_id) DO UPDATE SET
+               url = excluded.url, concurrency = excluded.concurrency,
+               timeout_ms = excluded.timeout_ms,
+               backoff_ms = excluded.backoff_ms,
+               updated_at = excluded.updated_at
+             RETURNING {PUSH_CONFIG_COLUMNS}"
+        );
+        sqlx::query_as::<_, PushConfig>(&sql)
+            .bind(cfg.queue_id)
+            .bind(&cfg.url)
+            .bind(cfg.concurrency)
+            .bind(cfg.timeout_ms)
+            .bind(cfg.backoff_ms)
+            .bind(cfg.created_at)
+            .bind(cfg.updated_at)
+            .fetch_one(&self.pool)
+            .await
+    }
+
+    async fn get_push_config(
+        &self,
+        queue_id: i64,
+    ) -> sqlx::Result<Option<PushConfig>> {
+        let sql = format!(
+            "SELECT {PUSH_CONFIG_COLUMNS} FROM push_config WHERE queue_id = $1"
+        );
+        sqlx::query_as::<_, PushConfig>(&sql)
+            .bind(queue_id)
+            .fetch_optional(&self.pool)
+            .await
+    }
+
+    async fn list_push_configs(&self) -> sqlx::Result<Vec<PushConfig>> {
+        let sql = format!(
+            "SELECT {PUSH_CONFIG_COLUMNS} FROM push_config ORDER BY queue_id"
+        );
+        sqlx::query_as::<_, PushConfig>(&sql).fetch_all(&self.pool).await
+    }
+
+    async fn delete_push_config(
+        &self,
+        queue_id: i64,
+    ) -> sqlx::Result<bool> {
+        let res = sqlx::query("DELETE FROM push_config WHERE queue_id = $1")
+            .bind(queue_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(res.rows_affected() > 0)
+    }
+
+    async fn record_push_deliveries(
+        &self,
+        log: &[PushDelivery],
+    ) -> sqlx::Result<()> {
+        let mut tx: Transaction<'_, Postgres> = self.pool.begin().await?;
+        for d in log {
+            sqlx::query(
+                "INSERT INTO push_delivery (queue_id, message_id, attempt, status_code, error, outcome, duration_ms, delivered_at)
+                 VALUES ($1, $2, $3, $4, $5, $6, $7, $8)",
+            )
+            .bind(d.queue_id)
+            .bind(d.message_id)
+            .bind(d.attempt)
+            .bind(d.status_code)
+            .bind(&d.error)
+            .bind(&d.outcome)
+            .bind(d.duration_ms)
+            .bind(d.delivered_at)
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await
+    }
+
+    async fn list_push_deliveries(
+        &self,
+        queue_id: i64,
+        limit: i64,
+    ) -> sqlx::Result<Vec<PushDelivery>> {
+        let sql = format!(
+            "SELECT {PUSH_DELIVERY_COLUMNS}
+             FROM push_delivery
+             WHERE queue_id = $1
+             ORDER BY id DESC
+             LIMIT $2"
+        );
+        sqlx::query_as::<_, PushDelivery>(&sql)
+            .bind(queue_id)
+            .bind(limit)
+            .fetch_all(&self.pool)
+            .await
+    }
+
+    async fn purge_push_deliveries(
+        &self,
+        before_ms: i64,
+    ) -> sqlx::Result<u64> {
+        let res =
+            sqlx::query("DELETE FROM push_delivery WHERE delivered_at < $1")
+                .bind(before_ms)
+                .execute(&self.pool)
+                .await?;
+        Ok(res.rows_affected())
+    }
+
+    async fn record_attempts(
+        &self,
+        leased: &[Message],
+        consumer: Option<&str>,
+        now_ms: i64,
+    ) -> sqlx::Result<()> {
+        if leased.is_empty() {
+            return Ok(());
+        }
+        let ids: Vec<i64> = leased.iter().map(|m| m.id).collect();
+        let mut tx: Transaction<'_, Postgres> = self.pool.begin().await?;
+        sqlx::query(
+            "UPDATE message_attempt SET outcome = 'lease_expired', settled_at = $1
+             WHERE outcome IS NULL AND message_id = ANY($2)",
+        )
+        .bind(now_ms)
+        .bind(&ids)
+        .execute(&mut *tx)
+        .await?;
+        for m in leased {
+            sqlx::query(
+                "INSERT INTO message_attempt (message_id, queue_id, attempt, lease_token, leased_at, consumer)
+                 VALUES ($1, $2, $3, $4, $5, $6)",
+            )
+            .bind(m.id)
+            .bind(m.queue_id)
+            .bind(m.attempts + 1)
+            .bind(m.lease_token.as_deref().unwrap_or_default())
+            .bind(now_ms)
+            .bind(consumer)
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await
+    }
+
+    async fn settle_attempts(
+        &self,
+        ids: &[i64],
+        lease_token: &str,
+        outcome: &str,
+        note: Option<&str>,
+        now_ms: i64,
+    ) -> sqlx::Result<()> {
+        if ids.is_empty() {
+            return Ok(());
+        }
+        sqlx::query(
+            "UPDATE message_attempt SET outcome = $1, note = $2, settled_at = $3
+             WHERE lease_token = $4 AND outcome IS NULL
+               AND message_id = ANY($5)",
+        )
+        .bind(outcome)
+        .bind(note)
+        .bind(now_ms)
+        .bind(lease_token)
+        .bind(ids)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn list_attempts(
+        &self,
+        message_id: i64,
+    ) -> sqlx::Result<Vec<MessageAttempt>> {
+        let sql = format!(
+            "SELECT {ATTEMPT_COLUMNS} FROM message_attempt
+             WHERE message_id = $1 ORDER BY id"
+        );
+        sqlx::query_as::<_, MessageAttempt>(&sql)
+            .bind(message_id)
+            .fetch_all(&self.pool)
+            .await
+    }
+
+    async fn purge_attempts(
+        &self,
+        before_ms: i64,
+    ) -> sqlx::Result<u64> {
+        let res =
+            sqlx::query("DELETE FROM message_attempt WHERE leased_at < $1")
+                .bind(before_ms)
+                .execute(&self.pool)
+                .await?;
+        Ok(res.rows_affected())
+    }
+
+    async fn list_in_flight(
+        &self,
+        queue_name: &str,
+        now_ms: i64,
+        limit: i64,
+    ) -> sqlx::Result<Vec<InFlightMessage>> {
+        sqlx::query_as(
+            "SELECT m.id AS message_id, m.public_id, m.attempts, a.consumer,
+                    a.leased_at,
+                    $1 - a.leased_at AS held_ms,
+                    m.available_at AS lease_expires_at
+             FROM message m
+             LEFT JOIN message_attempt a
+               ON a.message_id = m.id AND a.lease_token = m.lease_token
+             WHERE m.queue_id = (SELECT id FROM queue WHERE name = $2)
+               AND m.dead_at IS NULL
+               AND m.lease_token IS NOT NULL
+               AND m.available_at > $1
+             ORDER BY m.available_at, m.id
+             LIMIT $3",
+        )
+        .bind(now_ms)
+        .bind(queue_name)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    async fn create_admin_job(
+        &self,
+        job: &AdminJob,
+    ) -> sqlx::Result<AdminJob> {
+        let sql = format!(
+            "INSERT INTO admin_job
+               (kind, queue, path, batch_size, status, created_at)
+             VALUES ($1, $2, $3, $4, 'pending', $5)
+             RETURNING {ADMIN_JOB_COLUMNS}"
+        );
+        sqlx::query_as::<_, AdminJob>(&sql)
+            .bind(&job.kind)
+            .bind(&job.queue)
+            .bind(&job.path)
+            .bind(job.batch_size)
+            .bind(job.created_at)
+            .fetch_one(&self.pool)
+            .await
+    }
+
+    async fn get_admin_job(
+        &self,
+        id: i64,
+    ) -> sqlx::Result<Option<AdminJob>> {
+        let sql =
+            format!("SELECT {ADMIN_JOB_COLUMNS} FROM admin_job WHERE id = $1");
+        sqlx::query_as::<_, AdminJob>(&sql)
+            .bind(id)
+            .fetch_optional(&self.pool)
+            .await
+    }
+
+    async fn list_admin_jobs(
+        &self,
+        limit: i64,
+    ) -> sqlx::Result<Vec<AdminJob>> {
+        let sql = format!(
+            "SELECT {ADMIN_JOB_COLUMNS} FROM admin_job ORDER BY id DESC LIMIT $1"
+        );
+        sqlx::query_as::<_, AdminJob>(&sql)
+            .bind(limit)
+            .fetch_all(&self.pool)
+            .await
+    }
+
+    async fn claim_admin_job(
+        &self,
+        now: i64,
+    ) -> sqlx::Result<Option<AdminJob>> {
+        let sql = format!(
+            "UPDATE admin_job SET status = 'running', started_at = $1
+             WHERE id = (
+               SELECT id FROM admin_job WHERE status = 'pending'
+               ORDER BY id LIMIT 1
+                 FOR UPDATE SKIP LOCKED)
+             RETURNING {ADMIN_JOB_COLUMNS}"
+        );
+        sqlx::query_as::<_, AdminJob>(&sql)
+            .bind(now)
+            .fetch_optional(&self.pool)
+            .await
+    }
+
+    async fn set_admin_job_progress(
+        &self,
+        id: i64,
+        progress: i64,
+    ) -> sqlx::Result<bool> {
+        let cancel: Option<bool> = sqlx::query_scalar(
+            "UPDATE admin_job SET progress = $1 WHERE id = $2
+             RETURNING cancel_requested",
+        )
+        .bind(progress)
+        .bind(id)
+        .fetch_optional(&self.pool)
+        .await?;
+        // A job deleted from under its runner has nobody left to report to
+        Ok(cancel.unwrap_or(true))
+    }
+
+    async fn finish_admin_job(
+        &self,
+        id: i64,
+        status: &str,
+        error: Option<&str>,
+        now: i64,
+    ) -> sqlx::Result<()> {
+        sqlx::query(
+            "UPDATE admin_job SET status = $1, error = $2, finished_at = $3
+             WHERE id = $4",
+        )
+        .bind(status)
+        .bind(error)
+        .bind(now)
+        .bind(id)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn cancel_admin_job(
+        &self,
+        id: i64,
+        now: i64,
+    ) -> sqlx::Result<Option<AdminJob>> {
+        let sql = format!(
+            "UPDATE admin_job SET
+               cancel_requested = CASE WHEN status = 'running'
+                 THEN TRUE ELSE cancel_requested END,
+               finished_at = CASE WHEN status = 'pending'
+                 THEN $1 ELSE finished_at END,
+               status = CASE WHEN status = 'pending'
+                 THEN 'canceled' ELSE status END
+             WHERE id = $2
+             RETURNING {ADMIN_JOB_COLUMNS}"
+        );
+        sqlx::query_as::<_, AdminJob>(&sql)
+            .bind(now)
+            .bind(id)
+            .fetch_optional(&self.pool)
+            .await
+    }
+
+    async fn fail_running_admin_jobs(
+        &self,
+        error: &str,
+        now: i64,
+    ) -> sqlx::Result<u64> {
+        let res = sqlx::query(
+            "UPDATE admin_job SET status = 'failed', error = $1, finished_at = $2
+             WHERE status = 'running'",
+        )
+        .bind(error)
+        .bind(now)
+        .execute(&self.pool)
+        .await?;
+        Ok(res.rows_affected())
+    }
+
+    async fn purge_admin_jobs(
+        &self,
+        before_ms: i64,
+    ) -> sqlx::Result<u64> {
+        let res = sqlx::query("DELETE FROM admin_job WHERE finished_at < $1")
+            .bind(before_ms)
+            .execute(&self.pool)
+            .await?;
+        Ok(res.rows_affected())
+    }
+
+    async fn insert_audit_entry(
+        &self,
+        entry: &AuditEntry,
+    ) -> sqlx::Result<()> {
+        sqlx::query(
+            "INSERT INTO audit_log (at, actor, action, queue, params)
+             VALUES ($1, $2, $3, $4, $5)",
+        )
+        .bind(entry.at)
+        .bind(&entry.actor)
+        .bind(&entry.action)
+        .bind(&entry.queue)
+        .bind(entry.params.as_ref().map(Json))
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn list_audit_entries(
+        &self,
+        filter: &AuditFilter,
+        limit: i64,
+    ) -> sqlx::Result<Vec<AuditEntry>> {
+        let sql = format!(
+            "SELECT {AUDIT_COLUMNS} FROM audit_log
+             WHERE ($1::TEXT IS NULL OR action = $1)
+               AND ($2::TEXT IS NULL OR queue = $2)
+               AND ($3::TEXT IS NULL OR actor = $3)
+               AND ($4::BIGINT IS NULL OR at >= $4)
+             ORDER BY id DESC LIMIT $5"
+        );
+        sqlx::query_as::<_, AuditEntry>(&sql)
+            .bind(&filter.action)
+            .bind(&filter.queue)
+            .bind(&filter.actor)
+            .bind(filter.since)
+            .bind(limit)
+            .fetch_all(&self.pool)
+            .await
+    }
+
+    async fn record_queue_schema(
+        &self,
+        queue_id: i64,
+        schema: Option<&serde_json::Value>,
+        now: i64,
+    ) -> sqlx::Result<i64> {
+        let mut tx = self.pool.begin().await?;
+        let version =
+            insert_schema_version(&mut tx, queue_id, schema, now).await?;
+        tx.commit().await?;
+        Ok(version)
+    }
+
+    async fn update_queue_and_schema(
+        &self,
+        q: &Queue,
+        now: i64,
+    ) -> sqlx::Result<i64> {
+        let mut tx = self.pool.begin().await?;
+        update_queue_row(&mut tx, q).await?;
+        let version = insert_schema_version(
+            &mut tx,
+            q.id,
+            q.payload_schema.as_ref(),
+            now,
+        )
+        .await?;
+        tx.commit().await?;
+        Ok(version)
+    }
+
+    async fn get_queue_schema(
+        &self,
+        queue_id: i64,
+        version: Option<i64>,
+    ) -> sqlx::Result<Option<QueueSchema>> {
+        sqlx::query_as::<_, QueueSchema>(
+            "SELECT version, payload_schema, created_at FROM queue_schema
+             WHERE queue_id = $1 AND ($2::BIGINT IS NULL OR version = $2)
+             ORDER BY version DESC LIMIT 1",
+        )
+        .bind(queue_id)
+        .bind(version)
+        .fetch_optional(&self.pool)
+        .await
+    }
+
+    async fn list_queue_schemas(
+        &self,
+        queue_id: i64,
+    ) -> sqlx::Result<Vec<QueueSchema>> {
+        sqlx::query_as::<_, QueueSchema>(
+            "SELECT version, payload_schema, created_at FROM queue_schema
+             WHERE queue_id = $1 ORDER BY version DESC",
+        )
+        .bind(queue_id)
+        .fetch_all(&self.pool)
+        .await
+    }
+}
+
+// Overwrite the settings of queue `q.id`; see `Storage::update_queue`
+async fn update_queue_row(
+    conn: &mut PgConnection,
+    q: &Queue,
+) -> sqlx::Result<u64> {
+    let res = sqlx::query(
+        "UPDATE queue SET max_attempts = $1,
+             dedup_window_ms = $2,
+             retention_days = $3,
+             backoff_base_ms = $4,
+             backoff_multiplier = $5,
+             backoff_max_ms = $6,
+             backoff_jitter = $7,
+             default_visibility_ms = $8,
+             default_delay_ms = $9,
+             max_deliveries_per_second = $10,
+             max_payload_bytes = $11,
+             payload_schema = $12,
+             paused = $13,
+             strict_fifo = $14,
+             fair = $15,
+             max_depth = $16,
+             max_lease_expirations = $17,
+             ordering = $18,
+             schema_mode = $19
+         WHERE id = $20",
+    )
+    .bind(q.max_attempts)
+    .bind(q.dedup_window_ms)
+    .bind(q.retention_days)
+    .bind(q.backoff_base_ms)
+    .bind(q.backoff_multiplier)
+    .bind(q.backoff_max_ms)
+    .bind(q.backoff_jitter)
+    .bind(q.default_visibility_ms)
+    .bind(q.default_delay_ms)
+    .bind(q.max_deliveries_per_second)
+    .bind(q.max_payload_bytes)
+    .bind(q.payload_schema.as_ref().map(Json))
+    .bind(q.paused)
+    .bind(q.strict_fifo)
+    .bind(q.fair)
+    .bind(q.max_depth)
+    .bind(q.max_lease_expirations)
+    .bind(&q.ordering)
+    .bind(&q.schema_mode)
+    .bind(q.id)
+    .execute(&mut *conn)
+    .await?;
+    Ok(res.rows_affected())
+}
+
+// Bump a queue's `payload_schema_version` and record `schema` under it
+async fn insert_schema_version(
+    conn: &mut PgConnection,
+    queue_id: i64,
+    schema: Option<&serde_json::Value>,
+    now: i64,
+) -> sqlx::Result<i64> {
+    let version: i64 = sqlx::query_scalar(
+        "UPDATE queue SET payload_schema_version = payload_schema_version + 1
+         WHERE id = $1 RETURNING payload_schema_version",
+    )
+    .bind(queue_id)
+    .fetch_one(&mut *conn)
+    .await?;
+    sqlx::query(
+        "INSERT INTO queue_schema (queue_id, version, payload_schema, created_at)
+         VALUES ($1, $2, $3, $4)",
+    )
+    .bind(queue_id)
+    .bind(version)
+    .bind(schema.map(Json))
+    .bind(now)
+    .execute(&mut *conn)
+    .await?;
+    Ok(version)
+}
@@ -0,0 +1,4054 @@
+use super::{
+    AuditFilter, BackoffRow, CLAMP_TIMESTAMPS_SQL, DAY_MS,
+    DEFAULT_COMPRESS_THRESHOLD, DONE_BY_ALL_GROUPS, DRIFTED_COUNTERS, DbStatus,
+    DoctorReport, FAIR_MAX_KEYS, FairLanes, Keyring, ORPHAN_CHECKS, PeekFilter,
+    PollOrder, PoolOptions, QUEUE_USAGE_SQL, QueueMetrics, RECOUNT_SQL,
+    ReadyOrder, ReapedRow, RecoveryReport, RemoveFilter, SAMPLE_SHUFFLE_MAX,
+    STRANDED_LEASE_MS, Storage, WAL_OVERSIZED_BYTES, backoff_delay, now_ms,
+    quarantine_reason_sql, rate_tokens, report_quarantined, retry_busy,
+    sample_pivots, schema_problems,
+};
+use crate::models::{
+    AdminJob, Alarm, ApiKey, ArchivedMessage, AuditEntry, ConsumerGroup,
+    InFlightMessage, Message, MessageAttempt, PushConfig, PushDelivery, Queue,
+    QueueSchema, Schedule, StatsSample,
+};
+use anyhow::Context;
+use async_trait::async_trait;
+use sqlx::sqlite::{
+    SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions,
+};
+use sqlx::types::Json;
+use sqlx::{Connection, Executor, Sqlite, SqlitePool, Transaction};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::{env, fs};
+// Versioned schema migrations: applying `MIGRATIONS[i]` brings the schema to
+// version `i + 1`. Released migrations must never change; append new ones.
+const MIGRATIONS: &[&str] = &[
+    // 1: initial schema
+    r#"
+CREATE TABLE queue (
+  id            INTEGER PRIMARY KEY,
+  name          TEXT UNIQUE NOT NULL,
+  max_attempts  INTEGER NOT NULL DEFAULT 5
+);
+
+CREATE TABLE message (
+  id               INTEGER PRIMARY KEY,
+  queue_id         INTEGER NOT NULL REFERENCES queue(id) ON DELETE CASCADE,
+  payload          TEXT NOT NULL,
+  attempts         INTEGER NOT NULL DEFAULT 0,
+  available_at     INTEGER NOT NULL,
+  created_at       INTEGER NOT NULL
+);
+
+CREATE INDEX ix_msg_visible ON message(queue_id, available_at);
+"#,
+    // 2: dead letters, leases, priority, TTL, dedup, groups, schedules,
+    // retention archive and backoff
+    r#"
+ALTER TABLE queue ADD COLUMN expired_count INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE queue ADD COLUMN dedup_window_ms INTEGER NOT NULL DEFAULT 300000;
+ALTER TABLE queue ADD COLUMN retention_days INTEGER;
+ALTER TABLE queue ADD COLUMN backoff_base_ms INTEGER;
+ALTER TABLE queue ADD COLUMN backoff_multiplier REAL NOT NULL DEFAULT 2.0;
+ALTER TABLE queue ADD COLUMN backoff_max_ms INTEGER;
+ALTER TABLE queue ADD COLUMN backoff_jitter REAL NOT NULL DEFAULT 0.0;
+
+ALTER TABLE message ADD COLUMN dead_at INTEGER;
+ALTER TABLE message ADD COLUMN lease_token TEXT;
+ALTER TABLE message ADD COLUMN priority INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE message ADD COLUMN expires_at INTEGER;
+ALTER TABLE message ADD COLUMN dedup_key TEXT;
+ALTER TABLE message ADD COLUMN group_id TEXT;
+
+CREATE INDEX ix_msg_priority ON message(queue_id, priority DESC, available_at);
+CREATE INDEX ix_msg_expires ON message(expires_at) WHERE expires_at IS NOT NULL;
+CREATE UNIQUE INDEX ux_msg_dedup ON message(queue_id, dedup_key) WHERE dedup_key IS NOT NULL;
+CREATE INDEX ix_msg_dead ON message(queue_id, dead_at);
+CREATE INDEX ix_msg_group ON message(queue_id, group_id, id) WHERE group_id IS NOT NULL;
+
+CREATE TABLE schedule (
+  id               INTEGER PRIMARY KEY,
+  queue_id         INTEGER NOT NULL REFERENCES queue(id) ON DELETE CASCADE,
+  cron             TEXT NOT NULL,
+  payload          TEXT NOT NULL,
+  next_run_at      INTEGER NOT NULL,
+  created_at       INTEGER NOT NULL
+);
+
+CREATE INDEX ix_schedule_due ON schedule(next_run_at);
+
+CREATE TABLE message_archive (
+  id               INTEGER PRIMARY KEY,
+  message_id       INTEGER NOT NULL,
+  queue_id         INTEGER NOT NULL REFERENCES queue(id) ON DELETE CASCADE,
+  payload          TEXT NOT NULL,
+  attempts         INTEGER NOT NULL,
+  priority         INTEGER NOT NULL,
+  group_id         TEXT,
+  created_at       INTEGER NOT NULL,
+  acked_at         INTEGER NOT NULL,
+  purge_at         INTEGER NOT NULL
+);
+
+CREATE INDEX ix_archive_queue ON message_archive(queue_id, acked_at);
+CREATE INDEX ix_archive_purge ON message_archive(purge_at);
+"#,
+    // 3: message headers (JSON object)
+    "ALTER TABLE message ADD COLUMN headers TEXT;",
+    // 4: per-queue defaults for omitted visibility timeouts and delays
+    r#"
+ALTER TABLE queue ADD COLUMN default_visibility_ms INTEGER NOT NULL DEFAULT 30000;
+ALTER TABLE queue ADD COLUMN default_delay_ms INTEGER NOT NULL DEFAULT 0;
+"#,
+    // 5: compressed payloads; NULL means the payload is stored as plain text
+    r#"
+ALTER TABLE message ADD COLUMN payload_encoding TEXT;
+ALTER TABLE message_archive ADD COLUMN payload_encoding TEXT;
+"#,
+    // 6: consumer groups and their per-message delivery state
+    r#"
+CREATE TABLE consumer_group (
+  id               INTEGER PRIMARY KEY,
+  queue_id         INTEGER NOT NULL REFERENCES queue(id) ON DELETE CASCADE,
+  name             TEXT NOT NULL,
+  start_id         INTEGER NOT NULL,
+  created_at       INTEGER NOT NULL,
+  UNIQUE (queue_id, name)
+);
+
+CREATE TABLE group_delivery (
+  consumer_group_id INTEGER NOT NULL REFERENCES consumer_group(id) ON DELETE CASCADE,
+  message_id        INTEGER NOT NULL REFERENCES message(id) ON DELETE CASCADE,
+  attempts          INTEGER NOT NULL DEFAULT 0,
+  available_at      INTEGER NOT NULL,
+  lease_token       TEXT,
+  acked_at          INTEGER,
+  dead_at           INTEGER,
+  PRIMARY KEY (consumer_group_id, message_id)
+);
+
+CREATE INDEX ix_delivery_message ON group_delivery(message_id);
+CREATE INDEX ix_delivery_lease ON group_delivery(lease_token) WHERE lease_token IS NOT NULL;
+"#,
+    // 7: per-queue delivery rate limit and its token bucket
+    r#"
+ALTER TABLE queue ADD COLUMN max_deliveries_per_second REAL;
+ALTER TABLE queue ADD COLUMN rate_tokens REAL;
+ALTER TABLE queue ADD COLUMN rate_updated_at INTEGER;
+"#,
+    // 8: trace id correlating a message's lifecycle in logs
+    r#"
+ALTER TABLE message ADD COLUMN trace_id TEXT;
+"#,
+    // 9: queue depth and age alarms delivered to webhooks
+    r#"
+CREATE TABLE alarm (
+  id               INTEGER PRIMARY KEY,
+  queue_id         INTEGER NOT NULL REFERENCES queue(id) ON DELETE CASCADE,
+  metric           TEXT NOT NULL,
+  threshold        INTEGER NOT NULL,
+  for_ms           INTEGER NOT NULL,
+  webhook_url      TEXT NOT NULL,
+  breached_since   INTEGER,
+  firing           INTEGER NOT NULL DEFAULT 0,
+  created_at       INTEGER NOT NULL
+);
+"#,
+    // 10: enqueue and ack counters for stats
+    r#"
+ALTER TABLE queue ADD COLUMN enqueued_count INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE queue ADD COLUMN acked_count INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE queue ADD COLUMN ack_latency_ms_total INTEGER NOT NULL DEFAULT 0;
+UPDATE queue SET enqueued_count = (
+  SELECT COUNT(*) FROM message WHERE message.queue_id = queue.id
+);
+CREATE TRIGGER message_enqueued AFTER INSERT ON message
+BEGIN
+  UPDATE queue SET enqueued_count = enqueued_count + 1 WHERE id = NEW.queue_id;
+END;
+"#,
+    // 11: per-queue payload size limit and JSON Schema
+    r#"
+ALTER TABLE queue ADD COLUMN max_payload_bytes INTEGER;
+ALTER TABLE queue ADD COLUMN payload_schema TEXT;
+"#,
+    // 12: paused queues
+    r#"
+ALTER TABLE queue ADD COLUMN paused INTEGER NOT NULL DEFAULT 0;
+"#,
+    // 13: id of the key an encrypted payload was encrypted with
+    r#"
+ALTER TABLE message ADD COLUMN payload_key_id TEXT;
+ALTER TABLE message_archive ADD COLUMN payload_key_id TEXT;
+"#,
+    // 14: strict FIFO queues
+    r#"
+ALTER TABLE queue ADD COLUMN strict_fifo INTEGER NOT NULL DEFAULT 0;
+"#,
+    // 15: per-minute snapshots of queue depth and counters
+    r#"
+CREATE TABLE queue_stats_history (
+  id               INTEGER PRIMARY KEY,
+  queue_id         INTEGER NOT NULL REFERENCES queue(id) ON DELETE CASCADE,
+  recorded_at      INTEGER NOT NULL,
+  ready            INTEGER NOT NULL,
+  leased           INTEGER NOT NULL,
+  delayed          INTEGER NOT NULL,
+  dlq              INTEGER NOT NULL,
+  enqueued         INTEGER NOT NULL,
+  acked            INTEGER NOT NULL
+);
+
+CREATE INDEX ix_stats_history_queue ON queue_stats_history(queue_id, recorded_at);
+CREATE INDEX ix_stats_history_recorded ON queue_stats_history(recorded_at);
+"#,
+    // 16: push delivery of messages to HTTP endpoints, and its delivery log
+    r#"
+CREATE TABLE push_config (
+  queue_id         INTEGER PRIMARY KEY REFERENCES queue(id) ON DELETE CASCADE,
+  url              TEXT NOT NULL,
+  concurrency      INTEGER NOT NULL,
+  timeout_ms       INTEGER NOT NULL,
+  backoff_ms       INTEGER NOT NULL,
+  created_at       INTEGER NOT NULL,
+  updated_at       INTEGER NOT NULL
+);
+
+CREATE TABLE push_delivery (
+  id               INTEGER PRIMARY KEY,
+  queue_id         INTEGER NOT NULL REFERENCES queue(id) ON DELETE CASCADE,
+  message_id       INTEGER NOT NULL,
+  attempt          INTEGER NOT NULL,
+  status_code      INTEGER,
+  error            TEXT,
+  outcome          TEXT NOT NULL,
+  duration_ms      INTEGER NOT NULL,
+  delivered_at     INTEGER NOT NULL
+);
+
+CREATE INDEX ix_push_delivery_queue ON push_delivery(queue_id, id);
+CREATE INDEX ix_push_delivery_delivered ON push_delivery(delivered_at);
+"#,
+    // 17: per-queue cap on unacked messages
+    r#"
+ALTER TABLE queue ADD COLUMN max_depth INTEGER;
+"#,
+    // 18: log of each message delivery and how it ended
+    r#"
+CREATE TABLE message_attempt (
+  id               INTEGER PRIMARY KEY,
+  message_id       INTEGER NOT NULL,
+  queue_id         INTEGER NOT NULL REFERENCES queue(id) ON DELETE CASCADE,
+  attempt          INTEGER NOT NULL,
+  lease_token      TEXT NOT NULL,
+  leased_at        INTEGER NOT NULL,
+  consumer         TEXT,
+  outcome          TEXT,
+  note             TEXT,
+  settled_at       INTEGER
+);
+
+CREATE INDEX ix_attempt_message ON message_attempt(message_id, id);
+CREATE INDEX ix_attempt_leased ON message_attempt(leased_at);
+"#,
+    // 19: why a message was last nacked
+    r#"
+ALTER TABLE message ADD COLUMN last_error TEXT;
+"#,
+    // 20: live and dead message counters, kept by triggers
+    r#"
+ALTER TABLE queue ADD COLUMN message_count INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE queue ADD COLUMN dead_count INTEGER NOT NULL DEFAULT 0;
+UPDATE queue SET
+  message_count = (SELECT COUNT(*) FROM message
+                   WHERE message.queue_id = queue.id AND dead_at IS NULL),
+  dead_count = (SELECT COUNT(*) FROM message
+                WHERE message.queue_id = queue.id AND dead_at IS NOT NULL);
+CREATE TRIGGER message_counted AFTER INSERT ON message
+BEGIN
+  UPDATE queue SET message_count = message_count + (NEW.dead_at IS NULL),
+                   dead_count = dead_count + (NEW.dead_at IS NOT NULL)
+  WHERE id = NEW.queue_id;
+END;
+CREATE TRIGGER message_uncounted AFTER DELETE ON message
+BEGIN
+  UPDATE queue SET message_count = message_count - (OLD.dead_at IS NULL),
+                   dead_count = dead_count - (OLD.dead_at IS NOT NULL)
+  WHERE id = OLD.queue_id;
+END;
+CREATE TRIGGER message_recounted AFTER UPDATE OF queue_id, dead_at ON message
+WHEN OLD.queue_id IS NOT NEW.queue_id
+  OR (OLD.dead_at IS NULL) IS NOT (NEW.dead_at IS NULL)
+BEGIN
+  UPDATE queue SET message_count = message_count - (OLD.dead_at IS NULL),
+                   dead_count = dead_count - (OLD.dead_at IS NOT NULL)
+  WHERE id = OLD.queue_id;
+  UPDATE queue SET message_count = message_count + (NEW.dead_at IS NULL),
+                   dead_count = dead_count + (NEW.dead_at IS NOT NULL)
+  WHERE id = NEW.queue_id;
+END;
+"#,
+    // 21: API keys managed from the CLI, stored as SHA-256 hashes
+    r#"
+CREATE TABLE api_key (
+  id               INTEGER PRIMARY KEY,
+  name             TEXT NOT NULL UNIQUE,
+  key_hash         TEXT NOT NULL UNIQUE,
+  role             TEXT NOT NULL,
+  queue_pattern    TEXT,
+  created_at       INTEGER NOT NULL
+);
+"#,
+    // 22: fair polling across the messages' tenant keys
+    r#"
+ALTER TABLE message ADD COLUMN fair_key TEXT;
+ALTER TABLE queue ADD COLUMN fair INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE queue ADD COLUMN fair_cursor TEXT;
+CREATE INDEX ix_msg_fair ON message(queue_id, fair_key, priority DESC, available_at) WHERE fair_key IS NOT NULL;
+"#,
+    // 23: quarantine of messages whose leases keep expiring
+    r#"
+ALTER TABLE message ADD COLUMN lease_expirations INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE queue ADD COLUMN max_lease_expirations INTEGER;
+"#,
+    // 24: soft-deleted queues kept in the trash until purged
+    r#"
+ALTER TABLE queue ADD COLUMN deleted_at INTEGER;
+"#,
+    // 25: background admin jobs
+    r#"
+CREATE TABLE admin_job (
+  id               INTEGER PRIMARY KEY,
+  kind             TEXT NOT NULL,
+  queue            TEXT,
+  path             TEXT,
+  batch_size       INTEGER NOT NULL,
+  status           TEXT NOT NULL,
+  progress         INTEGER NOT NULL DEFAULT 0,
+  error            TEXT,
+  cancel_requested INTEGER NOT NULL DEFAULT 0,
+  created_at       INTEGER NOT NULL,
+  started_at       INTEGER,
+  finished_at      INTEGER
+);
+CREATE INDEX ix_admin_job_status ON admin_job(status, id);
+"#,
+    // 26: ordering ready messages by FIFO, priority or a sort key
+    r#"
+ALTER TABLE message ADD COLUMN sort_key REAL;
+ALTER TABLE queue ADD COLUMN ordering TEXT NOT NULL DEFAULT 'priority';
+CREATE INDEX ix_msg_sort_key ON message(queue_id, sort_key, available_at) WHERE sort_key IS NOT NULL;
+"#,
+    // 27: delivery receipts sent to a reply queue
+    r#"
+ALTER TABLE message ADD COLUMN reply_to TEXT;
+"#,
+    // 28: public message ids, time-ordered UUIDs (version 7) from the
+    // creation time. Enqueues set them; the trigger covers rows copied by
+    // SQL, such as receipts, clones and replays, and existing rows.
+    r#"
+ALTER TABLE message ADD COLUMN public_id TEXT;
+UPDATE message SET public_id = lower(printf('%08x-%04x-7%s-%s%s-%s',
+  created_at >> 16, created_at & 65535, substr(hex(randomblob(2)), 2),
+  substr('89ab', abs(random() % 4) + 1, 1), substr(hex(randomblob(2)), 2),
+  hex(randomblob(6))));
+CREATE UNIQUE INDEX ux_msg_public_id ON message(public_id);
+CREATE TRIGGER message_public_id AFTER INSERT ON message
+WHEN NEW.public_id IS NULL
+BEGIN
+  UPDATE message SET public_id = lower(printf('%08x-%04x-7%s-%s%s-%s',
+    NEW.created_at >> 16, NEW.created_at & 65535,
+    substr(hex(randomblob(2)), 2), substr('89ab', abs(random() % 4) + 1, 1),
+    substr(hex(randomblob(2)), 2), hex(randomblob(6))))
+  WHERE id = NEW.id;
+END;
+"#,
+    // 29: append-only audit log of administrative actions
+    r#"
+CREATE TABLE audit_log (
+  id               INTEGER PRIMARY KEY,
+  at               INTEGER NOT NULL,
+  actor            TEXT NOT NULL,
+  action           TEXT NOT NULL,
+  queue            TEXT,
+  params           TEXT
+);
+CREATE INDEX ix_audit_log_at ON audit_log(at);
+CREATE TRIGGER audit_log_no_update BEFORE UPDATE ON audit_log
+BEGIN
+  SELECT RAISE(ABORT, 'audit_log is append-only');
+END;
+CREATE TRIGGER audit_log_no_delete BEFORE DELETE ON audit_log
+BEGIN
+  SELECT RAISE(ABORT, 'audit_log is append-only');
+END;
+"#,
+    // 30: versioned payload schemas, and tagging payloads that break them
+    r#"
+ALTER TABLE queue ADD COLUMN schema_mode TEXT NOT NULL DEFAULT 'reject';
+ALTER TABLE queue ADD COLUMN payload_schema_version INTEGER NOT NULL DEFAULT 0;
+CREATE TABLE queue_schema (
+  queue_id         INTEGER NOT NULL REFERENCES queue(id) ON DELETE CASCADE,
+  version          INTEGER NOT NULL,
+  payload_schema   TEXT,
+  created_at       INTEGER NOT NULL,
+  PRIMARY KEY (queue_id, version)
+);
+INSERT INTO queue_schema (queue_id, version, payload_schema, created_at)
+  SELECT id, 1, payload_schema, CAST(strftime('%s', 'now') AS INTEGER) * 1000
+  FROM queue WHERE payload_schema IS NOT NULL;
+UPDATE queue SET payload_schema_version = 1 WHERE payload_schema IS NOT NULL;
+"#,
+];
+
+// Columns selected whenever a `Queue` row is loaded
+const QUEUE_COLUMNS: &str = "id, name, max_attempts, dedup_window_ms, \
+                             retention_days, backoff_base_ms, \
+                             backoff_multiplier, backoff_max_ms, \
+                             backoff_jitter, default_visibility_ms, \
+                             default_delay_ms, max_deliveries_per_second, \
+                             max_payload_bytes, payload_schema, \
+                             schema_mode, payload_schema_version, paused, \
+                             strict_fifo, fair, ordering, max_depth, \
+                             max_lease_expirations, deleted_at";
+
+// Columns selected whenever a full `Message` row is loaded (as a
+// `Packed<Message>`). The lease token is only handed out by poll, so other
+// reads never expose it.
+const MESSAGE_COLUMNS: &str = "id, queue_id, attempts, available_at, \
+                               created_at, dead_at, NULL AS lease_token, \
+                               priority, expires_at, dedup_key, group_id, \
+                               headers, trace_id, last_error, fair_key, \
+                               lease_expirations, sort_key, reply_to, \
+                               public_id, \
+                               CASE WHEN payload_encoding IS NULL \
+                                 THEN payload ELSE '' END AS payload, \
+                               CASE WHEN payload_encoding IS NOT NULL \
+                                 THEN payload END AS packed_payload, \
+                               payload_encoding, payload_key_id";
+const LEASED_MESSAGE_COLUMNS: &str = "id, queue_id, attempts, \
+                                      available_at, created_at, dead_at, \
+                                      lease_token, priority, expires_at, \
+                                      dedup_key, group_id, headers, \
+                                      trace_id, last_error, fair_key, \
+                                      lease_expirations, sort_key, \
+                                      reply_to, public_id, \
+                                      CASE WHEN payload_encoding IS NULL \
+                                        THEN payload ELSE '' END AS payload, \
+                                      CASE WHEN payload_encoding IS NOT NULL \
+                                        THEN payload END AS packed_payload, \
+                                      payload_encoding, payload_key_id";
+// Columns of a message leased to a consumer group, from `message m` joined
+// with its `group_delivery gd` row
+const GROUP_MESSAGE_COLUMNS: &str = "m.id, m.queue_id, gd.attempts, \
+                                     gd.available_at, m.created_at, \
+                                     gd.dead_at, gd.lease_token, m.priority, \
+                                     m.expires_at, m.dedup_key, m.group_id, \
+                                     m.headers, m.trace_id, m.fair_key, \
+                                     m.sort_key, m.reply_to, m.public_id, \
+                                     CASE WHEN m.payload_encoding IS NULL \
+                                       THEN m.payload ELSE '' END AS payload, \
+                                     CASE WHEN m.payload_encoding IS NOT NULL \
+                                       THEN m.payload END AS packed_payload, \
+                                     m.payload_encoding, m.payload_key_id";
+const ARCHIVED_MESSAGE_COLUMNS: &str = "message_id, queue_id, attempts, \
+                                        priority, group_id, created_at, \
+                                        acked_at, purge_at, \
+                                        CASE WHEN payload_encoding IS NULL \
+                                          THEN payload ELSE '' END AS payload, \
+                                        CASE WHEN payload_encoding IS NOT NULL \
+                                          THEN payload END AS packed_payload, \
+                                        payload_encoding, payload_key_id";
+
+const ALARM_COLUMNS: &str = "id, queue_id, metric, threshold, for_ms, \
+                             webhook_url, breached_since, firing, created_at";
+const ADMIN_JOB_COLUMNS: &str = "id, kind, queue, path, batch_size, status, \
+                                 progress, error, cancel_requested, \
+                                 created_at, started_at, finished_at";
+const AUDIT_COLUMNS: &str = "id, at, actor, action, queue, params";
+const PUSH_CONFIG_COLUMNS: &str = "queue_id, url, concurrency, timeout_ms, \
+                                   backoff_ms, created_at, updated_at";
+const PUSH_DELIVERY_COLUMNS: &str = "id, queue_id, message_id, attempt, \
+                                     status_code, error, outcome, \
+                                     duration_ms, delivered_at";
+const ATTEMPT_COLUMNS: &str = "id, message_id, queue_id, attempt, leased_at, \
+                               consumer, outcome, note, settled_at";
+
+// Values of `payload_encoding` for zstd-compressed payloads, encrypted
+// payloads, and payloads compressed then encrypted
+const ZSTD_ENCODING: &str = "zstd";
+const AES_GCM_ENCODING: &str = "aes-gcm";
+const ZSTD_AES_GCM_ENCODING: &str = "zstd+aes-gcm";
+
+// zstd level used for payloads; favours speed over ratio
+const ZSTD_LEVEL: i32 = 3;
+
+// Rows loaded per batch by `recompress_payloads` and `rotate_payload_key`
+const RECOMPRESS_BATCH: i64 = 500;
+
+// Ready messages of queue `q` at `?1`: its live message counter less the
+// live messages not yet visible and the expired ones not yet swept. Both
+// are range scans over messages in flight, never the whole backlog.
+const READY_COUNT: &str = "(q.message_count
+   - (SELECT COUNT(*) FROM message INDEXED BY ix_msg_visible
+      WHERE queue_id = q.id AND available_at > ?1 AND dead_at IS NULL)
+   - (SELECT COUNT(*) FROM message INDEXED BY ix_msg_expires
+      WHERE expires_at <= ?1 AND queue_id = q.id
+        AND available_at <= ?1 AND dead_at IS NULL))";
+
+// A stored payload as read for re-encryption: id, payload bytes, encoding and
+// key id
+type StoredPayload = (i64, Vec<u8>, Option<String>, Option<String>);
+
+// A row whose payload may be stored packed: plain payloads arrive in the
+// row's `payload`, compressed or encrypted ones as bytes in `packed_payload`
+#[derive(sqlx::FromRow)]
+struct Packed<T> {
+    #[sqlx(flatten)]
+    row: T,
+    packed_payload: Option<Vec<u8>>,
+    payload_encoding: Option<String>,
+    payload_key_id: Option<String>,
+}
+
+impl<T> Packed<T> {
+    // The row's payload once unpacked, or `None` if it is stored plain
+    fn unpacked_payload(
+        &self,
+        codec: &PayloadCodec,
+    ) -> sqlx::Result<Option<String>> {
+        let (Some(bytes), Some(encoding)) =
+            (&self.packed_payload, &self.payload_encoding)
+        else {
+            return Ok(None);
+        };
+        codec.unpack(bytes, encoding, self.payload_key_id.as_deref()).map(Some)
+    }
+}
+
+impl Packed<Message> {
+    fn unpack(
+        self,
+        codec: &PayloadCodec,
+    ) -> sqlx::Result<Message> {
+        let payload = self.unpacked_payload(codec)?;
+        let mut msg = self.row;
+        if let Some(payload) = payload {
+            msg.payload = payload;
+        }
+        Ok(msg)
+    }
+}
+
+impl Packed<ArchivedMessage> {
+    fn unpack(
+        self,
+        codec: &PayloadCodec,
+    ) -> sqlx::Result<ArchivedMessage> {
+        let payload = self.unpacked_payload(codec)?;
+        let mut msg = self.row;
+        if let Some(payload) = payload {
+            msg.payload = payload;
+        }
+        Ok(msg)
+    }
+}
+
+// How payloads are stored: compressed when larger than `compress_threshold`
+// bytes (0 disables compression), then encrypted when there is a keyring
+#[derive(Clone)]
+struct PayloadCodec {
+    compress_threshold: usize,
+    keyring: Option<Arc<Keyring>>,
+}
+
+// A payload in stored form, with its `payload_encoding` and key id
+struct PackedPayload {
+    bytes: Vec<u8>,
+    encoding: &'static str,
+    key_id: Option<String>,
+}
+
+impl PayloadCodec {
+    // The stored form of `payload`, or `None` to store it plain
+    fn pack(
+        &self,
+        payload: &str,
+    ) -> sqlx::Result<Option<PackedPayload>> {
+        let compressed = compress_payload(payload, self.compress_threshold)?;
+        let Some(keyring) = &self.keyring else {
+            return Ok(compressed.map(|bytes| PackedPayload {
+                bytes,
+                encoding: ZSTD_ENCODING,
+                key_id: None,
+            }));
+        };
+        let (plain, encoding) = match &compressed {
+            Some(bytes) => (bytes.as_slice(), ZSTD_AES_GCM_ENCODING),
+            None => (payload.as_bytes(), AES_GCM_ENCODING),
+        };
+        Ok(Some(PackedPayload {
+            bytes: keyring.encrypt(plain)?,
+            encoding,
+            key_id: Some(keyring.active_id().to_string()),
+        }))
+    }
+
+    // Reverse `pack` for a payload stored with `encoding`
+    fn unpack(
+        &self,
+        bytes: &[u8],
+        encoding: &str,
+        key_id: Option<&str>,
+    ) -> sqlx::Result<String> {
+        let plain;
+        let bytes = match encoding {
+            AES_GCM_ENCODING | ZSTD_AES_GCM_ENCODING => {
+                let keyring = self.keyring.as_ref().ok_or_else(|| {
+                    sqlx::Error::Decode(
+                        "payload is encrypted but no encryption key is configured"
+                            .into(),
+                    )
+                })?;
+                plain = keyring.decrypt(key_id.unwrap_or_default(), bytes)?;
+                plain.as_slice()
+            }
+            _ => bytes,
+        };
+        match encoding {
+            ZSTD_ENCODING | ZSTD_AES_GCM_ENCODING => decompress_payload(bytes),
+            _ => String::from_utf8(bytes.to_vec())
+                .map_err(|e| sqlx::Error::Decode(Box::new(e))),
+        }
+    }
+
+    // Unpack every row of a message query
+    fn unpack_all(
+        &self,
+        rows: Vec<Packed<Message>>,
+    ) -> sqlx::Result<Vec<Message>> {
+        rows.into_iter().map(|row| row.unpack(self)).collect()
+    }
+}
+
+// The zstd-compressed form of `payload` if it is larger than `threshold`
+// bytes (0 disables compression) and compression actually shrinks it
+fn compress_payload(
+    payload: &str,
+    threshold: usize,
+) -> sqlx::Result<Option<Vec<u8>>> {
+    if threshold == 0 || payload.len() <= threshold {
+        return Ok(None);
+    }
+    let packed = zstd::encode_all(payload.as_bytes(), ZSTD_LEVEL)
+        .map_err(|e| sqlx::Error::Encode(Box::new(e)))?;
+    Ok((packed.len() < payload.len()).then_some(packed))
+}
+
+fn decompress_payload(bytes: &[u8]) -> sqlx::Result<String> {
+    let raw = zstd::decode_all(bytes)
+        .map_err(|e| sqlx::Error::Decode(Box::new(e)))?;
+    String::from_utf8(raw).map_err(|e| sqlx::Error::Decode(Box::new(e)))
+}
+
+/// How long a connection waits on a locked database before failing
+pub const BUSY_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// Initialize the SQLite connection pool.
+pub async fn init_pool() -> anyhow::Result<SqlitePool> {
+    let current_dir =
+        env::current_dir().context("Failed to get current directory")?;
+    let db_file = current_dir.join("sqew.db");
+    init_pool_at(&db_file, &PoolOptions::default()).await
+}
+
+/// Path that selects a private in-memory database instead of a file
+pub const MEMORY_PATH: &str = ":memory:";
+
+/// Whether `path` selects an in-memory database ([`MEMORY_PATH`])
+pub fn is_memory(path: &Path) -> bool {
+    path.as_os_str() == MEMORY_PATH
+}
+
+/// Initialize the SQLite connection pool at a specific path.
+///
+/// Every connection runs in WAL mode with `synchronous=NORMAL`, a busy
+/// timeout and foreign keys enabled, so concurrent producers and consumers
+/// wait on each other instead of failing with "database is locked".
+///
+/// For [`MEMORY_PATH`] the pool opens a fresh, uniquely named shared-cache
+/// in-memory database instead. Shared-cache connections lock whole tables
+/// and fail with "deadlocked" rather than waiting, so the pool holds a single
+/// connection, kept open for its lifetime; the database is gone once the pool
+/// is closed.
+pub async fn init_pool_at(
+    path: &Path,
+    opts: &PoolOptions,
+) -> anyhow::Result<SqlitePool> {
+    if is_memory(path) {
+        let db_url = format!(
+            "sqlite:file:sqew-{}?mode=memory&cache=shared",
+            uuid::Uuid::new_v4()
+        );
+        let connect_opts = SqliteConnectOptions::from_str(&db_url)
+            .context("Invalid SQLite URL")?
+            .busy_timeout(BUSY_TIMEOUT)
+            .foreign_keys(true)
+            .statement_cache_capacity(opts.statement_cache_size);
+        let pool = SqlitePoolOptions::new()
+            .max_connections(1)
+            .min_connections(1)
+            .acquire_timeout(opts.acquire_timeout)
+            .idle_timeout(None)
+            .max_lifetime(None)
+            .connect_with(connect_opts)
+            .await
+            .context("Failed to open the in-memory database")?;
+        return Ok(pool);
+    }
+    let db_url = format!("sqlite://{}", path.to_string_lossy());
+    // Configure SQLite for better concurrency under load
+    let connect_opts = SqliteConnectOptions::from_str(&db_url)
+        .context("Invalid SQLite URL")?
+        .journal_mode(SqliteJournalMode::Wal)
+        .busy_timeout(BUSY_TIMEOUT)
+        .synchronous(sqlx::sqlite::SqliteSynchronous::Normal)
+        .foreign_keys(true)
+        .statement_cache_capacity(opts.statement_cache_size);
+    let pool = SqlitePoolOptions::new()
+        .max_connections(opts.max_connections.max(1))
+        .acquire_timeout(opts.acquire_timeout)
+        .connect_with(connect_opts)
+        .await
+        .context("Failed to connect to the database")?;
+    // Set WAL autocheckpoint to a reasonable value
+    sqlx::query("PRAGMA wal_autocheckpoint = 1000;").execute(&pool).await.ok();
+    Ok(pool)
+}
+
+/// Open a read-only pool of `opts.read_connections` on the database file at
+/// `path`, which must already exist in WAL mode. Its readers see every
+/// committed write without contending with writers for connections.
+pub async fn init_read_pool_at(
+    path: &Path,
+    opts: &PoolOptions,
+) -> anyhow::Result<SqlitePool> {
+    let connect_opts = SqliteConnectOptions::new()
+        .filename(path)
+        .read_only(true)
+        .busy_timeout(BUSY_TIMEOUT)
+        .statement_cache_capacity(opts.statement_cache_size);
+    SqlitePoolOptions::new()
+        .max_connections(opts.read_connections.max(1))
+        .acquire_timeout(opts.acquire_timeout)
+        .connect_with(connect_opts)
+        .await
+        .context("Failed to open the read-only database pool")
+}
+
+/// Create the database file (if missing) and run initial migrations.
+pub async fn create_db_if_needed() -> anyhow::Result<()> {
+    let current_dir =
+        env::current_dir().context("Failed to get current directory")?;
+    let db_file = current_dir.join("sqew.db");
+    create_db_if_needed_at(&db_file, false).await
+}
+
+/// Create the database file at the given path (if missing) and bring its
+/// schema up to date. If `force_recreate` is true, delete any existing file
+/// first.
+pub async fn create_db_if_needed_at(
+    path: &Path,
+    force_recreate: bool,
+) -> anyhow::Result<()> {
+    if force_recreate && path.exists() {
+        fs::remove_file(path).with_context(|| {
+            format!("Failed to delete DB at {}", path.display())
+        })?;
+    }
+    if !path.exists() {
+        fs::File::create(path).with_context(|| {
+            format!("Failed to create DB file at {}", path.display())
+        })?;
+    }
+    let db_url = format!("sqlite://{}", path.to_string_lossy());
+    let pool = SqlitePool::connect(&db_url)
+        .await
+        .context("Failed to connect to the database for initialization")?;
+    migrate(&pool).await.context("Failed to migrate database schema")?;
+    pool.close().await;
+    Ok(())
+}
+
+/// Replace the database file at `path` with a copy of the SQLite backup at
+/// `backup`, discarding the old file's WAL. The backup is checked first and
+/// the old file is only replaced once the copy is complete. Nothing may have
+/// the database open while it is restored.
+pub async fn restore_db_at(
+    path: &Path,
+    backup: &Path,
+) -> anyhow::Result<()> {
+    let opts = SqliteConnectOptions::new().filename(backup).read_only(true);
+    let mut conn =
+        sqlx::SqliteConnection::connect_with(&opts).await.with_context(
+            || format!("Failed to open backup {}", backup.display()),
+        )?;
+    let check: String = sqlx::query_scalar("PRAGMA quick_check")
+        .fetch_one(&mut conn)
+        .await
+        .with_context(|| {
+            format!(
+                "Invalid backup {}: not a SQLite database",
+                backup.display()
+            )
+        })?;
+    let has_queues: bool = sqlx::query_scalar(
+        "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'queue')",
+    )
+    .fetch_one(&mut conn)
+    .await?;
+    conn.close().await?;
+    if check != "ok" {
+        anyhow::bail!("Invalid backup {}: {}", backup.display(), check);
+    }
+    if !has_queues {
+        anyhow::bail!(
+            "Invalid backup {}: not a sqew database",
+            backup.display()
+        );
+    }
+
+    let mut staged = path.as_os_str().to_owned();
+    staged.push(".restore");
+    fs::copy(backup, &staged).with_context(|| {
+        format!("Failed to copy backup next to {}", path.display())
+    })?;
+    for suffix in ["-wal", "-shm"] {
+        let mut side = path.as_os_str().to_owned();
+        side.push(suffix);
+        if Path::new(&side).exists() {
+            fs::remove_file(&side).with_context(|| {
+                format!("Failed to remove {}", Path::new(&side).display())
+            })?;
+        }
+    }
+    fs::rename(&staged, path).with_context(|| {
+        format!("Failed to replace DB at {}", path.display())
+    })?;
+    Ok(())
+}
+
+/// Apply pending schema migrations, returning the versions applied.
+///
+/// Databases created before schema versioning have no `schema_version`
+/// table; they are recognised by their `queue` table and treated as
+/// version 1.
+pub async fn migrate(pool: &SqlitePool) -> sqlx::Result<Vec<i64>> {
+    let mut conn = pool.acquire().await?;
+    // IMMEDIATE takes the write lock up front, so concurrent processes
+    // migrate one at a time and each sees the others' progress
+    let mut tx = conn.begin_with("BEGIN IMMEDIATE").await?;
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS schema_version (
+           version    INTEGER PRIMARY KEY,
+           applied_at INTEGER NOT NULL
+         )",
+    )
+    .execute(&mut *tx)
+    .await?;
+    let mut current: i64 = sqlx::query_scalar(
+        "SELECT COALESCE(MAX(version), 0) FROM schema_version",
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+    if current == 0 {
+        let legacy: bool = sqlx::query_scalar(
+            "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'queue')",
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+        if legacy {
+            current = 1;
+            record_version(&mut tx, current).await?;
+        }
+    }
+    let mut applied = Vec::new();
+    for (i, sql) in MIGRATIONS.iter().enumerate().skip(current as usize) {
+        let version = i as i64 + 1;
+        tx.execute(*sql).await?;
+        record_version(&mut tx, version).await?;
+        applied.push(version);
+    }
+    tx.commit().await?;
+    Ok(applied)
+}
+
+// Record that the schema reached `version`
+async fn record_version(
+    tx: &mut Transaction<'_, Sqlite>,
+    version: i64,
+) -> sqlx::Result<()> {
+    sqlx::query(
+        "INSERT INTO schema_version (version, applied_at) VALUES (?, ?)",
+    )
+    .bind(version)
+    .bind(now_ms())
+    .execute(&mut **tx)
+    .await?;
+    Ok(())
+}
+
+/// SQLite storage: one database file, shared through a WAL-mode pool.
+///
+/// Payloads larger than the compression threshold are stored
+/// zstd-compressed (marked by the row's `payload_encoding`) and decompressed
+/// transparently on read. With a [`Keyring`], every payload is also
+/// encrypted, and the row records the id of the key used.
+#[derive(Clone)]
+pub struct SqliteStorage {
+    pool: SqlitePool,
+    read_pool: Option<SqlitePool>,
+    codec: PayloadCodec,
+}
+
+impl SqliteStorage {
+    /// Wrap an existing pool
+    pub fn new(pool: SqlitePool) -> Self {
+        let codec = PayloadCodec {
+            compress_threshold: DEFAULT_COMPRESS_THRESHOLD,
+            keyring: None,
+        };
+        SqliteStorage { pool, read_pool: None, codec }
+    }
+
+    /// Serve peeks, searches and stats from `pool`, a read-only pool on the
+    /// same database (see [`init_read_pool_at`])
+    pub fn with_read_pool(
+        mut self,
+        pool: SqlitePool,
+    ) -> Self {
+        self.read_pool = Some(pool);
+        self
+    }
+
+    /// Compress payloads larger than `bytes` on write; 0 disables
+    /// compression. Already stored payloads are read either way.
+    pub fn with_compress_threshold(
+        mut self,
+        bytes: usize,
+    ) -> Self {
+        self.codec.compress_threshold = bytes;
+        self
+    }
+
+    /// Encrypt payloads on write with the keyring's active key. Payloads
+    /// stored plain, or under any of its keys, are read either way.
+    pub fn with_keyring(
+        mut self,
+        keyring: Arc<Keyring>,
+    ) -> Self {
+        self.codec.keyring = Some(keyring);
+        self
+    }
+
+    /// Create the database file at `path` if needed (recreating it when
+    /// `force_recreate` is set) and open its pools as `opts` says.
+    /// [`MEMORY_PATH`] opens a new, empty in-memory database, which never
+    /// has a read-only pool.
+    pub async fn open(
+        path: &Path,
+        force_recreate: bool,
+        opts: &PoolOptions,
+    ) -> anyhow::Result<Self> {
+        if is_memory(path) {
+            let pool = init_pool_at(path, opts).await?;
+            migrate(&pool)
+                .await
+                .context("Failed to migrate database schema")?;
+            return Ok(Self::new(pool));
+        }
+        create_db_if_needed_at(path, force_recreate).await?;
+        let storage = Self::new(init_pool_at(path, opts).await?);
+        if opts.read_connections == 0 {
+            return Ok(storage);
+        }
+        Ok(storage.with_read_pool(init_read_pool_at(path, opts).await?))
+    }
+
+    /// The underlying connection pool
+    pub fn pool(&self) -> &SqlitePool {
+        &self.pool
+    }
+
+    // The pool for read-only queries: the read pool when there is one
+    fn reader(&self) -> &SqlitePool {
+        self.read_pool.as_ref().unwrap_or(&self.pool)
+    }
+
+    // Size of the write-ahead log; `None` without one
+    async fn wal_bytes(&self) -> sqlx::Result<Option<i64>> {
+        // Empty for an in-memory database
+        let file: String = sqlx::query_scalar(
+            "SELECT file FROM pragma_database_list WHERE name = 'main'",
+        )
+        .fetch_one(&self.pool)
+        .await?;
+        if file.is_empty() {
+            return Ok(None);
+        }
+        Ok(std::fs::metadata(format!("{file}-wal"))
+            .ok()
+            .map(|m| m.len() as i64))
+    }
+}
+
+// The public id to store for `msg`; NULL leaves it to the
+// `message_public_id` trigger
+fn public_id(msg: &Message) -> Option<&str> {
+    (!msg.public_id.is_empty()).then_some(msg.public_id.as_str())
+}
+
+// Insert a message row on the given connection, returning its id. The
+// payload is stored packed as `codec` decides.
+async fn insert_message(
+    conn: &mut sqlx::SqliteConnection,
+    msg: &Message,
+    codec: &PayloadCodec,
+) -> sqlx::Result<i64> {
+    let packed = codec.pack(&msg.payload)?;
+    let q = sqlx::query(
+        "INSERT INTO message (queue_id, payload, payload_encoding, payload_key_id, attempts, available_at, created_at, dead_at, priority, expires_at, dedup_key, group_id, headers, trace_id, fair_key, sort_key, reply_to, public_id) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+    )
+    .bind(msg.queue_id);
+    let q = match packed {
+        Some(p) => q.bind(p.bytes).bind(p.encoding).bind(p.key_id),
+        None => q.bind(&msg.payload).bind(None::<&str>).bind(None::<&str>),
+    };
+    let rec = q
+        .bind(msg.attempts)
+        .bind(msg.available_at)
+        .bind(msg.created_at)
+        .bind(msg.dead_at)
+        .bind(msg.priority)
+        .bind(msg.expires_at)
+        .bind(&msg.dedup_key)
+        .bind(&msg.group_id)
+        .bind(msg.headers.as_ref().map(Json))
+        .bind(&msg.trace_id)
+        .bind(&msg.fair_key)
+        .bind(msg.sort_key)
+        .bind(&msg.reply_to)
+        .bind(public_id(msg))
+        .execute(&mut *conn)
+        .await?;
+    Ok(rec.last_insert_rowid())
+}
+
+// Insert a message carrying a dedup key unless a message of its queue created
+// within `window_ms` already holds the key. Returns the id of the inserted (or
+// holding) message and whether it was inserted.
+async fn insert_message_dedup(
+    conn: &mut sqlx::SqliteConnection,
+    msg: &Message,
+    window_ms: i64,
+    codec: &PayloadCodec,
+) -> sqlx::Result<(i64, bool)> {
+    // Release the key from messages outside the window. Writing first takes
+    // the write lock, so the lookup below cannot go stale.
+    sqlx::query(
+        "UPDATE message SET dedup_key = NULL
+         WHERE queue_id = ? AND dedup_key = ? AND created_at <= ?",
+    )
+    .bind(msg.queue_id)
+    .bind(&msg.dedup_key)
+    .bind(msg.created_at - window_ms.max(0))
+    .execute(&mut *conn)
+    .await?;
+    let existing: Option<i64> = sqlx::query_scalar(
+        "SELECT id FROM message WHERE queue_id = ? AND dedup_key = ?",
+    )
+    .bind(msg.queue_id)
+    .bind(&msg.dedup_key)
+    .fetch_optional(&mut *conn)
+    .await?;
+    if let Some(id) = existing {
+        return Ok((id, false));
+    }
+    let id = insert_message(conn, msg, codec).await?;
+    Ok((id, true))
+}
+
+impl SqliteStorage {
+    /// Enqueue `msg` within the caller's transaction, so it commits or rolls
+    /// back together with the caller's own writes to the same database (the
+    /// outbox pattern). Consumers see the message once the transaction
+    /// commits. The payload is compressed and encrypted like any other.
+    ///
+    /// `msg.queue_id` must name an existing queue. A dedup key is honoured
+    /// with the queue's dedup window. Returns the id of the inserted message,
+    /// or of the message already holding the key, and whether it was inserted.
+    pub async fn enqueue_message_tx(
+        &self,
+        tx: &mut Transaction<'_, Sqlite>,
+        msg: &Message,
+    ) -> sqlx::Result<(i64, bool)> {
+        if msg.dedup_key.is_none() {
+            let id = insert_message(tx, msg, &self.codec).await?;
+            return Ok((id, true));
+        }
+        let window_ms: i64 = sqlx::query_scalar(
+            "SELECT dedup_window_ms FROM queue WHERE id = ?",
+        )
+        .bind(msg.queue_id)
+        .fetch_optional(&mut **tx)
+        .await?
+        .ok_or(sqlx::Error::RowNotFound)?;
+        insert_message_dedup(tx, msg, window_ms, &self.codec).await
+    }
+
+    // Look up a message by id
+    pub(crate) async fn find_message<'e, E>(
+        &self,
+        executor: E,
+        id: i64,
+    ) -> sqlx::Result<Option<Message>>
+    where
+        E: Executor<'e, Database = Sqlite>,
+    {
+        let sql = format!("SELECT {MESSAGE_COLUMNS} FROM message WHERE id = ?");
+        let row = sqlx::query_as::<_, Packed<Message>>(&sql)
+            .bind(id)
+            .fetch_optional(executor)
+            .await?;
+        row.map(|row| row.unpack(&self.codec)).transpose()
+    }
+
+    // The order a queue's `ordering` gives its ready messages
+    async fn ready_order(
+        &self,
+        queue_name: &str,
+    ) -> sqlx::Result<ReadyOrder> {
+        let ordering: Option<String> =
+            sqlx::query_scalar("SELECT ordering FROM queue WHERE name = ?")
+                .bind(queue_name)
+                .fetch_optional(self.reader())
+                .await?;
+        Ok(ReadyOrder::of(ordering.as_deref().unwrap_or_default()))
+    }
+}
+
+// Look up a queue by name; queues in the trash are not found
+pub(crate) async fn find_queue<'e, E: Executor<'e, Database = Sqlite>>(
+    executor: E,
+    name: &str,
+) -> sqlx::Result<Option<Queue>> {
+    let sql = format!(
+        "SELECT {QUEUE_COLUMNS} FROM queue WHERE name = ? AND deleted_at IS NULL"
+    );
+    sqlx::query_as::<_, Queue>(&sql).bind(name).fetch_optional(executor).await
+}
+
+// Add the acks of messages `ids`, still present, to their queues' ack
+// counters
+async fn record_acks(
+    conn: &mut sqlx::SqliteConnection,
+    ids: &[i64],
+    now: i64,
+) -> sqlx::Result<()> {
+    if ids.is_empty() {
+        return Ok(());
+    }
+    let placeholders =
+        std::iter::repeat_n("?", ids.len()).collect::<Vec<_>>().join(",");
+    let sql = format!(
+        "UPDATE queue SET acked_count = acked_count + c.n,
+                          ack_latency_ms_total = ack_latency_ms_total + c.ms
+         FROM (SELECT queue_id, COUNT(*) AS n, SUM(? - created_at) AS ms
+               FROM message WHERE id IN ({placeholders})
+               GROUP BY queue_id) AS c
+         WHERE queue.id = c.queue_id"
+    );
+    let mut q = sqlx::query(&sql).bind(now);
+    for id in ids {
+        q = q.bind(id);
+    }
+    q.execute(&mut *conn).await?;
+    Ok(())
+}
+
+// Enqueue a receipt of how the messages `ids`, still present, ended
+// (`acked` or `dead`) into the reply queue each names. An ack counts the
+// attempt being acked, which is not in `attempts` yet.
+async fn send_receipts(
+    conn: &mut sqlx::SqliteConnection,
+    ids: &[i64],
+    outcome: &str,
+    now: i64,
+) -> sqlx::Result<()> {
+    if ids.is_empty() {
+        return Ok(());
+    }
+    let placeholders =
+        std::iter::repeat_n("?", ids.len()).collect::<Vec<_>>().join(",");
+    let sql = format!(
+        "INSERT INTO message (queue_id, payload, attempts, available_at, created_at, trace_id)
+         SELECT rq.id,
+                json_object('message_id', m.id, 'queue', q.name,
+                            'outcome', ?, 'attempts', m.attempts + ?,
+                            'enqueued_at', m.created_at, 'settled_at', ?),
+                0, ?, ?, m.trace_id
+         FROM message m JOIN queue q ON q.id = m.queue_id
+         JOIN queue rq ON rq.name = m.reply_to AND rq.deleted_at IS NULL
+         WHERE m.id IN ({placeholders})
+         ORDER BY m.id"
+    );
+    let mut q = sqlx::query(&sql)
+        .bind(outcome)
+        .bind(i64::from(outcome == "acked"))
+        .bind(now)
+        .bind(now)
+        .bind(now);
+    for id in ids {
+        q = q.bind(id);
+    }
+    q.execute(&mut *conn).await?;
+    Ok(())
+}
+
+// Delete those of `ids` that every consumer group of their queue has acked or
+// dead-lettered
+async fn delete_finished(
+    conn: &mut sqlx::SqliteConnection,
+    ids: &[i64],
+) -> sqlx::Result<()> {
+    if ids.is_empty() {
+        return Ok(());
+    }
+    let placeholders =
+        std::iter::repeat_n("?", ids.len()).collect::<Vec<_>>().join(",");
+    let sql = format!(
+        "DELETE FROM message WHERE id IN ({placeholders}) AND {DONE_BY_ALL_GROUPS}"
+    );
+    let mut q = sqlx::query(&sql);
+    for id in ids {
+        q = q.bind(id);
+    }
+    q.execute(&mut *conn).await?;
+    Ok(())
+}
+
+// A queue's paused flag, rate limit, token bucket, strict FIFO and fair
+// flags and ordering, as read by `rate_limit`
+type RateRow =
+    (bool, Option<f64>, Option<f64>, Option<i64>, bool, bool, String);
+
+// Cap a poll's `limit` by the queue's delivery rate limit; a paused or
+// trashed queue leases nothing. Returns the capped limit, the bucket's
+// balance to charge leased messages against (`None` when the queue is not
+// rate-limited) and how the poll orders the queue's messages.
+async fn rate_limit(
+    conn: &mut sqlx::SqliteConnection,
+    queue_name: &str,
+    limit: i64,
+    now: i64,
+) -> sqlx::Result<(i64, Option<f64>, PollOrder)> {
+    let row: Option<RateRow> = sqlx::query_as(
+        "SELECT paused OR deleted_at IS NOT NULL, max_deliveries_per_second,
+                rate_tokens, rate_updated_at, strict_fifo, fair, ordering
+         FROM queue WHERE name = ?",
+    )
+    .bind(queue_name)
+    .fetch_optional(&mut *conn)
+    .await?;
+    match row {
+        Some((true, .., strict, fair, ordering)) => {
+            Ok((0, None, PollOrder::of(strict, fair, &ordering)))
+        }
+        Some((_, Some(rate), tokens, updated_at, strict, fair, ordering)) => {
+            let tokens = rate_tokens(rate, tokens, updated_at, now);
+            let capped = limit.min(tokens.floor() as i64);
+            Ok((capped, Some(tokens), PollOrder::of(strict, fair, &ordering)))
+        }
+        Some((.., strict, fair, ordering)) => {
+            Ok((limit, None, PollOrder::of(strict, fair, &ordering)))
+        }
+        None => Ok((limit, None, PollOrder::Ready(ReadyOrder::Priority))),
+    }
+}
+
+// Charge `leased` deliveries to a rate-limited queue's token bucket
+async fn spend_tokens(
+    conn: &mut sqlx::SqliteConnection,
+    queue_name: &str,
+    tokens: f64,
+    leased: u64,
+    now: i64,
+) -> sqlx::Result<()> {
+    sqlx::query(
+        "UPDATE queue SET rate_tokens = ?, rate_updated_at = ? WHERE name = ?",
+    )
+    .bind(tokens - leased as f64)
+    .bind(now)
+    .bind(queue_name)
+    .execute(&mut *conn)
+    .await?;
+    Ok(())
+}
+
+// Lease statement of `poll_messages` for strict FIFO queues: only the oldest
+// live message of each group (ungrouped messages forming one group) may be
+// leased, in enqueue order and regardless of priority, so a head that is
+// leased or delayed holds back everything behind it
+fn strict_fifo_poll_sql() -> String {
+    format!(
+        "UPDATE message SET available_at = ?4, lease_token = ?5
+         WHERE id IN (
+           SELECT m.id
+           FROM message m
+           WHERE m.id IN (
+               SELECT MIN(h.id) FROM message h
+               WHERE h.queue_id = (SELECT id FROM queue WHERE name = ?1)
+                 AND h.dead_at IS NULL
+                 AND (h.expires_at IS NULL OR h.expires_at > ?2)
+               GROUP BY h.group_id)
+             AND m.available_at <= ?2
+             AND NOT EXISTS (
+               SELECT 1 FROM consumer_group cg
+               WHERE cg.queue_id = m.queue_id)
+           ORDER BY m.id
+           LIMIT ?3)
+         RETURNING {LEASED_MESSAGE_COLUMNS}"
+    )
+}
+
+// Ids of up to `limit` ready messages of a fair queue, one from each fair key
+// in turn, starting with the key after the one that got the last message of
+// the previous poll. Returns them in lease order with the key that got the
+// last one.
+async fn fair_poll_ids(
+    conn: &mut sqlx::SqliteConnection,
+    queue_name: &str,
+    limit: i64,
+    now: i64,
+    order: ReadyOrder,
+) -> sqlx::Result<(Vec<i64>, Option<String>)> {
+    let Some((queue_id, cursor)): Option<(i64, Option<String>)> =
+        sqlx::query_as("SELECT id, fair_cursor FROM queue WHERE name = ?")
+            .bind(queue_name)
+            .fetch_optional(&mut *conn)
+            .await?
+    else {
+        return Ok((Vec::new(), None));
+    };
+    // Keys after the cursor come first, then the rest from the start
+    let keys: Vec<String> = sqlx::query_scalar(
+        "SELECT k FROM (
+           SELECT DISTINCT COALESCE(fair_key, '') AS k FROM message
+           WHERE queue_id = ?1 AND dead_at IS NULL AND available_at <= ?2
+             AND (expires_at IS NULL OR expires_at > ?2))
+         ORDER BY ?3 IS NOT NULL AND k <= ?3, k
+         LIMIT ?4",
+    )
+    .bind(queue_id)
+    .bind(now)
+    .bind(&cursor)
+    .bind(FAIR_MAX_KEYS)
+    .fetch_all(&mut *conn)
+    .await?;
+    let limit = limit.max(0) as usize;
+    let mut lanes = FairLanes::new();
+    for key in keys {
+        if lanes.len() == limit {
+            break;
+        }
+        let ids =
+            fair_ready_ids(conn, queue_id, &key, 0, 1, now, order).await?;
+        lanes.push(key, ids, 1);
+    }
+    while let Some((per, open)) = lanes.refill(limit) {
+        for (i, key, have) in open {
+            let ids =
+                fair_ready_ids(conn, queue_id, &key, have, per, now, order)
+                    .await?;
+            lanes.extend(i, ids, per);
+        }
+    }
+    Ok(lanes.interleave(limit))
+}
+
+// Ids of ready messages with fair key `key` (`""` for unkeyed ones) in the
+// order a poll leases them, skipping the first `skip`
+async fn fair_ready_ids(
+    conn: &mut sqlx::SqliteConnection,
+    queue_id: i64,
+    key: &str,
+    skip: usize,
+    take: usize,
+    now: i64,
+    order: ReadyOrder,
+) -> sqlx::Result<Vec<i64>> {
+    let order = order.sql();
+    let keyed =
+        if key.is_empty() { "m.fair_key IS NULL" } else { "m.fair_key = ?5" };
+    let sql = format!(
+        "SELECT m.id FROM message m
+         WHERE m.queue_id = ?1
+           AND {keyed}
+           AND m.dead_at IS NULL
+           AND m.available_at <= ?2
+           AND (m.expires_at IS NULL OR m.expires_at > ?2)
+           AND NOT EXISTS (
+             SELECT 1 FROM consumer_group cg WHERE cg.queue_id = m.queue_id)
+           AND (m.group_id IS NULL OR m.id = (
+             SELECT MIN(g.id) FROM message g
+             WHERE g.queue_id = m.queue_id
+               AND g.group_id = m.group_id
+               AND g.dead_at IS NULL
+               AND (g.expires_at IS NULL OR g.expires_at > ?2)))
+         ORDER BY {order}
+         LIMIT ?3 OFFSET ?4"
+    );
+    let mut q = sqlx::query_scalar(&sql)
+        .bind(queue_id)
+        .bind(now)
+        .bind(take as i64)
+        .bind(skip as i64);
+    if !key.is_empty() {
+        q = q.bind(key);
+    }
+    q.fetch_all(&mut *conn).await
+}
+
+// Lease the messages `ids` picked by `fair_poll_ids`, returning them in the
+// same order
+async fn lease_ids(
+    conn: &mut sqlx::SqliteConnection,
+    ids: &[i64],
+    until: i64,
+    lease_token: &str,
+) -> sqlx::Result<Vec<Packed<Message>>> {
+    if ids.is_empty() {
+        return Ok(Vec::new());
+    }
+    let placeholders =
+        std::iter::repeat_n("?", ids.len()).collect::<Vec<_>>().join(",");
+    let sql = format!(
+        "UPDATE message SET available_at = ?, lease_token = ?
+         WHERE id IN ({placeholders})
+         RETURNING {LEASED_MESSAGE_COLUMNS}"
+    );
+    let mut q = sqlx::query_as::<_, Packed<Message>>(&sql)
+        .bind(until)
+        .bind(lease_token);
+    for id in ids {
+        q = q.bind(id);
+    }
+    let mut rows = q.fetch_all(&mut *conn).await?;
+    rows.sort_by_key(|r| ids.iter().position(|&id| id == r.row.id));
+    Ok(rows)
+}
+
+// `(?, ?), (?, ?), ...` for `n` rows of a `VALUES` list of nacks
+fn nack_values(n: usize) -> String {
+    std::iter::repeat_n("(?, ?)", n).collect::<Vec<_>>().join(", ")
+}
+
+// Release leases (of one queue, or all) that expired by `now`, counting each
+// as a failed attempt: the message is requeued, or dead-lettered once it
+// reaches its queue's max_attempts. Consumer group deliveries are reaped the
+// same way. Returns `(requeued, dead_lettered)`.
+async fn reap_leases(
+    conn: &mut sqlx::SqliteConnection,
+    queue_name: Option<&str>,
+    now: i64,
+) -> sqlx::Result<(u64, u64)> {
+    // A message is quarantined once its leases expired the queue's
+    // max_lease_expirations times
+    let quarantine = "lease_expirations + 1 >= (
+               SELECT q.max_lease_expirations FROM queue q
+               WHERE q.id = message.queue_id)";
+    let reason = quarantine_reason_sql("lease_expirations + 1");
+    let sql = format!(
+        "UPDATE message
+         SET attempts = attempts + 1, lease_token = NULL,
+             lease_expirations = lease_expirations + 1,
+             dead_at = CASE WHEN attempts + 1 >= (
+               SELECT q.max_attempts FROM queue q WHERE q.id = message.queue_id)
+               OR {quarantine}
+               THEN ?1 END,
+             last_error = CASE WHEN {quarantine}
+               THEN {reason} ELSE last_error END
+         WHERE lease_token IS NOT NULL AND available_at <= ?1
+           AND dead_at IS NULL
+           AND (?2 IS NULL OR queue_id = (SELECT id FROM queue WHERE name = ?2))
+         RETURNING id, (SELECT q.name FROM queue q WHERE q.id = queue_id),
+                   trace_id, lease_expirations, dead_at IS NOT NULL,
+                   COALESCE(lease_expirations >= (
+                     SELECT q.max_lease_expirations FROM queue q
+                     WHERE q.id = queue_id), 0)"
+    );
+    let reaped: Vec<ReapedRow> = sqlx::query_as(&sql)
+        .bind(now)
+        .bind(queue_name)
+        .fetch_all(&mut *conn)
+        .await?;
+    settle_quarantined(conn, &report_quarantined(&reaped), now).await?;
+    let dead_messages: Vec<i64> =
+        reaped.iter().filter(|r| r.4).map(|r| r.0).collect();
+    send_receipts(conn, &dead_messages, "dead", now).await?;
+    let deliveries: Vec<(i64, bool)> = sqlx::query_as(
+        "UPDATE group_delivery
+         SET attempts = attempts + 1, lease_token = NULL,
+             dead_at = CASE WHEN attempts + 1 >= (
+               SELECT q.max_attempts FROM consumer_group cg
+               JOIN queue q ON q.id = cg.queue_id
+               WHERE cg.id = group_delivery.consumer_group_id)
+               THEN ? END
+         WHERE lease_token IS NOT NULL AND available_at <= ?
+           AND acked_at IS NULL AND dead_at IS NULL
+           AND (? IS NULL OR consumer_group_id IN (
+             SELECT cg.id FROM consumer_group cg
+             JOIN queue q ON q.id = cg.queue_id WHERE q.name = ?))
+         RETURNING message_id, dead_at IS NOT NULL",
+    )
+    .bind(now)
+    .bind(now)
+    .bind(queue_name)
+    .bind(queue_name)
+    .fetch_all(&mut *conn)
+    .await?;
+    let dead: Vec<i64> = deliveries
+        .iter()
+        .filter(|&&(_, dead)| dead)
+        .map(|&(id, _)| id)
+        .collect();
+    delete_finished(conn, &dead).await?;
+    let dead_count =
+        (reaped.iter().filter(|r| r.4).count() + dead.len()) as u64;
+    let total = (reaped.len() + deliveries.len()) as u64;
+    Ok((total - dead_count, dead_count))
+}
+
+// Close the open attempt log entries of quarantined messages
+async fn settle_quarantined(
+    conn: &mut sqlx::SqliteConnection,
+    ids: &[i64],
+    now: i64,
+) -> sqlx::Result<()> {
+    if ids.is_empty() {
+        return Ok(());
+    }
+    let placeholders =
+        std::iter::repeat_n("?", ids.len()).collect::<Vec<_>>().join(",");
+    let sql = format!(
+        "UPDATE message_attempt SET outcome = 'quarantined', settled_at = ?
+         WHERE outcome IS NULL AND message_id IN ({placeholders})"
+    );
+    let mut q = sqlx::query(&sql).bind(now);
+    for id in ids {
+        q = q.bind(id);
+    }
+    q.execute(&mut *conn).await?;
+    Ok(())
+}
+
+#[async_trait]
+impl Storage for SqliteStorage {
+    async fn schema_version(&self) -> sqlx::Result<i64> {
+        sqlx::query_scalar(
+            "SELECT COALESCE(MAX(version), 0) FROM schema_version",
+        )
+        .fetch_one(&self.pool)
+        .await
+    }
+
+    fn latest_schema_version(&self) -> i64 {
+        MIGRATIONS.len() as i64
+    }
+
+    fn as_sqlite(&self) -> Option<&SqliteStorage> {
+        Some(self)
+    }
+
+    async fn get_queue_by_name(
+        &self,
+        name: &str,
+    ) -> sqlx::Result<Option<Queue>> {
+        find_queue(&self.pool, name).await
+    }
+
+    async fn create_queue(
+        &self,
+        q: &Queue,
+    ) -> sqlx::Result<i64> {
+        let rec = sqlx::query(
+            "INSERT INTO queue (name, max_attempts, dedup_window_ms, retention_days, backoff_base_ms, backoff_multiplier, backoff_max_ms, backoff_jitter, default_visibility_ms, default_delay_ms, max_deliveries_per_second, max_payload_bytes, payload_schema, strict_fifo, fair, ordering, max_depth, max_lease_expirations, schema_mode)
+             VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+        )
+        .bind(&q.name)
+        .bind(q.max_attempts)
+        .bind(q.dedup_window_ms)
+        .bind(q.retention_days)
+        .bind(q.backoff_base_ms)
+        .bind(q.backoff_multiplier)
+        .bind(q.backoff_max_ms)
+        .bind(q.backoff_jitter)
+        .bind(q.default_visibility_ms)
+        .bind(q.default_delay_ms)
+        .bind(q.max_deliveries_per_second)
+        .bind(q.max_payload_bytes)
+        .bind(q.payload_schema.as_ref().map(Json))
+        .bind(q.strict_fifo)
+        .bind(q.fair)
+        .bind(&q.ordering)
+        .bind(q.max_depth)
+        .bind(q.max_lease_expirations)
+        .bind(&q.schema_mode)
+        .execute(&self.pool)
+        .await?;
+        Ok(rec.last_insert_rowid())
+    }
+
+    async fn enqueue_message(
+        &self,
+        msg: &Message,
+    ) -> sqlx::Result<i64> {
+        let mut conn = self.pool.acquire().await?;
+        insert_message(&mut conn, msg, &self.codec).await
+    }
+
+    async fn enqueue_message_dedup(
+        &self,
+        msg: &Message,
+        window_ms: i64,
+    ) -> sqlx::Result<(i64, bool)> {
+        let mut attempt = 0;
+        loop {
+            let mut tx: Transaction<'_, Sqlite> = self.pool.begin().await?;
+            let inserted =
+                insert_message_dedup(&mut tx, msg, window_ms, &self.codec)
+                    .await;
+            match inserted {
+                Ok(found) => {
+                    tx.commit().await?;
+                    return Ok(found);
+                }
+                // A concurrent producer inserted the same key first; look again
+                Err(sqlx::Error::Database(e))
+                    if e.is_unique_violation() && attempt == 0 =>
+                {
+                    tx.rollback().await?;
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    async fn get_message_by_id(
+        &self,
+        id: i64,
+    ) -> sqlx::Result<Option<Message>> {
+        self.find_message(&self.pool, id).await
+    }
+
+    async fn find_message_ids(
+        &self,
+        public_ids: &[String],
+    ) -> sqlx::Result<Vec<(String, i64)>> {
+        if public_ids.is_empty() {
+            return Ok(Vec::new());
+        }
+        let placeholders = std::iter::repeat_n("?", public_ids.len())
+            .collect::<Vec<_>>()
+            .join(",");
+        let sql = format!(
+            "SELECT public_id, id FROM message WHERE public_id IN ({placeholders})"
+        );
+        let mut q = sqlx::query_as(&sql);
+        for p in public_ids {
+            q = q.bind(p);
+        }
+        q.fetch_all(self.reader()).await
+    }
+
+    async fn export_messages(
+        &self,
+        queue_name: &str,
+        after_id: i64,
+        limit: i64,
+    ) -> sqlx::Result<Vec<Message>> {
+        let sql = format!(
+            "SELECT {MESSAGE_COLUMNS}
+             FROM message
+             WHERE queue_id = (SELECT id FROM queue WHERE name = ?) AND id > ?
+             ORDER BY id
+             LIMIT ?"
+        );
+        let rows = sqlx::query_as::<_, Packed<Message>>(&sql)
+            .bind(queue_name)
+            .bind(after_id)
+            .bind(limit)
+            .fetch_all(&self.pool)
+            .await?;
+        self.codec.unpack_all(rows)
+    }
+
+    async fn import_messages(
+        &self,
+        msgs: &[Message],
+    ) -> sqlx::Result<u64> {
+        let mut tx: Transaction<'_, Sqlite> = self.pool.begin().await?;
+        let mut imported = 0;
+        for msg in msgs {
+            if msg.dedup_key.is_some() {
+                let held: Option<i64> = sqlx::query_scalar(
+                    "SELECT id FROM message WHERE queue_id = ? AND dedup_key = ?",
+                )
+                .bind(msg.queue_id)
+                .bind(&msg.dedup_key)
+                .fetch_optional(&mut *tx)
+                .await?;
+                if held.is_some() {
+                    continue;
+                }
+            }
+            insert_message(&mut tx, msg, &self.codec).await?;
+            imported += 1;
+        }
+        tx.commit().await?;
+        Ok(imported)
+    }
+
+    async fn ack_messages(
+        &self,
+        ids: &[i64],
+        lease_token: &str,
+    ) -> sqlx::Result<Vec<i64>> {
+        // Acks are settled by lease token, so a try repeated after
+        // contention acks nothing twice
+        retry_busy(|| async move {
+            if ids.is_empty() {
+                return Ok(Vec::new());
+            }
+            let now = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_millis() as i64;
+            let placeholders = std::iter::repeat_n("?", ids.len())
+                .collect::<Vec<_>>()
+                .join(",");
+            let mut tx = self.pool.begin().await?;
+            // Archive first (a write, so the transaction holds the write lock
+            // before reading) for queues that retain acked messages
+            let sql = format!(
+                "INSERT INTO message_archive (message_id, queue_id, payload, payload_encoding, payload_key_id, attempts, priority, group_id, created_at, acked_at, purge_at)
+                 SELECT m.id, m.queue_id, m.payload, m.payload_encoding, m.payload_key_id, m.attempts, m.priority, m.group_id, m.created_at, ?, ? + q.retention_days * {DAY_MS}
+                 FROM message m JOIN queue q ON q.id = m.queue_id
+                 WHERE m.id IN ({placeholders}) AND m.lease_token = ? AND m.available_at > ?
+                   AND q.retention_days IS NOT NULL"
+            );
+            let mut q = sqlx::query(&sql).bind(now).bind(now);
+            for id in ids {
+                q = q.bind(id);
+            }
+            q.bind(lease_token).bind(now).execute(&mut *tx).await?;
+            let sql = format!(
+                "SELECT id FROM message
+                 WHERE id IN ({placeholders}) AND lease_token = ? AND available_at > ?"
+            );
+            let mut q = sqlx::query_scalar::<_, i64>(&sql);
+            for id in ids {
+                q = q.bind(id);
+            }
+            let acked =
+                q.bind(lease_token).bind(now).fetch_all(&mut *tx).await?;
+            record_acks(&mut tx, &acked, now).await?;
+            send_receipts(&mut tx, &acked, "acked", now).await?;
+            let placeholders = std::iter::repeat_n("?", acked.len())
+                .collect::<Vec<_>>()
+                .join(",");
+            let sql =
+                format!("DELETE FROM message WHERE id IN ({placeholders})");
+            let mut q = sqlx::query(&sql);
+            for id in &acked {
+                q = q.bind(id);
+            }
+            if !acked.is_empty() {
+                q.execute(&mut *tx).await?;
+            }
+            tx.commit().await?;
+            Ok(acked)
+        })
+        .await
+    }
+
+    async fn expire_leases(
+        &self,
+        ids: &[i64],
+        lease_token: &str,
+    ) -> sqlx::Result<u64> {
+        retry_busy(|| async move {
+            if ids.is_empty() {
+                return Ok(0);
+            }
+            let placeholders = std::iter::repeat_n("?", ids.len())
+                .collect::<Vec<_>>()
+                .join(",");
+            let sql = format!(
+                "UPDATE message SET available_at = ?
+                 WHERE id IN ({}) AND lease_token = ? AND available_at > ?",
+                placeholders
+            );
+            let now = now_ms();
+            let mut q = sqlx::query(&sql).bind(now);
+            for id in ids {
+                q = q.bind(id);
+            }
+            let res = q.bind(lease_token).bind(now).execute(&self.pool).await?;
+            Ok(res.rows_affected())
+        })
+        .await
+    }
+
+    async fn extend_visibility(
+        &self,
+        ids: &[i64],
+        lease_token: &str,
+        extra_ms: i64,
+    ) -> sqlx::Result<u64> {
+        if ids.is_empty() {
+            return Ok(0);
+        }
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as i64;
+        let placeholders =
+            std::iter::repeat_n("?", ids.len()).collect::<Vec<_>>().join(",");
+        let sql = format!(
+            "UPDATE message SET available_at = available_at + ?
+             WHERE id IN ({}) AND lease_token = ? AND available_at > ?",
+            placeholders
+        );
+        let mut q = sqlx::query(&sql).bind(extra_ms.max(0));
+        for id in ids {
+            q = q.bind(id);
+        }
+        let res = q.bind(lease_token).bind(now).execute(&self.pool).await?;
+        Ok(res.rows_affected())
+    }
+
+    async fn list_queues(&self) -> sqlx::Result<Vec<Queue>> {
+        let sql = format!(
+            "SELECT {QUEUE_COLUMNS} FROM queue WHERE deleted_at IS NULL
+             ORDER BY id"
+        );
+        sqlx::query_as::<_, Queue>(&sql).fetch_all(&self.pool).await
+    }
+
+    async fn list_queues_matching(
+        &self,
+        pattern: &str,
+    ) -> sqlx::Result<Vec<Queue>> {
+        // GLOB is case-sensitive; `?` and `[` are bracketed to stay literal
+        let glob = pattern.replace('[', "[[]").replace('?', "[?]");
+        let sql = format!(
+            "SELECT {QUEUE_COLUMNS} FROM queue
+             WHERE deleted_at IS NULL AND name GLOB ?
+             ORDER BY id"
+        );
+        sqlx::query_as::<_, Queue>(&sql).bind(glob).fetch_all(&self.pool).await
+    }
+
+    async fn update_queue(
+        &self,
+        q: &Queue,
+    ) -> sqlx::Result<u64> {
+        let mut conn = self.pool.acquire().await?;
+        update_queue_row(&mut conn, q).await
+    }
+
+    async fn clone_queue(
+        &self,
+        source: &str,
+        target: &str,
+        with_messages: bool,
+    ) -> sqlx::Result<Option<(i64, u64)>> {
+        let mut tx = self.pool.begin().await?;
+        let ids: Option<(i64, i64)> = sqlx::query_as(
+            "INSERT INTO queue (name, max_attempts, dedup_window_ms, retention_days, backoff_base_ms, backoff_multiplier, backoff_max_ms, backoff_jitter, default_visibility_ms, default_delay_ms, max_deliveries_per_second, max_payload_bytes, payload_schema, strict_fifo, fair, ordering, max_depth, max_lease_expirations, schema_mode)
+             SELECT ?, max_attempts, dedup_window_ms, retention_days, backoff_base_ms, backoff_multiplier, backoff_max_ms, backoff_jitter, default_visibility_ms, default_delay_ms, max_deliveries_per_second, max_payload_bytes, payload_schema, strict_fifo, fair, ordering, max_depth, max_lease_expirations, schema_mode
+             FROM queue WHERE name = ?
+             RETURNING id, (SELECT id FROM queue WHERE name = ?)",
+        )
+        .bind(target)
+        .bind(source)
+        .bind(source)
+        .fetch_optional(&mut *tx)
+        .await?;
+        let Some((id, source_id)) = ids else {
+            return Ok(None);
+        };
+        let mut copied = 0;
+        if with_messages {
+            copied = sqlx::query(
+                "INSERT INTO message (queue_id, payload, payload_encoding, payload_key_id, attempts, available_at, created_at, priority, expires_at, dedup_key, group_id, headers, trace_id, fair_key, lease_expirations, sort_key, reply_to)
+                 SELECT ?, payload, payload_encoding, payload_key_id, attempts,
+                        CASE WHEN lease_token IS NULL THEN available_at
+                             ELSE MIN(available_at, ?) END,
+                        created_at, priority, expires_at, dedup_key, group_id,
+                        headers, trace_id, fair_key, lease_expirations,
+                        sort_key, reply_to
+                 FROM message
+                 WHERE queue_id = ? AND dead_at IS NULL
+                 ORDER BY id",
+            )
+            .bind(id)
+            .bind(now_ms())
+            .bind(source_id)
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+        }
+        tx.commit().await?;
+        Ok(Some((id, copied)))
+    }
+
+    async fn delete_queue_by_name(
+        &self,
+        name: &str,
+    ) -> sqlx::Result<u64> {
+        let res = sqlx::query("DELETE FROM queue WHERE name = ?")
+            .bind(name)
+            .execute(&self.pool)
+            .await?;
+        Ok(res.rows_affected())
+    }
+
+    async fn trash_queue(
+        &self,
+        name: &str,
+        now: i64,
+    ) -> sqlx::Result<u64> {
+        let res = sqlx::query(
+            "UPDATE queue SET deleted_at = ? WHERE name = ? AND deleted_at IS NULL",
+        )
+        .bind(now)
+        .bind(name)
+        .execute(&self.pool)
+        .await?;
+        Ok(res.rows_affected())
+    }
+
+    async fn restore_queue(
+        &self,
+        name: &str,
+    ) -> sqlx::Result<u64> {
+        let res = sqlx::query(
+            "UPDATE queue SET deleted_at = NULL
+             WHERE name = ? AND deleted_at IS NOT NULL",
+        )
+        .bind(name)
+        .execute(&self.pool)
+        .await?;
+        Ok(res.rows_affected())
+    }
+
+    async fn list_trashed_queues(&self) -> sqlx::Result<Vec<Queue>> {
+        let sql = format!(
+            "SELECT {QUEUE_COLUMNS} FROM queue WHERE deleted_at IS NOT NULL
+             ORDER BY deleted_at, id"
+        );
+        sqlx::query_as::<_, Queue>(&sql).fetch_all(&self.pool).await
+    }
+
+    async fn purge_trashed_queues(
+        &self,
+        deleted_before: i64,
+    ) -> sqlx::Result<Vec<String>> {
+        sqlx::query_scalar(
+            "DELETE FROM queue WHERE deleted_at <= ? RETURNING name",
+        )
+        .bind(deleted_before)
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    async fn purge_messages_by_queue(
+        &self,
+        queue_name: &str,
+        limit: i64,
+    ) -> sqlx::Result<u64> {
+        // Delete live messages matching the queue name; dead letters are kept
+        let res = sqlx::query(
+            "DELETE FROM message WHERE id IN (
+               SELECT id FROM message
+               WHERE queue_id = (SELECT id FROM queue WHERE name = ?)
+                 AND dead_at IS NULL
+               LIMIT ?)",
+        )
+        .bind(queue_name)
+        .bind(limit)
+        .execute(&self.pool)
+        .await?;
+        Ok(res.rows_affected())
+    }
+
+    async fn remove_matching_messages(
+        &self,
+        queue_name: &str,
+        filter: &RemoveFilter,
+        limit: i64,
+    ) -> sqlx::Result<u64> {
+        // Compressed payloads are opaque to json_extract, as with peek
+        let (path, path_value) = filter.json_path.clone().unzip();
+        let res = sqlx::query(
+            "DELETE FROM message WHERE id IN (
+               SELECT id FROM message
+               WHERE queue_id = (SELECT id FROM queue WHERE name = ?)
+                 AND dead_at IS NULL
+                 AND (? IS NULL OR created_at < ?)
+                 AND (? IS NULL OR (payload_encoding IS NULL
+                                    AND CAST(json_extract(payload, ?) AS TEXT) = ?))
+               LIMIT ?)",
+        )
+        .bind(queue_name)
+        .bind(filter.created_before)
+        .bind(filter.created_before)
+        .bind(&path)
+        .bind(&path)
+        .bind(path_value)
+        .bind(limit)
+        .execute(&self.pool)
+        .await?;
+        Ok(res.rows_affected())
+    }
+
+    async fn peek_messages(
+        &self,
+        queue_name: &str,
+        limit: i64,
+        filter: &PeekFilter,
+    ) -> sqlx::Result<Vec<Message>> {
+        // A cursor (after_id) pages in id order instead of delivery order
+        let order = match filter.after_id {
+            Some(_) => "m.id",
+            None => self.ready_order(queue_name).await?.sql(),
+        };
+        let now = now_ms();
+        let state = filter.state.sql(now);
+        let sql = format!(
+            "SELECT {MESSAGE_COLUMNS}
+             FROM message m
+             WHERE queue_id = (SELECT id FROM queue WHERE name = ?)
+               AND dead_at IS NULL
+               AND (expires_at IS NULL OR expires_at > ?)
+               AND {state}
+               AND (? IS NULL OR id > ?)
+               AND (? IS NULL OR created_at >= ?)
+               AND (? IS NULL OR created_at < ?)
+               AND (? IS NULL OR EXISTS (
+                     SELECT 1 FROM json_each(m.headers)
+                     WHERE key = ? AND value = ?))
+               AND (? IS NULL OR (payload_encoding IS NULL
+                                  AND instr(payload, ?) > 0))
+               AND (? IS NULL OR (payload_encoding IS NULL
+                                  AND CAST(json_extract(payload, ?) AS TEXT) = ?))
+             ORDER BY {order}
+             LIMIT ? OFFSET ?"
+        );
+        let (key, value) = filter.header.clone().unzip();
+        let (path, path_value) = filter.json_path.clone().unzip();
+        let rows = sqlx::query_as::<_, Packed<Message>>(&sql)
+            .bind(queue_name)
+            .bind(now)
+            .bind(filter.after_id)
+            .bind(filter.after_id)
+            .bind(filter.created_after)
+            .bind(filter.created_after)
+            .bind(filter.created_before)
+            .bind(filter.created_before)
+            .bind(&key)
+            .bind(&key)
+            .bind(value)
+            .bind(&filter.payload_contains)
+            .bind(&filter.payload_contains)
+            .bind(&path)
+            .bind(&path)
+            .bind(path_value)
+            .bind(limit)
+            .bind(filter.offset.max(0))
+            .fetch_all(self.reader())
+            .await?;
+        self.codec.unpack_all(rows)
+    }
+
+    async fn search_messages(
+        &self,
+        queue_name: &str,
+        path: &str,
+        value: Option<&str>,
+        after_id: Option<i64>,
+        limit: i64,
+    ) -> sqlx::Result<Vec<Message>> {
+        // Compressed payloads are opaque to json_extract, as with peek
+        let sql = format!(
+            "SELECT {MESSAGE_COLUMNS}
+             FROM message
+             WHERE queue_id = (SELECT id FROM queue WHERE name = ?)
+               AND (? IS NULL OR id > ?)
+               AND payload_encoding IS NULL
+               AND CASE WHEN ? IS NULL THEN json_type(payload, ?) IS NOT NULL
+                        ELSE CAST(json_extract(payload, ?) AS TEXT) = ? END
+             ORDER BY id
+             LIMIT ?"
+        );
+        let rows = sqlx::query_as::<_, Packed<Message>>(&sql)
+            .bind(queue_name)
+            .bind(after_id)
+            .bind(after_id)
+            .bind(value)
+            .bind(path)
+            .bind(path)
+            .bind(value)
+            .bind(limit)
+            .fetch_all(self.reader())
+            .await?;
+        self.codec.unpack_all(rows)
+    }
+
+    async fn poll_messages(
+        &self,
+        queue_name: &str,
+        limit: i64,
+        visibility_ms: i64,
+    ) -> sqlx::Result<Vec<Message>> {
+        // The transaction of a try that hit SQLITE_BUSY (or BUSY_SNAPSHOT)
+        // rolled back, leasing nothing
+        retry_busy(|| async move {
+            let mut tx: Transaction<'_, Sqlite> = self.pool.begin().await?;
+            let now = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_millis() as i64;
+            // Count expired leases as attempts before they are handed out
+            // again, so a consumer that keeps crashing cannot retry forever
+            reap_leases(&mut tx, Some(queue_name), now).await?;
+            let (limit, bucket, order) =
+                rate_limit(&mut tx, queue_name, limit, now).await?;
+            let until = now + visibility_ms.max(0);
+            let lease_token = uuid::Uuid::new_v4().to_string();
+            if let PollOrder::Fair(ready) = order {
+                let (ids, last) =
+                    fair_poll_ids(&mut tx, queue_name, limit, now, ready)
+                        .await?;
+                let rows =
+                    lease_ids(&mut tx, &ids, until, &lease_token).await?;
+                if let Some(key) = last {
+                    sqlx::query(
+                        "UPDATE queue SET fair_cursor = ? WHERE name = ?",
+                    )
+                    .bind(key)
+                    .bind(queue_name)
+                    .execute(&mut *tx)
+                    .await?;
+                }
+                if let Some(tokens) = bucket.filter(|_| !rows.is_empty()) {
+                    let leased = rows.len() as u64;
+                    spend_tokens(&mut tx, queue_name, tokens, leased, now)
+                        .await?;
+                }
+                tx.commit().await?;
+                return self.codec.unpack_all(rows);
+            }
+            // Select and lease in one statement so the write lock is held
+            // for a single round trip. A grouped message is only eligible
+            // while it is the oldest live message of its group, so a group
+            // is never leased twice at once and is delivered in enqueue
+            // order. Unless the queue is ordered FIFO, the unary `+`
+            // keeps SQLite from picking the available_at index, so
+            // ix_msg_priority yields rows already in lease order instead
+            // of the whole ready set being sorted.
+            let ready = order.ready();
+            let sql = if order == PollOrder::StrictFifo {
+                strict_fifo_poll_sql()
+            } else {
+                let available = match ready {
+                    ReadyOrder::Fifo => "m.available_at",
+                    _ => "+m.available_at",
+                };
+                let order = ready.sql();
+                format!(
+                    "UPDATE message SET available_at = ?4, lease_token = ?5
+                 WHERE id IN (
+                   SELECT m.id
+                   FROM message m
+                   WHERE m.queue_id = (SELECT id FROM queue WHERE name = ?1)
+                     AND m.dead_at IS NULL
+                     AND {available} <= ?2
+                     AND (m.expires_at IS NULL OR m.expires_at > ?2)
+                     AND NOT EXISTS (
+                       SELECT 1 FROM consumer_group cg
+                       WHERE cg.queue_id = m.queue_id)
+                     AND (m.group_id IS NULL OR m.id = (
+                       SELECT MIN(g.id) FROM message g
+                       WHERE g.queue_id = m.queue_id
+                         AND g.group_id = m.group_id
+                         AND g.dead_at IS NULL
+                         AND (g.expires_at IS NULL OR g.expires_at > ?2)))
+                   ORDER BY {order}
+                   LIMIT ?3)
+                 RETURNING {LEASED_MESSAGE_COLUMNS}"
+                )
+            };
+            let mut rows = sqlx::query_as::<_, Packed<Message>>(&sql)
+                .bind(queue_name)
+                .bind(now)
+                .bind(limit)
+                .bind(until)
+                .bind(lease_token)
+                .fetch_all(&mut *tx)
+                .await?;
+            if let Some(tokens) = bucket.filter(|_| !rows.is_empty()) {
+                let leased = rows.len() as u64;
+                spend_tokens(&mut tx, queue_name, tokens, leased, now).await?;
+            }
+            tx.commit().await?;
+            // RETURNING yields rows in no particular order
+            ready.sort(&mut rows, |r| &r.row);
+            self.codec.unpack_all(rows)
+        })
+        .await
+    }
+
+    async fn count_ready_messages(
+        &self,
+        queue_id: i64,
+        now_ms: i64,
+    ) -> sqlx::Result<i64> {
+        let sql = format!("SELECT {READY_COUNT} FROM queue q WHERE q.id = ?2");
+        let count: Option<i64> = sqlx::query_scalar(&sql)
+            .bind(now_ms)
+            .bind(queue_id)
+            .fetch_optional(self.reader())
+            .await?;
+        Ok(count.unwrap_or(0))
+    }
+
+    async fn sample_messages(
+        &self,
+        queue_id: i64,
+        n: i64,
+        now_ms: i64,
+    ) -> sqlx::Result<Vec<Message>> {
+        const READY: &str = "queue_id = ?1 AND dead_at IS NULL
+               AND available_at <= ?2
+               AND (expires_at IS NULL OR expires_at > ?2)";
+        let ready = self.count_ready_messages(queue_id, now_ms).await?;
+        if ready <= SAMPLE_SHUFFLE_MAX {
+            let sql = format!(
+                "SELECT {MESSAGE_COLUMNS} FROM message WHERE {READY}
+                 ORDER BY RANDOM() LIMIT ?3"
+            );
+            let rows = sqlx::query_as::<_, Packed<Message>>(&sql)
+                .bind(queue_id)
+                .bind(now_ms)
+                .bind(n)
+                .fetch_all(self.reader())
+                .await?;
+            return self.codec.unpack_all(rows);
+        }
+        // Too many to shuffle: walk `ix_msg_dead` from random ids instead
+        let range: (Option<i64>, Option<i64>) = sqlx::query_as(
+            "SELECT
+               (SELECT MIN(id) FROM message
+                WHERE queue_id = ?1 AND dead_at IS NULL),
+               (SELECT MAX(id) FROM message
+                WHERE queue_id = ?1 AND dead_at IS NULL)",
+        )
+        .bind(queue_id)
+        .fetch_one(self.reader())
+        .await?;
+        let (Some(lo), Some(hi)) = range else {
+            return Ok(Vec::new());
+        };
+        let sql = format!(
+            "SELECT {MESSAGE_COLUMNS} FROM message INDEXED BY ix_msg_dead
+             WHERE {READY} AND id >= ?3
+             ORDER BY id LIMIT 1"
+        );
+        let mut rows: Vec<Packed<Message>> = Vec::new();
+        for pivot in sample_pivots(lo, hi, n) {
+            if rows.len() as i64 >= n {
+                break;
+            }
+            let row = sqlx::query_as::<_, Packed<Message>>(&sql)
+                .bind(queue_id)
+                .bind(now_ms)
+                .bind(pivot)
+                .fetch_optional(self.reader())
+                .await?;
+            if let Some(row) = row
+                && !rows.iter().any(|r| r.row.id == row.row.id)
+            {
+                rows.push(row);
+            }
+        }
+        self.codec.unpack_all(rows)
+    }
+
+    async fn oldest_message_created_at(
+        &self,
+        queue_id: i64,
+        now_ms: i64,
+    ) -> sqlx::Result<Option<i64>> {
+        sqlx::query_scalar(
+            "SELECT MIN(created_at) FROM message
+             WHERE queue_id = ?
+               AND dead_at IS NULL
+               AND (expires_at IS NULL OR expires_at > ?)",
+        )
+        .bind(queue_id)
+        .bind(now_ms)
+        .fetch_one(self.reader())
+        .await
+    }
+
+    async fn count_queued_messages_by_queue(
+        &self,
+        queue_id: i64,
+    ) -> sqlx::Result<i64> {
+        let count: Option<i64> =
+            sqlx::query_scalar("SELECT message_count FROM queue WHERE id = ?")
+                .bind(queue_id)
+                .fetch_optional(self.reader())
+                .await?;
+        Ok(count.unwrap_or(0))
+    }
+
+    async fn expire_messages(
+        &self,
+        now_ms: i64,
+    ) -> sqlx::Result<u64> {
+        retry_busy(|| async move {
+            const EXPIRED: &str = "dead_at IS NULL
+                   AND expires_at IS NOT NULL AND expires_at <= ?
+                   AND NOT (lease_token IS NOT NULL AND available_at > ?)";
+            let mut tx: Transaction<'_, Sqlite> = self.pool.begin().await?;
+            let count_sql = format!(
+                "UPDATE queue SET expired_count = expired_count + (
+                    SELECT COUNT(*) FROM message
+                    WHERE queue_id = queue.id AND {EXPIRED}
+                 )"
+            );
+            sqlx::query(&count_sql)
+                .bind(now_ms)
+                .bind(now_ms)
+                .execute(&mut *tx)
+                .await?;
+            let delete_sql = format!("DELETE FROM message WHERE {EXPIRED}");
+            let res = sqlx::query(&delete_sql)
+                .bind(now_ms)
+                .bind(now_ms)
+                .execute(&mut *tx)
+                .await?;
+            tx.commit().await?;
+            Ok(res.rows_affected())
+        })
+        .await
+    }
+
+    async fn doctor(
+        &self,
+        fix: bool,
+        now_ms: i64,
+    ) -> sqlx::Result<DoctorReport> {
+        const STUCK: &str = "lease_token IS NOT NULL
+               AND (dead_at IS NOT NULL OR available_at <= ?1)";
+        const IMPOSSIBLE: &str = "created_at < 0 OR created_at > ?1
+               OR available_at < created_at OR dead_at < created_at
+               OR expires_at < created_at";
+        let checks: Vec<String> = sqlx::query_scalar("PRAGMA integrity_check")
+            .fetch_all(&self.pool)
+            .await?;
+        let mut report = DoctorReport {
+            integrity_errors: checks
+                .into_iter()
+                .filter(|c| c != "ok")
+                .collect(),
+            fixed: fix,
+            ..DoctorReport::default()
+        };
+        let mut tx: Transaction<'_, Sqlite> = self.pool.begin().await?;
+        for (table, cond) in ORPHAN_CHECKS {
+            let sql = format!("SELECT COUNT(*) FROM {table} WHERE {cond}");
+            let n: i64 = sqlx::query_scalar(&sql).fetch_one(&mut *tx).await?;
+            report.orphaned_rows += n as u64;
+        }
+        let sql = format!("SELECT COUNT(*) FROM message WHERE {STUCK}");
+        let n: i64 =
+            sqlx::query_scalar(&sql).bind(now_ms).fetch_one(&mut *tx).await?;
+        report.stuck_leases = n as u64;
+        let sql = format!("SELECT COUNT(*) FROM message WHERE {IMPOSSIBLE}");
+        let n: i64 =
+            sqlx::query_scalar(&sql).bind(now_ms).fetch_one(&mut *tx).await?;
+        report.impossible_timestamps = n as u64;
+        let sql =
+            format!("SELECT COUNT(*) FROM queue WHERE {DRIFTED_COUNTERS}");
+        let n: i64 = sqlx::query_scalar(&sql).fetch_one(&mut *tx).await?;
+        report.drifted_counters = n as u64;
+
+        if fix {
+            for (table, cond) in ORPHAN_CHECKS {
+                let sql = format!("DELETE FROM {table} WHERE {cond}");
+                sqlx::query(&sql).execute(&mut *tx).await?;
+            }
+            sqlx::query(
+                "UPDATE message SET lease_token = NULL
+                 WHERE lease_token IS NOT NULL AND dead_at IS NOT NULL",
+            )
+            .execute(&mut *tx)
+            .await?;
+            reap_leases(&mut tx, None, now_ms).await?;
+            sqlx::query(
+                "UPDATE message SET created_at = ?1
+                 WHERE created_at < 0 OR created_at > ?1",
+            )
+            .bind(now_ms)
+            .execute(&mut *tx)
+            .await?;
+            sqlx::query(CLAMP_TIMESTAMPS_SQL).execute(&mut *tx).await?;
+            sqlx::query(RECOUNT_SQL).execute(&mut *tx).await?;
+        }
+        tx.commit().await?;
+        Ok(report)
+    }
+
+    async fn recovery_scan(
+        &self,
+        repair: bool,
+        now_ms: i64,
+    ) -> sqlx::Result<RecoveryReport> {
+        const STRANDED: &str = "lease_token IS NOT NULL AND dead_at IS NULL
+               AND available_at > ?1
+               AND NOT EXISTS (
+                 SELECT 1 FROM message_attempt a
+                 WHERE a.message_id = message.id
+                   AND a.lease_token = message.lease_token
+                   AND a.outcome IS NULL)";
+        let horizon = now_ms + STRANDED_LEASE_MS;
+        let sql = format!("SELECT COUNT(*) FROM message WHERE {STRANDED}");
+        let stranded: i64 = sqlx::query_scalar(&sql)
+            .bind(horizon)
+            .fetch_one(&self.pool)
+            .await?;
+        let versions: Vec<i64> = sqlx::query_scalar(
+            "SELECT version FROM schema_version ORDER BY version",
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        let wal_bytes = self.wal_bytes().await?;
+        let report = RecoveryReport {
+            stranded_leases: stranded as u64,
+            schema_problems: schema_problems(
+                &versions,
+                self.latest_schema_version(),
+            ),
+            wal_bytes,
+            wal_oversized: wal_bytes.is_some_and(|b| b >= WAL_OVERSIZED_BYTES),
+            repaired: repair,
+        };
+        if repair {
+            let sql = format!(
+                "UPDATE message SET lease_token = NULL, available_at = ?2
+                 WHERE {STRANDED}"
+            );
+            sqlx::query(&sql)
+                .bind(horizon)
+                .bind(now_ms)
+                .execute(&self.pool)
+                .await?;
+            if report.wal_oversized {
+                sqlx::query("PRAGMA wal_checkpoint(TRUNCATE)")
+                    .execute(&self.pool)
+                    .await?;
+            }
+        }
+        Ok(report)
+    }
+
+    async fn reap_expired_leases(
+        &self,
+        now_ms: i64,
+    ) -> sqlx::Result<(u64, u64)> {
+        retry_busy(|| async move {
+            let mut tx: Transaction<'_, Sqlite> = self.pool.begin().await?;
+            let reaped = reap_leases(&mut tx, None, now_ms).await?;
+            tx.commit().await?;
+            Ok(reaped)
+        })
+        .await
+    }
+
+    async fn count_expired_messages(
+        &self,
+        queue_id: i64,
+    ) -> sqlx::Result<i64> {
+        sqlx::query_scalar("SELECT expired_count FROM queue WHERE id = ?")
+            .bind(queue_id)
+            .fetch_one(self.reader())
+            .await
+    }
+
+    async fn queue_metrics(
+        &self,
+        queue_id: i64,
+        now_ms: i64,
+    ) -> sqlx::Result<QueueMetrics> {
+        sqlx::query_as::<_, QueueMetrics>(
+            "SELECT
+               (SELECT COUNT(*) FROM message
+                WHERE queue_id = ?1 AND dead_at IS NULL
+                  AND lease_token IS NOT NULL AND available_at > ?2) AS leased,
+               (SELECT COUNT(*) FROM message
+                WHERE queue_id = ?1 AND dead_at IS NULL
+                  AND lease_token IS NULL AND available_at > ?2
+                  AND (expires_at IS NULL OR expires_at > ?2)) AS delayed,
+               (SELECT MIN(created_at) FROM message
+                WHERE queue_id = ?1 AND dead_at IS NULL AND available_at <= ?2
+                  AND (expires_at IS NULL OR expires_at > ?2)) AS oldest_ready_at,
+               enqueued_count AS enqueued,
+               acked_count AS acked,
+               ack_latency_ms_total
+             FROM queue WHERE id = ?1",
+        )
+        .bind(queue_id)
+        .bind(now_ms)
+        .fetch_one(self.reader())
+        .await
+    }
+
+    async fn count_dead_messages(
+        &self,
+        queue_id: i64,
+    ) -> sqlx::Result<i64> {
+        let count: Option<i64> =
+            sqlx::query_scalar("SELECT dead_count FROM queue WHERE id = ?")
+                .bind(queue_id)
+                .fetch_optional(self.reader())
+                .await?;
+        Ok(count.unwrap_or(0))
+    }
+
+    async fn compact(&self) -> sqlx::Result<()> {
+        sqlx::query("VACUUM").execute(&self.pool).await?;
+        Ok(())
+    }
+
+    async fn compact_incremental(&self) -> sqlx::Result<()> {
+        // The auto_vacuum mode only takes effect through a VACUUM run on
+        // the connection that set it
+        let mut conn = self.pool.acquire().await?;
+        let mode: i64 = sqlx::query_scalar("PRAGMA auto_vacuum")
+            .fetch_one(&mut *conn)
+            .await?;
+        if mode == 2 {
+            sqlx::query("PRAGMA incremental_vacuum")
+                .execute(&mut *conn)
+                .await?;
+        } else {
+            sqlx::query("PRAGMA auto_vacuum = INCREMENTAL")
+                .execute(&mut *conn)
+                .await?;
+            sqlx::query("VACUUM").execute(&mut *conn).await?;
+        }
+        Ok(())
+    }
+
+    async fn db_status(&self) -> sqlx::Result<DbStatus> {
+        let page_size: i64 = sqlx::query_scalar("PRAGMA page_size")
+            .fetch_one(&self.pool)
+            .await?;
+        let page_count: i64 = sqlx::query_scalar("PRAGMA page_count")
+            .fetch_one(&self.pool)
+            .await?;
+        let free_pages: i64 = sqlx::query_scalar("PRAGMA freelist_count")
+            .fetch_one(&self.pool)
+            .await?;
+        let wal_bytes = self.wal_bytes().await?;
+        // One row per b-tree; indexes are counted with their table
+        let tables = sqlx::query_as(
+            "SELECT s.tbl_name AS name, SUM(d.pgsize) AS bytes
+             FROM dbstat('main', 1) d JOIN sqlite_schema s ON s.name = d.name
+             GROUP BY s.tbl_name
+             ORDER BY bytes DESC, name",
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        let queues =
+            sqlx::query_as(QUEUE_USAGE_SQL).fetch_all(self.reader()).await?;
+        Ok(DbStatus {
+            file_bytes: page_count * page_size,
+            wal_bytes,
+            page_size,
+            page_count,
+            free_pages,
+            tables,
+            queues,
+            compact_recommended: false,
+            auto_compact: None,
+        })
+    }
+
+    async fn backup(
+        &self,
+        path: &Path,
+    ) -> sqlx::Result<()> {
+        // A read transaction: writers carry on while the snapshot is written
+        sqlx::query("VACUUM INTO ?")
+            .bind(path.to_string_lossy())
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn recompress_payloads(&self) -> sqlx::Result<u64> {
+        if self.codec.compress_threshold == 0 {
+            return Ok(0);
+        }
+        let mut compressed = 0;
+        for table in ["message", "message_archive"] {
+            let select_sql = format!(
+                "SELECT id, payload FROM {table}
+                 WHERE payload_encoding IS NULL AND id > ?
+                   AND length(CAST(payload AS BLOB)) > ?
+                 ORDER BY id LIMIT ?"
+            );
+            let update_sql = format!(
+                "UPDATE {table}
+                 SET payload = ?, payload_encoding = ?, payload_key_id = ?
+                 WHERE id = ? AND payload_encoding IS NULL"
+            );
+            let mut after_id = 0;
+            loop {
+                let rows: Vec<(i64, String)> = sqlx::query_as(&select_sql)
+                    .bind(after_id)
+                    .bind(self.codec.compress_threshold as i64)
+                    .bind(RECOMPRESS_BATCH)
+                    .fetch_all(&self.pool)
+                    .await?;
+                let Some(&(last_id, _)) = rows.last() else {
+                    break;
+                };
+                after_id = last_id;
+                let mut tx = self.pool.begin().await?;
+                for (id, payload) in rows {
+                    let Some(packed) = self.codec.pack(&payload)? else {
+                        continue;
+                    };
+                    compressed += sqlx::query(&update_sql)
+                        .bind(packed.bytes)
+                        .bind(packed.encoding)
+                        .bind(packed.key_id)
+                        .bind(id)
+                        .execute(&mut *tx)
+                        .await?
+                        .rows_affected();
+                }
+                tx.commit().await?;
+            }
+        }
+        Ok(compressed)
+    }
+
+    async fn rotate_payload_key(&self) -> sqlx::Result<u64> {
+        let Some(keyring) = &self.codec.keyring else {
+            return Err(sqlx::Error::Configuration(
+                "no encryption key is configured".into(),
+            ));
+        };
+        let active = keyring.active_id();
+        let mut rotated = 0;
+        for table in ["message", "message_archive"] {
+            let select_sql = format!(
+                "SELECT id, CAST(payload AS BLOB), payload_encoding,
+                        payload_key_id
+                 FROM {table}
+                 WHERE payload_key_id IS NOT ? AND id > ?
+                 ORDER BY id LIMIT ?"
+            );
+            let update_sql = format!(
+                "UPDATE {table}
+                 SET payload = ?, payload_encoding = ?, payload_key_id = ?
+                 WHERE id = ? AND payload_key_id IS ?"
+            );
+            let mut after_id = 0;
+            loop {
+                let rows: Vec<StoredPayload> = sqlx::query_as(&select_sql)
+                    .bind(active)
+                    .bind(after_id)
+                    .bind(RECOMPRESS_BATCH)
+                    .fetch_all(&self.pool)
+                    .await?;
+                let Some((last_id, ..)) = rows.last() else {
+                    break;
+                };
+                after_id = *last_id;
+                let mut tx = self.pool.begin().await?;
+                for (id, bytes, encoding, key_id) in rows {
+                    let payload = match &encoding {
+                        Some(encoding) => self.codec.unpack(
+                            &bytes,
+                            encoding,
+                            key_id.as_deref(),
+                        )?,
+                        None => String::from_utf8(bytes)
+                            .map_err(|e| sqlx::Error::Decode(Box::new(e)))?,
+                    };
+                    let Some(packed) = self.codec.pack(&payload)? else {
+                        continue;
+                    };
+                    rotated += sqlx::query(&update_sql)
+                        .bind(packed.bytes)
+                        .bind(packed.encoding)
+                        .bind(packed.key_id)
+                        .bind(id)
+                        .bind(key_id)
+                        .execute(&mut *tx)
+                        .await?
+                        .rows_affected();
+                }
+                tx.commit().await?;
+            }
+        }
+        Ok(rotated)
+    }
+    async fn nack_messages(
+        &self,
+        nacks: &[(i64, i64)],
+        lease_token: &str,
+        reason: Option<&str>,
+    ) -> sqlx::Result<(Vec<i64>, Vec<i64>)> {
+        if nacks.is_empty() {
+            return Ok((Vec::new(), Vec::new()));
+        }
+        let mut tx: Transaction<'_, Sqlite> = self.pool.begin().await?;
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as i64;
+
+        // Requeue only messages whose lease is held by the caller, releasing the
+        // lease. Writing first takes the write lock up front, so a concurrent
+        // writer cannot invalidate this transaction's snapshot mid-way.
+        let update_sql = format!(
+            "WITH nack(id, delay_ms) AS (VALUES {})
+             UPDATE message SET attempts = attempts + 1, lease_token = NULL,
+               last_error = COALESCE(?, last_error),
+               available_at = ? + (
+                 SELECT MAX(delay_ms) FROM nack WHERE nack.id = message.id)
+             WHERE id IN (SELECT id FROM nack) AND dead_at IS NULL
+               AND lease_token = ? AND available_at > ?
+             RETURNING id",
+            nack_values(nacks.len())
+        );
+        let mut uq = sqlx::query_scalar::<_, i64>(&update_sql);
+        for &(id, delay_ms) in nacks {
+            uq = uq.bind(id).bind(delay_ms.max(0));
+        }
+        let ids = uq
+            .bind(reason)
+            .bind(now)
+            .bind(lease_token)
+            .bind(now)
+            .fetch_all(&mut *tx)
+            .await?;
+        if ids.is_empty() {
+            tx.commit().await?;
+            return Ok((Vec::new(), Vec::new()));
+        }
+        let placeholders =
+            std::iter::repeat_n("?", ids.len()).collect::<Vec<_>>().join(",");
+
+        // Queues with backoff configured override the requested delay
+        let backoff_sql = format!(
+            "SELECT m.id, m.attempts, q.backoff_base_ms, q.backoff_multiplier,
+                    q.backoff_max_ms, q.backoff_jitter
+             FROM message m JOIN queue q ON q.id = m.queue_id
+             WHERE m.id IN ({}) AND q.backoff_base_ms IS NOT NULL",
+            placeholders
+        );
+        let mut bq = sqlx::query_as::<_, BackoffRow>(&backoff_sql);
+        for id in &ids {
+            bq = bq.bind(id);
+        }
+        for (id, attempts, base, multiplier, max, jitter) in
+            bq.fetch_all(&mut *tx).await?
+        {
+            let delay = backoff_delay(attempts, base, multiplier, max, jitter);
+            sqlx::query("UPDATE message SET available_at = ? WHERE id = ?")
+                .bind(now + delay)
+                .bind(id)
+                .execute(&mut *tx)
+                .await?;
+        }
+
+        // Move messages exceeding max_attempts to the dead-letter queue
+        let dead_sql = format!(
+            "UPDATE message SET dead_at = ?
+             WHERE id IN (
+                SELECT m.id FROM message m
+                JOIN queue q ON q.id = m.queue_id
+                WHERE m.id IN ({}) AND m.dead_at IS NULL
+                  AND m.attempts >= q.max_attempts
+             )
+             RETURNING id",
+            placeholders
+        );
+        let mut dq = sqlx::query_scalar::<_, i64>(&dead_sql).bind(now);
+        for id in &ids {
+            dq = dq.bind(id);
+        }
+        let dead = dq.fetch_all(&mut *tx).await?;
+        send_receipts(&mut tx, &dead, "dead", now).await?;
+
+        tx.commit().await?;
+        let requeued =
+            ids.into_iter().filter(|id| !dead.contains(id)).collect();
+        Ok((requeued, dead))
+    }
+
+    async fn remove_message_by_id(
+        &self,
+        id: i64,
+    ) -> sqlx::Result<u64> {
+        let res = sqlx::query("DELETE FROM message WHERE id = ?")
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(res.rows_affected())
+    }
+
+    async fn list_dead_letters(
+        &self,
+        queue_name: &str,
+        limit: i64,
+    ) -> sqlx::Result<Vec<Message>> {
+        let sql = format!(
+            "SELECT {MESSAGE_COLUMNS}
+             FROM message
+             WHERE queue_id = (SELECT id FROM queue WHERE name = ?)
+               AND dead_at IS NOT NULL
+             ORDER BY dead_at, id
+             LIMIT ?"
+        );
+        let rows = sqlx::query_as::<_, Packed<Message>>(&sql)
+            .bind(queue_name)
+            .bind(limit)
+            .fetch_all(self.reader())
+            .await?;
+        self.codec.unpack_all(rows)
+    }
+
+    async fn redrive_dead_letters(
+        &self,
+        queue_name: &str,
+        ids: &[i64],
+    ) -> sqlx::Result<u64> {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as i64;
+        let mut sql = String::from(
+            "UPDATE message SET dead_at = NULL, attempts = 0, available_at = ?,
+                                lease_token = NULL, lease_expirations = 0
+             WHERE queue_id = (SELECT id FROM queue WHERE name = ?)
+               AND dead_at IS NOT NULL",
+        );
+        if !ids.is_empty() {
+            let placeholders = std::iter::repeat_n("?", ids.len())
+                .collect::<Vec<_>>()
+                .join(",");
+            sql.push_str(&format!(" AND id IN ({})", placeholders));
+        }
+        let mut q = sqlx::query(&sql).bind(now).bind(queue_name);
+        for id in ids {
+            q = q.bind(id);
+        }
+        let res = q.execute(&self.pool).await?;
+        Ok(res.rows_affected())
+    }
+
+    async fn move_messages(
+        &self,
+        ids: &[i64],
+        target_queue: &str,
+        from_queue: Option<&str>,
+        reset_attempts: bool,
+    ) -> sqlx::Result<u64> {
+        if ids.is_empty() {
+            return Ok(0);
+        }
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as i64;
+        let placeholders =
+            std::iter::repeat_n("?", ids.len()).collect::<Vec<_>>().join(",");
+        let sql = format!(
+            "UPDATE message
+             SET queue_id = (SELECT id FROM queue WHERE name = ?),
+                 attempts = CASE WHEN ? THEN 0 ELSE attempts END,
+                 lease_expirations =
+                   CASE WHEN ? THEN 0 ELSE lease_expirations END,
+                 dead_at = NULL, lease_token = NULL, dedup_key = NULL,
+                 available_at = ?
+             WHERE id IN ({})
+               AND (dead_at IS NOT NULL OR lease_token IS NULL
+                    OR available_at <= ?)
+               AND (? IS NULL
+                    OR queue_id = (SELECT id FROM queue WHERE name = ?))",
+            placeholders
+        );
+        let mut q = sqlx::query(&sql)
+            .bind(target_queue)
+            .bind(reset_attempts)
+            .bind(reset_attempts)
+            .bind(now);
+        for id in ids {
+            q = q.bind(id);
+        }
+        let res = q
+            .bind(now)
+            .bind(from_queue)
+            .bind(from_queue)
+            .execute(&self.pool)
+            .await?;
+        Ok(res.rows_affected())
+    }
+
+    async fn create_consumer_group(
+        &self,
+        g: &ConsumerGroup,
+    ) -> sqlx::Result<i64> {
+        let rec = sqlx::query(
+            "INSERT INTO consumer_group (queue_id, name, start_id, created_at)
+             VALUES (?, ?, (SELECT COALESCE(MAX(id), 0) FROM message), ?)",
+        )
+        .bind(g.queue_id)
+        .bind(&g.name)
+        .bind(g.created_at)
+        .execute(&self.pool)
+        .await?;
+        Ok(rec.last_insert_rowid())
+    }
+
+    async fn list_consumer_groups(
+        &self,
+        queue_name: &str,
+    ) -> sqlx::Result<Vec<ConsumerGroup>> {
+        sqlx::query_as::<_, ConsumerGroup>(
+            "SELECT id, queue_id, name, start_id, created_at
+             FROM consumer_group
+             WHERE queue_id = (SELECT id FROM queue WHERE name = ?)
+             ORDER BY id",
+        )
+        .bind(queue_name)
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    async fn delete_consumer_group(
+        &self,
+        queue_name: &str,
+        group: &str,
+    ) -> sqlx::Result<u64> {
+        let mut tx = self.pool.begin().await?;
+        let res = sqlx::query(
+            "DELETE FROM consumer_group
+             WHERE queue_id = (SELECT id FROM queue WHERE name = ?) AND name = ?",
+        )
+        .bind(queue_name)
+        .bind(group)
+        .execute(&mut *tx)
+        .await?;
+        let sql = format!(
+            "DELETE FROM message
+             WHERE queue_id = (SELECT id FROM queue WHERE name = ?)
+               AND {DONE_BY_ALL_GROUPS}"
+        );
+        sqlx::query(&sql).bind(queue_name).execute(&mut *tx).await?;
+        tx.commit().await?;
+        Ok(res.rows_affected())
+    }
+
+    async fn poll_group_messages(
+        &self,
+        queue_name: &str,
+        group: &str,
+        limit: i64,
+        visibility_ms: i64,
+    ) -> sqlx::Result<Vec<Message>> {
+        let now = now_ms();
+        let lease_token = uuid::Uuid::new_v4().to_string();
+        let mut tx = self.pool.begin().await?;
+        reap_leases(&mut tx, Some(queue_name), now).await?;
+        let (limit, bucket, order) =
+            rate_limit(&mut tx, queue_name, limit, now).await?;
+        let order = order.ready().group_sql();
+        // Lease in a single write, so the transaction holds the write lock
+        // before it reads; a delivery row is created on first lease
+        let sql = format!(
+            "INSERT INTO group_delivery
+               (consumer_group_id, message_id, attempts, available_at, lease_token)
+             SELECT cg.id, m.id, 0, ?, ?
+             FROM consumer_group cg
+             JOIN message m ON m.queue_id = cg.queue_id AND m.id > cg.start_id
+             LEFT JOIN group_delivery gd
+               ON gd.consumer_group_id = cg.id AND gd.message_id = m.id
+             WHERE cg.queue_id = (SELECT id FROM queue WHERE name = ?)
+               AND cg.name = ?
+               AND m.dead_at IS NULL
+               AND m.available_at <= ?
+               AND (m.expires_at IS NULL OR m.expires_at > ?)
+               AND (gd.message_id IS NULL
+                    OR (gd.acked_at IS NULL AND gd.dead_at IS NULL
+                        AND gd.available_at <= ?))
+             ORDER BY {order}
+             LIMIT ?
+             ON CONFLICT (consumer_group_id, message_id) DO UPDATE
+             SET available_at = excluded.available_at,
+                 lease_token = excluded.lease_token"
+        );
+        let leased = sqlx::query(&sql)
+            .bind(now + visibility_ms.max(0))
+            .bind(&lease_token)
+            .bind(queue_name)
+            .bind(group)
+            .bind(now)
+            .bind(now)
+            .bind(now)
+            .bind(limit)
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+        if let Some(tokens) = bucket {
+            spend_tokens(&mut tx, queue_name, tokens, leased, now).await?;
+        }
+        let sql = format!(
+            "SELECT {GROUP_MESSAGE_COLUMNS}
+             FROM group_delivery gd JOIN message m ON m.id = gd.message_id
+             WHERE gd.lease_token = ?
+             ORDER BY {order}"
+        );
+        let rows = sqlx::query_as::<_, Packed<Message>>(&sql)
+            .bind(&lease_token)
+            .fetch_all(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        self.codec.unpack_all(rows)
+    }
+
+    async fn ack_group_messages(
+        &self,
+        ids: &[i64],
+        lease_token: &str,
+    ) -> sqlx::Result<Vec<i64>> {
+        retry_busy(|| async move {
+            if ids.is_empty() {
+                return Ok(Vec::new());
+            }
+            let now = now_ms();
+            let placeholders = std::iter::repeat_n("?", ids.len())
+                .collect::<Vec<_>>()
+                .join(",");
+            let mut tx = self.pool.begin().await?;
+            let sql = format!(
+                "UPDATE group_delivery SET acked_at = ?, lease_token = NULL
+                 WHERE message_id IN ({placeholders})
+                   AND lease_token = ? AND available_at > ?
+                 RETURNING message_id"
+            );
+            let mut q = sqlx::query_scalar::<_, i64>(&sql).bind(now);
+            for id in ids {
+                q = q.bind(id);
+            }
+            let acked =
+                q.bind(lease_token).bind(now).fetch_all(&mut *tx).await?;
+            record_acks(&mut tx, &acked, now).await?;
+            delete_finished(&mut tx, &acked).await?;
+            tx.commit().await?;
+            Ok(acked)
+        })
+        .await
+    }
+
+    async fn nack_group_messages(
+        &self,
+        nacks: &[(i64, i64)],
+        lease_token: &str,
+    ) -> sqlx::Result<(Vec<i64>, Vec<i64>)> {
+        if nacks.is_empty() {
+            return Ok((Vec::new(), Vec::new()));
+        }
+        let now = now_ms();
+        let mut tx = self.pool.begin().await?;
+        let sql = format!(
+            "WITH nack(id, delay_ms) AS (VALUES {})
+             UPDATE group_delivery
+             SET attempts = attempts + 1, lease_token = NULL,
+               available_at = ? + (
+                 SELECT MAX(delay_ms) FROM nack
+                 WHERE nack.id = group_delivery.message_id)
+             WHERE message_id IN (SELECT id FROM nack)
+               AND lease_token = ? AND available_at > ?
+             RETURNING consumer_group_id, message_id",
+            nack_values(nacks.len())
+        );
+        let mut q = sqlx::query_as::<_, (i64, i64)>(&sql);
+        for &(id, delay_ms) in nacks {
+            q = q.bind(id).bind(delay_ms.max(0));
+        }
+        let nacked =
+            q.bind(now).bind(lease_token).bind(now).fetch_all(&mut *tx).await?;
+        // A lease token belongs to a single group
+        let Some(&(group_id, _)) = nacked.first() else {
+            tx.commit().await?;
+            return Ok((Vec::new(), Vec::new()));
+        };
+        let placeholders = std::iter::repeat_n("?", nacked.len())
+            .collect::<Vec<_>>()
+            .join(",");
+        let sql = format!(
+            "UPDATE group_delivery SET dead_at = ?
+             WHERE consumer_group_id = ? AND message_id IN ({placeholders})
+               AND attempts >= (
+                 SELECT q.max_attempts FROM consumer_group cg
+                 JOIN queue q ON q.id = cg.queue_id
+                 WHERE cg.id = group_delivery.consumer_group_id)
+             RETURNING message_id"
+        );
+        let mut q = sqlx::query_scalar::<_, i64>(&sql).bind(now).bind(group_id);
+        for (_, id) in &nacked {
+            q = q.bind(id);
+        }
+        let dead = q.fetch_all(&mut *tx).await?;
+        delete_finished(&mut tx, &dead).await?;
+        tx.commit().await?;
+        let requeued = nacked
+            .into_iter()
+            .map(|(_, id)| id)
+            .filter(|id| !dead.contains(id))
+            .collect();
+        Ok((requeued, dead))
+    }
+
+    async fn extend_group_visibility(
+        &self,
+        ids: &[i64],
+        lease_token: &str,
+        extra_ms: i64,
+    ) -> sqlx::Result<u64> {
+        if ids.is_empty() {
+            return Ok(0);
+        }
+        let placeholders =
+            std::iter::repeat_n("?", ids.len()).collect::<Vec<_>>().join(",");
+        let sql = format!(
+            "UPDATE group_delivery SET available_at = available_at + ?
+             WHERE message_id IN ({placeholders})
+               AND lease_token = ? AND available_at > ?"
+        );
+        let mut q = sqlx::query(&sql).bind(extra_ms.max(0));
+        for id in ids {
+            q = q.bind(id);
+        }
+        let res =
+            q.bind(lease_token).bind(now_ms()).execute(&self.pool).await?;
+        Ok(res.rows_affected())
+    }
+
+    async fn purge_dead_letters(
+        &self,
+        queue_name: &str,
+    ) -> sqlx::Result<u64> {
+        let res = sqlx::query(
+            "DELETE FROM message
+             WHERE queue_id = (SELECT id FROM queue WHERE name = ?)
+               AND dead_at IS NOT NULL",
+        )
+        .bind(queue_name)
+        .execute(&self.pool)
+        .await?;
+        Ok(res.rows_affected())
+    }
+
+    async fn list_archived_messages(
+        &self,
+        queue_name: &str,
+        limit: i64,
+    ) -> sqlx::Result<Vec<ArchivedMessage>> {
+        let sql = format!(
+            "SELECT {ARCHIVED_MESSAGE_COLUMNS}
+             FROM message_archive
+             WHERE queue_id = (SELECT id FROM queue WHERE name = ?)
+             ORDER BY acked_at DESC, id DESC
+             LIMIT ?"
+        );
+        let rows = sqlx::query_as::<_, Packed<ArchivedMessage>>(&sql)
+            .bind(queue_name)
+            .bind(limit)
+            .fetch_all(&self.pool)
+            .await?;
+        rows.into_iter().map(|row| row.unpack(&self.codec)).collect()
+    }
+
+    async fn replay_archived_messages(
+        &self,
+        queue_name: &str,
+        from_ms: Option<i64>,
+        to_ms: Option<i64>,
+        payload_contains: Option<&str>,
+    ) -> sqlx::Result<u64> {
+        // Compressed or encrypted payloads never match a contains filter,
+        // as with peek
+        let now = now_ms();
+        let res = sqlx::query(
+            "INSERT INTO message (queue_id, payload, payload_encoding, payload_key_id, attempts, available_at, created_at, priority, group_id)
+             SELECT queue_id, payload, payload_encoding, payload_key_id, 0, ?, ?, priority, group_id
+             FROM message_archive
+             WHERE queue_id = (SELECT id FROM queue WHERE name = ?)
+               AND (? IS NULL OR acked_at >= ?)
+               AND (? IS NULL OR acked_at < ?)
+               AND (? IS NULL OR (payload_encoding IS NULL
+                                  AND instr(payload, ?) > 0))
+             ORDER BY id",
+        )
+        .bind(now)
+        .bind(now)
+        .bind(queue_name)
+        .bind(from_ms)
+        .bind(from_ms)
+        .bind(to_ms)
+        .bind(to_ms)
+        .bind(payload_contains)
+        .bind(payload_contains)
+        .execute(&self.pool)
+        .await?;
+        Ok(res.rows_affected())
+    }
+
+    async fn purge_archived_messages(
+        &self,
+        now_ms: i64,
+    ) -> sqlx::Result<u64> {
+        let res =
+            sqlx::query("DELETE FROM message_archive WHERE purge_at <= ?")
+                .bind(now_ms)
+                .execute(&self.pool)
+                .await?;
+        Ok(res.rows_affected())
+    }
+
+    async fn record_stats_history(
+        &self,
+        now_ms: i64,
+    ) -> sqlx::Result<u64> {
+        // The counts match those of `queue_metrics` and the stats endpoint
+        let sql = format!(
+            "INSERT INTO queue_stats_history (queue_id, recorded_at, ready, leased, delayed, dlq, enqueued, acked)
+             SELECT q.id, ?1, {READY_COUNT},
+               (SELECT COUNT(*) FROM message
+                WHERE queue_id = q.id AND dead_at IS NULL
+                  AND lease_token IS NOT NULL AND available_at > ?1),
+               (SELECT COUNT(*) FROM message
+                WHERE queue_id = q.id AND dead_at IS NULL
+                  AND lease_token IS NULL AND available_at > ?1
+                  AND (expires_at IS NULL OR expires_at > ?1)),
+               q.dead_count, q.enqueued_count, q.acked_count
+             FROM queue q",
+        );
+        let res = sqlx::query(&sql).bind(now_ms).execute(&self.pool).await?;
+        Ok(res.rows_affected())
+    }
+
+    async fn list_stats_history(
+        &self,
+        queue_name: &str,
+        since_ms: i64,
+    ) -> sqlx::Result<Vec<StatsSample>> {
+        sqlx::query_as(
+            "SELECT recorded_at, ready, leased, delayed, dlq, enqueued, acked
+             FROM queue_stats_history
+             WHERE queue_id = (SELECT id FROM queue WHERE name = ?)
+               AND recorded_at >= ?
+             ORDER BY recorded_at, id",
+        )
+        .bind(queue_name)
+        .bind(since_ms)
+        .fetch_all(self.reader())
+        .await
+    }
+
+    async fn purge_stats_history(
+        &self,
+        before_ms: i64,
+    ) -> sqlx::Result<u64> {
+        let res = sqlx::query(
+            "DELETE FROM queue_stats_history WHERE recorded_at < ?",
+        )
+        .bind(before_ms)
+        .execute(&self.pool)
+        .await?;
+        Ok(res.rows_affected())
+    }
+
+    async fn create_schedule(
+        &self,
+        s: &Schedule,
+    ) -> sqlx::Result<i64> {
+        let rec = sqlx::query(
+            "INSERT INTO schedule (queue_id, cron, payload, next_run_at, created_at) VALUES (?, ?, ?, ?, ?)",
+        )
+        .bind(s.queue_id)
+        .bind(&s.cron)
+        .bind(&s.payload)
+        .bind(s.next_run_at)
+        .bind(s.created_at)
+        .execute(&self.pool)
+        .await?;
+        Ok(rec.last_insert_rowid())
+    }
+
+    async fn list_schedules(
+        &self,
+        queue_name: Option<&str>,
+    ) -> sqlx::Result<Vec<Schedule>> {
+        sqlx::query_as::<_, Schedule>(
+            "SELECT id, queue_id, cron, payload, next_run_at, created_at
+             FROM schedule
+             WHERE ?1 IS NULL OR queue_id = (SELECT id FROM queue WHERE name = ?1)
+             ORDER BY id",
+        )
+        .bind(queue_name)
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    async fn delete_schedule(
+        &self,
+        id: i64,
+    ) -> sqlx::Result<bool> {
+        let res = sqlx::query("DELETE FROM schedule WHERE id = ?")
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(res.rows_affected() > 0)
+    }
+
+    async fn due_schedules(
+        &self,
+        now_ms: i64,
+    ) -> sqlx::Result<Vec<Schedule>> {
+        sqlx::query_as::<_, Schedule>(
+            "SELECT id, queue_id, cron, payload, next_run_at, created_at
+             FROM schedule
+             WHERE next_run_at <= ?
+             ORDER BY next_run_at, id",
+        )
+        .bind(now_ms)
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    async fn fire_schedule(
+        &self,
+        id: i64,
+        prev_run_at: i64,
+        next_run_at: i64,
+        msg: &Message,
+    ) -> sqlx::Result<bool> {
+        let mut tx: Transaction<'_, Sqlite> = self.pool.begin().await?;
+        let res = sqlx::query(
+            "UPDATE schedule SET next_run_at = ? WHERE id = ? AND next_run_at = ?",
+        )
+        .bind(next_run_at)
+        .bind(id)
+        .bind(prev_run_at)
+        .execute(&mut *tx)
+        .await?;
+        if res.rows_affected() == 0 {
+            tx.rollback().await?;
+            return Ok(false);
+        }
+        insert_message(&mut tx, msg, &self.codec).await?;
+        tx.commit().await?;
+        Ok(true)
+    }
+
+    async fn create_alarm(
+        &self,
+        a: &Alarm,
+    ) -> sqlx::Result<i64> {
+        let rec = sqlx::query(
+            "INSERT INTO alarm (queue_id, metric, threshold, for_ms, webhook_url, created_at) VALUES (?, ?, ?, ?, ?, ?)",
+        )
+        .bind(a.queue_id)
+        .bind(&a.metric)
+        .bind(a.threshold)
+        .bind(a.for_ms)
+        .bind(&a.webhook_url)
+        .bind(a.created_at)
+        .execute(&self.pool)
+        .await?;
+        Ok(rec.last_insert_rowid())
+    }
+
+    async fn list_alarms(
+        &self,
+        queue_name: Option<&str>,
+    ) -> sqlx::Result<Vec<Alarm>> {
+        let sql = format!(
+            "SELECT {ALARM_COLUMNS}
+             FROM alarm
+             WHERE ?1 IS NULL OR queue_id = (SELECT id FROM queue WHERE name = ?1)
+             ORDER BY id"
+        );
+        sqlx::query_as::<_, Alarm>(&sql)
+            .bind(queue_name)
+            .fetch_all(&self.pool)
+            .await
+    }
+
+    async fn delete_alarm(
+        &self,
+        id: i64,
+    ) -> sqlx::Result<bool> {
+        let res = sqlx::query("DELETE FROM alarm WHERE id = ?")
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(res.rows_affected() > 0)
+    }
+
+    async fn set_alarm_state(
+        &self,
+        id: i64,
+        breached_since: Option<i64>,
+        firing: bool,
+    ) -> sqlx::Result<()> {
+        sqlx::query(
+            "UPDATE alarm SET breached_since = ?, firing = ? WHERE id = ?",
+        )
+        .bind(breached_since)
+        .bind(firing)
+        .bind(id)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn create_api_key(
+        &self,
+        key: &ApiKey,
+    ) -> sqlx::Result<i64> {
+        let sql = "INSERT INTO api_key (name, key_hash, role, queue_pattern, created_at) VALUES (?, ?, ?, ?, ?)";
+        let rec = sqlx::query(sql)
+            .bind(&key.name)
+            .bind(&key.key_hash)
+            .bind(&key.role)
+            .bind(&key.queue_pattern)
+            .bind(key.created_at)
+            .execute(&self.pool)
+            .await?;
+        Ok(rec.last_insert_rowid())
+    }
+
+    async fn list_api_keys(&self) -> sqlx::Result<Vec<ApiKey>> {
+        sqlx::query_as::<_, ApiKey>(
+            "SELECT id, name, key_hash, role, queue_pattern, created_at FROM api_key ORDER BY name",
+        )
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    async fn delete_api_key(
+        &self,
+        name: &str,
+    ) -> sqlx::Result<bool> {
+        let res = sqlx::query("DELETE FROM api_key WHERE name = ?")
+            .bind(name)
+            .execute(&self.pool)
+            .await?;
+        Ok(res.rows_affected() > 0)
+    }
+
+    async fn set_push_config(
+        &self,
+        cfg: &PushConfig,
+    ) -> sqlx::Result<PushConfig> {
+        let sql = format!(
+            "INSERT INTO push_config ({PUSH_CONFIG_COLUMNS})
+             VALUES (?, ?, ?, ?, ?, ?, ?)
+             ON CONFLICT (queue_id) DO UPDATE SET
+               url = excluded.url, concurrency = excluded.concurrency,
+               timeout_ms = excluded.timeout_ms,
+               backoff_ms = excluded.backoff_ms,
+               updated_at = excluded.updated_at
+             RETURNING {PUSH_CONFIG_COLUMNS}"
+        );
+        sqlx::query_as::<_, PushConfig>(&sql)
+            .bind(cfg.queue_id)
+            .bind(&cfg.url)
+            .bind(cfg.concurrency)
+            .bind(cfg.timeout_ms)
+            .bind(cfg.backoff_ms)
+            .bind(cfg.created_at)
+            .bind(cfg.updated_at)
+            .fetch_one(&self.pool)
+            .await
+    }
+
+    async fn get_push_config(
+        &self,
+        queue_id: i64,
+    ) -> sqlx::Result<Option<PushConfig>> {
+        let sql = format!(
+            "SELECT {PUSH_CONFIG_COLUMNS} FROM push_config WHERE queue_id = ?"
+        );
+        sqlx::query_as::<_, PushConfig>(&sql)
+            .bind(queue_id)
+            .fetch_optional(&self.pool)
+            .await
+    }
+
+    async fn list_push_configs(&self) -> sqlx::Result<Vec<PushConfig>> {
+        let sql = format!(
+            "SELECT {PUSH_CONFIG_COLUMNS} FROM push_config ORDER BY queue_id"
+        );
+        sqlx::query_as::<_, PushConfig>(&sql).fetch_all(&self.pool).await
+    }
+
+    async fn delete_push_config(
+        &self,
+        queue_id: i64,
+    ) -> sqlx::Result<bool> {
+        let res = sqlx::query("DELETE FROM push_config WHERE queue_id = ?")
+            .bind(queue_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(res.rows_affected() > 0)
+    }
+
+    async fn record_push_deliveries(
+        &self,
+        log: &[PushDelivery],
+    ) -> sqlx::Result<()> {
+        let mut tx: Transaction<'_, Sqlite> = self.pool.begin().await?;
+        for d in log {
+            sqlx::query(
+                "INSERT INTO push_delivery (queue_id, message_id, attempt, status_code, error, outcome, duration_ms, delivered_at)
+                 VALUES (?, ?, ?, ?, ?, ?, ?, ?)",
+            )
+            .bind(d.queue_id)
+            .bind(d.message_id)
+            .bind(d.attempt)
+            .bind(d.status_code)
+            .bind(&d.error)
+            .bind(&d.outcome)
+            .bind(d.duration_ms)
+            .bind(d.delivered_at)
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await
+    }
+
+    async fn list_push_deliveries(
+        &self,
+        queue_id: i64,
+        limit: i64,
+    ) -> sqlx::Result<Vec<PushDelivery>> {
+        let sql = format!(
+            "SELECT {PUSH_DELIVERY_COLUMNS}
+             FROM push_delivery
+             WHERE queue_id = ?
+             ORDER BY id DESC
+             LIMIT ?"
+        );
+        sqlx::query_as::<_, PushDelivery>(&sql)
+            .bind(queue_id)
+            .bind(limit)
+            .fetch_all(&self.pool)
+            .await
+    }
+
+    async fn purge_push_deliveries(
+        &self,
+        before_ms: i64,
+    ) -> sqlx::Result<u64> {
+        let res =
+            sqlx::query("DELETE FROM push_delivery WHERE delivered_at < ?")
+                .bind(before_ms)
+                .execute(&self.pool)
+                .await?;
+        Ok(res.rows_affected())
+    }
+
+    async fn record_attempts(
+        &self,
+        leased: &[Message],
+        consumer: Option<&str>,
+        now_ms: i64,
+    ) -> sqlx::Result<()> {
+        if leased.is_empty() {
+            return Ok(());
+        }
+        let mut tx: Transaction<'_, Sqlite> = self.pool.begin().await?;
+        let placeholders = std::iter::repeat_n("?", leased.len())
+            .collect::<Vec<_>>()
+            .join(",");
+        let sql = format!(
+            "UPDATE message_attempt SET outcome = 'lease_expired', settled_at = ?
+             WHERE outcome IS NULL AND message_id IN ({placeholders})"
+        );
+        let mut q = sqlx::query(&sql).bind(now_ms);
+        for m in leased {
+            q = q.bind(m.id);
+        }
+        q.execute(&mut *tx).await?;
+        for m in leased {
+            sqlx::query(
+                "INSERT INTO message_attempt (message_id, queue_id, attempt, lease_token, leased_at, consumer)
+                 VALUES (?, ?, ?, ?, ?, ?)",
+            )
+            .bind(m.id)
+            .bind(m.queue_id)
+            .bind(m.attempts + 1)
+            .bind(m.lease_token.as_deref().unwrap_or_default())
+            .bind(now_ms)
+            .bind(consumer)
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await
+    }
+
+    async fn settle_attempts(
+        &self,
+        ids: &[i64],
+        lease_token: &str,
+        outcome: &str,
+        note: Option<&str>,
+        now_ms: i64,
+    ) -> sqlx::Result<()> {
+        if ids.is_empty() {
+            return Ok(());
+        }
+        let placeholders =
+            std::iter::repeat_n("?", ids.len()).collect::<Vec<_>>().join(",");
+        let sql = format!(
+            "UPDATE message_attempt SET outcome = ?, note = ?, settled_at = ?
+             WHERE lease_token = ? AND outcome IS NULL
+               AND message_id IN ({placeholders})"
+        );
+        let mut q = sqlx::query(&sql)
+            .bind(outcome)
+            .bind(note)
+            .bind(now_ms)
+            .bind(lease_token);
+        for id in ids {
+            q = q.bind(id);
+        }
+        q.execute(&self.pool).await?;
+        Ok(())
+    }
+
+    async fn list_attempts(
+        &self,
+        message_id: i64,
+    ) -> sqlx::Result<Vec<MessageAttempt>> {
+        let sql = format!(
+            "SELECT {ATTEMPT_COLUMNS} FROM message_attempt
+             WHERE message_id = ? ORDER BY id"
+        );
+        sqlx::query_as::<_, MessageAttempt>(&sql)
+            .bind(message_id)
+            .fetch_all(self.reader())
+            .await
+    }
+
+    async fn purge_attempts(
+        &self,
+        before_ms: i64,
+    ) -> sqlx::Result<u64> {
+        let res =
+            sqlx::query("DELETE FROM message_attempt WHERE leased_at < ?")
+                .bind(before_ms)
+                .execute(&self.pool)
+                .await?;
+        Ok(res.rows_affected())
+    }
+
+    async fn list_in_flight(
+        &self,
+        queue_name: &str,
+        now_ms: i64,
+        limit: i64,
+    ) -> sqlx::Result<Vec<InFlightMessage>> {
+        sqlx::query_as(
+            "SELECT m.id AS message_id, m.public_id, m.attempts, a.consumer,
+                    a.leased_at,
+                    ?1 - a.leased_at AS held_ms,
+                    m.available_at AS lease_expires_at
+             FROM message m
+             LEFT JOIN message_attempt a
+               ON a.message_id = m.id AND a.lease_token = m.lease_token
+             WHERE m.queue_id = (SELECT id FROM queue WHERE name = ?2)
+               AND m.dead_at IS NULL
+               AND m.lease_token IS NOT NULL
+               AND m.available_at > ?1
+             ORDER BY m.available_at, m.id
+             LIMIT ?3",
+        )
+        .bind(now_ms)
+        .bind(queue_name)
+        .bind(limit)
+        .fetch_all(self.reader())
+        .await
+    }
+
+    async fn create_admin_job(
+        &self,
+        job: &AdminJob,
+    ) -> sqlx::Result<AdminJob> {
+        let sql = format!(
+            "INSERT INTO admin_job
+               (kind, queue, path, batch_size, status, created_at)
+             VALUES (?, ?, ?, ?, 'pending', ?)
+             RETURNING {ADMIN_JOB_COLUMNS}"
+        );
+        sqlx::query_as::<_, AdminJob>(&sql)
+            .bind(&job.kind)
+            .bind(&job.queue)
+            .bind(&job.path)
+            .bind(job.batch_size)
+            .bind(job.created_at)
+            .fetch_one(&self.pool)
+            .await
+    }
+
+    async fn get_admin_job(
+        &self,
+        id: i64,
+    ) -> sqlx::Result<Option<AdminJob>> {
+        let sql =
+            format!("SELECT {ADMIN_JOB_COLUMNS} FROM admin_job WHERE id = ?");
+        sqlx::query_as::<_, AdminJob>(&sql)
+            .bind(id)
+            .fetch_optional(&self.pool)
+            .await
+    }
+
+    async fn list_admin_jobs(
+        &self,
+        limit: i64,
+    ) -> sqlx::Result<Vec<AdminJob>> {
+        let sql = format!(
+            "SELECT {ADMIN_JOB_COLUMNS} FROM admin_job ORDER BY id DESC LIMIT ?"
+        );
+        sqlx::query_as::<_, AdminJob>(&sql)
+            .bind(limit)
+            .fetch_all(&self.pool)
+            .await
+    }
+
+    async fn claim_admin_job(
+        &self,
+        now: i64,
+    ) -> sqlx::Result<Option<AdminJob>> {
+        let sql = format!(
+            "UPDATE admin_job SET status = 'running', started_at = ?
+             WHERE id = (
+               SELECT id FROM admin_job WHERE status = 'pending'
+               ORDER BY id LIMIT 1)
+             RETURNING {ADMIN_JOB_COLUMNS}"
+        );
+        sqlx::query_as::<_, AdminJob>(&sql)
+            .bind(now)
+            .fetch_optional(&self.pool)
+            .await
+    }
+
+    async fn set_admin_job_progress(
+        &self,
+        id: i64,
+        progress: i64,
+    ) -> sqlx::Result<bool> {
+        let cancel: Option<bool> = sqlx::query_scalar(
+            "UPDATE admin_job SET progress = ? WHERE id = ?
+             RETURNING cancel_requested",
+        )
+        .bind(progress)
+        .bind(id)
+        .fetch_optional(&self.pool)
+        .await?;
+        // A job deleted from under its runner has nobody left to report to
+        Ok(cancel.unwrap_or(true))
+    }
+
+    async fn finish_admin_job(
+        &self,
+        id: i64,
+        status: &str,
+        error: Option<&str>,
+        now: i64,
+    ) -> sqlx::Result<()> {
+        sqlx::query(
+            "UPDATE admin_job SET status = ?, error = ?, finished_at = ?
+             WHERE id = ?",
+        )
+        .bind(status)
+        .bind(error)
+        .bind(now)
+        .bind(id)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn cancel_admin_job(
+        &self,
+        id: i64,
+        now: i64,
+    ) -> sqlx::Result<Option<AdminJob>> {
+        let sql = format!(
+            "UPDATE admin_job SET
+               cancel_requested = CASE WHEN status = 'running'
+                 THEN 1 ELSE cancel_requested END,
+               finished_at = CASE WHEN status = 'pending'
+                 THEN ?1 ELSE finished_at END,
+               status = CASE WHEN status = 'pending'
+                 THEN 'canceled' ELSE status END
+             WHERE id = ?2
+             RETURNING {ADMIN_JOB_COLUMNS}"
+        );
+        sqlx::query_as::<_, AdminJob>(&sql)
+            .bind(now)
+            .bind(id)
+            .fetch_optional(&self.pool)
+            .await
+    }
+
+    async fn fail_running_admin_jobs(
+        &self,
+        error: &str,
+        now: i64,
+    ) -> sqlx::Result<u64> {
+        let res = sqlx::query(
+            "UPDATE admin_job SET status = 'failed', error = ?, finished_at = ?
+             WHERE status = 'running'",
+        )
+        .bind(error)
+        .bind(now)
+        .execute(&self.pool)
+        .await?;
+        Ok(res.rows_affected())
+    }
+
+    async fn purge_admin_jobs(
+        &self,
+        before_ms: i64,
+    ) -> sqlx::Result<u64> {
+        let res = sqlx::query("DELETE FROM admin_job WHERE finished_at < ?")
+            .bind(before_ms)
+            .execute(&self.pool)
+            .await?;
+        Ok(res.rows_affected())
+    }
+
+    async fn insert_audit_entry(
+        &self,
+        entry: &AuditEntry,
+    ) -> sqlx::Result<()> {
+        sqlx::query(
+            "INSERT INTO audit_log (at, actor, action, queue, params)
+             VALUES (?, ?, ?, ?, ?)",
+        )
+        .bind(entry.at)
+        .bind(&entry.actor)
+        .bind(&entry.action)
+        .bind(&entry.queue)
+        .bind(entry.params.as_ref().map(Json))
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn list_audit_entries(
+        &self,
+        filter: &AuditFilter,
+        limit: i64,
+    ) -> sqlx::Result<Vec<AuditEntry>> {
+        let sql = format!(
+            "SELECT {AUDIT_COLUMNS} FROM audit_log
+             WHERE (?1 IS NULL OR action = ?1)
+               AND (?2 IS NULL OR queue = ?2)
+               AND (?3 IS NULL OR actor = ?3)
+               AND (?4 IS NULL OR at >= ?4)
+             ORDER BY id DESC LIMIT ?5"
+        );
+        sqlx::query_as::<_, AuditEntry>(&sql)
+            .bind(&filter.action)
+            .bind(&filter.queue)
+            .bind(&filter.actor)
+            .bind(filter.since)
+            .bind(limit)
+            .fetch_all(&self.pool)
+            .await
+    }
+
+    async fn record_queue_schema(
+        &self,
+        queue_id: i64,
+        schema: Option<&serde_json::Value>,
+        now: i64,
+    ) -> sqlx::Result<i64> {
+        let mut tx = self.pool.begin().await?;
+        let version =
+            insert_schema_version(&mut tx, queue_id, schema, now).await?;
+        tx.commit().await?;
+        Ok(version)
+    }
+
+    async fn update_queue_and_schema(
+        &self,
+        q: &Queue,
+        now: i64,
+    ) -> sqlx::Result<i64> {
+        let mut tx = self.pool.begin().await?;
+        update_queue_row(&mut tx, q).await?;
+        let version = insert_schema_version(
+            &mut tx,
+            q.id,
+            q.payload_schema.as_ref(),
+            now,
+        )
+        .await?;
+        tx.commit().await?;
+        Ok(version)
+    }
+
+    async fn get_queue_schema(
+        &self,
+        queue_id: i64,
+        version: Option<i64>,
+    ) -> sqlx::Result<Option<QueueSchema>> {
+        sqlx::query_as::<_, QueueSchema>(
+            "SELECT version, payload_schema, created_at FROM queue_schema
+             WHERE queue_id = ?1 AND (?2 IS NULL OR version = ?2)
+             ORDER BY version DESC LIMIT 1",
+        )
+        .bind(queue_id)
+        .bind(version)
+        .fetch_optional(&self.pool)
+        .await
+    }
+
+    async fn list_queue_schemas(
+        &self,
+        queue_id: i64,
+    ) -> sqlx::Result<Vec<QueueSchema>> {
+        sqlx::query_as::<_, QueueSchema>(
+            "SELECT version, payload_schema, created_at FROM queue_schema
+             WHERE queue_id = ?1 ORDER BY version DESC",
+        )
+        .bind(queue_id)
+        .fetch_all(&self.pool)
+        .await
+    }
+}
+
+// Overwrite the settings of queue `q.id`; see `Storage::update_queue`
+async fn update_queue_row(
+    conn: &mut sqlx::SqliteConnection,
+    q: &Queue,
+) -> sqlx::Result<u64> {
+    let res = sqlx::query(
+        "UPDATE queue SET max_attempts = ?,
+             dedup_window_ms = ?,
+             retention_days = ?,
+             backoff_base_ms = ?,
+             backoff_multiplier = ?,
+             backoff_max_ms = ?,
+             backoff_jitter = ?,
+             default_visibility_ms = ?,
+             default_delay_ms = ?,
+             max_deliveries_per_second = ?,
+             max_payload_bytes = ?,
+             payload_schema = ?,
+             paused = ?,
+             strict_fifo = ?,
+             fair = ?,
+             ordering = ?,
+             max_depth = ?,
+             max_lease_expirations = ?,
+             schema_mode = ?
+         WHERE id = ?",
+    )
+    .bind(q.max_attempts)
+    .bind(q.dedup_window_ms)
+    .bind(q.retention_days)
+    .bind(q.backoff_base_ms)
+    .bind(q.backoff_multiplier)
+    .bind(q.backoff_max_ms)
+    .bind(q.backoff_jitter)
+    .bind(q.default_visibility_ms)
+    .bind(q.default_delay_ms)
+    .bind(q.max_deliveries_per_second)
+    .bind(q.max_payload_bytes)
+    .bind(q.payload_schema.as_ref().map(Json))
+    .bind(q.paused)
+    .bind(q.strict_fifo)
+    .bind(q.fair)
+    .bind(&q.ordering)
+    .bind(q.max_depth)
+    .bind(q.max_lease_expirations)
+    .bind(&q.schema_mode)
+    .bind(q.id)
+    .execute(&mut *conn)
+    .await?;
+    Ok(res.rows_affected())
+}
+
+// Bump a queue's `payload_schema_version` and record `schema` under it
+async fn insert_schema_version(
+    conn: &mut sqlx::SqliteConnection,
+    queue_id: i64,
+    schema: Option<&serde_json::Value>,
+    now: i64,
+) -> sqlx::Result<i64> {
+    let version: i64 = sqlx::query_scalar(
+        "UPDATE queue SET payload_schema_version = payload_schema_version + 1
+         WHERE id = ?1 RETURNING payload_schema_version",
+    )
+    .bind(queue_id)
+    .fetch_one(&mut *conn)
+    .await?;
+    sqlx::query(
+        "INSERT INTO queue_schema (queue_id, version, payload_schema, created_at)
+         VALUES (?1, ?2, ?3, ?4)",
+    )
+    .bind(queue_id)
+    .bind(version)
+    .bind(schema.map(Json))
+    .bind(now)
+    .execute(&mut *conn)
+    .await?;
+    Ok(version)
+}
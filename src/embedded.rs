@@ -0,0 +1,362 @@
+use crate::db::Db;
+use crate::error::Result;
+use crate::models::{Message, Queue};
+use crate::queue::{
+    self, Config, EnqueueOptions, LeasedMessage, QueueOptions, QueueUpdate,
+    SubscribeOptions, TypedMessage, TypedPoll,
+};
+use serde_json::Value;
+use tokio_stream::Stream;
+
+/// A sqew database opened in-process: its storage pool together with the
+/// [`Config`] it was opened with. Queue operations hang off
+/// [`Sqew::queue`]; acks, nacks and lease extensions, which address messages
+/// by id, are methods of `Sqew` itself. Clones share the pool.
+///
+/// ```no_run
+/// # async fn example() -> sqew::error::Result<()> {
+/// use serde_json::json;
+///
+/// let sqew = sqew::Sqew::open(sqew::queue::Config::default()).await?;
+/// sqew.create_queue("jobs", 5).await?;
+/// let jobs = sqew.queue("jobs");
+/// jobs.enqueue(&json!({"task": "resize"})).await?;
+/// for m in jobs.poll(10, 30_000).await? {
+///     sqew.ack(&[m.id], m.lease_token.as_deref().unwrap()).await?;
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct Sqew {
+    db: Db,
+    config: Config,
+}
+
+impl Sqew {
+    /// Open the database selected by `config`, creating it and applying
+    /// pending migrations first
+    pub async fn open(config: Config) -> Result<Self> {
+        let db = queue::init_pool(&config).await?;
+        Ok(Sqew { db, config })
+    }
+
+    /// Wrap a pool already opened with `config`
+    pub fn from_db(
+        db: Db,
+        config: Config,
+    ) -> Self {
+        Sqew { db, config }
+    }
+
+    /// The storage pool, for the `sqew::queue` functions not exposed here
+    pub fn db(&self) -> &Db {
+        &self.db
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Operations on the queue `name`. The queue is looked up by each call,
+    /// so the handle can be made before the queue exists.
+    pub fn queue(
+        &self,
+        name: impl Into<String>,
+    ) -> QueueHandle<'_> {
+        QueueHandle { sqew: self, name: name.into() }
+    }
+
+    pub async fn list_queues(&self) -> Result<Vec<Queue>> {
+        queue::list_queues(&self.db).await
+    }
+
+    /// Create a queue with the config's queue defaults and `max_attempts`
+    pub async fn create_queue(
+        &self,
+        name: &str,
+        max_attempts: i32,
+    ) -> Result<Queue> {
+        let opts =
+            QueueOptions { max_attempts, ..self.config.queue_defaults.clone() };
+        queue::create_queue_with(&self.db, name, &opts).await
+    }
+
+    pub async fn create_queue_with(
+        &self,
+        name: &str,
+        opts: &QueueOptions,
+    ) -> Result<Queue> {
+        queue::create_queue_with(&self.db, name, opts).await
+    }
+
+    /// Fetch a message by id
+    pub async fn message(
+        &self,
+        id: i64,
+    ) -> Result<Message> {
+        queue::get_message_by_id(&self.db, id).await
+    }
+
+    /// Remove a message by id; returns whether it existed
+    pub async fn remove_message(
+        &self,
+        id: i64,
+    ) -> Result<bool> {
+        queue::remove_message(&self.db, id).await
+    }
+
+    /// Ack leased messages; returns how many were deleted
+    pub async fn ack(
+        &self,
+        ids: &[i64],
+        lease_token: &str,
+    ) -> Result<u64> {
+        queue::ack_messages(&self.db, ids, lease_token).await
+    }
+
+    /// Nack leased messages, making them visible again after `delay_ms`.
+    /// Returns `(requeued, dead_lettered)`.
+    pub async fn nack(
+        &self,
+        ids: &[i64],
+        lease_token: &str,
+        delay_ms: i64,
+    ) -> Result<(u64, u64)> {
+        queue::nack_messages(&self.db, ids, lease_token, delay_ms).await
+    }
+
+    /// Nack leased messages, each visible again after its own delay:
+    /// `nacks` pairs message ids with delays in milliseconds
+    pub async fn nack_with_delays(
+        &self,
+        nacks: &[(i64, i64)],
+        lease_token: &str,
+    ) -> Result<(u64, u64)> {
+        queue::nack_messages_with_delays(&self.db, nacks, lease_token).await
+    }
+
+    /// Extend leases held under `lease_token` by `extra_ms`; returns how
+    /// many were extended
+    pub async fn extend(
+        &self,
+        ids: &[i64],
+        lease_token: &str,
+        extra_ms: i64,
+    ) -> Result<u64> {
+        queue::extend_visibility(&self.db, ids, lease_token, extra_ms).await
+    }
+
+    /// Answer a request sent with [`QueueHandle::call`] and ack it; returns
+    /// whether the reply was delivered. See [`queue::respond`].
+    pub async fn respond(
+        &self,
+        request: &Message,
+        payload: &Value,
+    ) -> Result<bool> {
+        queue::respond(&self.db, request, payload).await
+    }
+}
+
+/// Operations on one queue of a [`Sqew`] database, from [`Sqew::queue`]
+#[derive(Clone)]
+pub struct QueueHandle<'a> {
+    sqew: &'a Sqew,
+    name: String,
+}
+
+impl QueueHandle<'_> {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The queue's settings
+    pub async fn info(&self) -> Result<Queue> {
+        queue::show_queue(&self.sqew.db, &self.name).await
+    }
+
+    pub async fn update(
+        &self,
+        update: &QueueUpdate,
+    ) -> Result<Queue> {
+        queue::update_queue(&self.sqew.db, &self.name, update).await
+    }
+
+    /// Stop polls leasing anything; enqueues are still accepted
+    pub async fn pause(&self) -> Result<Queue> {
+        queue::set_paused(&self.sqew.db, &self.name, true).await
+    }
+
+    pub async fn resume(&self) -> Result<Queue> {
+        queue::set_paused(&self.sqew.db, &self.name, false).await
+    }
+
+    /// Delete the queue; returns whether it existed
+    pub async fn delete(&self) -> Result<bool> {
+        queue::delete_queue(&self.sqew.db, &self.name).await
+    }
+
+    /// Move the queue to the trash; returns whether it existed
+    pub async fn trash(&self) -> Result<bool> {
+        queue::trash_queue(&self.sqew.db, &self.name).await
+    }
+
+    /// Bring the queue back out of the trash
+    pub async fn restore(&self) -> Result<Queue> {
+        queue::restore_queue(&self.sqew.db, &self.name).await
+    }
+
+    /// Delete all messages; returns how many
+    pub async fn purge(&self) -> Result<u64> {
+        queue::purge_queue(&self.sqew.db, &self.name).await
+    }
+
+    /// Ready, leased and dead-lettered counts
+    pub async fn stats(&self) -> Result<Value> {
+        queue::stats(&self.sqew.db, &self.name).await
+    }
+
+    /// Enqueue a JSON payload with default options
+    pub async fn enqueue(
+        &self,
+        payload: &Value,
+    ) -> Result<Message> {
+        self.enqueue_with(payload, &EnqueueOptions::default()).await
+    }
+
+    pub async fn enqueue_with(
+        &self,
+        payload: &Value,
+        opts: &EnqueueOptions,
+    ) -> Result<Message> {
+        queue::enqueue_message_with(&self.sqew.db, &self.name, payload, opts)
+            .await
+    }
+
+    /// Enqueue `bytes` of the media type `content_type`; see
+    /// [`queue::enqueue_bytes`]
+    pub async fn enqueue_bytes(
+        &self,
+        bytes: &[u8],
+        content_type: &str,
+        opts: &EnqueueOptions,
+    ) -> Result<Message> {
+        let db = &self.sqew.db;
+        queue::enqueue_bytes(db, &self.name, bytes, content_type, opts).await
+    }
+
+    /// Serialize `payload` as JSON and enqueue it
+    pub async fn enqueue_typed<T: serde::Serialize>(
+        &self,
+        payload: T,
+        opts: &EnqueueOptions,
+    ) -> Result<TypedMessage<T>> {
+        queue::enqueue_typed(&self.sqew.db, &self.name, payload, opts).await
+    }
+
+    /// Enqueue `payload` as a request and wait up to `timeout` for its
+    /// reply; see [`queue::call`]
+    pub async fn call(
+        &self,
+        payload: &Value,
+        timeout: std::time::Duration,
+    ) -> Result<Message> {
+        queue::call(&self.sqew.db, &self.name, payload, timeout).await
+    }
+
+    /// Lease up to `limit` messages for `visibility_ms`; each carries the
+    /// `lease_token` needed to ack or nack it
+    pub async fn poll(
+        &self,
+        limit: i64,
+        visibility_ms: i64,
+    ) -> Result<Vec<Message>> {
+        queue::poll_messages(&self.sqew.db, &self.name, limit, visibility_ms)
+            .await
+    }
+
+    /// Lease as [`QueueHandle::poll`] does and decode the payloads as `T`;
+    /// see [`queue::poll_typed`]
+    pub async fn poll_typed<T: serde::de::DeserializeOwned>(
+        &self,
+        limit: i64,
+        visibility_ms: i64,
+        nack_failures: bool,
+    ) -> Result<TypedPoll<T>> {
+        let db = &self.sqew.db;
+        queue::poll_typed(db, &self.name, limit, visibility_ms, nack_failures)
+            .await
+    }
+
+    /// A stream of the queue's messages, leased as it is consumed; see
+    /// [`queue::subscribe`]
+    pub fn subscribe(
+        &self,
+        opts: SubscribeOptions,
+    ) -> impl Stream<Item = Result<LeasedMessage>> + Send + Unpin + 'static
+    {
+        queue::subscribe(&self.sqew.db, &self.name, opts)
+    }
+
+    /// Lease up to `limit` messages for the consumer group `group`
+    pub async fn poll_group(
+        &self,
+        group: &str,
+        limit: i64,
+        visibility_ms: i64,
+    ) -> Result<Vec<Message>> {
+        let db = &self.sqew.db;
+        queue::poll_group_messages(db, &self.name, group, limit, visibility_ms)
+            .await
+    }
+
+    /// Messages in delivery order, without leasing them
+    pub async fn peek(
+        &self,
+        limit: i64,
+    ) -> Result<Vec<Message>> {
+        queue::peek_queue(&self.sqew.db, &self.name, limit).await
+    }
+
+    /// A random sample of up to `n` ready messages, without leasing them
+    pub async fn sample(
+        &self,
+        n: i64,
+    ) -> Result<Vec<Message>> {
+        queue::sample_messages(&self.sqew.db, &self.name, n).await
+    }
+
+    /// Messages with a value at JSON `path`, equal to `value` when given;
+    /// see [`queue::search_messages`]
+    pub async fn search(
+        &self,
+        path: &str,
+        value: Option<&str>,
+        after_id: Option<i64>,
+        limit: i64,
+    ) -> Result<Vec<Message>> {
+        let db = &self.sqew.db;
+        queue::search_messages(db, &self.name, path, value, after_id, limit)
+            .await
+    }
+
+    pub async fn dead_letters(
+        &self,
+        limit: i64,
+    ) -> Result<Vec<Message>> {
+        queue::list_dead_letters(&self.sqew.db, &self.name, limit).await
+    }
+
+    /// Requeue dead letters (all when `ids` is empty); returns how many
+    pub async fn redrive(
+        &self,
+        ids: &[i64],
+    ) -> Result<u64> {
+        queue::redrive_dead_letters(&self.sqew.db, &self.name, ids).await
+    }
+
+    /// Delete all dead letters; returns how many
+    pub async fn purge_dead_letters(&self) -> Result<u64> {
+        queue::purge_dead_letters(&self.sqew.db, &self.name).await
+    }
+}
@@ -0,0 +1,175 @@
+//! The error type of the [`crate::queue`] API.
+//!
+//! Each variant names what went wrong, so callers (the HTTP server among
+//! them) can react without matching on message text. The messages are the
+//! ones the CLI prints.
+
+use crate::db;
+use crate::queue::PayloadRejected;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Result of the queue API
+pub type Result<T, E = SqewError> = std::result::Result<T, E>;
+
+/// An error of the queue API
+#[derive(Debug, thiserror::Error)]
+pub enum SqewError {
+    #[error("Queue '{0}' not found")]
+    QueueNotFound(String),
+    #[error("Queue '{0}' already exists")]
+    QueueExists(String),
+    /// The name belongs to a queue in the trash
+    #[error(
+        "Queue '{0}' is in the trash: restore it or remove it for good first"
+    )]
+    QueueTrashed(String),
+    #[error("Message {0} not found")]
+    MessageNotFound(i64),
+    /// No message has the public id
+    #[error("Message {0} not found")]
+    PublicIdNotFound(String),
+    #[error("Job {0} not found")]
+    JobNotFound(i64),
+    /// The admin job already finished, so it cannot be canceled
+    #[error("Job {id} already finished ({status})")]
+    JobFinished { id: i64, status: String },
+    /// The queue never had a payload schema, or not the requested version
+    #[error(
+        "Queue '{queue}' has no schema{}",
+        .version.map(|v| format!(" version {v}")).unwrap_or_default()
+    )]
+    SchemaNotFound { queue: String, version: Option<i64> },
+    #[error("Consumer group '{group}' not found on queue '{queue}'")]
+    GroupNotFound { queue: String, group: String },
+    #[error("Consumer group '{group}' already exists on queue '{queue}'")]
+    GroupExists { queue: String, group: String },
+    /// The payload breaks the queue's size limit or schema
+    #[error(transparent)]
+    PayloadRejected(#[from] PayloadRejected),
+    /// A setting, filter or argument the caller gave is not valid; the
+    /// message names it
+    #[error("Invalid {0}")]
+    Invalid(String),
+    /// The queue holds its `max_depth` of unacked messages
+    #[error("Queue '{queue}' is full ({max_depth} unacked messages)")]
+    QueueFull { queue: String, max_depth: i64 },
+    #[error("Backup file '{}' already exists", .0.display())]
+    BackupExists(PathBuf),
+    /// The operation is not available on the configured backend
+    #[error("{0}")]
+    Unsupported(String),
+    #[error("Database did not answer within {0:?}")]
+    Timeout(Duration),
+    /// The database was locked by other writers past its busy timeout and
+    /// the retries of the storage layer, or a Postgres transaction lost a
+    /// serialization conflict or deadlock. Nothing was changed; the
+    /// operation may be tried again after `retry_after`.
+    #[error("{context}: the database is busy; retry in {}ms", .retry_after.as_millis())]
+    Busy {
+        context: String,
+        retry_after: Duration,
+        #[source]
+        source: sqlx::Error,
+    },
+    /// A [`call`](crate::queue::call) ended without a reply: it timed out,
+    /// or the request was dead-lettered or acked unanswered
+    #[error("No reply to message {message_id} on queue '{queue}': {reason}")]
+    NoReply { queue: String, message_id: i64, reason: String },
+    /// A database operation failed
+    #[error("{context}")]
+    Storage {
+        context: String,
+        #[source]
+        source: sqlx::Error,
+    },
+    #[error("{context}")]
+    Io {
+        context: String,
+        #[source]
+        source: std::io::Error,
+    },
+    #[error(transparent)]
+    Other(#[from] anyhow::Error),
+}
+
+impl SqewError {
+    /// How long to wait before trying a failed operation again, for errors
+    /// that are worth retrying as they are
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            SqewError::Busy { retry_after, .. } => Some(*retry_after),
+            _ => None,
+        }
+    }
+
+    // A database error, as `Busy` when it is lock contention
+    pub(crate) fn storage(
+        context: String,
+        source: sqlx::Error,
+    ) -> Self {
+        if db::is_busy(&source) {
+            let retry_after = db::BUSY_RETRY_AFTER;
+            SqewError::Busy { context, retry_after, source }
+        } else {
+            SqewError::Storage { context, source }
+        }
+    }
+}
+
+impl From<sqlx::Error> for SqewError {
+    fn from(source: sqlx::Error) -> Self {
+        SqewError::storage("Database query failed".into(), source)
+    }
+}
+
+impl From<std::io::Error> for SqewError {
+    fn from(source: std::io::Error) -> Self {
+        SqewError::Io { context: "I/O failed".into(), source }
+    }
+}
+
+/// Describe the operation behind a database or I/O error
+pub(crate) trait Context<T> {
+    fn context(
+        self,
+        context: &str,
+    ) -> Result<T>;
+
+    fn with_context(
+        self,
+        f: impl FnOnce() -> String,
+    ) -> Result<T>;
+}
+
+impl<T> Context<T> for std::result::Result<T, sqlx::Error> {
+    fn context(
+        self,
+        context: &str,
+    ) -> Result<T> {
+        self.with_context(|| context.to_string())
+    }
+
+    fn with_context(
+        self,
+        f: impl FnOnce() -> String,
+    ) -> Result<T> {
+        self.map_err(|source| SqewError::storage(f(), source))
+    }
+}
+
+impl<T> Context<T> for std::result::Result<T, std::io::Error> {
+    fn context(
+        self,
+        context: &str,
+    ) -> Result<T> {
+        self.with_context(|| context.to_string())
+    }
+
+    fn with_context(
+        self,
+        f: impl FnOnce() -> String,
+    ) -> Result<T> {
+        self.map_err(|source| SqewError::Io { context: f(), source })
+    }
+}
@@ -0,0 +1,205 @@
+use crate::error::{Result, SqewError};
+use crate::models::{Headers, Message};
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// Shape of the messages read by `queue import`
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Eq,
+    Serialize,
+    Deserialize,
+    clap::ValueEnum,
+    utoipa::ToSchema,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum ImportFormat {
+    /// Written by `queue export`: one sqew message per line
+    #[default]
+    Sqew,
+    /// Amazon SQS messages as returned by `ReceiveMessage`: a
+    /// `{"Messages": [...]}` document, an array of messages or one per line
+    SqsJson,
+    /// RabbitMQ management plugin "Get messages" output: an array of
+    /// messages or one per line
+    RabbitJson,
+}
+
+impl ImportFormat {
+    fn name(self) -> &'static str {
+        match self {
+            ImportFormat::Sqew => "sqew",
+            ImportFormat::SqsJson => "sqs-json",
+            ImportFormat::RabbitJson => "rabbit-json",
+        }
+    }
+}
+
+/// Map the messages of an SQS or RabbitMQ export in `text` to sqew messages,
+/// ready at `now` in no queue yet. Payloads that are JSON are kept as such,
+/// any other body becomes a JSON string.
+pub(crate) fn parse_foreign(
+    format: ImportFormat,
+    text: &str,
+    now: i64,
+) -> Result<Vec<Message>> {
+    let mut messages = Vec::new();
+    for (n, doc) in documents(format, text)?.iter().enumerate() {
+        let msg = match format {
+            ImportFormat::SqsJson => sqs_message(doc, now),
+            ImportFormat::RabbitJson => rabbit_message(doc, now),
+            ImportFormat::Sqew => unreachable!("sqew exports are read by line"),
+        };
+        messages.push(msg.map_err(|e| {
+            SqewError::Invalid(format!(
+                "{} message {}: {}",
+                format.name(),
+                n + 1,
+                e
+            ))
+        })?);
+    }
+    Ok(messages)
+}
+
+// The message objects of an export: those of an SQS `Messages` list, of a
+// top-level array, a single object, or one object per line
+fn documents(
+    format: ImportFormat,
+    text: &str,
+) -> Result<Vec<Value>> {
+    let invalid = |line: usize, e: String| {
+        SqewError::Invalid(format!("{} line {}: {}", format.name(), line, e))
+    };
+    if let Ok(doc) = serde_json::from_str::<Value>(text) {
+        return match doc {
+            Value::Object(mut o) if o.contains_key("Messages") => {
+                match o.remove("Messages") {
+                    Some(Value::Array(items)) => Ok(items),
+                    _ => Err(invalid(1, "Messages is not an array".into())),
+                }
+            }
+            Value::Array(items) => Ok(items),
+            other => Ok(vec![other]),
+        };
+    }
+    text.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(n, line)| {
+            serde_json::from_str(line)
+                .map_err(|e| invalid(n + 1, e.to_string()))
+        })
+        .collect()
+}
+
+// A payload: the body itself when it is JSON, else the body as a string
+fn json_or_string(body: &str) -> Value {
+    serde_json::from_str(body).unwrap_or_else(|_| Value::String(body.into()))
+}
+
+fn foreign_message(
+    payload: Value,
+    headers: Headers,
+    created_at: Option<i64>,
+    now: i64,
+) -> Message {
+    let created_at = created_at.unwrap_or(now);
+    Message {
+        id: 0,
+        queue_id: 0,
+        payload: payload.to_string(),
+        attempts: 0,
+        available_at: now,
+        created_at,
+        dead_at: None,
+        lease_token: None,
+        priority: 0,
+        expires_at: None,
+        dedup_key: None,
+        group_id: None,
+        headers: (!headers.is_empty()).then_some(headers),
+        trace_id: None,
+        last_error: None,
+        fair_key: None,
+        lease_expirations: 0,
+        sort_key: None,
+        reply_to: None,
+        public_id: crate::db::new_public_id(created_at),
+    }
+}
+
+// An SQS message: `Body` becomes the payload, string and binary
+// `MessageAttributes` the headers, and the FIFO group and deduplication ids
+// the group and dedup key. The SQS `MessageId` is kept as the trace id.
+fn sqs_message(
+    v: &Value,
+    now: i64,
+) -> std::result::Result<Message, String> {
+    let body = v["Body"].as_str().ok_or("no Body")?;
+    let headers = v["MessageAttributes"]
+        .as_object()
+        .into_iter()
+        .flatten()
+        .filter_map(|(key, attr)| {
+            let value = attr["StringValue"].as_str();
+            let value = value.or(attr["BinaryValue"].as_str())?;
+            Some((key.clone(), value.to_string()))
+        })
+        .collect();
+    let attrs = &v["Attributes"];
+    let sent = attrs["SentTimestamp"].as_str().and_then(|s| s.parse().ok());
+    let mut msg = foreign_message(json_or_string(body), headers, sent, now);
+    msg.group_id = attrs["MessageGroupId"].as_str().map(String::from);
+    msg.dedup_key = attrs["MessageDeduplicationId"].as_str().map(String::from);
+    msg.trace_id = v["MessageId"].as_str().map(String::from);
+    Ok(msg)
+}
+
+// A RabbitMQ message: the (possibly base64) `payload` becomes the payload,
+// the AMQP headers and `content_type` and `correlation_id` properties the
+// headers. `priority` is kept, the AMQP timestamp (in seconds) becomes the
+// creation time and `message_id` the trace id.
+fn rabbit_message(
+    v: &Value,
+    now: i64,
+) -> std::result::Result<Message, String> {
+    let raw = v["payload"].as_str().ok_or("no payload")?;
+    let body = match v["payload_encoding"].as_str() {
+        Some("base64") => {
+            let bytes = base64::engine::general_purpose::STANDARD
+                .decode(raw)
+                .map_err(|e| format!("payload: {e}"))?;
+            String::from_utf8(bytes).map_err(|_| "payload is not UTF-8")?
+        }
+        _ => raw.to_string(),
+    };
+    let props = &v["properties"];
+    let mut headers: Headers = props["headers"]
+        .as_object()
+        .into_iter()
+        .flatten()
+        .map(|(key, value)| {
+            let value = match value {
+                Value::String(s) => s.clone(),
+                other => other.to_string(),
+            };
+            (key.clone(), value)
+        })
+        .collect();
+    for key in ["content_type", "correlation_id"] {
+        if let Some(value) = props[key].as_str() {
+            headers.insert(key.to_string(), value.to_string());
+        }
+    }
+    let sent = props["timestamp"].as_i64().map(|s| s * 1000);
+    let mut msg = foreign_message(json_or_string(&body), headers, sent, now);
+    msg.priority = props["priority"].as_i64().unwrap_or(0) as i32;
+    msg.trace_id = props["message_id"].as_str().map(String::from);
+    Ok(msg)
+}
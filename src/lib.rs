@@ -1,5 +1,24 @@
+pub mod audit;
+pub mod auth;
+pub mod bench;
 pub mod cli;
+pub mod client;
+pub mod config;
+pub mod consumer;
 pub mod db;
+pub mod embedded;
+pub mod error;
+pub mod import;
+pub mod metrics;
 pub mod models;
+pub mod mqtt;
+pub mod notify;
+pub mod output;
 pub mod queue;
+pub mod replica;
+pub mod resp;
 pub mod server;
+pub mod ui;
+pub mod worker;
+
+pub use embedded::Sqew;
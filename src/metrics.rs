@@ -0,0 +1,191 @@
+use std::fmt::Write;
+use std::sync::LazyLock;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+
+/// A service-layer operation whose latency is measured
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    Enqueue,
+    Poll,
+    Ack,
+    Nack,
+}
+
+impl Op {
+    const ALL: [Op; 4] = [Op::Enqueue, Op::Poll, Op::Ack, Op::Nack];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Op::Enqueue => "enqueue",
+            Op::Poll => "poll",
+            Op::Ack => "ack",
+            Op::Nack => "nack",
+        }
+    }
+}
+
+/// Upper bounds, in seconds, of the operation duration buckets
+const DURATION_BUCKETS: [f64; 14] = [
+    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5,
+    5.0, 10.0,
+];
+
+/// Upper bounds of the batch size and rows affected buckets
+const COUNT_BUCKETS: [f64; 10] =
+    [1.0, 2.0, 5.0, 10.0, 25.0, 50.0, 100.0, 250.0, 500.0, 1000.0];
+
+// A Prometheus histogram over fixed buckets. Observations are integers in
+// units of `1 / scale`, so the sum can be kept in an atomic.
+struct Histogram {
+    bounds: &'static [f64],
+    scale: f64,
+    // Observations per bucket, not cumulative; the last one is `+Inf`
+    counts: Vec<AtomicU64>,
+    sum: AtomicU64,
+}
+
+impl Histogram {
+    fn new(
+        bounds: &'static [f64],
+        scale: f64,
+    ) -> Self {
+        let counts = (0..=bounds.len()).map(|_| AtomicU64::new(0)).collect();
+        Histogram { bounds, scale, counts, sum: AtomicU64::new(0) }
+    }
+
+    fn observe(
+        &self,
+        units: u64,
+    ) {
+        let value = units as f64 / self.scale;
+        let bucket = self
+            .bounds
+            .iter()
+            .position(|&b| value <= b)
+            .unwrap_or(self.bounds.len());
+        self.counts[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(units, Ordering::Relaxed);
+    }
+
+    fn render(
+        &self,
+        out: &mut String,
+        name: &str,
+        op: Op,
+    ) {
+        let op = op.name();
+        let mut cumulative = 0;
+        for (i, count) in self.counts.iter().enumerate() {
+            cumulative += count.load(Ordering::Relaxed);
+            let le = match self.bounds.get(i) {
+                Some(b) => b.to_string(),
+                None => "+Inf".to_string(),
+            };
+            let _ = writeln!(
+                out,
+                "{name}_bucket{{op=\"{op}\",le=\"{le}\"}} {cumulative}"
+            );
+        }
+        let sum = self.sum.load(Ordering::Relaxed) as f64 / self.scale;
+        let _ = writeln!(out, "{name}_sum{{op=\"{op}\"}} {sum}");
+        let _ = writeln!(out, "{name}_count{{op=\"{op}\"}} {cumulative}");
+    }
+}
+
+struct OpMetrics {
+    // In nanoseconds
+    duration: Histogram,
+    batch: Histogram,
+    rows: Histogram,
+}
+
+static METRICS: LazyLock<Vec<OpMetrics>> = LazyLock::new(|| {
+    Op::ALL
+        .iter()
+        .map(|_| OpMetrics {
+            duration: Histogram::new(&DURATION_BUCKETS, 1e9),
+            batch: Histogram::new(&COUNT_BUCKETS, 1.0),
+            rows: Histogram::new(&COUNT_BUCKETS, 1.0),
+        })
+        .collect()
+});
+
+/// Times one operation from its start until dropped, then records its
+/// duration, batch size and rows affected. Dropping a timer on an error
+/// path records it with the rows set so far (none unless set), so failed
+/// and slow calls show up alike.
+pub struct OpTimer {
+    op: Op,
+    started: Instant,
+    batch: u64,
+    rows: u64,
+}
+
+impl OpTimer {
+    /// Start timing `op` over a batch of `batch` messages (the poll limit
+    /// for polls)
+    pub fn start(
+        op: Op,
+        batch: usize,
+    ) -> Self {
+        OpTimer { op, started: Instant::now(), batch: batch as u64, rows: 0 }
+    }
+
+    /// Set how many messages the operation enqueued, leased or settled
+    pub fn rows(
+        &mut self,
+        rows: usize,
+    ) {
+        self.rows = rows as u64;
+    }
+}
+
+impl Drop for OpTimer {
+    fn drop(&mut self) {
+        let elapsed = self.started.elapsed();
+        let m = &METRICS[self.op as usize];
+        m.duration.observe(elapsed.as_nanos() as u64);
+        m.batch.observe(self.batch);
+        m.rows.observe(self.rows);
+        tracing::debug!(
+            op = self.op.name(),
+            duration_ms = elapsed.as_secs_f64() * 1000.0,
+            batch = self.batch,
+            rows = self.rows,
+            "timed"
+        );
+    }
+}
+
+/// The histograms of every operation since the process started, in the
+/// Prometheus text exposition format
+pub fn render() -> String {
+    type Family = (&'static str, &'static str, fn(&OpMetrics) -> &Histogram);
+    let families: [Family; 3] = [
+        (
+            "sqew_operation_duration_seconds",
+            "Time taken by enqueue, poll, ack and nack calls",
+            |m| &m.duration,
+        ),
+        (
+            "sqew_operation_batch_size",
+            "Messages per call (the requested limit for polls)",
+            |m| &m.batch,
+        ),
+        (
+            "sqew_operation_rows",
+            "Messages enqueued, leased or settled per call",
+            |m| &m.rows,
+        ),
+    ];
+    let mut out = String::new();
+    for (name, help, histogram) in families {
+        let _ = writeln!(out, "# HELP {name} {help}");
+        let _ = writeln!(out, "# TYPE {name} histogram");
+        for op in Op::ALL {
+            histogram(&METRICS[op as usize]).render(&mut out, name, op);
+        }
+    }
+    out
+}
@@ -1,14 +1,106 @@
 use serde::{Deserialize, Serialize};
 use sqlx::FromRow;
+use std::collections::BTreeMap;
+use utoipa::ToSchema;
 
-#[derive(Debug, Serialize, Deserialize, FromRow)]
+/// Message headers: string keys to string values
+pub type Headers = BTreeMap<String, String>;
+
+#[derive(Debug, Serialize, Deserialize, FromRow, ToSchema)]
 pub struct Queue {
     pub id: i64,
     pub name: String,
     pub max_attempts: i32,
+    /// Enqueues repeating a `dedup_key` within this window are deduplicated
+    pub dedup_window_ms: i64,
+    /// Days acked messages are kept in the archive; `None` deletes on ack
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retention_days: Option<i32>,
+    /// First retry delay of exponential nack backoff; `None` keeps the
+    /// delay requested by the consumer
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub backoff_base_ms: Option<i64>,
+    /// Factor the backoff delay grows by with each attempt
+    #[serde(default = "default_backoff_multiplier")]
+    pub backoff_multiplier: f64,
+    /// Upper bound on the backoff delay
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub backoff_max_ms: Option<i64>,
+    /// Fraction (0..=1) of each backoff delay that is randomized away
+    #[serde(default)]
+    pub backoff_jitter: f64,
+    /// Lease length used when a poll gives no visibility timeout
+    #[serde(default = "default_visibility_ms")]
+    pub default_visibility_ms: i64,
+    /// Delay used when an enqueue gives none
+    #[serde(default)]
+    pub default_delay_ms: i64,
+    /// Most messages polls may lease per second; `None` is unlimited
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_deliveries_per_second: Option<f64>,
+    /// Enqueues with larger (serialized) payloads are rejected
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_payload_bytes: Option<i64>,
+    /// JSON Schema every enqueued payload must match
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[sqlx(json(nullable))]
+    #[schema(value_type = Option<Object>)]
+    pub payload_schema: Option<serde_json::Value>,
+    /// What enqueues do with payloads that break the schema: `reject` them,
+    /// or `tag` them with the violations and accept them
+    #[serde(default = "default_schema_mode")]
+    pub schema_mode: String,
+    /// Version of the payload schema, counting every change from 1; 0 for
+    /// a queue that never had one
+    #[serde(default)]
+    pub payload_schema_version: i64,
+    /// Polls lease nothing while set; enqueues are still accepted
+    #[serde(default)]
+    pub paused: bool,
+    /// Polls lease only the oldest live message (of each FIFO group), and
+    /// nothing behind it until it is acked or dead-lettered
+    #[serde(default)]
+    pub strict_fifo: bool,
+    /// Polls take turns between the messages' fair keys (unkeyed messages
+    /// sharing one turn) instead of delivering strictly by priority and age
+    #[serde(default)]
+    pub fair: bool,
+    /// Order polls and peeks take ready messages in: `priority` (highest
+    /// first, then oldest), `fifo` (oldest first) or `sort_key` (smallest
+    /// message `sort_key` first)
+    #[serde(default = "default_ordering")]
+    pub ordering: String,
+    /// Enqueues are refused while this many messages are unacked (ready,
+    /// leased or delayed); `None` is unlimited
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_depth: Option<i64>,
+    /// A message whose lease expires this many times without an ack or nack
+    /// is quarantined into the dead letters; `None` never quarantines
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_lease_expirations: Option<i32>,
+    /// When the queue was moved to the trash (ms); a trashed queue is
+    /// hidden and refuses operations until restored or purged
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deleted_at: Option<i64>,
 }
 
-#[derive(Debug, Serialize, Deserialize, FromRow)]
+fn default_backoff_multiplier() -> f64 {
+    2.0
+}
+
+fn default_ordering() -> String {
+    "priority".into()
+}
+
+fn default_schema_mode() -> String {
+    "reject".into()
+}
+
+fn default_visibility_ms() -> i64 {
+    crate::db::DEFAULT_VISIBILITY_MS
+}
+
+#[derive(Debug, Serialize, Deserialize, FromRow, ToSchema)]
 pub struct Message {
     pub id: i64,
     pub queue_id: i64,
@@ -16,4 +108,354 @@ pub struct Message {
     pub attempts: i32,
     pub available_at: i64,
     pub created_at: i64,
+    /// Set when the message exhausted `max_attempts` and was dead-lettered
+    pub dead_at: Option<i64>,
+    /// Lease receipt returned by poll; required to ack or nack the message
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lease_token: Option<String>,
+    /// Higher priorities are delivered first
+    #[serde(default)]
+    pub priority: i32,
+    /// Messages not consumed by this time (ms) are expired
+    #[serde(default)]
+    pub expires_at: Option<i64>,
+    /// Producer-supplied key; repeats within the queue's dedup window are dropped
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dedup_key: Option<String>,
+    /// FIFO group; messages sharing a group are leased one at a time, in order
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group_id: Option<String>,
+    /// Routing metadata such as `content_type` or `correlation_id`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[sqlx(json(nullable))]
+    pub headers: Option<Headers>,
+    /// Correlates the message's enqueue, leases, nacks and ack in logs;
+    /// generated at enqueue unless the producer supplies one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub trace_id: Option<String>,
+    /// Reason given by the consumer that last nacked the message, such as
+    /// its error message or stack trace, or why it was quarantined
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[sqlx(default)]
+    pub last_error: Option<String>,
+    /// Tenant or producer the message belongs to; polls of a `fair` queue
+    /// take turns between keys so one key's backlog cannot starve the rest
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[sqlx(default)]
+    pub fair_key: Option<String>,
+    /// Times a lease on the message expired without an ack or nack
+    #[serde(default)]
+    #[sqlx(default)]
+    pub lease_expirations: i32,
+    /// Polls of a queue ordered by `sort_key` lease the smallest first, e.g.
+    /// a job's size or deadline
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[sqlx(default)]
+    pub sort_key: Option<f64>,
+    /// Queue that gets a receipt when the message is acked or dead-lettered
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[sqlx(default)]
+    pub reply_to: Option<String>,
+    /// Stable public identifier (a time-ordered UUID), accepted anywhere the
+    /// integer `id` is; unlike `id` it does not reveal message volumes
+    #[serde(default)]
+    #[sqlx(default)]
+    pub public_id: String,
+}
+
+/// A message as a client names it: by its integer `id` or its `public_id`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(untagged)]
+pub enum MessageRef {
+    Id(i64),
+    Public(String),
+}
+
+// A number, or a string read as `FromStr` reads it, so path segments such
+// as `/messages/42` name an `id` too
+impl<'de> Deserialize<'de> for MessageRef {
+    fn deserialize<D: serde::Deserializer<'de>>(
+        deserializer: D
+    ) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Raw {
+            Id(i64),
+            Text(String),
+        }
+        match Raw::deserialize(deserializer)? {
+            Raw::Id(id) => Ok(MessageRef::Id(id)),
+            Raw::Text(s) => s.parse().map_err(serde::de::Error::custom),
+        }
+    }
+}
+
+impl std::str::FromStr for MessageRef {
+    type Err = String;
+
+    /// Digits name an `id`; anything else must be a public id (a UUID)
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if let Ok(id) = s.parse() {
+            return Ok(MessageRef::Id(id));
+        }
+        uuid::Uuid::parse_str(s)
+            .map(|u| MessageRef::Public(u.to_string()))
+            .map_err(|_| {
+                format!("message id '{s}': expected an id or a public id")
+            })
+    }
+}
+
+impl std::fmt::Display for MessageRef {
+    fn fmt(
+        &self,
+        f: &mut std::fmt::Formatter<'_>,
+    ) -> std::fmt::Result {
+        match self {
+            MessageRef::Id(id) => write!(f, "{id}"),
+            MessageRef::Public(p) => f.write_str(p),
+        }
+    }
+}
+
+impl From<i64> for MessageRef {
+    fn from(id: i64) -> Self {
+        MessageRef::Id(id)
+    }
+}
+
+/// A recurring enqueue of a fixed payload, driven by a cron expression
+#[derive(Debug, Serialize, Deserialize, FromRow)]
+pub struct Schedule {
+    pub id: i64,
+    pub queue_id: i64,
+    pub cron: String,
+    pub payload: String,
+    /// When the schedule next fires (ms since the Unix epoch)
+    pub next_run_at: i64,
+    pub created_at: i64,
+}
+
+/// A threshold on a queue's depth or age whose breaches are posted to a
+/// webhook
+#[derive(Debug, Serialize, Deserialize, FromRow, ToSchema)]
+pub struct Alarm {
+    pub id: i64,
+    pub queue_id: i64,
+    /// `ready` (ready message count) or `oldest_age_ms` (age of the oldest
+    /// live message)
+    pub metric: String,
+    /// The alarm is breached while the metric is above this value
+    pub threshold: i64,
+    /// How long (ms) a breach must last before the alarm fires
+    pub for_ms: i64,
+    /// URL the firing and resolved notifications are POSTed to
+    pub webhook_url: String,
+    /// Start of the current breach (ms since the Unix epoch), if any
+    pub breached_since: Option<i64>,
+    /// Set from when the alarm fires until it resolves
+    pub firing: bool,
+    pub created_at: i64,
+}
+
+/// Where and how the server pushes a queue's ready messages
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow, ToSchema)]
+pub struct PushConfig {
+    pub queue_id: i64,
+    /// URL each message is POSTed to; a 2xx answer acks it
+    pub url: String,
+    /// Most deliveries in flight at once
+    pub concurrency: i32,
+    /// How long (ms) the endpoint may take to answer
+    pub timeout_ms: i64,
+    /// Delay (ms) before a failed message is retried, doubling with each
+    /// further attempt
+    pub backoff_ms: i64,
+    pub created_at: i64,
+    pub updated_at: i64,
+}
+
+/// One push of a message to its queue's endpoint, kept in the delivery log
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow, ToSchema)]
+pub struct PushDelivery {
+    pub id: i64,
+    pub queue_id: i64,
+    pub message_id: i64,
+    /// Which delivery of the message this was, starting at 1
+    pub attempt: i32,
+    /// HTTP status the endpoint answered with, if it answered
+    pub status_code: Option<i32>,
+    /// Why the delivery failed, if it did
+    pub error: Option<String>,
+    /// `acked`, `requeued`, `dead_lettered` or `lease_lost`
+    pub outcome: String,
+    pub duration_ms: i64,
+    pub delivered_at: i64,
+}
+
+/// One delivery of a message by a plain poll, kept in its attempt log
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow, ToSchema)]
+pub struct MessageAttempt {
+    pub id: i64,
+    pub message_id: i64,
+    pub queue_id: i64,
+    /// Which delivery of the message this was, starting at 1
+    pub attempt: i32,
+    pub leased_at: i64,
+    /// Who polled the message, as the consumer named itself
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub consumer: Option<String>,
+    /// `acked`, `requeued`, `dead_lettered`, `lease_expired` (delivered
+    /// again without an ack or nack) or `quarantined` (dead-lettered for
+    /// letting its lease expire too often); `None` while the lease is held
+    /// or after it lapsed
+    pub outcome: Option<String>,
+    /// Why the consumer nacked, as it told us
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub note: Option<String>,
+    pub settled_at: Option<i64>,
+}
+
+/// A message leased by a plain poll, and who holds it
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow, ToSchema)]
+pub struct InFlightMessage {
+    pub message_id: i64,
+    /// Public id of the message
+    pub public_id: String,
+    /// Deliveries so far, the current one included
+    pub attempts: i32,
+    /// Who polled the message, as the consumer named itself
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub consumer: Option<String>,
+    /// When the lease was taken; `None` if its attempt went unlogged
+    pub leased_at: Option<i64>,
+    /// How long the lease has been held so far, in ms
+    pub held_ms: Option<i64>,
+    /// When the lease lapses unless it is extended
+    pub lease_expires_at: i64,
+}
+
+/// An acked message kept in the archive of a queue with retention enabled
+#[derive(Debug, Serialize, Deserialize, FromRow, ToSchema)]
+pub struct ArchivedMessage {
+    /// ID the message had while queued
+    pub message_id: i64,
+    pub queue_id: i64,
+    pub payload: String,
+    pub attempts: i32,
+    pub priority: i32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group_id: Option<String>,
+    pub created_at: i64,
+    pub acked_at: i64,
+    /// When the archive purger deletes this entry
+    pub purge_at: i64,
+}
+
+/// A snapshot of a queue's depth and counters, recorded about once a minute
+/// by the server
+#[derive(
+    Debug, Clone, PartialEq, Eq, Serialize, Deserialize, FromRow, ToSchema,
+)]
+pub struct StatsSample {
+    /// When the snapshot was taken (ms since the Unix epoch)
+    pub recorded_at: i64,
+    pub ready: i64,
+    pub leased: i64,
+    pub delayed: i64,
+    pub dlq: i64,
+    /// Messages enqueued so far; the difference between two samples is the
+    /// enqueue throughput between them
+    pub enqueued: i64,
+    /// Messages acked so far, likewise
+    pub acked: i64,
+}
+
+/// A named subscription to a queue. Every group receives every message
+/// enqueued after it was created, with its own leases, acks and attempts,
+/// independently of the queue's other groups.
+#[derive(Debug, Serialize, Deserialize, FromRow, ToSchema)]
+pub struct ConsumerGroup {
+    pub id: i64,
+    pub queue_id: i64,
+    pub name: String,
+    /// Cursor: only messages with greater IDs are delivered to the group
+    pub start_id: i64,
+    pub created_at: i64,
+}
+
+/// An API key managed with `sqew auth`; only a hash of the key is stored
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow, ToSchema)]
+pub struct ApiKey {
+    pub id: i64,
+    /// Unique label the key is listed and revoked by
+    pub name: String,
+    /// SHA-256 of the key, hex-encoded
+    #[serde(skip)]
+    pub key_hash: String,
+    /// `admin`, `producer`, `consumer` or `read-only`
+    pub role: String,
+    /// Glob (`*` wildcards) limiting the key to matching queue names
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub queue_pattern: Option<String>,
+    pub created_at: i64,
+}
+
+/// An administrative action recorded in the append-only audit log
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow, ToSchema)]
+pub struct AuditEntry {
+    pub id: i64,
+    /// When the action was taken, in ms since the epoch
+    pub at: i64,
+    /// Who took it: `key:<name>` for a managed API key, `key` for a
+    /// configured one, `anonymous` without auth, `cli:<user>` from the CLI
+    pub actor: String,
+    /// What was done, such as `queue.create`, `queue.purge` or `key.grant`
+    pub action: String,
+    /// Queue the action applied to, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub queue: Option<String>,
+    /// Parameters of the action
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[sqlx(json(nullable))]
+    #[schema(value_type = Option<Object>)]
+    pub params: Option<serde_json::Value>,
+}
+
+/// A version of a queue's payload schema, recorded whenever it changes
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow, ToSchema)]
+pub struct QueueSchema {
+    pub version: i64,
+    /// The JSON Schema, or `None` for a version that removed it
+    #[sqlx(json(nullable))]
+    #[schema(value_type = Option<Object>)]
+    pub payload_schema: Option<serde_json::Value>,
+    pub created_at: i64,
+}
+
+/// A long-running admin operation run in the background by `sqew serve`
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow, ToSchema)]
+pub struct AdminJob {
+    pub id: i64,
+    /// `purge`, `compact`, `export` or `redrive`
+    pub kind: String,
+    /// Queue the job works on; `None` for `compact`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub queue: Option<String>,
+    /// File on the server an `export` writes to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    /// Messages handled per transaction
+    pub batch_size: i64,
+    /// `pending`, `running`, `done`, `failed` or `canceled`
+    pub status: String,
+    /// Messages purged, exported or redriven so far
+    pub progress: i64,
+    pub error: Option<String>,
+    /// Set by a cancel of a running job until the job stops
+    pub cancel_requested: bool,
+    pub created_at: i64,
+    pub started_at: Option<i64>,
+    pub finished_at: Option<i64>,
 }
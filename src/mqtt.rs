@@ -0,0 +1,284 @@
+//! A bridge between an MQTT broker and sqew, run by `sqew serve` when the
+//! configuration file has an `[mqtt]` section.
+//!
+//! Payloads published on each `[[mqtt.subscribe]]` topic filter are enqueued
+//! into its queue (created with default settings if needed), carrying the
+//! topic in the `mqtt_topic` header. Payloads that parse as JSON are stored
+//! as that JSON; anything else is stored as a JSON string. Each
+//! `[[mqtt.publish]]` queue is drained to its topic: ready messages are
+//! leased, published and acked once the MQTT client has taken them, JSON
+//! strings going out as their original text.
+
+use crate::queue::{self, EnqueueOptions};
+use crate::resp::{from_payload, to_payload};
+use crate::server::{AppState, POLL_RECHECK_INTERVAL};
+use anyhow::{Result, bail};
+use rumqttc::{AsyncClient, Event, MqttOptions, Packet, Publish, QoS};
+use serde::Deserialize;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::task::JoinSet;
+
+/// Port MQTT brokers listen on by default
+pub const DEFAULT_MQTT_PORT: u16 = 1883;
+
+/// How long the bridge waits before reconnecting to a lost broker
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// Messages leased at once when draining a queue to its topic
+const DRAIN_BATCH: i64 = 100;
+
+/// Lease taken on messages being published; ones not acked in time (the
+/// server stopped mid-batch) are delivered again
+const DRAIN_VISIBILITY_MS: i64 = 30_000;
+
+/// Requests the MQTT client buffers while the connection is busy or down
+const CLIENT_CAPACITY: usize = 100;
+
+/// `[mqtt]`: the broker to bridge and the topics mapped onto queues
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MqttConfig {
+    /// Broker host name or address
+    pub host: String,
+    #[serde(default = "default_port")]
+    pub port: u16,
+    #[serde(default = "default_client_id")]
+    pub client_id: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Topic filters whose payloads are enqueued
+    #[serde(default)]
+    pub subscribe: Vec<MqttSubscription>,
+    /// Queues whose messages are published
+    #[serde(default)]
+    pub publish: Vec<MqttPublication>,
+}
+
+/// `[[mqtt.subscribe]]`: enqueue what is published on `topic` into `queue`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MqttSubscription {
+    /// Topic filter; `+` and `#` wildcards are allowed
+    pub topic: String,
+    pub queue: String,
+    #[serde(default = "default_qos")]
+    pub qos: u8,
+}
+
+/// `[[mqtt.publish]]`: publish the messages of `queue` on `topic`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MqttPublication {
+    pub queue: String,
+    pub topic: String,
+    #[serde(default = "default_qos")]
+    pub qos: u8,
+    #[serde(default)]
+    pub retain: bool,
+}
+
+fn default_port() -> u16 {
+    DEFAULT_MQTT_PORT
+}
+
+fn default_client_id() -> String {
+    "sqew".to_string()
+}
+
+fn default_qos() -> u8 {
+    1
+}
+
+impl MqttConfig {
+    /// Check the QoS levels and that something is bridged
+    pub fn validate(&self) -> Result<()> {
+        if self.subscribe.is_empty() && self.publish.is_empty() {
+            bail!("Invalid [mqtt]: no subscribe or publish mappings");
+        }
+        let levels = self.subscribe.iter().map(|s| (&s.topic, s.qos));
+        for (topic, level) in
+            levels.chain(self.publish.iter().map(|p| (&p.topic, p.qos)))
+        {
+            qos(level).map_err(|_| {
+                anyhow::anyhow!("Invalid [mqtt] qos {level} for '{topic}'")
+            })?;
+        }
+        Ok(())
+    }
+}
+
+fn qos(level: u8) -> Result<QoS> {
+    Ok(rumqttc::qos(level)?)
+}
+
+/// Run the bridge until `stop` turns true, reconnecting whenever the broker
+/// is lost
+pub async fn run_bridge(
+    cfg: Arc<MqttConfig>,
+    state: AppState,
+    mut stop: watch::Receiver<bool>,
+) {
+    let mut opts = MqttOptions::new(&cfg.client_id, &cfg.host, cfg.port);
+    opts.set_keep_alive(Duration::from_secs(30));
+    // Incoming publishes are acked only once enqueued
+    opts.set_manual_acks(true);
+    if let (Some(user), Some(pass)) = (&cfg.username, &cfg.password) {
+        opts.set_credentials(user, pass);
+    }
+    let (client, mut events) = AsyncClient::new(opts, CLIENT_CAPACITY);
+    let mut drains = JoinSet::new();
+    for p in &cfg.publish {
+        drains.spawn(drain_queue(
+            client.clone(),
+            p.clone(),
+            state.clone(),
+            stop.clone(),
+        ));
+    }
+    loop {
+        let event = tokio::select! {
+            event = events.poll() => event,
+            _ = stop.wait_for(|stop| *stop) => break,
+        };
+        match event {
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                tracing::info!(
+                    "MQTT bridge connected to {}:{}",
+                    cfg.host,
+                    cfg.port
+                );
+                // Subscriptions do not outlive a clean session
+                for s in &cfg.subscribe {
+                    let level = qos(s.qos).unwrap_or(QoS::AtLeastOnce);
+                    if let Err(e) = client.try_subscribe(&s.topic, level) {
+                        tracing::warn!(
+                            "MQTT subscribe to '{}' failed: {e}",
+                            s.topic
+                        );
+                    }
+                }
+            }
+            Ok(Event::Incoming(Packet::Publish(p))) => {
+                enqueue_publish(&cfg, &state, &p).await;
+                if let Err(e) = client.try_ack(&p) {
+                    tracing::warn!("MQTT ack failed: {e}");
+                }
+            }
+            Ok(_) => {}
+            Err(e) => {
+                tracing::warn!(
+                    "MQTT connection to {}:{} failed: {e}",
+                    cfg.host,
+                    cfg.port
+                );
+                tokio::select! {
+                    _ = tokio::time::sleep(RECONNECT_DELAY) => {}
+                    _ = stop.wait_for(|stop| *stop) => break,
+                }
+            }
+        }
+    }
+    let _ = client.try_disconnect();
+    while drains.join_next().await.is_some() {}
+}
+
+// Enqueue a received payload into the queue of every subscription matching
+// its topic. Failures are logged: the broker is not asked to redeliver.
+async fn enqueue_publish(
+    cfg: &MqttConfig,
+    state: &AppState,
+    p: &Publish,
+) {
+    let text = String::from_utf8_lossy(&p.payload);
+    let payload = to_payload(&text);
+    let opts = EnqueueOptions {
+        headers: Some([("mqtt_topic".to_string(), p.topic.clone())].into()),
+        ..EnqueueOptions::default()
+    };
+    for s in
+        cfg.subscribe.iter().filter(|s| rumqttc::matches(&p.topic, &s.topic))
+    {
+        let enqueued = async {
+            state.ensure_queue(&s.queue).await?;
+            state.check_payload_size(&payload)?;
+            queue::enqueue_message_with(&state.db, &s.queue, &payload, &opts)
+                .await?;
+            anyhow::Ok(())
+        };
+        match enqueued.await {
+            Ok(()) => state.notifier.notify(&s.queue),
+            Err(e) => tracing::warn!(
+                "Dropped MQTT message on '{}' for queue '{}': {e:#}",
+                p.topic,
+                s.queue
+            ),
+        }
+    }
+}
+
+// Publish the messages of a queue as they become ready, until stopped
+async fn drain_queue(
+    client: AsyncClient,
+    p: MqttPublication,
+    state: AppState,
+    mut stop: watch::Receiver<bool>,
+) {
+    let level = qos(p.qos).unwrap_or(QoS::AtLeastOnce);
+    let wakeup = state.notifier.handle(&p.queue);
+    while !*stop.borrow() {
+        // Register for the wakeup before looking, so an enqueue landing in
+        // between is not missed
+        let woken = wakeup.notified();
+        match publish_ready(&client, &p, level, &state).await {
+            Ok(0) => {}
+            Ok(_) => continue,
+            Err(e) => {
+                tracing::warn!("Publishing '{}' to MQTT failed: {e:#}", p.queue)
+            }
+        }
+        tokio::select! {
+            _ = woken => {}
+            _ = tokio::time::sleep(POLL_RECHECK_INTERVAL) => {}
+            _ = stop.wait_for(|stop| *stop) => break,
+        }
+    }
+}
+
+// Lease a batch of ready messages, publish them and ack those the client
+// took. Returns how many were published.
+async fn publish_ready(
+    client: &AsyncClient,
+    p: &MqttPublication,
+    level: QoS,
+    state: &AppState,
+) -> Result<usize> {
+    let msgs = queue::poll_messages(
+        &state.db,
+        &p.queue,
+        DRAIN_BATCH,
+        DRAIN_VISIBILITY_MS,
+    )
+    .await?;
+    let Some(token) = msgs.first().and_then(|m| m.lease_token.clone()) else {
+        return Ok(0);
+    };
+    let mut published = Vec::with_capacity(msgs.len());
+    let mut failed = Vec::new();
+    for m in &msgs {
+        let body = from_payload(&m.payload);
+        match client.publish(&p.topic, level, p.retain, body).await {
+            Ok(()) => published.push(m.id),
+            Err(e) => {
+                tracing::warn!("MQTT publish of message {} failed: {e}", m.id);
+                failed.push(m.id);
+            }
+        }
+    }
+    queue::ack_messages(&state.db, &published, &token).await?;
+    if !failed.is_empty() {
+        queue::nack_messages(&state.db, &failed, &token, 0).await?;
+    }
+    Ok(published.len())
+}
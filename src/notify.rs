@@ -0,0 +1,36 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+
+/// In-process wakeups keyed by queue name, used to release long-polling
+/// consumers as soon as a message is enqueued.
+#[derive(Debug, Default)]
+pub struct QueueNotifier {
+    queues: Mutex<HashMap<String, Arc<Notify>>>,
+}
+
+impl QueueNotifier {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Get (or create) the wakeup handle for a queue
+    pub fn handle(
+        &self,
+        queue: &str,
+    ) -> Arc<Notify> {
+        let mut queues = self.queues.lock().expect("notifier lock poisoned");
+        queues.entry(queue.to_string()).or_default().clone()
+    }
+
+    /// Wake every waiter currently parked on the queue
+    pub fn notify(
+        &self,
+        queue: &str,
+    ) {
+        let queues = self.queues.lock().expect("notifier lock poisoned");
+        if let Some(n) = queues.get(queue) {
+            n.notify_waiters();
+        }
+    }
+}
@@ -0,0 +1,288 @@
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+/// Output format of CLI commands
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum OutputFormat {
+    /// Human-readable text
+    #[default]
+    Table,
+    /// One JSON document per command, for scripts
+    Json,
+    /// Comma-separated values with a header row, for spreadsheets
+    Csv,
+    /// Tab-separated values with a header row
+    Tsv,
+    /// One YAML document per command
+    Yaml,
+}
+
+impl OutputFormat {
+    /// Whether commands print their results as data rather than text
+    pub fn is_structured(self) -> bool {
+        self != OutputFormat::Table
+    }
+
+    /// Print a command's result on stdout. CSV and TSV print a row per
+    /// element of an array, or a single row, with nested objects flattened
+    /// into dotted columns; `Table` falls back to JSON.
+    pub(crate) fn print<T: Serialize + ?Sized>(
+        self,
+        value: &T,
+    ) -> anyhow::Result<()> {
+        let value = serde_json::to_value(value)?;
+        match self {
+            OutputFormat::Table | OutputFormat::Json => {
+                println!("{}", serde_json::to_string_pretty(&value)?)
+            }
+            OutputFormat::Yaml => print!("{}", yaml(&value)),
+            OutputFormat::Csv | OutputFormat::Tsv => {
+                let rows: Vec<Vec<(String, Value)>> = match value {
+                    Value::Array(items) => items.iter().map(flatten).collect(),
+                    other => vec![flatten(&other)],
+                };
+                let mut columns: Vec<String> = Vec::new();
+                for (name, _) in rows.iter().flatten() {
+                    if !columns.contains(name) {
+                        columns.push(name.clone());
+                    }
+                }
+                if rows.is_empty() {
+                    return Ok(());
+                }
+                println!("{}", self.row(columns.iter().map(|c| c.as_str())));
+                for row in &rows {
+                    println!("{}", self.record(&columns, row));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // A data row holding the cells of `row` under `columns`, empty where
+    // the row has no such column
+    fn record(
+        self,
+        columns: &[String],
+        row: &[(String, Value)],
+    ) -> String {
+        let cells: Vec<String> = columns
+            .iter()
+            .map(|c| {
+                row.iter()
+                    .find(|(name, _)| name == c)
+                    .map_or_else(String::new, |(_, v)| cell(v))
+            })
+            .collect();
+        self.row(cells.iter().map(|c| c.as_str()))
+    }
+
+    // Join cells into a line, quoted for CSV or escaped for TSV
+    fn row<'a>(
+        self,
+        cells: impl Iterator<Item = &'a str>,
+    ) -> String {
+        let (sep, escape): (&str, fn(&str) -> String) = match self {
+            OutputFormat::Tsv => ("\t", tsv_escape),
+            _ => (",", csv_escape),
+        };
+        cells.map(escape).collect::<Vec<_>>().join(sep)
+    }
+}
+
+/// Prints the records of a command that streams them, such as `message
+/// tail`, one at a time: a JSON document per line, a YAML document each, or
+/// CSV and TSV rows under a header taken from the first record
+pub(crate) struct RecordWriter {
+    format: OutputFormat,
+    columns: Option<Vec<String>>,
+}
+
+impl RecordWriter {
+    pub(crate) fn new(format: OutputFormat) -> Self {
+        RecordWriter { format, columns: None }
+    }
+
+    pub(crate) fn write<T: Serialize + ?Sized>(
+        &mut self,
+        value: &T,
+    ) -> anyhow::Result<()> {
+        let value = serde_json::to_value(value)?;
+        match self.format {
+            OutputFormat::Table | OutputFormat::Json => {
+                println!("{}", serde_json::to_string(&value)?)
+            }
+            OutputFormat::Yaml => print!("---\n{}", yaml(&value)),
+            OutputFormat::Csv | OutputFormat::Tsv => {
+                let row = flatten(&value);
+                let columns = self.columns.get_or_insert_with(|| {
+                    let names: Vec<String> =
+                        row.iter().map(|(name, _)| name.clone()).collect();
+                    println!(
+                        "{}",
+                        self.format.row(names.iter().map(|c| c.as_str()))
+                    );
+                    names
+                });
+                println!("{}", self.format.record(columns, &row));
+            }
+        }
+        Ok(())
+    }
+}
+
+// The columns of one row: scalars and arrays by their dotted path through
+// nested objects, or `value` for a bare scalar
+fn flatten(value: &Value) -> Vec<(String, Value)> {
+    fn walk(
+        prefix: &str,
+        value: &Value,
+        out: &mut Vec<(String, Value)>,
+    ) {
+        match value {
+            Value::Object(map) if !map.is_empty() => {
+                for (key, v) in map {
+                    let path = if prefix.is_empty() {
+                        key.clone()
+                    } else {
+                        format!("{prefix}.{key}")
+                    };
+                    walk(&path, v, out);
+                }
+            }
+            _ => {
+                let name = if prefix.is_empty() { "value" } else { prefix };
+                out.push((name.to_string(), value.clone()));
+            }
+        }
+    }
+    let mut out = Vec::new();
+    walk("", value, &mut out);
+    out
+}
+
+// Text of a cell: strings as they are, nothing for null, other values
+// (arrays included) as compact JSON
+fn cell(value: &Value) -> String {
+    match value {
+        Value::Null => String::new(),
+        Value::String(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
+
+// Quote a CSV field that holds a separator, quote or line break (RFC 4180)
+fn csv_escape(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+// Escape the characters that would break a TSV row
+fn tsv_escape(field: &str) -> String {
+    field
+        .replace('\\', "\\\\")
+        .replace('\t', "\\t")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r")
+}
+
+// Render a JSON value as a YAML block document
+fn yaml(value: &Value) -> String {
+    let mut out = String::new();
+    match value {
+        Value::Object(map) if !map.is_empty() => yaml_map(map, 0, &mut out),
+        Value::Array(items) if !items.is_empty() => {
+            yaml_seq(items, 0, &mut out)
+        }
+        scalar => {
+            out.push_str(&yaml_scalar(scalar));
+            out.push('\n');
+        }
+    }
+    out
+}
+
+fn yaml_map(
+    map: &Map<String, Value>,
+    indent: usize,
+    out: &mut String,
+) {
+    for (key, value) in map {
+        out.push_str(&" ".repeat(indent));
+        out.push_str(&yaml_string(key));
+        out.push(':');
+        match value {
+            Value::Object(m) if !m.is_empty() => {
+                out.push('\n');
+                yaml_map(m, indent + 2, out);
+            }
+            Value::Array(items) if !items.is_empty() => {
+                out.push('\n');
+                yaml_seq(items, indent + 2, out);
+            }
+            scalar => {
+                out.push(' ');
+                out.push_str(&yaml_scalar(scalar));
+                out.push('\n');
+            }
+        }
+    }
+}
+
+fn yaml_seq(
+    items: &[Value],
+    indent: usize,
+    out: &mut String,
+) {
+    for item in items {
+        out.push_str(&" ".repeat(indent));
+        out.push('-');
+        // A nested block starts on the dash's line, indented past it
+        let mut nested = String::new();
+        match item {
+            Value::Object(m) if !m.is_empty() => {
+                yaml_map(m, indent + 2, &mut nested)
+            }
+            Value::Array(v) if !v.is_empty() => {
+                yaml_seq(v, indent + 2, &mut nested)
+            }
+            scalar => {
+                out.push(' ');
+                out.push_str(&yaml_scalar(scalar));
+                out.push('\n');
+                continue;
+            }
+        }
+        out.push(' ');
+        out.push_str(&nested[indent + 2..]);
+    }
+}
+
+fn yaml_scalar(value: &Value) -> String {
+    match value {
+        Value::Null => "null".to_string(),
+        Value::String(s) => yaml_string(s),
+        Value::Object(_) => "{}".to_string(),
+        Value::Array(_) => "[]".to_string(),
+        other => other.to_string(),
+    }
+}
+
+// A string plain when YAML would read it back unchanged as a string,
+// otherwise double-quoted (JSON escapes are valid YAML)
+fn yaml_string(s: &str) -> String {
+    let plain = s
+        .chars()
+        .next()
+        .is_some_and(|c| c.is_alphabetic() || c == '_' || c == '/')
+        && s.chars().all(|c| c.is_alphanumeric() || " _-./".contains(c))
+        && !s.ends_with(' ')
+        && !matches!(
+            s.to_ascii_lowercase().as_str(),
+            "true" | "false" | "null" | "yes" | "no" | "on" | "off" | "y" | "n"
+        );
+    if plain { s.to_string() } else { Value::String(s.to_string()).to_string() }
+}
@@ -5,27 +5,237 @@ use clap::Subcommand;
 #[derive(Subcommand, Debug)]
 pub enum QueueCommands {
     /// List available queues
-    List,
+    List {
+        /// Only queues whose names match this pattern (`*` matches any run
+        /// of characters), e.g. 'prod-*'
+        #[arg(long)]
+        filter: Option<String>,
+    },
     /// Add a new queue
     Add {
         /// Queue name
         name: String,
         /// Maximum attempts (default: 5)
-        #[arg(long, default_value_t = 5)]
-        max_attempts: i32,
+        #[arg(long)]
+        max_attempts: Option<i32>,
+        /// Window in which repeated dedup keys are dropped (default: 5 minutes)
+        #[arg(long)]
+        dedup_window_ms: Option<i64>,
+        /// Archive acked messages for this many days instead of deleting them
+        #[arg(long)]
+        retention_days: Option<i32>,
+        /// Retry failed messages with exponential backoff starting at this
+        /// delay (overrides the delay given to nack)
+        #[arg(long)]
+        backoff_base_ms: Option<i64>,
+        /// Factor the backoff delay grows by per attempt (default: 2)
+        #[arg(long)]
+        backoff_multiplier: Option<f64>,
+        /// Upper bound on the backoff delay
+        #[arg(long)]
+        backoff_max_ms: Option<i64>,
+        /// Fraction (0-1) of each backoff delay to randomize (default: 0)
+        #[arg(long)]
+        backoff_jitter: Option<f64>,
+        /// Lease length for polls that give no --visibility-ms (default: 30000)
+        #[arg(long)]
+        default_visibility_ms: Option<i64>,
+        /// Delay for enqueues that give no --delay-ms (default: 0)
+        #[arg(long)]
+        default_delay_ms: Option<i64>,
+        /// Lease at most this many messages per second across all consumers
+        #[arg(long)]
+        max_deliveries_per_second: Option<f64>,
+        /// Reject enqueues whose JSON payload is larger than this many bytes
+        #[arg(long)]
+        max_payload_bytes: Option<i64>,
+        /// JSON Schema file every enqueued payload must match
+        #[arg(long)]
+        payload_schema: Option<PathBuf>,
+        /// What enqueues do with payloads that break the schema (default:
+        /// reject)
+        #[arg(long, value_enum)]
+        schema_mode: Option<SchemaMode>,
+        /// Deliver strictly in enqueue order: lease only the oldest message
+        /// (of each FIFO group) until it is acked or dead-lettered
+        #[arg(long)]
+        strict_fifo: bool,
+        /// Take turns between the messages' fair keys when polling, so one
+        /// tenant's backlog cannot starve the others
+        #[arg(long)]
+        fair: bool,
+        /// Order ready messages are polled in (default: priority)
+        #[arg(long, value_enum)]
+        ordering: Option<QueueOrdering>,
+        /// Refuse enqueues while this many messages are unacked
+        #[arg(long)]
+        max_depth: Option<i64>,
+        /// Quarantine a message into the dead letters after its lease
+        /// expires this many times without an ack or nack
+        #[arg(long)]
+        max_lease_expirations: Option<i32>,
+    },
+    /// Change a queue's settings in place
+    Update {
+        /// Queue name
+        name: String,
+        /// Maximum attempts before dead-lettering
+        #[arg(long)]
+        max_attempts: Option<i32>,
+        /// Window in which repeated dedup keys are dropped
+        #[arg(long)]
+        dedup_window_ms: Option<i64>,
+        /// Archive acked messages for this many days
+        #[arg(long, conflicts_with = "no_retention")]
+        retention_days: Option<i32>,
+        /// Delete acked messages instead of archiving them
+        #[arg(long)]
+        no_retention: bool,
+        /// First delay of exponential nack backoff
+        #[arg(long, conflicts_with = "no_backoff")]
+        backoff_base_ms: Option<i64>,
+        /// Turn off backoff; nacks use the delay they request
+        #[arg(long)]
+        no_backoff: bool,
+        /// Factor the backoff delay grows by per attempt
+        #[arg(long)]
+        backoff_multiplier: Option<f64>,
+        /// Upper bound on the backoff delay
+        #[arg(long, conflicts_with = "no_backoff_max")]
+        backoff_max_ms: Option<i64>,
+        /// Remove the upper bound on the backoff delay
+        #[arg(long)]
+        no_backoff_max: bool,
+        /// Fraction (0-1) of each backoff delay to randomize
+        #[arg(long)]
+        backoff_jitter: Option<f64>,
+        /// Lease length for polls that give no --visibility-ms
+        #[arg(long)]
+        default_visibility_ms: Option<i64>,
+        /// Delay for enqueues that give no --delay-ms
+        #[arg(long)]
+        default_delay_ms: Option<i64>,
+        /// Lease at most this many messages per second across all consumers
+        #[arg(long, conflicts_with = "no_rate_limit")]
+        max_deliveries_per_second: Option<f64>,
+        /// Remove the delivery rate limit
+        #[arg(long)]
+        no_rate_limit: bool,
+        /// Reject enqueues whose JSON payload is larger than this many bytes
+        #[arg(long, conflicts_with = "no_payload_limit")]
+        max_payload_bytes: Option<i64>,
+        /// Remove the payload size limit
+        #[arg(long)]
+        no_payload_limit: bool,
+        /// JSON Schema file every enqueued payload must match
+        #[arg(long, conflicts_with = "no_payload_schema")]
+        payload_schema: Option<PathBuf>,
+        /// Stop validating payloads against a schema
+        #[arg(long)]
+        no_payload_schema: bool,
+        /// What enqueues do with payloads that break the schema
+        #[arg(long, value_enum)]
+        schema_mode: Option<SchemaMode>,
+        /// Turn strict FIFO delivery on or off
+        #[arg(long)]
+        strict_fifo: Option<bool>,
+        /// Turn fair polling across fair keys on or off
+        #[arg(long)]
+        fair: Option<bool>,
+        /// Order ready messages are polled in
+        #[arg(long, value_enum)]
+        ordering: Option<QueueOrdering>,
+        /// Refuse enqueues while this many messages are unacked
+        #[arg(long, conflicts_with = "no_max_depth")]
+        max_depth: Option<i64>,
+        /// Remove the depth limit
+        #[arg(long)]
+        no_max_depth: bool,
+        /// Quarantine a message into the dead letters after its lease
+        /// expires this many times without an ack or nack
+        #[arg(long, conflicts_with = "no_quarantine")]
+        max_lease_expirations: Option<i32>,
+        /// Stop quarantining messages whose leases keep expiring
+        #[arg(long)]
+        no_quarantine: bool,
     },
-    /// Remove a queue
+    /// Remove a queue and its messages
     Remove {
+        /// Queue name, or a pattern such as 'tmp-*' (`*` matches any run of
+        /// characters) removing every queue it matches
+        name: String,
+        /// Move the queue to the trash instead, from which `queue restore`
+        /// brings it back until it is purged 7 days later
+        #[arg(long)]
+        soft: bool,
+        /// Remove the queues a pattern matches without asking first
+        #[arg(long)]
+        yes: bool,
+    },
+    /// Bring a queue back out of the trash
+    Restore {
         /// Queue name
         name: String,
     },
+    /// List the queues in the trash
+    Trash,
+    /// Create a queue with the settings of another
+    Clone {
+        /// Queue to copy
+        source: String,
+        /// Name of the new queue
+        target: String,
+        /// Also copy the source's live messages (leases are not copied)
+        #[arg(long)]
+        with_messages: bool,
+    },
     /// Show queue details and stats
     Show {
         /// Queue name
         name: String,
     },
+    /// Show a queue's stats, now or over time
+    Stats {
+        /// Queue name
+        name: String,
+        /// List the snapshots the server recorded over `--window` instead
+        #[arg(long)]
+        history: bool,
+        /// How far back the history goes: a number with a unit of s, m, h
+        /// or d
+        #[arg(long, default_value = "1h", requires = "history")]
+        window: String,
+    },
+    /// Print a queue's stats every interval, until Ctrl+C
+    Watch {
+        /// Queue name
+        name: String,
+        /// How often to refresh, in milliseconds
+        #[arg(long, default_value_t = 1000)]
+        interval_ms: u64,
+        /// Exit after this many refreshes
+        #[arg(long)]
+        count: Option<usize>,
+    },
     /// Purge (delete) all messages in the queue
     Purge {
+        /// Queue name, or a pattern such as 'batch-*' (`*` matches any run
+        /// of characters) purging every queue it matches
+        name: String,
+        /// Messages deleted per transaction
+        #[arg(long, default_value_t = PURGE_BATCH)]
+        batch_size: usize,
+        /// Purge the queues a pattern matches without asking first
+        #[arg(long)]
+        yes: bool,
+    },
+    /// Stop polls from leasing messages; enqueues are still accepted
+    Pause {
+        /// Queue name
+        name: String,
+    },
+    /// Let polls lease messages from a paused queue again
+    Resume {
         /// Queue name
         name: String,
     },
@@ -37,17 +247,326 @@ pub enum QueueCommands {
         #[arg(long, default_value_t = 1)]
         limit: i64,
     },
+    /// List the messages leased by plain polls: which consumer holds each
+    /// and for how long
+    Inflight {
+        /// Queue name
+        name: String,
+        /// Number of messages to list (soonest lease expiry first)
+        #[arg(long, default_value_t = 10)]
+        limit: i64,
+    },
     /// Compact the database (VACUUM)
     Compact {
         /// Queue name (unused, for CLI consistency)
         name: String,
+        /// First compress stored payloads above the compression threshold
+        /// that were written uncompressed
+        #[arg(long)]
+        recompress: bool,
+    },
+    /// Write every message of the queue to a newline-delimited JSON file
+    Export {
+        /// Queue name
+        name: String,
+        /// File to write; replaced if it exists
+        #[arg(long)]
+        file: PathBuf,
+    },
+    /// Load messages from a file written by `queue export`, or exported
+    /// from SQS or RabbitMQ
+    Import {
+        /// Queue name
+        name: String,
+        /// File to read
+        #[arg(long)]
+        file: PathBuf,
+        /// Shape of the file's messages
+        #[arg(long, value_enum, default_value_t)]
+        format: ImportFormat,
+    },
+    /// Dead-letter queue commands
+    #[command(subcommand)]
+    Dlq(DlqCommands),
+    /// Consumer group (fan-out) commands
+    #[command(subcommand)]
+    Group(GroupCommands),
+    /// Recurring (cron) message commands
+    #[command(subcommand)]
+    Schedule(ScheduleCommands),
+    /// Depth and age alarms notified through webhooks
+    #[command(subcommand)]
+    Alarm(AlarmCommands),
+    /// Push delivery of a queue's messages to an HTTP endpoint
+    #[command(subcommand)]
+    PushConfig(PushConfigCommands),
+    /// Versioned JSON Schema of a queue's payloads
+    #[command(subcommand)]
+    Schema(SchemaCommands),
+}
+
+/// Payload schema CLI subcommands
+#[derive(Subcommand, Debug)]
+pub enum SchemaCommands {
+    /// Validate enqueued payloads against a JSON Schema, recorded as the
+    /// queue's next schema version
+    Set {
+        /// Queue name
+        name: String,
+        /// JSON Schema file
+        #[arg(long)]
+        file: PathBuf,
+        /// What enqueues do with payloads that break the schema
+        #[arg(long, value_enum)]
+        mode: Option<SchemaMode>,
+    },
+    /// Print a queue's current schema, or an earlier version
+    Show {
+        /// Queue name
+        name: String,
+        /// Version to print
+        #[arg(long)]
+        version: Option<i64>,
+    },
+    /// List every version of a queue's schema, newest first
+    History {
+        /// Queue name
+        name: String,
+    },
+}
+
+/// Dead-letter queue CLI subcommands
+#[derive(Subcommand, Debug)]
+pub enum DlqCommands {
+    /// List dead-lettered messages
+    List {
+        /// Queue name
+        name: String,
+        /// Number of messages to list
+        #[arg(long, default_value_t = 10)]
+        limit: i64,
+    },
+    /// Requeue dead letters with attempts reset (all if no IDs given)
+    Redrive {
+        /// Queue name
+        name: String,
+        /// Comma-separated message IDs or public ids, e.g. 1,2,3
+        #[arg(long, value_delimiter = ',')]
+        ids: Vec<MessageRef>,
+    },
+    /// Delete all dead-lettered messages
+    Purge {
+        /// Queue name
+        name: String,
+    },
+}
+
+/// Consumer group CLI subcommands
+#[derive(Subcommand, Debug)]
+pub enum GroupCommands {
+    /// Add a consumer group; it receives messages enqueued from now on
+    Add {
+        /// Queue name
+        name: String,
+        /// Group name
+        group: String,
+    },
+    /// List the consumer groups of a queue
+    List {
+        /// Queue name
+        name: String,
+    },
+    /// Remove a consumer group and its delivery state
+    Remove {
+        /// Queue name
+        name: String,
+        /// Group name
+        group: String,
+    },
+}
+
+/// Schedule CLI subcommands
+#[derive(Subcommand, Debug)]
+pub enum ScheduleCommands {
+    /// Enqueue a payload into a queue on a cron schedule (UTC)
+    Add {
+        /// Queue name
+        name: String,
+        /// Cron expression, e.g. "*/5 * * * *" (an optional leading seconds field is allowed)
+        #[arg(long)]
+        cron: String,
+        /// JSON payload to enqueue on each run
+        #[arg(long)]
+        payload: String,
+    },
+    /// List schedules (all queues if no name given)
+    List {
+        /// Queue name
+        name: Option<String>,
+    },
+    /// Remove a schedule
+    Remove {
+        /// Schedule ID
+        id: i64,
+    },
+}
+
+/// Push delivery CLI subcommands
+#[derive(Subcommand, Debug)]
+pub enum PushConfigCommands {
+    /// Have the server POST a queue's ready messages to a URL
+    Set {
+        /// Queue name
+        name: String,
+        /// URL each message is POSTed to; a 2xx answer acks it
+        #[arg(long)]
+        url: String,
+        /// Most deliveries in flight at once
+        #[arg(long, default_value_t = 4)]
+        concurrency: i32,
+        /// How long (ms) the endpoint may take to answer
+        #[arg(long, default_value_t = 10_000)]
+        timeout_ms: i64,
+        /// Retry delay (ms) after a failed delivery, doubled per attempt
+        #[arg(long, default_value_t = 1000)]
+        backoff_ms: i64,
+    },
+    /// Show a queue's push config
+    Show {
+        /// Queue name
+        name: String,
+    },
+    /// Stop pushing a queue's messages
+    Remove {
+        /// Queue name
+        name: String,
+    },
+    /// Recent push deliveries of a queue, newest first
+    Log {
+        /// Queue name
+        name: String,
+        /// Number of deliveries to show
+        #[arg(long, default_value_t = 20)]
+        limit: i64,
+    },
+}
+
+/// Alarm CLI subcommands
+#[derive(Subcommand, Debug)]
+pub enum AlarmCommands {
+    /// Notify a webhook when a queue metric stays above a threshold
+    Add {
+        /// Queue name
+        name: String,
+        /// Metric to watch
+        #[arg(long, value_parser = ["ready", "oldest_age_ms"])]
+        metric: String,
+        /// The alarm fires while the metric is above this value
+        #[arg(long)]
+        threshold: i64,
+        /// How long (ms) the metric must stay above the threshold to fire
+        #[arg(long, default_value_t = 0)]
+        for_ms: i64,
+        /// URL the firing and resolved notifications are POSTed to
+        #[arg(long)]
+        webhook: String,
+    },
+    /// List alarms (all queues if no name given)
+    List {
+        /// Queue name
+        name: Option<String>,
+    },
+    /// Remove an alarm
+    Remove {
+        /// Alarm ID
+        id: i64,
+    },
+}
+
+/// Database maintenance CLI subcommands
+#[derive(Subcommand, Debug)]
+pub enum DbCommands {
+    /// Apply pending schema migrations and report the schema version
+    Migrate,
+    /// Write a snapshot of the SQLite database to a new file while it stays
+    /// in use
+    Backup {
+        /// Backup file to create; must not exist
+        path: PathBuf,
+    },
+    /// Replace the SQLite database with a backup. Stop servers and workers
+    /// using the database first.
+    Restore {
+        /// Backup file to restore from
+        path: PathBuf,
+    },
+    /// Check integrity and look for orphaned rows, stuck leases and
+    /// impossible timestamps, e.g. after a crash or manual edits
+    Doctor {
+        /// Repair the problems found (all but integrity errors)
+        #[arg(long)]
+        fix: bool,
+    },
+    /// Report the database size, free space and the rows of each queue, and
+    /// whether compacting would reclaim enough to be worthwhile
+    Status,
+    /// Re-encrypt stored payloads with the active (first) encryption key:
+    /// payloads written under an older key or before encryption was enabled
+    RotateKey,
+    /// Upload a compressed snapshot of the SQLite database to an S3 bucket
+    /// or a directory every interval, keeping the latest few. Snapshots
+    /// matching the latest uploaded are skipped.
+    Replicate {
+        #[command(flatten)]
+        replica: crate::replica::ReplicaOptions,
+        /// Milliseconds between snapshots
+        #[arg(long, default_value_t = 60_000)]
+        interval_ms: u64,
+        /// Snapshots kept in the replica
+        #[arg(long, default_value_t = 24)]
+        retain: usize,
+        /// Take one snapshot and exit
+        #[arg(long)]
+        once: bool,
+    },
+    /// Replace the SQLite database with the latest snapshot in a replica.
+    /// Stop servers and workers using the database first.
+    RestoreFromReplica {
+        #[command(flatten)]
+        replica: crate::replica::ReplicaOptions,
+        /// Restore the latest snapshot taken by this RFC 3339 time instead
+        #[arg(long)]
+        at: Option<String>,
+    },
+}
+
+/// Admin job CLI subcommands
+#[derive(Subcommand, Debug)]
+pub enum JobCommands {
+    /// List the latest admin jobs, newest first
+    List {
+        /// Most jobs listed
+        #[arg(long, default_value_t = 20)]
+        limit: i64,
+    },
+    /// Show a job's status and progress
+    Status {
+        /// Job id
+        id: i64,
+    },
+    /// Cancel a pending job, or stop a running one after its current batch
+    Cancel {
+        /// Job id
+        id: i64,
     },
 }
 
 /// Message-related CLI subcommands
 #[derive(Subcommand, Debug)]
 pub enum MessageCommands {
-    /// Enqueue a JSON message. Use --payload or --file (NDJSON or JSON array).
+    /// Enqueue a message. Use --payload, --file (NDJSON or JSON array),
+    /// --payload-file (one raw payload of any type) or --stdin (streamed
+    /// NDJSON).
     Enqueue {
         /// Queue name
         queue: String,
@@ -57,9 +576,64 @@ pub enum MessageCommands {
         /// Read payload(s) from file (NDJSON or JSON array)
         #[arg(long)]
         file: Option<std::path::PathBuf>,
-        /// Delay visibility in milliseconds (default: 0)
+        /// Enqueue the bytes of a file as one payload, e.g. protobuf or
+        /// msgpack, of the media type --content-type
+        #[arg(long, conflicts_with_all = ["payload", "file"])]
+        payload_file: Option<std::path::PathBuf>,
+        /// Media type of --payload-file; JSON types are enqueued as JSON
+        #[arg(
+            long,
+            default_value = "application/octet-stream",
+            requires = "payload_file"
+        )]
+        content_type: String,
+        /// Stream NDJSON payloads from standard input, committing in batches
+        #[arg(
+            long,
+            conflicts_with_all = ["payload", "file", "payload_file", "dedup_key"]
+        )]
+        stdin: bool,
+        /// Messages per transaction with --stdin
+        #[arg(long, default_value_t = 1000, requires = "stdin")]
+        batch_size: usize,
+        /// Delay visibility in milliseconds (default: the queue's default delay)
+        #[arg(long)]
+        delay_ms: Option<i64>,
+        /// Deliver at an RFC 3339 time with a UTC offset instead, e.g.
+        /// 2025-06-02T09:00:00+02:00; a time already past delivers at once
+        #[arg(long, value_parser = parse_deliver_at, conflicts_with = "delay_ms")]
+        deliver_at: Option<i64>,
+        /// Priority; higher values are polled first (default: 0)
         #[arg(long, default_value_t = 0)]
-        delay_ms: i64,
+        priority: i32,
+        /// Expire the message if not consumed within this many milliseconds
+        #[arg(long)]
+        ttl_ms: Option<i64>,
+        /// Deduplication key; repeats within the queue's window are dropped
+        #[arg(long)]
+        dedup_key: Option<String>,
+        /// FIFO group; messages in a group are delivered one at a time, in order
+        #[arg(long = "group")]
+        group_id: Option<String>,
+        /// Header as key=value (repeatable), e.g. --header content_type=json
+        #[arg(long = "header", value_parser = parse_header)]
+        headers: Vec<(String, String)>,
+        /// Trace id correlating the message in logs (default: generated)
+        #[arg(long)]
+        trace_id: Option<String>,
+        /// Tenant key: polls of a fair queue take turns between keys
+        #[arg(long)]
+        fair_key: Option<String>,
+        /// Sort key: polls of a sort_key queue take lower keys first
+        #[arg(long)]
+        sort_key: Option<f64>,
+        /// Queue to send a receipt to once the message is acked or
+        /// dead-lettered
+        #[arg(long)]
+        reply_to: Option<String>,
+        /// Wait up to this many milliseconds for room in a full queue
+        #[arg(long)]
+        wait_ms: Option<i64>,
     },
     /// Poll (lease) up to N messages; updates visibility via available_at.
     Poll {
@@ -68,29 +642,77 @@ pub enum MessageCommands {
         /// Batch size (default: 1)
         #[arg(long, default_value_t = 1)]
         batch: i64,
-        /// Visibility timeout in ms (default: 30000)
-        #[arg(long, default_value_t = 30_000)]
-        visibility_ms: i64,
+        /// Visibility timeout in ms (default: the queue's default visibility)
+        #[arg(long)]
+        visibility_ms: Option<i64>,
+        /// Consumer group to poll for (required on queues with groups)
+        #[arg(long)]
+        group: Option<String>,
+        /// Name recorded as the consumer holding the leases, shown by
+        /// `queue inflight` and in the messages' attempt logs
+        #[arg(long, visible_alias = "consumer-id", conflicts_with = "group")]
+        consumer: Option<String>,
     },
     /// Acknowledge (delete) messages by IDs
     Ack {
-        /// Comma-separated message IDs, e.g. 1,2,3
+        /// Comma-separated message IDs or public ids, e.g. 1,2,3
         #[arg(long, value_delimiter = ',')]
-        ids: Vec<i64>,
+        ids: Vec<MessageRef>,
+        /// Lease token returned by poll
+        #[arg(long)]
+        lease_token: String,
     },
     /// Negative-acknowledge: increment attempts and requeue after delay
     Nack {
-        /// Comma-separated message IDs, e.g. 1,2,3
+        /// Comma-separated message IDs or public ids, e.g. 1,2,3
         #[arg(long, value_delimiter = ',')]
-        ids: Vec<i64>,
+        ids: Vec<MessageRef>,
+        /// Lease token returned by poll
+        #[arg(long)]
+        lease_token: String,
         /// Delay before message becomes visible again
         #[arg(long, default_value_t = 1000)]
         delay_ms: i64,
+        /// Messages with their own delay, as comma-separated ID:MS pairs,
+        /// e.g. 4:500,5:30000
+        #[arg(long, value_delimiter = ',', value_parser = parse_nack_delay)]
+        delays: Vec<(MessageRef, i64)>,
+        /// Why the messages failed (error message or stack trace), kept on
+        /// the messages and their dead letters
+        #[arg(long)]
+        reason: Option<String>,
+    },
+    /// Extend the visibility timeout of leased messages (heartbeat)
+    Extend {
+        /// Comma-separated message IDs or public ids, e.g. 1,2,3
+        #[arg(long, value_delimiter = ',')]
+        ids: Vec<MessageRef>,
+        /// Lease token returned by poll
+        #[arg(long)]
+        lease_token: String,
+        /// Milliseconds to add to the current lease
+        #[arg(long, default_value_t = 30_000)]
+        extra_ms: i64,
     },
-    /// Remove a message by ID (hard delete)
+    /// Remove a message by ID (hard delete), or every live message of a
+    /// queue matching --filter and --older-than
     Remove {
-        /// Message ID
-        id: i64,
+        /// Message ID or public id
+        #[arg(required_unless_present = "queue", conflicts_with = "queue")]
+        id: Option<MessageRef>,
+        /// Queue to remove matching messages from; dead letters are kept
+        #[arg(long)]
+        queue: Option<String>,
+        /// Only messages whose payload matches a JSON path predicate, e.g.
+        /// '$.type == "spam"'
+        #[arg(long, requires = "queue")]
+        filter: Option<String>,
+        /// Only messages enqueued longer ago than this, e.g. 1h or 7d
+        #[arg(long, requires = "queue")]
+        older_than: Option<String>,
+        /// Messages deleted per transaction
+        #[arg(long, default_value_t = PURGE_BATCH, requires = "queue")]
+        batch_size: usize,
     },
     /// Peek messages in a queue (no leasing)
     Peek {
@@ -99,314 +721,5782 @@ pub enum MessageCommands {
         /// Number of messages to peek (default: 1)
         #[arg(long, default_value_t = 1)]
         limit: u32,
+        /// Only show messages with this header, as key=value
+        #[arg(long, value_parser = parse_header)]
+        header: Option<(String, String)>,
+        /// Skip this many matching messages
+        #[arg(long, default_value_t = 0)]
+        offset: i64,
+        /// Only messages with a greater ID, in ID order (cursor paging)
+        #[arg(long)]
+        after_id: Option<i64>,
+        /// Only messages created at or after this time (ms since epoch)
+        #[arg(long)]
+        created_after: Option<i64>,
+        /// Only messages created before this time (ms since epoch)
+        #[arg(long)]
+        created_before: Option<i64>,
+        /// Only messages whose payload contains this text
+        #[arg(long)]
+        contains: Option<String>,
+        /// Only messages whose payload matches path=value, e.g. '$.kind=email'
+        #[arg(long, value_parser = parse_json_filter)]
+        json_path: Option<(String, String)>,
+        /// Only messages that are ready, delayed or leased
+        #[arg(long, value_enum, default_value_t)]
+        state: MessageState,
+    },
+    /// Print messages as they are enqueued, until Ctrl+C
+    Tail {
+        /// Queue name
+        queue: String,
+        /// Lease each message and ack it once printed, consuming the queue
+        /// (including messages already waiting in it)
+        #[arg(long)]
+        ack: bool,
+        /// How often to check for new messages, in milliseconds
+        #[arg(long, default_value_t = 1000)]
+        interval_ms: u64,
+        /// Exit after printing this many messages
+        #[arg(long)]
+        count: Option<usize>,
+    },
+    /// Search all messages in a queue (including leased and dead-lettered)
+    /// by a value in their JSON payload
+    Search {
+        /// Queue name
+        queue: String,
+        /// JSON path into the payload, e.g. '$.order.id'
+        #[arg(long)]
+        jsonpath: String,
+        /// Value the path must equal; without it, any message with the path matches
+        #[arg(long)]
+        value: Option<String>,
+        /// Only messages with a greater ID (cursor paging)
+        #[arg(long)]
+        after_id: Option<i64>,
+        /// Maximum number of messages to return
+        #[arg(long, default_value_t = 20)]
+        limit: u32,
     },
     /// Peek a single message by ID
     PeekId {
-        /// Message ID
-        id: i64,
+        /// Message ID or public id
+        id: MessageRef,
+    },
+    /// Write a message's payload as raw bytes, decoding binary payloads
+    Payload {
+        /// Message ID or public id
+        id: MessageRef,
+        /// File to write instead of standard output
+        #[arg(long)]
+        out: Option<PathBuf>,
+    },
+    /// Move messages (including dead letters) into another queue
+    Move {
+        /// Comma-separated message IDs or public ids, e.g. 1,2,3
+        #[arg(long, value_delimiter = ',', required = true)]
+        ids: Vec<MessageRef>,
+        /// Target queue name
+        #[arg(long)]
+        to: String,
+        /// Only move messages currently in this queue
+        #[arg(long)]
+        from: Option<String>,
+        /// Reset the attempt count of moved messages
+        #[arg(long)]
+        reset_attempts: bool,
+    },
+    /// List acked messages archived by a queue with retention enabled
+    History {
+        /// Queue name
+        queue: String,
+        /// Number of messages to list (most recent first)
+        #[arg(long, default_value_t = 10)]
+        limit: i64,
+    },
+    /// List a message's delivery attempts and how each ended
+    Attempts {
+        /// Message ID or public id
+        id: MessageRef,
+    },
+    /// Re-enqueue archived messages, with fresh attempts, from a queue with
+    /// retention enabled
+    Replay {
+        /// Queue name
+        queue: String,
+        /// Only messages acked at or after this time (ms since epoch)
+        #[arg(long)]
+        from: Option<i64>,
+        /// Only messages acked before this time (ms since epoch)
+        #[arg(long)]
+        to: Option<i64>,
+        /// Only messages whose payload contains this text
+        #[arg(long)]
+        contains: Option<String>,
     },
 }
 
 /// Execute a queue command
-use crate::db;
-use crate::models::Message;
+use crate::audit::{self, Actor};
+use crate::db::{
+    self, CompactRun, Db, DbStatus, DoctorReport, Keyring, MessageState,
+    PeekFilter, PgStorage, PoolOptions, RecoveryReport, RemoveFilter,
+    SqliteStorage,
+};
+use crate::error::{Context, Result, SqewError};
+use crate::import::{self, ImportFormat};
+use crate::metrics::{Op, OpTimer};
+use crate::models::AdminJob;
+use crate::models::Alarm;
+use crate::models::ArchivedMessage;
+use crate::models::ConsumerGroup;
 use crate::models::Queue;
-use anyhow::{Context, Result, anyhow};
+use crate::models::QueueSchema;
+use crate::models::Schedule;
+use crate::models::StatsSample;
+use crate::models::{
+    Headers, InFlightMessage, Message, MessageAttempt, MessageRef,
+};
+use crate::models::{PushConfig, PushDelivery};
+use crate::notify::QueueNotifier;
+use crate::output::{OutputFormat, RecordWriter};
+use base64::Engine;
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use sqlx::SqlitePool;
-use std::path::PathBuf;
+use sqlx::{Sqlite, Transaction};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_stream::Stream;
+use tokio_stream::wrappers::ReceiverStream;
 
 // Service-level queue operations, wrapping the DB layer
 /// List all queues
-pub async fn list_queues(pool: &SqlitePool) -> Result<Vec<Queue>> {
-    db::list_queues(pool).await.context("Failed to list queues")
+#[tracing::instrument(level = "debug", skip_all)]
+pub async fn list_queues(db: &Db) -> Result<Vec<Queue>> {
+    db.list_queues().await.context("Failed to list queues")
 }
 
-/// Create a new queue, return the created Queue
-pub async fn create_queue(
-    pool: &SqlitePool,
-    name: &str,
-    max_attempts: i32,
-) -> Result<Queue> {
-    if db::get_queue_by_name(pool, name).await?.is_some() {
-        return Err(anyhow!("Queue '{}' already exists", name));
+/// List the queues whose names match `pattern`, where `*` matches any run
+/// of characters
+#[tracing::instrument(level = "debug", skip_all, fields(pattern = %pattern))]
+pub async fn list_queues_matching(
+    db: &Db,
+    pattern: &str,
+) -> Result<Vec<Queue>> {
+    if pattern.is_empty() {
+        return Err(SqewError::Invalid(
+            "queue pattern: must not be empty".into(),
+        ));
     }
-    db::create_queue(pool, name, max_attempts)
-        .await
-        .context("Failed to create queue")?;
-    let q = db::get_queue_by_name(pool, name)
-        .await
-        .context("Failed to fetch created queue")?
-        .ok_or_else(|| anyhow!("Queue '{}' not found after creation", name))?;
-    Ok(q)
+    db.list_queues_matching(pattern).await.context("Failed to list queues")
 }
 
-/// Delete a queue by name. Returns true if a queue was deleted
-pub async fn delete_queue(
-    pool: &SqlitePool,
-    name: &str,
-) -> Result<bool> {
-    let deleted = db::delete_queue_by_name(pool, name)
-        .await
-        .context("Failed to delete queue")?;
-    Ok(deleted > 0)
+/// Optional settings for creating a queue
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QueueOptions {
+    /// Deliveries before a message is dead-lettered
+    pub max_attempts: i32,
+    /// Enqueues repeating a dedup key within this window are deduplicated
+    pub dedup_window_ms: i64,
+    /// Days acked messages stay in the archive; `None` deletes them on ack
+    pub retention_days: Option<i32>,
+    /// First nack delay of exponential backoff; `None` disables backoff
+    pub backoff_base_ms: Option<i64>,
+    /// Backoff growth factor per attempt (at least 1)
+    pub backoff_multiplier: f64,
+    /// Cap on the backoff delay
+    pub backoff_max_ms: Option<i64>,
+    /// Fraction (0..=1) of each backoff delay that is randomized away
+    pub backoff_jitter: f64,
+    /// Lease length for polls that give no visibility timeout
+    pub default_visibility_ms: i64,
+    /// Delay for enqueues that give none
+    pub default_delay_ms: i64,
+    /// Most messages polls may lease per second; `None` is unlimited
+    pub max_deliveries_per_second: Option<f64>,
+    /// Largest serialized payload accepted by enqueue; `None` is unlimited
+    pub max_payload_bytes: Option<i64>,
+    /// JSON Schema enqueued payloads must match
+    pub payload_schema: Option<Value>,
+    /// What enqueues do with payloads that break the schema
+    pub schema_mode: SchemaMode,
+    /// Lease only the oldest message (of each FIFO group) at a time
+    pub strict_fifo: bool,
+    /// Take turns between fair keys when polling
+    pub fair: bool,
+    /// Order in which ready messages are polled and peeked
+    pub ordering: QueueOrdering,
+    /// Most unacked messages the queue holds; `None` is unlimited
+    pub max_depth: Option<i64>,
+    /// Silent lease expirations before a message is quarantined; `None`
+    /// never quarantines
+    pub max_lease_expirations: Option<i32>,
+}
+
+/// Order in which a queue hands out its ready messages. Ties are broken by
+/// age, and strict FIFO queues always use enqueue order.
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Eq,
+    Serialize,
+    Deserialize,
+    clap::ValueEnum,
+    utoipa::ToSchema,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum QueueOrdering {
+    /// Oldest first, ignoring priority
+    Fifo,
+    /// Highest priority first
+    #[default]
+    Priority,
+    /// Lowest sort key first; messages without one come last
+    SortKey,
+}
+
+impl QueueOrdering {
+    pub fn name(self) -> &'static str {
+        match self {
+            QueueOrdering::Fifo => "fifo",
+            QueueOrdering::Priority => "priority",
+            QueueOrdering::SortKey => "sort_key",
+        }
+    }
+}
+
+/// What enqueues do with a payload that breaks its queue's schema
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Eq,
+    Serialize,
+    Deserialize,
+    clap::ValueEnum,
+    utoipa::ToSchema,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum SchemaMode {
+    /// Refuse the enqueue
+    #[default]
+    Reject,
+    /// Accept the message, naming the violations in its schema-violations
+    /// header
+    Tag,
+}
+
+impl SchemaMode {
+    pub fn name(self) -> &'static str {
+        match self {
+            SchemaMode::Reject => "reject",
+            SchemaMode::Tag => "tag",
+        }
+    }
+}
+
+/// Header listing the schema violations of a payload a `tag` queue accepted
+pub const SCHEMA_VIOLATIONS_HEADER: &str = "schema-violations";
+/// Header with the version of the schema a tagged payload broke
+pub const SCHEMA_VERSION_HEADER: &str = "schema-version";
+
+impl Default for QueueOptions {
+    fn default() -> Self {
+        QueueOptions {
+            max_attempts: 5,
+            dedup_window_ms: db::DEFAULT_DEDUP_WINDOW_MS,
+            retention_days: None,
+            backoff_base_ms: None,
+            backoff_multiplier: 2.0,
+            backoff_max_ms: None,
+            backoff_jitter: 0.0,
+            default_visibility_ms: db::DEFAULT_VISIBILITY_MS,
+            default_delay_ms: 0,
+            max_deliveries_per_second: None,
+            max_payload_bytes: None,
+            payload_schema: None,
+            schema_mode: SchemaMode::default(),
+            strict_fifo: false,
+            fair: false,
+            ordering: QueueOrdering::default(),
+            max_depth: None,
+            max_lease_expirations: None,
+        }
+    }
+}
+
+/// Create a new queue, return the created Queue
+#[tracing::instrument(level = "debug", skip_all, fields(queue = %name))]
+pub async fn create_queue(
+    db: &Db,
+    name: &str,
+    max_attempts: i32,
+) -> Result<Queue> {
+    let opts = QueueOptions { max_attempts, ..QueueOptions::default() };
+    create_queue_with(db, name, &opts).await
+}
+
+/// Create a new queue with explicit options, return the created Queue
+#[tracing::instrument(level = "debug", skip_all, fields(queue = %name))]
+pub async fn create_queue_with(
+    db: &Db,
+    name: &str,
+    opts: &QueueOptions,
+) -> Result<Queue> {
+    if db.get_queue_by_name(name).await?.is_some() {
+        return Err(SqewError::QueueExists(name.to_string()));
+    }
+    if list_trash(db).await?.iter().any(|q| q.name == name) {
+        return Err(SqewError::QueueTrashed(name.to_string()));
+    }
+    let q = Queue {
+        id: 0,
+        name: name.to_string(),
+        max_attempts: opts.max_attempts,
+        dedup_window_ms: opts.dedup_window_ms.max(0),
+        retention_days: opts.retention_days.map(|d| d.max(0)),
+        backoff_base_ms: opts.backoff_base_ms.map(|ms| ms.max(0)),
+        backoff_multiplier: opts.backoff_multiplier.max(1.0),
+        backoff_max_ms: opts.backoff_max_ms.map(|ms| ms.max(0)),
+        backoff_jitter: opts.backoff_jitter.clamp(0.0, 1.0),
+        default_visibility_ms: opts.default_visibility_ms.max(0),
+        default_delay_ms: opts.default_delay_ms.max(0),
+        max_deliveries_per_second: opts
+            .max_deliveries_per_second
+            .filter(|r| *r > 0.0 && r.is_finite()),
+        max_payload_bytes: opts.max_payload_bytes.filter(|n| *n > 0),
+        payload_schema: opts.payload_schema.clone(),
+        schema_mode: opts.schema_mode.name().into(),
+        payload_schema_version: 0,
+        paused: false,
+        strict_fifo: opts.strict_fifo,
+        fair: opts.fair,
+        ordering: opts.ordering.name().into(),
+        max_depth: opts.max_depth,
+        max_lease_expirations: opts.max_lease_expirations,
+        deleted_at: None,
+    };
+    validate_schema(q.payload_schema.as_ref())?;
+    let id = db.create_queue(&q).await.context("Failed to create queue")?;
+    if q.payload_schema.is_some() {
+        record_schema(db, id, q.payload_schema.as_ref()).await?;
+    }
+    let q = db
+        .get_queue_by_name(name)
+        .await
+        .context("Failed to fetch created queue")?
+        .ok_or_else(|| SqewError::QueueNotFound(name.to_string()))?;
+    Ok(q)
+}
+
+/// Changes to a queue's settings; `None` fields are left as they are. The
+/// nullable settings take `Some(None)` to switch the feature off (JSON `null`).
+#[derive(Debug, Clone, Default, serde::Deserialize, utoipa::ToSchema)]
+pub struct QueueUpdate {
+    pub max_attempts: Option<i32>,
+    pub dedup_window_ms: Option<i64>,
+    #[serde(default, deserialize_with = "explicit_null")]
+    #[schema(value_type = Option<i32>)]
+    pub retention_days: Option<Option<i32>>,
+    #[serde(default, deserialize_with = "explicit_null")]
+    #[schema(value_type = Option<i64>)]
+    pub backoff_base_ms: Option<Option<i64>>,
+    pub backoff_multiplier: Option<f64>,
+    #[serde(default, deserialize_with = "explicit_null")]
+    #[schema(value_type = Option<i64>)]
+    pub backoff_max_ms: Option<Option<i64>>,
+    pub backoff_jitter: Option<f64>,
+    pub default_visibility_ms: Option<i64>,
+    pub default_delay_ms: Option<i64>,
+    #[serde(default, deserialize_with = "explicit_null")]
+    #[schema(value_type = Option<f64>)]
+    pub max_deliveries_per_second: Option<Option<f64>>,
+    #[serde(default, deserialize_with = "explicit_null")]
+    #[schema(value_type = Option<i64>)]
+    pub max_payload_bytes: Option<Option<i64>>,
+    #[serde(default, deserialize_with = "explicit_null")]
+    #[schema(value_type = Option<Object>)]
+    pub payload_schema: Option<Option<Value>>,
+    pub schema_mode: Option<SchemaMode>,
+    pub paused: Option<bool>,
+    pub strict_fifo: Option<bool>,
+    pub fair: Option<bool>,
+    pub ordering: Option<QueueOrdering>,
+    #[serde(default, deserialize_with = "explicit_null")]
+    #[schema(value_type = Option<i64>)]
+    pub max_depth: Option<Option<i64>>,
+    #[serde(default, deserialize_with = "explicit_null")]
+    #[schema(value_type = Option<i32>)]
+    pub max_lease_expirations: Option<Option<i32>>,
+}
+
+// Distinguish a field set to `null` (`Some(None)`) from one left out (`None`)
+fn explicit_null<'de, D, T>(d: D) -> Result<Option<Option<T>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: serde::Deserialize<'de>,
+{
+    <Option<T> as serde::Deserialize>::deserialize(d).map(Some)
+}
+
+/// Apply `update` to a queue's settings in place and return the updated
+/// queue. Invalid values are rejected and nothing is changed.
+#[tracing::instrument(level = "debug", skip_all, fields(queue = %name))]
+pub async fn update_queue(
+    db: &Db,
+    name: &str,
+    update: &QueueUpdate,
+) -> Result<Queue> {
+    let mut q = show_queue(db, name).await?;
+    let schema_changed = update
+        .payload_schema
+        .as_ref()
+        .is_some_and(|schema| *schema != q.payload_schema);
+    if let Some(n) = update.max_attempts {
+        q.max_attempts = n;
+    }
+    if let Some(ms) = update.dedup_window_ms {
+        q.dedup_window_ms = ms;
+    }
+    if let Some(days) = update.retention_days {
+        q.retention_days = days;
+    }
+    if let Some(ms) = update.backoff_base_ms {
+        q.backoff_base_ms = ms;
+    }
+    if let Some(x) = update.backoff_multiplier {
+        q.backoff_multiplier = x;
+    }
+    if let Some(ms) = update.backoff_max_ms {
+        q.backoff_max_ms = ms;
+    }
+    if let Some(j) = update.backoff_jitter {
+        q.backoff_jitter = j;
+    }
+    if let Some(ms) = update.default_visibility_ms {
+        q.default_visibility_ms = ms;
+    }
+    if let Some(ms) = update.default_delay_ms {
+        q.default_delay_ms = ms;
+    }
+    if let Some(rate) = update.max_deliveries_per_second {
+        q.max_deliveries_per_second = rate;
+    }
+    if let Some(bytes) = update.max_payload_bytes {
+        q.max_payload_bytes = bytes;
+    }
+    if let Some(schema) = &update.payload_schema {
+        q.payload_schema = schema.clone();
+    }
+    if let Some(mode) = update.schema_mode {
+        q.schema_mode = mode.name().into();
+    }
+    if let Some(paused) = update.paused {
+        q.paused = paused;
+    }
+    if let Some(strict) = update.strict_fifo {
+        q.strict_fifo = strict;
+    }
+    if let Some(fair) = update.fair {
+        q.fair = fair;
+    }
+    if let Some(ordering) = update.ordering {
+        q.ordering = ordering.name().into();
+    }
+    if let Some(depth) = update.max_depth {
+        q.max_depth = depth;
+    }
+    if let Some(n) = update.max_lease_expirations {
+        q.max_lease_expirations = n;
+    }
+    validate_queue(&q)?;
+    if schema_changed {
+        db.update_queue_and_schema(&q, db::now_ms())
+            .await
+            .context("Failed to update queue")?;
+    } else {
+        db.update_queue(&q).await.context("Failed to update queue")?;
+    }
+    show_queue(db, name).await
+}
+
+// Record a queue's new payload schema as its next version
+async fn record_schema(
+    db: &Db,
+    queue_id: i64,
+    schema: Option<&Value>,
+) -> Result<i64> {
+    db.record_queue_schema(queue_id, schema, db::now_ms())
+        .await
+        .context("Failed to record schema version")
+}
+
+/// Replace a queue's payload schema, recording it as a new version, and
+/// with `mode` change what enqueues do with payloads that break it
+#[tracing::instrument(level = "debug", skip_all, fields(queue = %name))]
+pub async fn set_queue_schema(
+    db: &Db,
+    name: &str,
+    schema: Value,
+    mode: Option<SchemaMode>,
+) -> Result<Queue> {
+    let update = QueueUpdate {
+        payload_schema: Some(Some(schema)),
+        schema_mode: mode,
+        ..QueueUpdate::default()
+    };
+    update_queue(db, name, &update).await
+}
+
+/// A version of a queue's payload schema, the current one for `None`.
+/// Fails with [`SqewError::SchemaNotFound`] if the queue never had a
+/// schema or not that version.
+pub async fn queue_schema(
+    db: &Db,
+    name: &str,
+    version: Option<i64>,
+) -> Result<QueueSchema> {
+    let q = show_queue(db, name).await?;
+    db.get_queue_schema(q.id, version)
+        .await
+        .context("Failed to read queue schema")?
+        .ok_or_else(|| SqewError::SchemaNotFound {
+            queue: name.to_string(),
+            version,
+        })
+}
+
+/// Every version of a queue's payload schema, newest first
+pub async fn queue_schema_history(
+    db: &Db,
+    name: &str,
+) -> Result<Vec<QueueSchema>> {
+    let q = show_queue(db, name).await?;
+    db.list_queue_schemas(q.id)
+        .await
+        .context("Failed to list queue schema versions")
+}
+
+/// Pause or resume a queue. Polls of a paused queue lease nothing; enqueues
+/// are still accepted.
+pub async fn set_paused(
+    db: &Db,
+    name: &str,
+    paused: bool,
+) -> Result<Queue> {
+    let update = QueueUpdate { paused: Some(paused), ..QueueUpdate::default() };
+    update_queue(db, name, &update).await
+}
+
+// Reject settings a queue cannot operate with
+fn validate_queue(q: &Queue) -> Result<()> {
+    let invalid =
+        |what: &str| Err(SqewError::Invalid(format!("queue setting: {what}")));
+    if q.max_attempts < 1 {
+        return invalid("max_attempts must be at least 1");
+    }
+    if q.dedup_window_ms < 0 {
+        return invalid("dedup_window_ms must not be negative");
+    }
+    if q.retention_days.is_some_and(|d| d < 0) {
+        return invalid("retention_days must not be negative");
+    }
+    if q.backoff_base_ms.is_some_and(|ms| ms < 0)
+        || q.backoff_max_ms.is_some_and(|ms| ms < 0)
+    {
+        return invalid("backoff delays must not be negative");
+    }
+    if q.backoff_multiplier.is_nan() || q.backoff_multiplier < 1.0 {
+        return invalid("backoff_multiplier must be at least 1");
+    }
+    if !(0.0..=1.0).contains(&q.backoff_jitter) {
+        return invalid("backoff_jitter must be between 0 and 1");
+    }
+    if q.default_visibility_ms < 1 {
+        return invalid("default_visibility_ms must be positive");
+    }
+    if q.default_delay_ms < 0 {
+        return invalid("default_delay_ms must not be negative");
+    }
+    if q.max_deliveries_per_second.is_some_and(|r| !(r > 0.0 && r.is_finite()))
+    {
+        return invalid("max_deliveries_per_second must be positive");
+    }
+    if q.max_payload_bytes.is_some_and(|n| n < 1) {
+        return invalid("max_payload_bytes must be positive");
+    }
+    if q.max_depth.is_some_and(|n| n < 1) {
+        return invalid("max_depth must be positive");
+    }
+    if q.max_lease_expirations.is_some_and(|n| n < 1) {
+        return invalid("max_lease_expirations must be positive");
+    }
+    validate_schema(q.payload_schema.as_ref())
+}
+
+// Reject a payload schema that is not a valid JSON Schema
+fn validate_schema(schema: Option<&Value>) -> Result<()> {
+    match schema.map(jsonschema::validator_for) {
+        Some(Err(e)) => Err(SqewError::Invalid(format!(
+            "queue setting: payload_schema: {e}"
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// An enqueue refused because its payload breaks a size limit or the queue's
+/// JSON Schema. The HTTP API answers it with a structured error body.
+#[derive(Debug, thiserror::Error, serde::Serialize)]
+#[serde(tag = "error", rename_all = "snake_case")]
+pub enum PayloadRejected {
+    #[error("Payload of {size} bytes exceeds the limit of {limit} bytes")]
+    PayloadTooLarge { size: usize, limit: usize },
+    #[error("Payload does not match the queue's schema: {}", .violations.join("; "))]
+    SchemaViolation {
+        /// One entry per failed constraint, prefixed by its JSON pointer
+        violations: Vec<String>,
+    },
+}
+
+// Check a payload against its queue's size limit and schema. A `tag`
+// queue accepts a payload breaking its schema, tagging `msg` instead.
+fn check_payload(
+    q: &Queue,
+    payload: &Value,
+    msg: &mut Message,
+) -> Result<(), PayloadRejected> {
+    let size = msg.payload.len();
+    if let Some(limit) = q.max_payload_bytes
+        && size as i64 > limit
+    {
+        return Err(PayloadRejected::PayloadTooLarge {
+            size,
+            limit: limit as usize,
+        });
+    }
+    let Some(schema) = &q.payload_schema else {
+        return Ok(());
+    };
+    // Schemas are checked when set, so compiling cannot fail here unless
+    // the stored value was edited behind our back
+    let violations = match jsonschema::validator_for(schema) {
+        Ok(validator) => validator
+            .iter_errors(payload)
+            .map(|e| format!("{}: {e}", e.instance_path()))
+            .collect::<Vec<_>>(),
+        Err(e) => vec![format!("schema does not compile: {e}")],
+    };
+    if violations.is_empty() {
+        Ok(())
+    } else if q.schema_mode == SchemaMode::Tag.name() {
+        let headers = msg.headers.get_or_insert_default();
+        headers.insert(SCHEMA_VIOLATIONS_HEADER.into(), violations.join("; "));
+        headers.insert(
+            SCHEMA_VERSION_HEADER.into(),
+            q.payload_schema_version.to_string(),
+        );
+        Ok(())
+    } else {
+        Err(PayloadRejected::SchemaViolation { violations })
+    }
+}
+
+/// Create queue `target` with the settings of `source` and, with
+/// `with_messages`, copies of its live messages, all in one transaction.
+/// Returns the new queue and how many messages were copied.
+#[tracing::instrument(level = "debug", skip_all, fields(source = %source, target = %target))]
+pub async fn clone_queue(
+    db: &Db,
+    source: &str,
+    target: &str,
+    with_messages: bool,
+) -> Result<(Queue, u64)> {
+    show_queue(db, source).await?;
+    if db.get_queue_by_name(target).await?.is_some() {
+        return Err(SqewError::QueueExists(target.to_string()));
+    }
+    if list_trash(db).await?.iter().any(|q| q.name == target) {
+        return Err(SqewError::QueueTrashed(target.to_string()));
+    }
+    let (id, copied) = db
+        .clone_queue(source, target, with_messages)
+        .await
+        .context("Failed to clone queue")?
+        .ok_or_else(|| SqewError::QueueNotFound(source.to_string()))?;
+    let mut q = show_queue(db, target).await?;
+    // The copy's schema history starts over from its current schema
+    if q.payload_schema.is_some() {
+        record_schema(db, id, q.payload_schema.as_ref()).await?;
+        q = show_queue(db, target).await?;
+    }
+    tracing::debug!(copied, "cloned");
+    Ok((q, copied))
+}
+
+/// Delete a queue by name, with all its messages, for good; a queue in
+/// the trash can be deleted too. Returns true if a queue was deleted
+#[tracing::instrument(level = "debug", skip_all, fields(queue = %name))]
+pub async fn delete_queue(
+    db: &Db,
+    name: &str,
+) -> Result<bool> {
+    let deleted = db
+        .delete_queue_by_name(name)
+        .await
+        .context("Failed to delete queue")?;
+    Ok(deleted > 0)
+}
+
+/// How long a queue stays in the trash before the server purges it (7 days)
+pub const TRASH_RETENTION_MS: i64 = 7 * 86_400_000;
+
+/// Move a queue to the trash: it disappears from listings and refuses
+/// operations, but keeps its messages and can be brought back with
+/// [`restore_queue`] for [`TRASH_RETENTION_MS`]. Leases already handed
+/// out can still be acked and nacked. Returns true if a queue was trashed
+#[tracing::instrument(level = "debug", skip_all, fields(queue = %name))]
+pub async fn trash_queue(
+    db: &Db,
+    name: &str,
+) -> Result<bool> {
+    let trashed = db
+        .trash_queue(name, db::now_ms())
+        .await
+        .context("Failed to trash queue")?;
+    Ok(trashed > 0)
+}
+
+/// Take a queue out of the trash, with its messages and settings
+#[tracing::instrument(level = "debug", skip_all, fields(queue = %name))]
+pub async fn restore_queue(
+    db: &Db,
+    name: &str,
+) -> Result<Queue> {
+    let restored =
+        db.restore_queue(name).await.context("Failed to restore queue")?;
+    if restored == 0 {
+        return Err(SqewError::QueueNotFound(name.to_string()));
+    }
+    show_queue(db, name).await
+}
+
+/// The queues in the trash, longest trashed first
+#[tracing::instrument(level = "debug", skip_all)]
+pub async fn list_trash(db: &Db) -> Result<Vec<Queue>> {
+    db.list_trashed_queues().await.context("Failed to list trash")
+}
+
+/// Delete the queues trashed more than [`TRASH_RETENTION_MS`] ago, with
+/// their messages; returns their names
+#[tracing::instrument(level = "debug", skip_all)]
+pub async fn purge_trash(db: &Db) -> Result<Vec<String>> {
+    db.purge_trashed_queues(db::now_ms() - TRASH_RETENTION_MS)
+        .await
+        .context("Failed to purge trash")
 }
 
 /// Show a queue by name
+#[tracing::instrument(level = "debug", skip_all, fields(queue = %name))]
 pub async fn show_queue(
-    pool: &SqlitePool,
+    db: &Db,
     name: &str,
 ) -> Result<Queue> {
-    let q = db::get_queue_by_name(pool, name)
+    let q = db
+        .get_queue_by_name(name)
         .await
         .context("Failed to fetch queue")?
-        .ok_or_else(|| anyhow!("Queue '{}' not found", name))?;
+        .ok_or_else(|| SqewError::QueueNotFound(name.to_string()))?;
     Ok(q)
 }
 
-/// Purge all messages from a queue, return count
+/// Messages [`purge_queue`] deletes per transaction by default
+pub const PURGE_BATCH: usize = 10_000;
+
+/// Pause between purge batches, letting other writers at the database
+const PURGE_BATCH_PAUSE: Duration = Duration::from_millis(5);
+
+/// Purge all live messages from a queue, in transactions of
+/// [`PURGE_BATCH`] messages; return count
 pub async fn purge_queue(
-    pool: &SqlitePool,
+    db: &Db,
     name: &str,
 ) -> Result<u64> {
-    let deleted = db::purge_messages_by_queue(pool, name)
+    purge_queue_batched(db, name, PURGE_BATCH, |_| {}).await
+}
+
+/// Purge all live messages from a queue, deleting `batch_size` per
+/// transaction so a large queue never holds the write lock for long.
+/// `on_batch` is called with the running total after each commit. On an
+/// error the batches already committed stay deleted.
+#[tracing::instrument(level = "debug", skip_all, fields(queue = %name, batch_size))]
+pub async fn purge_queue_batched(
+    db: &Db,
+    name: &str,
+    batch_size: usize,
+    mut on_batch: impl FnMut(u64),
+) -> Result<u64> {
+    if batch_size == 0 {
+        return Err(SqewError::Invalid(
+            "batch size 0: must be positive".into(),
+        ));
+    }
+    show_queue(db, name).await?;
+    let mut deleted = 0;
+    loop {
+        let n = db
+            .purge_messages_by_queue(name, batch_size as i64)
+            .await
+            .with_context(|| {
+                format!("Failed to purge messages after deleting {deleted}")
+            })?;
+        deleted += n;
+        if n > 0 {
+            on_batch(deleted);
+        }
+        if n < batch_size as u64 {
+            return Ok(deleted);
+        }
+        tokio::time::sleep(PURGE_BATCH_PAUSE).await;
+    }
+}
+
+/// Delete the live messages of a queue matching `filter`, `batch_size` per
+/// transaction, as [`purge_queue_batched`] does; dead letters are kept.
+/// A filter without conditions is refused rather than purging the queue.
+#[tracing::instrument(level = "debug", skip_all, fields(queue = %name, batch_size))]
+pub async fn remove_messages_matching(
+    db: &Db,
+    name: &str,
+    filter: &RemoveFilter,
+    batch_size: usize,
+    mut on_batch: impl FnMut(u64),
+) -> Result<u64> {
+    if filter.json_path.is_none() && filter.created_before.is_none() {
+        return Err(SqewError::Invalid(
+            "remove filter: give a JSON path predicate or an age; purge the queue to delete everything".into(),
+        ));
+    }
+    if batch_size == 0 {
+        return Err(SqewError::Invalid(
+            "batch size 0: must be positive".into(),
+        ));
+    }
+    show_queue(db, name).await?;
+    let mut deleted = 0;
+    loop {
+        let n = db
+            .remove_matching_messages(name, filter, batch_size as i64)
+            .await
+            .with_context(|| {
+                format!("Failed to remove messages after deleting {deleted}")
+            })?;
+        deleted += n;
+        if n > 0 {
+            on_batch(deleted);
+        }
+        if n < batch_size as u64 {
+            return Ok(deleted);
+        }
+        tokio::time::sleep(PURGE_BATCH_PAUSE).await;
+    }
+}
+
+/// Build the [`RemoveFilter`] of a JSON path predicate such as
+/// `$.type == "spam"` (or `$.type=spam`, as [`parse_json_filter`] reads it)
+/// and an age such as `7d` (see [`parse_window`]): messages created more
+/// than that long before `now`
+pub fn remove_filter(
+    predicate: Option<&str>,
+    older_than: Option<&str>,
+    now: i64,
+) -> Result<RemoveFilter> {
+    Ok(RemoveFilter {
+        json_path: predicate.map(parse_json_predicate).transpose()?,
+        created_before: older_than
+            .map(|age| parse_window(age).map(|ms| now - ms))
+            .transpose()?,
+    })
+}
+
+/// Parse a `path == value` JSON payload predicate, e.g. `$.type == "spam"`
+/// or `$.user.id == 42`, into the path and the text the value must equal.
+/// A JSON string stands for its contents; other values are taken as they
+/// are written. `path=value` is read as [`parse_json_filter`] reads it.
+pub fn parse_json_predicate(s: &str) -> Result<(String, String)> {
+    let Some((path, value)) = s.split_once("==") else {
+        return parse_json_filter(s);
+    };
+    let (path, value) = (path.trim(), value.trim());
+    if !path.starts_with('$') || value.is_empty() {
+        return Err(SqewError::Invalid(format!(
+            "JSON predicate '{s}': expected $.path == value"
+        )));
+    }
+    let value = match serde_json::from_str(value) {
+        Ok(Value::String(text)) => text,
+        _ => value.to_string(),
+    };
+    Ok((path.to_string(), value))
+}
+
+/// Most messages [`sample_messages`] returns at once
+pub const MAX_SAMPLE: i64 = 100;
+
+/// A random sample of up to `n` ready messages, without leasing them, e.g.
+/// for a dashboard to show representative payloads rather than the head of
+/// the queue
+#[tracing::instrument(level = "debug", skip_all, fields(queue = %name, n))]
+pub async fn sample_messages(
+    db: &Db,
+    name: &str,
+    n: i64,
+) -> Result<Vec<Message>> {
+    if !(1..=MAX_SAMPLE).contains(&n) {
+        return Err(SqewError::Invalid(format!(
+            "n {}: must be between 1 and {}",
+            n, MAX_SAMPLE
+        )));
+    }
+    let q = show_queue(db, name).await?;
+    db.sample_messages(q.id, n, db::now_ms())
         .await
-        .context("Failed to purge messages")?;
-    Ok(deleted)
+        .context("Failed to sample messages")
 }
 
 /// Peek messages without leasing
+#[tracing::instrument(level = "debug", skip_all, fields(queue = %name, limit))]
 pub async fn peek_queue(
-    pool: &SqlitePool,
+    db: &Db,
+    name: &str,
+    limit: i64,
+) -> Result<Vec<Message>> {
+    peek_queue_with(db, name, limit, None).await
+}
+
+/// Peek messages without leasing, optionally only those whose header `key`
+/// equals `value`
+#[tracing::instrument(level = "debug", skip_all, fields(queue = %name, limit))]
+pub async fn peek_queue_with(
+    db: &Db,
+    name: &str,
+    limit: i64,
+    header: Option<(&str, &str)>,
+) -> Result<Vec<Message>> {
+    let filter = PeekFilter {
+        header: header.map(|(k, v)| (k.to_string(), v.to_string())),
+        ..PeekFilter::default()
+    };
+    peek_queue_filtered(db, name, limit, &filter).await
+}
+
+/// Peek messages without leasing, paged and narrowed by `filter`
+#[tracing::instrument(level = "debug", skip_all, fields(queue = %name, limit))]
+pub async fn peek_queue_filtered(
+    db: &Db,
     name: &str,
     limit: i64,
+    filter: &PeekFilter,
 ) -> Result<Vec<Message>> {
-    let msgs = db::peek_messages(pool, name, limit)
+    if let Some((path, _)) = &filter.json_path
+        && !path.starts_with('$')
+    {
+        return Err(SqewError::Invalid(format!(
+            "JSON path '{}': must start with '$'",
+            path
+        )));
+    }
+    show_queue(db, name).await?;
+    let msgs = db
+        .peek_messages(name, limit, filter)
         .await
         .context("Failed to peek messages")?;
     Ok(msgs)
 }
 
-/// Compact the database (VACUUM)
-pub async fn compact(pool: &SqlitePool) -> Result<()> {
-    db::compact_db(pool).await.context("Failed to compact database")
-}
-/// Statistics for a queue: ready, leased, dlq counts
-pub async fn stats(
-    pool: &SqlitePool,
+/// Search a queue's messages (leased, delayed and dead-lettered included) by
+/// payload contents: those with a value at JSON `path`, equal to `value` when
+/// given. Results are in id order; pass the last id back as `after_id` for the
+/// next page.
+#[tracing::instrument(level = "debug", skip_all, fields(queue = %name, limit))]
+pub async fn search_messages(
+    db: &Db,
     name: &str,
-) -> Result<serde_json::Value> {
-    // Get queue
-    let q = show_queue(pool, name).await?;
-    // Current time in ms
-    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as i64;
-    // Counts
-    let ready = db::count_ready_messages(pool, q.id, now)
+    path: &str,
+    value: Option<&str>,
+    after_id: Option<i64>,
+    limit: i64,
+) -> Result<Vec<Message>> {
+    // Both backends reject malformed paths only when evaluating them, so
+    // catch them here rather than as an opaque storage error
+    validate_json_path(path)?;
+    show_queue(db, name).await?;
+    db.search_messages(name, path, value, after_id, limit.max(1))
         .await
-        .context("Failed to count ready messages")?;
-    Ok(serde_json::json!({ "ready": ready}))
+        .context("Failed to search messages")
 }
 
-use std::time::{SystemTime, UNIX_EPOCH};
+/// Check that `path` is a JSON path both backends read alike: `$` followed
+/// by `.key`, `."quoted key"` and `[index]` steps, e.g. `$.items[0].sku`
+pub fn validate_json_path(path: &str) -> Result<()> {
+    let invalid = |why: &str| {
+        SqewError::Invalid(format!("JSON path '{}': {}", path, why))
+    };
+    let mut rest =
+        path.strip_prefix('$').ok_or_else(|| invalid("must start with '$'"))?;
+    while !rest.is_empty() {
+        if let Some(quoted) = rest.strip_prefix(".\"") {
+            let end = quoted
+                .find(['"', '\\'])
+                .filter(|&i| quoted[i..].starts_with('"'))
+                .ok_or_else(|| invalid("unterminated quoted key"))?;
+            rest = &quoted[end + 1..];
+        } else if let Some(key) = rest.strip_prefix('.') {
+            let end = key
+                .find(|c: char| !(c.is_alphanumeric() || c == '_'))
+                .unwrap_or(key.len());
+            if end == 0 || key.starts_with(|c: char| c.is_ascii_digit()) {
+                return Err(invalid("expected a key after '.'"));
+            }
+            rest = &key[end..];
+        } else if let Some(index) = rest.strip_prefix('[') {
+            let end = index
+                .find(']')
+                .filter(|&i| {
+                    i > 0 && index[..i].bytes().all(|b| b.is_ascii_digit())
+                })
+                .ok_or_else(|| invalid("expected an array index like [0]"))?;
+            rest = &index[end + 1..];
+        } else {
+            return Err(invalid(
+                "expected .key, .\"quoted key\" or [index] steps",
+            ));
+        }
+    }
+    Ok(())
+}
 
-/// Configuration for queue/database setup
-#[derive(Debug, Clone)]
-pub struct Config {
-    pub db_path: PathBuf,
-    pub force_recreate: bool,
+/// Parse a `key=value` header argument
+pub fn parse_header(s: &str) -> Result<(String, String)> {
+    let (key, value) = s.split_once('=').ok_or_else(|| {
+        SqewError::Invalid(format!("header '{}': expected key=value", s))
+    })?;
+    if key.is_empty() {
+        return Err(SqewError::Invalid(format!("header '{}': empty key", s)));
+    }
+    Ok((key.to_string(), value.to_string()))
 }
 
-impl Default for Config {
-    fn default() -> Self {
-        let cwd =
-            std::env::current_dir().unwrap_or_else(|_| PathBuf::from("."));
-        Self { db_path: cwd.join("sqew.db"), force_recreate: false }
+/// Parse an `id:ms` nack argument: a message ID or public id and its
+/// requeue delay
+pub fn parse_nack_delay(s: &str) -> Result<(MessageRef, i64)> {
+    let invalid =
+        || SqewError::Invalid(format!("nack delay '{}': expected ID:MS", s));
+    let (id, ms) = s.split_once(':').ok_or_else(invalid)?;
+    let id = id.trim().parse().map_err(|_| invalid())?;
+    let ms: i64 = ms.trim().parse().map_err(|_| invalid())?;
+    if ms < 0 {
+        return Err(SqewError::Invalid(format!(
+            "nack delay '{}': negative delay",
+            s
+        )));
     }
+    Ok((id, ms))
 }
 
-/// Enqueue a message into a queue by name
-pub async fn enqueue_message(
-    pool: &sqlx::SqlitePool,
-    queue_name: &str,
-    payload: &Value,
-    delay_ms: i64,
-) -> Result<Message> {
-    let q = db::get_queue_by_name(pool, queue_name)
-        .await?
-        .ok_or_else(|| anyhow!("Queue '{}' not found", queue_name))?;
-    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as i64;
-    let msg = Message {
-        id: 0,
-        queue_id: q.id,
-        payload: payload.to_string(),
-        attempts: 0,
-        available_at: now + delay_ms.max(0),
-        created_at: now,
-    };
-    let id = db::enqueue_message(pool, &msg)
-        .await
-        .context("Failed to enqueue message")?;
-    let created = db::get_message_by_id(pool, id)
-        .await
-        .context("Failed to fetch enqueued message")?
-        .ok_or_else(|| anyhow!("Message not found after enqueue"))?;
-    Ok(created)
+/// Parse a `path=value` JSON payload filter, e.g. `$.user.id=42`
+pub fn parse_json_filter(s: &str) -> Result<(String, String)> {
+    let (path, value) = s.split_once('=').ok_or_else(|| {
+        SqewError::Invalid(format!("JSON filter '{}': expected path=value", s))
+    })?;
+    if !path.starts_with('$') {
+        return Err(SqewError::Invalid(format!(
+            "JSON filter '{}': path must start with '$'",
+            s
+        )));
+    }
+    Ok((path.to_string(), value.to_string()))
 }
 
-/// Fetch a message by id
-pub async fn get_message_by_id(
-    pool: &sqlx::SqlitePool,
-    id: i64,
-) -> Result<Message> {
-    db::get_message_by_id(pool, id)
-        .await
-        .context("Failed to fetch message")?
-        .ok_or_else(|| anyhow!("Message '{}' not found", id))
+/// Compact the database (VACUUM)
+#[tracing::instrument(level = "debug", skip_all)]
+pub async fn compact(db: &Db) -> Result<()> {
+    db.compact().await.context("Failed to compact database")
 }
 
-/// Poll (lease) up to `limit` visible messages; set visibility to now + visibility_ms
-pub async fn poll_messages(
-    pool: &sqlx::SqlitePool,
-    queue_name: &str,
-    limit: i64,
-    visibility_ms: i64,
-) -> Result<Vec<Message>> {
-    let msgs = db::poll_messages(pool, queue_name, limit, visibility_ms)
+/// Compress stored payloads above the compression threshold that are still
+/// stored plain, returning how many were compressed
+#[tracing::instrument(level = "debug", skip_all)]
+pub async fn recompress_payloads(db: &Db) -> Result<u64> {
+    db.recompress_payloads().await.context("Failed to recompress payloads")
+}
+
+/// Re-encrypt every stored payload not yet encrypted with the active
+/// encryption key, returning how many were rewritten. Run after putting a
+/// new key first in the keyring, or after enabling encryption.
+#[tracing::instrument(level = "debug", skip_all)]
+pub async fn rotate_key(db: &Db) -> Result<u64> {
+    db.rotate_payload_key().await.context("Failed to rotate encryption key")
+}
+
+/// Check the database for corruption, orphaned rows, stuck leases and
+/// impossible timestamps, repairing all but corruption when `fix` is set
+#[tracing::instrument(level = "debug", skip_all, fields(fix))]
+pub async fn doctor(
+    db: &Db,
+    fix: bool,
+) -> Result<DoctorReport> {
+    let now = db::now_ms();
+    db.doctor(fix, now).await.context("Failed to check database")
+}
+
+/// Look for anomalies a crash may have left behind (see
+/// [`RecoveryReport`]), repairing what can be repaired when `repair` is set
+#[tracing::instrument(level = "debug", skip_all, fields(repair))]
+pub async fn recovery_scan(
+    db: &Db,
+    repair: bool,
+) -> Result<RecoveryReport> {
+    db.recovery_scan(repair, db::now_ms())
         .await
-        .context("Failed to poll messages")?;
-    Ok(msgs)
+        .context("Failed to scan database for recovery")
 }
 
-/// Ack (delete) messages by IDs; returns how many were deleted
-pub async fn ack_messages(
-    pool: &sqlx::SqlitePool,
-    ids: &[i64],
-) -> Result<u64> {
-    let n =
-        db::ack_messages(pool, ids).await.context("Failed to ack messages")?;
-    Ok(n)
+/// Free space from which [`db_status`] recommends compacting, provided it
+/// is also at least a fifth of the database
+pub const COMPACT_MIN_FREE_BYTES: i64 = 1024 * 1024;
+
+/// Report the size of the database and the space free in it, the rows of
+/// each queue and roughly how many bytes they take, and whether compacting
+/// would be worthwhile
+#[tracing::instrument(level = "debug", skip_all)]
+pub async fn db_status(db: &Db) -> Result<DbStatus> {
+    let mut status =
+        db.db_status().await.context("Failed to read database status")?;
+    // Share out each table's bytes by the rows each queue has in it
+    let table_bytes = |name: &str| {
+        status.tables.iter().find(|t| t.name == name).map_or(0, |t| t.bytes)
+    };
+    let (message_bytes, archive_bytes) =
+        (table_bytes("message"), table_bytes("message_archive"));
+    let rows: i64 =
+        status.queues.iter().map(|q| q.messages + q.dead_letters).sum();
+    let archived: i64 = status.queues.iter().map(|q| q.archived).sum();
+    for q in &mut status.queues {
+        let share = |part: i64, total: i64, bytes: i64| {
+            if total == 0 {
+                0
+            } else {
+                (bytes as i128 * part as i128 / total as i128) as i64
+            }
+        };
+        q.estimated_bytes =
+            share(q.messages + q.dead_letters, rows, message_bytes)
+                + share(q.archived, archived, archive_bytes);
+    }
+    let free_bytes = status.free_pages * status.page_size;
+    status.compact_recommended = free_bytes >= COMPACT_MIN_FREE_BYTES
+        && status.free_pages * 5 >= status.page_count;
+    Ok(status)
+}
+
+/// How automatic compaction reclaims free pages
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Eq,
+    Serialize,
+    Deserialize,
+    clap::ValueEnum,
+    utoipa::ToSchema,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum CompactMode {
+    /// Hand free pages back without rebuilding the database (SQLite's
+    /// incremental vacuum)
+    #[default]
+    Incremental,
+    /// Rebuild the whole database, as `queue compact` does
+    Full,
+}
+
+impl CompactMode {
+    pub fn name(self) -> &'static str {
+        match self {
+            CompactMode::Incremental => "incremental",
+            CompactMode::Full => "full",
+        }
+    }
+}
+
+/// `[auto_compact]`: compact the database by itself, off-peak, once enough
+/// pages are free
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AutoCompactConfig {
+    /// Cron expression (UTC) of the minutes compaction may start in, e.g.
+    /// `* 2-4 * * *` for 02:00 to 04:59
+    pub window: String,
+    #[serde(default)]
+    pub mode: CompactMode,
+    /// Free pages from which compacting is worthwhile
+    #[serde(default = "default_min_free_pages")]
+    pub min_free_pages: i64,
+}
+
+fn default_min_free_pages() -> i64 {
+    256
+}
+
+impl AutoCompactConfig {
+    /// Reject a window that is not a cron expression
+    pub fn validate(&self) -> Result<()> {
+        parse_cron(&self.window).map(|_| ())
+    }
+
+    /// Whether `at_ms` falls in a minute of the window
+    pub fn in_window(
+        &self,
+        at_ms: i64,
+    ) -> bool {
+        let minute = at_ms - at_ms.rem_euclid(60_000);
+        parse_cron(&self.window)
+            .ok()
+            .and_then(|c| next_cron_run(&c, minute - 1))
+            .is_some_and(|next| next < minute + 60_000)
+    }
+}
+
+/// Compact the database as `cfg` says if `now_ms` is in its window and at
+/// least `min_free_pages` pages are free. Returns the run, or `None` when
+/// there was nothing to do.
+#[tracing::instrument(level = "debug", skip_all, fields(mode = cfg.mode.name()))]
+pub async fn auto_compact(
+    db: &Db,
+    cfg: &AutoCompactConfig,
+    now_ms: i64,
+) -> Result<Option<CompactRun>> {
+    if !cfg.in_window(now_ms) {
+        return Ok(None);
+    }
+    let before = db_status(db).await?.free_pages;
+    if before < cfg.min_free_pages.max(1) {
+        return Ok(None);
+    }
+    let started = std::time::Instant::now();
+    match cfg.mode {
+        CompactMode::Incremental => db.compact_incremental().await,
+        CompactMode::Full => db.compact().await,
+    }
+    .context("Failed to compact database")?;
+    let after = db_status(db).await?.free_pages;
+    Ok(Some(CompactRun {
+        mode: cfg.mode.name().into(),
+        started_at: now_ms,
+        duration_ms: started.elapsed().as_millis() as i64,
+        free_pages_before: before,
+        free_pages_after: after,
+    }))
+}
+
+/// Write a consistent snapshot of the database to `path`, which must not
+/// exist yet, while it stays online. Returns the size of the backup in bytes.
+#[tracing::instrument(level = "debug", skip_all, fields(path = %path.display()))]
+pub async fn backup_database(
+    db: &Db,
+    path: &Path,
+) -> Result<u64> {
+    if path.exists() {
+        return Err(SqewError::BackupExists(path.to_path_buf()));
+    }
+    db.backup(path).await.context("Failed to back up database")?;
+    let meta = std::fs::metadata(path).with_context(|| {
+        format!("Failed to read backup at {}", path.display())
+    })?;
+    Ok(meta.len())
+}
+
+/// Replace the SQLite database selected by `cfg` with the backup at
+/// `backup` and bring its schema up to date, returning the schema version.
+/// Nothing else may be using the database while it is restored.
+pub async fn restore_database(
+    cfg: &Config,
+    backup: &Path,
+) -> Result<i64> {
+    let Some(path) = sqlite_path(cfg)? else {
+        return Err(SqewError::Unsupported(
+            "Restoring is only supported for SQLite databases".into(),
+        ));
+    };
+    if db::sqlite::is_memory(&path) {
+        return Err(SqewError::Unsupported(
+            "Cannot restore into an in-memory database".into(),
+        ));
+    }
+    db::sqlite::restore_db_at(&path, backup).await?;
+    let cfg = Config { force_recreate: false, ..cfg.clone() };
+    let db = init_pool(&cfg).await?;
+    db.schema_version().await.context("Failed to read schema version")
+}
+
+/// Readiness of the database: `Ok` with the current and latest schema
+/// versions once a query succeeds, an error if it fails or takes longer
+/// than `timeout`
+pub async fn check_database(
+    db: &Db,
+    timeout: std::time::Duration,
+) -> Result<(i64, i64)> {
+    let version = tokio::time::timeout(timeout, db.schema_version())
+        .await
+        .map_err(|_| SqewError::Timeout(timeout))?
+        .context("Database query failed")?;
+    Ok((version, db.latest_schema_version()))
+}
+
+/// List acked messages kept in a queue's archive, most recent first
+#[tracing::instrument(level = "debug", skip_all, fields(queue = %name, limit))]
+pub async fn message_history(
+    db: &Db,
+    name: &str,
+    limit: i64,
+) -> Result<Vec<ArchivedMessage>> {
+    show_queue(db, name).await?;
+    db.list_archived_messages(name, limit)
+        .await
+        .context("Failed to list archived messages")
+}
+
+/// Re-enqueue archived messages of a queue acked in `[from, to)` (ms since
+/// epoch, either bound optional), optionally only those whose payload
+/// contains `contains`. They come back as new messages with fresh attempts;
+/// the archive keeps its copies. Returns how many were enqueued.
+#[tracing::instrument(level = "debug", skip_all, fields(queue = %name, from = ?from, to = ?to))]
+pub async fn replay_messages(
+    db: &Db,
+    name: &str,
+    from: Option<i64>,
+    to: Option<i64>,
+    contains: Option<&str>,
+) -> Result<u64> {
+    show_queue(db, name).await?;
+    if let (Some(from), Some(to)) = (from, to)
+        && from > to
+    {
+        return Err(SqewError::Invalid(
+            "replay range: --from is after --to".into(),
+        ));
+    }
+    db.replay_archived_messages(name, from, to, contains)
+        .await
+        .context("Failed to replay archived messages")
+}
+
+/// Delete archived messages whose retention period has ended, and attempt
+/// logs older than [`ATTEMPT_LOG_RETENTION_MS`]; returns how many archived
+/// messages were deleted
+#[tracing::instrument(level = "debug", skip_all)]
+pub async fn purge_archives(db: &Db) -> Result<u64> {
+    let now = db::now_ms();
+    db.purge_attempts(now - ATTEMPT_LOG_RETENTION_MS)
+        .await
+        .context("Failed to purge attempt logs")?;
+    db.purge_archived_messages(now)
+        .await
+        .context("Failed to purge archived messages")
+}
+
+/// List dead-lettered messages in a queue
+#[tracing::instrument(level = "debug", skip_all, fields(queue = %name, limit))]
+pub async fn list_dead_letters(
+    db: &Db,
+    name: &str,
+    limit: i64,
+) -> Result<Vec<Message>> {
+    show_queue(db, name).await?;
+    db.list_dead_letters(name, limit)
+        .await
+        .context("Failed to list dead letters")
+}
+
+/// Requeue dead letters (all when `ids` is empty); returns how many moved
+#[tracing::instrument(level = "debug", skip_all, fields(queue = %name, ids = ?ids))]
+pub async fn redrive_dead_letters(
+    db: &Db,
+    name: &str,
+    ids: &[i64],
+) -> Result<u64> {
+    show_queue(db, name).await?;
+    db.redrive_dead_letters(name, ids)
+        .await
+        .context("Failed to redrive dead letters")
+}
+
+/// Move messages into `target` (optionally only those in `from`), reviving
+/// dead letters; returns how many moved. Actively leased messages stay put.
+#[tracing::instrument(level = "debug", skip_all, fields(ids = ?ids, to = %target, from = ?from))]
+pub async fn move_messages(
+    db: &Db,
+    ids: &[i64],
+    target: &str,
+    from: Option<&str>,
+    reset_attempts: bool,
+) -> Result<u64> {
+    show_queue(db, target).await?;
+    if let Some(from) = from {
+        show_queue(db, from).await?;
+    }
+    db.move_messages(ids, target, from, reset_attempts)
+        .await
+        .context("Failed to move messages")
+}
+
+/// Delete all dead letters in a queue, return count
+#[tracing::instrument(level = "debug", skip_all, fields(queue = %name))]
+pub async fn purge_dead_letters(
+    db: &Db,
+    name: &str,
+) -> Result<u64> {
+    show_queue(db, name).await?;
+    db.purge_dead_letters(name).await.context("Failed to purge dead letters")
+}
+
+// Messages read or written per page when exporting or importing a queue
+const EXPORT_BATCH: usize = 500;
+
+/// Write every message of a queue, including leased and dead-lettered ones,
+/// to `out` as newline-delimited JSON; returns how many were written.
+/// Leases are not exported.
+#[tracing::instrument(level = "debug", skip_all, fields(queue = %name))]
+pub async fn export_queue(
+    db: &Db,
+    name: &str,
+    out: &mut impl std::io::Write,
+) -> Result<u64> {
+    show_queue(db, name).await?;
+    let mut after_id = 0;
+    let mut written = 0;
+    loop {
+        let page = db
+            .export_messages(name, after_id, EXPORT_BATCH as i64)
+            .await
+            .context("Failed to export messages")?;
+        write_export(out, &page)?;
+        written += page.len() as u64;
+        match page.last() {
+            Some(last) if page.len() == EXPORT_BATCH => after_id = last.id,
+            _ => break,
+        }
+    }
+    out.flush().context("Failed to write export")?;
+    Ok(written)
+}
+
+// Write messages as export lines
+fn write_export(
+    out: &mut impl std::io::Write,
+    page: &[Message],
+) -> Result<()> {
+    for msg in page {
+        serde_json::to_writer(&mut *out, msg)
+            .map_err(std::io::Error::from)
+            .context("Failed to write export")?;
+        out.write_all(b"\n").context("Failed to write export")?;
+    }
+    Ok(())
+}
+
+/// Load messages written by [`export_queue`] into the queue `name`, keeping
+/// their attempts, timestamps, dead-letter state and headers. Messages get
+/// new ids and public ids; ones whose dedup key is already held in the
+/// queue are skipped. Returns `(imported, skipped)`.
+#[tracing::instrument(level = "debug", skip_all, fields(queue = %name))]
+pub async fn import_queue(
+    db: &Db,
+    name: &str,
+    input: impl std::io::BufRead,
+) -> Result<(u64, u64)> {
+    let q = show_queue(db, name).await?;
+    let mut batch = Vec::with_capacity(EXPORT_BATCH);
+    let (mut imported, mut read) = (0, 0);
+    for (n, line) in input.lines().enumerate() {
+        let line = line.context("Failed to read import")?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let mut msg: Message = serde_json::from_str(&line).map_err(|e| {
+            SqewError::Invalid(format!("export line {}: {}", n + 1, e))
+        })?;
+        if serde_json::from_str::<Value>(&msg.payload).is_err() {
+            return Err(SqewError::Invalid(format!(
+                "export line {}: payload is not JSON",
+                n + 1
+            )));
+        }
+        msg.queue_id = q.id;
+        msg.lease_token = None;
+        msg.public_id = db::new_public_id(msg.created_at);
+        batch.push(msg);
+        read += 1;
+        if batch.len() == EXPORT_BATCH {
+            imported += db
+                .import_messages(&batch)
+                .await
+                .context("Failed to import messages")?;
+            batch.clear();
+        }
+    }
+    if !batch.is_empty() {
+        imported += db
+            .import_messages(&batch)
+            .await
+            .context("Failed to import messages")?;
+    }
+    Ok((imported, read - imported))
+}
+
+/// Load messages exported in `format` into the queue `name`. sqew exports
+/// are read as [`import_queue`] does; SQS and RabbitMQ messages are enqueued
+/// ready now, with their attributes or properties as headers (see
+/// [`ImportFormat`]). Returns `(imported, skipped)`, skipping messages whose
+/// dedup key is already held in the queue.
+#[tracing::instrument(level = "debug", skip_all, fields(queue = %name))]
+pub async fn import_queue_as(
+    db: &Db,
+    name: &str,
+    format: ImportFormat,
+    mut input: impl std::io::BufRead,
+) -> Result<(u64, u64)> {
+    if format == ImportFormat::Sqew {
+        return import_queue(db, name, input).await;
+    }
+    let q = show_queue(db, name).await?;
+    let mut text = String::new();
+    input.read_to_string(&mut text).context("Failed to read import")?;
+    let mut messages = import::parse_foreign(format, &text, db::now_ms())?;
+    for msg in &mut messages {
+        msg.queue_id = q.id;
+        msg.trace_id.get_or_insert_with(new_trace_id);
+    }
+    let mut imported = 0;
+    for batch in messages.chunks(EXPORT_BATCH) {
+        imported += db
+            .import_messages(batch)
+            .await
+            .context("Failed to import messages")?;
+    }
+    Ok((imported, messages.len() as u64 - imported))
+}
+
+/// What an admin job does
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    Serialize,
+    Deserialize,
+    clap::ValueEnum,
+    utoipa::ToSchema,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum AdminJobKind {
+    /// Delete a queue's live messages, as `queue purge` does
+    Purge,
+    /// Compact the database, as `queue compact` does
+    Compact,
+    /// Write a queue's messages to a file on the server, as `queue export`
+    /// does
+    Export,
+    /// Requeue all of a queue's dead letters
+    Redrive,
+}
+
+impl AdminJobKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            AdminJobKind::Purge => "purge",
+            AdminJobKind::Compact => "compact",
+            AdminJobKind::Export => "export",
+            AdminJobKind::Redrive => "redrive",
+        }
+    }
+}
+
+/// How long finished admin jobs are kept for their status to be read
+pub const ADMIN_JOB_RETENTION_MS: i64 = 7 * 86_400_000;
+
+/// Queue an admin job for the server to run in the background. `queue` is
+/// required by all but `compact`, `path` (a file that must not exist yet)
+/// by `export`. `batch_size` defaults to [`PURGE_BATCH`] messages, or
+/// [`EXPORT_BATCH`] for exports.
+#[tracing::instrument(level = "debug", skip_all, fields(kind = kind.name(), queue = ?queue))]
+pub async fn start_admin_job(
+    db: &Db,
+    kind: AdminJobKind,
+    queue: Option<&str>,
+    path: Option<&Path>,
+    batch_size: Option<usize>,
+) -> Result<AdminJob> {
+    let queue = match (kind, queue) {
+        (AdminJobKind::Compact, _) => None,
+        (_, Some(name)) => Some(show_queue(db, name).await?.name),
+        (_, None) => {
+            return Err(SqewError::Invalid(format!(
+                "{} job: a queue is required",
+                kind.name()
+            )));
+        }
+    };
+    let path = match (kind, path) {
+        (AdminJobKind::Export, Some(path)) if path.exists() => {
+            return Err(SqewError::Invalid(format!(
+                "export job: {} already exists",
+                path.display()
+            )));
+        }
+        (AdminJobKind::Export, Some(path)) => {
+            Some(path.to_string_lossy().into_owned())
+        }
+        (AdminJobKind::Export, None) => {
+            return Err(SqewError::Invalid(
+                "export job: a path is required".into(),
+            ));
+        }
+        _ => None,
+    };
+    let default_batch = match kind {
+        AdminJobKind::Export => EXPORT_BATCH,
+        _ => PURGE_BATCH,
+    };
+    let batch_size = batch_size.unwrap_or(default_batch);
+    if batch_size == 0 {
+        return Err(SqewError::Invalid(
+            "batch size 0: must be positive".into(),
+        ));
+    }
+    let job = AdminJob {
+        id: 0,
+        kind: kind.name().to_string(),
+        queue,
+        path,
+        batch_size: batch_size as i64,
+        status: "pending".into(),
+        progress: 0,
+        error: None,
+        cancel_requested: false,
+        created_at: db::now_ms(),
+        started_at: None,
+        finished_at: None,
+    };
+    db.create_admin_job(&job).await.context("Failed to create job")
+}
+
+/// Fetch an admin job by id
+pub async fn admin_job(
+    db: &Db,
+    id: i64,
+) -> Result<AdminJob> {
+    db.get_admin_job(id)
+        .await
+        .context("Failed to fetch job")?
+        .ok_or(SqewError::JobNotFound(id))
+}
+
+/// The latest `limit` admin jobs, newest first
+pub async fn list_admin_jobs(
+    db: &Db,
+    limit: i64,
+) -> Result<Vec<AdminJob>> {
+    db.list_admin_jobs(limit).await.context("Failed to list jobs")
+}
+
+/// Cancel an admin job: a pending one never runs, a running one stops
+/// after its current batch, keeping the work done so far. Fails with
+/// [`SqewError::JobFinished`] for a job that already finished.
+#[tracing::instrument(level = "debug", skip_all, fields(id))]
+pub async fn cancel_admin_job(
+    db: &Db,
+    id: i64,
+) -> Result<AdminJob> {
+    let job = db
+        .cancel_admin_job(id, db::now_ms())
+        .await
+        .context("Failed to cancel job")?
+        .ok_or(SqewError::JobNotFound(id))?;
+    if job.finished_at.is_some() && job.status != "canceled" {
+        return Err(SqewError::JobFinished { id, status: job.status });
+    }
+    Ok(job)
+}
+
+/// Mark the admin jobs left running by a server that stopped mid-job as
+/// failed; returns how many. Called by `sqew serve` before it starts
+/// running jobs.
+pub async fn fail_interrupted_admin_jobs(db: &Db) -> Result<u64> {
+    db.fail_running_admin_jobs("interrupted by a server restart", db::now_ms())
+        .await
+        .context("Failed to update interrupted jobs")
+}
+
+/// Run the pending admin jobs one at a time, oldest first, then forget
+/// jobs finished more than [`ADMIN_JOB_RETENTION_MS`] ago. Returns how many
+/// jobs ran. A job that fails is marked `failed` with its error; it does not
+/// fail the others.
+pub async fn run_admin_jobs(db: &Db) -> Result<u64> {
+    let mut ran = 0;
+    while let Some(job) =
+        db.claim_admin_job(db::now_ms()).await.context("Failed to claim job")?
+    {
+        ran += 1;
+        let mut progress = 0;
+        let outcome = run_admin_job(db, &job, &mut progress).await;
+        let (status, error) = match outcome {
+            Ok(true) => ("done", None),
+            Ok(false) => ("canceled", None),
+            Err(e) => {
+                let error = format!("{:#}", anyhow::Error::from(e));
+                ("failed", Some(error))
+            }
+        };
+        tracing::info!(
+            "{} job {} {} after {} message(s)",
+            job.kind,
+            job.id,
+            status,
+            progress
+        );
+        job_canceled(db, job.id, progress).await?;
+        db.finish_admin_job(job.id, status, error.as_deref(), db::now_ms())
+            .await
+            .context("Failed to finish job")?;
+    }
+    db.purge_admin_jobs(db::now_ms() - ADMIN_JOB_RETENTION_MS)
+        .await
+        .context("Failed to purge finished jobs")?;
+    Ok(ran)
+}
+
+// Run a claimed job, counting the messages handled in `progress`. Returns
+// false when the job was canceled before it could finish.
+async fn run_admin_job(
+    db: &Db,
+    job: &AdminJob,
+    progress: &mut i64,
+) -> Result<bool> {
+    let queue = job.queue.as_deref().unwrap_or_default();
+    let batch = job.batch_size;
+    match job.kind.as_str() {
+        "compact" => compact(db).await.map(|_| true),
+        "purge" => {
+            show_queue(db, queue).await?;
+            loop {
+                let n = db
+                    .purge_messages_by_queue(queue, batch)
+                    .await
+                    .context("Failed to purge messages")?;
+                *progress += n as i64;
+                if n < batch as u64 {
+                    return Ok(true);
+                }
+                if job_canceled(db, job.id, *progress).await? {
+                    return Ok(false);
+                }
+                tokio::time::sleep(PURGE_BATCH_PAUSE).await;
+            }
+        }
+        "redrive" => {
+            show_queue(db, queue).await?;
+            loop {
+                let page = db
+                    .list_dead_letters(queue, batch)
+                    .await
+                    .context("Failed to list dead letters")?;
+                if page.is_empty() {
+                    return Ok(true);
+                }
+                let ids: Vec<i64> = page.iter().map(|m| m.id).collect();
+                *progress += db
+                    .redrive_dead_letters(queue, &ids)
+                    .await
+                    .context("Failed to redrive dead letters")?
+                    as i64;
+                if page.len() < batch as usize {
+                    return Ok(true);
+                }
+                if job_canceled(db, job.id, *progress).await? {
+                    return Ok(false);
+                }
+            }
+        }
+        "export" => {
+            let path = Path::new(job.path.as_deref().unwrap_or_default());
+            show_queue(db, queue).await?;
+            let file = std::fs::File::create_new(path).with_context(|| {
+                format!("Failed to create {}", path.display())
+            })?;
+            let exported = export_job(db, job, file, progress).await;
+            // Leave no half-written export behind
+            if !matches!(exported, Ok(true)) {
+                let _ = std::fs::remove_file(path);
+            }
+            exported
+        }
+        kind => Err(SqewError::Invalid(format!("job kind '{kind}'"))),
+    }
+}
+
+// Record a running job's progress; true when it has been asked to cancel
+async fn job_canceled(
+    db: &Db,
+    id: i64,
+    progress: i64,
+) -> Result<bool> {
+    db.set_admin_job_progress(id, progress)
+        .await
+        .context("Failed to record job progress")
+}
+
+// Write the messages of an export job's queue to `file` page by page
+async fn export_job(
+    db: &Db,
+    job: &AdminJob,
+    file: std::fs::File,
+    progress: &mut i64,
+) -> Result<bool> {
+    use std::io::Write;
+    let queue = job.queue.as_deref().unwrap_or_default();
+    let mut out = std::io::BufWriter::new(file);
+    let mut after_id = 0;
+    loop {
+        let page = db
+            .export_messages(queue, after_id, job.batch_size)
+            .await
+            .context("Failed to export messages")?;
+        write_export(&mut out, &page)?;
+        *progress += page.len() as i64;
+        match page.last() {
+            Some(last) if page.len() as i64 == job.batch_size => {
+                after_id = last.id
+            }
+            _ => break,
+        }
+        if job_canceled(db, job.id, *progress).await? {
+            return Ok(false);
+        }
+    }
+    out.flush().context("Failed to write export")?;
+    Ok(true)
+}
+
+/// Enqueue the newline-delimited JSON payloads read from `input` with `opts`,
+/// committing them in transactions of `batch_size` messages so a feed of any
+/// length is never held in memory. `on_batch` is called with the running
+/// total after each commit. On a malformed or rejected line the batches
+/// already committed stay enqueued; the error reports how many there were.
+pub async fn enqueue_stream(
+    db: &Db,
+    queue_name: &str,
+    input: impl tokio::io::AsyncBufRead + Unpin,
+    opts: &EnqueueOptions,
+    batch_size: usize,
+    mut on_batch: impl FnMut(u64),
+) -> Result<u64> {
+    use tokio::io::AsyncBufReadExt;
+    if batch_size == 0 {
+        return Err(SqewError::Invalid(
+            "batch size 0: must be positive".into(),
+        ));
+    }
+    check_options(opts)?;
+    let q = show_queue(db, queue_name).await?;
+    let mut lines = input.lines();
+    let mut batch = Vec::with_capacity(batch_size);
+    let (mut enqueued, mut n) = (0, 0);
+    while let Some(line) =
+        lines.next_line().await.context("Failed to read input")?
+    {
+        n += 1;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let payload: Value = serde_json::from_str(&line).map_err(|e| {
+            SqewError::Invalid(format!(
+                "JSON at line {}: {} ({} message(s) already enqueued)",
+                n, e, enqueued
+            ))
+        })?;
+        let mut msg = new_message(&q, &payload, opts, db::now_ms());
+        check_payload(&q, &payload, &mut msg)?;
+        batch.push(msg);
+        if batch.len() == batch_size {
+            wait_for_room(db, &q, batch.len() as i64, opts.wait_ms).await?;
+            enqueued += db
+                .import_messages(&batch)
+                .await
+                .context("Failed to enqueue messages")?;
+            batch.clear();
+            on_batch(enqueued);
+        }
+    }
+    if !batch.is_empty() {
+        wait_for_room(db, &q, batch.len() as i64, opts.wait_ms).await?;
+        enqueued += db
+            .import_messages(&batch)
+            .await
+            .context("Failed to enqueue messages")?;
+        on_batch(enqueued);
+    }
+    Ok(enqueued)
+}
+
+// Parse a cron expression. Standard 5-field expressions are accepted and run
+// at second 0; 6/7-field expressions (with seconds/years) are used as given.
+fn parse_cron(expr: &str) -> Result<cron::Schedule> {
+    let expr = expr.trim();
+    let full = if expr.split_whitespace().count() == 5 {
+        format!("0 {expr}")
+    } else {
+        expr.to_string()
+    };
+    cron::Schedule::from_str(&full).map_err(|e| {
+        SqewError::Invalid(format!("cron expression '{}': {}", expr, e))
+    })
+}
+
+// Next time (ms) strictly after `after_ms` that the cron expression fires
+fn next_cron_run(
+    sched: &cron::Schedule,
+    after_ms: i64,
+) -> Option<i64> {
+    let after = chrono::DateTime::from_timestamp_millis(after_ms)?;
+    sched.after(&after).next().map(|t| t.timestamp_millis())
+}
+
+/// Register a cron schedule that enqueues `payload` into a queue
+#[tracing::instrument(level = "debug", skip_all, fields(queue = %name, cron = %cron_expr))]
+pub async fn add_schedule(
+    db: &Db,
+    name: &str,
+    cron_expr: &str,
+    payload: &Value,
+) -> Result<Schedule> {
+    let q = show_queue(db, name).await?;
+    let sched = parse_cron(cron_expr)?;
+    let now = db::now_ms();
+    let next_run_at = next_cron_run(&sched, now).ok_or_else(|| {
+        SqewError::Invalid(format!(
+            "cron expression '{}': never fires",
+            cron_expr.trim()
+        ))
+    })?;
+    let s = Schedule {
+        id: 0,
+        queue_id: q.id,
+        cron: cron_expr.trim().to_string(),
+        payload: payload.to_string(),
+        next_run_at,
+        created_at: now,
+    };
+    let id =
+        db.create_schedule(&s).await.context("Failed to create schedule")?;
+    Ok(Schedule { id, ..s })
+}
+
+/// List schedules, optionally only those targeting one queue
+#[tracing::instrument(level = "debug", skip_all, fields(queue = ?name))]
+pub async fn list_schedules(
+    db: &Db,
+    name: Option<&str>,
+) -> Result<Vec<Schedule>> {
+    if let Some(name) = name {
+        show_queue(db, name).await?;
+    }
+    db.list_schedules(name).await.context("Failed to list schedules")
+}
+
+/// Remove a schedule by ID. Returns true if it existed
+#[tracing::instrument(level = "debug", skip_all, fields(schedule_id = id))]
+pub async fn remove_schedule(
+    db: &Db,
+    id: i64,
+) -> Result<bool> {
+    db.delete_schedule(id).await.context("Failed to remove schedule")
+}
+
+/// Enqueue the payload of every due schedule and advance it to its next run.
+/// Runs missed while no server was up are coalesced into a single enqueue.
+/// Returns the names of queues that received a message.
+#[tracing::instrument(level = "debug", skip_all)]
+pub async fn run_due_schedules(db: &Db) -> Result<Vec<String>> {
+    let now = db::now_ms();
+    let due =
+        db.due_schedules(now).await.context("Failed to load due schedules")?;
+    let queues = db.list_queues().await?;
+    let mut fired = Vec::new();
+    for s in due {
+        // A stored expression always parsed when added; never fire again if not
+        let next = parse_cron(&s.cron)
+            .ok()
+            .and_then(|c| next_cron_run(&c, now))
+            .unwrap_or(i64::MAX);
+        let msg = Message {
+            id: 0,
+            queue_id: s.queue_id,
+            payload: s.payload.clone(),
+            attempts: 0,
+            available_at: now,
+            created_at: now,
+            dead_at: None,
+            lease_token: None,
+            priority: 0,
+            expires_at: None,
+            dedup_key: None,
+            group_id: None,
+            headers: None,
+            trace_id: Some(new_trace_id()),
+            last_error: None,
+            fair_key: None,
+            lease_expirations: 0,
+            sort_key: None,
+            reply_to: None,
+            public_id: db::new_public_id(now),
+        };
+        let ran = db
+            .fire_schedule(s.id, s.next_run_at, next, &msg)
+            .await
+            .with_context(|| format!("Failed to run schedule {}", s.id))?;
+        if ran && let Some(q) = queues.iter().find(|q| q.id == s.queue_id) {
+            fired.push(q.name.clone());
+        }
+    }
+    Ok(fired)
+}
+
+/// Metrics an alarm can watch: `ready` is the queue's ready message count,
+/// `oldest_age_ms` the age of its oldest live message
+pub const ALARM_METRICS: &[&str] = &["ready", "oldest_age_ms"];
+
+/// A change of alarm state, POSTed as JSON to the alarm's webhook
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct AlarmNotification {
+    pub alarm_id: i64,
+    pub queue: String,
+    pub metric: String,
+    pub threshold: i64,
+    /// The metric's value when the alarm changed state
+    pub value: i64,
+    /// `firing` or `resolved`
+    pub state: &'static str,
+    /// When the change was detected (ms since the Unix epoch)
+    pub at: i64,
+    #[serde(skip)]
+    pub webhook_url: String,
+}
+
+/// Add an alarm on a queue that fires once `metric` has stayed above
+/// `threshold` for `for_ms`, notifying `webhook_url`
+#[tracing::instrument(level = "debug", skip_all, fields(queue = %name, metric = %metric))]
+pub async fn add_alarm(
+    db: &Db,
+    name: &str,
+    metric: &str,
+    threshold: i64,
+    for_ms: i64,
+    webhook_url: &str,
+) -> Result<Alarm> {
+    let q = show_queue(db, name).await?;
+    if !ALARM_METRICS.contains(&metric) {
+        return Err(SqewError::Invalid(format!(
+            "alarm metric '{}': expected one of {}",
+            metric,
+            ALARM_METRICS.join(", ")
+        )));
+    }
+    if threshold < 0 || for_ms < 0 {
+        return Err(SqewError::Invalid(
+            "alarm: threshold and for_ms must not be negative".into(),
+        ));
+    }
+    if !(webhook_url.starts_with("http://")
+        || webhook_url.starts_with("https://"))
+    {
+        return Err(SqewError::Invalid(format!(
+            "webhook URL '{}'",
+            webhook_url
+        )));
+    }
+    let now = db::now_ms();
+    let a = Alarm {
+        id: 0,
+        queue_id: q.id,
+        metric: metric.to_string(),
+        threshold,
+        for_ms,
+        webhook_url: webhook_url.to_string(),
+        breached_since: None,
+        firing: false,
+        created_at: now,
+    };
+    let id = db.create_alarm(&a).await.context("Failed to create alarm")?;
+    Ok(Alarm { id, ..a })
+}
+
+/// List alarms, optionally only those on one queue
+#[tracing::instrument(level = "debug", skip_all, fields(queue = ?name))]
+pub async fn list_alarms(
+    db: &Db,
+    name: Option<&str>,
+) -> Result<Vec<Alarm>> {
+    if let Some(name) = name {
+        show_queue(db, name).await?;
+    }
+    db.list_alarms(name).await.context("Failed to list alarms")
+}
+
+/// Remove an alarm by ID. Returns true if it existed
+#[tracing::instrument(level = "debug", skip_all, fields(alarm_id = id))]
+pub async fn remove_alarm(
+    db: &Db,
+    id: i64,
+) -> Result<bool> {
+    db.delete_alarm(id).await.context("Failed to remove alarm")
+}
+
+// Current value of an alarm's metric
+async fn alarm_metric(
+    db: &Db,
+    a: &Alarm,
+    now: i64,
+) -> Result<i64> {
+    let value = match a.metric.as_str() {
+        "ready" => db.count_ready_messages(a.queue_id, now).await,
+        _ => db
+            .oldest_message_created_at(a.queue_id, now)
+            .await
+            .map(|at| at.map_or(0, |created_at| (now - created_at).max(0))),
+    };
+    value.with_context(|| format!("Failed to evaluate alarm {}", a.id))
+}
+
+/// Evaluate every alarm and record breaches. Returns a notification for
+/// each alarm that started firing (its metric stayed above the threshold
+/// for `for_ms`) or resolved (its metric dropped back) since the last
+/// evaluation.
+#[tracing::instrument(level = "debug", skip_all)]
+pub async fn evaluate_alarms(db: &Db) -> Result<Vec<AlarmNotification>> {
+    let alarms = db.list_alarms(None).await.context("Failed to list alarms")?;
+    if alarms.is_empty() {
+        return Ok(Vec::new());
+    }
+    let queues = db.list_queues().await?;
+    let now = db::now_ms();
+    let mut changed = Vec::new();
+    for a in alarms {
+        let value = alarm_metric(db, &a, now).await?;
+        let breached_since =
+            (value > a.threshold).then(|| a.breached_since.unwrap_or(now));
+        let firing =
+            breached_since.is_some_and(|since| now - since >= a.for_ms);
+        if breached_since != a.breached_since || firing != a.firing {
+            db.set_alarm_state(a.id, breached_since, firing)
+                .await
+                .context("Failed to update alarm")?;
+        }
+        if firing == a.firing {
+            continue;
+        }
+        let queue = queues
+            .iter()
+            .find(|q| q.id == a.queue_id)
+            .map(|q| q.name.clone())
+            .unwrap_or_default();
+        changed.push(AlarmNotification {
+            alarm_id: a.id,
+            queue,
+            metric: a.metric,
+            threshold: a.threshold,
+            value,
+            state: if firing { "firing" } else { "resolved" },
+            at: now,
+            webhook_url: a.webhook_url,
+        });
+    }
+    Ok(changed)
+}
+
+/// POST a notification to its alarm's webhook
+pub async fn send_alarm_webhook(
+    client: &reqwest::Client,
+    n: &AlarmNotification,
+) -> Result<()> {
+    client
+        .post(&n.webhook_url)
+        .json(n)
+        .send()
+        .await
+        .and_then(|resp| resp.error_for_status())
+        .map_err(|e| {
+            anyhow::Error::new(e).context(format!(
+                "Failed to notify {} of alarm {}",
+                n.webhook_url, n.alarm_id
+            ))
+        })?;
+    Ok(())
+}
+
+/// Evaluate every alarm and deliver the resulting notifications. A failed
+/// delivery is logged and not retried. Returns how many were delivered.
+pub async fn run_alarms(
+    db: &Db,
+    client: &reqwest::Client,
+) -> Result<usize> {
+    let mut delivered = 0;
+    for n in evaluate_alarms(db).await? {
+        tracing::info!(
+            "Alarm {} on '{}' {}: {} = {} (threshold {})",
+            n.alarm_id,
+            n.queue,
+            n.state,
+            n.metric,
+            n.value,
+            n.threshold
+        );
+        match send_alarm_webhook(client, &n).await {
+            Ok(()) => delivered += 1,
+            Err(e) => tracing::warn!("{e:#}"),
+        }
+    }
+    Ok(delivered)
+}
+
+/// Most deliveries a queue's push config may keep in flight
+pub const MAX_PUSH_CONCURRENCY: i32 = 100;
+
+/// How long push deliveries stay in the delivery log
+pub const PUSH_LOG_RETENTION_MS: i64 = 7 * 24 * 60 * 60 * 1000;
+
+// Longest delay before a failed push is retried
+const PUSH_BACKOFF_MAX_MS: i64 = 60 * 60 * 1000;
+
+/// Have the server POST the ready messages of a queue to `url`, at most
+/// `concurrency` at a time. A 2xx answer within `timeout_ms` acks the
+/// message; anything else nacks it for `backoff_ms`, doubled with each
+/// further attempt. Replaces the queue's existing push config.
+#[tracing::instrument(level = "debug", skip_all, fields(queue = %name))]
+pub async fn set_push_config(
+    db: &Db,
+    name: &str,
+    url: &str,
+    concurrency: i32,
+    timeout_ms: i64,
+    backoff_ms: i64,
+) -> Result<PushConfig> {
+    let q = show_queue(db, name).await?;
+    if !(url.starts_with("http://") || url.starts_with("https://")) {
+        return Err(SqewError::Invalid(format!("push URL '{}'", url)));
+    }
+    if !(1..=MAX_PUSH_CONCURRENCY).contains(&concurrency) {
+        return Err(SqewError::Invalid(format!(
+            "push concurrency {}: expected 1 to {}",
+            concurrency, MAX_PUSH_CONCURRENCY
+        )));
+    }
+    if timeout_ms <= 0 || backoff_ms < 0 {
+        return Err(SqewError::Invalid(
+            "push config: timeout_ms must be positive and backoff_ms not \
+             negative"
+                .into(),
+        ));
+    }
+    if !db.list_consumer_groups(name).await?.is_empty() {
+        return Err(SqewError::Invalid(format!(
+            "push config: queue '{}' has consumer groups",
+            name
+        )));
+    }
+    let now = db::now_ms();
+    let cfg = PushConfig {
+        queue_id: q.id,
+        url: url.to_string(),
+        concurrency,
+        timeout_ms,
+        backoff_ms,
+        created_at: now,
+        updated_at: now,
+    };
+    db.set_push_config(&cfg).await.context("Failed to set push config")
+}
+
+/// The push config of a queue, if it has one
+#[tracing::instrument(level = "debug", skip_all, fields(queue = %name))]
+pub async fn push_config(
+    db: &Db,
+    name: &str,
+) -> Result<Option<PushConfig>> {
+    let q = show_queue(db, name).await?;
+    db.get_push_config(q.id).await.context("Failed to read push config")
+}
+
+/// Stop pushing a queue's messages. Returns true if it had a push config
+#[tracing::instrument(level = "debug", skip_all, fields(queue = %name))]
+pub async fn remove_push_config(
+    db: &Db,
+    name: &str,
+) -> Result<bool> {
+    let q = show_queue(db, name).await?;
+    db.delete_push_config(q.id).await.context("Failed to remove push config")
+}
+
+/// The last `limit` push deliveries of a queue, newest first
+#[tracing::instrument(level = "debug", skip_all, fields(queue = %name))]
+pub async fn push_deliveries(
+    db: &Db,
+    name: &str,
+    limit: i64,
+) -> Result<Vec<PushDelivery>> {
+    let q = show_queue(db, name).await?;
+    db.list_push_deliveries(q.id, limit.max(0))
+        .await
+        .context("Failed to list push deliveries")
+}
+
+// The JSON body a message is pushed as
+#[derive(serde::Serialize)]
+struct PushMessage<'a> {
+    id: i64,
+    queue: &'a str,
+    payload: Value,
+    /// Deliveries before this one
+    attempts: i32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    headers: Option<&'a Headers>,
+    trace_id: Option<&'a str>,
+    created_at: i64,
+}
+
+/// Push the ready messages of every queue with a push config to its
+/// endpoint, queues side by side, and log each delivery. A queue is pushed
+/// until it runs dry or a delivery fails; the rest wait for the next run.
+/// Returns how many messages were acked.
+pub async fn run_push(
+    db: &Db,
+    client: &reqwest::Client,
+) -> Result<usize> {
+    let configs =
+        db.list_push_configs().await.context("Failed to list push configs")?;
+    if configs.is_empty() {
+        return Ok(0);
+    }
+    let queues = db.list_queues().await?;
+    let mut pushes = tokio::task::JoinSet::new();
+    for cfg in configs {
+        let Some(q) = queues.iter().find(|q| q.id == cfg.queue_id) else {
+            continue;
+        };
+        let (db, client, name) = (db.clone(), client.clone(), q.name.clone());
+        pushes.spawn(async move {
+            let pushed = push_queue(&db, &client, &name, &cfg).await;
+            (name, pushed)
+        });
+    }
+    let mut acked = 0;
+    while let Some(joined) = pushes.join_next().await {
+        match joined {
+            Ok((_, Ok(n))) => acked += n,
+            Ok((name, Err(e))) => {
+                tracing::warn!("Push delivery for '{name}' failed: {e:#}")
+            }
+            Err(e) => tracing::error!("Push delivery panicked: {e}"),
+        }
+    }
+    db.purge_push_deliveries(db::now_ms() - PUSH_LOG_RETENTION_MS)
+        .await
+        .context("Failed to purge push deliveries")?;
+    Ok(acked)
+}
+
+// Push a queue's ready messages, `concurrency` at a time, until it runs dry
+// or a delivery fails. Returns how many were acked.
+async fn push_queue(
+    db: &Db,
+    client: &reqwest::Client,
+    name: &str,
+    cfg: &PushConfig,
+) -> Result<usize> {
+    let mut acked = 0;
+    loop {
+        // Lease for longer than a request may take, so an answered delivery
+        // is still leased when it is acked or nacked
+        let msgs = poll_messages_as(
+            db,
+            name,
+            cfg.concurrency.into(),
+            cfg.timeout_ms * 2,
+            Some("push"),
+        )
+        .await?;
+        let Some(token) = msgs.first().and_then(|m| m.lease_token.clone())
+        else {
+            return Ok(acked);
+        };
+        let drained = msgs.len() < cfg.concurrency as usize;
+        let mut sends = tokio::task::JoinSet::new();
+        for m in msgs {
+            let (client, cfg, name) =
+                (client.clone(), cfg.clone(), name.to_string());
+            sends.spawn(
+                async move { push_message(&client, &cfg, &name, m).await },
+            );
+        }
+        let mut log = Vec::new();
+        let (mut ok, mut failed) = (Vec::new(), Vec::new());
+        while let Some(sent) = sends.join_next().await {
+            let (m, d) = sent.map_err(anyhow::Error::new)?;
+            if d.error.is_none() {
+                ok.push(m.id);
+            } else {
+                let retry = cfg
+                    .backoff_ms
+                    .saturating_mul(1 << m.attempts.clamp(0, 20))
+                    .min(PUSH_BACKOFF_MAX_MS);
+                failed.push((m.id, retry));
+            }
+            log.push(d);
+        }
+        let acked_now = acked_ids(db, &ok, &token).await?;
+        let (requeued, dead) = nacked_ids(db, &failed, &token, None).await?;
+        for d in &mut log {
+            let outcome = if acked_now.contains(&d.message_id) {
+                "acked"
+            } else if requeued.contains(&d.message_id) {
+                "requeued"
+            } else if dead.contains(&d.message_id) {
+                "dead_lettered"
+            } else {
+                "lease_lost"
+            };
+            d.outcome = outcome.to_string();
+        }
+        db.record_push_deliveries(&log)
+            .await
+            .context("Failed to log push deliveries")?;
+        acked += acked_now.len();
+        if drained || !failed.is_empty() {
+            return Ok(acked);
+        }
+    }
+}
+
+// POST a leased message to its queue's push URL. The returned log entry has
+// no outcome yet; a delivery failed if it has an error.
+async fn push_message(
+    client: &reqwest::Client,
+    cfg: &PushConfig,
+    queue: &str,
+    m: Message,
+) -> (Message, PushDelivery) {
+    let started = std::time::Instant::now();
+    let body = PushMessage {
+        id: m.id,
+        queue,
+        payload: serde_json::from_str(&m.payload)
+            .unwrap_or_else(|_| Value::String(m.payload.clone())),
+        attempts: m.attempts,
+        headers: m.headers.as_ref(),
+        trace_id: m.trace_id.as_deref(),
+        created_at: m.created_at,
+    };
+    let sent = client
+        .post(&cfg.url)
+        .timeout(std::time::Duration::from_millis(cfg.timeout_ms as u64))
+        .json(&body)
+        .send()
+        .await;
+    let (status_code, error) = match sent {
+        Ok(resp) if resp.status().is_success() => {
+            (Some(resp.status().as_u16().into()), None)
+        }
+        Ok(resp) => (
+            Some(resp.status().as_u16().into()),
+            Some(format!("HTTP {}", resp.status())),
+        ),
+        Err(e) => (None, Some(e.to_string())),
+    };
+    let d = PushDelivery {
+        id: 0,
+        queue_id: cfg.queue_id,
+        message_id: m.id,
+        attempt: m.attempts + 1,
+        status_code,
+        error,
+        outcome: String::new(),
+        duration_ms: started.elapsed().as_millis() as i64,
+        delivered_at: db::now_ms(),
+    };
+    (m, d)
+}
+
+/// Statistics for a queue: ready, leased, dlq counts
+#[tracing::instrument(level = "debug", skip_all, fields(queue = %name))]
+pub async fn stats(
+    db: &Db,
+    name: &str,
+) -> Result<serde_json::Value> {
+    // Get queue
+    let q = show_queue(db, name).await?;
+    // Current time in ms
+    let now = db::now_ms();
+    // Counts
+    let ready = db
+        .count_ready_messages(q.id, now)
+        .await
+        .context("Failed to count ready messages")?;
+    let dlq = db
+        .count_dead_messages(q.id)
+        .await
+        .context("Failed to count dead letters")?;
+    let expired = db
+        .count_expired_messages(q.id)
+        .await
+        .context("Failed to count expired messages")?;
+    let m = db
+        .queue_metrics(q.id, now)
+        .await
+        .context("Failed to read queue metrics")?;
+    let oldest_ready_age_ms = m.oldest_ready_at.map(|at| (now - at).max(0));
+    let avg_ack_ms = (m.acked > 0).then(|| m.ack_latency_ms_total / m.acked);
+    Ok(serde_json::json!({
+        "ready": ready,
+        "leased": m.leased,
+        "delayed": m.delayed,
+        "dlq": dlq,
+        "expired": expired,
+        "enqueued": m.enqueued,
+        "acked": m.acked,
+        "oldest_ready_age_ms": oldest_ready_age_ms,
+        "avg_ack_ms": avg_ack_ms,
+    }))
+}
+
+/// How long the server keeps queue stats snapshots (7 days)
+pub const STATS_HISTORY_RETENTION_MS: i64 = 7 * 86_400_000;
+
+/// Snapshot the stats of every queue into the history, dropping snapshots
+/// older than [`STATS_HISTORY_RETENTION_MS`]; returns how many queues were
+/// recorded
+#[tracing::instrument(level = "debug", skip_all)]
+pub async fn record_stats_history(db: &Db) -> Result<u64> {
+    let now = db::now_ms();
+    let n = db
+        .record_stats_history(now)
+        .await
+        .context("Failed to record queue stats")?;
+    db.purge_stats_history(now - STATS_HISTORY_RETENTION_MS)
+        .await
+        .context("Failed to purge queue stats history")?;
+    Ok(n)
+}
+
+/// Stats snapshots of a queue from the last `window_ms`, oldest first
+#[tracing::instrument(level = "debug", skip_all, fields(queue = %name, window_ms))]
+pub async fn stats_history(
+    db: &Db,
+    name: &str,
+    window_ms: i64,
+) -> Result<Vec<StatsSample>> {
+    if window_ms <= 0 {
+        return Err(SqewError::Invalid("window: must be positive".into()));
+    }
+    show_queue(db, name).await?;
+    db.list_stats_history(name, db::now_ms() - window_ms)
+        .await
+        .context("Failed to read queue stats history")
+}
+
+/// Parse a window such as `90s`, `15m`, `1h` or `7d` into milliseconds
+pub fn parse_window(s: &str) -> Result<i64> {
+    let invalid = || {
+        SqewError::Invalid(format!(
+            "window '{s}': expected a number with a unit of s, m, h or d"
+        ))
+    };
+    let unit_ms = match s.chars().last() {
+        Some('s') => 1000,
+        Some('m') => 60_000,
+        Some('h') => 3_600_000,
+        Some('d') => 86_400_000,
+        _ => return Err(invalid()),
+    };
+    let n: i64 = s[..s.len() - 1].parse().map_err(|_| invalid())?;
+    n.checked_mul(unit_ms).filter(|ms| *ms > 0).ok_or_else(invalid)
+}
+
+/// Configuration for queue/database setup
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// SQLite database file, used when no `database_url` is set; `:memory:`
+    /// keeps a throwaway database in memory for the life of the pool
+    pub db_path: PathBuf,
+    /// Connection URL selecting the backend: `postgres://...` for Postgres,
+    /// `sqlite://<path>` for SQLite
+    pub database_url: Option<String>,
+    pub force_recreate: bool,
+    /// Maximum number of pooled database connections
+    pub pool_size: u32,
+    /// How long a query waits for a free pooled connection before failing
+    pub acquire_timeout: std::time::Duration,
+    /// Prepared statements cached per database connection
+    pub statement_cache_size: usize,
+    /// SQLite only: connections of a read-only pool serving peeks, searches
+    /// and stats apart from writers; 0 runs them on the main pool
+    pub read_pool_size: u32,
+    /// SQLite stores payloads larger than this many bytes compressed; 0
+    /// disables compression
+    pub compress_threshold: usize,
+    /// Keys SQLite encrypts payloads with; see [`Keyring`]
+    pub encryption_keys: Option<Arc<Keyring>>,
+    /// File to read the encryption keys from when `encryption_keys` is unset
+    pub encryption_keyfile: Option<PathBuf>,
+    /// Settings of queues created without explicit ones
+    pub queue_defaults: QueueOptions,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        let cwd =
+            std::env::current_dir().unwrap_or_else(|_| PathBuf::from("."));
+        Self {
+            db_path: cwd.join("sqew.db"),
+            database_url: None,
+            force_recreate: false,
+            pool_size: db::DEFAULT_POOL_SIZE,
+            acquire_timeout: db::DEFAULT_ACQUIRE_TIMEOUT,
+            statement_cache_size: db::DEFAULT_STATEMENT_CACHE_SIZE,
+            read_pool_size: 0,
+            compress_threshold: db::DEFAULT_COMPRESS_THRESHOLD,
+            encryption_keys: None,
+            encryption_keyfile: None,
+            queue_defaults: QueueOptions::default(),
+        }
+    }
+}
+
+impl Config {
+    /// The connection pool settings of this configuration
+    pub fn pool_options(&self) -> PoolOptions {
+        PoolOptions {
+            max_connections: self.pool_size,
+            acquire_timeout: self.acquire_timeout,
+            statement_cache_size: self.statement_cache_size,
+            read_connections: self.read_pool_size,
+        }
+    }
+}
+
+/// Optional settings for enqueueing a message
+#[derive(Debug, Clone, Default)]
+pub struct EnqueueOptions {
+    /// Delay visibility in milliseconds; `None` uses the queue's default
+    pub delay_ms: Option<i64>,
+    /// Deliver at this Unix time in milliseconds instead (see
+    /// [`parse_deliver_at`]); a time already past delivers at once. Excludes
+    /// `delay_ms`.
+    pub deliver_at: Option<i64>,
+    /// Higher priorities are polled first (default 0)
+    pub priority: i32,
+    /// Expire the message if not consumed within this many milliseconds
+    pub ttl_ms: Option<i64>,
+    /// Drop the enqueue if this key was used in the queue's dedup window
+    pub dedup_key: Option<String>,
+    /// FIFO group: at most one message per group is leased at a time
+    pub group_id: Option<String>,
+    /// Routing metadata returned with the message
+    pub headers: Option<Headers>,
+    /// Correlates the message's lifecycle in logs; generated when `None`
+    pub trace_id: Option<String>,
+    /// Tenant the message belongs to: polls of a `fair` queue take turns
+    /// between keys
+    pub fair_key: Option<String>,
+    /// Polls of a `sort_key` queue take lower keys first
+    pub sort_key: Option<f64>,
+    /// Queue to send a receipt to once the message is acked or
+    /// dead-lettered; receipts to a missing queue are dropped
+    pub reply_to: Option<String>,
+    /// How long to wait for room in a queue at its `max_depth` before
+    /// failing with [`SqewError::QueueFull`]; `None` fails at once
+    pub wait_ms: Option<i64>,
+}
+
+// A fresh trace id for a message enqueued without one
+fn new_trace_id() -> String {
+    uuid::Uuid::new_v4().simple().to_string()
+}
+
+// Log each leased message with its attempt number, so its deliveries can be
+// correlated with its enqueue through the trace id
+fn trace_leased(msgs: &[Message]) {
+    for m in msgs {
+        tracing::debug!(
+            message_id = m.id,
+            attempt = m.attempts + 1,
+            trace_id = m.trace_id.as_deref(),
+            "leased"
+        );
+    }
+}
+
+/// Enqueue a message into a queue by name
+#[tracing::instrument(level = "debug", skip_all, fields(queue = %queue_name))]
+pub async fn enqueue_message(
+    db: &Db,
+    queue_name: &str,
+    payload: &Value,
+    delay_ms: i64,
+) -> Result<Message> {
+    let opts = EnqueueOptions {
+        delay_ms: Some(delay_ms),
+        ..EnqueueOptions::default()
+    };
+    enqueue_message_with(db, queue_name, payload, &opts).await
+}
+
+/// Enqueue a message into a queue by name with explicit options
+#[tracing::instrument(level = "debug", skip_all, fields(queue = %queue_name))]
+pub async fn enqueue_message_with(
+    db: &Db,
+    queue_name: &str,
+    payload: &Value,
+    opts: &EnqueueOptions,
+) -> Result<Message> {
+    check_options(opts)?;
+    let q = db
+        .get_queue_by_name(queue_name)
+        .await?
+        .ok_or_else(|| SqewError::QueueNotFound(queue_name.to_string()))?;
+    let now = db::now_ms();
+    let mut msg = new_message(&q, payload, opts, now);
+    check_payload(&q, payload, &mut msg)?;
+    wait_for_room(db, &q, 1, opts.wait_ms).await?;
+    let mut timer = OpTimer::start(Op::Enqueue, 1);
+    if msg.dedup_key.is_some() {
+        let (id, inserted) = db
+            .enqueue_message_dedup(&msg, q.dedup_window_ms)
+            .await
+            .context("Failed to enqueue message")?;
+        timer.rows(inserted as usize);
+        if !inserted {
+            tracing::debug!(message_id = id, "duplicate of held dedup key");
+            // Duplicate: hand back the message that already holds the key
+            return db
+                .get_message_by_id(id)
+                .await?
+                .ok_or(SqewError::MessageNotFound(id));
+        }
+        tracing::debug!(
+            message_id = id,
+            trace_id = msg.trace_id.as_deref(),
+            "enqueued"
+        );
+        return Ok(Message { id, ..msg });
+    }
+    let id =
+        db.enqueue_message(&msg).await.context("Failed to enqueue message")?;
+    timer.rows(1);
+    tracing::debug!(
+        message_id = id,
+        trace_id = msg.trace_id.as_deref(),
+        "enqueued"
+    );
+    // Build the result from the inserted row rather than re-reading it: a
+    // fast consumer may already have polled and acked the message.
+    Ok(Message { id, ..msg })
+}
+
+/// Header naming the media type of a binary payload
+pub const CONTENT_TYPE_HEADER: &str = "content-type";
+
+/// Whether the media type `content_type` is JSON: `application/json` or a
+/// `+json` type, parameters aside
+pub fn is_json_content_type(content_type: &str) -> bool {
+    let essence = content_type.split(';').next().unwrap_or("").trim();
+    let essence = essence.to_ascii_lowercase();
+    essence == "application/json" || essence.ends_with("+json")
+}
+
+/// The payload of a message as `(content type, bytes)`. A binary payload,
+/// one enqueued with a non-JSON [`CONTENT_TYPE_HEADER`], is stored as a JSON
+/// string holding the bytes in base64 and decoded back; any other payload is
+/// its JSON text.
+pub fn payload_bytes(msg: &Message) -> Result<(String, Vec<u8>)> {
+    let content_type =
+        msg.headers.as_ref().and_then(|h| h.get(CONTENT_TYPE_HEADER));
+    match content_type {
+        Some(ct) if !is_json_content_type(ct) => {
+            let encoded: String =
+                serde_json::from_str(&msg.payload).map_err(|_| {
+                    SqewError::Invalid(format!(
+                        "message {}: binary payload is not a string",
+                        msg.id
+                    ))
+                })?;
+            let bytes = base64::engine::general_purpose::STANDARD
+                .decode(encoded)
+                .map_err(|e| {
+                    SqewError::Invalid(format!("message {}: {e}", msg.id))
+                })?;
+            Ok((ct.clone(), bytes))
+        }
+        _ => Ok(("application/json".into(), msg.payload.clone().into_bytes())),
+    }
+}
+
+/// Enqueue `bytes` of the media type `content_type`. JSON types are parsed
+/// and enqueued as JSON; other bytes, e.g. protobuf or msgpack, are kept as
+/// they are (see [`payload_bytes`]) with `content_type` in the message's
+/// [`CONTENT_TYPE_HEADER`].
+pub async fn enqueue_bytes(
+    db: &Db,
+    queue_name: &str,
+    bytes: &[u8],
+    content_type: &str,
+    opts: &EnqueueOptions,
+) -> Result<Message> {
+    if is_json_content_type(content_type) {
+        let payload: Value = serde_json::from_slice(bytes)
+            .map_err(|e| SqewError::Invalid(format!("JSON payload: {e}")))?;
+        return enqueue_message_with(db, queue_name, &payload, opts).await;
+    }
+    let payload = binary_payload(bytes);
+    let mut headers = opts.headers.clone().unwrap_or_default();
+    headers.insert(CONTENT_TYPE_HEADER.into(), content_type.into());
+    let opts = EnqueueOptions { headers: Some(headers), ..opts.clone() };
+    enqueue_message_with(db, queue_name, &payload, &opts).await
+}
+
+/// The JSON payload a binary payload is stored as: its bytes in base64
+pub fn binary_payload(bytes: &[u8]) -> Value {
+    Value::String(base64::engine::general_purpose::STANDARD.encode(bytes))
+}
+
+/// How often an enqueue waiting for room in a full queue checks again
+const QUEUE_FULL_RECHECK: Duration = Duration::from_millis(100);
+
+// Wait up to `wait_ms` until `q` has room for `incoming` more unacked
+// messages under its `max_depth`. The depth is counted before inserting, so
+// producers racing for the last slots may overshoot it slightly.
+async fn wait_for_room(
+    db: &Db,
+    q: &Queue,
+    incoming: i64,
+    wait_ms: Option<i64>,
+) -> Result<()> {
+    let Some(max_depth) = q.max_depth else {
+        return Ok(());
+    };
+    let wait = Duration::from_millis(wait_ms.unwrap_or(0).max(0) as u64);
+    let deadline = tokio::time::Instant::now() + wait;
+    loop {
+        let depth = db
+            .count_queued_messages_by_queue(q.id)
+            .await
+            .context("Failed to count messages")?;
+        if depth + incoming <= max_depth {
+            return Ok(());
+        }
+        let left = deadline - tokio::time::Instant::now();
+        if left.is_zero() {
+            return Err(SqewError::QueueFull {
+                queue: q.name.clone(),
+                max_depth,
+            });
+        }
+        tokio::time::sleep(left.min(QUEUE_FULL_RECHECK)).await;
+    }
+}
+
+// Reject options scheduling a message both after a delay and at a time,
+// giving an empty fair key or a sort key that is not a finite number
+fn check_options(opts: &EnqueueOptions) -> Result<()> {
+    if opts.deliver_at.is_some() && opts.delay_ms.is_some() {
+        return Err(SqewError::Invalid(
+            "deliver_at: conflicts with delay_ms; give one or the other".into(),
+        ));
+    }
+    if opts.fair_key.as_deref().is_some_and(str::is_empty) {
+        return Err(SqewError::Invalid("fair_key: must not be empty".into()));
+    }
+    if opts.sort_key.is_some_and(|k| !k.is_finite()) {
+        return Err(SqewError::Invalid(
+            "sort_key: must be a finite number".into(),
+        ));
+    }
+    if opts.reply_to.as_deref().is_some_and(str::is_empty) {
+        return Err(SqewError::Invalid("reply_to: must not be empty".into()));
+    }
+    Ok(())
+}
+
+/// Parse an RFC 3339 time such as `2025-06-02T09:00:00+02:00` into Unix
+/// milliseconds, for [`EnqueueOptions::deliver_at`]. The UTC offset (`Z`
+/// for UTC) is required, so a time means the same wherever it is sent from.
+pub fn parse_deliver_at(s: &str) -> Result<i64> {
+    parse_time("deliver_at", s)
+}
+
+// Parse an RFC 3339 time with a UTC offset into Unix milliseconds; errors
+// name the `field` it was given for
+fn parse_time(
+    field: &str,
+    s: &str,
+) -> Result<i64> {
+    let at = chrono::DateTime::parse_from_rfc3339(s.trim()).map_err(|e| {
+        SqewError::Invalid(format!(
+            "{} '{}': {}; expected an RFC 3339 time with a UTC offset, e.g. 2025-06-02T09:00:00+02:00",
+            field, s, e
+        ))
+    })?;
+    Ok(at.timestamp_millis())
+}
+
+// The row of a message enqueued into `q` at `now`
+fn new_message(
+    q: &Queue,
+    payload: &Value,
+    opts: &EnqueueOptions,
+    now: i64,
+) -> Message {
+    Message {
+        id: 0,
+        queue_id: q.id,
+        payload: payload.to_string(),
+        attempts: 0,
+        available_at: match opts.deliver_at {
+            Some(at) => at.max(now),
+            None => now + opts.delay_ms.unwrap_or(q.default_delay_ms).max(0),
+        },
+        created_at: now,
+        dead_at: None,
+        lease_token: None,
+        priority: opts.priority,
+        expires_at: opts.ttl_ms.map(|ttl| now + ttl.max(0)),
+        dedup_key: opts.dedup_key.clone(),
+        group_id: opts.group_id.clone(),
+        headers: opts.headers.clone().filter(|h| !h.is_empty()),
+        trace_id: Some(opts.trace_id.clone().unwrap_or_else(new_trace_id)),
+        last_error: None,
+        fair_key: opts.fair_key.clone(),
+        lease_expirations: 0,
+        sort_key: opts.sort_key,
+        reply_to: opts.reply_to.clone(),
+        public_id: db::new_public_id(now),
+    }
+}
+
+/// Begin a transaction on the SQLite database behind `db`, in which an
+/// embedding application can write its own rows and enqueue messages with
+/// [`enqueue_message_tx`] atomically
+pub async fn begin_transaction(
+    db: &Db
+) -> Result<Transaction<'static, Sqlite>> {
+    let sqlite = as_sqlite(db)?;
+    sqlite.pool().begin().await.context("Failed to begin transaction")
+}
+
+// The SQLite storage behind `db`, required by transactional enqueue
+fn as_sqlite(db: &Db) -> Result<&SqliteStorage> {
+    db.as_sqlite().ok_or_else(|| {
+        SqewError::Unsupported(
+            "Transactional enqueue requires the SQLite backend".into(),
+        )
+    })
+}
+
+/// Enqueue a message as part of the caller's transaction, begun with
+/// [`begin_transaction`] on the same `db`: it reaches consumers only once the
+/// transaction commits and is discarded if it rolls back. Long-polling
+/// consumers of a server are not woken early; they find the message on their
+/// next poll.
+#[tracing::instrument(level = "debug", skip_all, fields(queue = %queue_name))]
+pub async fn enqueue_message_tx(
+    db: &Db,
+    tx: &mut Transaction<'_, Sqlite>,
+    queue_name: &str,
+    payload: &Value,
+    opts: &EnqueueOptions,
+) -> Result<Message> {
+    check_options(opts)?;
+    let q = db::sqlite::find_queue(&mut **tx, queue_name)
+        .await?
+        .ok_or_else(|| SqewError::QueueNotFound(queue_name.to_string()))?;
+    let now = db::now_ms();
+    let mut msg = new_message(&q, payload, opts, now);
+    check_payload(&q, payload, &mut msg)?;
+    // Never wait here: the caller's transaction may hold the write lock
+    wait_for_room(db, &q, 1, None).await?;
+    let sqlite = as_sqlite(db)?;
+    let (id, inserted) = sqlite
+        .enqueue_message_tx(tx, &msg)
+        .await
+        .context("Failed to enqueue message")?;
+    if !inserted {
+        tracing::debug!(message_id = id, "duplicate of held dedup key");
+        return sqlite
+            .find_message(&mut **tx, id)
+            .await?
+            .ok_or(SqewError::MessageNotFound(id));
+    }
+    tracing::debug!(
+        message_id = id,
+        trace_id = msg.trace_id.as_deref(),
+        "enqueued"
+    );
+    Ok(Message { id, ..msg })
+}
+
+/// Most messages one [`enqueue_transaction`] may enqueue
+pub const MAX_TRANSACTION_MESSAGES: usize = 1000;
+
+/// Enqueue messages into any number of queues in one transaction: `messages`
+/// pairs queue names with payloads and options, and either all of them are
+/// enqueued or, on any error, none is. Returns the messages in the order
+/// given. Queues with a `max_depth` must have room for all of their messages
+/// up front; nothing waits for room. Requires the SQLite backend.
+#[tracing::instrument(level = "debug", skip_all, fields(n = messages.len()))]
+pub async fn enqueue_transaction(
+    db: &Db,
+    messages: &[(String, Value, EnqueueOptions)],
+) -> Result<Vec<Message>> {
+    if messages.is_empty() || messages.len() > MAX_TRANSACTION_MESSAGES {
+        return Err(SqewError::Invalid(format!(
+            "transaction of {} message(s): must be 1 to {}",
+            messages.len(),
+            MAX_TRANSACTION_MESSAGES
+        )));
+    }
+    as_sqlite(db)?;
+    let mut incoming = std::collections::BTreeMap::new();
+    for (queue_name, _, _) in messages {
+        *incoming.entry(queue_name.as_str()).or_insert(0) += 1;
+    }
+    for (queue_name, n) in incoming {
+        let q = show_queue(db, queue_name).await?;
+        wait_for_room(db, &q, n, None).await?;
+    }
+    let mut tx = begin_transaction(db).await?;
+    let mut created = Vec::with_capacity(messages.len());
+    for (queue_name, payload, opts) in messages {
+        created.push(
+            enqueue_message_tx(db, &mut tx, queue_name, payload, opts).await?,
+        );
+    }
+    tx.commit().await.context("Failed to commit transaction")?;
+    Ok(created)
+}
+
+/// Delete messages whose TTL has passed; returns how many were expired
+#[tracing::instrument(level = "debug", skip_all)]
+pub async fn expire_messages(db: &Db) -> Result<u64> {
+    let now = db::now_ms();
+    db.expire_messages(now).await.context("Failed to expire messages")
+}
+
+/// Requeue or dead-letter messages whose lease expired without an ack or
+/// nack, counting each expiry as an attempt; returns `(requeued, dead_lettered)`
+#[tracing::instrument(level = "debug", skip_all)]
+pub async fn reap_expired_leases(db: &Db) -> Result<(u64, u64)> {
+    let now = db::now_ms();
+    let (requeued, dead) = db
+        .reap_expired_leases(now)
+        .await
+        .context("Failed to reap expired leases")?;
+    if requeued + dead > 0 {
+        tracing::debug!(
+            requeued,
+            dead_lettered = dead,
+            "reaped expired leases"
+        );
+    }
+    Ok((requeued, dead))
+}
+
+/// Fetch a message by id
+#[tracing::instrument(level = "debug", skip_all, fields(message_id = id))]
+pub async fn get_message_by_id(
+    db: &Db,
+    id: i64,
+) -> Result<Message> {
+    db.get_message_by_id(id)
+        .await
+        .context("Failed to fetch message")?
+        .ok_or(SqewError::MessageNotFound(id))
+}
+
+/// The integer id of the message `r` names
+pub async fn resolve_message_id(
+    db: &Db,
+    r: &MessageRef,
+) -> Result<i64> {
+    Ok(resolve_message_ids(db, std::slice::from_ref(r)).await?[0])
+}
+
+/// The integer ids of the messages `refs` name, in order. Public ids are
+/// looked up in one query; one that names no message is an error, as is
+/// the public id of a message already acked.
+pub async fn resolve_message_ids(
+    db: &Db,
+    refs: &[MessageRef],
+) -> Result<Vec<i64>> {
+    let public: Vec<String> = refs
+        .iter()
+        .filter_map(|r| match r {
+            MessageRef::Public(p) => Some(p.to_ascii_lowercase()),
+            MessageRef::Id(_) => None,
+        })
+        .collect();
+    let found: std::collections::HashMap<String, i64> = db
+        .find_message_ids(&public)
+        .await
+        .context("Failed to look up public ids")?
+        .into_iter()
+        .collect();
+    refs.iter()
+        .map(|r| match r {
+            MessageRef::Id(id) => Ok(*id),
+            MessageRef::Public(p) => found
+                .get(&p.to_ascii_lowercase())
+                .copied()
+                .ok_or_else(|| SqewError::PublicIdNotFound(p.clone())),
+        })
+        .collect()
+}
+
+/// Poll (lease) up to `limit` visible messages; set visibility to now + visibility_ms
+pub async fn poll_messages(
+    db: &Db,
+    queue_name: &str,
+    limit: i64,
+    visibility_ms: i64,
+) -> Result<Vec<Message>> {
+    poll_messages_as(db, queue_name, limit, visibility_ms, None).await
+}
+
+/// Poll as [`poll_messages`] does, naming the consumer in the messages'
+/// attempt logs
+#[tracing::instrument(level = "debug", skip_all, fields(queue = %queue_name, batch = limit))]
+pub async fn poll_messages_as(
+    db: &Db,
+    queue_name: &str,
+    limit: i64,
+    visibility_ms: i64,
+    consumer: Option<&str>,
+) -> Result<Vec<Message>> {
+    let mut timer = OpTimer::start(Op::Poll, limit.max(0) as usize);
+    let msgs = db
+        .poll_messages(queue_name, limit, visibility_ms)
+        .await
+        .context("Failed to poll messages")?;
+    timer.rows(msgs.len());
+    if msgs.is_empty() && !db.list_consumer_groups(queue_name).await?.is_empty()
+    {
+        return Err(SqewError::Invalid(format!(
+            "poll: queue '{}' has consumer groups; poll with a group",
+            queue_name
+        )));
+    }
+    trace_leased(&msgs);
+    if let Err(e) = db.record_attempts(&msgs, consumer, db::now_ms()).await {
+        // The messages are leased either way; losing their log entries must
+        // not lose the delivery
+        tracing::warn!("Failed to log delivery attempts: {e}");
+    }
+    Ok(msgs)
+}
+
+/// The messages of a queue currently leased by plain polls, with the
+/// consumer holding each and how long it has held it
+#[tracing::instrument(level = "debug", skip_all, fields(queue = %name, limit))]
+pub async fn in_flight(
+    db: &Db,
+    name: &str,
+    limit: i64,
+) -> Result<Vec<InFlightMessage>> {
+    show_queue(db, name).await?;
+    db.list_in_flight(name, db::now_ms(), limit)
+        .await
+        .context("Failed to list in-flight messages")
+}
+
+/// How long the server keeps message attempt logs (7 days)
+pub const ATTEMPT_LOG_RETENTION_MS: i64 = 7 * 86_400_000;
+
+/// The logged delivery attempts of a message, oldest first. The log
+/// outlives the message itself (for [`ATTEMPT_LOG_RETENTION_MS`]), so the
+/// attempts of acked and removed messages can still be read.
+#[tracing::instrument(level = "debug", skip_all, fields(message_id = id))]
+pub async fn message_attempts(
+    db: &Db,
+    id: i64,
+) -> Result<Vec<MessageAttempt>> {
+    let attempts =
+        db.list_attempts(id).await.context("Failed to list attempts")?;
+    if attempts.is_empty() {
+        // Tell a message never delivered from one that does not exist
+        get_message_by_id(db, id).await?;
+    }
+    Ok(attempts)
+}
+
+/// The name of the queue message `id` is on, or was on when its logged
+/// attempts were made if it has been acked or removed since
+pub async fn message_queue(
+    db: &Db,
+    id: i64,
+) -> Result<String> {
+    let attempts =
+        db.list_attempts(id).await.context("Failed to list attempts")?;
+    let queue_id = match attempts.first() {
+        Some(a) => a.queue_id,
+        None => get_message_by_id(db, id).await?.queue_id,
+    };
+    let queues = db.list_queues().await?;
+    queues
+        .into_iter()
+        .find(|q| q.id == queue_id)
+        .map(|q| q.name)
+        .ok_or(SqewError::MessageNotFound(id))
+}
+
+// Record how the attempts leased under `lease_token` ended. The messages
+// are settled either way, so a failure is only logged.
+async fn settle_attempts(
+    db: &Db,
+    ids: &[i64],
+    lease_token: &str,
+    outcome: &str,
+    note: Option<&str>,
+) {
+    let settled =
+        db.settle_attempts(ids, lease_token, outcome, note, db::now_ms()).await;
+    if let Err(e) = settled {
+        tracing::warn!("Failed to log {outcome} attempts: {e}");
+    }
+}
+
+/// A message whose payload was decoded as (or encoded from) a `T`
+#[derive(Debug)]
+pub struct TypedMessage<T> {
+    /// The message itself, including its raw payload and lease token
+    pub message: Message,
+    pub payload: T,
+}
+
+/// A leased message whose payload did not decode as the requested type
+#[derive(Debug, thiserror::Error)]
+#[error("Message {} payload does not decode: {source}", .message.id)]
+pub struct DecodeError {
+    pub message: Message,
+    pub source: serde_json::Error,
+    /// Whether the message was nacked back to the queue (counting an attempt)
+    pub nacked: bool,
+}
+
+/// Messages leased by [`poll_typed`]: those that decoded, and those that
+/// did not
+#[derive(Debug)]
+pub struct TypedPoll<T> {
+    pub messages: Vec<TypedMessage<T>>,
+    pub failures: Vec<DecodeError>,
+}
+
+/// Serialize `payload` as JSON and enqueue it
+pub async fn enqueue_typed<T: serde::Serialize>(
+    db: &Db,
+    queue_name: &str,
+    payload: T,
+    opts: &EnqueueOptions,
+) -> Result<TypedMessage<T>> {
+    let value = serde_json::to_value(&payload)
+        .map_err(|e| SqewError::Invalid(format!("payload: {e}")))?;
+    let message = enqueue_message_with(db, queue_name, &value, opts).await?;
+    Ok(TypedMessage { message, payload })
+}
+
+/// Poll (lease) up to `limit` messages and decode their payloads as `T`.
+/// Messages that do not decode are returned as failures, still leased,
+/// unless `nack_failures` is set: then they are nacked right away so they
+/// are retried and, if they never decode, dead-lettered.
+pub async fn poll_typed<T: serde::de::DeserializeOwned>(
+    db: &Db,
+    queue_name: &str,
+    limit: i64,
+    visibility_ms: i64,
+    nack_failures: bool,
+) -> Result<TypedPoll<T>> {
+    let leased = poll_messages(db, queue_name, limit, visibility_ms).await?;
+    let mut polled = TypedPoll { messages: Vec::new(), failures: Vec::new() };
+    for message in leased {
+        match serde_json::from_str(&message.payload) {
+            Ok(payload) => {
+                polled.messages.push(TypedMessage { message, payload })
+            }
+            Err(source) => {
+                let nacked = nack_failures && {
+                    let token =
+                        message.lease_token.as_deref().unwrap_or_default();
+                    let (requeued, dead) =
+                        nack_messages(db, &[message.id], token, 0).await?;
+                    requeued + dead > 0
+                };
+                polled.failures.push(DecodeError { message, source, nacked });
+            }
+        }
+    }
+    Ok(polled)
+}
+
+/// Options for [`subscribe`]
+#[derive(Debug, Clone)]
+pub struct SubscribeOptions {
+    /// Messages leased per poll (default 10)
+    pub batch: i64,
+    /// Lease length (default: the queue's default visibility)
+    pub visibility_ms: Option<i64>,
+    /// Consumer named in the messages' attempt logs
+    pub consumer: Option<String>,
+    /// Wait before polling an empty queue again, doubled while it stays
+    /// empty up to `max_backoff` (default 50ms)
+    pub min_backoff: Duration,
+    /// Longest wait between polls of an empty queue (default 2s)
+    pub max_backoff: Duration,
+    /// Wakeups for the queue's enqueues, which end a wait at once
+    pub notifier: Option<Arc<QueueNotifier>>,
+}
+
+impl Default for SubscribeOptions {
+    fn default() -> Self {
+        SubscribeOptions {
+            batch: 10,
+            visibility_ms: None,
+            consumer: None,
+            min_backoff: Duration::from_millis(50),
+            max_backoff: Duration::from_secs(2),
+            notifier: None,
+        }
+    }
+}
+
+/// A message leased by [`subscribe`], settled with [`LeasedMessage::ack`]
+/// or [`LeasedMessage::nack`]. It derefs to the [`Message`].
+pub struct LeasedMessage {
+    db: Db,
+    message: Message,
+}
+
+impl LeasedMessage {
+    fn token(&self) -> &str {
+        self.message.lease_token.as_deref().unwrap_or_default()
+    }
+
+    /// Ack the message; false if its lease was lost first
+    pub async fn ack(self) -> Result<bool> {
+        let ids = [self.message.id];
+        Ok(ack_messages(&self.db, &ids, self.token()).await? > 0)
+    }
+
+    /// Return the message to the queue after `delay_ms`, or dead-letter it
+    /// on its last attempt; false if its lease was lost first
+    pub async fn nack(
+        self,
+        delay_ms: i64,
+    ) -> Result<bool> {
+        self.nack_with_reason(delay_ms, None).await
+    }
+
+    /// Nack as [`LeasedMessage::nack`] does, keeping `reason` as the
+    /// message's `last_error`
+    pub async fn nack_with_reason(
+        self,
+        delay_ms: i64,
+        reason: Option<&str>,
+    ) -> Result<bool> {
+        let nacks = [(self.message.id, delay_ms)];
+        let (requeued, dead) =
+            nack_messages_with_reason(&self.db, &nacks, self.token(), reason)
+                .await?;
+        Ok(requeued + dead > 0)
+    }
+
+    /// Extend the lease by `extra_ms`; false if it was lost first
+    pub async fn extend(
+        &self,
+        extra_ms: i64,
+    ) -> Result<bool> {
+        let ids = [self.message.id];
+        Ok(extend_visibility(&self.db, &ids, self.token(), extra_ms).await? > 0)
+    }
+
+    /// The message, leaving it leased
+    pub fn into_message(self) -> Message {
+        self.message
+    }
+}
+
+impl std::ops::Deref for LeasedMessage {
+    type Target = Message;
+
+    fn deref(&self) -> &Message {
+        &self.message
+    }
+}
+
+impl std::fmt::Debug for LeasedMessage {
+    fn fmt(
+        &self,
+        f: &mut std::fmt::Formatter<'_>,
+    ) -> std::fmt::Result {
+        f.debug_tuple("LeasedMessage").field(&self.message).finish()
+    }
+}
+
+/// Subscribe to `queue_name`: a stream of its messages, leased in batches
+/// as the stream is consumed. An empty queue is polled again after a
+/// backoff growing from `opts.min_backoff` to `opts.max_backoff`, cut short
+/// by an enqueue announced on `opts.notifier`. Poll errors are yielded and
+/// polling goes on, after the error's [`SqewError::retry_after`] hint when
+/// it has one; an unknown queue, or one with consumer groups, ends the
+/// stream after its error. Messages leased but not yet yielded when the
+/// stream is dropped are redelivered once their lease runs out. Must be
+/// called within a Tokio runtime.
+///
+/// ```no_run
+/// # async fn example(db: sqew::db::Db) -> sqew::error::Result<()> {
+/// use sqew::queue::{self, SubscribeOptions};
+/// use tokio_stream::StreamExt;
+///
+/// let mut jobs = queue::subscribe(&db, "jobs", SubscribeOptions::default());
+/// while let Some(msg) = jobs.next().await {
+///     let msg = msg?;
+///     println!("{}", msg.payload);
+///     msg.ack().await?;
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub fn subscribe(
+    db: &Db,
+    queue_name: &str,
+    opts: SubscribeOptions,
+) -> impl Stream<Item = Result<LeasedMessage>> + Send + Unpin + 'static {
+    let (tx, rx) = tokio::sync::mpsc::channel(1);
+    tokio::spawn(feed_subscription(
+        db.clone(),
+        queue_name.to_string(),
+        opts,
+        tx,
+    ));
+    ReceiverStream::new(rx)
+}
+
+// Poll for a subscription until its stream is dropped
+async fn feed_subscription(
+    db: Db,
+    name: String,
+    opts: SubscribeOptions,
+    tx: tokio::sync::mpsc::Sender<Result<LeasedMessage>>,
+) {
+    let visibility_ms = match opts.visibility_ms {
+        Some(ms) => ms,
+        None => match show_queue(&db, &name).await {
+            Ok(q) => q.default_visibility_ms,
+            Err(e) => {
+                let _ = tx.send(Err(e)).await;
+                return;
+            }
+        },
+    };
+    let wakeup =
+        opts.notifier.as_ref().map(|n| n.handle(&name)).unwrap_or_default();
+    let mut backoff = opts.min_backoff;
+    loop {
+        // Register for wakeups before polling so an enqueue landing between
+        // the poll and the wait is not missed
+        let notified = wakeup.notified();
+        tokio::pin!(notified);
+        notified.as_mut().enable();
+
+        let polled = poll_messages_as(
+            &db,
+            &name,
+            opts.batch.max(1),
+            visibility_ms,
+            opts.consumer.as_deref(),
+        )
+        .await;
+        let pause = match polled {
+            Ok(msgs) if !msgs.is_empty() => {
+                backoff = opts.min_backoff;
+                for message in msgs {
+                    let leased = LeasedMessage { db: db.clone(), message };
+                    if tx.send(Ok(leased)).await.is_err() {
+                        return;
+                    }
+                }
+                continue;
+            }
+            Ok(_) => backoff,
+            // Polling again cannot succeed
+            Err(e @ (SqewError::QueueNotFound(_) | SqewError::Invalid(_))) => {
+                let _ = tx.send(Err(e)).await;
+                return;
+            }
+            Err(e) => {
+                let pause = e.retry_after().unwrap_or(backoff);
+                if tx.send(Err(e)).await.is_err() {
+                    return;
+                }
+                pause
+            }
+        };
+        backoff = (backoff * 2).min(opts.max_backoff.max(opts.min_backoff));
+        tokio::select! {
+            _ = notified => backoff = opts.min_backoff,
+            _ = tokio::time::sleep(pause) => {}
+            _ = tx.closed() => return,
+        }
+    }
+}
+
+/// Header carrying the id that matches a [`call`]'s request to its reply
+pub const CORRELATION_ID_HEADER: &str = "correlation-id";
+
+// How often a `call` checks its reply queue
+const REPLY_RECHECK: Duration = Duration::from_millis(50);
+
+/// Enqueue `payload` into `queue` as a request and wait up to `timeout` for
+/// the reply a consumer sends with [`respond`]. The request carries a fresh
+/// [`CORRELATION_ID_HEADER`] and, as its `reply_to`, a queue created for the
+/// call and deleted once it ends. It expires with the timeout, so no
+/// consumer picks it up after the caller gave up. Fails with
+/// [`SqewError::NoReply`] on timeout, and as soon as the request is
+/// dead-lettered or acked without a reply.
+#[tracing::instrument(level = "debug", skip_all, fields(queue = %queue_name))]
+pub async fn call(
+    db: &Db,
+    queue_name: &str,
+    payload: &Value,
+    timeout: Duration,
+) -> Result<Message> {
+    let correlation_id = uuid::Uuid::new_v4().simple().to_string();
+    let reply_queue = format!("{queue_name}.reply.{correlation_id}");
+    create_queue(db, &reply_queue, 1).await?;
+    let reply = await_reply(
+        db,
+        queue_name,
+        &reply_queue,
+        &correlation_id,
+        payload,
+        timeout,
+    )
+    .await;
+    delete_queue(db, &reply_queue).await?;
+    reply
+}
+
+// Enqueue a `call`'s request and wait for its reply, or the receipt of the
+// request ending without one, in `reply_queue`
+async fn await_reply(
+    db: &Db,
+    queue_name: &str,
+    reply_queue: &str,
+    correlation_id: &str,
+    payload: &Value,
+    timeout: Duration,
+) -> Result<Message> {
+    let header = (CORRELATION_ID_HEADER.into(), correlation_id.into());
+    let opts = EnqueueOptions {
+        ttl_ms: Some((timeout.as_millis() as i64).max(1)),
+        headers: Some(Headers::from([header])),
+        reply_to: Some(reply_queue.into()),
+        ..EnqueueOptions::default()
+    };
+    let request = enqueue_message_with(db, queue_name, payload, &opts).await?;
+    let no_reply = |reason: String| SqewError::NoReply {
+        queue: queue_name.to_string(),
+        message_id: request.id,
+        reason,
+    };
+    let deadline = tokio::time::Instant::now() + timeout;
+    loop {
+        for m in peek_queue(db, reply_queue, 10).await? {
+            let replied = m.headers.as_ref().and_then(|h| {
+                h.get(CORRELATION_ID_HEADER).filter(|id| *id == correlation_id)
+            });
+            if replied.is_some() {
+                return Ok(m);
+            }
+            // Anything else is a receipt: `respond` enqueues the reply
+            // before acking, so an acked request went unanswered
+            let receipt: Value =
+                serde_json::from_str(&m.payload).unwrap_or_default();
+            if receipt["message_id"] != request.id {
+                continue;
+            }
+            match receipt["outcome"].as_str() {
+                Some("dead") => return Err(no_reply("dead-lettered".into())),
+                Some("acked") => {
+                    return Err(no_reply("acked without a reply".into()));
+                }
+                _ => {}
+            }
+        }
+        let left =
+            deadline.saturating_duration_since(tokio::time::Instant::now());
+        if left.is_zero() {
+            return Err(no_reply(format!("timed out after {timeout:?}")));
+        }
+        tokio::time::sleep(left.min(REPLY_RECHECK)).await;
+    }
+}
+
+/// Answer a request sent with [`call`]: enqueue `payload` into its reply
+/// queue under its correlation id, then ack it under its lease. Returns
+/// whether the reply was delivered; it is not once the caller gave up and
+/// its reply queue is gone. Fails with [`SqewError::Invalid`] for a message
+/// that is not such a request.
+#[tracing::instrument(level = "debug", skip_all, fields(message_id = request.id))]
+pub async fn respond(
+    db: &Db,
+    request: &Message,
+    payload: &Value,
+) -> Result<bool> {
+    let correlation_id =
+        request.headers.as_ref().and_then(|h| h.get(CORRELATION_ID_HEADER));
+    let (Some(reply_queue), Some(correlation_id)) =
+        (request.reply_to.as_deref(), correlation_id)
+    else {
+        return Err(SqewError::Invalid(format!(
+            "message {}: not a request; it needs a reply_to and a {CORRELATION_ID_HEADER} header",
+            request.id
+        )));
+    };
+    let header = (CORRELATION_ID_HEADER.into(), correlation_id.clone());
+    let opts = EnqueueOptions {
+        headers: Some(Headers::from([header])),
+        trace_id: request.trace_id.clone(),
+        ..EnqueueOptions::default()
+    };
+    let delivered =
+        match enqueue_message_with(db, reply_queue, payload, &opts).await {
+            Ok(_) => true,
+            Err(SqewError::QueueNotFound(_)) => false,
+            Err(e) => return Err(e),
+        };
+    let token = request.lease_token.as_deref().unwrap_or_default();
+    ack_messages(db, &[request.id], token).await?;
+    Ok(delivered)
+}
+
+/// Poll (lease) up to `limit` messages for a consumer group. Each group
+/// receives every message enqueued after it was created, independently of
+/// the other groups.
+#[tracing::instrument(level = "debug", skip_all, fields(queue = %queue_name, group = %group, batch = limit))]
+pub async fn poll_group_messages(
+    db: &Db,
+    queue_name: &str,
+    group: &str,
+    limit: i64,
+    visibility_ms: i64,
+) -> Result<Vec<Message>> {
+    let groups = list_consumer_groups(db, queue_name).await?;
+    if !groups.iter().any(|g| g.name == group) {
+        return Err(SqewError::GroupNotFound {
+            queue: queue_name.to_string(),
+            group: group.to_string(),
+        });
+    }
+    let msgs = db
+        .poll_group_messages(queue_name, group, limit, visibility_ms)
+        .await
+        .context("Failed to poll messages")?;
+    trace_leased(&msgs);
+    Ok(msgs)
+}
+
+/// Create a consumer group on a queue. Once a queue has groups, its messages
+/// are delivered to each group and deleted after every group acked them.
+#[tracing::instrument(level = "debug", skip_all, fields(queue = %queue_name, group = %group))]
+pub async fn create_consumer_group(
+    db: &Db,
+    queue_name: &str,
+    group: &str,
+) -> Result<ConsumerGroup> {
+    let q = show_queue(db, queue_name).await?;
+    let groups = db.list_consumer_groups(queue_name).await?;
+    if groups.iter().any(|g| g.name == group) {
+        return Err(SqewError::GroupExists {
+            queue: queue_name.to_string(),
+            group: group.to_string(),
+        });
+    }
+    let now = db::now_ms();
+    let g = ConsumerGroup {
+        id: 0,
+        queue_id: q.id,
+        name: group.to_string(),
+        start_id: 0,
+        created_at: now,
+    };
+    db.create_consumer_group(&g)
+        .await
+        .context("Failed to create consumer group")?;
+    db.list_consumer_groups(queue_name)
+        .await?
+        .into_iter()
+        .find(|g| g.name == group)
+        .ok_or_else(|| SqewError::GroupNotFound {
+            queue: queue_name.to_string(),
+            group: group.to_string(),
+        })
+}
+
+/// List the consumer groups of a queue
+#[tracing::instrument(level = "debug", skip_all, fields(queue = %queue_name))]
+pub async fn list_consumer_groups(
+    db: &Db,
+    queue_name: &str,
+) -> Result<Vec<ConsumerGroup>> {
+    show_queue(db, queue_name).await?;
+    db.list_consumer_groups(queue_name)
+        .await
+        .context("Failed to list consumer groups")
+}
+
+/// Delete a consumer group; returns whether it existed
+#[tracing::instrument(level = "debug", skip_all, fields(queue = %queue_name, group = %group))]
+pub async fn delete_consumer_group(
+    db: &Db,
+    queue_name: &str,
+    group: &str,
+) -> Result<bool> {
+    show_queue(db, queue_name).await?;
+    let n = db
+        .delete_consumer_group(queue_name, group)
+        .await
+        .context("Failed to delete consumer group")?;
+    Ok(n > 0)
+}
+
+/// Most message IDs one [`ack_batch`] or [`nack_batch`] may name
+pub const MAX_ACK_BATCH: usize = 1000;
+
+/// Longest nack reason kept on a message; longer ones are cut short
+pub const MAX_NACK_REASON_BYTES: usize = 8 * 1024;
+
+// `reason` cut to at most MAX_NACK_REASON_BYTES, on a character boundary
+fn truncate_reason(reason: &str) -> &str {
+    let mut end = reason.len().min(MAX_NACK_REASON_BYTES);
+    while !reason.is_char_boundary(end) {
+        end -= 1;
+    }
+    &reason[..end]
+}
+
+/// What an ack or nack did to one message of a batch
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    serde::Serialize,
+    serde::Deserialize,
+    utoipa::ToSchema,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum AckStatus {
+    /// Deleted (or archived)
+    Acked,
+    /// Visible again after its delay
+    Requeued,
+    DeadLettered,
+    /// No such message: already acked, expired or never existed
+    NotFound,
+    /// The message exists but is not leased under the token, or the lease
+    /// expired; the caller should treat it as lost and may see it again
+    LeaseMismatch,
+}
+
+/// The outcome of one message of a batch ack or nack
+#[derive(
+    Debug,
+    Clone,
+    PartialEq,
+    Eq,
+    serde::Serialize,
+    serde::Deserialize,
+    utoipa::ToSchema,
+)]
+pub struct AckResult {
+    pub id: i64,
+    pub status: AckStatus,
+}
+
+/// Ack (delete) messages by IDs under their lease token; returns how many were deleted.
+/// Messages whose lease expired or is held under another token are left untouched.
+#[tracing::instrument(level = "debug", skip_all, fields(ids = ?ids))]
+pub async fn ack_messages(
+    db: &Db,
+    ids: &[i64],
+    lease_token: &str,
+) -> Result<u64> {
+    Ok(acked_ids(db, ids, lease_token).await?.len() as u64)
+}
+
+/// Ack up to [`MAX_ACK_BATCH`] messages under their lease token, reporting
+/// per message whether it was acked, not found or held under another lease
+#[tracing::instrument(level = "debug", skip_all, fields(ids = ?ids))]
+pub async fn ack_batch(
+    db: &Db,
+    ids: &[i64],
+    lease_token: &str,
+) -> Result<Vec<AckResult>> {
+    check_batch_size(ids.len())?;
+    let acked = acked_ids(db, ids, lease_token).await?;
+    let mut results = Vec::with_capacity(ids.len());
+    for &id in ids {
+        let status = if acked.contains(&id) {
+            AckStatus::Acked
+        } else {
+            unapplied_status(db, id).await?
+        };
+        results.push(AckResult { id, status });
+    }
+    Ok(results)
+}
+
+// Ack plain and consumer group leases, returning the IDs acked
+async fn acked_ids(
+    db: &Db,
+    ids: &[i64],
+    lease_token: &str,
+) -> Result<Vec<i64>> {
+    let mut timer = OpTimer::start(Op::Ack, ids.len());
+    let mut acked = db
+        .ack_messages(ids, lease_token)
+        .await
+        .context("Failed to ack messages")?;
+    settle_attempts(db, &acked, lease_token, "acked", None).await;
+    if acked.len() < ids.len() {
+        // The lease may belong to a consumer group
+        acked.extend(
+            db.ack_group_messages(ids, lease_token)
+                .await
+                .context("Failed to ack messages")?,
+        );
+    }
+    timer.rows(acked.len());
+    tracing::debug!(acked = acked.len(), "acked");
+    Ok(acked)
+}
+
+fn check_batch_size(n: usize) -> Result<()> {
+    if n > MAX_ACK_BATCH {
+        return Err(SqewError::Invalid(format!(
+            "batch of {n} messages: at most {MAX_ACK_BATCH} allowed"
+        )));
+    }
+    Ok(())
+}
+
+// Why an ack or nack left a message alone
+async fn unapplied_status(
+    db: &Db,
+    id: i64,
+) -> Result<AckStatus> {
+    let found =
+        db.get_message_by_id(id).await.context("Failed to look up message")?;
+    Ok(match found {
+        Some(_) => AckStatus::LeaseMismatch,
+        None => AckStatus::NotFound,
+    })
+}
+
+/// Nack messages: increment attempts and requeue with delay; dead-letters if attempts exceed max_attempts
+#[tracing::instrument(level = "debug", skip_all, fields(ids = ?ids, delay_ms))]
+pub async fn nack_messages(
+    db: &Db,
+    ids: &[i64],
+    lease_token: &str,
+    delay_ms: i64,
+) -> Result<(u64, u64)> {
+    let nacks: Vec<(i64, i64)> = ids.iter().map(|&id| (id, delay_ms)).collect();
+    nack_messages_with_delays(db, &nacks, lease_token).await
+}
+
+/// Nack messages, each requeued after its own delay: `nacks` pairs message
+/// ids with delays in milliseconds. Queues with backoff configured use their
+/// backoff instead, as with [`nack_messages`].
+#[tracing::instrument(level = "debug", skip_all, fields(nacks = ?nacks))]
+pub async fn nack_messages_with_delays(
+    db: &Db,
+    nacks: &[(i64, i64)],
+    lease_token: &str,
+) -> Result<(u64, u64)> {
+    nack_messages_with_reason(db, nacks, lease_token, None).await
+}
+
+/// Nack as [`nack_messages_with_delays`] does, keeping `reason` (why the
+/// messages failed, e.g. an error message or stack trace) as their
+/// `last_error` and in their attempt logs. Reasons longer than
+/// [`MAX_NACK_REASON_BYTES`] are truncated.
+pub async fn nack_messages_with_reason(
+    db: &Db,
+    nacks: &[(i64, i64)],
+    lease_token: &str,
+    reason: Option<&str>,
+) -> Result<(u64, u64)> {
+    let (requeued, dead) = nacked_ids(db, nacks, lease_token, reason).await?;
+    Ok((requeued.len() as u64, dead.len() as u64))
+}
+
+/// Nack up to [`MAX_ACK_BATCH`] messages as [`nack_messages_with_delays`]
+/// does, reporting per message whether it was requeued, dead-lettered, not
+/// found or held under another lease
+#[tracing::instrument(level = "debug", skip_all, fields(nacks = ?nacks))]
+pub async fn nack_batch(
+    db: &Db,
+    nacks: &[(i64, i64)],
+    lease_token: &str,
+) -> Result<Vec<AckResult>> {
+    nack_batch_with_reason(db, nacks, lease_token, None).await
+}
+
+/// Nack as [`nack_batch`] does, keeping `reason` as the messages'
+/// `last_error`, as [`nack_messages_with_reason`] does
+pub async fn nack_batch_with_reason(
+    db: &Db,
+    nacks: &[(i64, i64)],
+    lease_token: &str,
+    reason: Option<&str>,
+) -> Result<Vec<AckResult>> {
+    check_batch_size(nacks.len())?;
+    let (requeued, dead) = nacked_ids(db, nacks, lease_token, reason).await?;
+    let mut results = Vec::with_capacity(nacks.len());
+    for &(id, _) in nacks {
+        let status = if requeued.contains(&id) {
+            AckStatus::Requeued
+        } else if dead.contains(&id) {
+            AckStatus::DeadLettered
+        } else {
+            unapplied_status(db, id).await?
+        };
+        results.push(AckResult { id, status });
+    }
+    Ok(results)
+}
+
+// Nack plain and consumer group leases, returning the IDs
+// `(requeued, dead_lettered)`
+async fn nacked_ids(
+    db: &Db,
+    nacks: &[(i64, i64)],
+    lease_token: &str,
+    reason: Option<&str>,
+) -> Result<(Vec<i64>, Vec<i64>)> {
+    let mut timer = OpTimer::start(Op::Nack, nacks.len());
+    let reason = reason.map(truncate_reason);
+    let (mut requeued, mut dead) = db
+        .nack_messages(nacks, lease_token, reason)
+        .await
+        .context("Failed to nack messages")?;
+    settle_attempts(db, &requeued, lease_token, "requeued", reason).await;
+    settle_attempts(db, &dead, lease_token, "dead_lettered", reason).await;
+    if requeued.len() + dead.len() < nacks.len() {
+        let (r, d) = db
+            .nack_group_messages(nacks, lease_token)
+            .await
+            .context("Failed to nack messages")?;
+        requeued.extend(r);
+        dead.extend(d);
+    }
+    timer.rows(requeued.len() + dead.len());
+    tracing::debug!(
+        requeued = requeued.len(),
+        dead_lettered = dead.len(),
+        "nacked"
+    );
+    Ok((requeued, dead))
+}
+
+/// End leases held under `lease_token` at once, so the messages are
+/// delivered again as if their visibility timeout had passed. Returns how
+/// many leases ended.
+#[tracing::instrument(level = "debug", skip_all, fields(ids = ?ids))]
+pub async fn expire_leases(
+    db: &Db,
+    ids: &[i64],
+    lease_token: &str,
+) -> Result<u64> {
+    db.expire_leases(ids, lease_token).await.context("Failed to expire leases")
+}
+
+/// Extend leases held under `lease_token` by `extra_ms`; returns how many were extended
+#[tracing::instrument(level = "debug", skip_all, fields(ids = ?ids, extra_ms))]
+pub async fn extend_visibility(
+    db: &Db,
+    ids: &[i64],
+    lease_token: &str,
+    extra_ms: i64,
+) -> Result<u64> {
+    let mut n = db
+        .extend_visibility(ids, lease_token, extra_ms)
+        .await
+        .context("Failed to extend visibility")?;
+    if (n as usize) < ids.len() {
+        n += db
+            .extend_group_visibility(ids, lease_token, extra_ms)
+            .await
+            .context("Failed to extend visibility")?;
+    }
+    Ok(n)
+}
+
+/// Remove a message by ID
+#[tracing::instrument(level = "debug", skip_all, fields(message_id = id))]
+pub async fn remove_message(
+    db: &Db,
+    id: i64,
+) -> Result<bool> {
+    let n = db
+        .remove_message_by_id(id)
+        .await
+        .context("Failed to remove message")?;
+    Ok(n > 0)
+}
+
+/// Connect to the storage backend selected by `cfg`, ensuring the database
+/// and schema exist first.
+pub async fn init_pool(cfg: &Config) -> Result<Db> {
+    let keys = match (&cfg.encryption_keys, &cfg.encryption_keyfile) {
+        (Some(keys), _) => Some(keys.clone()),
+        (None, Some(path)) => Some(Arc::new(Keyring::read(path)?)),
+        (None, None) => None,
+    };
+    let Some(path) = sqlite_path(cfg)? else {
+        if keys.is_some() {
+            return Err(SqewError::Unsupported(
+                "Payload encryption requires the SQLite backend".into(),
+            ));
+        }
+        let url = cfg.database_url.as_deref().unwrap_or_default();
+        let pg =
+            PgStorage::connect(url, cfg.force_recreate, &cfg.pool_options())
+                .await?;
+        return Ok(Arc::new(pg));
+    };
+    let sqlite =
+        SqliteStorage::open(&path, cfg.force_recreate, &cfg.pool_options())
+            .await?
+            .with_compress_threshold(cfg.compress_threshold);
+    let sqlite = match keys {
+        Some(keys) => sqlite.with_keyring(keys),
+        None => sqlite,
+    };
+    Ok(Arc::new(sqlite))
+}
+
+// The SQLite database file selected by `cfg`, or `None` for Postgres
+fn sqlite_path(cfg: &Config) -> Result<Option<PathBuf>> {
+    let url = cfg.database_url.as_deref().unwrap_or_default();
+    if url.starts_with("postgres://") || url.starts_with("postgresql://") {
+        return Ok(None);
+    }
+    match url.strip_prefix("sqlite:") {
+        Some(rest) => Ok(Some(PathBuf::from(rest.trim_start_matches("//")))),
+        None if url.is_empty() => Ok(Some(cfg.db_path.clone())),
+        None => Err(SqewError::Unsupported(format!(
+            "Unsupported database URL '{}'",
+            url
+        ))),
+    }
+}
+
+// Whether a queue name given to the CLI is a pattern over several queues
+fn is_queue_pattern(name: &str) -> bool {
+    name.contains('*')
+}
+
+// The names of the queues `pattern` matches, once the user confirms acting
+// on them (or passed `--yes`); fails when none match or the user declines
+async fn confirm_matching(
+    db: &Db,
+    pattern: &str,
+    verb: &str,
+    yes: bool,
+) -> anyhow::Result<Vec<String>> {
+    let names: Vec<String> = list_queues_matching(db, pattern)
+        .await?
+        .into_iter()
+        .map(|q| q.name)
+        .collect();
+    if names.is_empty() {
+        return Err(anyhow::anyhow!("No queues match '{}'", pattern));
+    }
+    if !yes {
+        eprint!(
+            "{} {} queue(s): {}? [y/N] ",
+            verb,
+            names.len(),
+            names.join(", ")
+        );
+        let mut answer = String::new();
+        std::io::stdin().read_line(&mut answer)?;
+        if !matches!(answer.trim(), "y" | "Y" | "yes") {
+            return Err(anyhow::anyhow!(
+                "{} canceled; pass --yes to skip the question",
+                verb
+            ));
+        }
+    }
+    Ok(names)
+}
+
+// Most messages `message tail --ack` leases at a time
+const TAIL_BATCH: i64 = 100;
+
+// Print messages enqueued into `queue` from now on, one line (or record)
+// each, until `count` have been printed. With `ack` they are leased and
+// acked instead, so the queue is drained as it is followed.
+async fn tail_queue(
+    db: &Db,
+    queue: &str,
+    ack: bool,
+    interval: std::time::Duration,
+    count: Option<usize>,
+    output: OutputFormat,
+) -> anyhow::Result<()> {
+    let mut records = RecordWriter::new(output);
+    let mut printed = 0;
+    let mut filter = PeekFilter {
+        after_id: Some(0),
+        created_after: Some(db::now_ms()),
+        ..PeekFilter::default()
+    };
+    while count.is_none_or(|n| printed < n) {
+        let limit =
+            count.map_or(TAIL_BATCH, |n| TAIL_BATCH.min((n - printed) as i64));
+        let msgs = if ack {
+            poll_messages(db, queue, limit, db::DEFAULT_VISIBILITY_MS).await?
+        } else {
+            peek_queue_filtered(db, queue, limit, &filter).await?
+        };
+        for m in &msgs {
+            if output.is_structured() {
+                records.write(m)?;
+            } else {
+                println!(
+                    "[id={}] priority={} attempts={} payload={}",
+                    m.id, m.priority, m.attempts, m.payload
+                );
+            }
+        }
+        if let Some(last) = msgs.last() {
+            filter.after_id = Some(last.id);
+        }
+        if ack && !msgs.is_empty() {
+            let ids: Vec<i64> = msgs.iter().map(|m| m.id).collect();
+            let token = msgs[0].lease_token.as_deref().unwrap_or_default();
+            ack_messages(db, &ids, token).await?;
+        }
+        printed += msgs.len();
+        // A full batch suggests more are waiting
+        if (msgs.len() as i64) < limit {
+            tokio::time::sleep(interval).await;
+        }
+    }
+    Ok(())
+}
+
+// Print a queue's stats every `interval`, with enqueue and ack rates since
+// the previous refresh, until `count` refreshes
+async fn watch_queue(
+    db: &Db,
+    name: &str,
+    interval: std::time::Duration,
+    count: Option<usize>,
+    output: OutputFormat,
+) -> anyhow::Result<()> {
+    let mut records = RecordWriter::new(output);
+    let mut last: Option<(std::time::Instant, Value)> = None;
+    for refresh in 0..count.unwrap_or(usize::MAX) {
+        if refresh > 0 {
+            tokio::time::sleep(interval).await;
+        }
+        let mut s = stats(db, name).await?;
+        let now = std::time::Instant::now();
+        let rate = |key: &str| {
+            last.as_ref().map(|(at, prev)| {
+                let secs = now.duration_since(*at).as_secs_f64().max(0.001);
+                let delta = s[key].as_i64().unwrap_or(0)
+                    - prev[key].as_i64().unwrap_or(0);
+                (delta.max(0) as f64 / secs * 10.0).round() / 10.0
+            })
+        };
+        let (enqueued_rate, acked_rate) = (rate("enqueued"), rate("acked"));
+        s["enqueued_per_sec"] = serde_json::json!(enqueued_rate);
+        s["acked_per_sec"] = serde_json::json!(acked_rate);
+        if output.is_structured() {
+            records.write(&s)?;
+        } else {
+            let per_sec = |r: Option<f64>| {
+                r.map_or_else(|| "-".to_string(), |r| r.to_string())
+            };
+            println!(
+                "{} ready={} leased={} delayed={} dlq={} enqueued/s={} acked/s={}",
+                db::now_ms(),
+                s["ready"],
+                s["leased"],
+                s["delayed"],
+                s["dlq"],
+                per_sec(enqueued_rate),
+                per_sec(acked_rate)
+            );
+        }
+        last = Some((now, s));
+    }
+    Ok(())
+}
+
+// Load a JSON Schema file given on the command line
+fn read_schema(path: &Path) -> anyhow::Result<Value> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    anyhow::Context::with_context(serde_json::from_str(&text), || {
+        format!("{} is not valid JSON", path.display())
+    })
+}
+
+// A `--no-*` flag clears a nullable setting; otherwise a given value sets it
+
+fn clear_or<T>(
+    clear: bool,
+    value: Option<T>,
+) -> Option<Option<T>> {
+    if clear { Some(None) } else { value.map(Some) }
+}
+
+/// Execute a queue command
+pub async fn run_queue_command(
+    cmd: QueueCommands,
+    cfg: &Config,
+    output: OutputFormat,
+) -> anyhow::Result<()> {
+    use anyhow::Context;
+    // Connect to the configured storage backend
+    let db = init_pool(cfg).await?;
+    let structured = output.is_structured();
+
+    match cmd {
+        QueueCommands::List { filter } => {
+            let queues: Vec<Queue> = match filter {
+                Some(pattern) => list_queues_matching(&db, &pattern).await,
+                None => list_queues(&db).await,
+            }
+            .context("Error listing queues")?;
+            if structured {
+                output.print(&queues)?;
+            } else if queues.is_empty() {
+                println!("No queues found");
+            } else {
+                println!("{:<5} {:<20} {:<12}", "ID", "NAME", "MAX_ATTEMPTS");
+                for q in queues {
+                    println!(
+                        "{:<5} {:<20} {:<12}",
+                        q.id, q.name, q.max_attempts
+                    );
+                }
+            }
+        }
+        QueueCommands::Add {
+            name,
+            max_attempts,
+            dedup_window_ms,
+            retention_days,
+            backoff_base_ms,
+            backoff_multiplier,
+            backoff_max_ms,
+            backoff_jitter,
+            default_visibility_ms,
+            default_delay_ms,
+            max_deliveries_per_second,
+            max_payload_bytes,
+            payload_schema,
+            schema_mode,
+            strict_fifo,
+            fair,
+            ordering,
+            max_depth,
+            max_lease_expirations,
+        } => {
+            // Create queue via service; settings not given come from the
+            // configured queue defaults
+            let payload_schema =
+                payload_schema.as_deref().map(read_schema).transpose()?;
+            let defaults = cfg.queue_defaults.clone();
+            let opts = QueueOptions {
+                max_attempts: max_attempts.unwrap_or(defaults.max_attempts),
+                dedup_window_ms: dedup_window_ms
+                    .unwrap_or(defaults.dedup_window_ms),
+                retention_days: retention_days.or(defaults.retention_days),
+                backoff_base_ms: backoff_base_ms.or(defaults.backoff_base_ms),
+                backoff_multiplier: backoff_multiplier
+                    .unwrap_or(defaults.backoff_multiplier),
+                backoff_max_ms: backoff_max_ms.or(defaults.backoff_max_ms),
+                backoff_jitter: backoff_jitter
+                    .unwrap_or(defaults.backoff_jitter),
+                default_visibility_ms: default_visibility_ms
+                    .unwrap_or(defaults.default_visibility_ms),
+                default_delay_ms: default_delay_ms
+                    .unwrap_or(defaults.default_delay_ms),
+                max_deliveries_per_second: max_deliveries_per_second
+                    .or(defaults.max_deliveries_per_second),
+                max_payload_bytes: max_payload_bytes
+                    .or(defaults.max_payload_bytes),
+                payload_schema: payload_schema.or(defaults.payload_schema),
+                schema_mode: schema_mode.unwrap_or(defaults.schema_mode),
+                strict_fifo: strict_fifo || defaults.strict_fifo,
+                fair: fair || defaults.fair,
+                ordering: ordering.unwrap_or(defaults.ordering),
+                max_depth: max_depth.or(defaults.max_depth),
+                max_lease_expirations: max_lease_expirations
+                    .or(defaults.max_lease_expirations),
+            };
+            let q = create_queue_with(&db, &name, &opts)
+                .await
+                .context("Error creating queue")?;
+            audit::record(
+                &db,
+                &Actor::cli(),
+                "queue.create",
+                Some(&q.name),
+                serde_json::to_value(&q).unwrap_or_default(),
+            )
+            .await;
+            if structured {
+                output.print(&q)?;
+            } else {
+                println!("Created queue '{}' with ID {}", q.name, q.id);
+            }
+        }
+        QueueCommands::Update {
+            name,
+            max_attempts,
+            dedup_window_ms,
+            retention_days,
+            no_retention,
+            backoff_base_ms,
+            no_backoff,
+            backoff_multiplier,
+            backoff_max_ms,
+            no_backoff_max,
+            backoff_jitter,
+            default_visibility_ms,
+            default_delay_ms,
+            max_deliveries_per_second,
+            no_rate_limit,
+            max_payload_bytes,
+            no_payload_limit,
+            payload_schema,
+            no_payload_schema,
+            schema_mode,
+            strict_fifo,
+            fair,
+            ordering,
+            max_depth,
+            no_max_depth,
+            max_lease_expirations,
+            no_quarantine,
+        } => {
+            let payload_schema =
+                payload_schema.as_deref().map(read_schema).transpose()?;
+            let update = QueueUpdate {
+                max_attempts,
+                dedup_window_ms,
+                retention_days: clear_or(no_retention, retention_days),
+                backoff_base_ms: clear_or(no_backoff, backoff_base_ms),
+                backoff_multiplier,
+                backoff_max_ms: clear_or(no_backoff_max, backoff_max_ms),
+                backoff_jitter,
+                default_visibility_ms,
+                default_delay_ms,
+                max_deliveries_per_second: clear_or(
+                    no_rate_limit,
+                    max_deliveries_per_second,
+                ),
+                max_payload_bytes: clear_or(
+                    no_payload_limit,
+                    max_payload_bytes,
+                ),
+                payload_schema: clear_or(no_payload_schema, payload_schema),
+                schema_mode,
+                paused: None,
+                strict_fifo,
+                fair,
+                ordering,
+                max_depth: clear_or(no_max_depth, max_depth),
+                max_lease_expirations: clear_or(
+                    no_quarantine,
+                    max_lease_expirations,
+                ),
+            };
+            let q = update_queue(&db, &name, &update)
+                .await
+                .context("Error updating queue")?;
+            audit::record(
+                &db,
+                &Actor::cli(),
+                "queue.update",
+                Some(&q.name),
+                serde_json::to_value(&q).unwrap_or_default(),
+            )
+            .await;
+            if structured {
+                output.print(&q)?;
+            } else {
+                println!("Updated queue '{}'", q.name);
+            }
+        }
+        QueueCommands::Remove { name, soft, yes }
+            if is_queue_pattern(&name) =>
+        {
+            let verb = if soft { "Trash" } else { "Remove" };
+            let names = confirm_matching(&db, &name, verb, yes).await?;
+            let mut removed = Vec::with_capacity(names.len());
+            for name in names {
+                let done = if soft {
+                    trash_queue(&db, &name).await
+                } else {
+                    delete_queue(&db, &name).await
+                }
+                .with_context(|| format!("Error removing queue '{name}'"))?;
+                if done {
+                    audit::record(
+                        &db,
+                        &Actor::cli(),
+                        "queue.delete",
+                        Some(&name),
+                        serde_json::json!({ "soft": soft }),
+                    )
+                    .await;
+                }
+                if !structured && done {
+                    if soft {
+                        println!("Moved queue '{}' to the trash", name);
+                    } else {
+                        println!("Removed queue '{}'", name);
+                    }
+                }
+                removed.push(serde_json::json!({
+                    "name": name,
+                    "removed": done,
+                    "soft": soft,
+                }));
+            }
+            if structured {
+                output.print(&removed)?;
+            }
+        }
+        QueueCommands::Remove { name, soft, .. } => {
+            // Delete queue via service
+            let removed = if soft {
+                trash_queue(&db, &name).await
+            } else {
+                delete_queue(&db, &name).await
+            }
+            .context("Error removing queue")?;
+            if removed {
+                audit::record(
+                    &db,
+                    &Actor::cli(),
+                    "queue.delete",
+                    Some(&name),
+                    serde_json::json!({ "soft": soft }),
+                )
+                .await;
+            }
+            if structured {
+                output.print(&serde_json::json!({
+                    "name": name,
+                    "removed": removed,
+                    "soft": soft,
+                }))?;
+            } else if removed && soft {
+                println!(
+                    "Moved queue '{}' to the trash; `sqew queue restore {}` brings it back",
+                    name, name
+                );
+            } else if removed {
+                println!("Removed queue '{}'", name);
+            } else {
+                eprintln!("Queue '{}' not found", name);
+            }
+            if !removed {
+                std::process::exit(1);
+            }
+        }
+        QueueCommands::Restore { name } => {
+            let q = restore_queue(&db, &name).await?;
+            audit::record(
+                &db,
+                &Actor::cli(),
+                "queue.restore",
+                Some(&name),
+                serde_json::Value::Null,
+            )
+            .await;
+            if structured {
+                output.print(&q)?;
+            } else {
+                println!("Restored queue '{}'", q.name);
+            }
+        }
+        QueueCommands::Trash => {
+            let queues = list_trash(&db).await?;
+            if structured {
+                output.print(&queues)?;
+            } else if queues.is_empty() {
+                println!("The trash is empty");
+            } else {
+                for q in queues {
+                    let deleted_at = q.deleted_at.unwrap_or_default();
+                    println!(
+                        "[{}] {} deleted_at={} purge_at={}",
+                        q.id,
+                        q.name,
+                        deleted_at,
+                        deleted_at + TRASH_RETENTION_MS
+                    );
+                }
+            }
+        }
+        QueueCommands::Clone { source, target, with_messages } => {
+            let (q, copied) =
+                clone_queue(&db, &source, &target, with_messages).await?;
+            audit::record(
+                &db,
+                &Actor::cli(),
+                "queue.clone",
+                Some(&q.name),
+                serde_json::json!({
+                    "from": source,
+                    "with_messages": with_messages,
+                    "copied": copied,
+                }),
+            )
+            .await;
+            if structured {
+                output.print(&serde_json::json!({
+                    "queue": q,
+                    "copied": copied,
+                }))?;
+            } else if with_messages {
+                println!(
+                    "Cloned queue '{}' into '{}' with {} message(s)",
+                    source, q.name, copied
+                );
+            } else {
+                println!("Cloned queue '{}' into '{}'", source, q.name);
+            }
+        }
+        QueueCommands::Show { name } => {
+            // Show queue details and stats
+            let q =
+                show_queue(&db, &name).await.context("Error fetching queue")?;
+            let s = stats(&db, &name).await?;
+            if structured {
+                output.print(&serde_json::json!({ "queue": q, "stats": s }))?;
+                return Ok(());
+            }
+            println!("Queue '{}' (ID={})", q.name, q.id);
+            println!("  max_attempts: {}", q.max_attempts);
+            println!("  dedup_window_ms: {}", q.dedup_window_ms);
+            if let Some(days) = q.retention_days {
+                println!("  retention_days: {}", days);
+            }
+            if let Some(base) = q.backoff_base_ms {
+                let max = q
+                    .backoff_max_ms
+                    .map_or_else(|| "none".to_string(), |ms| ms.to_string());
+                println!(
+                    "  backoff: base_ms={} multiplier={} max_ms={} jitter={}",
+                    base, q.backoff_multiplier, max, q.backoff_jitter
+                );
+            }
+            println!("  default_visibility_ms: {}", q.default_visibility_ms);
+            println!("  default_delay_ms: {}", q.default_delay_ms);
+            if let Some(rate) = q.max_deliveries_per_second {
+                println!("  max_deliveries_per_second: {}", rate);
+            }
+            if let Some(bytes) = q.max_payload_bytes {
+                println!("  max_payload_bytes: {}", bytes);
+            }
+            if let Some(schema) = &q.payload_schema {
+                println!(
+                    "  payload_schema: {} (version {}, {})",
+                    schema, q.payload_schema_version, q.schema_mode
+                );
+            }
+            if q.paused {
+                println!("  paused: true");
+            }
+            if q.strict_fifo {
+                println!("  strict_fifo: true");
+            }
+            if q.fair {
+                println!("  fair: true");
+            }
+            if q.ordering != "priority" {
+                println!("  ordering: {}", q.ordering);
+            }
+            if let Some(depth) = q.max_depth {
+                println!("  max_depth: {}", depth);
+            }
+            if let Some(n) = q.max_lease_expirations {
+                println!("  max_lease_expirations: {}", n);
+            }
+            println!(
+                "Stats: ready={} leased={} delayed={} dlq={} expired={}",
+                s["ready"], s["leased"], s["delayed"], s["dlq"], s["expired"]
+            );
+            println!(
+                "  enqueued={} acked={} oldest_ready_age_ms={} avg_ack_ms={}",
+                s["enqueued"],
+                s["acked"],
+                s["oldest_ready_age_ms"],
+                s["avg_ack_ms"]
+            );
+        }
+        QueueCommands::Stats { name, history: false, .. } => {
+            let s = stats(&db, &name).await?;
+            if structured {
+                output.print(&s)?;
+                return Ok(());
+            }
+            for key in [
+                "ready",
+                "leased",
+                "delayed",
+                "dlq",
+                "expired",
+                "enqueued",
+                "acked",
+                "oldest_ready_age_ms",
+                "avg_ack_ms",
+            ] {
+                println!("{}: {}", key, s[key]);
+            }
+        }
+        QueueCommands::Stats { name, history: true, window } => {
+            let samples = stats_history(&db, &name, parse_window(&window)?)
+                .await
+                .context("Error reading stats history")?;
+            if structured {
+                output.print(&samples)?;
+                return Ok(());
+            }
+            if samples.is_empty() {
+                println!(
+                    "No stats recorded for '{}' in the last {}; snapshots are taken by `sqew serve`",
+                    name, window
+                );
+            }
+            for s in samples {
+                println!(
+                    "{} ready={} leased={} delayed={} dlq={} enqueued={} acked={}",
+                    s.recorded_at,
+                    s.ready,
+                    s.leased,
+                    s.delayed,
+                    s.dlq,
+                    s.enqueued,
+                    s.acked
+                );
+            }
+        }
+        QueueCommands::Purge { name, batch_size, yes }
+            if is_queue_pattern(&name) =>
+        {
+            let names = confirm_matching(&db, &name, "Purge", yes).await?;
+            let mut purged = Vec::with_capacity(names.len());
+            for name in names {
+                let deleted = purge_queue_batched(
+                    &db,
+                    &name,
+                    batch_size,
+                    |_| {},
+                )
+                .await
+                .with_context(|| format!("Error purging queue '{name}'"))?;
+                audit::record(
+                    &db,
+                    &Actor::cli(),
+                    "queue.purge",
+                    Some(&name),
+                    serde_json::json!({ "purged": deleted }),
+                )
+                .await;
+                if !structured {
+                    println!(
+                        "Purged {} messages from queue '{}'",
+                        deleted, name
+                    );
+                }
+                purged.push(serde_json::json!({
+                    "name": name,
+                    "purged": deleted,
+                }));
+            }
+            if structured {
+                output.print(&purged)?;
+            }
+        }
+        QueueCommands::Purge { name, batch_size, .. } => {
+            // Purge all messages in the queue, reporting each batch
+            let deleted =
+                purge_queue_batched(&db, &name, batch_size, |total| {
+                    if !structured {
+                        eprintln!("Purged {total} message(s)...");
+                    }
+                })
+                .await
+                .context("Error purging messages")?;
+            audit::record(
+                &db,
+                &Actor::cli(),
+                "queue.purge",
+                Some(&name),
+                serde_json::json!({ "purged": deleted }),
+            )
+            .await;
+            if structured {
+                output.print(&serde_json::json!({ "purged": deleted }))?;
+            } else {
+                println!("Purged {} messages from queue '{}'", deleted, name);
+            }
+        }
+        QueueCommands::Watch { name, interval_ms, count } => {
+            show_queue(&db, &name).await?;
+            let interval = std::time::Duration::from_millis(interval_ms.max(1));
+            let follow = watch_queue(&db, &name, interval, count, output);
+            tokio::select! {
+                res = follow => res?,
+                _ = tokio::signal::ctrl_c() => {}
+            }
+        }
+        QueueCommands::Pause { name } => {
+            let q = set_paused(&db, &name, true).await?;
+            audit::record(
+                &db,
+                &Actor::cli(),
+                "queue.pause",
+                Some(&name),
+                serde_json::Value::Null,
+            )
+            .await;
+            if structured {
+                output.print(&q)?;
+            } else {
+                println!("Paused queue '{}'", name);
+            }
+        }
+        QueueCommands::Resume { name } => {
+            let q = set_paused(&db, &name, false).await?;
+            audit::record(
+                &db,
+                &Actor::cli(),
+                "queue.resume",
+                Some(&name),
+                serde_json::Value::Null,
+            )
+            .await;
+            if structured {
+                output.print(&q)?;
+            } else {
+                println!("Resumed queue '{}'", name);
+            }
+        }
+        QueueCommands::Peek { name, limit } => {
+            // Peek messages without leasing
+            let msgs = peek_queue(&db, &name, limit)
+                .await
+                .context("Error peeking messages")?;
+            if structured {
+                output.print(&msgs)?;
+            } else {
+                for m in msgs {
+                    println!("[{}] {}", m.id, m.payload);
+                }
+            }
+        }
+        QueueCommands::Inflight { name, limit } => {
+            let msgs = in_flight(&db, &name, limit).await?;
+            if structured {
+                output.print(&msgs)?;
+            } else if msgs.is_empty() {
+                println!("No messages in flight on queue '{}'", name);
+            } else {
+                for m in msgs {
+                    println!(
+                        "[{}] consumer={} held_ms={} attempts={} lease_expires_at={}",
+                        m.message_id,
+                        m.consumer.as_deref().unwrap_or("-"),
+                        m.held_ms.map_or("-".into(), |ms| ms.to_string()),
+                        m.attempts,
+                        m.lease_expires_at
+                    );
+                }
+            }
+        }
+        QueueCommands::Compact { name: _, recompress } => {
+            // Compress large plain payloads first so VACUUM reclaims the space
+            let recompressed = if recompress {
+                recompress_payloads(&db)
+                    .await
+                    .context("Error recompressing payloads")?
+            } else {
+                0
+            };
+            // Compact the SQLite database
+            compact(&db).await.context("Error compacting database")?;
+            if structured {
+                output.print(&serde_json::json!({
+                    "compacted": true,
+                    "recompressed": recompressed,
+                }))?;
+            } else {
+                if recompress {
+                    println!("Recompressed {} payloads", recompressed);
+                }
+                println!("Compacted database (VACUUM)");
+            }
+        }
+        QueueCommands::Dlq(cmd) => run_dlq_command(&db, cmd, output).await?,
+        QueueCommands::Export { name, file } => {
+            let out = std::fs::File::create(&file);
+            let out = anyhow::Context::with_context(out, || {
+                format!("Failed to create {}", file.display())
+            })?;
+            let mut out = std::io::BufWriter::new(out);
+            let exported = export_queue(&db, &name, &mut out)
+                .await
+                .context("Error exporting queue")?;
+            if structured {
+                output.print(&serde_json::json!({ "exported": exported }))?;
+            } else {
+                println!(
+                    "Exported {} messages from queue '{}' to {}",
+                    exported,
+                    name,
+                    file.display()
+                );
+            }
+        }
+        QueueCommands::Import { name, file, format } => {
+            let input = std::fs::File::open(&file);
+            let input = anyhow::Context::with_context(input, || {
+                format!("Failed to open {}", file.display())
+            })?;
+            let input = std::io::BufReader::new(input);
+            let (imported, skipped) =
+                import_queue_as(&db, &name, format, input)
+                    .await
+                    .context("Error importing queue")?;
+            audit::record(
+                &db,
+                &Actor::cli(),
+                "queue.import",
+                Some(&name),
+                serde_json::json!({
+                    "format": format,
+                    "imported": imported,
+                    "skipped": skipped,
+                }),
+            )
+            .await;
+            if structured {
+                output.print(&serde_json::json!({
+                    "imported": imported,
+                    "skipped": skipped,
+                }))?;
+            } else {
+                println!(
+                    "Imported {} messages into queue '{}' ({} duplicates skipped)",
+                    imported, name, skipped
+                );
+            }
+        }
+        QueueCommands::Group(cmd) => {
+            run_group_command(&db, cmd, output).await?
+        }
+        QueueCommands::Schedule(cmd) => {
+            run_schedule_command(&db, cmd, output).await?
+        }
+        QueueCommands::Alarm(cmd) => {
+            run_alarm_command(&db, cmd, output).await?
+        }
+        QueueCommands::PushConfig(cmd) => {
+            run_push_config_command(&db, cmd, output).await?
+        }
+        QueueCommands::Schema(cmd) => {
+            run_schema_command(&db, cmd, output).await?
+        }
+    }
+    Ok(())
+}
+
+/// Execute a payload schema command
+async fn run_schema_command(
+    db: &Db,
+    cmd: SchemaCommands,
+    output: OutputFormat,
+) -> anyhow::Result<()> {
+    use anyhow::Context;
+    let structured = output.is_structured();
+    match cmd {
+        SchemaCommands::Set { name, file, mode } => {
+            let schema = read_schema(&file)?;
+            let q = set_queue_schema(db, &name, schema, mode)
+                .await
+                .context("Error setting schema")?;
+            audit::record(
+                db,
+                &Actor::cli(),
+                "queue.schema",
+                Some(&q.name),
+                serde_json::json!({
+                    "version": q.payload_schema_version,
+                    "mode": q.schema_mode,
+                }),
+            )
+            .await;
+            if structured {
+                output.print(&q)?;
+            } else {
+                println!(
+                    "Set schema version {} on '{}' (mode: {})",
+                    q.payload_schema_version, name, q.schema_mode
+                );
+            }
+        }
+        SchemaCommands::Show { name, version } => {
+            let s = queue_schema(db, &name, version)
+                .await
+                .context("Error reading schema")?;
+            if structured {
+                output.print(&s)?;
+            } else {
+                match &s.payload_schema {
+                    Some(schema) => println!(
+                        "{}",
+                        serde_json::to_string_pretty(schema)
+                            .unwrap_or_default()
+                    ),
+                    None => println!(
+                        "Schema version {} of '{}' removed the schema",
+                        s.version, name
+                    ),
+                }
+            }
+        }
+        SchemaCommands::History { name } => {
+            let versions = queue_schema_history(db, &name)
+                .await
+                .context("Error listing schema versions")?;
+            if structured {
+                output.print(&versions)?;
+            } else if versions.is_empty() {
+                println!("No schema versions on '{}'", name);
+            } else {
+                for v in versions {
+                    println!(
+                        "[version={}] created_at={} schema={}",
+                        v.version,
+                        v.created_at,
+                        v.payload_schema
+                            .map_or("none".to_string(), |s| s.to_string())
+                    );
+                }
+            }
+        }
+    }
+    Ok(())
 }
 
-/// Nack messages: increment attempts and requeue with delay; drops if attempts exceed max_attempts
-pub async fn nack_messages(
-    pool: &sqlx::SqlitePool,
-    ids: &[i64],
-    delay_ms: i64,
-) -> Result<(u64, u64)> {
-    let (requeued, dropped) = db::nack_messages(pool, ids, delay_ms)
-        .await
-        .context("Failed to nack messages")?;
-    Ok((requeued, dropped))
+/// Execute a dead-letter queue command
+async fn run_dlq_command(
+    db: &Db,
+    cmd: DlqCommands,
+    output: OutputFormat,
+) -> anyhow::Result<()> {
+    use anyhow::Context;
+    let structured = output.is_structured();
+    match cmd {
+        DlqCommands::List { name, limit } => {
+            let msgs = list_dead_letters(db, &name, limit)
+                .await
+                .context("Error listing dead letters")?;
+            if structured {
+                output.print(&msgs)?;
+            } else if msgs.is_empty() {
+                println!("No dead letters in '{}'", name);
+            } else {
+                for m in msgs {
+                    // Quoted, so a multi-line stack trace stays on one line
+                    println!(
+                        "[id={}] attempts={} dead_at={} payload={}{}",
+                        m.id,
+                        m.attempts,
+                        m.dead_at.unwrap_or_default(),
+                        m.payload,
+                        m.last_error
+                            .map_or(String::new(), |e| format!(" error={e:?}"))
+                    );
+                }
+            }
+        }
+        DlqCommands::Redrive { name, ids } => {
+            let ids = resolve_message_ids(db, &ids).await?;
+            let n = redrive_dead_letters(db, &name, &ids)
+                .await
+                .context("Error redriving dead letters")?;
+            audit::record(
+                db,
+                &Actor::cli(),
+                "dlq.redrive",
+                Some(&name),
+                serde_json::json!({ "ids": ids, "redriven": n }),
+            )
+            .await;
+            if structured {
+                output.print(&serde_json::json!({ "redriven": n }))?;
+            } else {
+                println!("Redrove {} message(s) into '{}'", n, name);
+            }
+        }
+        DlqCommands::Purge { name } => {
+            let n = purge_dead_letters(db, &name)
+                .await
+                .context("Error purging dead letters")?;
+            audit::record(
+                db,
+                &Actor::cli(),
+                "dlq.purge",
+                Some(&name),
+                serde_json::json!({ "purged": n }),
+            )
+            .await;
+            if structured {
+                output.print(&serde_json::json!({ "purged": n }))?;
+            } else {
+                println!("Purged {} dead letter(s) from '{}'", n, name);
+            }
+        }
+    }
+    Ok(())
 }
 
-/// Remove a message by ID
-pub async fn remove_message(
-    pool: &sqlx::SqlitePool,
-    id: i64,
-) -> Result<bool> {
-    let n = db::remove_message_by_id(pool, id)
-        .await
-        .context("Failed to remove message")?;
-    Ok(n > 0)
+/// Execute a consumer group command
+async fn run_group_command(
+    db: &Db,
+    cmd: GroupCommands,
+    output: OutputFormat,
+) -> anyhow::Result<()> {
+    use anyhow::Context;
+    let structured = output.is_structured();
+    match cmd {
+        GroupCommands::Add { name, group } => {
+            let g = create_consumer_group(db, &name, &group)
+                .await
+                .context("Error adding consumer group")?;
+            audit::record(
+                db,
+                &Actor::cli(),
+                "group.create",
+                Some(&name),
+                serde_json::json!({"group": g.name}),
+            )
+            .await;
+            if structured {
+                output.print(&g)?;
+            } else {
+                println!("Added consumer group '{}' to '{}'", g.name, name);
+            }
+        }
+        GroupCommands::List { name } => {
+            let groups = list_consumer_groups(db, &name)
+                .await
+                .context("Error listing consumer groups")?;
+            if structured {
+                output.print(&groups)?;
+            } else if groups.is_empty() {
+                println!("No consumer groups on '{}'", name);
+            } else {
+                for g in groups {
+                    println!(
+                        "[id={}] {} start_id={} created_at={}",
+                        g.id, g.name, g.start_id, g.created_at
+                    );
+                }
+            }
+        }
+        GroupCommands::Remove { name, group } => {
+            let removed = delete_consumer_group(db, &name, &group)
+                .await
+                .context("Error removing consumer group")?;
+            if removed {
+                audit::record(
+                    db,
+                    &Actor::cli(),
+                    "group.delete",
+                    Some(&name),
+                    serde_json::json!({"group": group}),
+                )
+                .await;
+            }
+            if structured {
+                output.print(&serde_json::json!({ "removed": removed }))?;
+            } else if removed {
+                println!("Removed consumer group '{}' from '{}'", group, name);
+            } else {
+                println!("Consumer group '{}' not found on '{}'", group, name);
+            }
+        }
+    }
+    Ok(())
 }
 
-/// Initialize the pool, ensuring the database exists first.
-pub async fn init_pool(cfg: &Config) -> Result<SqlitePool> {
-    db::create_db_if_needed_at(&cfg.db_path, cfg.force_recreate).await?;
-    let pool = db::init_pool_at(&cfg.db_path).await?;
-    Ok(pool)
+// Execute a schedule subcommand
+async fn run_schedule_command(
+    db: &Db,
+    cmd: ScheduleCommands,
+    output: OutputFormat,
+) -> anyhow::Result<()> {
+    use anyhow::Context;
+    let structured = output.is_structured();
+    match cmd {
+        ScheduleCommands::Add { name, cron, payload } => {
+            let v: Value = serde_json::from_str(&payload)
+                .context("Invalid JSON payload")?;
+            let s = add_schedule(db, &name, &cron, &v)
+                .await
+                .context("Error adding schedule")?;
+            if structured {
+                output.print(&s)?;
+            } else {
+                println!(
+                    "Added schedule {} on '{}' ({}), next run at {}",
+                    s.id, name, s.cron, s.next_run_at
+                );
+            }
+        }
+        ScheduleCommands::List { name } => {
+            let schedules = list_schedules(db, name.as_deref())
+                .await
+                .context("Error listing schedules")?;
+            if structured {
+                output.print(&schedules)?;
+            } else if schedules.is_empty() {
+                println!("No schedules found");
+            } else {
+                let queues = list_queues(db).await?;
+                for s in schedules {
+                    let queue = queues
+                        .iter()
+                        .find(|q| q.id == s.queue_id)
+                        .map(|q| q.name.as_str())
+                        .unwrap_or("?");
+                    println!(
+                        "[id={}] queue={} cron=\"{}\" next_run_at={} payload={}",
+                        s.id, queue, s.cron, s.next_run_at, s.payload
+                    );
+                }
+            }
+        }
+        ScheduleCommands::Remove { id } => {
+            let removed = remove_schedule(db, id).await?;
+            if structured {
+                output.print(
+                    &serde_json::json!({ "id": id, "removed": removed }),
+                )?;
+            } else if removed {
+                println!("Removed schedule {}", id);
+            } else {
+                eprintln!("Schedule {} not found", id);
+            }
+            if !removed {
+                std::process::exit(1);
+            }
+        }
+    }
+    Ok(())
 }
 
-/// Execute a queue command
-pub async fn run_queue_command(cmd: QueueCommands) -> Result<()> {
-    // Initialize database pool
-    let pool = init_pool(&Config::default()).await?;
-
+/// Execute a push config command
+async fn run_push_config_command(
+    db: &Db,
+    cmd: PushConfigCommands,
+    output: OutputFormat,
+) -> anyhow::Result<()> {
+    use anyhow::Context;
+    let structured = output.is_structured();
     match cmd {
-        QueueCommands::List => {
-            let queues: Vec<Queue> =
-                list_queues(&pool).await.context("Error listing queues")?;
-            if queues.is_empty() {
-                println!("No queues found");
+        PushConfigCommands::Set {
+            name,
+            url,
+            concurrency,
+            timeout_ms,
+            backoff_ms,
+        } => {
+            let cfg = set_push_config(
+                db,
+                &name,
+                &url,
+                concurrency,
+                timeout_ms,
+                backoff_ms,
+            )
+            .await
+            .context("Error setting push config")?;
+            if structured {
+                output.print(&cfg)?;
             } else {
-                println!("{:<5} {:<20} {:<12}", "ID", "NAME", "MAX_ATTEMPTS");
-                for q in queues {
+                println!(
+                    "Pushing '{}' to {} ({} at a time, timeout {}ms, backoff {}ms)",
+                    name,
+                    cfg.url,
+                    cfg.concurrency,
+                    cfg.timeout_ms,
+                    cfg.backoff_ms
+                );
+            }
+        }
+        PushConfigCommands::Show { name } => {
+            let cfg = push_config(db, &name).await?;
+            if structured {
+                output.print(&cfg)?;
+            } else if let Some(cfg) = cfg {
+                println!(
+                    "queue={} url={} concurrency={} timeout_ms={} backoff_ms={}",
+                    name,
+                    cfg.url,
+                    cfg.concurrency,
+                    cfg.timeout_ms,
+                    cfg.backoff_ms
+                );
+            } else {
+                println!("Queue '{}' has no push config", name);
+            }
+        }
+        PushConfigCommands::Remove { name } => {
+            let removed = remove_push_config(db, &name).await?;
+            if structured {
+                output.print(
+                    &serde_json::json!({ "queue": name, "removed": removed }),
+                )?;
+            } else if removed {
+                println!("Stopped pushing '{}'", name);
+            } else {
+                eprintln!("Queue '{}' has no push config", name);
+            }
+            if !removed {
+                std::process::exit(1);
+            }
+        }
+        PushConfigCommands::Log { name, limit } => {
+            let log = push_deliveries(db, &name, limit)
+                .await
+                .context("Error listing push deliveries")?;
+            if structured {
+                output.print(&log)?;
+            } else if log.is_empty() {
+                println!("No push deliveries found");
+            } else {
+                for d in log {
                     println!(
-                        "{:<5} {:<20} {:<12}",
-                        q.id, q.name, q.max_attempts
+                        "[at={}] message={} attempt={} {} status={} {}ms{}",
+                        d.delivered_at,
+                        d.message_id,
+                        d.attempt,
+                        d.outcome,
+                        d.status_code
+                            .map_or_else(|| "-".into(), |s| s.to_string()),
+                        d.duration_ms,
+                        d.error
+                            .map(|e| format!(" error={e}"))
+                            .unwrap_or_default()
                     );
                 }
             }
         }
-        QueueCommands::Add { name, max_attempts } => {
-            // Create queue via service
-            let q = create_queue(&pool, &name, max_attempts)
+    }
+    Ok(())
+}
+
+/// Execute an alarm command
+async fn run_alarm_command(
+    db: &Db,
+    cmd: AlarmCommands,
+    output: OutputFormat,
+) -> anyhow::Result<()> {
+    use anyhow::Context;
+    let structured = output.is_structured();
+    match cmd {
+        AlarmCommands::Add { name, metric, threshold, for_ms, webhook } => {
+            let a = add_alarm(db, &name, &metric, threshold, for_ms, &webhook)
                 .await
-                .context("Error creating queue")?;
-            println!("Created queue '{}' with ID {}", q.name, q.id);
+                .context("Error adding alarm")?;
+            audit::record(
+                db,
+                &Actor::cli(),
+                "alarm.create",
+                Some(&name),
+                serde_json::to_value(&a).unwrap_or_default(),
+            )
+            .await;
+            if structured {
+                output.print(&a)?;
+            } else {
+                println!(
+                    "Added alarm {} on '{}': {} > {} for {}ms -> {}",
+                    a.id, name, a.metric, a.threshold, a.for_ms, a.webhook_url
+                );
+            }
         }
-        QueueCommands::Remove { name } => {
-            // Delete queue via service
-            let removed = delete_queue(&pool, &name)
+        AlarmCommands::List { name } => {
+            let alarms = list_alarms(db, name.as_deref())
                 .await
-                .context("Error removing queue")?;
+                .context("Error listing alarms")?;
+            if structured {
+                output.print(&alarms)?;
+            } else if alarms.is_empty() {
+                println!("No alarms found");
+            } else {
+                let queues = list_queues(db).await?;
+                for a in alarms {
+                    let queue = queues
+                        .iter()
+                        .find(|q| q.id == a.queue_id)
+                        .map(|q| q.name.as_str())
+                        .unwrap_or("?");
+                    println!(
+                        "[id={}] queue={} {} > {} for {}ms firing={} webhook={}",
+                        a.id,
+                        queue,
+                        a.metric,
+                        a.threshold,
+                        a.for_ms,
+                        a.firing,
+                        a.webhook_url
+                    );
+                }
+            }
+        }
+        AlarmCommands::Remove { id } => {
+            let removed = remove_alarm(db, id).await?;
             if removed {
-                println!("Removed queue '{}'", name);
+                audit::record(
+                    db,
+                    &Actor::cli(),
+                    "alarm.delete",
+                    None,
+                    serde_json::json!({"id": id}),
+                )
+                .await;
+            }
+            if structured {
+                output.print(
+                    &serde_json::json!({ "id": id, "removed": removed }),
+                )?;
+            } else if removed {
+                println!("Removed alarm {}", id);
             } else {
-                eprintln!("Queue '{}' not found", name);
+                eprintln!("Alarm {} not found", id);
+            }
+            if !removed {
                 std::process::exit(1);
             }
         }
-        QueueCommands::Show { name } => {
-            // Show queue details and stats
-            let q = show_queue(&pool, &name)
-                .await
-                .context("Error fetching queue")?;
-            // Compute stats
-            let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis()
-                as i64;
-            let ready = db::count_ready_messages(&pool, q.id, now).await?;
-            println!("Queue '{}' (ID={})", q.name, q.id);
-            println!("  max_attempts: {}", q.max_attempts);
-            println!("Stats: ready={}", ready);
+    }
+    Ok(())
+}
+
+/// Execute a database maintenance command
+pub async fn run_db_command(
+    cmd: DbCommands,
+    cfg: &Config,
+    output: OutputFormat,
+) -> anyhow::Result<()> {
+    use anyhow::{Context, anyhow};
+    let structured = output.is_structured();
+    match cmd {
+        DbCommands::Migrate => {
+            // Opening the database already applies pending migrations
+            let db = init_pool(cfg).await?;
+            let version = anyhow::Context::context(
+                db.schema_version().await,
+                "Error reading schema version",
+            )?;
+            if structured {
+                output.print(&serde_json::json!({ "version": version }))?;
+            } else {
+                println!("Database schema is at version {}", version);
+            }
         }
-        QueueCommands::Purge { name } => {
-            // Purge all messages in the queue
-            let deleted = purge_queue(&pool, &name)
+        DbCommands::Backup { path } => {
+            let db = init_pool(cfg).await?;
+            let bytes = backup_database(&db, &path)
                 .await
-                .context("Error purging messages")?;
-            println!("Purged {} messages from queue '{}'", deleted, name);
+                .context("Error backing up database")?;
+            audit::record(
+                &db,
+                &Actor::cli(),
+                "db.backup",
+                None,
+                serde_json::json!({"path": path, "bytes": bytes}),
+            )
+            .await;
+            if structured {
+                output.print(&serde_json::json!({
+                    "backup": path,
+                    "bytes": bytes,
+                }))?;
+            } else {
+                println!(
+                    "Backed up database to {} ({} bytes)",
+                    path.display(),
+                    bytes
+                );
+            }
         }
-        QueueCommands::Peek { name, limit } => {
-            // Peek messages without leasing
-            let msgs = peek_queue(&pool, &name, limit)
+        DbCommands::Restore { path } => {
+            // Not opened first: the file is replaced underneath
+            let version = restore_database(cfg, &path)
                 .await
-                .context("Error peeking messages")?;
-            for m in msgs {
-                println!("[{}] {}", m.id, m.payload);
+                .context("Error restoring database")?;
+            if structured {
+                output.print(&serde_json::json!({
+                    "restored": path,
+                    "version": version,
+                }))?;
+            } else {
+                println!(
+                    "Restored database from {} (schema version {})",
+                    path.display(),
+                    version
+                );
             }
         }
-        QueueCommands::Compact { name: _ } => {
-            // Compact the SQLite database
-            compact(&pool).await.context("Error compacting database")?;
-            println!("Compacted database (VACUUM)");
+        DbCommands::Doctor { fix } => {
+            let db = init_pool(cfg).await?;
+            let report = doctor(&db, fix).await?;
+            if structured {
+                output.print(&report)?;
+            } else {
+                let verdict = if report.fixed { "fixed" } else { "found" };
+                for e in &report.integrity_errors {
+                    println!("Integrity error: {}", e);
+                }
+                println!("Orphaned rows {}: {}", verdict, report.orphaned_rows);
+                println!("Stuck leases {}: {}", verdict, report.stuck_leases);
+                println!(
+                    "Impossible timestamps {}: {}",
+                    verdict, report.impossible_timestamps
+                );
+                println!(
+                    "Drifted counters {}: {}",
+                    verdict, report.drifted_counters
+                );
+            }
+            // Fail so scripts notice a database that still needs attention
+            if !report.integrity_errors.is_empty() {
+                return Err(anyhow!("Database integrity check failed"));
+            }
+            if !fix && report.problems() > 0 {
+                return Err(anyhow!(
+                    "Found {} problem(s); rerun with --fix to repair them",
+                    report.problems()
+                ));
+            }
+        }
+        DbCommands::Status => {
+            let db = init_pool(cfg).await?;
+            let status = db_status(&db).await?;
+            if structured {
+                output.print(&status)?;
+                return Ok(());
+            }
+            println!(
+                "Database: {} bytes ({} pages of {} bytes, {} free)",
+                status.file_bytes,
+                status.page_count,
+                status.page_size,
+                status.free_pages
+            );
+            if let Some(wal) = status.wal_bytes {
+                println!("Write-ahead log: {} bytes", wal);
+            }
+            for t in &status.tables {
+                println!("Table {}: {} bytes", t.name, t.bytes);
+            }
+            for q in &status.queues {
+                println!(
+                    "Queue {}: {} messages, {} dead letters, {} archived, ~{} bytes",
+                    q.name,
+                    q.messages,
+                    q.dead_letters,
+                    q.archived,
+                    q.estimated_bytes
+                );
+            }
+            if status.compact_recommended {
+                println!(
+                    "Compacting is recommended: run `sqew queue compact` to reclaim {} bytes",
+                    status.free_pages * status.page_size
+                );
+            } else {
+                println!("Compacting is not needed");
+            }
+        }
+        DbCommands::RotateKey => {
+            let db = init_pool(cfg).await?;
+            let rotated = rotate_key(&db).await?;
+            if structured {
+                output.print(&serde_json::json!({ "rotated": rotated }))?;
+            } else {
+                println!("Re-encrypted {} payloads", rotated);
+            }
+        }
+        DbCommands::Replicate { replica, interval_ms, retain, once } => {
+            let replica = crate::replica::Replica::open(&replica)?;
+            let db = init_pool(cfg).await?;
+            let interval = std::time::Duration::from_millis(interval_ms.max(1));
+            let replicate = async {
+                loop {
+                    match crate::replica::replicate(&db, &replica, retain).await
+                    {
+                        Ok(snapshot) if structured => {
+                            output.print(&snapshot)?
+                        }
+                        Ok(Some(s)) => println!(
+                            "Uploaded snapshot {} ({} bytes)",
+                            s.name, s.bytes
+                        ),
+                        Ok(None) => println!("Database unchanged, skipped"),
+                        Err(e) if once => {
+                            return Err(anyhow::Error::new(e)
+                                .context("Error replicating database"));
+                        }
+                        // Keep going: the replica may only be unreachable
+                        Err(e) => tracing::error!("Replication failed: {e}"),
+                    }
+                    if once {
+                        return Ok(());
+                    }
+                    tokio::time::sleep(interval).await;
+                }
+            };
+            tokio::select! {
+                res = replicate => res?,
+                _ = tokio::signal::ctrl_c() => {}
+            }
+        }
+        DbCommands::RestoreFromReplica { replica, at } => {
+            let replica = crate::replica::Replica::open(&replica)?;
+            let at =
+                at.as_deref().map(|at| parse_time("--at", at)).transpose()?;
+            // Not opened first: the file is replaced underneath
+            let (snapshot, version) =
+                crate::replica::restore_from_replica(cfg, &replica, at)
+                    .await
+                    .context("Error restoring database")?;
+            if structured {
+                output.print(&serde_json::json!({
+                    "restored": snapshot,
+                    "version": version,
+                }))?;
+            } else {
+                println!(
+                    "Restored database from snapshot {} (schema version {})",
+                    snapshot.name, version
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+// One line describing an admin job
+fn describe_job(job: &AdminJob) -> String {
+    let mut line = format!("[{}] {} {}", job.id, job.kind, job.status);
+    if let Some(queue) = &job.queue {
+        line += &format!(" queue={queue}");
+    }
+    if let Some(path) = &job.path {
+        line += &format!(" path={path}");
+    }
+    line += &format!(" progress={}", job.progress);
+    if job.cancel_requested && job.finished_at.is_none() {
+        line += " (cancel requested)";
+    }
+    if let Some(error) = &job.error {
+        line += &format!(" error={error}");
+    }
+    line
+}
+
+/// Execute an admin job command
+pub async fn run_job_command(
+    cmd: JobCommands,
+    cfg: &Config,
+    output: OutputFormat,
+) -> anyhow::Result<()> {
+    let db = init_pool(cfg).await?;
+    let structured = output.is_structured();
+    match cmd {
+        JobCommands::List { limit } => {
+            let jobs = list_admin_jobs(&db, limit).await?;
+            if structured {
+                output.print(&jobs)?;
+            } else if jobs.is_empty() {
+                println!("No jobs found");
+            } else {
+                for job in &jobs {
+                    println!("{}", describe_job(job));
+                }
+            }
+        }
+        JobCommands::Status { id } => {
+            let job = admin_job(&db, id).await?;
+            if structured {
+                output.print(&job)?;
+            } else {
+                println!("{}", describe_job(&job));
+            }
+        }
+        JobCommands::Cancel { id } => {
+            let job = cancel_admin_job(&db, id).await?;
+            audit::record(
+                &db,
+                &Actor::cli(),
+                "job.cancel",
+                job.queue.as_deref(),
+                serde_json::json!({"job": job.id, "kind": job.kind}),
+            )
+            .await;
+            if structured {
+                output.print(&job)?;
+            } else if job.status == "canceled" {
+                println!("Canceled job {}", id);
+            } else {
+                println!("Asked job {} to stop after its current batch", id);
+            }
         }
     }
     Ok(())
 }
 
 /// Execute a message command
-pub async fn run_message_command(cmd: MessageCommands) -> Result<()> {
-    let pool = init_pool(&Config::default()).await?;
+pub async fn run_message_command(
+    cmd: MessageCommands,
+    cfg: &Config,
+    output: OutputFormat,
+) -> anyhow::Result<()> {
+    use anyhow::{Context, anyhow};
+    let db = init_pool(cfg).await?;
+    let structured = output.is_structured();
 
     match cmd {
-        MessageCommands::Enqueue { queue, payload, file, delay_ms } => {
-            let mut count = 0usize;
+        MessageCommands::Enqueue {
+            queue,
+            payload,
+            file,
+            payload_file,
+            content_type,
+            stdin,
+            batch_size,
+            delay_ms,
+            deliver_at,
+            priority,
+            ttl_ms,
+            dedup_key,
+            group_id,
+            headers,
+            trace_id,
+            fair_key,
+            sort_key,
+            reply_to,
+            wait_ms,
+        } => {
+            let opts = EnqueueOptions {
+                delay_ms,
+                deliver_at,
+                priority,
+                ttl_ms,
+                dedup_key,
+                group_id,
+                headers: Some(headers.into_iter().collect()),
+                trace_id,
+                fair_key,
+                sort_key,
+                reply_to,
+                wait_ms,
+            };
+            if stdin {
+                let input = tokio::io::BufReader::new(tokio::io::stdin());
+                let enqueued = enqueue_stream(
+                    &db,
+                    &queue,
+                    input,
+                    &opts,
+                    batch_size,
+                    |total| {
+                        if !structured {
+                            eprintln!("Enqueued {total} message(s)...");
+                        }
+                    },
+                )
+                .await?;
+                if structured {
+                    output.print(&serde_json::json!({
+                        "queue": queue,
+                        "enqueued": enqueued,
+                    }))?;
+                } else {
+                    println!(
+                        "Enqueued {} message(s) into '{}'",
+                        enqueued, queue
+                    );
+                }
+                return Ok(());
+            }
+            let mut ids = Vec::new();
             if let Some(path) = file {
-                let content =
-                    std::fs::read_to_string(&path).with_context(|| {
-                        format!("Failed to read file: {}", path.display())
-                    })?;
+                let content = anyhow::Context::with_context(
+                    std::fs::read_to_string(&path),
+                    || format!("Failed to read file: {}", path.display()),
+                )?;
                 let mut items: Vec<Value> = Vec::new();
                 if let Ok(arr) = serde_json::from_str::<Vec<Value>>(&content) {
                     items = arr;
@@ -424,73 +6514,416 @@ pub async fn run_message_command(cmd: MessageCommands) -> Result<()> {
                     }
                 }
                 for v in items {
-                    let _ =
-                        enqueue_message(&pool, &queue, &v, delay_ms).await?;
-                    count += 1;
+                    let m =
+                        enqueue_message_with(&db, &queue, &v, &opts).await?;
+                    ids.push(m.id);
                 }
             }
             if let Some(raw) = payload {
                 let v: Value = serde_json::from_str(&raw)
                     .context("Invalid JSON payload")?;
-                let _ = enqueue_message(&pool, &queue, &v, delay_ms).await?;
-                count += 1;
+                let m = enqueue_message_with(&db, &queue, &v, &opts).await?;
+                ids.push(m.id);
+            }
+            if let Some(path) = payload_file {
+                let bytes = anyhow::Context::with_context(
+                    std::fs::read(&path),
+                    || format!("Failed to read file: {}", path.display()),
+                )?;
+                let m =
+                    enqueue_bytes(&db, &queue, &bytes, &content_type, &opts)
+                        .await?;
+                ids.push(m.id);
+            }
+            if ids.is_empty() {
+                anyhow::bail!(
+                    "Provide --payload, --file, --payload-file or --stdin"
+                );
             }
-            if count == 0 {
-                anyhow::bail!("Provide --payload or --file");
+            if structured {
+                output.print(&serde_json::json!({
+                    "queue": queue,
+                    "enqueued": ids.len(),
+                    "ids": ids,
+                }))?;
+            } else {
+                println!("Enqueued {} message(s) into '{}'", ids.len(), queue);
             }
-            println!("Enqueued {} message(s) into '{}'", count, queue);
         }
-        MessageCommands::Poll { queue, batch, visibility_ms } => {
-            let msgs =
-                poll_messages(&pool, &queue, batch, visibility_ms).await?;
-            if msgs.is_empty() {
+        MessageCommands::Poll {
+            queue,
+            batch,
+            visibility_ms,
+            group,
+            consumer,
+        } => {
+            let visibility_ms = match visibility_ms {
+                Some(ms) => ms,
+                None => show_queue(&db, &queue).await?.default_visibility_ms,
+            };
+            let msgs = match group {
+                Some(group) => {
+                    poll_group_messages(
+                        &db,
+                        &queue,
+                        &group,
+                        batch,
+                        visibility_ms,
+                    )
+                    .await?
+                }
+                None => {
+                    poll_messages_as(
+                        &db,
+                        &queue,
+                        batch,
+                        visibility_ms,
+                        consumer.as_deref(),
+                    )
+                    .await?
+                }
+            };
+            if structured {
+                output.print(&msgs)?;
+            } else if msgs.is_empty() {
                 println!("No messages available in '{}'", queue);
             } else {
+                println!(
+                    "lease_token={}",
+                    msgs[0].lease_token.as_deref().unwrap_or_default()
+                );
                 for m in msgs {
                     println!(
-                        "[id={}] attempts={} available_at={} payload={}",
-                        m.id, m.attempts, m.available_at, m.payload
+                        "[id={}] priority={} attempts={} available_at={} payload={}",
+                        m.id, m.priority, m.attempts, m.available_at, m.payload
                     );
                 }
             }
         }
-        MessageCommands::Ack { ids } => {
-            let n = ack_messages(&pool, &ids).await?;
-            println!("Acked {} message(s)", n);
+        MessageCommands::Ack { ids, lease_token } => {
+            let ids = resolve_message_ids(&db, &ids).await?;
+            let n = ack_messages(&db, &ids, &lease_token).await?;
+            if structured {
+                output.print(&serde_json::json!({ "acked": n }))?;
+            } else {
+                println!("Acked {} message(s)", n);
+            }
+            if (n as usize) < ids.len() {
+                eprintln!(
+                    "{} message(s) not acked: lease token mismatch or expired",
+                    ids.len() - n as usize
+                );
+            }
+        }
+        MessageCommands::Nack {
+            ids,
+            lease_token,
+            delay_ms,
+            delays,
+            reason,
+        } => {
+            if ids.is_empty() && delays.is_empty() {
+                return Err(anyhow!("Invalid nack: give --ids or --delays"));
+            }
+            let (delayed, delays): (Vec<_>, Vec<_>) =
+                delays.into_iter().unzip();
+            let ids = resolve_message_ids(&db, &ids).await?;
+            let delayed = resolve_message_ids(&db, &delayed).await?;
+            let nacks: Vec<(i64, i64)> = ids
+                .iter()
+                .map(|&id| (id, delay_ms))
+                .chain(delayed.into_iter().zip(delays))
+                .collect();
+            let (requeued, dropped) = nack_messages_with_reason(
+                &db,
+                &nacks,
+                &lease_token,
+                reason.as_deref(),
+            )
+            .await?;
+            if structured {
+                output.print(&serde_json::json!({
+                    "requeued": requeued,
+                    "dead_lettered": dropped,
+                }))?;
+            } else {
+                println!(
+                    "Nacked: requeued={} dead_lettered={}",
+                    requeued, dropped
+                );
+            }
+        }
+        MessageCommands::Extend { ids, lease_token, extra_ms } => {
+            let ids = resolve_message_ids(&db, &ids).await?;
+            let n =
+                extend_visibility(&db, &ids, &lease_token, extra_ms).await?;
+            if structured {
+                output.print(&serde_json::json!({ "extended": n }))?;
+            } else {
+                println!("Extended {} lease(s) by {}ms", n, extra_ms);
+            }
+            if (n as usize) < ids.len() {
+                eprintln!(
+                    "{} lease(s) not extended: lease token mismatch or expired",
+                    ids.len() - n as usize
+                );
+            }
         }
-        MessageCommands::Nack { ids, delay_ms } => {
-            let (requeued, dropped) =
-                nack_messages(&pool, &ids, delay_ms).await?;
-            println!("Nacked: requeued={} dropped={}", requeued, dropped);
+        MessageCommands::Remove {
+            id: None,
+            queue: Some(queue),
+            filter,
+            older_than,
+            batch_size,
+        } => {
+            let mut params = serde_json::json!({
+                "filter": filter,
+                "older_than": older_than,
+            });
+            let filter = remove_filter(
+                filter.as_deref(),
+                older_than.as_deref(),
+                db::now_ms(),
+            )?;
+            let n = remove_messages_matching(
+                &db,
+                &queue,
+                &filter,
+                batch_size,
+                |total| {
+                    if !structured {
+                        eprintln!("Removed {total} message(s)...");
+                    }
+                },
+            )
+            .await
+            .context("Error removing messages")?;
+            params["purged"] = n.into();
+            audit::record(
+                &db,
+                &Actor::cli(),
+                "queue.purge",
+                Some(&queue),
+                params,
+            )
+            .await;
+            if structured {
+                output.print(
+                    &serde_json::json!({ "queue": queue, "removed": n }),
+                )?;
+            } else {
+                println!("Removed {} message(s) from '{}'", n, queue);
+            }
         }
-        MessageCommands::Remove { id } => {
-            if remove_message(&pool, id).await? {
+        MessageCommands::Remove { id, .. } => {
+            let id =
+                id.ok_or_else(|| anyhow!("Give a message ID or --queue"))?;
+            let id = resolve_message_id(&db, &id).await?;
+            let removed = remove_message(&db, id).await?;
+            if structured {
+                output.print(
+                    &serde_json::json!({ "id": id, "removed": removed }),
+                )?;
+            } else if removed {
                 println!("Removed message {}", id);
             } else {
                 println!("Message {} not found", id);
             }
         }
-        MessageCommands::Peek { queue, limit } => {
-            let msgs = peek_queue(&pool, &queue, limit as i64)
+        MessageCommands::Peek {
+            queue,
+            limit,
+            header,
+            offset,
+            after_id,
+            created_after,
+            created_before,
+            contains,
+            json_path,
+            state,
+        } => {
+            let filter = PeekFilter {
+                offset,
+                after_id,
+                created_after,
+                created_before,
+                header,
+                payload_contains: contains,
+                json_path,
+                state,
+            };
+            let msgs = peek_queue_filtered(&db, &queue, limit as i64, &filter)
                 .await
                 .context("Error peeking messages")?;
-            if msgs.is_empty() {
+            if structured {
+                output.print(&msgs)?;
+            } else if msgs.is_empty() {
                 println!("No messages available in '{}'", queue);
             } else {
                 for m in msgs {
                     println!(
-                        "[id={}] attempts={} available_at={} payload={}",
-                        m.id, m.attempts, m.available_at, m.payload
+                        "[id={}] priority={} attempts={} available_at={} payload={}",
+                        m.id, m.priority, m.attempts, m.available_at, m.payload
+                    );
+                }
+            }
+        }
+        MessageCommands::Tail { queue, ack, interval_ms, count } => {
+            show_queue(&db, &queue).await?;
+            let interval = std::time::Duration::from_millis(interval_ms.max(1));
+            let follow = tail_queue(&db, &queue, ack, interval, count, output);
+            tokio::select! {
+                res = follow => res?,
+                _ = tokio::signal::ctrl_c() => {}
+            }
+        }
+        MessageCommands::Search { queue, jsonpath, value, after_id, limit } => {
+            let msgs = search_messages(
+                &db,
+                &queue,
+                &jsonpath,
+                value.as_deref(),
+                after_id,
+                limit as i64,
+            )
+            .await
+            .context("Error searching messages")?;
+            if structured {
+                output.print(&msgs)?;
+            } else if msgs.is_empty() {
+                println!("No matching messages in '{}'", queue);
+            } else {
+                for m in &msgs {
+                    let state = if m.dead_at.is_some() { " dead" } else { "" };
+                    println!(
+                        "[id={}]{} attempts={} available_at={} payload={}",
+                        m.id, state, m.attempts, m.available_at, m.payload
                     );
                 }
+                if msgs.len() as u32 == limit
+                    && let Some(last) = msgs.last()
+                {
+                    println!("More results: --after-id {}", last.id);
+                }
             }
         }
         MessageCommands::PeekId { id } => {
-            let m = get_message_by_id(&pool, id).await?;
-            println!(
-                "[id={}] attempts={} available_at={} payload={}",
-                m.id, m.attempts, m.available_at, m.payload
-            );
+            let id = resolve_message_id(&db, &id).await?;
+            let m = get_message_by_id(&db, id).await?;
+            if structured {
+                output.print(&m)?;
+            } else {
+                println!(
+                    "[id={}] attempts={} available_at={} payload={}",
+                    m.id, m.attempts, m.available_at, m.payload
+                );
+                if let Some(e) = m.last_error {
+                    println!("last error: {e}");
+                }
+            }
+        }
+        MessageCommands::Payload { id, out } => {
+            let id = resolve_message_id(&db, &id).await?;
+            let m = get_message_by_id(&db, id).await?;
+            let (_, bytes) = payload_bytes(&m)?;
+            match out {
+                Some(path) => anyhow::Context::with_context(
+                    std::fs::write(&path, bytes),
+                    || format!("Failed to write file: {}", path.display()),
+                )?,
+                None => {
+                    use std::io::Write;
+                    std::io::stdout().write_all(&bytes)?;
+                }
+            }
+        }
+        MessageCommands::Move { ids, to, from, reset_attempts } => {
+            let ids = resolve_message_ids(&db, &ids).await?;
+            let n =
+                move_messages(&db, &ids, &to, from.as_deref(), reset_attempts)
+                    .await
+                    .context("Error moving messages")?;
+            audit::record(
+                &db,
+                &Actor::cli(),
+                "queue.move",
+                Some(&to),
+                serde_json::json!({
+                    "from": from,
+                    "ids": ids,
+                    "reset_attempts": reset_attempts,
+                    "moved": n,
+                }),
+            )
+            .await;
+            if structured {
+                output.print(&serde_json::json!({ "moved": n, "to": to }))?;
+            } else {
+                println!("Moved {} message(s) into '{}'", n, to);
+            }
+        }
+        MessageCommands::History { queue, limit } => {
+            let msgs = message_history(&db, &queue, limit)
+                .await
+                .context("Error listing message history")?;
+            if structured {
+                output.print(&msgs)?;
+            } else if msgs.is_empty() {
+                println!("No archived messages in '{}'", queue);
+            } else {
+                for m in msgs {
+                    println!(
+                        "[id={}] attempts={} acked_at={} payload={}",
+                        m.message_id, m.attempts, m.acked_at, m.payload
+                    );
+                }
+            }
+        }
+        MessageCommands::Attempts { id } => {
+            let id = resolve_message_id(&db, &id).await?;
+            let attempts = message_attempts(&db, id)
+                .await
+                .context("Error listing attempts")?;
+            if structured {
+                output.print(&attempts)?;
+            } else if attempts.is_empty() {
+                println!("Message {} has not been delivered", id);
+            } else {
+                for a in attempts {
+                    println!(
+                        "[attempt={}] leased_at={} consumer={} outcome={} settled_at={}{}",
+                        a.attempt,
+                        a.leased_at,
+                        a.consumer.as_deref().unwrap_or("-"),
+                        a.outcome.as_deref().unwrap_or("leased"),
+                        a.settled_at.map_or("-".into(), |t| t.to_string()),
+                        a.note.map_or(String::new(), |n| format!(" note={n}")),
+                    );
+                }
+            }
+        }
+        MessageCommands::Replay { queue, from, to, contains } => {
+            let n = replay_messages(&db, &queue, from, to, contains.as_deref())
+                .await
+                .context("Error replaying archived messages")?;
+            audit::record(
+                &db,
+                &Actor::cli(),
+                "archive.replay",
+                Some(&queue),
+                serde_json::json!({
+                    "from": from,
+                    "to": to,
+                    "contains": contains,
+                    "replayed": n,
+                }),
+            )
+            .await;
+            if structured {
+                output.print(&serde_json::json!({ "replayed": n }))?;
+            } else {
+                println!("Replayed {} archived message(s) into '{}'", n, queue);
+            }
         }
     }
     Ok(())
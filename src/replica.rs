@@ -0,0 +1,555 @@
+//! Replication of the SQLite database to object storage.
+//!
+//! `sqew db replicate` snapshots the live database every interval the way
+//! `sqew db backup` does, compresses the snapshot with zstd and uploads it
+//! to an S3-compatible bucket (or a directory, e.g. a network mount),
+//! keeping the latest few. A snapshot whose contents match the latest one
+//! uploaded is not uploaded again. `sqew db restore-from-replica` downloads
+//! the newest snapshot, or the newest taken by a given time, and restores
+//! it as `sqew db restore` does.
+
+use crate::db::{self, Db};
+use crate::error::{Context, Result, SqewError};
+use crate::queue::{self, Config};
+use anyhow::anyhow;
+use chrono::{DateTime, Utc};
+use clap::Args;
+use hmac::{Hmac, Mac};
+use reqwest::{Method, Url};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::path::PathBuf;
+
+// zstd level snapshots are compressed at
+const ZSTD_LEVEL: i32 = 3;
+
+// Hex digits of the SHA-256 of its contents a snapshot is named by
+const DIGEST_LEN: usize = 16;
+
+/// Where replicas are kept and how to reach them
+#[derive(Args, Debug, Clone)]
+pub struct ReplicaOptions {
+    /// Replica location: `s3://<bucket>[/<prefix>]` or `file://<dir>`
+    #[arg(long = "replica", env = "SQEW_REPLICA_URL")]
+    pub url: String,
+    /// S3 endpoint, for S3-compatible stores such as MinIO (default:
+    /// https://s3.<region>.amazonaws.com)
+    #[arg(long, env = "SQEW_REPLICA_ENDPOINT")]
+    pub endpoint: Option<String>,
+    /// S3 region
+    #[arg(long, env = "AWS_REGION", default_value = "us-east-1")]
+    pub region: String,
+    /// S3 access key id
+    #[arg(long, env = "AWS_ACCESS_KEY_ID", hide_env_values = true)]
+    pub access_key_id: Option<String>,
+    /// S3 secret access key
+    #[arg(long, env = "AWS_SECRET_ACCESS_KEY", hide_env_values = true)]
+    pub secret_access_key: Option<String>,
+    /// Session token of temporary credentials
+    #[arg(long, env = "AWS_SESSION_TOKEN", hide_env_values = true)]
+    pub session_token: Option<String>,
+}
+
+/// A database snapshot kept in a replica
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Snapshot {
+    /// Object name under the replica's location
+    pub name: String,
+    /// When it was taken, in milliseconds since the Unix epoch
+    pub taken_at: i64,
+    /// Leading hex digits of the SHA-256 of the uncompressed database
+    pub digest: String,
+    /// Compressed size in bytes
+    pub bytes: u64,
+}
+
+impl Snapshot {
+    // The snapshot named `name`, if it is one; names sort by time taken
+    fn parse(
+        name: &str,
+        bytes: u64,
+    ) -> Option<Snapshot> {
+        let (taken_at, digest) =
+            name.strip_suffix(".db.zst")?.split_once('-')?;
+        Some(Snapshot {
+            name: name.to_string(),
+            taken_at: taken_at.parse().ok()?,
+            digest: digest.to_string(),
+            bytes,
+        })
+    }
+}
+
+/// Access key used to sign S3 requests
+#[derive(Clone)]
+pub struct Credentials {
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    pub session_token: Option<String>,
+}
+
+impl std::fmt::Debug for Credentials {
+    fn fmt(
+        &self,
+        f: &mut std::fmt::Formatter<'_>,
+    ) -> std::fmt::Result {
+        f.debug_struct("Credentials")
+            .field("access_key_id", &self.access_key_id)
+            .finish_non_exhaustive()
+    }
+}
+
+/// A place snapshots are uploaded to and downloaded from
+#[derive(Debug)]
+pub enum Replica {
+    Dir(PathBuf),
+    S3(S3Bucket),
+}
+
+impl Replica {
+    /// The replica `opts` point at; S3 replicas need credentials
+    pub fn open(opts: &ReplicaOptions) -> Result<Replica> {
+        if let Some(dir) = opts.url.strip_prefix("file://") {
+            return Ok(Replica::Dir(PathBuf::from(dir)));
+        }
+        let Some(location) = opts.url.strip_prefix("s3://") else {
+            return Err(SqewError::Invalid(format!(
+                "replica '{}': expected s3://<bucket>[/<prefix>] or file://<dir>",
+                opts.url
+            )));
+        };
+        let (bucket, prefix) =
+            location.split_once('/').unwrap_or((location, ""));
+        let prefix = prefix.trim_matches('/');
+        let (Some(access_key_id), Some(secret_access_key)) =
+            (&opts.access_key_id, &opts.secret_access_key)
+        else {
+            return Err(SqewError::Invalid(
+                "replica: S3 needs --access-key-id and --secret-access-key"
+                    .into(),
+            ));
+        };
+        let endpoint = match &opts.endpoint {
+            Some(endpoint) => endpoint.trim_end_matches('/').to_string(),
+            None => format!("https://s3.{}.amazonaws.com", opts.region),
+        };
+        Ok(Replica::S3(S3Bucket {
+            http: reqwest::Client::new(),
+            endpoint,
+            bucket: bucket.to_string(),
+            prefix: if prefix.is_empty() {
+                String::new()
+            } else {
+                format!("{prefix}/")
+            },
+            region: opts.region.clone(),
+            credentials: Credentials {
+                access_key_id: access_key_id.clone(),
+                secret_access_key: secret_access_key.clone(),
+                session_token: opts.session_token.clone(),
+            },
+        }))
+    }
+
+    /// The snapshots in the replica, oldest first
+    pub async fn snapshots(&self) -> Result<Vec<Snapshot>> {
+        let mut snapshots = match self {
+            Replica::Dir(dir) => {
+                let entries = match std::fs::read_dir(dir) {
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                        return Ok(Vec::new());
+                    }
+                    entries => entries.with_context(|| {
+                        format!("Failed to list {}", dir.display())
+                    })?,
+                };
+                let mut snapshots = Vec::new();
+                for entry in entries {
+                    let entry = entry.context("Failed to list replica")?;
+                    let bytes = entry.metadata().map_or(0, |m| m.len());
+                    let name = entry.file_name().to_string_lossy().into_owned();
+                    snapshots.extend(Snapshot::parse(&name, bytes));
+                }
+                snapshots
+            }
+            Replica::S3(s3) => s3.list().await?,
+        };
+        snapshots.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(snapshots)
+    }
+
+    async fn put(
+        &self,
+        name: &str,
+        body: Vec<u8>,
+    ) -> Result<()> {
+        match self {
+            Replica::Dir(dir) => {
+                std::fs::create_dir_all(dir).with_context(|| {
+                    format!("Failed to create {}", dir.display())
+                })?;
+                // Renamed into place so a partial upload is never listed
+                let partial = dir.join(format!(".{name}.partial"));
+                std::fs::write(&partial, body)
+                    .and_then(|_| std::fs::rename(&partial, dir.join(name)))
+                    .with_context(|| format!("Failed to write {name}"))
+            }
+            Replica::S3(s3) => {
+                s3.send(Method::PUT, s3.object_url(name)?, body).await?;
+                Ok(())
+            }
+        }
+    }
+
+    async fn get(
+        &self,
+        name: &str,
+    ) -> Result<Vec<u8>> {
+        match self {
+            Replica::Dir(dir) => std::fs::read(dir.join(name))
+                .with_context(|| format!("Failed to read {name}")),
+            Replica::S3(s3) => {
+                let url = s3.object_url(name)?;
+                let resp = s3.send(Method::GET, url, Vec::new()).await?;
+                let body = resp
+                    .bytes()
+                    .await
+                    .map_err(|e| anyhow!("Failed to download {name}: {e}"))?;
+                Ok(body.to_vec())
+            }
+        }
+    }
+
+    async fn delete(
+        &self,
+        name: &str,
+    ) -> Result<()> {
+        match self {
+            Replica::Dir(dir) => std::fs::remove_file(dir.join(name))
+                .with_context(|| format!("Failed to delete {name}")),
+            Replica::S3(s3) => {
+                let url = s3.object_url(name)?;
+                s3.send(Method::DELETE, url, Vec::new()).await?;
+                Ok(())
+            }
+        }
+    }
+}
+
+/// An S3 bucket, or a prefix in one, addressed path-style
+#[derive(Debug)]
+pub struct S3Bucket {
+    http: reqwest::Client,
+    endpoint: String,
+    bucket: String,
+    // Empty, or ending in '/'
+    prefix: String,
+    region: String,
+    credentials: Credentials,
+}
+
+impl S3Bucket {
+    fn object_url(
+        &self,
+        name: &str,
+    ) -> Result<Url> {
+        let key = format!("{}{}", self.prefix, name);
+        let url = format!(
+            "{}/{}/{}",
+            self.endpoint,
+            uri_encode(&self.bucket, true),
+            uri_encode(&key, false)
+        );
+        Url::parse(&url).map_err(|e| {
+            SqewError::Invalid(format!("replica URL '{url}': {e}"))
+        })
+    }
+
+    // Every snapshot under the prefix, following continuation tokens
+    async fn list(&self) -> Result<Vec<Snapshot>> {
+        let mut snapshots = Vec::new();
+        let mut token: Option<String> = None;
+        loop {
+            let mut url = self.object_url("")?;
+            url.set_path(&format!("/{}", uri_encode(&self.bucket, true)));
+            url.query_pairs_mut()
+                .append_pair("list-type", "2")
+                .append_pair("prefix", &self.prefix);
+            if let Some(token) = &token {
+                url.query_pairs_mut().append_pair("continuation-token", token);
+            }
+            let resp = self.send(Method::GET, url, Vec::new()).await?;
+            let xml = resp
+                .text()
+                .await
+                .map_err(|e| anyhow!("Failed to list replica: {e}"))?;
+            for contents in xml_elements(&xml, "Contents") {
+                let key = xml_elements(contents, "Key").next().unwrap_or("");
+                let size = xml_elements(contents, "Size").next();
+                let name = xml_unescape(key);
+                let name = name.strip_prefix(&self.prefix).unwrap_or(&name);
+                let bytes = size.and_then(|s| s.parse().ok()).unwrap_or(0);
+                snapshots.extend(Snapshot::parse(name, bytes));
+            }
+            token = xml_elements(&xml, "NextContinuationToken")
+                .next()
+                .map(xml_unescape);
+            if xml_elements(&xml, "IsTruncated").next() != Some("true")
+                || token.is_none()
+            {
+                return Ok(snapshots);
+            }
+        }
+    }
+
+    // Sign and send a request, failing on any status but 2xx
+    async fn send(
+        &self,
+        method: Method,
+        url: Url,
+        body: Vec<u8>,
+    ) -> Result<reqwest::Response> {
+        let now = Utc::now();
+        let payload_sha256 = hex(&Sha256::digest(&body));
+        let mut headers = vec![
+            ("host".to_string(), host(&url)),
+            ("x-amz-content-sha256".to_string(), payload_sha256.clone()),
+            (
+                "x-amz-date".to_string(),
+                now.format("%Y%m%dT%H%M%SZ").to_string(),
+            ),
+        ];
+        if let Some(token) = &self.credentials.session_token {
+            headers.push(("x-amz-security-token".to_string(), token.clone()));
+        }
+        let authorization = sign_v4(
+            method.as_str(),
+            &url,
+            &headers,
+            &payload_sha256,
+            &self.credentials,
+            &self.region,
+            now,
+        );
+        let mut req = self
+            .http
+            .request(method.clone(), url.clone())
+            .header("authorization", authorization)
+            .body(body);
+        // reqwest sends the host itself
+        for (name, value) in headers.iter().filter(|(n, _)| n != "host") {
+            req = req.header(name, value);
+        }
+        let what = format!("S3 {} {}", method, url.path());
+        let resp = req.send().await.map_err(|e| anyhow!("{what}: {e}"))?;
+        let status = resp.status();
+        if !status.is_success() {
+            let text = resp.text().await.unwrap_or_default();
+            return Err(anyhow!("{what} failed with {status}: {text}").into());
+        }
+        Ok(resp)
+    }
+}
+
+/// AWS Signature Version 4 `Authorization` header for an S3 request to
+/// `url` at `at`. `headers` are the headers signed, `host`, `x-amz-date`
+/// and `x-amz-content-sha256` among them; `payload_sha256` is the hex
+/// SHA-256 of the body.
+pub fn sign_v4(
+    method: &str,
+    url: &Url,
+    headers: &[(String, String)],
+    payload_sha256: &str,
+    credentials: &Credentials,
+    region: &str,
+    at: DateTime<Utc>,
+) -> String {
+    let amz_date = at.format("%Y%m%dT%H%M%SZ").to_string();
+    let date = at.format("%Y%m%d").to_string();
+    let mut query: Vec<(String, String)> = url
+        .query_pairs()
+        .map(|(k, v)| (uri_encode(&k, true), uri_encode(&v, true)))
+        .collect();
+    query.sort();
+    let query: Vec<String> =
+        query.iter().map(|(k, v)| format!("{k}={v}")).collect();
+    let mut headers: Vec<(String, String)> = headers
+        .iter()
+        .map(|(n, v)| (n.to_lowercase(), v.trim().to_string()))
+        .collect();
+    headers.sort();
+    let canonical_headers: String =
+        headers.iter().map(|(n, v)| format!("{n}:{v}\n")).collect();
+    let signed_headers: Vec<&str> =
+        headers.iter().map(|(n, _)| n.as_str()).collect();
+    let signed_headers = signed_headers.join(";");
+    let canonical_request = format!(
+        "{method}\n{}\n{}\n{canonical_headers}\n{signed_headers}\n{payload_sha256}",
+        url.path(),
+        query.join("&")
+    );
+    let scope = format!("{date}/{region}/s3/aws4_request");
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{amz_date}\n{scope}\n{}",
+        hex(&Sha256::digest(canonical_request.as_bytes()))
+    );
+    let secret = format!("AWS4{}", credentials.secret_access_key);
+    let key = [date.as_str(), region, "s3", "aws4_request"]
+        .iter()
+        .fold(secret.into_bytes(), |key, part| hmac(&key, part.as_bytes()));
+    let signature = hex(&hmac(&key, string_to_sign.as_bytes()));
+    format!(
+        "AWS4-HMAC-SHA256 Credential={}/{scope},SignedHeaders={signed_headers},Signature={signature}",
+        credentials.access_key_id
+    )
+}
+
+fn hmac(
+    key: &[u8],
+    data: &[u8],
+) -> Vec<u8> {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes any key");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+// `host` or `host:port` as sent in the Host header
+fn host(url: &Url) -> String {
+    let host = url.host_str().unwrap_or_default();
+    match url.port() {
+        Some(port) => format!("{host}:{port}"),
+        None => host.to_string(),
+    }
+}
+
+// Percent-encode all but unreserved characters (and `/` unless
+// `encode_slash`), as SigV4 canonical URIs and queries require
+fn uri_encode(
+    s: &str,
+    encode_slash: bool,
+) -> String {
+    let mut out = String::with_capacity(s.len());
+    for b in s.bytes() {
+        match b {
+            b'A'..=b'Z'
+            | b'a'..=b'z'
+            | b'0'..=b'9'
+            | b'-'
+            | b'_'
+            | b'.'
+            | b'~' => out.push(b as char),
+            b'/' if !encode_slash => out.push('/'),
+            _ => out.push_str(&format!("%{b:02X}")),
+        }
+    }
+    out
+}
+
+// The contents of each `<tag>...</tag>` element in `xml`, in order
+fn xml_elements<'a>(
+    xml: &'a str,
+    tag: &str,
+) -> impl Iterator<Item = &'a str> {
+    let (open, close) = (format!("<{tag}>"), format!("</{tag}>"));
+    let mut rest = xml;
+    std::iter::from_fn(move || {
+        let start = rest.find(&open)? + open.len();
+        let len = rest[start..].find(&close)?;
+        let inner = &rest[start..start + len];
+        rest = &rest[start + len + close.len()..];
+        Some(inner)
+    })
+}
+
+fn xml_unescape(s: &str) -> String {
+    s.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+// A file in the temporary directory for a snapshot on its way in or out
+fn temp_snapshot_path() -> PathBuf {
+    std::env::temp_dir()
+        .join(format!("sqew-snapshot-{}.db", uuid::Uuid::new_v4()))
+}
+
+/// Snapshot the database and upload it to `replica`, unless the latest
+/// snapshot there holds the same contents, then delete all but the latest
+/// `retain` snapshots. Returns the snapshot uploaded, if any. Only SQLite
+/// databases can be replicated.
+#[tracing::instrument(level = "debug", skip_all)]
+pub async fn replicate(
+    db: &Db,
+    replica: &Replica,
+    retain: usize,
+) -> Result<Option<Snapshot>> {
+    let taken_at = db::now_ms();
+    let path = temp_snapshot_path();
+    let raw = match queue::backup_database(db, &path).await {
+        Ok(_) => std::fs::read(&path).context("Failed to read snapshot"),
+        Err(e) => Err(e),
+    };
+    let _ = std::fs::remove_file(&path);
+    let raw = raw?;
+    let digest = hex(&Sha256::digest(&raw))[..DIGEST_LEN].to_string();
+    let mut snapshots = replica.snapshots().await?;
+    let uploaded = if snapshots.last().is_some_and(|s| s.digest == digest) {
+        None
+    } else {
+        let body = zstd::encode_all(&raw[..], ZSTD_LEVEL)
+            .context("Failed to compress snapshot")?;
+        let snapshot = Snapshot {
+            name: format!("{taken_at:013}-{digest}.db.zst"),
+            taken_at,
+            digest,
+            bytes: body.len() as u64,
+        };
+        replica.put(&snapshot.name, body).await?;
+        snapshots.push(snapshot.clone());
+        Some(snapshot)
+    };
+    let excess = snapshots.len().saturating_sub(retain.max(1));
+    for old in &snapshots[..excess] {
+        replica.delete(&old.name).await?;
+    }
+    Ok(uploaded)
+}
+
+/// Replace the SQLite database selected by `cfg` with the newest snapshot
+/// in `replica` taken at or before `at` (the newest of all when `None`), as
+/// [`queue::restore_database`] does. Returns the snapshot restored and the
+/// schema version. Nothing else may be using the database meanwhile.
+pub async fn restore_from_replica(
+    cfg: &Config,
+    replica: &Replica,
+    at: Option<i64>,
+) -> Result<(Snapshot, i64)> {
+    let snapshots = replica.snapshots().await?;
+    let Some(snapshot) = snapshots
+        .into_iter()
+        .rev()
+        .find(|s| at.is_none_or(|at| s.taken_at <= at))
+    else {
+        return Err(SqewError::Invalid(match at {
+            Some(at) => format!("replica: no snapshot taken by {at}"),
+            None => "replica: no snapshots".into(),
+        }));
+    };
+    let body = replica.get(&snapshot.name).await?;
+    let raw =
+        zstd::decode_all(&body[..]).context("Failed to decompress snapshot")?;
+    let path = temp_snapshot_path();
+    let restored = match std::fs::write(&path, raw) {
+        Ok(()) => queue::restore_database(cfg, &path).await,
+        Err(e) => Err(e.into()),
+    };
+    let _ = std::fs::remove_file(&path);
+    Ok((snapshot, restored?))
+}
@@ -0,0 +1,437 @@
+//! A listener speaking a subset of the Redis protocol (RESP), so clients of
+//! Redis list-based queues can use sqew unchanged.
+//!
+//! Keys name queues. `LPUSH` enqueues (creating the queue with default
+//! settings if needed), `RPOP`/`BRPOP` lease and immediately ack the oldest
+//! ready message, and `LLEN` counts ready messages. Values that parse as JSON
+//! are stored as that JSON; anything else is stored as a JSON string, and
+//! popped back as the original text. When the server has API keys, clients
+//! must `AUTH` with one first, and the key's role and queue pattern limit
+//! which keys they may push to, pop from or count.
+
+use crate::auth::{Grant, Permission};
+use crate::db::{self, Db};
+use crate::error::SqewError;
+use crate::queue;
+use crate::server::{AppState, POLL_RECHECK_INTERVAL};
+use serde_json::Value;
+use std::future::{Future, poll_fn};
+use std::pin::Pin;
+use std::task::Poll;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::watch;
+use tokio::task::JoinSet;
+
+/// Largest bulk string (pushed value) accepted from a client
+pub const MAX_BULK_LEN: usize = 64 * 1024 * 1024;
+
+/// Largest number of arguments accepted in one command
+const MAX_ARGS: usize = 1024;
+
+/// A reply in the Redis protocol
+#[derive(Debug, Clone, PartialEq)]
+pub enum Reply {
+    Simple(&'static str),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<String>),
+    /// `None` is the null array BRPOP answers on timeout
+    Array(Option<Vec<Reply>>),
+}
+
+impl Reply {
+    fn encode(
+        &self,
+        out: &mut Vec<u8>,
+    ) {
+        match self {
+            Reply::Simple(s) => {
+                out.extend_from_slice(format!("+{s}\r\n").as_bytes())
+            }
+            Reply::Error(e) => {
+                // Errors are single-line
+                let line = e.replace(['\r', '\n'], " ");
+                out.extend_from_slice(format!("-{line}\r\n").as_bytes());
+            }
+            Reply::Integer(n) => {
+                out.extend_from_slice(format!(":{n}\r\n").as_bytes())
+            }
+            Reply::Bulk(None) => out.extend_from_slice(b"$-1\r\n"),
+            Reply::Bulk(Some(s)) => {
+                out.extend_from_slice(format!("${}\r\n", s.len()).as_bytes());
+                out.extend_from_slice(s.as_bytes());
+                out.extend_from_slice(b"\r\n");
+            }
+            Reply::Array(None) => out.extend_from_slice(b"*-1\r\n"),
+            Reply::Array(Some(items)) => {
+                out.extend_from_slice(
+                    format!("*{}\r\n", items.len()).as_bytes(),
+                );
+                for item in items {
+                    item.encode(out);
+                }
+            }
+        }
+    }
+}
+
+/// Accept RESP connections on `listener` until `stop` turns true, serving
+/// each against the same database and wakeups as the HTTP API
+pub async fn serve_resp(
+    listener: TcpListener,
+    state: AppState,
+    mut stop: watch::Receiver<bool>,
+) {
+    let mut conns = JoinSet::new();
+    let conn_stop = stop.clone();
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, peer)) => {
+                    tracing::debug!(%peer, "redis client connected");
+                    conns.spawn(handle_connection(
+                        stream,
+                        state.clone(),
+                        conn_stop.clone(),
+                    ));
+                }
+                Err(e) => tracing::warn!("Redis accept failed: {e}"),
+            },
+            // Reap finished connections so the set does not grow unbounded
+            Some(_) = conns.join_next(), if !conns.is_empty() => {}
+            _ = stop.wait_for(|stop| *stop) => break,
+        }
+    }
+    // Connections notice the stop themselves; blocked pops answer nil
+    while conns.join_next().await.is_some() {}
+}
+
+// Serve one client until it disconnects, sends QUIT or the server stops
+async fn handle_connection(
+    stream: TcpStream,
+    state: AppState,
+    mut stop: watch::Receiver<bool>,
+) {
+    let (read, mut write) = stream.into_split();
+    let mut reader = BufReader::new(read);
+    // With API keys configured, only AUTH and QUIT work until AUTH succeeds
+    let mut grant = match state.requires_auth().await {
+        Ok(false) => Some(Grant::admin()),
+        Ok(true) | Err(_) => None,
+    };
+    loop {
+        let args = tokio::select! {
+            args = read_command(&mut reader) => args,
+            _ = stop.wait_for(|stop| *stop) => return,
+        };
+        let args = match args {
+            Ok(Some(args)) => args,
+            Ok(None) => return,
+            Err(e) => {
+                // The stream is out of sync; report and hang up
+                let mut out = Vec::new();
+                Reply::Error(format!("ERR Protocol error: {e}"))
+                    .encode(&mut out);
+                let _ = write.write_all(&out).await;
+                return;
+            }
+        };
+        if args.is_empty() {
+            continue;
+        }
+        let quit = args[0].eq_ignore_ascii_case("quit");
+        let reply = if quit {
+            Reply::Simple("OK")
+        } else if args[0].eq_ignore_ascii_case("auth") {
+            match auth(&state, &args).await {
+                Ok(authed) => {
+                    grant = Some(authed);
+                    Reply::Simple("OK")
+                }
+                Err(reply) => reply,
+            }
+        } else if let Some(grant) = &grant {
+            execute(&state, grant, &args, stop.clone()).await
+        } else {
+            Reply::Error("NOAUTH Authentication required.".into())
+        };
+        let mut out = Vec::new();
+        reply.encode(&mut out);
+        if write.write_all(&out).await.is_err() || quit {
+            return;
+        }
+    }
+}
+
+// Read one command: a RESP array of bulk strings, or an inline command line.
+// `None` at end of stream.
+async fn read_command<R: tokio::io::AsyncBufRead + Unpin>(
+    reader: &mut R
+) -> std::io::Result<Option<Vec<String>>> {
+    let Some(line) = read_line(reader).await? else {
+        return Ok(None);
+    };
+    let Some(count) = line.strip_prefix('*') else {
+        // Inline command, as typed into telnet
+        return Ok(Some(line.split_whitespace().map(str::to_string).collect()));
+    };
+    let count = parse_len(count, MAX_ARGS)?;
+    let mut args = Vec::with_capacity(count);
+    for _ in 0..count {
+        let Some(header) = read_line(reader).await? else {
+            return Ok(None);
+        };
+        let Some(len) = header.strip_prefix('$') else {
+            return Err(invalid("expected '$'"));
+        };
+        let len = parse_len(len, MAX_BULK_LEN)?;
+        let mut buf = vec![0; len + 2];
+        reader.read_exact(&mut buf).await?;
+        if !buf.ends_with(b"\r\n") {
+            return Err(invalid("bulk string not terminated by CRLF"));
+        }
+        buf.truncate(len);
+        let arg = String::from_utf8(buf)
+            .map_err(|_| invalid("arguments must be UTF-8"))?;
+        args.push(arg);
+    }
+    Ok(Some(args))
+}
+
+// Read a CRLF- (or LF-) terminated line; `None` at end of stream
+async fn read_line<R: tokio::io::AsyncBufRead + Unpin>(
+    reader: &mut R
+) -> std::io::Result<Option<String>> {
+    let mut line = Vec::new();
+    let n =
+        reader.take(MAX_BULK_LEN as u64).read_until(b'\n', &mut line).await?;
+    if n == 0 {
+        return Ok(None);
+    }
+    if line.pop() != Some(b'\n') {
+        return Err(invalid("line too long"));
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    String::from_utf8(line)
+        .map(Some)
+        .map_err(|_| invalid("arguments must be UTF-8"))
+}
+
+fn parse_len(
+    s: &str,
+    max: usize,
+) -> std::io::Result<usize> {
+    match s.parse::<usize>() {
+        Ok(n) if n <= max => Ok(n),
+        Ok(_) => Err(invalid("length too large")),
+        Err(_) => Err(invalid("invalid length")),
+    }
+}
+
+fn invalid(msg: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, msg.to_string())
+}
+
+// Check `AUTH <key>` (or Redis 6 style `AUTH <user> <key>`, ignoring the
+// user) against the server's API keys, returning the key's grant
+async fn auth(
+    state: &AppState,
+    args: &[String],
+) -> Result<Grant, Reply> {
+    let key = match args {
+        [_, key] | [_, _, key] => key,
+        _ => {
+            return Err(Reply::Error(
+                "ERR wrong number of arguments for 'auth' command".into(),
+            ));
+        }
+    };
+    let storage_error = |e: sqlx::Error| Reply::Error(format!("ERR {e}"));
+    if !state.requires_auth().await.map_err(storage_error)? {
+        return Err(Reply::Error(
+            "ERR AUTH called without any API key configured".into(),
+        ));
+    }
+    state
+        .grant_for(key)
+        .await
+        .map_err(storage_error)?
+        .ok_or_else(|| Reply::Error("WRONGPASS invalid API key".into()))
+}
+
+// Run a command and build its reply
+async fn execute(
+    state: &AppState,
+    grant: &Grant,
+    args: &[String],
+    stop: watch::Receiver<bool>,
+) -> Reply {
+    let name = args[0].to_ascii_lowercase();
+    let arity_ok = match name.as_str() {
+        "ping" => args.len() <= 2,
+        "select" | "rpop" | "llen" => args.len() == 2,
+        "lpush" | "brpop" => args.len() >= 3,
+        _ => return Reply::Error(format!("ERR unknown command '{}'", args[0])),
+    };
+    if !arity_ok {
+        return Reply::Error(format!(
+            "ERR wrong number of arguments for '{name}' command"
+        ));
+    }
+    // The keys (queues) the command touches and what it does to them
+    let (perm, keys) = match name.as_str() {
+        "lpush" => (Permission::Produce, &args[1..2]),
+        "rpop" => (Permission::Consume, &args[1..2]),
+        "brpop" => (Permission::Consume, &args[1..args.len() - 1]),
+        "llen" => (Permission::Read, &args[1..2]),
+        _ => (Permission::Read, &args[..0]),
+    };
+    if let Some(key) = keys.iter().find(|k| !grant.allows(perm, Some(k))) {
+        return Reply::Error(format!(
+            "NOPERM this API key may not run '{name}' on '{key}'"
+        ));
+    }
+    let result = match name.as_str() {
+        "ping" => match args.get(1) {
+            Some(msg) => Ok(Reply::Bulk(Some(msg.clone()))),
+            None => Ok(Reply::Simple("PONG")),
+        },
+        // A single keyspace; accepted so clients configured with a database
+        // number still connect
+        "select" => Ok(Reply::Simple("OK")),
+        "lpush" => lpush(state, &args[1], &args[2..]).await,
+        "rpop" => pop(&state.db, &args[1])
+            .await
+            .map(|popped| Reply::Bulk(popped.map(|(_, value)| value))),
+        "llen" => llen(&state.db, &args[1]).await,
+        "brpop" => brpop(state, &args[1..], stop).await,
+        _ => unreachable!("arity checked above"),
+    };
+    result.unwrap_or_else(|e| Reply::Error(format!("ERR {e}")))
+}
+
+// Enqueue each value, creating the queue on first use; replies with the
+// queue's ready count like Redis replies with the list length
+async fn lpush(
+    state: &AppState,
+    key: &str,
+    values: &[String],
+) -> anyhow::Result<Reply> {
+    state.ensure_queue(key).await?;
+    for value in values {
+        let payload = to_payload(value);
+        state.check_payload_size(&payload)?;
+        queue::enqueue_message(&state.db, key, &payload, 0).await?;
+    }
+    state.notifier.notify(key);
+    llen(&state.db, key).await
+}
+
+async fn llen(
+    db: &Db,
+    key: &str,
+) -> anyhow::Result<Reply> {
+    match queue::stats(db, key).await {
+        Ok(stats) => Ok(Reply::Integer(stats["ready"].as_i64().unwrap_or(0))),
+        // A missing key is an empty list
+        Err(SqewError::QueueNotFound(_)) => Ok(Reply::Integer(0)),
+        Err(e) => Err(e.into()),
+    }
+}
+
+// Lease and ack the next ready message of `key`, returning the key and the
+// message's value
+async fn pop(
+    db: &Db,
+    key: &str,
+) -> anyhow::Result<Option<(String, String)>> {
+    let leased =
+        queue::poll_messages(db, key, 1, db::DEFAULT_VISIBILITY_MS).await?;
+    let Some(m) = leased.into_iter().next() else {
+        return Ok(None);
+    };
+    let token = m.lease_token.as_deref().unwrap_or_default();
+    queue::ack_messages(db, &[m.id], token).await?;
+    Ok(Some((key.to_string(), from_payload(&m.payload))))
+}
+
+// Pop from the first of `keys` with a ready message, waiting up to the
+// timeout (seconds, 0 = forever) in the last argument
+async fn brpop(
+    state: &AppState,
+    args: &[String],
+    mut stop: watch::Receiver<bool>,
+) -> anyhow::Result<Reply> {
+    let (timeout, keys) = args.split_last().expect("arity checked");
+    let timeout: f64 = timeout
+        .parse()
+        .ok()
+        .filter(|t: &f64| t.is_finite() && *t >= 0.0)
+        .ok_or_else(|| {
+            anyhow::anyhow!("timeout is not a float or out of range")
+        })?;
+    let deadline = (timeout > 0.0).then(|| {
+        tokio::time::Instant::now() + Duration::from_secs_f64(timeout)
+    });
+    let wakeups: Vec<_> =
+        keys.iter().map(|k| state.notifier.handle(k)).collect();
+    loop {
+        // Register for wakeups before popping so an enqueue landing between
+        // the pop and the wait is not missed
+        let mut notified: Vec<_> =
+            wakeups.iter().map(|w| Box::pin(w.notified())).collect();
+        for n in &mut notified {
+            n.as_mut().enable();
+        }
+        for key in keys {
+            if let Some((key, value)) = pop(&state.db, key).await? {
+                return Ok(Reply::Array(Some(vec![
+                    Reply::Bulk(Some(key)),
+                    Reply::Bulk(Some(value)),
+                ])));
+            }
+        }
+        let now = tokio::time::Instant::now();
+        let recheck = match deadline {
+            Some(deadline) if now >= deadline => return Ok(Reply::Array(None)),
+            Some(deadline) => (deadline - now).min(POLL_RECHECK_INTERVAL),
+            None => POLL_RECHECK_INTERVAL,
+        };
+        tokio::select! {
+            _ = any_notified(&mut notified) => {}
+            _ = tokio::time::sleep(recheck) => {}
+            // Answer nil rather than hold up a shutdown
+            _ = stop.wait_for(|stop| *stop) => return Ok(Reply::Array(None)),
+        }
+    }
+}
+
+// Resolve once any of the wakeups fires
+async fn any_notified<F: Future<Output = ()>>(notified: &mut [Pin<Box<F>>]) {
+    poll_fn(|cx| {
+        for n in notified.iter_mut() {
+            if n.as_mut().poll(cx).is_ready() {
+                return Poll::Ready(());
+            }
+        }
+        Poll::Pending
+    })
+    .await
+}
+
+// The payload stored for a pushed value
+pub(crate) fn to_payload(value: &str) -> Value {
+    serde_json::from_str(value).unwrap_or_else(|_| Value::String(value.into()))
+}
+
+// The value popped for a stored payload: JSON strings come back unquoted
+pub(crate) fn from_payload(payload: &str) -> String {
+    match serde_json::from_str::<Value>(payload) {
+        Ok(Value::String(s)) => s,
+        _ => payload.to_string(),
+    }
+}
@@ -0,0 +1,192 @@
+//! Fault injection for testing consumers, enabled with `sqew serve --chaos`.
+//!
+//! Each API request meets a fault with probability `p`, picked at random
+//! from the enabled ones: `delay` holds the request for up to `delay_ms`
+//! before handling it, `unavailable` answers `503` without handling it, and
+//! `redeliver` lets a poll succeed but ends the lease of its first message at
+//! once, so the message is delivered again and the first consumer's ack
+//! fails. Responses that met a fault name it in an `x-sqew-chaos` header.
+
+use super::AppState;
+use crate::queue;
+use axum::{
+    body::Body,
+    extract::{Request, State},
+    http::{HeaderValue, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use rand::Rng;
+use rand::seq::SliceRandom;
+use serde_json::{Value, json};
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Longest delay injected when `delay_ms` is not given
+pub const DEFAULT_CHAOS_DELAY: Duration = Duration::from_secs(2);
+
+/// Largest poll response read back to redeliver one of its messages
+const MAX_POLL_BODY: usize = 64 * 1024 * 1024;
+
+/// A fault the server can inject
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    Delay,
+    Unavailable,
+    Redeliver,
+}
+
+impl Fault {
+    pub fn name(self) -> &'static str {
+        match self {
+            Fault::Delay => "delay",
+            Fault::Unavailable => "unavailable",
+            Fault::Redeliver => "redeliver",
+        }
+    }
+}
+
+/// How often and how `sqew serve` misbehaves, parsed from a spec such as
+/// `p=0.05` or `p=0.2,delay_ms=500,faults=unavailable+redeliver`
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChaosConfig {
+    /// Chance, from 0 to 1, that a request meets a fault
+    pub probability: f64,
+    /// Longest injected delay
+    pub max_delay: Duration,
+    /// Faults to pick from
+    pub faults: Vec<Fault>,
+}
+
+impl FromStr for ChaosConfig {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let mut cfg = ChaosConfig {
+            probability: f64::NAN,
+            max_delay: DEFAULT_CHAOS_DELAY,
+            faults: vec![Fault::Delay, Fault::Unavailable, Fault::Redeliver],
+        };
+        for part in spec.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            let (key, value) = part
+                .split_once('=')
+                .ok_or_else(|| format!("expected key=value, got '{part}'"))?;
+            match key.trim() {
+                "p" => {
+                    cfg.probability = value
+                        .parse()
+                        .ok()
+                        .filter(|p| (0.0..=1.0).contains(p))
+                        .ok_or_else(|| {
+                            format!("p must be between 0 and 1, got '{value}'")
+                        })?;
+                }
+                "delay_ms" => {
+                    let ms = value.parse().map_err(|_| {
+                        format!("delay_ms must be a number, got '{value}'")
+                    })?;
+                    cfg.max_delay = Duration::from_millis(ms);
+                }
+                "faults" => {
+                    cfg.faults = value
+                        .split('+')
+                        .map(|f| match f.trim() {
+                            "delay" => Ok(Fault::Delay),
+                            "unavailable" => Ok(Fault::Unavailable),
+                            "redeliver" => Ok(Fault::Redeliver),
+                            other => Err(format!(
+                                "unknown fault '{other}': expected delay, \
+                                 unavailable or redeliver"
+                            )),
+                        })
+                        .collect::<Result<_, _>>()?;
+                }
+                other => {
+                    return Err(format!("unknown chaos setting '{other}'"));
+                }
+            }
+        }
+        if cfg.probability.is_nan() {
+            return Err("missing p (e.g. p=0.05)".into());
+        }
+        Ok(cfg)
+    }
+}
+
+impl ChaosConfig {
+    /// Pick the fault a request meets, if any. Only polls can be redelivered.
+    pub fn roll(
+        &self,
+        poll: bool,
+    ) -> Option<Fault> {
+        let mut rng = rand::thread_rng();
+        if !rng.gen_bool(self.probability) {
+            return None;
+        }
+        let eligible: Vec<Fault> = self
+            .faults
+            .iter()
+            .copied()
+            .filter(|f| poll || *f != Fault::Redeliver)
+            .collect();
+        eligible.choose(&mut rng).copied()
+    }
+}
+
+// Inject a fault into API requests, as configured
+pub(super) async fn inject_faults(
+    State(state): State<AppState>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let Some(chaos) = &state.chaos else {
+        return next.run(req).await;
+    };
+    let poll =
+        req.method() == Method::POST && req.uri().path().ends_with("/poll");
+    let Some(fault) = chaos.roll(poll) else {
+        return next.run(req).await;
+    };
+    tracing::debug!(fault = fault.name(), path = %req.uri().path(), "chaos");
+    let mut resp = match fault {
+        Fault::Delay => {
+            let ms = chaos.max_delay.as_millis() as u64;
+            let delay = rand::thread_rng().gen_range(0..=ms);
+            tokio::time::sleep(Duration::from_millis(delay)).await;
+            next.run(req).await
+        }
+        Fault::Unavailable => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            axum::Json(json!({"message": "Chaos: injected failure"})),
+        )
+            .into_response(),
+        Fault::Redeliver => redeliver(&state, next.run(req).await).await,
+    };
+    resp.headers_mut()
+        .insert("x-sqew-chaos", HeaderValue::from_static(fault.name()));
+    resp
+}
+
+// End the lease of the first message of a successful poll response
+async fn redeliver(
+    state: &AppState,
+    resp: Response,
+) -> Response {
+    if resp.status() != StatusCode::OK {
+        return resp;
+    }
+    let (parts, body) = resp.into_parts();
+    let Ok(bytes) = axum::body::to_bytes(body, MAX_POLL_BODY).await else {
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    };
+    let first = serde_json::from_slice::<Value>(&bytes).ok().and_then(|v| {
+        let m = v.get(0)?;
+        Some((m["id"].as_i64()?, m["lease_token"].as_str()?.to_string()))
+    });
+    if let Some((id, token)) = first
+        && let Err(e) = queue::expire_leases(&state.db, &[id], &token).await
+    {
+        tracing::warn!("Chaos redelivery of message {id} failed: {e}");
+    }
+    Response::from_parts(parts, Body::from(bytes))
+}
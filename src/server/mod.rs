@@ -0,0 +1,3114 @@
+use crate::audit::{self, Actor};
+use crate::auth::{Grant, KeyCache, Permission};
+use crate::db::{
+    self, AutoCompactStatus, CompactRun, Db, DbStatus, MessageState,
+    PeekFilter, RecoveryReport,
+};
+use crate::error::SqewError;
+use crate::import::ImportFormat;
+use crate::models::{
+    AdminJob, Alarm, AuditEntry, ConsumerGroup, Headers, InFlightMessage,
+    Message, MessageAttempt, MessageRef, Queue, QueueSchema, StatsSample,
+};
+use crate::mqtt::{self, MqttConfig};
+use crate::notify::QueueNotifier;
+use crate::queue;
+use crate::queue::Config as QueueConfig;
+use crate::queue::PayloadRejected;
+use crate::queue::{AckResult, AckStatus, AdminJobKind};
+use crate::resp;
+use crate::ui;
+use anyhow::anyhow;
+use axum::{
+    Extension, Json, Router,
+    body::Bytes,
+    extract::{
+        DefaultBodyLimit, FromRef, MatchedPath, Path, Query, Request, State,
+        rejection::PathRejection,
+    },
+    http::{HeaderMap, HeaderValue, Method, StatusCode, header},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{delete, get, post},
+};
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tasks::TaskRegistry;
+use tokio::net::TcpListener;
+use tokio::signal;
+use tokio::sync::watch;
+use tokio::task::JoinSet;
+use tower_http::compression::CompressionLayer;
+use tower_http::cors::{AllowOrigin, CorsLayer};
+use tower_http::decompression::RequestDecompressionLayer;
+use tower_http::timeout::TimeoutLayer;
+use tracing_subscriber::EnvFilter;
+use utoipa::{IntoParams, OpenApi, ToSchema};
+use utoipa_swagger_ui::SwaggerUi;
+
+pub mod chaos;
+pub mod tasks;
+pub mod version;
+
+pub use chaos::ChaosConfig;
+pub use tasks::TaskIntervals;
+
+/// Port `sqew serve` listens on by default
+pub const DEFAULT_PORT: u16 = 8888;
+
+/// Default time `sqew serve` gives in-flight requests to finish on shutdown
+pub const DEFAULT_DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Log at the levels selected by `RUST_LOG` (default `info`).
+/// `RUST_LOG=sqew=debug` adds a span per handler and storage call, and an
+/// event per enqueue, lease, ack and nack carrying the message id, attempt
+/// and trace id.
+pub fn init_tracing() {
+    let filter = EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| EnvFilter::new("info"));
+    tracing_subscriber::fmt().with_env_filter(filter).init();
+}
+
+/// Settings of `sqew serve`
+#[derive(Debug, Clone)]
+pub struct ServeOptions {
+    /// Address to listen on, for both HTTP and the Redis protocol
+    pub bind: IpAddr,
+    /// HTTP port
+    pub port: u16,
+    /// Also accept Redis protocol clients on this port
+    pub redis_port: Option<u16>,
+    /// Time in-flight requests get to finish on shutdown
+    pub drain_timeout: Duration,
+    /// Largest payload any enqueue may carry, on top of per-queue limits
+    pub max_payload_bytes: Option<usize>,
+    /// Keys clients must present; empty leaves the server open
+    pub api_keys: Vec<String>,
+    /// How often the background tasks run
+    pub tasks: TaskIntervals,
+    /// MQTT broker to bridge, if any
+    pub mqtt: Option<MqttConfig>,
+    /// Faults to inject into API requests, for testing consumers
+    pub chaos: Option<ChaosConfig>,
+    /// Origins browsers may call the API from; empty disables CORS
+    pub cors_origins: Vec<HeaderValue>,
+    /// Largest request body accepted; `None` keeps axum's 2 MiB default
+    pub max_body_bytes: Option<usize>,
+    /// Time a request may take before it is answered with 408
+    pub request_timeout: Option<Duration>,
+    /// When to compact the database by itself; `None` never does
+    pub auto_compact: Option<queue::AutoCompactConfig>,
+    /// Repair the anomalies the startup recovery scan finds instead of
+    /// only reporting them
+    pub repair_on_start: bool,
+}
+
+impl Default for ServeOptions {
+    fn default() -> Self {
+        ServeOptions {
+            bind: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            port: DEFAULT_PORT,
+            redis_port: None,
+            drain_timeout: DEFAULT_DRAIN_TIMEOUT,
+            max_payload_bytes: None,
+            api_keys: Vec::new(),
+            tasks: TaskIntervals::default(),
+            mqtt: None,
+            chaos: None,
+            cors_origins: Vec::new(),
+            max_body_bytes: None,
+            request_timeout: None,
+            auto_compact: None,
+            repair_on_start: false,
+        }
+    }
+}
+
+/// Parse an origin browsers may call the API from: `*` for any, or a
+/// `scheme://host[:port]` origin such as `https://dash.example.com`
+pub fn parse_cors_origin(s: &str) -> Result<HeaderValue, String> {
+    let s = s.trim();
+    let host = s.strip_prefix("http://").or_else(|| s.strip_prefix("https://"));
+    let valid =
+        s == "*" || host.is_some_and(|h| !h.is_empty() && !h.contains('/'));
+    if !valid {
+        return Err(format!(
+            "invalid CORS origin '{s}': expected * or scheme://host[:port]"
+        ));
+    }
+    HeaderValue::from_str(s)
+        .map_err(|e| format!("invalid CORS origin '{s}': {e}"))
+}
+
+/// Run the HTTP server (and the Redis protocol listener, if configured)
+/// against the configured database until Ctrl+C or SIGTERM, then drain for
+/// up to the drain timeout
+pub async fn run_server(
+    opts: &ServeOptions,
+    cfg: &QueueConfig,
+) -> anyhow::Result<()> {
+    init_tracing();
+
+    // Initialize storage (ensures DB exists and schema is ready)
+    let db = queue::init_pool(cfg).await?;
+    tracing::info!("Using database at {}", cfg.db_path.display());
+    let report = queue::recovery_scan(&db, opts.repair_on_start).await?;
+    log_recovery(&report);
+
+    let ip = opts.bind;
+    let addr = SocketAddr::from((ip, opts.port));
+    tracing::info!("Listening on {} - Use Ctrl+C to quit.", addr);
+    let listener = TcpListener::bind(addr).await.map_err(|e| {
+        tracing::error!("Failed to bind address: {e}");
+        anyhow!("Bind error: {e}")
+    })?;
+    if let Some(chaos) = &opts.chaos {
+        tracing::warn!(
+            "Chaos mode: {:.1}% of API requests meet an injected fault",
+            chaos.probability * 100.0
+        );
+    }
+    let redis = match opts.redis_port {
+        Some(port) => {
+            let addr = SocketAddr::from((ip, port));
+            tracing::info!("Redis protocol listening on {}", addr);
+            Some(TcpListener::bind(addr).await.map_err(|e| {
+                tracing::error!("Failed to bind Redis address: {e}");
+                anyhow!("Bind error: {e}")
+            })?)
+        }
+        None => None,
+    };
+    let state = AppState::new(db)
+        .with_max_payload_bytes(opts.max_payload_bytes)
+        .with_api_keys(opts.api_keys.clone())
+        .with_queue_defaults(cfg.queue_defaults.clone())
+        .with_task_intervals(opts.tasks)
+        .with_mqtt(opts.mqtt.clone())
+        .with_chaos(opts.chaos.clone())
+        .with_cors_origins(opts.cors_origins.clone())
+        .with_max_body_bytes(opts.max_body_bytes)
+        .with_request_timeout(opts.request_timeout)
+        .with_auto_compact(opts.auto_compact.clone());
+    serve_until(listener, redis, state, opts.drain_timeout, shutdown_signal())
+        .await
+}
+
+// Log what the startup recovery scan found, as structured fields
+fn log_recovery(report: &RecoveryReport) {
+    if report.anomalies() == 0 {
+        tracing::info!(
+            wal_bytes = report.wal_bytes,
+            "Recovery scan found no anomalies"
+        );
+        return;
+    }
+    let hint = if report.repaired {
+        "repaired what sqew can"
+    } else {
+        "start with --repair-on-start to repair them"
+    };
+    tracing::warn!(
+        stranded_leases = report.stranded_leases,
+        schema_problems = ?report.schema_problems,
+        wal_bytes = report.wal_bytes,
+        wal_oversized = report.wal_oversized,
+        repaired = report.repaired,
+        "Recovery scan found {} anomalies; {}",
+        report.anomalies(),
+        hint
+    );
+}
+
+/// Serve the API and its background tasks on `listener`, and the Redis
+/// protocol on `redis` if given, until `shutdown` resolves. Shutdown stops
+/// accepting connections, stops the background tasks, returns pending long
+/// polls early and waits up to `drain_timeout` for in-flight requests before
+/// dropping them.
+pub async fn serve_until(
+    listener: TcpListener,
+    redis: Option<TcpListener>,
+    state: AppState,
+    drain_timeout: Duration,
+    shutdown: impl Future<Output = ()> + Send + 'static,
+) -> anyhow::Result<()> {
+    let db = state.db.clone();
+    let stop = state.shutdown.clone();
+    let mut stopped = stop.subscribe();
+
+    // Jobs a previous server stopped in the middle of will not resume
+    let interrupted = queue::fail_interrupted_admin_jobs(&db).await?;
+    if interrupted > 0 {
+        tracing::warn!(
+            "Marked {} interrupted admin job(s) failed",
+            interrupted
+        );
+    }
+    let mut tasks = JoinSet::new();
+    // Expiry sweeps, lease reaping, alarms, archive purges and schedules
+    state.task_registry.register_builtin(&db, state.tasks);
+    if let Some(cfg) = state.auto_compact.clone() {
+        let (d, last) = (db.clone(), state.last_compaction.clone());
+        state.task_registry.register(
+            "auto_compact",
+            state.tasks.auto_compact,
+            move || tasks::auto_compact(d.clone(), cfg.clone(), last.clone()),
+        );
+    }
+    state.task_registry.spawn(&mut tasks, &stop);
+    // Redis protocol listener sharing the API's wakeups
+    if let Some(redis) = redis {
+        tasks.spawn(resp::serve_resp(redis, state.clone(), stop.subscribe()));
+    }
+    if let Some(cfg) = state.mqtt.clone() {
+        tasks.spawn(mqtt::run_bridge(cfg, state.clone(), stop.subscribe()));
+    }
+
+    let server = axum::serve(listener, routes(state)).with_graceful_shutdown(
+        async move {
+            shutdown.await;
+            stop.send_replace(true);
+        },
+    );
+    let drained = tokio::select! {
+        res = server => res.map_err(|e| {
+            tracing::error!("Server error: {e}");
+            anyhow!("Server error: {e}")
+        }),
+        _ = async {
+            let _ = stopped.wait_for(|stop| *stop).await;
+            tokio::time::sleep(drain_timeout).await;
+        } => {
+            tracing::warn!("Drain timeout elapsed; dropping open requests");
+            Ok(())
+        }
+    };
+    // Background tasks stop between runs; give a running one the same grace
+    let tasks_done = async { while tasks.join_next().await.is_some() {} };
+    if tokio::time::timeout(drain_timeout, tasks_done).await.is_err() {
+        tracing::warn!(
+            "Background tasks did not stop within the drain timeout"
+        );
+    }
+    drained
+}
+
+// Resolve on Ctrl+C or, on Unix, SIGTERM (sent by container runtimes)
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        signal::ctrl_c().await.expect("failed to install Ctrl+C handler");
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        signal::unix::signal(signal::unix::SignalKind::terminate())
+            .expect("failed to install SIGTERM handler")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+    tokio::select! {
+        _ = ctrl_c => tracing::info!("Received Ctrl+C, shutting down gracefully..."),
+        _ = terminate => tracing::info!("Received SIGTERM, shutting down gracefully..."),
+    }
+}
+
+/// Longest a poll request may be held open waiting for messages
+pub const MAX_POLL_WAIT_MS: i64 = 20_000;
+
+/// How often a long poll re-checks the database even without a wakeup, so
+/// messages enqueued by other processes (e.g. the CLI) are still picked up
+pub(crate) const POLL_RECHECK_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Shared state for HTTP handlers
+#[derive(Clone)]
+pub struct AppState {
+    pub db: Db,
+    pub notifier: Arc<QueueNotifier>,
+    /// Set to true when the server begins shutting down
+    pub shutdown: Arc<watch::Sender<bool>>,
+    /// Server-wide cap on enqueued payload size; `None` leaves it to queues
+    pub max_payload_bytes: Option<usize>,
+    /// Keys accepted as bearer tokens with the admin role; with these empty
+    /// and no keys stored by `sqew auth grant` the API is open
+    pub api_keys: Arc<Vec<String>>,
+    /// Keys stored by `sqew auth grant`, with their roles
+    pub key_cache: Arc<KeyCache>,
+    /// Settings of queues created without explicit ones
+    pub queue_defaults: Arc<queue::QueueOptions>,
+    /// How often [`serve_until`] runs the background tasks
+    pub tasks: TaskIntervals,
+    /// The background jobs [`serve_until`] runs, with their last-run status
+    pub task_registry: Arc<TaskRegistry>,
+    /// MQTT broker [`serve_until`] bridges, if any
+    pub mqtt: Option<Arc<MqttConfig>>,
+    /// Faults injected into API requests, if any
+    pub chaos: Option<Arc<ChaosConfig>>,
+    /// Origins allowed to call the API from a browser; empty disables CORS
+    pub cors_origins: Arc<Vec<HeaderValue>>,
+    /// Largest request body accepted; `None` keeps axum's default
+    pub max_body_bytes: Option<usize>,
+    /// Requests taking longer are answered with 408
+    pub request_timeout: Option<Duration>,
+    /// When [`serve_until`] compacts the database by itself, if ever
+    pub auto_compact: Option<Arc<queue::AutoCompactConfig>>,
+    /// The last automatic compaction, reported at `/admin/db`
+    pub last_compaction: Arc<Mutex<Option<CompactRun>>>,
+}
+
+impl AppState {
+    pub fn new(db: Db) -> Self {
+        AppState {
+            db,
+            notifier: Arc::new(QueueNotifier::new()),
+            shutdown: Arc::new(watch::Sender::new(false)),
+            max_payload_bytes: None,
+            api_keys: Arc::new(Vec::new()),
+            key_cache: Arc::new(KeyCache::new()),
+            queue_defaults: Arc::new(queue::QueueOptions::default()),
+            tasks: TaskIntervals::default(),
+            task_registry: Arc::new(TaskRegistry::new()),
+            mqtt: None,
+            chaos: None,
+            cors_origins: Arc::new(Vec::new()),
+            max_body_bytes: None,
+            request_timeout: None,
+            auto_compact: None,
+            last_compaction: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Reject enqueues whose serialized payload exceeds `bytes`
+    pub fn with_max_payload_bytes(
+        mut self,
+        bytes: Option<usize>,
+    ) -> Self {
+        self.max_payload_bytes = bytes;
+        self
+    }
+
+    /// Require API requests and Redis connections to present one of `keys`
+    pub fn with_api_keys(
+        mut self,
+        keys: Vec<String>,
+    ) -> Self {
+        self.api_keys = Arc::new(keys);
+        self
+    }
+
+    /// Create queues with `opts` where callers give no settings of their own
+    pub fn with_queue_defaults(
+        mut self,
+        opts: queue::QueueOptions,
+    ) -> Self {
+        self.queue_defaults = Arc::new(opts);
+        self
+    }
+
+    /// Run the background tasks at these intervals
+    pub fn with_task_intervals(
+        mut self,
+        tasks: TaskIntervals,
+    ) -> Self {
+        self.tasks = tasks;
+        self
+    }
+
+    /// Bridge the MQTT broker of `cfg` while serving
+    pub fn with_mqtt(
+        mut self,
+        cfg: Option<MqttConfig>,
+    ) -> Self {
+        self.mqtt = cfg.map(Arc::new);
+        self
+    }
+
+    /// Inject faults into API requests, to test consumers against them
+    pub fn with_chaos(
+        mut self,
+        chaos: Option<ChaosConfig>,
+    ) -> Self {
+        self.chaos = chaos.map(Arc::new);
+        self
+    }
+
+    /// Answer CORS requests from these origins (`*` for any), so browser
+    /// dashboards can call the API
+    pub fn with_cors_origins(
+        mut self,
+        origins: Vec<HeaderValue>,
+    ) -> Self {
+        self.cors_origins = Arc::new(origins);
+        self
+    }
+
+    /// Reject request bodies larger than `bytes` with 413
+    pub fn with_max_body_bytes(
+        mut self,
+        bytes: Option<usize>,
+    ) -> Self {
+        self.max_body_bytes = bytes;
+        self
+    }
+
+    /// Answer requests still running after `timeout` with 408. Long polls
+    /// count towards it.
+    pub fn with_request_timeout(
+        mut self,
+        timeout: Option<Duration>,
+    ) -> Self {
+        self.request_timeout = timeout;
+        self
+    }
+
+    /// Compact the database as `cfg` says while serving
+    pub fn with_auto_compact(
+        mut self,
+        cfg: Option<queue::AutoCompactConfig>,
+    ) -> Self {
+        self.auto_compact = cfg.map(Arc::new);
+        self
+    }
+
+    /// Create `name` with the default queue settings unless it exists, for
+    /// protocols that enqueue into any queue name
+    pub(crate) async fn ensure_queue(
+        &self,
+        name: &str,
+    ) -> Result<(), SqewError> {
+        if queue::show_queue(&self.db, name).await.is_ok() {
+            return Ok(());
+        }
+        // Lost a race with another client creating it: fine either way
+        match queue::create_queue_with(&self.db, name, &self.queue_defaults)
+            .await
+        {
+            Ok(_) | Err(SqewError::QueueExists(_)) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Whether clients must authenticate: API keys are configured or stored
+    pub async fn requires_auth(&self) -> sqlx::Result<bool> {
+        Ok(!self.api_keys.is_empty()
+            || !self.key_cache.keys(&self.db).await?.is_empty())
+    }
+
+    /// The grant of a client presenting `key`, or `None` if the key is not
+    /// accepted. Configured keys are admins; stored keys carry their role.
+    pub async fn grant_for(
+        &self,
+        key: &str,
+    ) -> sqlx::Result<Option<Grant>> {
+        Ok(self.caller_for(key).await?.map(|(_, grant)| grant))
+    }
+
+    /// Who a client presenting `key` is, for the audit log, with its grant
+    pub async fn caller_for(
+        &self,
+        key: &str,
+    ) -> sqlx::Result<Option<(Actor, Grant)>> {
+        if self.api_keys.iter().any(|k| k == key) {
+            return Ok(Some((Actor::configured_key(), Grant::admin())));
+        }
+        let keys = self.key_cache.keys(&self.db).await?;
+        Ok(keys
+            .get(&crate::auth::hash_key(key))
+            .map(|(name, grant)| (Actor::key(name), grant.clone())))
+    }
+
+    /// Check a payload against the server-wide size limit
+    pub fn check_payload_size(
+        &self,
+        payload: &serde_json::Value,
+    ) -> Result<(), PayloadRejected> {
+        let Some(limit) = self.max_payload_bytes else {
+            return Ok(());
+        };
+        let size = payload.to_string().len();
+        if size > limit {
+            return Err(PayloadRejected::PayloadTooLarge { size, limit });
+        }
+        Ok(())
+    }
+}
+
+impl FromRef<AppState> for Db {
+    fn from_ref(state: &AppState) -> Self {
+        state.db.clone()
+    }
+}
+
+/// OpenAPI description of every route in [`app_router`], served at
+/// `/openapi.json` and browsable at `/docs`
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "sqew", description = "Durable message queue HTTP API"),
+    paths(
+        health,
+        healthz,
+        readyz,
+        list_queues,
+        create_queue,
+        show_queue,
+        update_queue,
+        delete_queue,
+        clone_queue,
+        restore_queue,
+        show_queue_schema,
+        list_queue_schemas,
+        queue_stats,
+        queue_stats_history,
+        peek_messages,
+        sample_messages,
+        search_messages,
+        enqueue_message_http,
+        purge_messages,
+        start_purge,
+        purge_status,
+        poll_messages,
+        move_messages,
+        export_queue,
+        import_queue,
+        ack_messages,
+        nack_messages,
+        extend_visibility,
+        message_payload,
+        message_attempts,
+        list_in_flight,
+        enqueue_transaction,
+        list_dead_letters,
+        purge_dead_letters,
+        redrive_dead_letters,
+        replay_archived_messages,
+        list_consumer_groups,
+        create_consumer_group,
+        delete_consumer_group,
+        list_alarms,
+        create_alarm,
+        delete_alarm,
+        backup_database,
+        database_status,
+        list_tasks,
+        start_admin_job,
+        list_admin_jobs,
+        show_admin_job,
+        cancel_admin_job,
+        list_trash,
+        list_audit_log,
+        metrics,
+    ),
+    tags(
+        (name = "queues", description = "Queue management"),
+        (name = "messages", description = "Enqueue, lease and settle messages"),
+        (name = "dlq", description = "Dead letters"),
+        (name = "groups", description = "Consumer groups (fan-out)"),
+        (name = "alarms", description = "Queue depth and age alarms"),
+        (name = "admin", description = "Database maintenance"),
+    )
+)]
+pub struct ApiDoc;
+
+/// Construct the Axum `Router` for the service, injecting shared state.
+pub fn app_router(db: Db) -> Router {
+    routes(AppState::new(db))
+}
+
+// All API routes over the given state, plus the OpenAPI document, Swagger
+// UI and the admin UI
+fn routes(state: AppState) -> Router {
+    let cors = cors_layer(&state.cors_origins);
+    let max_body_bytes = state.max_body_bytes;
+    let request_timeout = state.request_timeout;
+    let api = api_routes(&state);
+    let mut router = Router::new()
+        .nest(version::API_PREFIX, api.clone())
+        // Unversioned paths of the first releases, kept as aliases
+        .merge(api.route_layer(middleware::from_fn(version::deprecated_alias)))
+        // Open to probes even when API keys are configured
+        .route("/health", get(health))
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+        .with_state(state)
+        .merge(ui::routes())
+        .merge(SwaggerUi::new("/docs").url("/openapi.json", api_doc()))
+        // gzip or zstd, as the client accepts and sends
+        .layer(CompressionLayer::new())
+        .layer(RequestDecompressionLayer::new());
+    if let Some(timeout) = request_timeout {
+        router = router.layer(TimeoutLayer::with_status_code(
+            StatusCode::REQUEST_TIMEOUT,
+            timeout,
+        ));
+    }
+    if let Some(bytes) = max_body_bytes {
+        router = router.layer(DefaultBodyLimit::max(bytes));
+    }
+    // Outermost, so preflights are answered before authentication and
+    // errors carry CORS headers too
+    match cors {
+        Some(cors) => router.layer(cors),
+        None => router,
+    }
+}
+
+// The API routes, behind API keys and chaos, relative to the version prefix
+fn api_routes(state: &AppState) -> Router<AppState> {
+    Router::new()
+        // Queue endpoints
+        .route("/queues", get(list_queues).post(create_queue))
+        .route(
+            "/queues/{name}",
+            get(show_queue).patch(update_queue).delete(delete_queue),
+        )
+        .route("/queues/{name}/stats", get(queue_stats))
+        .route("/queues/{name}/stats/history", get(queue_stats_history))
+        .route("/queues/{name}/clone", post(clone_queue))
+        .route("/queues/{name}/restore", post(restore_queue))
+        .route("/queues/{name}/schema", get(show_queue_schema))
+        .route("/queues/{name}/schema/versions", get(list_queue_schemas))
+        // Message endpoints
+        .route(
+            "/queues/{name}/messages",
+            get(peek_messages)
+                .post(enqueue_message_http)
+                .delete(purge_messages),
+        )
+        .route("/queues/{name}/messages/search", get(search_messages))
+        .route("/queues/{name}/messages/{id}/payload", get(message_payload))
+        .route("/queues/{name}/purges", post(start_purge))
+        .route("/queues/{name}/purges/{id}", get(purge_status))
+        .route("/queues/{name}/sample", get(sample_messages))
+        .route("/queues/{name}/messages/poll", post(poll_messages))
+        .route("/queues/{name}/messages/move", post(move_messages))
+        .route("/queues/{name}/in-flight", get(list_in_flight))
+        .route("/queues/{name}/export", get(export_queue))
+        .route("/queues/{name}/import", post(import_queue))
+        .route("/messages/ack", post(ack_messages))
+        .route("/messages/nack", post(nack_messages))
+        .route("/messages/{id}/extend", post(extend_visibility))
+        .route("/messages/{id}/attempts", get(message_attempts))
+        .route("/transactions/enqueue", post(enqueue_transaction))
+        // Dead-letter endpoints
+        .route(
+            "/queues/{name}/dlq",
+            get(list_dead_letters).delete(purge_dead_letters),
+        )
+        .route("/queues/{name}/dlq/redrive", post(redrive_dead_letters))
+        // Archive endpoints
+        .route("/queues/{name}/archive/replay", post(replay_archived_messages))
+        // Consumer group endpoints
+        .route(
+            "/queues/{name}/groups",
+            get(list_consumer_groups).post(create_consumer_group),
+        )
+        .route("/queues/{name}/groups/{group}", delete(delete_consumer_group))
+        // Alarm endpoints
+        .route("/queues/{name}/alarms", get(list_alarms).post(create_alarm))
+        .route("/queues/{name}/alarms/{id}", delete(delete_alarm))
+        // Admin endpoints
+        .route("/admin/backup", post(backup_database))
+        .route("/admin/db", get(database_status))
+        .route("/admin/tasks", get(list_tasks))
+        .route("/admin/jobs", get(list_admin_jobs).post(start_admin_job))
+        .route("/admin/jobs/{id}", get(show_admin_job))
+        .route("/admin/jobs/{id}/cancel", post(cancel_admin_job))
+        .route("/admin/trash", get(list_trash))
+        .route("/admin/audit", get(list_audit_log))
+        .route("/metrics", get(metrics))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            chaos::inject_faults,
+        ))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            require_api_key,
+        ))
+        .route_layer(middleware::from_fn(retry_after_unavailable))
+        .route_layer(middleware::from_fn(version::negotiate))
+}
+
+// Ask clients answered 503, as while the database is too busy to take a
+// write, to try again after `db::BUSY_RETRY_AFTER`
+async fn retry_after_unavailable(
+    req: Request,
+    next: Next,
+) -> Response {
+    let mut resp = next.run(req).await;
+    if resp.status() == StatusCode::SERVICE_UNAVAILABLE
+        && !resp.headers().contains_key(header::RETRY_AFTER)
+    {
+        let secs = db::BUSY_RETRY_AFTER.as_secs().max(1);
+        resp.headers_mut().insert(header::RETRY_AFTER, HeaderValue::from(secs));
+    }
+    resp
+}
+
+// The OpenAPI document, with the API's paths under the version prefix
+fn api_doc() -> utoipa::openapi::OpenApi {
+    const UNVERSIONED: [&str; 3] = ["/health", "/healthz", "/readyz"];
+    let mut doc = ApiDoc::openapi();
+    let paths = std::mem::take(&mut doc.paths.paths);
+    for (path, item) in paths {
+        let path = if UNVERSIONED.contains(&path.as_str()) {
+            path
+        } else {
+            format!("{}{}", version::API_PREFIX, path)
+        };
+        doc.paths.paths.insert(path, item);
+    }
+    doc
+}
+
+// How long browsers may cache a preflight response
+const CORS_MAX_AGE: Duration = Duration::from_secs(600);
+
+// CORS for the API's methods and headers from `origins`, if any
+fn cors_layer(origins: &[HeaderValue]) -> Option<CorsLayer> {
+    if origins.is_empty() {
+        return None;
+    }
+    let allow = if origins.iter().any(|o| o == "*") {
+        AllowOrigin::any()
+    } else {
+        AllowOrigin::list(origins.iter().cloned())
+    };
+    Some(
+        CorsLayer::new()
+            .allow_origin(allow)
+            .allow_methods([
+                Method::GET,
+                Method::POST,
+                Method::PATCH,
+                Method::DELETE,
+            ])
+            .allow_headers([header::AUTHORIZATION, header::CONTENT_TYPE])
+            .expose_headers([header::RETRY_AFTER])
+            .max_age(CORS_MAX_AGE),
+    )
+}
+// Request payload for creating a queue
+#[derive(Deserialize, ToSchema)]
+struct CreateQueueBody {
+    name: String,
+    max_attempts: Option<i32>,
+    dedup_window_ms: Option<i64>,
+    retention_days: Option<i32>,
+    backoff_base_ms: Option<i64>,
+    backoff_multiplier: Option<f64>,
+    backoff_max_ms: Option<i64>,
+    backoff_jitter: Option<f64>,
+    default_visibility_ms: Option<i64>,
+    default_delay_ms: Option<i64>,
+    max_deliveries_per_second: Option<f64>,
+    max_payload_bytes: Option<i64>,
+    /// JSON Schema every enqueued payload must match
+    #[schema(value_type = Option<Object>)]
+    payload_schema: Option<serde_json::Value>,
+    /// What enqueues do with payloads that break the schema (default
+    /// `reject`)
+    schema_mode: Option<queue::SchemaMode>,
+    /// Lease only the oldest message (of each FIFO group) at a time
+    strict_fifo: Option<bool>,
+    /// Take turns between the messages' fair keys when polling
+    fair: Option<bool>,
+    /// Order ready messages are polled in (default `priority`)
+    ordering: Option<queue::QueueOrdering>,
+    /// Refuse enqueues while this many messages are unacked
+    max_depth: Option<i64>,
+    /// Quarantine a message after its lease expires this many times without
+    /// an ack or nack
+    max_lease_expirations: Option<i32>,
+}
+
+// Query parameters for peeking messages
+#[derive(Deserialize, IntoParams)]
+struct PeekParams {
+    limit: Option<i64>,
+    /// Only messages carrying this header, as `key=value`
+    header: Option<String>,
+    offset: Option<i64>,
+    /// Cursor: only messages with a greater id, in id order
+    after_id: Option<i64>,
+    created_after: Option<i64>,
+    created_before: Option<i64>,
+    /// Payload substring
+    contains: Option<String>,
+    /// Payload match as `path=value`, e.g. `$.kind=email`
+    json_path: Option<String>,
+    /// Only messages in this state (default: all)
+    #[param(inline)]
+    state: Option<MessageState>,
+}
+
+// Query parameters for searching messages by payload contents
+#[derive(Deserialize, IntoParams)]
+struct SearchParams {
+    /// JSON path into the payload, e.g. `$.order.id`
+    jsonpath: String,
+    /// Value the path must equal; omit to match any message with the path
+    value: Option<String>,
+    /// Cursor: only messages with a greater id
+    after_id: Option<i64>,
+    limit: Option<i64>,
+}
+
+// Request payload for polling (leasing) messages
+#[derive(Deserialize, Default, ToSchema)]
+struct PollBody {
+    batch: Option<i64>,
+    visibility_ms: Option<i64>,
+    /// Hold the request open up to this long (capped) while the queue is empty
+    wait_ms: Option<i64>,
+    /// Consumer group to lease for; required on queues with groups
+    group: Option<String>,
+    /// Names the consumer holding the leases, as listed by the in-flight
+    /// route and the messages' attempt logs
+    #[serde(alias = "consumer_id")]
+    consumer: Option<String>,
+}
+
+// Request payload for acking messages under a lease
+#[derive(Deserialize, ToSchema)]
+struct AckBody {
+    /// Message ids or public ids
+    ids: Vec<MessageRef>,
+    lease_token: String,
+}
+
+// Request payload for nacking messages under a lease: `ids` share
+// `delay_ms`, while `delays` give messages their own delay
+#[derive(Deserialize, ToSchema)]
+struct NackBody {
+    /// Message ids or public ids
+    #[serde(default)]
+    ids: Vec<MessageRef>,
+    lease_token: String,
+    delay_ms: Option<i64>,
+    #[serde(default)]
+    delays: Vec<NackDelay>,
+    /// Why the messages failed (error message or stack trace), kept as the
+    /// messages' `last_error`
+    reason: Option<String>,
+}
+
+// A message to nack with its own delay
+#[derive(Deserialize, ToSchema)]
+struct NackDelay {
+    id: MessageRef,
+    delay_ms: i64,
+}
+
+// Request payload for cloning a queue
+#[derive(Deserialize, ToSchema)]
+struct CloneBody {
+    to: String,
+    #[serde(default)]
+    with_messages: bool,
+}
+
+// Request payload for extending a message lease
+#[derive(Deserialize, ToSchema)]
+struct ExtendBody {
+    lease_token: String,
+    extra_ms: i64,
+}
+
+// Request payload for redriving dead letters; no ids means all
+#[derive(Deserialize, Default, ToSchema)]
+struct RedriveBody {
+    #[serde(default)]
+    ids: Vec<MessageRef>,
+}
+
+// Request payload for replaying archived messages; omitted filters match
+// every archived message
+#[derive(Deserialize, Default, ToSchema)]
+struct ReplayBody {
+    /// Only messages acked at or after this time (ms since epoch)
+    from: Option<i64>,
+    /// Only messages acked before this time (ms since epoch)
+    to: Option<i64>,
+    /// Only messages whose payload contains this text
+    contains: Option<String>,
+}
+
+// Request payload for moving messages out of a queue
+#[derive(Deserialize, ToSchema)]
+struct MoveBody {
+    ids: Vec<MessageRef>,
+    /// Target queue name
+    to: String,
+    #[serde(default)]
+    reset_attempts: bool,
+}
+
+// Request payload for creating a consumer group
+#[derive(Deserialize, ToSchema)]
+struct CreateGroupBody {
+    name: String,
+}
+
+// Request payload for adding an alarm
+#[derive(Deserialize, ToSchema)]
+struct CreateAlarmBody {
+    /// `ready` or `oldest_age_ms`
+    metric: String,
+    threshold: i64,
+    /// How long (ms) the metric must stay above the threshold (default 0)
+    #[serde(default)]
+    for_ms: i64,
+    webhook_url: String,
+}
+
+// Request payload for starting an admin job
+#[derive(Deserialize, ToSchema)]
+struct StartJobBody {
+    kind: AdminJobKind,
+    /// Queue to work on; required by all kinds but `compact`
+    queue: Option<String>,
+    /// File on the server an `export` writes to; must not exist
+    #[schema(value_type = Option<String>)]
+    path: Option<PathBuf>,
+    /// Messages handled per transaction (default: 10000, or 500 for
+    /// exports)
+    batch_size: Option<usize>,
+}
+
+// Query parameters for listing admin jobs
+#[derive(Deserialize, IntoParams)]
+struct ListJobsParams {
+    /// Most jobs returned (default: 20)
+    limit: Option<i64>,
+}
+
+// Query parameters for listing the audit log
+#[derive(Deserialize, IntoParams)]
+struct AuditParams {
+    /// Only this action, e.g. `queue.purge`
+    action: Option<String>,
+    /// Only actions on this queue
+    queue: Option<String>,
+    /// Only actions by this actor, e.g. `key:deploy`
+    actor: Option<String>,
+    /// Only actions within this window, e.g. `24h` or `7d`
+    since: Option<String>,
+    /// Most entries returned (default: 100)
+    limit: Option<i64>,
+}
+
+// Request payload for backing up the database
+#[derive(Deserialize, ToSchema)]
+struct BackupBody {
+    /// Backup file to create on the server; must not exist
+    #[schema(value_type = String)]
+    path: PathBuf,
+}
+
+// Request payload for enqueueing a message
+#[derive(Deserialize, ToSchema)]
+struct EnqueueBody {
+    /// Any JSON value
+    payload: serde_json::Value,
+    #[serde(default)]
+    delay_ms: Option<i64>,
+    /// Deliver at this RFC 3339 time (with a UTC offset) instead of after
+    /// `delay_ms`
+    #[serde(default)]
+    deliver_at: Option<String>,
+    #[serde(default)]
+    priority: Option<i32>,
+    #[serde(default)]
+    ttl_ms: Option<i64>,
+    #[serde(default)]
+    dedup_key: Option<String>,
+    #[serde(default)]
+    group_id: Option<String>,
+    #[serde(default)]
+    headers: Option<Headers>,
+    /// Correlates the message in logs; generated when omitted
+    #[serde(default)]
+    trace_id: Option<String>,
+    /// Tenant key: polls of a fair queue take turns between keys
+    #[serde(default)]
+    fair_key: Option<String>,
+    /// Polls of a `sort_key` queue take lower keys first
+    #[serde(default)]
+    sort_key: Option<f64>,
+    /// Queue to send a receipt to once the message is acked or
+    /// dead-lettered
+    #[serde(default)]
+    reply_to: Option<String>,
+    /// Wait up to this many milliseconds (at most 20000) for room in a
+    /// full queue
+    #[serde(default)]
+    wait_ms: Option<i64>,
+}
+
+// Enqueue options given as query parameters when the request body is the
+// raw payload rather than an `EnqueueBody`
+#[derive(Deserialize, IntoParams)]
+struct RawEnqueueParams {
+    delay_ms: Option<i64>,
+    /// RFC 3339 time to deliver at instead of after `delay_ms`
+    deliver_at: Option<String>,
+    priority: Option<i32>,
+    ttl_ms: Option<i64>,
+    dedup_key: Option<String>,
+    group_id: Option<String>,
+    trace_id: Option<String>,
+    fair_key: Option<String>,
+    sort_key: Option<f64>,
+    reply_to: Option<String>,
+    wait_ms: Option<i64>,
+}
+
+impl RawEnqueueParams {
+    // The enqueue of `bytes` of the non-JSON media type `content_type`
+    fn into_body(
+        self,
+        content_type: &str,
+        bytes: &[u8],
+    ) -> EnqueueBody {
+        let header = (queue::CONTENT_TYPE_HEADER.into(), content_type.into());
+        EnqueueBody {
+            payload: queue::binary_payload(bytes),
+            delay_ms: self.delay_ms,
+            deliver_at: self.deliver_at,
+            priority: self.priority,
+            ttl_ms: self.ttl_ms,
+            dedup_key: self.dedup_key,
+            group_id: self.group_id,
+            headers: Some(Headers::from([header])),
+            trace_id: self.trace_id,
+            fair_key: self.fair_key,
+            sort_key: self.sort_key,
+            reply_to: self.reply_to,
+            wait_ms: self.wait_ms,
+        }
+    }
+}
+
+// Request payload for enqueueing into several queues atomically
+#[derive(Deserialize, ToSchema)]
+struct TransactionBody {
+    /// At most 1000 messages, enqueued all together or not at all
+    messages: Vec<TransactionMessage>,
+}
+
+// One message of a transactional enqueue, with the queue it goes to
+#[derive(Deserialize, ToSchema)]
+struct TransactionMessage {
+    queue: String,
+    /// Any JSON value
+    payload: serde_json::Value,
+    #[serde(default)]
+    delay_ms: Option<i64>,
+    /// Deliver at this RFC 3339 time (with a UTC offset) instead of after
+    /// `delay_ms`
+    #[serde(default)]
+    deliver_at: Option<String>,
+    #[serde(default)]
+    priority: Option<i32>,
+    #[serde(default)]
+    ttl_ms: Option<i64>,
+    #[serde(default)]
+    dedup_key: Option<String>,
+    #[serde(default)]
+    group_id: Option<String>,
+    #[serde(default)]
+    headers: Option<Headers>,
+    /// Correlates the message in logs; generated when omitted
+    #[serde(default)]
+    trace_id: Option<String>,
+    /// Tenant key: polls of a fair queue take turns between keys
+    #[serde(default)]
+    fair_key: Option<String>,
+    /// Polls of a `sort_key` queue take lower keys first
+    #[serde(default)]
+    sort_key: Option<f64>,
+    /// Queue to send a receipt to once the message is acked or
+    /// dead-lettered
+    #[serde(default)]
+    reply_to: Option<String>,
+}
+
+// Reject API requests that do not carry an accepted key as an
+// `Authorization: Bearer` token, or whose key's role or queue pattern does
+// not allow what they do. Handlers find the caller's `Grant` and `Actor` in
+// the request extensions.
+async fn require_api_key(
+    State(state): State<AppState>,
+    matched: MatchedPath,
+    params: Result<Path<HashMap<String, String>>, PathRejection>,
+    mut req: Request,
+    next: Next,
+) -> Response {
+    let token = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .map(str::to_string);
+    let (actor, grant) = match authenticate(&state, token.as_deref()).await {
+        Ok(Some(caller)) => caller,
+        Ok(None) => {
+            return (
+                StatusCode::UNAUTHORIZED,
+                [(header::WWW_AUTHENTICATE, "Bearer")],
+                "Missing or invalid API key",
+            )
+                .into_response();
+        }
+        Err(e) => {
+            return (StatusCode::SERVICE_UNAVAILABLE, e.to_string())
+                .into_response();
+        }
+    };
+    let path = matched.as_str();
+    let path = path.strip_prefix(version::API_PREFIX).unwrap_or(path);
+    let perm = route_permission(req.method(), path);
+    let params = params.map(|Path(p)| p).unwrap_or_default();
+    let queue = params.get("name").map(String::as_str);
+    // Server-wide admin endpoints are beyond keys limited to some queues
+    let server_wide =
+        path.starts_with("/admin/") && grant.queue_pattern.is_some();
+    if server_wide || !grant.allows(perm, queue) {
+        return forbidden(&grant, queue).into_response();
+    }
+    req.extensions_mut().insert(grant);
+    req.extensions_mut().insert(actor);
+    next.run(req).await
+}
+
+// The caller and grant of the request's bearer token, an anonymous admin
+// when the API is open
+async fn authenticate(
+    state: &AppState,
+    token: Option<&str>,
+) -> sqlx::Result<Option<(Actor, Grant)>> {
+    if !state.requires_auth().await? {
+        return Ok(Some((Actor::anonymous(), Grant::admin())));
+    }
+    match token {
+        Some(token) => state.caller_for(token).await,
+        None => Ok(None),
+    }
+}
+
+// The permission a route needs. Acks, nacks and extends are not tied to a
+// queue by the route: holding the lease token is what entitles them.
+fn route_permission(
+    method: &Method,
+    path: &str,
+) -> Permission {
+    match (method, path) {
+        (&Method::GET, path) if !path.starts_with("/admin/") => {
+            Permission::Read
+        }
+        (
+            &Method::POST,
+            "/queues/{name}/messages" | "/transactions/enqueue",
+        ) => Permission::Produce,
+        (
+            &Method::POST,
+            "/queues/{name}/messages/poll"
+            | "/messages/ack"
+            | "/messages/nack"
+            | "/messages/{id}/extend",
+        ) => Permission::Consume,
+        _ => Permission::Admin,
+    }
+}
+
+// Refuse `perm` on a queue named in a request body that the caller's key
+// does not cover
+fn authorize(
+    grant: &Grant,
+    perm: Permission,
+    queue: &str,
+) -> Result<(), (StatusCode, String)> {
+    if grant.allows(perm, Some(queue)) {
+        Ok(())
+    } else {
+        Err(forbidden(grant, Some(queue)))
+    }
+}
+
+fn forbidden(
+    grant: &Grant,
+    queue: Option<&str>,
+) -> (StatusCode, String) {
+    let msg = match queue {
+        Some(q) if !grant.covers(q) => {
+            format!("API key is not allowed to access queue '{}'", q)
+        }
+        _ => format!(
+            "API key role '{}' is not allowed to do this",
+            grant.role.as_str()
+        ),
+    };
+    (StatusCode::FORBIDDEN, msg)
+}
+
+// Liveness check
+#[utoipa::path(
+    get,
+    path = "/health",
+    responses((status = 200, description = "The server is up", body = String))
+)]
+async fn health() -> &'static str {
+    "ok"
+}
+
+// Latency, batch size and rows affected histograms of the service layer's
+// enqueues, polls, acks and nacks
+#[utoipa::path(
+    get,
+    path = "/metrics",
+    responses((status = 200, description = "Prometheus text exposition format", body = String, content_type = "text/plain"))
+)]
+async fn metrics() -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        crate::metrics::render(),
+    )
+}
+
+/// Longest `/readyz` waits for the database before reporting it down
+const READINESS_DB_TIMEOUT: Duration = Duration::from_secs(2);
+
+// Liveness probe: the process is serving requests
+#[utoipa::path(
+    get,
+    path = "/healthz",
+    responses((status = 200, description = "`{\"status\": \"ok\"}`", body = Object))
+)]
+async fn healthz() -> Json<serde_json::Value> {
+    Json(json!({ "status": "ok" }))
+}
+
+// Readiness probe: the database answers, its schema is fully migrated and
+// the server is not shutting down
+#[utoipa::path(
+    get,
+    path = "/readyz",
+    responses(
+        (status = 200, description = "Ready: `{\"status\": \"ready\", \"components\": {\"database\", \"migrations\", \"server\"}}`, each component carrying its own `status`", body = Object),
+        (status = 503, description = "Not ready; the failing components say why", body = Object)
+    )
+)]
+async fn readyz(State(state): State<AppState>) -> Response {
+    let started = std::time::Instant::now();
+    let checked = queue::check_database(&state.db, READINESS_DB_TIMEOUT).await;
+    let latency_ms = started.elapsed().as_secs_f64() * 1000.0;
+    let (database, migrations) = match checked {
+        Ok((current, latest)) => (
+            json!({ "status": "ok", "latency_ms": latency_ms }),
+            json!({
+                "status": if current < latest { "pending" } else { "ok" },
+                "current": current,
+                "latest": latest,
+                "pending": (latest - current).max(0),
+            }),
+        ),
+        Err(e) => (
+            json!({ "status": "down", "error": format!("{e:#}") }),
+            json!({ "status": "unknown" }),
+        ),
+    };
+    let draining = *state.shutdown.borrow();
+    let server =
+        json!({ "status": if draining { "shutting_down" } else { "ok" } });
+    let ready =
+        [&database, &migrations, &server].iter().all(|c| c["status"] == "ok");
+    let status =
+        if ready { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+    let body = json!({
+        "status": if ready { "ready" } else { "unavailable" },
+        "components": {
+            "database": database,
+            "migrations": migrations,
+            "server": server,
+        },
+    });
+    (status, Json(body)).into_response()
+}
+
+// List all queues
+#[utoipa::path(
+    get,
+    path = "/queues",
+    tag = "queues",
+    responses((status = 200, description = "All queues", body = [Queue]))
+)]
+#[tracing::instrument(level = "debug", skip_all)]
+async fn list_queues(
+    State(db): State<Db>,
+    Extension(grant): Extension<Grant>,
+) -> Result<Json<Vec<Queue>>, (StatusCode, String)> {
+    let mut queues = queue::list_queues(&db).await.map_err(error_response)?;
+    // Keys limited to some queues see only those
+    queues.retain(|q| grant.covers(&q.name));
+    Ok(Json(queues))
+}
+
+// Create a new queue
+#[utoipa::path(
+    post,
+    path = "/queues",
+    tag = "queues",
+    request_body = CreateQueueBody,
+    responses(
+        (status = 201, description = "Queue created", body = Queue),
+        (status = 400, description = "Invalid settings, such as a payload schema that does not compile"),
+        (status = 409, description = "A queue with this name already exists")
+    )
+)]
+#[tracing::instrument(level = "debug", skip_all, fields(queue = %body.name))]
+async fn create_queue(
+    State(state): State<AppState>,
+    Extension(grant): Extension<Grant>,
+    Extension(actor): Extension<Actor>,
+    Json(body): Json<CreateQueueBody>,
+) -> Result<(StatusCode, Json<Queue>), (StatusCode, String)> {
+    authorize(&grant, Permission::Admin, &body.name)?;
+    let db = state.db;
+    let defaults = state.queue_defaults.as_ref().clone();
+    let opts = queue::QueueOptions {
+        max_attempts: body.max_attempts.unwrap_or(defaults.max_attempts),
+        dedup_window_ms: body
+            .dedup_window_ms
+            .unwrap_or(defaults.dedup_window_ms),
+        retention_days: body.retention_days.or(defaults.retention_days),
+        backoff_base_ms: body.backoff_base_ms.or(defaults.backoff_base_ms),
+        backoff_multiplier: body
+            .backoff_multiplier
+            .unwrap_or(defaults.backoff_multiplier),
+        backoff_max_ms: body.backoff_max_ms.or(defaults.backoff_max_ms),
+        backoff_jitter: body.backoff_jitter.unwrap_or(defaults.backoff_jitter),
+        default_visibility_ms: body
+            .default_visibility_ms
+            .unwrap_or(defaults.default_visibility_ms),
+        default_delay_ms: body
+            .default_delay_ms
+            .unwrap_or(defaults.default_delay_ms),
+        max_deliveries_per_second: body
+            .max_deliveries_per_second
+            .or(defaults.max_deliveries_per_second),
+        max_payload_bytes: body
+            .max_payload_bytes
+            .or(defaults.max_payload_bytes),
+        payload_schema: body.payload_schema.or(defaults.payload_schema),
+        schema_mode: body.schema_mode.unwrap_or(defaults.schema_mode),
+        strict_fifo: body.strict_fifo.unwrap_or(defaults.strict_fifo),
+        fair: body.fair.unwrap_or(defaults.fair),
+        ordering: body.ordering.unwrap_or(defaults.ordering),
+        max_depth: body.max_depth.or(defaults.max_depth),
+        max_lease_expirations: body
+            .max_lease_expirations
+            .or(defaults.max_lease_expirations),
+    };
+    // Create queue via service layer
+    let new_q = queue::create_queue_with(&db, &body.name, &opts)
+        .await
+        .map_err(error_response)?;
+    let settings = serde_json::to_value(&new_q).unwrap_or_default();
+    audit::record(&db, &actor, "queue.create", Some(&new_q.name), settings)
+        .await;
+    Ok((StatusCode::CREATED, Json(new_q)))
+}
+
+// Get queue details
+#[utoipa::path(
+    get,
+    path = "/queues/{name}",
+    tag = "queues",
+    params(("name" = String, Path, description = "Queue name")),
+    responses(
+        (status = 200, description = "Queue settings", body = Queue),
+        (status = 404, description = "Queue not found")
+    )
+)]
+#[tracing::instrument(level = "debug", skip_all, fields(queue = %name))]
+async fn show_queue(
+    Path(name): Path<String>,
+    State(db): State<Db>,
+) -> Result<Json<Queue>, (StatusCode, String)> {
+    let q = queue::show_queue(&db, &name).await.map_err(error_response)?;
+    Ok(Json(q))
+}
+
+// Change a queue's settings; fields left out of the body are unchanged
+#[utoipa::path(
+    patch,
+    path = "/queues/{name}",
+    tag = "queues",
+    params(("name" = String, Path, description = "Queue name")),
+    request_body = queue::QueueUpdate,
+    responses(
+        (status = 200, description = "Updated queue", body = Queue),
+        (status = 400, description = "Invalid setting"),
+        (status = 404, description = "Queue not found")
+    )
+)]
+#[tracing::instrument(level = "debug", skip_all, fields(queue = %name))]
+async fn update_queue(
+    Path(name): Path<String>,
+    State(db): State<Db>,
+    Extension(actor): Extension<Actor>,
+    Json(body): Json<queue::QueueUpdate>,
+) -> Result<Json<Queue>, (StatusCode, String)> {
+    let q =
+        queue::update_queue(&db, &name, &body).await.map_err(error_response)?;
+    let action = match body.paused {
+        Some(true) => "queue.pause",
+        Some(false) => "queue.resume",
+        None => "queue.update",
+    };
+    let settings = serde_json::to_value(&q).unwrap_or_default();
+    audit::record(&db, &actor, action, Some(&name), settings).await;
+    Ok(Json(q))
+}
+
+// Query parameters for fetching a queue's payload schema
+#[derive(Deserialize, IntoParams)]
+struct SchemaParams {
+    /// Version to fetch (default: the current one)
+    version: Option<i64>,
+}
+
+// Fetch a queue's payload schema, so consumers can check what producers send
+#[utoipa::path(
+    get,
+    path = "/queues/{name}/schema",
+    tag = "queues",
+    params(("name" = String, Path, description = "Queue name"), SchemaParams),
+    responses(
+        (status = 200, description = "The schema version; `payload_schema` is null for a version that removed the schema", body = QueueSchema),
+        (status = 404, description = "Queue not found, or it has no schema (version)")
+    )
+)]
+#[tracing::instrument(level = "debug", skip_all, fields(queue = %name))]
+async fn show_queue_schema(
+    Path(name): Path<String>,
+    Query(params): Query<SchemaParams>,
+    State(db): State<Db>,
+) -> Result<Json<QueueSchema>, (StatusCode, String)> {
+    let schema = queue::queue_schema(&db, &name, params.version)
+        .await
+        .map_err(error_response)?;
+    Ok(Json(schema))
+}
+
+// List every version of a queue's payload schema
+#[utoipa::path(
+    get,
+    path = "/queues/{name}/schema/versions",
+    tag = "queues",
+    params(("name" = String, Path, description = "Queue name")),
+    responses(
+        (status = 200, description = "Schema versions, newest first", body = [QueueSchema]),
+        (status = 404, description = "Queue not found")
+    )
+)]
+#[tracing::instrument(level = "debug", skip_all, fields(queue = %name))]
+async fn list_queue_schemas(
+    Path(name): Path<String>,
+    State(db): State<Db>,
+) -> Result<Json<Vec<QueueSchema>>, (StatusCode, String)> {
+    let versions = queue::queue_schema_history(&db, &name)
+        .await
+        .map_err(error_response)?;
+    Ok(Json(versions))
+}
+
+// Query parameters for deleting a queue
+#[derive(Deserialize, IntoParams)]
+struct DeleteQueueParams {
+    /// Move the queue to the trash, restorable for 7 days, instead of
+    /// deleting it (default: false)
+    #[serde(default)]
+    soft: bool,
+}
+
+// Delete a queue, or move it to the trash
+#[utoipa::path(
+    delete,
+    path = "/queues/{name}",
+    tag = "queues",
+    params(("name" = String, Path, description = "Queue name"), DeleteQueueParams),
+    responses(
+        (status = 204, description = "Queue and its messages deleted, or trashed"),
+        (status = 404, description = "Queue not found")
+    )
+)]
+#[tracing::instrument(level = "debug", skip_all, fields(queue = %name))]
+async fn delete_queue(
+    Path(name): Path<String>,
+    Query(params): Query<DeleteQueueParams>,
+    State(db): State<Db>,
+    Extension(actor): Extension<Actor>,
+) -> StatusCode {
+    let deleted = if params.soft {
+        queue::trash_queue(&db, &name).await
+    } else {
+        queue::delete_queue(&db, &name).await
+    };
+    match deleted {
+        Ok(true) => {
+            let soft = json!({"soft": params.soft});
+            audit::record(&db, &actor, "queue.delete", Some(&name), soft).await;
+            StatusCode::NO_CONTENT
+        }
+        _ => StatusCode::NOT_FOUND,
+    }
+}
+
+// Bring a queue back out of the trash
+#[utoipa::path(
+    post,
+    path = "/queues/{name}/restore",
+    tag = "queues",
+    params(("name" = String, Path, description = "Queue name")),
+    responses(
+        (status = 200, description = "Queue restored", body = Queue),
+        (status = 404, description = "No queue of that name in the trash")
+    )
+)]
+#[tracing::instrument(level = "debug", skip_all, fields(queue = %name))]
+async fn restore_queue(
+    Path(name): Path<String>,
+    State(db): State<Db>,
+    Extension(actor): Extension<Actor>,
+) -> Result<Json<Queue>, (StatusCode, String)> {
+    let q = queue::restore_queue(&db, &name).await.map_err(error_response)?;
+    let none = serde_json::Value::Null;
+    audit::record(&db, &actor, "queue.restore", Some(&name), none).await;
+    Ok(Json(q))
+}
+
+// List the queues in the trash
+#[utoipa::path(
+    get,
+    path = "/admin/trash",
+    tag = "admin",
+    responses(
+        (status = 200, description = "Trashed queues, longest trashed first; each is purged 7 days after its `deleted_at`", body = [Queue])
+    )
+)]
+async fn list_trash(
+    State(db): State<Db>
+) -> Result<Json<Vec<Queue>>, (StatusCode, String)> {
+    let queues = queue::list_trash(&db).await.map_err(error_response)?;
+    Ok(Json(queues))
+}
+
+// List the administrative actions recorded in the audit log
+#[utoipa::path(
+    get,
+    path = "/admin/audit",
+    tag = "admin",
+    params(AuditParams),
+    responses(
+        (status = 200, description = "Audit log entries, newest first", body = [AuditEntry]),
+        (status = 400, description = "Invalid window")
+    )
+)]
+async fn list_audit_log(
+    Query(params): Query<AuditParams>,
+    State(db): State<Db>,
+) -> Result<Json<Vec<AuditEntry>>, (StatusCode, String)> {
+    let filter = audit::audit_filter(
+        params.action,
+        params.queue,
+        params.actor,
+        params.since.as_deref(),
+        db::now_ms(),
+    )
+    .map_err(error_response)?;
+    let limit = params.limit.unwrap_or(audit::DEFAULT_AUDIT_LIMIT);
+    let entries = audit::list_entries(&db, &filter, limit)
+        .await
+        .map_err(error_response)?;
+    Ok(Json(entries))
+}
+
+// Create a queue with another's settings and, optionally, its messages
+#[utoipa::path(
+    post,
+    path = "/queues/{name}/clone",
+    tag = "queues",
+    params(("name" = String, Path, description = "Queue to copy")),
+    request_body = CloneBody,
+    responses(
+        (status = 201, description = "`{\"queue\": Queue, \"copied\": n}`", body = Object),
+        (status = 404, description = "Queue not found"),
+        (status = 409, description = "Target queue already exists")
+    )
+)]
+#[tracing::instrument(level = "debug", skip_all, fields(queue = %name, to = %body.to))]
+async fn clone_queue(
+    Path(name): Path<String>,
+    State(db): State<Db>,
+    Extension(grant): Extension<Grant>,
+    Extension(actor): Extension<Actor>,
+    Json(body): Json<CloneBody>,
+) -> Result<(StatusCode, Json<serde_json::Value>), (StatusCode, String)> {
+    authorize(&grant, Permission::Admin, &body.to)?;
+    let (q, copied) =
+        queue::clone_queue(&db, &name, &body.to, body.with_messages)
+            .await
+            .map_err(error_response)?;
+    let clone = json!({
+        "from": name,
+        "with_messages": body.with_messages,
+        "copied": copied,
+    });
+    audit::record(&db, &actor, "queue.clone", Some(&q.name), clone).await;
+    Ok((StatusCode::CREATED, Json(json!({"queue": q, "copied": copied}))))
+}
+
+// Get queue stats
+#[utoipa::path(
+    get,
+    path = "/queues/{name}/stats",
+    tag = "queues",
+    params(("name" = String, Path, description = "Queue name")),
+    responses(
+        (status = 200, description = "Message counts by state, enqueue and ack counters, oldest ready message age and average time to ack", body = Object),
+        (status = 404, description = "Queue not found")
+    )
+)]
+#[tracing::instrument(level = "debug", skip_all, fields(queue = %name))]
+async fn queue_stats(
+    Path(name): Path<String>,
+    State(db): State<Db>,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let stats = queue::stats(&db, &name).await.map_err(error_response)?;
+    Ok(Json(stats))
+}
+
+// Query parameters for the stats history of a queue
+#[derive(Deserialize, IntoParams)]
+struct StatsHistoryParams {
+    /// How far back to look: a number with a unit of s, m, h or d
+    /// (default: 1h)
+    window: Option<String>,
+}
+
+// Stats snapshots of a queue over a recent window
+#[utoipa::path(
+    get,
+    path = "/queues/{name}/stats/history",
+    tag = "queues",
+    params(("name" = String, Path, description = "Queue name"), StatsHistoryParams),
+    responses(
+        (status = 200, description = "Snapshots recorded about once a minute, oldest first", body = [StatsSample]),
+        (status = 400, description = "Invalid window"),
+        (status = 404, description = "Queue not found")
+    )
+)]
+#[tracing::instrument(level = "debug", skip_all, fields(queue = %name))]
+async fn queue_stats_history(
+    Path(name): Path<String>,
+    Query(params): Query<StatsHistoryParams>,
+    State(db): State<Db>,
+) -> Result<Json<Vec<StatsSample>>, (StatusCode, String)> {
+    let window = queue::parse_window(params.window.as_deref().unwrap_or("1h"))
+        .map_err(error_response)?;
+    let samples = queue::stats_history(&db, &name, window)
+        .await
+        .map_err(error_response)?;
+    Ok(Json(samples))
+}
+
+// Peek messages in a queue
+#[utoipa::path(
+    get,
+    path = "/queues/{name}/messages",
+    tag = "messages",
+    params(("name" = String, Path, description = "Queue name"), PeekParams),
+    responses(
+        (status = 200, description = "Messages in delivery order, not leased by the peek", body = [Message]),
+        (status = 400, description = "Malformed filter")
+    )
+)]
+#[tracing::instrument(level = "debug", skip_all, fields(queue = %name))]
+async fn peek_messages(
+    Path(name): Path<String>,
+    Query(params): Query<PeekParams>,
+    State(db): State<Db>,
+) -> Result<Json<Vec<Message>>, (StatusCode, String)> {
+    let limit = params.limit.unwrap_or(1);
+    let header = params
+        .header
+        .as_deref()
+        .map(queue::parse_header)
+        .transpose()
+        .map_err(error_response)?;
+    let json_path = params
+        .json_path
+        .as_deref()
+        .map(queue::parse_json_filter)
+        .transpose()
+        .map_err(error_response)?;
+    let filter = PeekFilter {
+        offset: params.offset.unwrap_or(0),
+        after_id: params.after_id,
+        created_after: params.created_after,
+        created_before: params.created_before,
+        header,
+        payload_contains: params.contains,
+        json_path,
+        state: params.state.unwrap_or_default(),
+    };
+    let msgs = queue::peek_queue_filtered(&db, &name, limit, &filter)
+        .await
+        .map_err(error_response)?;
+    Ok(Json(msgs))
+}
+
+// Query parameters for sampling a queue's messages
+#[derive(Deserialize, IntoParams)]
+struct SampleParams {
+    /// How many ready messages to return, at most 100 (default: 10)
+    n: Option<i64>,
+}
+
+// A random sample of a queue's ready messages
+#[utoipa::path(
+    get,
+    path = "/queues/{name}/sample",
+    tag = "messages",
+    params(("name" = String, Path, description = "Queue name"), SampleParams),
+    responses(
+        (status = 200, description = "Ready messages in random order, not leased", body = [Message]),
+        (status = 400, description = "`n` outside 1..=100"),
+        (status = 404, description = "Queue not found")
+    )
+)]
+#[tracing::instrument(level = "debug", skip_all, fields(queue = %name))]
+async fn sample_messages(
+    Path(name): Path<String>,
+    Query(params): Query<SampleParams>,
+    State(db): State<Db>,
+) -> Result<Json<Vec<Message>>, (StatusCode, String)> {
+    let n = params.n.unwrap_or(10);
+    let msgs =
+        queue::sample_messages(&db, &name, n).await.map_err(error_response)?;
+    Ok(Json(msgs))
+}
+
+// Search a queue's messages by a value in their JSON payload
+#[utoipa::path(
+    get,
+    path = "/queues/{name}/messages/search",
+    tag = "messages",
+    params(("name" = String, Path, description = "Queue name"), SearchParams),
+    responses(
+        (status = 200, description = "Matching messages in id order, including leased, delayed and dead-lettered ones", body = [Message]),
+        (status = 400, description = "Invalid JSON path"),
+        (status = 404, description = "Queue not found")
+    )
+)]
+#[tracing::instrument(level = "debug", skip_all, fields(queue = %name))]
+async fn search_messages(
+    Path(name): Path<String>,
+    Query(params): Query<SearchParams>,
+    State(db): State<Db>,
+) -> Result<Json<Vec<Message>>, (StatusCode, String)> {
+    let msgs = queue::search_messages(
+        &db,
+        &name,
+        &params.jsonpath,
+        params.value.as_deref(),
+        params.after_id,
+        params.limit.unwrap_or(20),
+    )
+    .await
+    .map_err(error_response)?;
+    Ok(Json(msgs))
+}
+
+// Query parameters for purging a queue
+#[derive(Deserialize, IntoParams)]
+struct PurgeParams {
+    /// Messages deleted per transaction (default: 10000)
+    batch_size: Option<usize>,
+}
+
+// Query parameters for deleting a queue's messages; without `filter` or
+// `older_than` every live message is deleted
+#[derive(Deserialize, IntoParams)]
+struct DeleteMessagesParams {
+    /// Messages deleted per transaction (default: 10000)
+    batch_size: Option<usize>,
+    /// Only messages whose payload matches a JSON path predicate, e.g.
+    /// `$.type == "spam"`
+    filter: Option<String>,
+    /// Only messages enqueued longer ago than this, e.g. `1h` or `7d`
+    older_than: Option<String>,
+}
+
+// Purge all messages in a queue, or those matching a filter
+#[utoipa::path(
+    delete,
+    path = "/queues/{name}/messages",
+    tag = "messages",
+    params(("name" = String, Path, description = "Queue name"), DeleteMessagesParams),
+    responses(
+        (status = 200, description = "`{\"deleted\": n}`", body = Object),
+        (status = 400, description = "Invalid filter, age or batch size"),
+        (status = 404, description = "Queue not found")
+    )
+)]
+#[tracing::instrument(level = "debug", skip_all, fields(queue = %name))]
+async fn purge_messages(
+    Path(name): Path<String>,
+    Query(params): Query<DeleteMessagesParams>,
+    State(db): State<Db>,
+    Extension(actor): Extension<Actor>,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let batch_size = params.batch_size.unwrap_or(queue::PURGE_BATCH);
+    let deleted = if params.filter.is_none() && params.older_than.is_none() {
+        queue::purge_queue_batched(&db, &name, batch_size, |_| {}).await
+    } else {
+        let filter = queue::remove_filter(
+            params.filter.as_deref(),
+            params.older_than.as_deref(),
+            db::now_ms(),
+        )
+        .map_err(error_response)?;
+        queue::remove_messages_matching(&db, &name, &filter, batch_size, |_| {})
+            .await
+    }
+    .map_err(error_response)?;
+    let purge = json!({
+        "filter": params.filter,
+        "older_than": params.older_than,
+        "purged": deleted,
+    });
+    audit::record(&db, &actor, "queue.purge", Some(&name), purge).await;
+    Ok(Json(json!({"deleted": deleted})))
+}
+
+// Start purging a queue in the background
+#[utoipa::path(
+    post,
+    path = "/queues/{name}/purges",
+    tag = "messages",
+    params(("name" = String, Path, description = "Queue name"), PurgeParams),
+    responses(
+        (status = 202, description = "Purge queued as an admin job; poll its progress by id", body = AdminJob),
+        (status = 400, description = "Invalid batch size"),
+        (status = 404, description = "Queue not found")
+    )
+)]
+#[tracing::instrument(level = "debug", skip_all, fields(queue = %name))]
+async fn start_purge(
+    Path(name): Path<String>,
+    Query(params): Query<PurgeParams>,
+    State(db): State<Db>,
+    Extension(actor): Extension<Actor>,
+) -> Result<(StatusCode, Json<AdminJob>), (StatusCode, String)> {
+    let kind = AdminJobKind::Purge;
+    let job =
+        queue::start_admin_job(&db, kind, Some(&name), None, params.batch_size)
+            .await
+            .map_err(error_response)?;
+    record_job_start(&db, &actor, &job).await;
+    Ok((StatusCode::ACCEPTED, Json(job)))
+}
+
+// Progress of a background purge
+#[utoipa::path(
+    get,
+    path = "/queues/{name}/purges/{id}",
+    tag = "messages",
+    params(
+        ("name" = String, Path, description = "Queue name"),
+        ("id" = i64, Path, description = "Admin job id")
+    ),
+    responses(
+        (status = 200, description = "The purge job, with the messages deleted so far as its `progress`", body = AdminJob),
+        (status = 404, description = "No such purge of the queue")
+    )
+)]
+async fn purge_status(
+    Path((name, id)): Path<(String, i64)>,
+    State(db): State<Db>,
+) -> Result<Json<AdminJob>, (StatusCode, String)> {
+    match queue::admin_job(&db, id).await {
+        Ok(job)
+            if job.kind == "purge" && job.queue.as_deref() == Some(&name) =>
+        {
+            Ok(Json(job))
+        }
+        Ok(_) | Err(SqewError::JobNotFound(_)) => Err((
+            StatusCode::NOT_FOUND,
+            format!("Purge {id} not found on queue '{name}'"),
+        )),
+        Err(e) => Err(error_response(e)),
+    }
+}
+
+// Enqueue a single message into a queue via HTTP
+#[utoipa::path(
+    post,
+    path = "/queues/{name}/messages",
+    tag = "messages",
+    params(("name" = String, Path, description = "Queue name"), RawEnqueueParams),
+    request_body(
+        content(
+            (EnqueueBody = "application/json"),
+            (Vec<u8> = "application/octet-stream")
+        ),
+        description = "An `EnqueueBody`, or with any non-JSON `Content-Type` the raw payload itself, e.g. protobuf or msgpack, with the options as query parameters. Raw payloads are stored as base64 in a JSON string, with the `Content-Type` in the `content-type` header."
+    ),
+    responses(
+        (status = 201, description = "Enqueued (or deduplicated) message", body = Message),
+        (status = 400, description = "The payload does not match the queue's schema: `{\"error\": \"schema_violation\", \"message\", \"violations\"}`", body = Object),
+        (status = 404, description = "Queue not found"),
+        (status = 413, description = "The payload exceeds the queue's or the server's size limit: `{\"error\": \"payload_too_large\", \"message\", \"size\", \"limit\"}`", body = Object),
+        (status = 429, description = "The queue holds its `max_depth` of unacked messages (after waiting `wait_ms`): `{\"error\": \"queue_full\", \"message\", \"max_depth\"}`, with `Retry-After`", body = Object)
+    )
+)]
+#[tracing::instrument(level = "debug", skip_all, fields(queue = %name))]
+async fn enqueue_message_http(
+    Path(name): Path<String>,
+    Query(params): Query<RawEnqueueParams>,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    bytes: Bytes,
+) -> Result<(StatusCode, Json<Message>), Response> {
+    let content_type =
+        headers.get(header::CONTENT_TYPE).and_then(|v| v.to_str().ok());
+    let body = match content_type {
+        Some(ct) if !queue::is_json_content_type(ct) => {
+            params.into_body(ct, &bytes)
+        }
+        _ => {
+            Json::<EnqueueBody>::from_bytes(&bytes)
+                .map_err(IntoResponse::into_response)?
+                .0
+        }
+    };
+    state
+        .check_payload_size(&body.payload)
+        .map_err(|e| payload_rejected_response(&e))?;
+    let deliver_at = body
+        .deliver_at
+        .as_deref()
+        .map(queue::parse_deliver_at)
+        .transpose()
+        .map_err(enqueue_error_response)?;
+    let opts = queue::EnqueueOptions {
+        delay_ms: body.delay_ms,
+        deliver_at,
+        priority: body.priority.unwrap_or(0),
+        ttl_ms: body.ttl_ms,
+        dedup_key: body.dedup_key,
+        group_id: body.group_id,
+        headers: body.headers,
+        trace_id: body.trace_id,
+        fair_key: body.fair_key,
+        sort_key: body.sort_key,
+        reply_to: body.reply_to,
+        wait_ms: body.wait_ms.map(|ms| ms.clamp(0, MAX_POLL_WAIT_MS)),
+    };
+    let created =
+        queue::enqueue_message_with(&state.db, &name, &body.payload, &opts)
+            .await
+            .map_err(enqueue_error_response)?;
+    state.notifier.notify(&name);
+    Ok((StatusCode::CREATED, Json(created)))
+}
+
+// Enqueue messages into several queues atomically via HTTP
+#[utoipa::path(
+    post,
+    path = "/transactions/enqueue",
+    tag = "messages",
+    request_body = TransactionBody,
+    responses(
+        (status = 201, description = "Every message, enqueued (or deduplicated) in the order given", body = [Message]),
+        (status = 400, description = "No messages, more than 1000, or a payload that does not match its queue's schema; nothing was enqueued"),
+        (status = 404, description = "A queue was not found; nothing was enqueued"),
+        (status = 413, description = "A payload exceeds its queue's or the server's size limit; nothing was enqueued", body = Object),
+        (status = 429, description = "A queue lacks room for its messages under its `max_depth`; nothing was enqueued", body = Object),
+        (status = 501, description = "The server runs on Postgres")
+    )
+)]
+#[tracing::instrument(level = "debug", skip_all, fields(n = body.messages.len()))]
+async fn enqueue_transaction(
+    State(state): State<AppState>,
+    Extension(grant): Extension<Grant>,
+    Json(body): Json<TransactionBody>,
+) -> Result<(StatusCode, Json<Vec<Message>>), Response> {
+    let mut messages = Vec::with_capacity(body.messages.len());
+    for m in body.messages {
+        authorize(&grant, Permission::Produce, &m.queue)
+            .map_err(IntoResponse::into_response)?;
+        state
+            .check_payload_size(&m.payload)
+            .map_err(|e| payload_rejected_response(&e))?;
+        let deliver_at = m
+            .deliver_at
+            .as_deref()
+            .map(queue::parse_deliver_at)
+            .transpose()
+            .map_err(enqueue_error_response)?;
+        let opts = queue::EnqueueOptions {
+            delay_ms: m.delay_ms,
+            deliver_at,
+            priority: m.priority.unwrap_or(0),
+            ttl_ms: m.ttl_ms,
+            dedup_key: m.dedup_key,
+            group_id: m.group_id,
+            headers: m.headers,
+            trace_id: m.trace_id,
+            fair_key: m.fair_key,
+            sort_key: m.sort_key,
+            reply_to: m.reply_to,
+            wait_ms: None,
+        };
+        messages.push((m.queue, m.payload, opts));
+    }
+    let created = queue::enqueue_transaction(&state.db, &messages)
+        .await
+        .map_err(enqueue_error_response)?;
+    // Committed: wake long-polling consumers of every queue written
+    let mut queues: Vec<&str> =
+        messages.iter().map(|(q, _, _)| q.as_str()).collect();
+    queues.sort_unstable();
+    queues.dedup();
+    for q in queues {
+        state.notifier.notify(q);
+    }
+    Ok((StatusCode::CREATED, Json(created)))
+}
+
+// The response to a failed enqueue: structured bodies for rejected payloads
+// and full queues, plain errors otherwise
+fn enqueue_error_response(e: SqewError) -> Response {
+    match e {
+        SqewError::PayloadRejected(rejected) => {
+            payload_rejected_response(&rejected)
+        }
+        SqewError::QueueFull { max_depth, .. } => {
+            queue_full_response(&e, max_depth)
+        }
+        e => error_response(e).into_response(),
+    }
+}
+
+// A structured error body for a rejected payload: 413 when too large, 400
+// when it breaks the queue's schema
+fn payload_rejected_response(e: &PayloadRejected) -> Response {
+    let status = match e {
+        PayloadRejected::PayloadTooLarge { .. } => {
+            StatusCode::PAYLOAD_TOO_LARGE
+        }
+        PayloadRejected::SchemaViolation { .. } => StatusCode::BAD_REQUEST,
+    };
+    let mut body = serde_json::to_value(e).unwrap_or_else(|_| json!({}));
+    body["message"] = json!(e.to_string());
+    (status, Json(body)).into_response()
+}
+
+// Seconds a producer refused by a full queue is asked to wait
+const QUEUE_FULL_RETRY_AFTER_SECS: u64 = 1;
+
+// A 429 for an enqueue into a queue at its `max_depth`, asking the producer
+// to retry once consumers have made room
+fn queue_full_response(
+    e: &SqewError,
+    max_depth: i64,
+) -> Response {
+    let body = json!({
+        "error": "queue_full",
+        "message": e.to_string(),
+        "max_depth": max_depth,
+    });
+    (
+        StatusCode::TOO_MANY_REQUESTS,
+        [(header::RETRY_AFTER, QUEUE_FULL_RETRY_AFTER_SECS.to_string())],
+        Json(body),
+    )
+        .into_response()
+}
+
+// The status code and message answering a failed queue operation
+fn error_response(e: SqewError) -> (StatusCode, String) {
+    let status = match &e {
+        SqewError::QueueNotFound(_)
+        | SqewError::MessageNotFound(_)
+        | SqewError::PublicIdNotFound(_)
+        | SqewError::JobNotFound(_)
+        | SqewError::GroupNotFound { .. }
+        | SqewError::SchemaNotFound { .. } => StatusCode::NOT_FOUND,
+        SqewError::QueueExists(_)
+        | SqewError::QueueTrashed(_)
+        | SqewError::JobFinished { .. }
+        | SqewError::GroupExists { .. }
+        | SqewError::BackupExists(_) => StatusCode::CONFLICT,
+        SqewError::Invalid(_) => StatusCode::BAD_REQUEST,
+        SqewError::QueueFull { .. } => StatusCode::TOO_MANY_REQUESTS,
+        SqewError::PayloadRejected(PayloadRejected::PayloadTooLarge {
+            ..
+        }) => StatusCode::PAYLOAD_TOO_LARGE,
+        SqewError::PayloadRejected(PayloadRejected::SchemaViolation {
+            ..
+        }) => StatusCode::BAD_REQUEST,
+        SqewError::Unsupported(_) => StatusCode::NOT_IMPLEMENTED,
+        SqewError::Timeout(_) | SqewError::Busy { .. } => {
+            StatusCode::SERVICE_UNAVAILABLE
+        }
+        SqewError::NoReply { .. } => StatusCode::GATEWAY_TIMEOUT,
+        SqewError::Storage { .. }
+        | SqewError::Io { .. }
+        | SqewError::Other(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    (status, e.to_string())
+}
+
+// List dead-lettered messages in a queue
+#[utoipa::path(
+    get,
+    path = "/queues/{name}/dlq",
+    tag = "dlq",
+    params(("name" = String, Path, description = "Queue name"), PeekParams),
+    responses(
+        (status = 200, description = "Dead letters, oldest first", body = [Message]),
+        (status = 404, description = "Queue not found")
+    )
+)]
+#[tracing::instrument(level = "debug", skip_all, fields(queue = %name))]
+async fn list_dead_letters(
+    Path(name): Path<String>,
+    Query(params): Query<PeekParams>,
+    State(db): State<Db>,
+) -> Result<Json<Vec<Message>>, (StatusCode, String)> {
+    let limit = params.limit.unwrap_or(10);
+    let msgs = queue::list_dead_letters(&db, &name, limit)
+        .await
+        .map_err(error_response)?;
+    Ok(Json(msgs))
+}
+
+// Requeue dead letters back into the queue
+#[utoipa::path(
+    post,
+    path = "/queues/{name}/dlq/redrive",
+    tag = "dlq",
+    params(("name" = String, Path, description = "Queue name")),
+    request_body(content = Option<RedriveBody>, description = "Omit to redrive all"),
+    responses(
+        (status = 200, description = "`{\"redriven\": n}`", body = Object),
+        (status = 404, description = "Queue not found")
+    )
+)]
+#[tracing::instrument(level = "debug", skip_all, fields(queue = %name))]
+async fn redrive_dead_letters(
+    Path(name): Path<String>,
+    State(db): State<Db>,
+    Extension(actor): Extension<Actor>,
+    body: Option<Json<RedriveBody>>,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let Json(body) = body.unwrap_or_default();
+    let ids = queue::resolve_message_ids(&db, &body.ids)
+        .await
+        .map_err(error_response)?;
+    let redriven = queue::redrive_dead_letters(&db, &name, &ids)
+        .await
+        .map_err(error_response)?;
+    let redrive = json!({"ids": ids, "redriven": redriven});
+    audit::record(&db, &actor, "dlq.redrive", Some(&name), redrive).await;
+    Ok(Json(json!({"redriven": redriven})))
+}
+
+// Re-enqueue archived (acked) messages with fresh attempts
+#[utoipa::path(
+    post,
+    path = "/queues/{name}/archive/replay",
+    tag = "messages",
+    params(("name" = String, Path, description = "Queue name")),
+    request_body(content = Option<ReplayBody>, description = "Omit to replay the whole archive"),
+    responses(
+        (status = 200, description = "`{\"replayed\": n}`", body = Object),
+        (status = 400, description = "`from` is after `to`"),
+        (status = 404, description = "Queue not found")
+    )
+)]
+#[tracing::instrument(level = "debug", skip_all, fields(queue = %name))]
+async fn replay_archived_messages(
+    Path(name): Path<String>,
+    State(state): State<AppState>,
+    Extension(actor): Extension<Actor>,
+    body: Option<Json<ReplayBody>>,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let Json(body) = body.unwrap_or_default();
+    let replayed = queue::replay_messages(
+        &state.db,
+        &name,
+        body.from,
+        body.to,
+        body.contains.as_deref(),
+    )
+    .await
+    .map_err(error_response)?;
+    if replayed > 0 {
+        state.notifier.notify(&name);
+    }
+    let replay = json!({
+        "from": body.from,
+        "to": body.to,
+        "contains": body.contains,
+        "replayed": replayed,
+    });
+    audit::record(&state.db, &actor, "archive.replay", Some(&name), replay)
+        .await;
+    Ok(Json(json!({"replayed": replayed})))
+}
+
+// List the consumer groups of a queue
+#[utoipa::path(
+    get,
+    path = "/queues/{name}/groups",
+    tag = "groups",
+    params(("name" = String, Path, description = "Queue name")),
+    responses(
+        (status = 200, description = "Consumer groups", body = [ConsumerGroup]),
+        (status = 404, description = "Queue not found")
+    )
+)]
+#[tracing::instrument(level = "debug", skip_all, fields(queue = %name))]
+async fn list_consumer_groups(
+    Path(name): Path<String>,
+    State(db): State<Db>,
+) -> Result<Json<Vec<ConsumerGroup>>, (StatusCode, String)> {
+    let groups = queue::list_consumer_groups(&db, &name)
+        .await
+        .map_err(error_response)?;
+    Ok(Json(groups))
+}
+
+// Create a consumer group; it receives messages enqueued from now on
+#[utoipa::path(
+    post,
+    path = "/queues/{name}/groups",
+    tag = "groups",
+    params(("name" = String, Path, description = "Queue name")),
+    request_body = CreateGroupBody,
+    responses(
+        (status = 201, description = "Consumer group created", body = ConsumerGroup),
+        (status = 404, description = "Queue not found"),
+        (status = 409, description = "A group with this name already exists")
+    )
+)]
+#[tracing::instrument(level = "debug", skip_all, fields(queue = %name, group = %body.name))]
+async fn create_consumer_group(
+    Path(name): Path<String>,
+    State(db): State<Db>,
+    Extension(actor): Extension<Actor>,
+    Json(body): Json<CreateGroupBody>,
+) -> Result<(StatusCode, Json<ConsumerGroup>), (StatusCode, String)> {
+    let g = queue::create_consumer_group(&db, &name, &body.name)
+        .await
+        .map_err(error_response)?;
+    let group = json!({"group": g.name});
+    audit::record(&db, &actor, "group.create", Some(&name), group).await;
+    Ok((StatusCode::CREATED, Json(g)))
+}
+
+// Delete a consumer group and its delivery state
+#[utoipa::path(
+    delete,
+    path = "/queues/{name}/groups/{group}",
+    tag = "groups",
+    params(
+        ("name" = String, Path, description = "Queue name"),
+        ("group" = String, Path, description = "Consumer group name")
+    ),
+    responses(
+        (status = 204, description = "Consumer group deleted"),
+        (status = 404, description = "Queue or consumer group not found")
+    )
+)]
+#[tracing::instrument(level = "debug", skip_all, fields(queue = %name, group = %group))]
+async fn delete_consumer_group(
+    Path((name, group)): Path<(String, String)>,
+    State(db): State<Db>,
+    Extension(actor): Extension<Actor>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let removed = queue::delete_consumer_group(&db, &name, &group)
+        .await
+        .map_err(error_response)?;
+    if removed {
+        let group = json!({"group": group});
+        audit::record(&db, &actor, "group.delete", Some(&name), group).await;
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err((
+            StatusCode::NOT_FOUND,
+            format!("Consumer group '{}' not found", group),
+        ))
+    }
+}
+
+// List the alarms of a queue
+#[utoipa::path(
+    get,
+    path = "/queues/{name}/alarms",
+    tag = "alarms",
+    params(("name" = String, Path, description = "Queue name")),
+    responses(
+        (status = 200, description = "Alarms", body = [Alarm]),
+        (status = 404, description = "Queue not found")
+    )
+)]
+#[tracing::instrument(level = "debug", skip_all, fields(queue = %name))]
+async fn list_alarms(
+    Path(name): Path<String>,
+    State(db): State<Db>,
+) -> Result<Json<Vec<Alarm>>, (StatusCode, String)> {
+    let alarms =
+        queue::list_alarms(&db, Some(&name)).await.map_err(error_response)?;
+    Ok(Json(alarms))
+}
+
+// Add an alarm posting to a webhook when a queue metric stays too high
+#[utoipa::path(
+    post,
+    path = "/queues/{name}/alarms",
+    tag = "alarms",
+    params(("name" = String, Path, description = "Queue name")),
+    request_body = CreateAlarmBody,
+    responses(
+        (status = 201, description = "Alarm created", body = Alarm),
+        (status = 400, description = "Invalid metric, threshold or URL"),
+        (status = 404, description = "Queue not found")
+    )
+)]
+#[tracing::instrument(level = "debug", skip_all, fields(queue = %name, metric = %body.metric))]
+async fn create_alarm(
+    Path(name): Path<String>,
+    State(db): State<Db>,
+    Extension(actor): Extension<Actor>,
+    Json(body): Json<CreateAlarmBody>,
+) -> Result<(StatusCode, Json<Alarm>), (StatusCode, String)> {
+    let a = queue::add_alarm(
+        &db,
+        &name,
+        &body.metric,
+        body.threshold,
+        body.for_ms,
+        &body.webhook_url,
+    )
+    .await
+    .map_err(error_response)?;
+    let alarm = serde_json::to_value(&a).unwrap_or_default();
+    audit::record(&db, &actor, "alarm.create", Some(&name), alarm).await;
+    Ok((StatusCode::CREATED, Json(a)))
+}
+
+// Delete an alarm of a queue
+#[utoipa::path(
+    delete,
+    path = "/queues/{name}/alarms/{id}",
+    tag = "alarms",
+    params(
+        ("name" = String, Path, description = "Queue name"),
+        ("id" = i64, Path, description = "Alarm ID")
+    ),
+    responses(
+        (status = 204, description = "Alarm deleted"),
+        (status = 404, description = "Queue or alarm not found")
+    )
+)]
+#[tracing::instrument(level = "debug", skip_all, fields(queue = %name, alarm_id = id))]
+async fn delete_alarm(
+    Path((name, id)): Path<(String, i64)>,
+    State(db): State<Db>,
+    Extension(actor): Extension<Actor>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let alarms =
+        queue::list_alarms(&db, Some(&name)).await.map_err(error_response)?;
+    if !alarms.iter().any(|a| a.id == id) {
+        return Err((StatusCode::NOT_FOUND, format!("Alarm {} not found", id)));
+    }
+    queue::remove_alarm(&db, id).await.map_err(error_response)?;
+    let alarm = json!({"id": id});
+    audit::record(&db, &actor, "alarm.delete", Some(&name), alarm).await;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// Move messages (live or dead-lettered) from this queue into another
+#[utoipa::path(
+    post,
+    path = "/queues/{name}/messages/move",
+    tag = "messages",
+    params(("name" = String, Path, description = "Queue name")),
+    request_body = MoveBody,
+    responses(
+        (status = 200, description = "`{\"moved\": n}`", body = Object),
+        (status = 404, description = "Queue not found")
+    )
+)]
+#[tracing::instrument(level = "debug", skip_all, fields(queue = %name, ids = ?body.ids, to = %body.to))]
+async fn move_messages(
+    Path(name): Path<String>,
+    State(state): State<AppState>,
+    Extension(grant): Extension<Grant>,
+    Extension(actor): Extension<Actor>,
+    Json(body): Json<MoveBody>,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    authorize(&grant, Permission::Admin, &body.to)?;
+    let ids = queue::resolve_message_ids(&state.db, &body.ids)
+        .await
+        .map_err(error_response)?;
+    let moved = queue::move_messages(
+        &state.db,
+        &ids,
+        &body.to,
+        Some(&name),
+        body.reset_attempts,
+    )
+    .await
+    .map_err(error_response)?;
+    if moved > 0 {
+        state.notifier.notify(&body.to);
+    }
+    let params = json!({
+        "from": name,
+        "ids": ids,
+        "reset_attempts": body.reset_attempts,
+        "moved": moved,
+    });
+    audit::record(&state.db, &actor, "queue.move", Some(&body.to), params)
+        .await;
+    Ok(Json(json!({"moved": moved})))
+}
+
+// Export every message of a queue as newline-delimited JSON
+#[utoipa::path(
+    get,
+    path = "/queues/{name}/export",
+    tag = "queues",
+    params(("name" = String, Path, description = "Queue name")),
+    responses(
+        (status = 200, description = "One message per line", body = String, content_type = "application/x-ndjson"),
+        (status = 404, description = "Queue not found")
+    )
+)]
+#[tracing::instrument(level = "debug", skip_all, fields(queue = %name))]
+async fn export_queue(
+    Path(name): Path<String>,
+    State(db): State<Db>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let mut out = Vec::new();
+    queue::export_queue(&db, &name, &mut out).await.map_err(error_response)?;
+    Ok(([(header::CONTENT_TYPE, "application/x-ndjson")], out))
+}
+
+// Query parameters for importing into a queue
+#[derive(Deserialize, IntoParams)]
+struct ImportParams {
+    /// Shape of the body: `sqew` (default), `sqs-json` or `rabbit-json`
+    #[param(value_type = Option<String>)]
+    format: Option<ImportFormat>,
+}
+
+// Import messages written by the export endpoint, or exported from SQS or
+// RabbitMQ, into a queue
+#[utoipa::path(
+    post,
+    path = "/queues/{name}/import",
+    tag = "queues",
+    params(("name" = String, Path, description = "Queue name"), ImportParams),
+    request_body(content = String, description = "Exported messages, one per line, or an SQS or RabbitMQ export", content_type = "application/x-ndjson"),
+    responses(
+        (status = 200, description = "`{\"imported\": n, \"skipped\": n}`", body = Object),
+        (status = 400, description = "Malformed export"),
+        (status = 404, description = "Queue not found")
+    )
+)]
+#[tracing::instrument(level = "debug", skip_all, fields(queue = %name))]
+async fn import_queue(
+    Path(name): Path<String>,
+    Query(params): Query<ImportParams>,
+    State(state): State<AppState>,
+    Extension(actor): Extension<Actor>,
+    body: String,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let format = params.format.unwrap_or_default();
+    let (imported, skipped) =
+        queue::import_queue_as(&state.db, &name, format, body.as_bytes())
+            .await
+            .map_err(error_response)?;
+    if imported > 0 {
+        state.notifier.notify(&name);
+    }
+    let import = json!({
+        "format": format,
+        "imported": imported,
+        "skipped": skipped,
+    });
+    audit::record(&state.db, &actor, "queue.import", Some(&name), import).await;
+    Ok(Json(json!({"imported": imported, "skipped": skipped})))
+}
+
+// Purge all dead letters in a queue
+#[utoipa::path(
+    delete,
+    path = "/queues/{name}/dlq",
+    tag = "dlq",
+    params(("name" = String, Path, description = "Queue name")),
+    responses(
+        (status = 200, description = "`{\"deleted\": n}`", body = Object),
+        (status = 404, description = "Queue not found")
+    )
+)]
+#[tracing::instrument(level = "debug", skip_all, fields(queue = %name))]
+async fn purge_dead_letters(
+    Path(name): Path<String>,
+    State(db): State<Db>,
+    Extension(actor): Extension<Actor>,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let deleted =
+        queue::purge_dead_letters(&db, &name).await.map_err(error_response)?;
+    let purge = json!({"purged": deleted});
+    audit::record(&db, &actor, "dlq.purge", Some(&name), purge).await;
+    Ok(Json(json!({"deleted": deleted})))
+}
+
+// Poll (lease) messages; each returned message carries the lease token.
+// With `wait_ms`, an empty queue holds the request open until a message is
+// enqueued or the wait elapses.
+#[utoipa::path(
+    post,
+    path = "/queues/{name}/messages/poll",
+    tag = "messages",
+    params(("name" = String, Path, description = "Queue name")),
+    request_body(content = Option<PollBody>, description = "Omit to lease one message"),
+    responses(
+        (status = 200, description = "Leased messages, each with its lease token", body = [Message]),
+        (status = 400, description = "The queue has consumer groups and no group was given"),
+        (status = 404, description = "Queue or consumer group not found")
+    )
+)]
+#[tracing::instrument(level = "debug", skip_all, fields(queue = %name))]
+async fn poll_messages(
+    Path(name): Path<String>,
+    State(state): State<AppState>,
+    body: Option<Json<PollBody>>,
+) -> Result<Json<Vec<Message>>, (StatusCode, String)> {
+    let Json(body) = body.unwrap_or_default();
+    let db = &state.db;
+    let q = queue::show_queue(db, &name).await.map_err(error_response)?;
+    let batch = body.batch.unwrap_or(1);
+    let visibility_ms = body.visibility_ms.unwrap_or(q.default_visibility_ms);
+    let wait = body.wait_ms.unwrap_or(0).clamp(0, MAX_POLL_WAIT_MS);
+    let deadline =
+        tokio::time::Instant::now() + Duration::from_millis(wait as u64);
+    let wakeup = state.notifier.handle(&name);
+    let mut shutdown = state.shutdown.subscribe();
+    loop {
+        // Register for wakeups before polling so an enqueue landing between
+        // the poll and the wait is not missed
+        let notified = wakeup.notified();
+        tokio::pin!(notified);
+        notified.as_mut().enable();
+
+        let msgs = match &body.group {
+            Some(group) => {
+                queue::poll_group_messages(
+                    db,
+                    &name,
+                    group,
+                    batch,
+                    visibility_ms,
+                )
+                .await
+            }
+            None => {
+                queue::poll_messages_as(
+                    db,
+                    &name,
+                    batch,
+                    visibility_ms,
+                    body.consumer.as_deref(),
+                )
+                .await
+            }
+        }
+        .map_err(error_response)?;
+        let now = tokio::time::Instant::now();
+        if !msgs.is_empty() || now >= deadline {
+            return Ok(Json(msgs));
+        }
+        let recheck = (deadline - now).min(POLL_RECHECK_INTERVAL);
+        tokio::select! {
+            _ = notified => {}
+            _ = tokio::time::sleep(recheck) => {}
+            // Answer empty rather than hold up a shutdown
+            _ = shutdown.wait_for(|stop| *stop) => return Ok(Json(msgs)),
+        }
+    }
+}
+
+// Reject a partially applied ack/nack so callers know some leases were lost
+fn check_lease_outcome(
+    total: usize,
+    applied: u64,
+) -> Result<(), (StatusCode, String)> {
+    let rejected = total.saturating_sub(applied as usize);
+    if rejected > 0 {
+        return Err((
+            StatusCode::CONFLICT,
+            format!(
+                "Lease token mismatch or expired for {} of {} message(s)",
+                rejected, total
+            ),
+        ));
+    }
+    Ok(())
+}
+
+// Answer a batch ack or nack with its counts and per-message results: 200
+// when every message was applied, otherwise 409 with the same body plus a
+// `message`, so callers can retry just the messages that failed
+fn batch_response(
+    mut body: serde_json::Value,
+    results: Vec<AckResult>,
+) -> Response {
+    let total = results.len();
+    let rejected = results
+        .iter()
+        .filter(|r| {
+            matches!(r.status, AckStatus::NotFound | AckStatus::LeaseMismatch)
+        })
+        .count();
+    body["results"] = json!(results);
+    if rejected == 0 {
+        return Json(body).into_response();
+    }
+    body["message"] = json!(format!(
+        "Lease token mismatch or expired for {} of {} message(s)",
+        rejected, total
+    ));
+    (StatusCode::CONFLICT, Json(body)).into_response()
+}
+
+fn count_status(
+    results: &[AckResult],
+    status: AckStatus,
+) -> usize {
+    results.iter().filter(|r| r.status == status).count()
+}
+
+// Ack messages held under a lease token
+#[utoipa::path(
+    post,
+    path = "/messages/ack",
+    tag = "messages",
+    request_body = AckBody,
+    responses(
+        (status = 200, description = "`{\"acked\": n, \"results\": [{\"id\", \"status\"}]}`", body = Object),
+        (status = 400, description = "More ids than allowed in one batch"),
+        (status = 409, description = "Some messages were not found or their leases were lost; `results` tells which")
+    )
+)]
+#[tracing::instrument(level = "debug", skip_all, fields(ids = ?body.ids))]
+async fn ack_messages(
+    State(db): State<Db>,
+    Json(body): Json<AckBody>,
+) -> Result<Response, (StatusCode, String)> {
+    let ids = queue::resolve_message_ids(&db, &body.ids)
+        .await
+        .map_err(error_response)?;
+    let results = queue::ack_batch(&db, &ids, &body.lease_token)
+        .await
+        .map_err(error_response)?;
+    let acked = count_status(&results, AckStatus::Acked);
+    Ok(batch_response(json!({"acked": acked}), results))
+}
+
+// Nack messages held under a lease token
+#[utoipa::path(
+    post,
+    path = "/messages/nack",
+    tag = "messages",
+    request_body = NackBody,
+    responses(
+        (status = 200, description = "`{\"requeued\": n, \"dead_lettered\": n, \"results\": [{\"id\", \"status\"}]}`", body = Object),
+        (status = 400, description = "A negative delay, or more ids than allowed in one batch"),
+        (status = 409, description = "Some messages were not found or their leases were lost; `results` tells which")
+    )
+)]
+#[tracing::instrument(level = "debug", skip_all, fields(ids = ?body.ids))]
+async fn nack_messages(
+    State(db): State<Db>,
+    Json(body): Json<NackBody>,
+) -> Result<Response, (StatusCode, String)> {
+    let delay_ms = body.delay_ms.unwrap_or(1000);
+    if let Some(d) = body.delays.iter().find(|d| d.delay_ms < 0) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("Invalid delay_ms {} for message {}", d.delay_ms, d.id),
+        ));
+    }
+    let refs: Vec<MessageRef> = body
+        .ids
+        .iter()
+        .chain(body.delays.iter().map(|d| &d.id))
+        .cloned()
+        .collect();
+    let ids =
+        queue::resolve_message_ids(&db, &refs).await.map_err(error_response)?;
+    let delays = std::iter::repeat_n(delay_ms, body.ids.len())
+        .chain(body.delays.iter().map(|d| d.delay_ms));
+    let nacks: Vec<(i64, i64)> = ids.into_iter().zip(delays).collect();
+    let results = queue::nack_batch_with_reason(
+        &db,
+        &nacks,
+        &body.lease_token,
+        body.reason.as_deref(),
+    )
+    .await
+    .map_err(error_response)?;
+    let requeued = count_status(&results, AckStatus::Requeued);
+    let dead = count_status(&results, AckStatus::DeadLettered);
+    Ok(batch_response(
+        json!({"requeued": requeued, "dead_lettered": dead}),
+        results,
+    ))
+}
+
+// Extend the lease on a single message (consumer heartbeat)
+#[utoipa::path(
+    post,
+    path = "/messages/{id}/extend",
+    tag = "messages",
+    params(("id" = MessageRef, Path, description = "Message ID or public id")),
+    request_body = ExtendBody,
+    responses(
+        (status = 200, description = "`{\"extended\": 1}`", body = Object),
+        (status = 409, description = "The lease was lost or expired")
+    )
+)]
+#[tracing::instrument(level = "debug", skip_all, fields(message_id = %id))]
+async fn extend_visibility(
+    Path(id): Path<MessageRef>,
+    State(db): State<Db>,
+    Json(body): Json<ExtendBody>,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let id =
+        queue::resolve_message_id(&db, &id).await.map_err(error_response)?;
+    let extended =
+        queue::extend_visibility(&db, &[id], &body.lease_token, body.extra_ms)
+            .await
+            .map_err(error_response)?;
+    check_lease_outcome(1, extended)?;
+    Ok(Json(json!({"extended": extended})))
+}
+
+// A message's payload as raw bytes of its own media type
+#[utoipa::path(
+    get,
+    path = "/queues/{name}/messages/{id}/payload",
+    tag = "messages",
+    params(
+        ("name" = String, Path, description = "Queue name"),
+        ("id" = MessageRef, Path, description = "Message ID or public id")
+    ),
+    responses(
+        (status = 200, description = "The payload, with the `Content-Type` it was enqueued with (`application/json` for JSON payloads)", body = Vec<u8>, content_type = "application/octet-stream"),
+        (status = 404, description = "Message not found in the queue"),
+        (status = 406, description = "`Accept` excludes the payload's media type")
+    )
+)]
+#[tracing::instrument(level = "debug", skip_all, fields(queue = %name, message_id = %id))]
+async fn message_payload(
+    Path((name, id)): Path<(String, MessageRef)>,
+    State(db): State<Db>,
+    headers: HeaderMap,
+) -> Result<Response, (StatusCode, String)> {
+    let id =
+        queue::resolve_message_id(&db, &id).await.map_err(error_response)?;
+    let q = queue::show_queue(&db, &name).await.map_err(error_response)?;
+    let msg = match queue::get_message_by_id(&db, id).await {
+        Ok(msg) if msg.queue_id == q.id => msg,
+        Ok(_) => return Err(error_response(SqewError::MessageNotFound(id))),
+        Err(e) => return Err(error_response(e)),
+    };
+    let (content_type, bytes) =
+        queue::payload_bytes(&msg).map_err(error_response)?;
+    let accept = headers.get(header::ACCEPT).and_then(|v| v.to_str().ok());
+    if !accept.is_none_or(|accept| accepts(accept, &content_type)) {
+        return Err((
+            StatusCode::NOT_ACCEPTABLE,
+            format!("Message {id} is {content_type}"),
+        ));
+    }
+    Ok(([(header::CONTENT_TYPE, content_type)], bytes).into_response())
+}
+
+// Whether an `Accept` header admits the media type `content_type`
+fn accepts(
+    accept: &str,
+    content_type: &str,
+) -> bool {
+    let essence =
+        |s: &str| s.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+    let content_type = essence(content_type);
+    let main_type = content_type.split('/').next().unwrap_or("");
+    accept.split(',').map(essence).any(|range| {
+        range == "*/*"
+            || range == content_type
+            || range.strip_suffix("/*") == Some(main_type)
+    })
+}
+
+// List a message's delivery attempts and how each ended
+#[utoipa::path(
+    get,
+    path = "/messages/{id}/attempts",
+    tag = "messages",
+    params(("id" = MessageRef, Path, description = "Message ID or public id")),
+    responses(
+        (status = 200, description = "Attempts, oldest first", body = [MessageAttempt]),
+        (status = 404, description = "Message not found and never delivered")
+    )
+)]
+#[tracing::instrument(level = "debug", skip_all, fields(message_id = %id))]
+async fn message_attempts(
+    Path(id): Path<MessageRef>,
+    State(db): State<Db>,
+    Extension(grant): Extension<Grant>,
+) -> Result<Json<Vec<MessageAttempt>>, (StatusCode, String)> {
+    let id =
+        queue::resolve_message_id(&db, &id).await.map_err(error_response)?;
+    // The route names no queue, so check the key against the message's
+    if grant.queue_pattern.is_some() {
+        let queue =
+            queue::message_queue(&db, id).await.map_err(error_response)?;
+        authorize(&grant, Permission::Read, &queue)?;
+    }
+    let attempts =
+        queue::message_attempts(&db, id).await.map_err(error_response)?;
+    Ok(Json(attempts))
+}
+
+// List the messages of a queue leased by plain polls, and who holds them
+#[utoipa::path(
+    get,
+    path = "/queues/{name}/in-flight",
+    tag = "messages",
+    params(("name" = String, Path, description = "Queue name"), PeekParams),
+    responses(
+        (status = 200, description = "Leased messages, soonest lease expiry first", body = [InFlightMessage]),
+        (status = 404, description = "Queue not found")
+    )
+)]
+#[tracing::instrument(level = "debug", skip_all, fields(queue = %name))]
+async fn list_in_flight(
+    Path(name): Path<String>,
+    Query(params): Query<PeekParams>,
+    State(db): State<Db>,
+) -> Result<Json<Vec<InFlightMessage>>, (StatusCode, String)> {
+    let limit = params.limit.unwrap_or(10);
+    let msgs =
+        queue::in_flight(&db, &name, limit).await.map_err(error_response)?;
+    Ok(Json(msgs))
+}
+
+// Snapshot the live database to a file on the server
+#[utoipa::path(
+    post,
+    path = "/admin/backup",
+    tag = "admin",
+    request_body = BackupBody,
+    responses(
+        (status = 201, description = "`{\"path\": \"...\", \"bytes\": n}`", body = Object),
+        (status = 409, description = "The backup file already exists")
+    )
+)]
+#[tracing::instrument(level = "debug", skip_all)]
+async fn backup_database(
+    State(db): State<Db>,
+    Extension(actor): Extension<Actor>,
+    Json(body): Json<BackupBody>,
+) -> Result<(StatusCode, Json<serde_json::Value>), (StatusCode, String)> {
+    let bytes = queue::backup_database(&db, &body.path).await.map_err(|e| {
+        match e {
+            SqewError::BackupExists(_) => error_response(e),
+            // The cause, e.g. an unwritable directory, is what callers need
+            e => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("{:#}", anyhow::Error::from(e)),
+            ),
+        }
+    })?;
+    let backup = json!({"path": body.path, "bytes": bytes});
+    audit::record(&db, &actor, "db.backup", None, backup.clone()).await;
+    Ok((StatusCode::CREATED, Json(backup)))
+}
+
+// Report the database size, free space and the rows held by each queue
+#[utoipa::path(
+    get,
+    path = "/admin/db",
+    tag = "admin",
+    responses(
+        (status = 200, description = "Storage use, whether compacting is recommended and how automatic compaction went", body = DbStatus)
+    )
+)]
+#[tracing::instrument(level = "debug", skip_all)]
+async fn database_status(
+    State(state): State<AppState>
+) -> Result<Json<DbStatus>, (StatusCode, String)> {
+    let mut status =
+        queue::db_status(&state.db).await.map_err(error_response)?;
+    status.auto_compact =
+        state.auto_compact.as_ref().map(|cfg| AutoCompactStatus {
+            window: cfg.window.clone(),
+            mode: cfg.mode.name().into(),
+            min_free_pages: cfg.min_free_pages,
+            in_window: cfg.in_window(db::now_ms()),
+            last_run: state.last_compaction.lock().unwrap().clone(),
+        });
+    Ok(Json(status))
+}
+
+// Report the background jobs and how their last runs went
+#[utoipa::path(
+    get,
+    path = "/admin/tasks",
+    tag = "admin",
+    responses(
+        (status = 200, description = "Background jobs in registration order", body = [tasks::TaskStatus])
+    )
+)]
+#[tracing::instrument(level = "debug", skip_all)]
+async fn list_tasks(
+    State(state): State<AppState>
+) -> Json<Vec<tasks::TaskStatus>> {
+    Json(state.task_registry.statuses())
+}
+
+// Queue an admin job for the background runner
+#[utoipa::path(
+    post,
+    path = "/admin/jobs",
+    tag = "admin",
+    request_body = StartJobBody,
+    responses(
+        (status = 202, description = "Job queued; poll its progress by id", body = AdminJob),
+        (status = 400, description = "Missing queue or path, existing export file or invalid batch size"),
+        (status = 404, description = "Queue not found")
+    )
+)]
+#[tracing::instrument(level = "debug", skip_all)]
+async fn start_admin_job(
+    State(db): State<Db>,
+    Extension(actor): Extension<Actor>,
+    Json(body): Json<StartJobBody>,
+) -> Result<(StatusCode, Json<AdminJob>), (StatusCode, String)> {
+    let job = queue::start_admin_job(
+        &db,
+        body.kind,
+        body.queue.as_deref(),
+        body.path.as_deref(),
+        body.batch_size,
+    )
+    .await
+    .map_err(error_response)?;
+    record_job_start(&db, &actor, &job).await;
+    Ok((StatusCode::ACCEPTED, Json(job)))
+}
+
+// Record the start of an admin job in the audit log as `job.<kind>`
+async fn record_job_start(
+    db: &Db,
+    actor: &Actor,
+    job: &AdminJob,
+) {
+    let action = format!("job.{}", job.kind);
+    let params = json!({
+        "job": job.id,
+        "path": job.path,
+        "batch_size": job.batch_size,
+    });
+    audit::record(db, actor, &action, job.queue.as_deref(), params).await;
+}
+
+// List the latest admin jobs
+#[utoipa::path(
+    get,
+    path = "/admin/jobs",
+    tag = "admin",
+    params(ListJobsParams),
+    responses(
+        (status = 200, description = "Admin jobs, newest first", body = [AdminJob])
+    )
+)]
+async fn list_admin_jobs(
+    Query(params): Query<ListJobsParams>,
+    State(db): State<Db>,
+) -> Result<Json<Vec<AdminJob>>, (StatusCode, String)> {
+    let limit = params.limit.unwrap_or(20);
+    let jobs =
+        queue::list_admin_jobs(&db, limit).await.map_err(error_response)?;
+    Ok(Json(jobs))
+}
+
+// Status and progress of an admin job
+#[utoipa::path(
+    get,
+    path = "/admin/jobs/{id}",
+    tag = "admin",
+    params(("id" = i64, Path, description = "Admin job id")),
+    responses(
+        (status = 200, description = "The job", body = AdminJob),
+        (status = 404, description = "Job not found")
+    )
+)]
+async fn show_admin_job(
+    Path(id): Path<i64>,
+    State(db): State<Db>,
+) -> Result<Json<AdminJob>, (StatusCode, String)> {
+    let job = queue::admin_job(&db, id).await.map_err(error_response)?;
+    Ok(Json(job))
+}
+
+// Cancel a pending or running admin job
+#[utoipa::path(
+    post,
+    path = "/admin/jobs/{id}/cancel",
+    tag = "admin",
+    params(("id" = i64, Path, description = "Admin job id")),
+    responses(
+        (status = 200, description = "The job: `canceled` if it was pending, else still `running` with `cancel_requested` until it stops after its current batch", body = AdminJob),
+        (status = 404, description = "Job not found"),
+        (status = 409, description = "The job already finished")
+    )
+)]
+#[tracing::instrument(level = "debug", skip_all, fields(id))]
+async fn cancel_admin_job(
+    Path(id): Path<i64>,
+    State(db): State<Db>,
+    Extension(actor): Extension<Actor>,
+) -> Result<Json<AdminJob>, (StatusCode, String)> {
+    let job = queue::cancel_admin_job(&db, id).await.map_err(error_response)?;
+    let cancel = json!({"job": job.id, "kind": job.kind});
+    audit::record(&db, &actor, "job.cancel", job.queue.as_deref(), cancel)
+        .await;
+    Ok(Json(job))
+}
@@ -0,0 +1,376 @@
+//! Periodic background jobs of `sqew serve`.
+//!
+//! A [`TaskRegistry`] holds named jobs, each run on its own interval with a
+//! little random jitter so jobs sharing an interval do not fire in lockstep.
+//! Every run executes in its own tokio task: a job that fails or panics is
+//! recorded in its [`TaskStatus`] and simply runs again at its next tick.
+//! The statuses are served at `/admin/tasks`.
+
+use crate::db::{CompactRun, Db};
+use crate::queue;
+use rand::Rng;
+use serde::Serialize;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+use tokio::task::JoinSet;
+use utoipa::ToSchema;
+
+/// How often the server sweeps expired messages by default
+const EXPIRY_SWEEP_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How often the server reaps expired leases by default
+const LEASE_REAP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often the server evaluates alarms by default
+const ALARM_EVAL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How long an alarm webhook may take to answer
+const ALARM_WEBHOOK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How often the server purges archived messages past their retention
+/// by default
+const ARCHIVE_PURGE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How often the server checks for due schedules by default
+const SCHEDULE_TICK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often the server snapshots queue stats into their history by default
+const STATS_SNAPSHOT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How often the server pushes ready messages to push endpoints by default
+const PUSH_TICK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often the server purges queues past their time in the trash by
+/// default
+const TRASH_PURGE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How often the server looks for pending admin jobs by default
+const ADMIN_JOB_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often the server checks whether to compact the database by default
+const AUTO_COMPACT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Largest fraction of its interval a job's run is moved earlier or later
+const JITTER: f64 = 0.1;
+
+/// How often the server's background tasks run
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TaskIntervals {
+    pub expiry_sweep: Duration,
+    pub lease_reap: Duration,
+    pub alarm_eval: Duration,
+    pub archive_purge: Duration,
+    pub schedule_tick: Duration,
+    pub stats_snapshot: Duration,
+    pub push_tick: Duration,
+    pub trash_purge: Duration,
+    pub admin_jobs: Duration,
+    pub auto_compact: Duration,
+}
+
+impl Default for TaskIntervals {
+    fn default() -> Self {
+        TaskIntervals {
+            expiry_sweep: EXPIRY_SWEEP_INTERVAL,
+            lease_reap: LEASE_REAP_INTERVAL,
+            alarm_eval: ALARM_EVAL_INTERVAL,
+            archive_purge: ARCHIVE_PURGE_INTERVAL,
+            schedule_tick: SCHEDULE_TICK_INTERVAL,
+            stats_snapshot: STATS_SNAPSHOT_INTERVAL,
+            push_tick: PUSH_TICK_INTERVAL,
+            trash_purge: TRASH_PURGE_INTERVAL,
+            admin_jobs: ADMIN_JOB_INTERVAL,
+            auto_compact: AUTO_COMPACT_INTERVAL,
+        }
+    }
+}
+
+type JobFuture = Pin<Box<dyn Future<Output = anyhow::Result<()>> + Send>>;
+type JobFn = Arc<dyn Fn() -> JobFuture + Send + Sync>;
+
+/// Last-run report of a background job
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct TaskStatus {
+    pub name: String,
+    pub interval_ms: u64,
+    /// Whether a run is in progress
+    pub running: bool,
+    /// Completed runs, failed ones included
+    pub runs: u64,
+    /// Runs that returned an error or panicked
+    pub failures: u64,
+    /// When the last run started, in milliseconds since the Unix epoch
+    pub last_started_at: Option<i64>,
+    pub last_duration_ms: Option<u64>,
+    /// `ok`, `failed` or `panicked`
+    pub last_outcome: Option<String>,
+    pub last_error: Option<String>,
+}
+
+struct Job {
+    interval: Duration,
+    run: JobFn,
+    status: Mutex<TaskStatus>,
+}
+
+/// Named background jobs and their last-run status
+#[derive(Default)]
+pub struct TaskRegistry {
+    jobs: Mutex<Vec<Arc<Job>>>,
+}
+
+impl TaskRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a job running `run` every `interval`; replaces a job of the same
+    /// name
+    pub fn register<F, Fut>(
+        &self,
+        name: &str,
+        interval: Duration,
+        run: F,
+    ) where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = anyhow::Result<()>> + Send + 'static,
+    {
+        let job = Arc::new(Job {
+            interval,
+            run: Arc::new(move || Box::pin(run())),
+            status: Mutex::new(TaskStatus {
+                name: name.to_string(),
+                interval_ms: interval.as_millis() as u64,
+                running: false,
+                runs: 0,
+                failures: 0,
+                last_started_at: None,
+                last_duration_ms: None,
+                last_outcome: None,
+                last_error: None,
+            }),
+        });
+        let mut jobs = self.jobs.lock().unwrap();
+        jobs.retain(|j| j.status.lock().unwrap().name != name);
+        jobs.push(job);
+    }
+
+    /// Register the server's built-in jobs at the given intervals
+    pub fn register_builtin(
+        &self,
+        db: &Db,
+        every: TaskIntervals,
+    ) {
+        let d = db.clone();
+        self.register("expiry_sweep", every.expiry_sweep, move || {
+            expire_messages(d.clone())
+        });
+        let d = db.clone();
+        self.register("lease_reap", every.lease_reap, move || {
+            reap_leases(d.clone())
+        });
+        match reqwest::Client::builder().timeout(ALARM_WEBHOOK_TIMEOUT).build()
+        {
+            Ok(client) => {
+                let d = db.clone();
+                self.register("alarm_eval", every.alarm_eval, move || {
+                    let (db, client) = (d.clone(), client.clone());
+                    async move {
+                        queue::run_alarms(&db, &client).await?;
+                        Ok(())
+                    }
+                });
+            }
+            Err(e) => tracing::error!("Alarm webhooks disabled: {e}"),
+        }
+        let d = db.clone();
+        self.register("archive_purge", every.archive_purge, move || {
+            purge_archives(d.clone())
+        });
+        let d = db.clone();
+        self.register("schedule_tick", every.schedule_tick, move || {
+            run_schedules(d.clone())
+        });
+        let d = db.clone();
+        self.register("stats_snapshot", every.stats_snapshot, move || {
+            record_stats(d.clone())
+        });
+        // Each push carries its queue's own timeout
+        match reqwest::Client::builder().build() {
+            Ok(client) => {
+                let d = db.clone();
+                self.register("push_delivery", every.push_tick, move || {
+                    let (db, client) = (d.clone(), client.clone());
+                    async move {
+                        queue::run_push(&db, &client).await?;
+                        Ok(())
+                    }
+                });
+            }
+            Err(e) => tracing::error!("Push delivery disabled: {e}"),
+        }
+        let d = db.clone();
+        self.register("trash_purge", every.trash_purge, move || {
+            purge_trash(d.clone())
+        });
+        let d = db.clone();
+        self.register("admin_jobs", every.admin_jobs, move || {
+            run_admin_jobs(d.clone())
+        });
+    }
+
+    /// Status of every job, in registration order
+    pub fn statuses(&self) -> Vec<TaskStatus> {
+        let jobs = self.jobs.lock().unwrap();
+        jobs.iter().map(|j| j.status.lock().unwrap().clone()).collect()
+    }
+
+    /// Run every registered job on `set` until `stop` turns true. Each job
+    /// runs once right away, then about every interval.
+    pub fn spawn(
+        &self,
+        set: &mut JoinSet<()>,
+        stop: &watch::Sender<bool>,
+    ) {
+        for job in self.jobs.lock().unwrap().iter() {
+            set.spawn(run_job(job.clone(), stop.subscribe()));
+        }
+    }
+}
+
+// Run a job until stopped, recording each run's outcome
+async fn run_job(
+    job: Arc<Job>,
+    mut stop: watch::Receiver<bool>,
+) {
+    while !*stop.borrow() {
+        let started = Instant::now();
+        let name = {
+            let mut status = job.status.lock().unwrap();
+            status.running = true;
+            status.last_started_at = Some(now_ms());
+            status.name.clone()
+        };
+        let outcome = tokio::spawn((job.run)()).await;
+        {
+            let mut status = job.status.lock().unwrap();
+            status.running = false;
+            status.runs += 1;
+            status.last_duration_ms =
+                Some(started.elapsed().as_millis() as u64);
+            let (outcome, error) = match outcome {
+                Ok(Ok(())) => ("ok", None),
+                Ok(Err(e)) => {
+                    tracing::warn!("Task {name} failed: {e:#}");
+                    ("failed", Some(format!("{e:#}")))
+                }
+                Err(e) => {
+                    tracing::error!("Task {name} panicked: {e}");
+                    ("panicked", Some(e.to_string()))
+                }
+            };
+            if error.is_some() {
+                status.failures += 1;
+            }
+            status.last_outcome = Some(outcome.to_string());
+            status.last_error = error;
+        }
+        tokio::select! {
+            _ = tokio::time::sleep(jittered(job.interval)) => {}
+            _ = stop.wait_for(|stop| *stop) => break,
+        }
+    }
+}
+
+// `interval` moved by up to a JITTER fraction either way
+fn jittered(interval: Duration) -> Duration {
+    let factor = 1.0 + JITTER * rand::thread_rng().gen_range(-1.0..=1.0);
+    interval.mul_f64(factor)
+}
+
+fn now_ms() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as i64
+}
+
+// Delete messages whose TTL has passed
+async fn expire_messages(db: Db) -> anyhow::Result<()> {
+    let n = queue::expire_messages(&db).await?;
+    if n > 0 {
+        tracing::info!("Expired {} message(s)", n);
+    }
+    Ok(())
+}
+
+// Requeue or dead-letter messages whose lease expired without an ack or nack
+async fn reap_leases(db: Db) -> anyhow::Result<()> {
+    let (requeued, dead) = queue::reap_expired_leases(&db).await?;
+    if requeued + dead > 0 {
+        tracing::info!(
+            "Reaped expired leases: {} requeued, {} dead-lettered",
+            requeued,
+            dead
+        );
+    }
+    Ok(())
+}
+
+// Delete archived messages whose retention has ended
+async fn purge_archives(db: Db) -> anyhow::Result<()> {
+    let n = queue::purge_archives(&db).await?;
+    if n > 0 {
+        tracing::info!("Purged {} archived message(s)", n);
+    }
+    Ok(())
+}
+
+// Delete the queues whose time in the trash is up
+async fn purge_trash(db: Db) -> anyhow::Result<()> {
+    for name in queue::purge_trash(&db).await? {
+        tracing::info!("Purged queue '{}' from the trash", name);
+    }
+    Ok(())
+}
+
+// Run the pending admin jobs, one at a time
+async fn run_admin_jobs(db: Db) -> anyhow::Result<()> {
+    queue::run_admin_jobs(&db).await?;
+    Ok(())
+}
+
+// Compact the database if it is in its window with enough pages free,
+// keeping the run for `/admin/db`
+pub(super) async fn auto_compact(
+    db: Db,
+    cfg: Arc<queue::AutoCompactConfig>,
+    last: Arc<Mutex<Option<CompactRun>>>,
+) -> anyhow::Result<()> {
+    if let Some(run) = queue::auto_compact(&db, &cfg, now_ms()).await? {
+        tracing::info!(
+            "Compacted database ({}): {} free page(s) left of {}",
+            run.mode,
+            run.free_pages_after,
+            run.free_pages_before
+        );
+        *last.lock().unwrap() = Some(run);
+    }
+    Ok(())
+}
+
+// Snapshot every queue's stats into the history
+async fn record_stats(db: Db) -> anyhow::Result<()> {
+    queue::record_stats_history(&db).await?;
+    Ok(())
+}
+
+// Enqueue the payloads of due cron schedules
+async fn run_schedules(db: Db) -> anyhow::Result<()> {
+    for name in queue::run_due_schedules(&db).await? {
+        tracing::info!("Scheduled message enqueued into '{}'", name);
+    }
+    Ok(())
+}
@@ -0,0 +1,86 @@
+//! Versioning of the HTTP API.
+//!
+//! The API is served under `/v1`. A client may also name the version it
+//! was written against in a `sqew-api-version` request header; a server
+//! that no longer (or not yet) speaks that version answers `406` instead of
+//! misreading the request, and every API response carries the version that
+//! handled it. A breaking change, such as a new lease token or error shape,
+//! goes under a new prefix while the old one keeps its behavior.
+//!
+//! The unversioned paths of the first releases still work as aliases of
+//! `/v1`, marked deprecated with `Deprecation`, `Warning` and a `Link` to
+//! the versioned path.
+
+use axum::{
+    extract::Request,
+    http::{HeaderMap, HeaderValue, StatusCode, header},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+
+/// Version of the HTTP API this server speaks
+pub const API_VERSION: u32 = 1;
+
+/// Path prefix of the current API version
+pub const API_PREFIX: &str = "/v1";
+
+/// Header in which clients name the API version they expect, and responses
+/// the version that served them
+pub const API_VERSION_HEADER: &str = "sqew-api-version";
+
+// The version a request asks for in its header, `Err` with the offending
+// text when it is not a number
+fn requested_version(headers: &HeaderMap) -> Result<Option<u32>, String> {
+    let Some(value) = headers.get(API_VERSION_HEADER) else {
+        return Ok(None);
+    };
+    let text = value.to_str().unwrap_or_default().trim();
+    text.parse().map(Some).map_err(|_| text.to_string())
+}
+
+// Refuse requests for an API version other than this one, and name the
+// version on every response
+pub(super) async fn negotiate(
+    req: Request,
+    next: Next,
+) -> Response {
+    let mut resp = match requested_version(req.headers()) {
+        Ok(None) => next.run(req).await,
+        Ok(Some(v)) if v == API_VERSION => next.run(req).await,
+        Ok(Some(v)) => (
+            StatusCode::NOT_ACCEPTABLE,
+            format!(
+                "API version {v} is not supported; this server speaks version {API_VERSION}"
+            ),
+        )
+            .into_response(),
+        Err(text) => (
+            StatusCode::BAD_REQUEST,
+            format!("Invalid {API_VERSION_HEADER} header '{text}'"),
+        )
+            .into_response(),
+    };
+    resp.headers_mut()
+        .insert(API_VERSION_HEADER, HeaderValue::from(API_VERSION));
+    resp
+}
+
+// Mark responses to an unversioned path as deprecated, pointing at its
+// `/v1` successor
+pub(super) async fn deprecated_alias(
+    req: Request,
+    next: Next,
+) -> Response {
+    let successor = format!("{API_PREFIX}{}", req.uri().path());
+    let mut resp = next.run(req).await;
+    let headers = resp.headers_mut();
+    headers.insert("deprecation", HeaderValue::from_static("true"));
+    let warning = format!("299 sqew \"Deprecated API path; use {successor}\"");
+    let link = format!("<{successor}>; rel=\"successor-version\"");
+    for (name, value) in [(header::WARNING, warning), (header::LINK, link)] {
+        if let Ok(value) = HeaderValue::from_str(&value) {
+            headers.insert(name, value);
+        }
+    }
+    resp
+}
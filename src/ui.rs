@@ -0,0 +1,37 @@
+//! The admin UI served at `/ui`: a single page compiled into the binary that
+//! drives the JSON API from the browser. It lists queues with live stats
+//! graphs, peeks, purges, pauses and resumes queues, and redrives dead
+//! letters.
+
+use axum::{
+    Router,
+    http::header,
+    response::{IntoResponse, Redirect},
+    routing::get,
+};
+
+const INDEX_HTML: &str = include_str!("../assets/ui/index.html");
+const APP_JS: &str = include_str!("../assets/ui/app.js");
+const APP_CSS: &str = include_str!("../assets/ui/app.css");
+
+/// Routes serving the UI's static assets; they hold no state of their own
+pub fn routes<S>() -> Router<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    Router::new()
+        .route("/ui", get(|| async { Redirect::permanent("/ui/") }))
+        .route("/ui/", get(|| asset("text/html; charset=utf-8", INDEX_HTML)))
+        .route(
+            "/ui/app.js",
+            get(|| asset("text/javascript; charset=utf-8", APP_JS)),
+        )
+        .route("/ui/app.css", get(|| asset("text/css; charset=utf-8", APP_CSS)))
+}
+
+async fn asset(
+    content_type: &'static str,
+    body: &'static str,
+) -> impl IntoResponse {
+    ([(header::CONTENT_TYPE, content_type)], body)
+}
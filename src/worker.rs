@@ -0,0 +1,109 @@
+use crate::consumer::Consumer;
+use crate::db::Db;
+use crate::models::Message;
+use crate::queue::{self, Config};
+use crate::server;
+use anyhow::{Context, Result};
+use clap::Args;
+use std::process::Stdio;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+
+/// Options for `sqew worker`
+#[derive(Args, Debug, Clone)]
+pub struct WorkerOptions {
+    /// Queue name
+    pub queue: String,
+    /// Shell command run per message (via `sh -c`); the payload is its stdin
+    #[arg(long)]
+    pub command: String,
+    /// Number of messages processed in parallel
+    #[arg(long, default_value_t = 1)]
+    pub concurrency: usize,
+    /// Lease length in milliseconds, renewed while the command is running
+    /// (default: the queue's default visibility)
+    #[arg(long)]
+    pub visibility_ms: Option<i64>,
+    /// Kill (and nack) a command running longer than this many milliseconds
+    #[arg(long)]
+    pub max_runtime: Option<u64>,
+    /// Delay before a failed message becomes visible again
+    #[arg(long, default_value_t = 1000)]
+    pub retry_delay_ms: i64,
+}
+
+/// Run the worker against the configured database until Ctrl+C
+pub async fn run_worker_command(
+    opts: WorkerOptions,
+    cfg: &Config,
+) -> Result<()> {
+    server::init_tracing();
+    let db = queue::init_pool(cfg).await?;
+    tracing::info!(
+        "Worker on '{}' with concurrency {} - Use Ctrl+C to quit.",
+        opts.queue,
+        opts.concurrency
+    );
+    run_worker(&db, &opts, async {
+        let _ = tokio::signal::ctrl_c().await;
+        tracing::info!("Received Ctrl+C, finishing in-flight messages...");
+    })
+    .await
+}
+
+/// Process messages from `opts.queue` until `shutdown` resolves. Messages
+/// already leased when shutdown is requested are run to completion.
+pub async fn run_worker(
+    db: &Db,
+    opts: &WorkerOptions,
+    shutdown: impl Future<Output = ()>,
+) -> Result<()> {
+    let shared = Arc::new(opts.clone());
+    let mut consumer = Consumer::new(db, &opts.queue, move |msg| {
+        let opts = shared.clone();
+        async move { run_command(&opts, &msg).await }
+    })
+    .concurrency(opts.concurrency)
+    .retry_delay_ms(opts.retry_delay_ms);
+    if let Some(ms) = opts.visibility_ms {
+        consumer = consumer.visibility_ms(ms);
+    }
+    if let Some(ms) = opts.max_runtime {
+        consumer = consumer.max_runtime(Duration::from_millis(ms));
+    }
+    consumer.run(shutdown).await
+}
+
+// Spawn the command with the payload on stdin and wait for it to exit
+// successfully. The child is killed if the consumer gives up on it.
+async fn run_command(
+    opts: &WorkerOptions,
+    msg: &Message,
+) -> Result<()> {
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(&opts.command)
+        .env("SQEW_QUEUE", &opts.queue)
+        .env("SQEW_MESSAGE_ID", msg.id.to_string())
+        .env("SQEW_ATTEMPTS", msg.attempts.to_string())
+        .env("SQEW_TRACE_ID", msg.trace_id.as_deref().unwrap_or_default())
+        .stdin(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .with_context(|| format!("Failed to spawn '{}'", opts.command))?;
+    if let Some(mut stdin) = child.stdin.take() {
+        // Write from a task so a command that never drains stdin cannot stall
+        // the lease heartbeat or the runtime limit
+        let payload = msg.payload.clone().into_bytes();
+        tokio::spawn(async move {
+            let _ = stdin.write_all(&payload).await;
+        });
+    }
+    let status = child.wait().await.context("Failed to wait for command")?;
+    if !status.success() {
+        anyhow::bail!("command exited with {status}");
+    }
+    Ok(())
+}
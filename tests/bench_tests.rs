@@ -0,0 +1,78 @@
+use sqew::bench::{BenchOptions, BenchTarget, run_bench};
+use sqew::client::SqewClient;
+use sqew::queue::{self, Config};
+use sqew::server::app_router;
+use tokio::net::TcpListener;
+
+fn test_config(tmp: &tempfile::TempDir) -> Config {
+    Config {
+        db_path: tmp.path().join("bench.db"),
+        force_recreate: true,
+        ..Config::default()
+    }
+}
+
+fn small_run() -> BenchOptions {
+    BenchOptions {
+        queue: "bench".into(),
+        messages: 200,
+        producers: 3,
+        consumers: 2,
+        batch: 16,
+        payload_bytes: 32,
+        visibility_ms: 30_000,
+        server: None,
+        api_key: None,
+        prefill: false,
+    }
+}
+
+#[tokio::test]
+async fn bench_drains_every_message_locally() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let pool = queue::init_pool(&test_config(&dir)).await?;
+    // A leftover message from an earlier run is discarded with the queue
+    let _q = queue::create_queue(&pool, "bench", 5).await?;
+    let _old =
+        queue::enqueue_message(&pool, "bench", &serde_json::json!({}), 0)
+            .await?;
+
+    let report =
+        run_bench(BenchTarget::Local(pool.clone()), &small_run()).await?;
+    assert_eq!(report.messages, 200);
+    assert!(report.throughput_per_sec > 0.0);
+    assert!(report.enqueue_p50_ms <= report.enqueue_p99_ms);
+    assert!(report.end_to_end_p50_ms <= report.end_to_end_p99_ms);
+    assert!(report.poll_p50_ms <= report.poll_p99_ms);
+    assert!(queue::peek_queue(&pool, "bench", 10).await?.is_empty());
+    Ok(())
+}
+
+#[tokio::test]
+async fn bench_prefill_drains_a_backlog() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let pool = queue::init_pool(&test_config(&dir)).await?;
+    let opts = BenchOptions { prefill: true, ..small_run() };
+    let report = run_bench(BenchTarget::Local(pool.clone()), &opts).await?;
+    assert_eq!(report.messages, 200);
+    assert!(report.consume_per_sec >= report.throughput_per_sec);
+    assert!(report.poll_p50_ms <= report.poll_p99_ms);
+    assert!(queue::peek_queue(&pool, "bench", 10).await?.is_empty());
+    Ok(())
+}
+
+#[tokio::test]
+async fn bench_drives_a_remote_server() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let pool = queue::init_pool(&test_config(&dir)).await?;
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    let app = app_router(pool.clone());
+    tokio::spawn(async move { axum::serve(listener, app).await });
+    let client = SqewClient::new(format!("http://{addr}"));
+
+    let report = run_bench(BenchTarget::Remote(client), &small_run()).await?;
+    assert_eq!(report.messages, 200);
+    assert!(queue::peek_queue(&pool, "bench", 10).await?.is_empty());
+    Ok(())
+}
@@ -0,0 +1,612 @@
+use std::path::PathBuf;
+
+use clap::Parser;
+use sqew::cli::{Cli, Commands};
+
+#[test]
+fn db_flag_is_global() {
+    let args = ["sqew", "--db", "/tmp/a.db", "queue", "list"];
+    let cli = Cli::try_parse_from(args).unwrap();
+    assert_eq!(cli.db, Some(PathBuf::from("/tmp/a.db")));
+    assert!(matches!(cli.command, Commands::Queue(_)));
+
+    // Also accepted after the subcommand
+    let cli =
+        Cli::try_parse_from(["sqew", "serve", "--db", "/tmp/b.db"]).unwrap();
+    assert_eq!(cli.db, Some(PathBuf::from("/tmp/b.db")));
+    let cli =
+        Cli::try_parse_from(["sqew", "serve", "--repair-on-start"]).unwrap();
+    assert!(matches!(
+        cli.command,
+        Commands::Serve { repair_on_start: true, .. }
+    ));
+}
+
+#[test]
+fn deliver_at_takes_an_rfc3339_time_with_an_offset() {
+    use sqew::queue::MessageCommands;
+    let enqueue = |at: &str| {
+        let args = ["sqew", "message", "enqueue", "q", "--deliver-at", at];
+        Cli::try_parse_from(args).map(|cli| match cli.command {
+            Commands::Message(MessageCommands::Enqueue {
+                deliver_at, ..
+            }) => deliver_at,
+            _ => None,
+        })
+    };
+    assert_eq!(
+        enqueue("2030-01-01T09:00:00+02:00").unwrap(),
+        Some(1_893_481_200_000)
+    );
+    assert_eq!(
+        enqueue("2030-01-01T07:00:00Z").unwrap(),
+        Some(1_893_481_200_000)
+    );
+    assert!(enqueue("2030-01-01T09:00:00").is_err());
+    assert!(enqueue("monday 9am").is_err());
+    let both = [
+        "sqew",
+        "message",
+        "enqueue",
+        "q",
+        "--delay-ms",
+        "5",
+        "--deliver-at",
+        "2030-01-01T07:00:00Z",
+    ];
+    assert!(Cli::try_parse_from(both).is_err());
+}
+
+#[test]
+fn json_output_is_machine_readable() {
+    let dir = tempfile::tempdir().unwrap();
+    let db = dir.path().join("cli.db");
+    let sqew = |args: &[&str]| -> serde_json::Value {
+        let out = std::process::Command::new(env!("CARGO_BIN_EXE_sqew"))
+            .arg("--db")
+            .arg(&db)
+            .args(["--output", "json"])
+            .args(args)
+            .output()
+            .unwrap();
+        assert!(out.status.success(), "{:?}", out);
+        serde_json::from_slice(&out.stdout).unwrap()
+    };
+
+    let q = sqew(&["queue", "add", "demo"]);
+    assert_eq!(q["name"], "demo");
+    let enq = sqew(&["message", "enqueue", "demo", "--payload", "{\"n\":1}"]);
+    assert_eq!(enq["enqueued"], 1);
+    let polled = sqew(&["message", "poll", "demo"]);
+    assert_eq!(polled[0]["id"], enq["ids"][0]);
+    assert!(polled[0]["lease_token"].is_string());
+    let shown = sqew(&["queue", "show", "demo"]);
+    assert_eq!(shown["stats"]["ready"], 0);
+    assert_eq!(sqew(&["queue", "stats", "demo"])["leased"], 1);
+    let history =
+        sqew(&["queue", "stats", "demo", "--history", "--window", "5m"]);
+    assert_eq!(history.as_array().map(Vec::len), Some(0));
+    assert_eq!(sqew(&["queue", "list"]).as_array().unwrap().len(), 1);
+}
+
+#[test]
+fn auth_grant_list_and_revoke_keys() {
+    let dir = tempfile::tempdir().unwrap();
+    let db = dir.path().join("cli.db");
+    let sqew = |args: &[&str]| {
+        std::process::Command::new(env!("CARGO_BIN_EXE_sqew"))
+            .arg("--db")
+            .arg(&db)
+            .args(["--output", "json"])
+            .args(args)
+            .output()
+            .unwrap()
+    };
+    let json = |out: std::process::Output| -> serde_json::Value {
+        assert!(out.status.success(), "{:?}", out);
+        serde_json::from_slice(&out.stdout).unwrap()
+    };
+
+    let granted = json(sqew(&[
+        "auth", "grant", "shop", "--role", "producer", "--queue", "orders-*",
+    ]));
+    assert_eq!(granted["key"]["role"], "producer");
+    assert!(granted["key"].get("key_hash").is_none());
+    assert!(granted["secret"].as_str().unwrap().starts_with("sqew_"));
+    let mine = "a-key-of-my-own-choosing";
+    let granted = json(sqew(&[
+        "auth",
+        "grant",
+        "ro",
+        "--role",
+        "read-only",
+        "--key",
+        mine,
+    ]));
+    assert_eq!(granted["secret"], mine);
+    assert!(
+        !sqew(&["auth", "grant", "ro", "--role", "admin"]).status.success()
+    );
+    assert!(!sqew(&["auth", "grant", "x", "--role", "owner"]).status.success());
+
+    let keys = json(sqew(&["auth", "list"]));
+    assert_eq!(keys[0]["name"], "ro");
+    assert_eq!(keys[1]["queue_pattern"], "orders-*");
+    assert_eq!(json(sqew(&["auth", "revoke", "shop"]))["revoked"], true);
+    assert!(!sqew(&["auth", "revoke", "shop"]).status.success());
+    assert_eq!(json(sqew(&["auth", "list"])).as_array().unwrap().len(), 1);
+}
+
+#[test]
+fn config_file_supplies_settings_flags_override() {
+    let dir = tempfile::tempdir().unwrap();
+    let file = dir.path().join("sqew.toml");
+    // Relative paths are resolved against the file's directory
+    std::fs::write(
+        &file,
+        "[database]\npath = \"from-file.db\"\nread_pool_size = 2\n\n\
+         [queue_defaults]\nmax_attempts = 7\nretention_days = 3\n",
+    )
+    .unwrap();
+    let sqew = |args: &[&str]| -> serde_json::Value {
+        let out = std::process::Command::new(env!("CARGO_BIN_EXE_sqew"))
+            .arg("--config")
+            .arg(&file)
+            .args(["--output", "json"])
+            .args(args)
+            .env_remove("SQEW_DB_PATH")
+            .output()
+            .unwrap();
+        assert!(out.status.success(), "{:?}", out);
+        serde_json::from_slice(&out.stdout).unwrap()
+    };
+
+    let q = sqew(&["queue", "add", "a"]);
+    assert_eq!(
+        (q["max_attempts"].as_i64(), q["retention_days"].as_i64()),
+        (Some(7), Some(3))
+    );
+    assert!(dir.path().join("from-file.db").exists());
+    let q = sqew(&["queue", "add", "b", "--max-attempts", "2"]);
+    assert_eq!(q["max_attempts"], 2);
+    let other = dir.path().join("flag.db");
+    let q = sqew(&["--db", other.to_str().unwrap(), "queue", "list"]);
+    assert_eq!(q.as_array().unwrap().len(), 0);
+
+    // Unknown keys, zero task intervals and bad compaction windows are
+    // rejected
+    let bad = |text: &str| {
+        std::fs::write(&file, text).unwrap();
+        sqew::config::ConfigFile::load(&file).is_err()
+    };
+    assert!(bad("[server]\nprot = 1\n"));
+    assert!(bad("[tasks]\nlease_reap_ms = 0\n"));
+    assert!(bad("[server]\ncors_origins = [\"dash.example.com\"]\n"));
+    assert!(!bad("[server]\ncors_origins = [\"https://dash.example.com\"]\n"));
+    assert!(!bad("[tasks]\nlease_reap_ms = 250\n"));
+    assert!(bad("[auto_compact]\nwindow = \"nightly\"\n"));
+    assert!(!bad("[auto_compact]\nwindow = \"* 2-4 * * *\"\n"));
+}
+
+#[test]
+fn config_profiles_override_the_base_settings() {
+    let dir = tempfile::tempdir().unwrap();
+    let file = dir.path().join("sqew.toml");
+    std::fs::write(
+        &file,
+        "[database]\npath = \"base.db\"\n\n\
+         [queue_defaults]\nmax_attempts = 7\nretention_days = 3\n\n\
+         [profiles.dev.database]\npath = \"dev.db\"\n\n\
+         [profiles.prod.database]\npath = \"prod.db\"\n\n\
+         [profiles.prod.queue_defaults]\nmax_attempts = 20\n",
+    )
+    .unwrap();
+    let sqew = |profile: Option<&str>, args: &[&str]| {
+        let mut cmd = std::process::Command::new(env!("CARGO_BIN_EXE_sqew"));
+        cmd.arg("--config")
+            .arg(&file)
+            .args(["--output", "json"])
+            .args(args)
+            .env_remove("SQEW_DB_PATH")
+            .env_remove("SQEW_PROFILE");
+        if let Some(profile) = profile {
+            cmd.env("SQEW_PROFILE", profile);
+        }
+        cmd.output().unwrap()
+    };
+    let json = |out: std::process::Output| -> serde_json::Value {
+        assert!(out.status.success(), "{:?}", out);
+        serde_json::from_slice(&out.stdout).unwrap()
+    };
+
+    // A profile's settings override the file's, which fill in the rest
+    let q = json(sqew(None, &["--profile", "prod", "queue", "add", "a"]));
+    assert_eq!(
+        (q["max_attempts"].as_i64(), q["retention_days"].as_i64()),
+        (Some(20), Some(3))
+    );
+    assert!(dir.path().join("prod.db").exists());
+    let q = json(sqew(Some("dev"), &["queue", "add", "b"]));
+    assert_eq!(q["max_attempts"], 7);
+    assert!(dir.path().join("dev.db").exists());
+    let q = json(sqew(None, &["queue", "add", "c"]));
+    assert_eq!(q["max_attempts"], 7);
+    assert!(dir.path().join("base.db").exists());
+    // The flag beats the environment variable
+    let queues =
+        json(sqew(Some("dev"), &["--profile", "prod", "queue", "list"]));
+    assert_eq!(queues.as_array().unwrap().len(), 1);
+    assert_eq!(queues[0]["name"], "a");
+
+    // Unknown profiles, and profiles without a config file, are refused
+    let out = sqew(None, &["--profile", "staging", "queue", "list"]);
+    assert!(!out.status.success());
+    assert!(String::from_utf8_lossy(&out.stderr).contains("dev, prod"));
+    let out = std::process::Command::new(env!("CARGO_BIN_EXE_sqew"))
+        .args(["--profile", "dev", "queue", "list"])
+        .env_remove("SQEW_CONFIG")
+        .output()
+        .unwrap();
+    assert!(!out.status.success());
+    let bad = |text: &str| {
+        std::fs::write(&file, text).unwrap();
+        sqew::config::ConfigFile::load_profile(&file, Some("dev")).is_err()
+    };
+    assert!(bad("[profiles.dev.server]\nprot = 1\n"));
+    assert!(bad("profiles = 1\n"));
+    assert!(!bad("[profiles.dev.server]\nport = 9999\n"));
+}
+
+#[test]
+fn tail_and_watch_follow_a_queue() {
+    let dir = tempfile::tempdir().unwrap();
+    let db = dir.path().join("cli.db");
+    let sqew = |args: &[&str]| -> std::process::Command {
+        let mut cmd = std::process::Command::new(env!("CARGO_BIN_EXE_sqew"));
+        cmd.arg("--db").arg(&db).args(["--output", "json"]).args(args);
+        cmd
+    };
+    let lines = |out: std::process::Output| -> Vec<serde_json::Value> {
+        assert!(out.status.success(), "{:?}", out);
+        String::from_utf8(out.stdout)
+            .unwrap()
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect()
+    };
+    sqew(&["queue", "add", "demo"]).output().unwrap();
+    sqew(&["message", "enqueue", "demo", "--payload", "{\"n\":0}"])
+        .output()
+        .unwrap();
+
+    // Without --ack only messages enqueued after the tail started show up
+    let mut tail = sqew(&["message", "tail", "demo", "--count", "1"])
+        .args(["--interval-ms", "20"])
+        .stdout(std::process::Stdio::piped())
+        .spawn()
+        .unwrap();
+    // Keep enqueueing until the tail, however slow to start, sees one
+    while tail.try_wait().unwrap().is_none() {
+        sqew(&["message", "enqueue", "demo", "--payload", "{\"n\":1}"])
+            .output()
+            .unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(100));
+    }
+    let seen = lines(tail.wait_with_output().unwrap());
+    assert_eq!(seen.len(), 1);
+    assert_eq!(seen[0]["payload"], "{\"n\":1}");
+
+    // --ack consumes what is already waiting
+    let stats = sqew(&["queue", "stats", "demo"]).output().unwrap();
+    let stats: serde_json::Value =
+        serde_json::from_slice(&stats.stdout).unwrap();
+    let ready = stats["ready"].as_u64().unwrap();
+    let count = ready.to_string();
+    let out = sqew(&["message", "tail", "demo", "--ack", "--count", &count])
+        .output()
+        .unwrap();
+    assert_eq!(lines(out).len() as u64, ready);
+
+    let out = sqew(&["queue", "watch", "demo", "--count", "2"])
+        .args(["--interval-ms", "10"])
+        .output()
+        .unwrap();
+    let refreshes = lines(out);
+    assert_eq!(refreshes.len(), 2);
+    assert_eq!(refreshes[1]["ready"], 0);
+    assert_eq!(refreshes[1]["acked"], ready);
+    assert!(refreshes[0]["acked_per_sec"].is_null());
+    assert!(refreshes[1]["acked_per_sec"].is_number());
+}
+
+#[test]
+fn enqueue_streams_ndjson_from_stdin() {
+    use std::io::Write;
+    let dir = tempfile::tempdir().unwrap();
+    let db = dir.path().join("cli.db");
+    let sqew = |args: &[&str]| -> std::process::Command {
+        let mut cmd = std::process::Command::new(env!("CARGO_BIN_EXE_sqew"));
+        cmd.arg("--db").arg(&db).args(["--output", "json"]).args(args);
+        cmd
+    };
+    sqew(&["queue", "add", "feed"]).output().unwrap();
+
+    let mut child = sqew(&["message", "enqueue", "feed", "--stdin"])
+        .args(["--batch-size", "2", "--priority", "3"])
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut input = child.stdin.take().unwrap();
+    for n in 0..5 {
+        writeln!(input, "{{\"n\":{n}}}").unwrap();
+    }
+    writeln!(input).unwrap();
+    drop(input);
+    let out = child.wait_with_output().unwrap();
+    assert!(out.status.success(), "{:?}", out);
+    let v: serde_json::Value = serde_json::from_slice(&out.stdout).unwrap();
+    assert_eq!(v["enqueued"], 5);
+    let out =
+        sqew(&["message", "peek", "feed", "--limit", "10"]).output().unwrap();
+    let v: serde_json::Value = serde_json::from_slice(&out.stdout).unwrap();
+    let msgs = v.as_array().unwrap();
+    assert_eq!(msgs.len(), 5);
+    assert!(msgs.iter().all(|m| m["priority"] == 3));
+
+    // A bad line fails the command, keeping the batches committed before it
+    let mut child = sqew(&["message", "enqueue", "feed", "--stdin"])
+        .args(["--batch-size", "2"])
+        .stdin(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut input = child.stdin.take().unwrap();
+    input.write_all(b"{\"n\":5}\n{\"n\":6}\n{\"n\":7}\nnot json\n").unwrap();
+    drop(input);
+    let out = child.wait_with_output().unwrap();
+    assert!(!out.status.success());
+    let err = String::from_utf8(out.stderr).unwrap();
+    assert!(err.contains("line 4"), "{err}");
+    assert!(err.contains("2 message(s) already enqueued"), "{err}");
+    let out =
+        sqew(&["message", "peek", "feed", "--limit", "10"]).output().unwrap();
+    let v: serde_json::Value = serde_json::from_slice(&out.stdout).unwrap();
+    assert_eq!(v.as_array().unwrap().len(), 7);
+}
+
+#[test]
+fn queue_patterns_purge_remove_and_list_after_confirmation() {
+    use std::io::Write;
+    let dir = tempfile::tempdir().unwrap();
+    let db = dir.path().join("cli.db");
+    let sqew = |args: &[&str]| -> std::process::Command {
+        let mut cmd = std::process::Command::new(env!("CARGO_BIN_EXE_sqew"));
+        cmd.arg("--db").arg(&db).args(["--output", "json"]).args(args);
+        cmd
+    };
+    let json = |cmd: &mut std::process::Command| -> serde_json::Value {
+        let out = cmd.output().unwrap();
+        assert!(out.status.success(), "{:?}", out);
+        serde_json::from_slice(&out.stdout).unwrap()
+    };
+    for name in ["batch-1", "batch-2", "prod-a"] {
+        json(&mut sqew(&["queue", "add", name]));
+        json(&mut sqew(&["message", "enqueue", name, "--payload", "{}"]));
+    }
+    let listed = json(&mut sqew(&["queue", "list", "--filter", "batch-*"]));
+    assert_eq!(listed.as_array().unwrap().len(), 2);
+
+    // Declining the question leaves the queues alone
+    let mut child = sqew(&["queue", "purge", "batch-*"])
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(b"n\n").unwrap();
+    let out = child.wait_with_output().unwrap();
+    assert!(!out.status.success());
+    let err = String::from_utf8(out.stderr).unwrap();
+    assert!(err.contains("Purge 2 queue(s): batch-1, batch-2?"), "{err}");
+
+    let mut child = sqew(&["queue", "purge", "batch-*"])
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(b"y\n").unwrap();
+    let out = child.wait_with_output().unwrap();
+    assert!(out.status.success(), "{:?}", out);
+    let purged: serde_json::Value =
+        serde_json::from_slice(&out.stdout).unwrap();
+    assert_eq!(purged[0]["name"], "batch-1");
+    assert_eq!(purged[1]["purged"], 1);
+
+    let removed = json(&mut sqew(&["queue", "remove", "batch-*", "--yes"]));
+    assert_eq!(removed.as_array().unwrap().len(), 2);
+    let left = json(&mut sqew(&["queue", "list"]));
+    assert_eq!(left[0]["name"], "prod-a");
+    assert_eq!(left.as_array().unwrap().len(), 1);
+    let out = sqew(&["queue", "remove", "tmp-*", "--yes"]).output().unwrap();
+    assert!(!out.status.success());
+}
+
+#[test]
+fn list_peek_and_stats_print_csv_tsv_and_yaml() {
+    let dir = tempfile::tempdir().unwrap();
+    let db = dir.path().join("cli.db");
+    let sqew = |output: &str, args: &[&str]| -> String {
+        let out = std::process::Command::new(env!("CARGO_BIN_EXE_sqew"))
+            .arg("--db")
+            .arg(&db)
+            .args(["--output", output])
+            .args(args)
+            .output()
+            .unwrap();
+        assert!(out.status.success(), "{:?}", out);
+        String::from_utf8(out.stdout).unwrap()
+    };
+    sqew("json", &["queue", "add", "jobs"]);
+    sqew("json", &["queue", "add", "mail"]);
+    sqew(
+        "json",
+        &["message", "enqueue", "jobs", "--payload", "\"a, \\\"b\\\"\""],
+    );
+
+    // A header row, then a row per queue
+    let csv = sqew("csv", &["queue", "list"]);
+    let rows: Vec<Vec<&str>> =
+        csv.lines().map(|l| l.split(',').collect()).collect();
+    assert_eq!(rows.len(), 3);
+    let name = rows[0].iter().position(|c| *c == "name").unwrap();
+    assert_eq!((rows[1][name], rows[2][name]), ("jobs", "mail"));
+    let tsv = sqew("tsv", &["queue", "list"]);
+    assert_eq!(tsv.lines().nth(2).unwrap().split('\t').nth(name), Some("mail"));
+
+    // Fields with separators or quotes are quoted
+    let csv = sqew("csv", &["message", "peek", "jobs"]);
+    assert!(csv.lines().nth(1).unwrap().contains(r#""""a, \""b\"""""#));
+
+    // Single results are one row; YAML keeps the structure
+    let csv = sqew("csv", &["queue", "stats", "jobs"]);
+    let (header, row) = (csv.lines().next().unwrap(), csv.lines().nth(1));
+    let ready = header.split(',').position(|c| c == "ready").unwrap();
+    assert_eq!(row.unwrap().split(',').nth(ready), Some("1"));
+    let yaml = sqew("yaml", &["queue", "stats", "jobs"]);
+    assert!(yaml.lines().any(|l| l == "ready: 1"));
+    let yaml = sqew("yaml", &["queue", "list"]);
+    assert!(yaml.lines().any(|l| l == "- backoff_jitter: 0.0"));
+    assert!(yaml.lines().any(|l| l == "  name: mail"));
+}
+
+#[test]
+fn message_remove_takes_an_id_or_a_queue_filter() {
+    let dir = tempfile::tempdir().unwrap();
+    let db = dir.path().join("cli.db");
+    let sqew = |args: &[&str]| {
+        std::process::Command::new(env!("CARGO_BIN_EXE_sqew"))
+            .arg("--db")
+            .arg(&db)
+            .args(["--output", "json"])
+            .args(args)
+            .output()
+            .unwrap()
+    };
+    sqew(&["queue", "add", "inbox"]);
+    for payload in [r#"{"type":"spam"}"#, r#"{"type":"mail"}"#] {
+        sqew(&["message", "enqueue", "inbox", "--payload", payload]);
+    }
+
+    let out = sqew(&[
+        "message",
+        "remove",
+        "--queue",
+        "inbox",
+        "--filter",
+        r#"$.type == "spam""#,
+        "--older-than",
+        "7d",
+    ]);
+    assert!(out.status.success(), "{:?}", out);
+    let v: serde_json::Value = serde_json::from_slice(&out.stdout).unwrap();
+    assert_eq!(v["removed"], 0);
+    let out = sqew(&[
+        "message",
+        "remove",
+        "--queue",
+        "inbox",
+        "--filter",
+        r#"$.type == "spam""#,
+    ]);
+    let v: serde_json::Value = serde_json::from_slice(&out.stdout).unwrap();
+    assert_eq!(v["removed"], 1);
+
+    // Without a filter or age, or with an id too, nothing is removed
+    assert!(!sqew(&["message", "remove", "--queue", "inbox"]).status.success());
+    assert!(
+        !sqew(&["message", "remove", "1", "--queue", "inbox"]).status.success()
+    );
+    assert!(
+        !sqew(&["message", "remove", "--filter", "$.a=1"]).status.success()
+    );
+    let out = sqew(&["message", "peek", "inbox", "--limit", "10"]);
+    let v: serde_json::Value = serde_json::from_slice(&out.stdout).unwrap();
+    assert_eq!(v[0]["payload"], r#"{"type":"mail"}"#);
+}
+
+#[test]
+fn admin_commands_are_recorded_in_the_audit_log() {
+    let dir = tempfile::tempdir().unwrap();
+    let db = dir.path().join("cli.db");
+    let sqew = |args: &[&str]| -> serde_json::Value {
+        let out = std::process::Command::new(env!("CARGO_BIN_EXE_sqew"))
+            .env("USER", "alice")
+            .arg("--db")
+            .arg(&db)
+            .args(["--output", "json"])
+            .args(args)
+            .output()
+            .unwrap();
+        assert!(out.status.success(), "{:?}", out);
+        serde_json::from_slice(&out.stdout).unwrap()
+    };
+    sqew(&["queue", "add", "inbox"]);
+    sqew(&["queue", "pause", "inbox"]);
+    sqew(&["auth", "grant", "shop", "--role", "producer"]);
+
+    let entries = sqew(&["audit", "list"]);
+    let actions: Vec<_> = entries
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|e| e["action"].as_str().unwrap())
+        .collect();
+    assert_eq!(actions, ["key.grant", "queue.pause", "queue.create"]);
+    assert_eq!(entries[0]["actor"], "cli:alice");
+    assert_eq!(entries[0]["params"]["role"], "producer");
+    let paused = sqew(&["audit", "list", "--queue", "inbox", "--limit", "1"]);
+    assert_eq!(paused[0]["action"], "queue.pause");
+}
+
+#[test]
+fn queue_schema_set_show_and_history() {
+    let dir = tempfile::tempdir().unwrap();
+    let db = dir.path().join("cli.db");
+    let run = |args: &[&str]| {
+        let out = std::process::Command::new(env!("CARGO_BIN_EXE_sqew"))
+            .arg("--db")
+            .arg(&db)
+            .args(args)
+            .output()
+            .unwrap();
+        assert!(out.status.success(), "{:?}", out);
+        String::from_utf8(out.stdout).unwrap()
+    };
+    let schema = dir.path().join("order.json");
+    std::fs::write(&schema, r#"{"type": "object", "required": ["id"]}"#)
+        .unwrap();
+    let file = schema.to_str().unwrap();
+    run(&["queue", "add", "orders"]);
+    let set = run(&["queue", "schema", "set", "orders", "--file", file]);
+    assert!(set.contains("Set schema version 1 on 'orders' (mode: reject)"));
+    let set = run(&[
+        "queue", "schema", "set", "orders", "--file", file, "--mode", "tag",
+    ]);
+    // The same schema keeps its version; only the mode changed
+    assert!(set.contains("version 1 on 'orders' (mode: tag)"), "{set}");
+    let shown = run(&["queue", "schema", "show", "orders"]);
+    assert!(shown.contains(r#""required": ["#), "{shown}");
+
+    run(&["queue", "update", "orders", "--no-payload-schema"]);
+    let history: serde_json::Value = serde_json::from_str(&run(&[
+        "--output", "json", "queue", "schema", "history", "orders",
+    ]))
+    .unwrap();
+    assert_eq!(history[0]["version"], 2);
+    assert!(history[0]["payload_schema"].is_null());
+    let first = run(&["queue", "schema", "show", "orders", "--version", "1"]);
+    assert!(first.contains(r#""id""#), "{first}");
+    let audit = run(&["--output", "json", "audit", "list", "--limit", "2"]);
+    assert!(audit.contains("queue.schema"), "{audit}");
+}
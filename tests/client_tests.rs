@@ -0,0 +1,64 @@
+use serde_json::json;
+use sqew::client::{ClientError, PollRequest, SqewClient};
+use sqew::queue::{self, Config};
+use sqew::server::app_router;
+use tokio::net::TcpListener;
+
+// Serve the router on an ephemeral local port and return a client for it
+async fn spawn_server(tmp: &tempfile::TempDir) -> anyhow::Result<SqewClient> {
+    let cfg = Config {
+        db_path: tmp.path().join("client.db"),
+        force_recreate: true,
+        ..Config::default()
+    };
+    let pool = queue::init_pool(&cfg).await?;
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    tokio::spawn(async move { axum::serve(listener, app_router(pool)).await });
+    Ok(SqewClient::new(format!("http://{addr}")))
+}
+
+#[tokio::test]
+async fn client_round_trip() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let client = spawn_server(&dir).await?;
+
+    let q = client.create_queue("jobs", 3).await?;
+    assert_eq!(q.max_attempts, 3);
+    assert!(matches!(
+        client.create_queue("jobs", 3).await,
+        Err(ClientError::Conflict(_))
+    ));
+    assert!(matches!(
+        client.get_queue("missing").await,
+        Err(ClientError::NotFound(_))
+    ));
+
+    client.enqueue("jobs", &json!({"n": 1})).await?;
+    client.enqueue("jobs", &json!({"n": 2})).await?;
+    assert_eq!(client.peek("jobs", 10).await?.len(), 2);
+
+    let opts = PollRequest { batch: Some(2), ..PollRequest::default() };
+    let msgs = client.poll("jobs", &opts).await?;
+    assert_eq!(msgs.len(), 2);
+    let token = msgs[0].lease_token.clone().unwrap();
+    client.extend(msgs[0].id, &token, 1000).await?;
+    assert_eq!(client.ack(&[msgs[0].id], &token).await?, 1);
+    let out = client.nack(&[msgs[1].id], &token, 0).await?;
+    assert_eq!(out.requeued, 1);
+    assert!(matches!(
+        client.ack(&[msgs[1].id], &token).await,
+        Err(ClientError::Conflict(_))
+    ));
+
+    let raw = vec![0xde, 0xad, 0xbe, 0xef];
+    let m = client.enqueue_bytes("jobs", raw.clone(), "image/png").await?;
+    let (content_type, bytes) = client.payload("jobs", m.id).await?;
+    assert_eq!((content_type.as_str(), bytes), ("image/png", raw));
+
+    let stats = client.stats("jobs").await?;
+    assert_eq!(stats["ready"], 2);
+    client.delete_queue("jobs").await?;
+    assert!(client.list_queues().await?.is_empty());
+    Ok(())
+}
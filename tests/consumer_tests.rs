@@ -0,0 +1,67 @@
+use serde_json::json;
+use sqew::consumer::Consumer;
+use sqew::queue::{self, Config};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+fn test_config(tmp: &tempfile::TempDir) -> Config {
+    Config {
+        db_path: tmp.path().join("consumer.db"),
+        force_recreate: true,
+        ..Config::default()
+    }
+}
+
+#[tokio::test]
+async fn consumer_acks_nacks_and_renews_leases() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let pool = queue::init_pool(&test_config(&dir)).await?;
+    let _q = queue::create_queue(&pool, "jobs", 1).await?;
+    let ok = queue::enqueue_message(&pool, "jobs", &json!("ok"), 0).await?;
+    let bad = queue::enqueue_message(&pool, "jobs", &json!("bad"), 0).await?;
+    let long = queue::enqueue_message(&pool, "jobs", &json!("long"), 0).await?;
+    let hung = queue::enqueue_message(&pool, "jobs", &json!("hung"), 0).await?;
+
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let log = seen.clone();
+    Consumer::new(&pool, "jobs", move |msg| {
+        let log = log.clone();
+        async move {
+            log.lock().unwrap().push(msg.id);
+            match msg.payload.as_str() {
+                "\"bad\"" => anyhow::bail!("bad payload"),
+                // Outlives several leases, kept alive by the heartbeat
+                "\"long\"" => {
+                    tokio::time::sleep(Duration::from_millis(700)).await
+                }
+                "\"hung\"" => std::future::pending().await,
+                _ => {}
+            }
+            Ok(())
+        }
+    })
+    .concurrency(3)
+    .visibility_ms(200)
+    .max_runtime(Duration::from_millis(1000))
+    .retry_delay_ms(0)
+    .poll_interval(Duration::from_millis(20))
+    .run(tokio::time::sleep(Duration::from_millis(1500)))
+    .await?;
+
+    // Each message was handled exactly once
+    let mut seen = seen.lock().unwrap().clone();
+    seen.sort();
+    assert_eq!(seen, vec![ok.id, bad.id, long.id, hung.id]);
+    // Successes are acked; the failure and the timeout are dead-lettered
+    assert!(queue::get_message_by_id(&pool, ok.id).await.is_err());
+    assert!(queue::get_message_by_id(&pool, long.id).await.is_err());
+    let dead = queue::list_dead_letters(&pool, "jobs", 10).await?;
+    let mut dead_ids: Vec<i64> = dead.iter().map(|m| m.id).collect();
+    dead_ids.sort();
+    assert_eq!(dead_ids, vec![bad.id, hung.id]);
+
+    // Unknown queues fail fast
+    let missing = Consumer::new(&pool, "nope", |_| async { Ok(()) });
+    assert!(missing.run(async {}).await.is_err());
+    Ok(())
+}
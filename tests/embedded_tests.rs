@@ -0,0 +1,57 @@
+use serde_json::json;
+use sqew::Sqew;
+use sqew::error::SqewError;
+use sqew::queue::{Config, QueueOptions};
+
+#[tokio::test]
+async fn embedded_round_trip() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let cfg = Config {
+        db_path: dir.path().join("embedded.db"),
+        force_recreate: true,
+        queue_defaults: QueueOptions {
+            default_delay_ms: 0,
+            backoff_base_ms: Some(1000),
+            ..QueueOptions::default()
+        },
+        ..Config::default()
+    };
+    let sqew = Sqew::open(cfg).await?;
+
+    // Queues pick up the config's defaults
+    let q = sqew.create_queue("jobs", 3).await?;
+    assert_eq!((q.max_attempts, q.backoff_base_ms), (3, Some(1000)));
+    assert!(matches!(
+        sqew.create_queue("jobs", 3).await,
+        Err(SqewError::QueueExists(_))
+    ));
+    assert!(matches!(
+        sqew.queue("missing").info().await,
+        Err(SqewError::QueueNotFound(_))
+    ));
+
+    let jobs = sqew.queue("jobs");
+    assert_eq!(jobs.name(), "jobs");
+    let first = jobs.enqueue(&json!({"n": 1})).await?;
+    jobs.enqueue(&json!({"n": 2})).await?;
+    assert_eq!(jobs.peek(10).await?.len(), 2);
+    assert_eq!(sqew.message(first.id).await?.payload, first.payload);
+
+    let leased = jobs.poll(1, 30_000).await?;
+    assert_eq!(leased.len(), 1);
+    let token = leased[0].lease_token.clone().unwrap();
+    assert_eq!(sqew.extend(&[leased[0].id], &token, 1000).await?, 1);
+    assert_eq!(sqew.ack(&[leased[0].id], &token).await?, 1);
+
+    let leased = jobs.poll(1, 30_000).await?;
+    let token = leased[0].lease_token.clone().unwrap();
+    assert_eq!(sqew.nack(&[leased[0].id], &token, 0).await?, (1, 0));
+
+    // Clones share the pool
+    let other = sqew.clone();
+    assert_eq!(other.queue("jobs").peek(10).await?.len(), 1);
+    assert_eq!(sqew.list_queues().await?.len(), 1);
+    assert_eq!(jobs.purge().await?, 1);
+    assert!(jobs.delete().await?);
+    Ok(())
+}
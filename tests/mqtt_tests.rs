@@ -0,0 +1,174 @@
+use rumqttc::{AsyncClient, Event, MqttOptions, Packet, QoS};
+use serde_json::json;
+use sqew::config::ConfigFile;
+use sqew::queue::{self, Config};
+use sqew::server::{AppState, serve_until};
+use std::time::Duration;
+
+fn test_config(tmp: &tempfile::TempDir) -> Config {
+    Config {
+        db_path: tmp.path().join("test.db"),
+        force_recreate: true,
+        ..Config::default()
+    }
+}
+
+// Start an in-process broker on a free port, returning the port
+fn start_broker() -> anyhow::Result<u16> {
+    let port = std::net::TcpListener::bind("127.0.0.1:0")?.local_addr()?.port();
+    let cfg: rumqttd::Config = toml::from_str(&format!(
+        r#"
+        id = 0
+        [router]
+        max_connections = 10
+        max_outgoing_packet_count = 200
+        max_segment_size = 104857600
+        max_segment_count = 10
+        [v4.1]
+        name = "v4-1"
+        listen = "127.0.0.1:{port}"
+        next_connection_delay_ms = 1
+        [v4.1.connections]
+        connection_timeout_ms = 60000
+        max_payload_size = 20480
+        max_inflight_count = 100
+        "#
+    ))?;
+    std::thread::spawn(move || {
+        // Runs until the test process exits
+        let _ = rumqttd::Broker::new(cfg).start();
+    });
+    Ok(port)
+}
+
+async fn wait_for(mut done: impl AsyncFnMut() -> bool) -> bool {
+    for _ in 0..100 {
+        if done().await {
+            return true;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    false
+}
+
+#[tokio::test]
+async fn bridge_enqueues_subscribed_topics_and_publishes_queues()
+-> anyhow::Result<()> {
+    let port = start_broker()?;
+    let dir = tempfile::tempdir()?;
+    let pool = queue::init_pool(&test_config(&dir)).await?;
+    let _q = queue::create_queue(&pool, "alerts", 5).await?;
+
+    let path = dir.path().join("sqew.toml");
+    std::fs::write(
+        &path,
+        format!(
+            r#"
+            [mqtt]
+            host = "127.0.0.1"
+            port = {port}
+            client_id = "sqew-test"
+
+            [[mqtt.subscribe]]
+            topic = "sensors/+/reading"
+            queue = "readings"
+
+            [[mqtt.publish]]
+            queue = "alerts"
+            topic = "out/alerts"
+            "#
+        ),
+    )?;
+    let file = ConfigFile::load(&path)?;
+
+    // A device publishing readings and listening for alerts
+    let (device, mut events) =
+        AsyncClient::new(MqttOptions::new("device", "127.0.0.1", port), 10);
+    device.subscribe("out/alerts", QoS::AtLeastOnce).await?;
+    let (alerts_tx, mut alerts) = tokio::sync::mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        while let Ok(event) = events.poll().await {
+            if let Event::Incoming(Packet::Publish(p)) = event {
+                let _ = alerts_tx.send(p.payload.to_vec());
+            }
+        }
+    });
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let state = AppState::new(pool.clone()).with_mqtt(file.mqtt);
+    let (stop_tx, stop_rx) = tokio::sync::oneshot::channel::<()>();
+    let server = tokio::spawn(serve_until(
+        listener,
+        None,
+        state,
+        Duration::from_secs(5),
+        async {
+            let _ = stop_rx.await;
+        },
+    ));
+
+    // Keep publishing until the bridge has subscribed and enqueued one
+    let enqueued = wait_for(async || {
+        device
+            .publish(
+                "sensors/a/reading",
+                QoS::AtLeastOnce,
+                false,
+                r#"{"t":21}"#,
+            )
+            .await
+            .unwrap();
+        queue::peek_queue(&pool, "readings", 1)
+            .await
+            .is_ok_and(|m| !m.is_empty())
+    })
+    .await;
+    assert!(enqueued);
+    let msg = &queue::peek_queue(&pool, "readings", 1).await?[0];
+    assert_eq!(msg.payload, json!({"t": 21}).to_string());
+    let topic = msg.headers.as_ref().and_then(|h| h.get("mqtt_topic"));
+    assert_eq!(topic.map(String::as_str), Some("sensors/a/reading"));
+
+    // Messages of a published queue go out on its topic and are acked
+    queue::enqueue_message(&pool, "alerts", &json!({"level": "high"}), 0)
+        .await?;
+    queue::enqueue_message(&pool, "alerts", &json!("plain text"), 0).await?;
+    let mut received = Vec::new();
+    while received.len() < 2 {
+        let payload =
+            tokio::time::timeout(Duration::from_secs(5), alerts.recv()).await?;
+        received.push(String::from_utf8(payload.unwrap())?);
+    }
+    assert_eq!(received, [r#"{"level":"high"}"#, "plain text"]);
+    let drained = wait_for(async || {
+        queue::stats(&pool, "alerts").await.unwrap()["acked"] == 2
+    })
+    .await;
+    assert!(drained);
+
+    let _ = stop_tx.send(());
+    server.await??;
+    Ok(())
+}
+
+#[test]
+fn mqtt_config_is_validated() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("sqew.toml");
+    std::fs::write(&path, "[mqtt]\nhost = \"broker\"\n")?;
+    assert!(ConfigFile::load(&path).is_err());
+    std::fs::write(
+        &path,
+        "[mqtt]\nhost = \"broker\"\n[[mqtt.publish]]\nqueue = \"q\"\ntopic = \"t\"\nqos = 3\n",
+    )?;
+    let err = ConfigFile::load(&path).unwrap_err();
+    assert!(err.to_string().contains("qos 3"), "{err}");
+    std::fs::write(
+        &path,
+        "[mqtt]\nhost = \"broker\"\n[[mqtt.subscribe]]\ntopic = \"a/#\"\nqueue = \"q\"\n",
+    )?;
+    let mqtt = ConfigFile::load(&path)?.mqtt.unwrap();
+    assert_eq!((mqtt.port, mqtt.client_id.as_str()), (1883, "sqew"));
+    assert_eq!(mqtt.subscribe[0].qos, 1);
+    Ok(())
+}
@@ -0,0 +1,595 @@
+use serde_json::json;
+use sqew::audit::{self, Actor};
+use sqew::auth::{self, Role};
+use sqew::db::{AuditFilter, Keyring, MessageState, PeekFilter};
+use sqew::models::{MessageRef, PushDelivery};
+use sqew::queue::{
+    AckStatus, Config, EnqueueOptions, QueueOptions, QueueOrdering,
+    QueueUpdate, SCHEMA_VERSION_HEADER, SchemaMode, ack_batch, ack_messages,
+    add_alarm, add_schedule, create_consumer_group, create_queue,
+    create_queue_with, db_status, delete_queue, doctor, enqueue_message,
+    enqueue_message_with, evaluate_alarms, expire_leases, expire_messages,
+    export_queue, extend_visibility, get_message_by_id, import_queue,
+    in_flight, init_pool, list_alarms, list_dead_letters, list_queues,
+    list_queues_matching, message_attempts, message_history, move_messages,
+    nack_messages, nack_messages_with_delays, nack_messages_with_reason,
+    peek_queue, peek_queue_filtered, peek_queue_with, poll_group_messages,
+    poll_messages, purge_archives, purge_queue, push_config, push_deliveries,
+    queue_schema, queue_schema_history, record_stats_history, recovery_scan,
+    redrive_dead_letters, remove_filter, remove_messages_matching,
+    remove_push_config, replay_messages, resolve_message_ids,
+    run_due_schedules, sample_messages, search_messages, set_paused,
+    set_push_config, set_queue_schema, stats, stats_history, update_queue,
+};
+
+// Runs only when SQEW_TEST_DATABASE_URL points at a scratch Postgres database;
+// its tables are dropped and recreated.
+fn test_config() -> Option<Config> {
+    let url = std::env::var("SQEW_TEST_DATABASE_URL").ok()?;
+    Some(Config {
+        database_url: Some(url),
+        force_recreate: true,
+        pool_size: 4,
+        ..Config::default()
+    })
+}
+
+#[tokio::test]
+async fn postgres_backend_round_trip() -> anyhow::Result<()> {
+    let Some(cfg) = test_config() else {
+        eprintln!("SQEW_TEST_DATABASE_URL not set; skipping");
+        return Ok(());
+    };
+    let pool = init_pool(&cfg).await?;
+    assert_eq!(pool.schema_version().await?, 27);
+    let keys = Keyring::parse(&"ab".repeat(32))?;
+    let encrypted = Config {
+        encryption_keys: Some(std::sync::Arc::new(keys)),
+        force_recreate: false,
+        ..cfg.clone()
+    };
+    assert!(init_pool(&encrypted).await.is_err());
+    let _q = create_queue(&pool, "pg", 2).await?;
+    assert!(create_queue(&pool, "pg", 2).await.is_err());
+    assert_eq!(list_queues(&pool).await?.len(), 1);
+
+    // Priority ordering, leasing, extend and ack
+    let low = enqueue_message(&pool, "pg", &json!({"n":1}), 0).await?;
+    let urgent = EnqueueOptions { priority: 5, ..EnqueueOptions::default() };
+    let high =
+        enqueue_message_with(&pool, "pg", &json!({"n":2}), &urgent).await?;
+    assert_eq!(peek_queue(&pool, "pg", 10).await?[0].id, high.id);
+    let leased = poll_messages(&pool, "pg", 10, 5000).await?;
+    let ids: Vec<i64> = leased.iter().map(|m| m.id).collect();
+    assert_eq!(ids, vec![high.id, low.id]);
+    let token = leased[0].lease_token.clone().unwrap();
+    assert!(poll_messages(&pool, "pg", 10, 5000).await?.is_empty());
+    assert_eq!(extend_visibility(&pool, &ids, &token, 1000).await?, 2);
+    assert_eq!(ack_messages(&pool, &[high.id], "bogus").await?, 0);
+    let results = ack_batch(&pool, &[high.id, high.id + 1000], &token).await?;
+    assert_eq!(results[0].status, AckStatus::Acked);
+    assert_eq!(results[1].status, AckStatus::NotFound);
+    assert_eq!(ack_messages(&pool, &[high.id], &token).await?, 0);
+
+    // Nack twice dead-letters (max_attempts = 2), then redrive
+    assert_eq!(nack_messages(&pool, &[low.id], &token, 0).await?, (1, 0));
+    let token = poll_messages(&pool, "pg", 1, 5000).await?[0]
+        .lease_token
+        .clone()
+        .unwrap();
+    let nacks = [(low.id, 0)];
+    let reason = Some("boom");
+    let nacked = nack_messages_with_reason(&pool, &nacks, &token, reason);
+    assert_eq!(nacked.await?, (0, 1));
+    let dead = list_dead_letters(&pool, "pg", 10).await?;
+    assert_eq!(dead.len(), 1);
+    assert_eq!(dead[0].last_error.as_deref(), Some("boom"));
+    assert_eq!(redrive_dead_letters(&pool, "pg", &[]).await?, 1);
+    assert_eq!(purge_queue(&pool, "pg").await?, 1);
+
+    // Dedup keys and message groups
+    let keyed = EnqueueOptions {
+        dedup_key: Some("k".into()),
+        group_id: Some("g".into()),
+        ..EnqueueOptions::default()
+    };
+    let first =
+        enqueue_message_with(&pool, "pg", &json!({"d":1}), &keyed).await?;
+    let dup =
+        enqueue_message_with(&pool, "pg", &json!({"d":2}), &keyed).await?;
+    assert_eq!(dup.id, first.id);
+    let grouped = EnqueueOptions { dedup_key: None, ..keyed };
+    let _second =
+        enqueue_message_with(&pool, "pg", &json!({"d":3}), &grouped).await?;
+    let leased = poll_messages(&pool, "pg", 10, 5000).await?;
+    assert_eq!(leased.len(), 1);
+    assert_eq!(leased[0].id, first.id);
+    assert_eq!(purge_queue(&pool, "pg").await?, 2);
+
+    // TTL expiry is counted in stats
+    let short = EnqueueOptions { ttl_ms: Some(1), ..EnqueueOptions::default() };
+    let _ = enqueue_message_with(&pool, "pg", &json!({}), &short).await?;
+    tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    assert_eq!(expire_messages(&pool).await?, 1);
+    let s = stats(&pool, "pg").await?;
+    assert_eq!(s["expired"], 1);
+    assert_eq!(
+        (s["enqueued"].as_i64(), s["acked"].as_i64()),
+        (Some(5), Some(1))
+    );
+
+    // Schedules fire once when due
+    let every =
+        add_schedule(&pool, "pg", "* * * * * *", &json!({"s":1})).await?;
+    let wait = every.next_run_at - every.created_at + 50;
+    tokio::time::sleep(std::time::Duration::from_millis(wait as u64)).await;
+    assert_eq!(run_due_schedules(&pool).await?, vec!["pg".to_string()]);
+    assert_eq!(peek_queue(&pool, "pg", 10).await?.len(), 1);
+
+    // Acked messages of a queue with retention are archived
+    let keep =
+        QueueOptions { retention_days: Some(0), ..QueueOptions::default() };
+    let _kept = create_queue_with(&pool, "pg-archive", &keep).await?;
+    let m = enqueue_message(&pool, "pg-archive", &json!({"a":1}), 0).await?;
+    let token = poll_messages(&pool, "pg-archive", 1, 5000).await?[0]
+        .lease_token
+        .clone()
+        .unwrap();
+    assert_eq!(ack_messages(&pool, &[m.id], &token).await?, 1);
+    let history = message_history(&pool, "pg-archive", 10).await?;
+    assert_eq!(history[0].message_id, m.id);
+    let n =
+        replay_messages(&pool, "pg-archive", None, None, Some("\"a\"")).await?;
+    assert_eq!(n, 1);
+    let replayed = poll_messages(&pool, "pg-archive", 10, 5000).await?;
+    assert_eq!(replayed.len(), 1);
+    assert_eq!(replayed[0].payload, json!({"a":1}).to_string());
+    assert_eq!(purge_archives(&pool).await?, 1);
+    assert!(record_stats_history(&pool).await? >= 1);
+    let samples = stats_history(&pool, "pg-archive", 60_000).await?;
+    assert_eq!((samples[0].ready, samples[0].enqueued), (0, 2));
+
+    // Push configs are replaced in place and deliveries logged
+    let first =
+        set_push_config(&pool, "pg-archive", "http://a/", 1, 1000, 0).await?;
+    let second =
+        set_push_config(&pool, "pg-archive", "http://b/", 3, 500, 10).await?;
+    assert_eq!((second.created_at, second.concurrency), (first.created_at, 3));
+    assert_eq!(
+        push_config(&pool, "pg-archive").await?.unwrap().url,
+        "http://b/"
+    );
+    let logged = PushDelivery {
+        id: 0,
+        queue_id: second.queue_id,
+        message_id: 1,
+        attempt: 1,
+        status_code: Some(503),
+        error: Some("HTTP 503".into()),
+        outcome: "requeued".into(),
+        duration_ms: 3,
+        delivered_at: 0,
+    };
+    pool.record_push_deliveries(&[logged.clone(), logged]).await?;
+    assert_eq!(push_deliveries(&pool, "pg-archive", 1).await?.len(), 1);
+    assert_eq!(pool.purge_push_deliveries(1).await?, 2);
+    assert!(remove_push_config(&pool, "pg-archive").await?);
+
+    // Headers are stored and filterable
+    let tagged = EnqueueOptions {
+        headers: Some([("kind".to_string(), "a".to_string())].into()),
+        ..EnqueueOptions::default()
+    };
+    let h = enqueue_message_with(&pool, "pg", &json!({"h":1}), &tagged).await?;
+    let found = peek_queue_with(&pool, "pg", 10, Some(("kind", "a"))).await?;
+    assert_eq!(found.len(), 1);
+    assert_eq!(found[0].id, h.id);
+    assert_eq!(found[0].headers, tagged.headers);
+    assert!(
+        peek_queue_with(&pool, "pg", 10, Some(("kind", "b"))).await?.is_empty()
+    );
+    let by_path = PeekFilter {
+        json_path: Some(("$.h".into(), "1".into())),
+        payload_contains: Some("\"h\"".into()),
+        after_id: Some(h.id - 1),
+        ..PeekFilter::default()
+    };
+    let found = peek_queue_filtered(&pool, "pg", 10, &by_path).await?;
+    assert_eq!(found.len(), 1);
+    assert_eq!(found[0].id, h.id);
+    let ready = PeekFilter { state: MessageState::Ready, ..by_path.clone() };
+    assert_eq!(peek_queue_filtered(&pool, "pg", 10, &ready).await?.len(), 1);
+    let leased = PeekFilter { state: MessageState::Leased, ..by_path };
+    assert!(peek_queue_filtered(&pool, "pg", 10, &leased).await?.is_empty());
+    let refs = [MessageRef::Public(h.public_id.clone())];
+    assert_eq!(resolve_message_ids(&pool, &refs).await?, vec![h.id]);
+    let found =
+        search_messages(&pool, "pg", "$.h", Some("1"), None, 10).await?;
+    assert_eq!(found.len(), 1);
+    assert_eq!(found[0].id, h.id);
+    let found =
+        search_messages(&pool, "pg", "$.h", None, Some(h.id), 10).await?;
+    assert!(found.is_empty());
+    let err = search_messages(&pool, "pg", "$.[", None, None, 10).await;
+    assert!(err.unwrap_err().to_string().starts_with("Invalid JSON path"));
+
+    // Bulk removal by JSON path predicate and age
+    let _r = create_queue(&pool, "pg-remove", 5).await?;
+    for kind in ["spam", "mail", "spam"] {
+        enqueue_message(&pool, "pg-remove", &json!({"type": kind}), 0).await?;
+    }
+    let spam = remove_filter(Some(r#"$.type == "spam""#), Some("1h"), 0)?;
+    let n = remove_messages_matching(&pool, "pg-remove", &spam, 1, |_| {});
+    assert_eq!(n.await?, 0);
+    let spam = remove_filter(Some(r#"$.type == "spam""#), None, 0)?;
+    let n = remove_messages_matching(&pool, "pg-remove", &spam, 1, |_| {});
+    assert_eq!(n.await?, 2);
+    assert_eq!(peek_queue(&pool, "pg-remove", 10).await?.len(), 1);
+
+    // Audit log entries keep their parameters and list newest first
+    let ops = Actor::key("ops");
+    audit::record(
+        &pool,
+        &ops,
+        "queue.purge",
+        Some("pg-remove"),
+        json!({"n": 2}),
+    )
+    .await;
+    audit::record(&pool, &ops, "key.revoke", None, json!(null)).await;
+    let purges = AuditFilter {
+        queue: Some("pg-remove".into()),
+        ..AuditFilter::default()
+    };
+    let entries = audit::list_entries(&pool, &purges, 10).await?;
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].params, Some(json!({"n": 2})));
+    let all = audit::list_entries(&pool, &AuditFilter::default(), 10).await?;
+    assert_eq!((all[0].action.as_str(), &all[0].params), ("key.revoke", &None));
+
+    // Backoff replaces the requested nack delay
+    let backoff = QueueOptions {
+        backoff_base_ms: Some(60_000),
+        ..QueueOptions::default()
+    };
+    let _b = create_queue_with(&pool, "pg-backoff", &backoff).await?;
+    let m = enqueue_message(&pool, "pg-backoff", &json!({"b":1}), 0).await?;
+    let token = poll_messages(&pool, "pg-backoff", 1, 5000).await?[0]
+        .lease_token
+        .clone()
+        .unwrap();
+    assert_eq!(nack_messages(&pool, &[m.id], &token, 0).await?, (1, 0));
+    assert!(poll_messages(&pool, "pg-backoff", 1, 5000).await?.is_empty());
+
+    // Nacks with per-message delays
+    let _d = create_queue(&pool, "pg-delays", 5).await?;
+    let a = enqueue_message(&pool, "pg-delays", &json!({"d":1}), 0).await?;
+    let b = enqueue_message(&pool, "pg-delays", &json!({"d":2}), 0).await?;
+    let token = poll_messages(&pool, "pg-delays", 2, 5000).await?[0]
+        .lease_token
+        .clone()
+        .unwrap();
+    let nacks = [(a.id, 60_000), (b.id, 0)];
+    assert_eq!(nack_messages_with_delays(&pool, &nacks, &token).await?, (2, 0));
+    let ready = poll_messages(&pool, "pg-delays", 2, 5000).await?;
+    assert_eq!(ready.iter().map(|m| m.id).collect::<Vec<_>>(), [b.id]);
+
+    // Cloning copies settings and live messages: the leased one becomes
+    // visible, the delayed one stays delayed
+    let (copy, copied) =
+        sqew::queue::clone_queue(&pool, "pg-delays", "pg-clone", true).await?;
+    assert_eq!((copy.max_attempts, copied), (5, 2));
+    let polled = poll_messages(&pool, "pg-clone", 5, 5000).await?;
+    assert_eq!(polled.len(), 1);
+    assert_eq!(polled[0].payload, b.payload);
+
+    // Strict FIFO queues lease only their head
+    let strict = QueueOptions { strict_fifo: true, ..QueueOptions::default() };
+    let _f = create_queue_with(&pool, "pg-fifo", &strict).await?;
+    let f1 = enqueue_message(&pool, "pg-fifo", &json!({"f":1}), 0).await?;
+    let _f2 = enqueue_message(&pool, "pg-fifo", &json!({"f":2}), 0).await?;
+    let leased = poll_messages(&pool, "pg-fifo", 5, 5000).await?;
+    assert_eq!(leased.iter().map(|m| m.id).collect::<Vec<_>>(), [f1.id]);
+    assert!(poll_messages(&pool, "pg-fifo", 5, 5000).await?.is_empty());
+
+    // Messages whose leases keep expiring are quarantined
+    let crashy = QueueOptions {
+        max_lease_expirations: Some(1),
+        ..QueueOptions::default()
+    };
+    let _q = create_queue_with(&pool, "pg-crashy", &crashy).await?;
+    let c = enqueue_message(&pool, "pg-crashy", &json!({"c":1}), 0).await?;
+    let _leased = poll_messages(&pool, "pg-crashy", 1, 20).await?;
+    tokio::time::sleep(std::time::Duration::from_millis(40)).await;
+    assert!(poll_messages(&pool, "pg-crashy", 1, 20).await?.is_empty());
+    let dead = sqew::queue::list_dead_letters(&pool, "pg-crashy", 5).await?;
+    assert_eq!((dead[0].id, dead[0].lease_expirations), (c.id, 1));
+
+    // Soft-deleted queues stay in the trash until restored or purged
+    assert!(sqew::queue::trash_queue(&pool, "pg-crashy").await?);
+    assert!(list_queues(&pool).await?.iter().all(|q| q.name != "pg-crashy"));
+    assert!(poll_messages(&pool, "pg-crashy", 1, 20).await?.is_empty());
+    let trash = sqew::queue::list_trash(&pool).await?;
+    assert_eq!(trash[0].name, "pg-crashy");
+    assert!(sqew::queue::purge_trash(&pool).await?.is_empty());
+    let q = sqew::queue::restore_queue(&pool, "pg-crashy").await?;
+    assert_eq!(q.deleted_at, None);
+
+    // Admin jobs run in the background, one at a time
+    use sqew::queue::AdminJobKind;
+    let redrive = sqew::queue::start_admin_job(
+        &pool,
+        AdminJobKind::Redrive,
+        Some("pg-crashy"),
+        None,
+        None,
+    )
+    .await?;
+    let compact = sqew::queue::start_admin_job(
+        &pool,
+        AdminJobKind::Compact,
+        None,
+        None,
+        None,
+    )
+    .await?;
+    let canceled = sqew::queue::cancel_admin_job(&pool, compact.id).await?;
+    assert_eq!(canceled.status, "canceled");
+    assert_eq!(sqew::queue::run_admin_jobs(&pool).await?, 1);
+    let job = sqew::queue::admin_job(&pool, redrive.id).await?;
+    assert_eq!((job.status.as_str(), job.progress), ("done", 1));
+
+    // Fair queues take turns between fair keys
+    let fair = QueueOptions { fair: true, ..QueueOptions::default() };
+    let _q = create_queue_with(&pool, "pg-fair", &fair).await?;
+    let keyed = |key: &str| EnqueueOptions {
+        fair_key: Some(key.to_string()),
+        ..EnqueueOptions::default()
+    };
+    let a1 =
+        enqueue_message_with(&pool, "pg-fair", &json!({"a":1}), &keyed("a"))
+            .await?;
+    let a2 =
+        enqueue_message_with(&pool, "pg-fair", &json!({"a":2}), &keyed("a"))
+            .await?;
+    let b1 =
+        enqueue_message_with(&pool, "pg-fair", &json!({"b":1}), &keyed("b"))
+            .await?;
+    let leased = poll_messages(&pool, "pg-fair", 5, 5000).await?;
+    let ids: Vec<i64> = leased.iter().map(|m| m.id).collect();
+    assert_eq!(ids, [a1.id, b1.id, a2.id]);
+
+    // Sort key queues take lower keys first, messages without one last
+    let sorted = QueueOptions {
+        ordering: QueueOrdering::SortKey,
+        ..QueueOptions::default()
+    };
+    let _q = create_queue_with(&pool, "pg-sorted", &sorted).await?;
+    let keyed = |key: Option<f64>| EnqueueOptions {
+        sort_key: key,
+        ..EnqueueOptions::default()
+    };
+    let none =
+        enqueue_message_with(&pool, "pg-sorted", &json!({}), &keyed(None))
+            .await?;
+    let late =
+        enqueue_message_with(&pool, "pg-sorted", &json!({}), &keyed(Some(2.0)))
+            .await?;
+    let early =
+        enqueue_message_with(&pool, "pg-sorted", &json!({}), &keyed(Some(1.0)))
+            .await?;
+    let peeked = peek_queue(&pool, "pg-sorted", 10).await?;
+    let ids: Vec<i64> = peeked.iter().map(|m| m.id).collect();
+    assert_eq!(ids, [early.id, late.id, none.id]);
+    let leased = poll_messages(&pool, "pg-sorted", 2, 5000).await?;
+    let ids: Vec<i64> = leased.iter().map(|m| m.id).collect();
+    assert_eq!(ids, [early.id, late.id]);
+
+    // Queue patterns: `*` is the only wildcard, matched case-sensitively
+    let _q = create_queue(&pool, "pg_x", 1).await?;
+    let matched: Vec<String> = list_queues_matching(&pool, "pg-s*")
+        .await?
+        .into_iter()
+        .map(|q| q.name)
+        .collect();
+    assert_eq!(matched, ["pg-sorted"]);
+    assert_eq!(list_queues_matching(&pool, "pg_*").await?.len(), 1);
+    assert!(list_queues_matching(&pool, "PG*").await?.is_empty());
+
+    // Acks and dead letters send receipts to the message's reply queue
+    let _q = create_queue(&pool, "pg-jobs", 1).await?;
+    let _q = create_queue(&pool, "pg-replies", 5).await?;
+    let reply = EnqueueOptions {
+        reply_to: Some("pg-replies".into()),
+        ..EnqueueOptions::default()
+    };
+    let acked =
+        enqueue_message_with(&pool, "pg-jobs", &json!({}), &reply).await?;
+    let token = poll_messages(&pool, "pg-jobs", 1, 5000).await?[0]
+        .lease_token
+        .clone()
+        .unwrap();
+    assert_eq!(ack_messages(&pool, &[acked.id], &token).await?, 1);
+    let dead =
+        enqueue_message_with(&pool, "pg-jobs", &json!({}), &reply).await?;
+    let token = poll_messages(&pool, "pg-jobs", 1, 5000).await?[0]
+        .lease_token
+        .clone()
+        .unwrap();
+    assert_eq!(nack_messages(&pool, &[dead.id], &token, 0).await?, (0, 1));
+    let receipts: Vec<serde_json::Value> = peek_queue(&pool, "pg-replies", 10)
+        .await?
+        .iter()
+        .map(|m| serde_json::from_str(&m.payload))
+        .collect::<Result<_, _>>()?;
+    assert_eq!(receipts.len(), 2);
+    assert_eq!(receipts[0]["message_id"], acked.id);
+    assert_eq!(receipts[0]["outcome"], "acked");
+    assert_eq!(receipts[0]["attempts"], 1);
+    assert_eq!(receipts[1]["message_id"], dead.id);
+    assert_eq!(receipts[1]["outcome"], "dead");
+    assert_eq!(receipts[1]["queue"], "pg-jobs");
+
+    // Enqueues without a delay use the queue's default delay
+    let delayed =
+        QueueOptions { default_delay_ms: 60_000, ..QueueOptions::default() };
+    let q = create_queue_with(&pool, "pg-delayed", &delayed).await?;
+    assert_eq!(q.default_delay_ms, 60_000);
+    let _d = enqueue_message_with(
+        &pool,
+        "pg-delayed",
+        &json!({}),
+        &EnqueueOptions::default(),
+    )
+    .await?;
+    assert!(poll_messages(&pool, "pg-delayed", 1, 5000).await?.is_empty());
+
+    // Settings can be changed in place
+    let update = QueueUpdate {
+        max_attempts: Some(7),
+        default_delay_ms: Some(0),
+        ..QueueUpdate::default()
+    };
+    let q = update_queue(&pool, "pg-delayed", &update).await?;
+    assert_eq!((q.max_attempts, q.default_delay_ms), (7, 0));
+
+    // Polls are capped by the delivery rate limit
+    let limited = QueueOptions {
+        max_deliveries_per_second: Some(2.0),
+        ..QueueOptions::default()
+    };
+    let _r = create_queue_with(&pool, "pg-rate", &limited).await?;
+    for n in 0..3 {
+        let _m =
+            enqueue_message(&pool, "pg-rate", &json!({ "n": n }), 0).await?;
+    }
+    assert_eq!(poll_messages(&pool, "pg-rate", 10, 5000).await?.len(), 2);
+    assert!(poll_messages(&pool, "pg-rate", 10, 5000).await?.is_empty());
+
+    // Paused queues lease nothing until resumed
+    let _h = create_queue(&pool, "pg-paused", 5).await?;
+    let _m = enqueue_message(&pool, "pg-paused", &json!({"p": 1}), 0).await?;
+    assert!(set_paused(&pool, "pg-paused", true).await?.paused);
+    assert!(poll_messages(&pool, "pg-paused", 10, 5000).await?.is_empty());
+    assert!(!set_paused(&pool, "pg-paused", false).await?.paused);
+    assert_eq!(poll_messages(&pool, "pg-paused", 10, 5000).await?.len(), 1);
+
+    // Payload limits and schemas are stored and enforced
+    let strict = QueueOptions {
+        max_payload_bytes: Some(64),
+        payload_schema: Some(json!({"type": "object", "required": ["n"]})),
+        ..QueueOptions::default()
+    };
+    let s = create_queue_with(&pool, "pg-strict", &strict).await?;
+    assert_eq!(s.payload_schema, strict.payload_schema);
+    let _ok = enqueue_message(&pool, "pg-strict", &json!({"n": 1}), 0).await?;
+    assert!(enqueue_message(&pool, "pg-strict", &json!({}), 0).await.is_err());
+    // Schema changes are versioned; `tag` accepts breaking payloads
+    assert_eq!(s.payload_schema_version, 1);
+    let versioned = set_queue_schema(
+        &pool,
+        "pg-strict",
+        json!({"type": "object", "required": ["id"]}),
+        Some(SchemaMode::Tag),
+    )
+    .await?;
+    assert_eq!(versioned.payload_schema_version, 2);
+    let m = enqueue_message(&pool, "pg-strict", &json!({}), 0).await?;
+    assert_eq!(m.headers.unwrap()[SCHEMA_VERSION_HEADER], "2");
+    let history = queue_schema_history(&pool, "pg-strict").await?;
+    assert_eq!(history.len(), 2);
+    assert_eq!(
+        queue_schema(&pool, "pg-strict", Some(1)).await?.payload_schema,
+        strict.payload_schema
+    );
+
+    // A queue at its max depth refuses enqueues
+    let capped = QueueOptions { max_depth: Some(1), ..QueueOptions::default() };
+    let c = create_queue_with(&pool, "pg-capped", &capped).await?;
+    assert_eq!(c.max_depth, Some(1));
+    let _one = enqueue_message(&pool, "pg-capped", &json!(1), 0).await?;
+    assert!(matches!(
+        enqueue_message(&pool, "pg-capped", &json!(2), 0).await,
+        Err(sqew::error::SqewError::QueueFull { .. })
+    ));
+
+    // Messages move between queues
+    let moved =
+        move_messages(&pool, &[h.id], "pg-delayed", Some("pg"), true).await?;
+    assert_eq!(moved, 1);
+    assert_eq!(get_message_by_id(&pool, h.id).await?.queue_id, q.id);
+
+    // Consumer groups each receive every message
+    let _f = create_queue(&pool, "pg-fan", 5).await?;
+    for group in ["a", "b"] {
+        let _g = create_consumer_group(&pool, "pg-fan", group).await?;
+    }
+    let m = enqueue_message(&pool, "pg-fan", &json!({"f":1}), 0).await?;
+    assert!(poll_messages(&pool, "pg-fan", 1, 5000).await.is_err());
+    for group in ["a", "b"] {
+        let leased =
+            poll_group_messages(&pool, "pg-fan", group, 10, 5000).await?;
+        assert_eq!(leased.len(), 1);
+        assert_eq!(leased[0].trace_id, m.trace_id);
+        assert_eq!(peek_queue(&pool, "pg-fan", 10).await?.len(), 1);
+        let token = leased[0].lease_token.clone().unwrap();
+        assert_eq!(ack_messages(&pool, &[m.id], &token).await?, 1);
+    }
+    assert!(peek_queue(&pool, "pg-fan", 10).await?.is_empty());
+
+    // Exports load into another queue with their state intact
+    let mut file = Vec::new();
+    assert_eq!(export_queue(&pool, "pg-delayed", &mut file).await?, 2);
+    let _c = create_queue(&pool, "pg-copy", 5).await?;
+    assert_eq!(import_queue(&pool, "pg-copy", file.as_slice()).await?, (2, 0));
+    let copied = peek_queue(&pool, "pg-copy", 10).await?;
+    assert!(copied.iter().any(|m| m.headers == tagged.headers));
+
+    // Alarms fire once their metric is above the threshold
+    let hook = "http://127.0.0.1:9/hook";
+    let alarm = add_alarm(&pool, "pg-copy", "ready", 0, 0, hook).await?;
+    let fired = evaluate_alarms(&pool).await?;
+    assert_eq!((fired.len(), fired[0].alarm_id), (1, alarm.id));
+    assert!(list_alarms(&pool, Some("pg-copy")).await?[0].firing);
+
+    // An expired lease is delivered again and no longer acks
+    let _l = create_queue(&pool, "pg-lapse", 5).await?;
+    let m = enqueue_message(&pool, "pg-lapse", &json!({}), 0).await?;
+    let token = poll_messages(&pool, "pg-lapse", 1, 60_000).await?[0]
+        .lease_token
+        .clone()
+        .unwrap();
+    assert_eq!(expire_leases(&pool, &[m.id], &token).await?, 1);
+    assert_eq!(poll_messages(&pool, "pg-lapse", 1, 60_000).await?[0].id, m.id);
+    assert!(sample_messages(&pool, "pg-lapse", 5).await?.is_empty());
+    let held = in_flight(&pool, "pg-lapse", 10).await?;
+    assert_eq!((held.len(), held[0].message_id), (1, m.id));
+    assert!(held[0].leased_at.is_some());
+    assert_eq!(ack_messages(&pool, &[m.id], &token).await?, 0);
+    let attempts = message_attempts(&pool, m.id).await?;
+    assert_eq!(attempts.len(), 2);
+    assert_eq!(attempts[0].outcome.as_deref(), Some("lease_expired"));
+
+    // A consistent database passes the doctor's checks
+    assert_eq!(doctor(&pool, true).await?.problems(), 0);
+    let report = recovery_scan(&pool, true).await?;
+    assert_eq!((report.anomalies(), report.wal_bytes), (0, None));
+    let status = db_status(&pool).await?;
+    assert!(status.file_bytes > 0);
+    assert!(status.tables.iter().any(|t| t.name == "message"));
+    assert!(status.queues.iter().any(|q| q.name == "pg-lapse"));
+
+    // API keys are stored hashed and revoked by name
+    let (key, secret) =
+        auth::grant_key(&pool, "ops", Role::Admin, None, None).await?;
+    assert_eq!(key.key_hash, auth::hash_key(&secret));
+    assert!(
+        auth::grant_key(&pool, "ops", Role::Admin, None, None).await.is_err()
+    );
+    assert_eq!(auth::list_keys(&pool).await?[0].name, "ops");
+    assert!(auth::revoke_key(&pool, "ops").await?);
+    assert!(!auth::revoke_key(&pool, "ops").await?);
+
+    assert!(delete_queue(&pool, "pg").await?);
+    Ok(())
+}
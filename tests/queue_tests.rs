@@ -1,21 +1,51 @@
-use std::path::PathBuf;
-
+use chrono::{TimeZone, Utc};
 use serde_json::json;
+use sqew::audit::{self, Actor};
+use sqew::db::{
+    AuditFilter, Keyring, MessageState, PeekFilter, PoolOptions, SqliteStorage,
+};
+use sqew::error::SqewError;
+use sqew::import::ImportFormat;
+use sqew::models::MessageRef;
+use sqew::notify::QueueNotifier;
 use sqew::queue::{
-    Config, ack_messages, compact, create_queue, delete_queue, enqueue_message,
-    get_message_by_id, init_pool, list_queues, nack_messages, peek_queue,
-    poll_messages, purge_queue, show_queue, stats,
+    AckResult, AckStatus, AdminJobKind, AutoCompactConfig, CompactMode, Config,
+    EnqueueOptions, MAX_ACK_BATCH, MAX_NACK_REASON_BYTES, PayloadRejected,
+    QueueOptions, QueueOrdering, QueueUpdate, SCHEMA_VERSION_HEADER,
+    SCHEMA_VIOLATIONS_HEADER, SchemaMode, SubscribeOptions, TRASH_RETENTION_MS,
+    ack_batch, ack_messages, add_alarm, add_schedule, admin_job, auto_compact,
+    backup_database, begin_transaction, call, cancel_admin_job, clone_queue,
+    compact, create_consumer_group, create_queue, create_queue_with, db_status,
+    delete_consumer_group, delete_queue, doctor, enqueue_bytes,
+    enqueue_message, enqueue_message_tx, enqueue_message_with, enqueue_stream,
+    enqueue_transaction, enqueue_typed, evaluate_alarms, expire_messages,
+    export_queue, extend_visibility, fail_interrupted_admin_jobs,
+    get_message_by_id, import_queue, import_queue_as, in_flight, init_pool,
+    list_admin_jobs, list_alarms, list_consumer_groups, list_dead_letters,
+    list_queues, list_queues_matching, list_schedules, list_trash,
+    message_attempts, message_history, move_messages, nack_batch,
+    nack_messages, nack_messages_with_delays, nack_messages_with_reason,
+    parse_deliver_at, parse_window, payload_bytes, peek_queue,
+    peek_queue_filtered, peek_queue_with, poll_group_messages, poll_messages,
+    poll_messages_as, poll_typed, purge_archives, purge_dead_letters,
+    purge_queue, purge_queue_batched, purge_trash, queue_schema,
+    queue_schema_history, reap_expired_leases, recompress_payloads,
+    record_stats_history, recovery_scan, redrive_dead_letters, remove_alarm,
+    remove_filter, remove_message, remove_messages_matching, remove_schedule,
+    replay_messages, resolve_message_id, resolve_message_ids, respond,
+    restore_database, restore_queue, rotate_key, run_admin_jobs,
+    run_due_schedules, sample_messages, search_messages, set_paused,
+    set_queue_schema, show_queue, start_admin_job, stats, stats_history,
+    subscribe, trash_queue, update_queue,
 };
+use std::sync::Arc;
 
 fn test_config(tmp: &tempfile::TempDir) -> Config {
-    let mut cfg = {
-        let cwd =
-            std::env::current_dir().unwrap_or_else(|_| PathBuf::from("."));
-        Config { db_path: cwd.join("sqew.db"), force_recreate: false }
-    };
-    cfg.db_path = tmp.path().join("test.db");
-    cfg.force_recreate = true;
-    cfg
+    Config {
+        db_path: tmp.path().join("test.db"),
+        force_recreate: true,
+        ..Config::default()
+    }
 }
 
 #[tokio::test]
@@ -43,6 +73,212 @@ async fn queue_create_list_show_delete() -> anyhow::Result<()> {
     Ok(())
 }
 
+#[tokio::test]
+async fn queue_patterns_match_names_with_wildcards() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let pool = init_pool(&test_config(&dir)).await?;
+    for name in ["batch-1", "batch-2", "Batch-3", "prod-batch", "a?c", "a_c"] {
+        let _q = create_queue(&pool, name, 5).await?;
+    }
+    let names = |queues: Vec<sqew::models::Queue>| -> Vec<String> {
+        queues.into_iter().map(|q| q.name).collect()
+    };
+    assert_eq!(
+        names(list_queues_matching(&pool, "batch-*").await?),
+        ["batch-1", "batch-2"]
+    );
+    assert_eq!(
+        names(list_queues_matching(&pool, "*batch*").await?),
+        ["batch-1", "batch-2", "prod-batch"]
+    );
+    // Only `*` is a wildcard
+    assert_eq!(names(list_queues_matching(&pool, "a?c").await?), ["a?c"]);
+    assert_eq!(names(list_queues_matching(&pool, "a_*").await?), ["a_c"]);
+    assert_eq!(
+        names(list_queues_matching(&pool, "batch-1").await?),
+        ["batch-1"]
+    );
+
+    // Trashed queues do not match
+    assert!(trash_queue(&pool, "batch-2").await?);
+    assert_eq!(
+        names(list_queues_matching(&pool, "batch-*").await?),
+        ["batch-1"]
+    );
+    let err = list_queues_matching(&pool, "").await.unwrap_err();
+    assert!(matches!(err, SqewError::Invalid(_)));
+    Ok(())
+}
+
+#[tokio::test]
+async fn trashed_queues_are_hidden_until_restored_or_purged()
+-> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let pool = init_pool(&test_config(&dir)).await?;
+    let _q = create_queue(&pool, "old", 1).await?;
+    enqueue_message(&pool, "old", &json!({"n":1}), 0).await?;
+
+    assert!(trash_queue(&pool, "old").await?);
+    assert!(!trash_queue(&pool, "old").await?);
+    assert!(list_queues(&pool).await?.is_empty());
+    let err = enqueue_message(&pool, "old", &json!({}), 0).await.unwrap_err();
+    assert!(matches!(err, SqewError::QueueNotFound(_)));
+    assert!(peek_queue(&pool, "old", 10).await.is_err());
+    assert!(purge_queue(&pool, "old").await.is_err());
+    assert!(poll_messages(&pool, "old", 10, 5000).await?.is_empty());
+    // The name stays taken while the queue can come back
+    let err = create_queue(&pool, "old", 1).await.unwrap_err();
+    assert!(matches!(err, SqewError::QueueTrashed(_)));
+    let trash = list_trash(&pool).await?;
+    assert_eq!(trash.len(), 1);
+    assert!(trash[0].deleted_at.is_some());
+
+    // Restored with its messages
+    let q = restore_queue(&pool, "old").await?;
+    assert_eq!(q.deleted_at, None);
+    assert_eq!(poll_messages(&pool, "old", 10, 5000).await?.len(), 1);
+    assert!(list_trash(&pool).await?.is_empty());
+    let err = restore_queue(&pool, "old").await.unwrap_err();
+    assert!(matches!(err, SqewError::QueueNotFound(_)));
+
+    // Purged once its time in the trash is up
+    assert!(trash_queue(&pool, "old").await?);
+    assert!(purge_trash(&pool).await?.is_empty());
+    let raw = pool.as_sqlite().expect("sqlite backend").pool().clone();
+    sqlx::query("UPDATE queue SET deleted_at = deleted_at - ?")
+        .bind(TRASH_RETENTION_MS)
+        .execute(&raw)
+        .await?;
+    assert_eq!(purge_trash(&pool).await?, ["old"]);
+    assert!(list_trash(&pool).await?.is_empty());
+    let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM message")
+        .fetch_one(&raw)
+        .await?;
+    assert_eq!(count, 0);
+    create_queue(&pool, "old", 1).await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn errors_name_their_cause() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let cfg = test_config(&dir);
+    let pool = init_pool(&cfg).await?;
+    let _q = create_queue(&pool, "jobs", 5).await?;
+
+    let err = show_queue(&pool, "nope").await.unwrap_err();
+    assert!(matches!(&err, SqewError::QueueNotFound(name) if name == "nope"));
+    assert_eq!(err.to_string(), "Queue 'nope' not found");
+    let err = create_queue(&pool, "jobs", 5).await.unwrap_err();
+    assert!(matches!(err, SqewError::QueueExists(_)));
+    let err = get_message_by_id(&pool, 42).await.unwrap_err();
+    assert!(matches!(err, SqewError::MessageNotFound(42)));
+    let err =
+        poll_group_messages(&pool, "jobs", "audit", 1, 1000).await.unwrap_err();
+    assert!(matches!(err, SqewError::GroupNotFound { .. }));
+    let zero = QueueUpdate { max_attempts: Some(0), ..QueueUpdate::default() };
+    let err = update_queue(&pool, "jobs", &zero).await.unwrap_err();
+    assert!(matches!(err, SqewError::Invalid(_)));
+    assert!(err.to_string().starts_with("Invalid queue setting"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn pool_uses_wal_and_foreign_keys() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let cfg = Config { pool_size: 4, ..test_config(&dir) };
+    let storage = SqliteStorage::open(
+        &cfg.db_path,
+        cfg.force_recreate,
+        &cfg.pool_options(),
+    )
+    .await?;
+    let pool = storage.pool();
+    let mode: String =
+        sqlx::query_scalar("PRAGMA journal_mode").fetch_one(pool).await?;
+    assert_eq!(mode, "wal");
+    let fk: i64 =
+        sqlx::query_scalar("PRAGMA foreign_keys").fetch_one(pool).await?;
+    assert_eq!(fk, 1);
+    assert_eq!(pool.options().get_max_connections(), 4);
+    Ok(())
+}
+
+#[tokio::test]
+async fn read_pool_serves_peeks_and_stats() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let cfg = Config {
+        read_pool_size: 2,
+        statement_cache_size: 10,
+        acquire_timeout: std::time::Duration::from_secs(1),
+        ..test_config(&dir)
+    };
+    let pool = init_pool(&cfg).await?;
+    let _q = create_queue(&pool, "reads", 5).await?;
+    let m = enqueue_message(&pool, "reads", &json!({"n": 1}), 0).await?;
+    // Writes are visible to the read pool as soon as they commit
+    assert_eq!(peek_queue(&pool, "reads", 10).await?[0].id, m.id);
+    assert_eq!(stats(&pool, "reads").await?["ready"], 1);
+    let token = poll_messages(&pool, "reads", 1, 1000).await?[0]
+        .lease_token
+        .clone()
+        .unwrap();
+    assert_eq!(stats(&pool, "reads").await?["leased"], 1);
+    ack_messages(&pool, &[m.id], &token).await?;
+    assert!(peek_queue(&pool, "reads", 10).await?.is_empty());
+
+    let read_only =
+        sqew::db::sqlite::init_read_pool_at(&cfg.db_path, &cfg.pool_options())
+            .await?;
+    assert!(
+        sqlx::query("DELETE FROM message").execute(&read_only).await.is_err()
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn lock_contention_surfaces_as_a_retryable_busy_error()
+-> anyhow::Result<()> {
+    use sqlx::Connection;
+    use sqlx::sqlite::{SqliteConnectOptions, SqliteConnection};
+    let dir = tempfile::tempdir()?;
+    let cfg = test_config(&dir);
+    let pool = init_pool(&cfg).await?;
+    let _q = create_queue(&pool, "locked", 5).await?;
+    let m = enqueue_message(&pool, "locked", &json!({"n": 1}), 0).await?;
+
+    let opts = SqliteConnectOptions::new()
+        .filename(&cfg.db_path)
+        .busy_timeout(std::time::Duration::ZERO);
+    let mut holder = SqliteConnection::connect_with(&opts).await?;
+    sqlx::query("BEGIN IMMEDIATE").execute(&mut holder).await?;
+
+    // A writer that does not wait gets a typed error with a retry hint
+    let mut other = SqliteConnection::connect_with(&opts).await?;
+    let e = sqlx::query("DELETE FROM message")
+        .execute(&mut other)
+        .await
+        .unwrap_err();
+    assert!(sqew::db::is_busy(&e));
+    let err = SqewError::from(e);
+    assert!(matches!(err, SqewError::Busy { .. }), "{err}");
+    assert_eq!(err.retry_after(), Some(sqew::db::BUSY_RETRY_AFTER));
+    assert!(err.to_string().contains("busy"), "{err}");
+    assert_eq!(SqewError::from(sqlx::Error::RowNotFound).retry_after(), None);
+
+    // A poll blocked behind the lock completes once it is released
+    let poller = {
+        let pool = pool.clone();
+        tokio::spawn(
+            async move { poll_messages(&pool, "locked", 1, 1000).await },
+        )
+    };
+    tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+    sqlx::query("ROLLBACK").execute(&mut holder).await?;
+    assert_eq!(poller.await??[0].id, m.id);
+    Ok(())
+}
+
 #[tokio::test]
 async fn enqueue_peek_get_and_purge() -> anyhow::Result<()> {
     let dir = tempfile::tempdir()?;
@@ -70,6 +306,294 @@ async fn enqueue_peek_get_and_purge() -> anyhow::Result<()> {
     Ok(())
 }
 
+#[tokio::test]
+async fn purge_deletes_in_batches() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let pool = init_pool(&test_config(&dir)).await?;
+    let _q = create_queue(&pool, "bulk", 1).await?;
+    let dead = enqueue_message(&pool, "bulk", &json!({"dead":true}), 0).await?;
+    let token = poll_messages(&pool, "bulk", 1, 5000).await?[0]
+        .lease_token
+        .clone()
+        .unwrap();
+    nack_messages(&pool, &[dead.id], &token, 0).await?;
+    for n in 0..25 {
+        enqueue_message(&pool, "bulk", &json!({"n":n}), 0).await?;
+    }
+
+    let mut progress = Vec::new();
+    let purged =
+        purge_queue_batched(&pool, "bulk", 10, |total| progress.push(total))
+            .await?;
+    assert_eq!(purged, 25);
+    assert_eq!(progress, [10, 20, 25]);
+    assert!(peek_queue(&pool, "bulk", 10).await?.is_empty());
+    // Dead letters are kept
+    assert_eq!(list_dead_letters(&pool, "bulk", 10).await?.len(), 1);
+    assert_eq!(stats(&pool, "bulk").await?["ready"], 0);
+
+    let err = purge_queue_batched(&pool, "bulk", 0, |_| {}).await.unwrap_err();
+    assert!(matches!(err, SqewError::Invalid(_)));
+    let err = purge_queue(&pool, "missing").await.unwrap_err();
+    assert!(matches!(err, SqewError::QueueNotFound(_)));
+    Ok(())
+}
+
+#[tokio::test]
+async fn remove_deletes_messages_matching_a_predicate_or_age()
+-> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let pool = init_pool(&test_config(&dir)).await?;
+    let _q = create_queue(&pool, "inbox", 1).await?;
+    let dead =
+        enqueue_message(&pool, "inbox", &json!({"type": "spam"}), 0).await?;
+    let token = poll_messages(&pool, "inbox", 1, 5000).await?[0]
+        .lease_token
+        .clone()
+        .unwrap();
+    nack_messages(&pool, &[dead.id], &token, 0).await?;
+    for n in 0..5 {
+        let kind = if n % 2 == 0 { "spam" } else { "mail" };
+        let payload = json!({"type": kind, "n": n});
+        enqueue_message(&pool, "inbox", &payload, 0).await?;
+    }
+
+    let spam = remove_filter(Some(r#"$.type == "spam""#), None, 0)?;
+    assert_eq!(spam.json_path, Some(("$.type".into(), "spam".into())));
+    let mut progress = Vec::new();
+    let removed = remove_messages_matching(&pool, "inbox", &spam, 2, |total| {
+        progress.push(total)
+    })
+    .await?;
+    assert_eq!((removed, progress), (3, vec![2, 3]));
+    let left = peek_queue(&pool, "inbox", 10).await?;
+    assert!(left.iter().all(|m| m.payload.contains("mail")));
+    // Dead letters are kept
+    assert_eq!(list_dead_letters(&pool, "inbox", 10).await?.len(), 1);
+
+    // Ages count back from `now`, so from an hour ahead everything is old
+    let now = Utc::now().timestamp_millis();
+    let recent = remove_filter(None, Some("1h"), now)?;
+    let n = remove_messages_matching(&pool, "inbox", &recent, 10, |_| {});
+    assert_eq!(n.await?, 0);
+    let old = remove_filter(Some("$.n == 1"), Some("1h"), now + 7_200_000)?;
+    let n = remove_messages_matching(&pool, "inbox", &old, 10, |_| {});
+    assert_eq!(n.await?, 1);
+    assert_eq!(peek_queue(&pool, "inbox", 10).await?.len(), 1);
+
+    let none = remove_filter(None, None, now)?;
+    let err = remove_messages_matching(&pool, "inbox", &none, 10, |_| {});
+    assert!(matches!(err.await, Err(SqewError::Invalid(_))));
+    assert!(remove_filter(Some("$.type =="), None, now).is_err());
+    assert!(remove_filter(None, Some("7 days"), now).is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn audit_log_is_append_only_and_filterable() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let pool = init_pool(&test_config(&dir)).await?;
+    let ops = Actor::key("ops");
+    audit::record(&pool, &ops, "queue.create", Some("jobs"), json!({"n": 1}))
+        .await;
+    audit::record(
+        &pool,
+        &Actor::cli(),
+        "queue.purge",
+        Some("jobs"),
+        json!(null),
+    )
+    .await;
+    audit::record(&pool, &ops, "key.grant", None, json!({"name": "shop"}))
+        .await;
+
+    // Newest first, narrowed by action, queue, actor and window
+    let all = AuditFilter::default();
+    let entries = audit::list_entries(&pool, &all, 10).await?;
+    let actions: Vec<_> = entries.iter().map(|e| e.action.as_str()).collect();
+    assert_eq!(actions, ["key.grant", "queue.purge", "queue.create"]);
+    assert_eq!(entries[0].actor, "key:ops");
+    assert_eq!(entries[0].params, Some(json!({"name": "shop"})));
+    assert_eq!(entries[1].params, None);
+    assert_eq!(audit::list_entries(&pool, &all, 1).await?.len(), 1);
+    let now = Utc::now().timestamp_millis();
+    let by =
+        |action: Option<&str>, queue: Option<&str>, actor: Option<&str>| {
+            audit::audit_filter(
+                action.map(str::to_string),
+                queue.map(str::to_string),
+                actor.map(str::to_string),
+                Some("1h"),
+                now,
+            )
+        };
+    let jobs = by(None, Some("jobs"), None)?;
+    assert_eq!(audit::list_entries(&pool, &jobs, 10).await?.len(), 2);
+    let ops_creates = by(Some("queue.create"), None, Some("key:ops"))?;
+    assert_eq!(audit::list_entries(&pool, &ops_creates, 10).await?.len(), 1);
+    let later =
+        AuditFilter { since: Some(now + 1000), ..AuditFilter::default() };
+    assert!(audit::list_entries(&pool, &later, 10).await?.is_empty());
+    assert!(audit::audit_filter(None, None, None, Some("soon"), now).is_err());
+
+    // Entries cannot be changed or removed
+    let raw = pool.as_sqlite().expect("sqlite backend").pool().clone();
+    let update = sqlx::query("UPDATE audit_log SET actor = 'someone-else'");
+    assert!(update.execute(&raw).await.is_err());
+    let delete = sqlx::query("DELETE FROM audit_log");
+    assert!(delete.execute(&raw).await.is_err());
+    assert_eq!(audit::list_entries(&pool, &all, 10).await?.len(), 3);
+    Ok(())
+}
+
+#[tokio::test]
+async fn admin_jobs_run_in_the_background() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let pool = init_pool(&test_config(&dir)).await?;
+    let _q = create_queue(&pool, "bulk", 1).await?;
+    for n in 0..5 {
+        enqueue_message(&pool, "bulk", &json!({"n":n}), 0).await?;
+    }
+    let leased = poll_messages(&pool, "bulk", 3, 5000).await?;
+    let ids: Vec<i64> = leased.iter().map(|m| m.id).collect();
+    let token = leased[0].lease_token.clone().unwrap();
+    nack_messages(&pool, &ids, &token, 0).await?;
+
+    let export = dir.path().join("bulk.ndjson");
+    let exported = start_admin_job(
+        &pool,
+        AdminJobKind::Export,
+        Some("bulk"),
+        Some(&export),
+        Some(2),
+    )
+    .await?;
+    assert_eq!(exported.status, "pending");
+    let redriven = start_admin_job(
+        &pool,
+        AdminJobKind::Redrive,
+        Some("bulk"),
+        None,
+        Some(2),
+    )
+    .await?;
+    let purged = start_admin_job(
+        &pool,
+        AdminJobKind::Purge,
+        Some("bulk"),
+        None,
+        Some(2),
+    )
+    .await?;
+    let compacted =
+        start_admin_job(&pool, AdminJobKind::Compact, Some("bulk"), None, None)
+            .await?;
+    assert_eq!(compacted.queue, None);
+    assert_eq!(run_admin_jobs(&pool).await?, 4);
+    assert_eq!(run_admin_jobs(&pool).await?, 0);
+
+    let job = admin_job(&pool, exported.id).await?;
+    assert_eq!((job.status.as_str(), job.progress), ("done", 5));
+    assert_eq!(std::fs::read_to_string(&export)?.lines().count(), 5);
+    let job = admin_job(&pool, redriven.id).await?;
+    assert_eq!((job.status.as_str(), job.progress), ("done", 3));
+    let job = admin_job(&pool, purged.id).await?;
+    assert_eq!((job.status.as_str(), job.progress), ("done", 5));
+    assert!(job.started_at.is_some() && job.finished_at.is_some());
+    assert_eq!(stats(&pool, "bulk").await?["ready"], 0);
+    assert_eq!(admin_job(&pool, compacted.id).await?.status, "done");
+    let jobs = list_admin_jobs(&pool, 10).await?;
+    assert_eq!(jobs.first().map(|j| j.id), Some(compacted.id));
+
+    // A pending job canceled never runs; a finished one cannot be canceled
+    let pending =
+        start_admin_job(&pool, AdminJobKind::Purge, Some("bulk"), None, None)
+            .await?;
+    let job = cancel_admin_job(&pool, pending.id).await?;
+    assert_eq!(job.status, "canceled");
+    assert_eq!(run_admin_jobs(&pool).await?, 0);
+    let err = cancel_admin_job(&pool, purged.id).await.unwrap_err();
+    assert!(matches!(err, SqewError::JobFinished { .. }));
+
+    // A running job is asked to stop at its next batch
+    let running =
+        start_admin_job(&pool, AdminJobKind::Compact, None, None, None).await?;
+    let claimed = pool.claim_admin_job(0).await?.unwrap();
+    assert_eq!(claimed.id, running.id);
+    let job = cancel_admin_job(&pool, running.id).await?;
+    assert_eq!(job.status, "running");
+    assert!(job.cancel_requested);
+    assert!(pool.set_admin_job_progress(running.id, 1).await?);
+    // ...and failed if the server stops first
+    assert_eq!(fail_interrupted_admin_jobs(&pool).await?, 1);
+    let job = admin_job(&pool, running.id).await?;
+    assert_eq!(job.status, "failed");
+    assert!(job.error.is_some());
+
+    let err = start_admin_job(&pool, AdminJobKind::Purge, None, None, None)
+        .await
+        .unwrap_err();
+    assert!(matches!(err, SqewError::Invalid(_)));
+    let err =
+        start_admin_job(&pool, AdminJobKind::Export, Some("bulk"), None, None)
+            .await
+            .unwrap_err();
+    assert!(matches!(err, SqewError::Invalid(_)));
+    let err = start_admin_job(
+        &pool,
+        AdminJobKind::Export,
+        Some("bulk"),
+        Some(&export),
+        None,
+    )
+    .await
+    .unwrap_err();
+    assert!(matches!(err, SqewError::Invalid(_)));
+    let err =
+        start_admin_job(&pool, AdminJobKind::Redrive, Some("nope"), None, None)
+            .await
+            .unwrap_err();
+    assert!(matches!(err, SqewError::QueueNotFound(_)));
+    let err = admin_job(&pool, 999).await.unwrap_err();
+    assert!(matches!(err, SqewError::JobNotFound(999)));
+    Ok(())
+}
+
+#[tokio::test]
+async fn binary_payloads_round_trip() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let pool = init_pool(&test_config(&dir)).await?;
+    let _q = create_queue(&pool, "bin", 5).await?;
+    let opts = EnqueueOptions::default();
+
+    let raw = [0x82, 0xa1, b'n', 0x01, 0xc0, 0xff];
+    let m =
+        enqueue_bytes(&pool, "bin", &raw, "application/msgpack", &opts).await?;
+    let headers = m.headers.clone().unwrap_or_default();
+    assert_eq!(headers["content-type"], "application/msgpack");
+    let polled = poll_messages(&pool, "bin", 1, 1000).await?;
+    let (content_type, bytes) = payload_bytes(&polled[0])?;
+    assert_eq!(
+        (content_type.as_str(), bytes.as_slice()),
+        ("application/msgpack", &raw[..])
+    );
+
+    // JSON types are enqueued as JSON, and must be JSON
+    let ct = "application/vnd.api+json; charset=utf-8";
+    let m = enqueue_bytes(&pool, "bin", br#"{"n":2}"#, ct, &opts).await?;
+    assert_eq!(m.payload, r#"{"n":2}"#);
+    assert!(m.headers.is_none_or(|h| h.is_empty()));
+    let (content_type, bytes) =
+        payload_bytes(&get_message_by_id(&pool, m.id).await?)?;
+    assert_eq!(content_type, "application/json");
+    assert_eq!(bytes, br#"{"n":2}"#);
+    let err = enqueue_bytes(&pool, "bin", b"\x00", "application/json", &opts)
+        .await
+        .unwrap_err();
+    assert!(matches!(err, SqewError::Invalid(_)));
+    Ok(())
+}
+
 #[tokio::test]
 async fn poll_and_ack() -> anyhow::Result<()> {
     let dir = tempfile::tempdir()?;
@@ -86,8 +610,13 @@ async fn poll_and_ack() -> anyhow::Result<()> {
     assert_eq!(leased.id, m.id);
     assert!(leased.available_at > leased.created_at);
 
+    let token = leased.lease_token.clone().expect("poll returns a lease token");
+
+    // Ack with the wrong token is rejected
+    assert_eq!(ack_messages(&pool, &[leased.id], "bogus").await?, 0);
+
     // Ack deletes
-    let n = ack_messages(&pool, &[leased.id]).await?;
+    let n = ack_messages(&pool, &[leased.id], &token).await?;
     assert_eq!(n, 1);
     // Ensure not found
     assert!(get_message_by_id(&pool, leased.id).await.is_err());
@@ -95,7 +624,7 @@ async fn poll_and_ack() -> anyhow::Result<()> {
 }
 
 #[tokio::test]
-async fn nack_and_drop_on_max_attempts() -> anyhow::Result<()> {
+async fn nack_dead_letters_on_max_attempts() -> anyhow::Result<()> {
     let dir = tempfile::tempdir()?;
     let cfg = test_config(&dir);
     let pool = init_pool(&cfg).await?;
@@ -104,32 +633,2741 @@ async fn nack_and_drop_on_max_attempts() -> anyhow::Result<()> {
     let m = enqueue_message(&pool, "q3", &json!({"x":1}), 0).await?;
 
     // First nack -> requeue with attempts=1
-    let (requeued, dropped) = nack_messages(&pool, &[m.id], 10).await?;
+    let token = poll_messages(&pool, "q3", 1, 1000).await?[0]
+        .lease_token
+        .clone()
+        .unwrap();
+    let (requeued, dropped) = nack_messages(&pool, &[m.id], &token, 0).await?;
     assert_eq!((requeued, dropped), (1, 0));
     let after1 = get_message_by_id(&pool, m.id).await?;
     assert_eq!(after1.attempts, 1);
 
-    // Second nack -> attempts becomes 2, equals max_attempts => drop
-    let (requeued2, dropped2) = nack_messages(&pool, &[m.id], 10).await?;
+    // The lease was released, so the old token no longer applies
+    assert_eq!(nack_messages(&pool, &[m.id], &token, 0).await?, (0, 0));
+
+    // Second nack -> attempts becomes 2, equals max_attempts => dead-letter
+    let token = poll_messages(&pool, "q3", 1, 1000).await?[0]
+        .lease_token
+        .clone()
+        .unwrap();
+    let (requeued2, dropped2) =
+        nack_messages(&pool, &[m.id], &token, 0).await?;
     assert_eq!((requeued2, dropped2), (0, 1));
+    let dead = get_message_by_id(&pool, m.id).await?;
+    assert!(dead.dead_at.is_some());
+
+    // Dead letters are hidden from peek and poll
+    assert!(peek_queue(&pool, "q3", 10).await?.is_empty());
+    assert!(poll_messages(&pool, "q3", 10, 100).await?.is_empty());
+    Ok(())
+}
+
+#[tokio::test]
+async fn sample_returns_distinct_ready_messages() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let pool = init_pool(&test_config(&dir)).await?;
+    let _q = create_queue(&pool, "few", 5).await?;
+    for n in 0..20 {
+        enqueue_message(&pool, "few", &json!(n), 0).await?;
+    }
+    enqueue_message(&pool, "few", &json!("later"), 60_000).await?;
+    let leased = poll_messages(&pool, "few", 5, 60_000).await?;
+    let sample = sample_messages(&pool, "few", 100).await?;
+    let mut ids: Vec<_> = sample.iter().map(|m| m.id).collect();
+    ids.sort_unstable();
+    ids.dedup();
+    assert_eq!(ids.len(), 15);
+    assert!(sample.iter().all(|m| m.lease_token.is_none()));
+    assert!(!ids.contains(&leased[0].id));
+    assert!(matches!(
+        sample_messages(&pool, "few", 101).await,
+        Err(SqewError::Invalid(_))
+    ));
+    assert!(matches!(
+        sample_messages(&pool, "nope", 1).await,
+        Err(SqewError::QueueNotFound(_))
+    ));
+
+    // Past ten thousand ready messages the sample is drawn by random id
+    let _q = create_queue(&pool, "many", 5).await?;
+    let feed: String = (0..10_050).map(|n| format!("{n}\n")).collect();
+    let opts = EnqueueOptions::default();
+    enqueue_stream(&pool, "many", feed.as_bytes(), &opts, 5000, |_| {}).await?;
+    let leased = poll_messages(&pool, "many", 10, 60_000).await?;
+    let sample = sample_messages(&pool, "many", 20).await?;
+    let mut ids: Vec<_> = sample.iter().map(|m| m.id).collect();
+    ids.sort_unstable();
+    ids.dedup();
+    assert_eq!(ids.len(), 20);
+    assert!(sample.iter().all(|m| m.lease_token.is_none()));
+    assert!(leased.iter().all(|m| !ids.contains(&m.id)));
+    // Not just the head of the queue
+    assert!(ids[19] > leased[9].id + 20);
+    Ok(())
+}
+
+#[tokio::test]
+async fn deliver_at_schedules_at_an_absolute_time() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let pool = init_pool(&test_config(&dir)).await?;
+    let _q = create_queue(&pool, "at", 1).await?;
+    let monday = parse_deliver_at("2030-01-07T09:00:00-05:00")?;
+    assert_eq!(monday, parse_deliver_at("2030-01-07T14:00:00Z")?);
+    assert!(matches!(
+        parse_deliver_at("2030-01-07T09:00:00"),
+        Err(SqewError::Invalid(_))
+    ));
+
+    let later = EnqueueOptions {
+        deliver_at: Some(monday),
+        ..EnqueueOptions::default()
+    };
+    let m = enqueue_message_with(&pool, "at", &json!(1), &later).await?;
+    assert_eq!(m.available_at, monday);
+    // A time already past delivers at once
+    let past = EnqueueOptions {
+        deliver_at: Some(parse_deliver_at("2001-01-01T00:00:00Z")?),
+        ..EnqueueOptions::default()
+    };
+    let now = enqueue_message_with(&pool, "at", &json!(2), &past).await?;
+    assert_eq!(now.available_at, now.created_at);
+    assert_eq!(poll_messages(&pool, "at", 10, 1000).await?[0].id, now.id);
+
+    let both = EnqueueOptions { delay_ms: Some(10), ..later };
+    assert!(matches!(
+        enqueue_message_with(&pool, "at", &json!(3), &both).await,
+        Err(SqewError::Invalid(_))
+    ));
+    Ok(())
+}
+
+#[tokio::test]
+async fn nack_with_per_message_delays() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let cfg = test_config(&dir);
+    let pool = init_pool(&cfg).await?;
+    let _q = create_queue(&pool, "delays", 5).await?;
+    let a = enqueue_message(&pool, "delays", &json!({"n": 1}), 0).await?;
+    let b = enqueue_message(&pool, "delays", &json!({"n": 2}), 0).await?;
+    let c = enqueue_message(&pool, "delays", &json!({"n": 3}), 0).await?;
+
+    let token = poll_messages(&pool, "delays", 3, 5000).await?[0]
+        .lease_token
+        .clone()
+        .unwrap();
+    let nacks = [(a.id, 0), (b.id, 60_000), (c.id, 120_000)];
+    assert_eq!(nack_messages_with_delays(&pool, &nacks, &token).await?, (3, 0));
+    let (b, c) = (
+        get_message_by_id(&pool, b.id).await?,
+        get_message_by_id(&pool, c.id).await?,
+    );
+    assert!(c.available_at - b.available_at >= 59_000);
+    assert_eq!(b.attempts, 1);
+
+    // Only the message nacked without a delay is ready again
+    let ready = poll_messages(&pool, "delays", 10, 5000).await?;
+    assert_eq!(ready.len(), 1);
+    assert_eq!(ready[0].id, a.id);
+
+    // Consumer group leases take per-message delays too
+    let _g = create_consumer_group(&pool, "delays", "audit").await?;
+    let d = enqueue_message(&pool, "delays", &json!({"n": 4}), 0).await?;
+    let e = enqueue_message(&pool, "delays", &json!({"n": 5}), 0).await?;
+    let leased =
+        poll_group_messages(&pool, "delays", "audit", 10, 5000).await?;
+    assert_eq!(leased.len(), 2);
+    let token = leased[0].lease_token.clone().unwrap();
+    let nacks = [(d.id, 60_000), (e.id, 0)];
+    assert_eq!(nack_messages_with_delays(&pool, &nacks, &token).await?, (2, 0));
+    let ready = poll_group_messages(&pool, "delays", "audit", 10, 5000).await?;
+    assert_eq!(ready.len(), 1);
+    assert_eq!(ready[0].id, e.id);
+    Ok(())
+}
+
+#[tokio::test]
+async fn clone_copies_settings_and_optionally_messages() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let cfg = test_config(&dir);
+    let pool = init_pool(&cfg).await?;
+    let opts = QueueOptions {
+        max_attempts: 3,
+        backoff_base_ms: Some(250),
+        default_delay_ms: 10,
+        payload_schema: Some(json!({"type": "object"})),
+        ..QueueOptions::default()
+    };
+    let src = create_queue_with(&pool, "blue", &opts).await?;
+    let urgent = EnqueueOptions {
+        delay_ms: Some(0),
+        priority: 5,
+        ..EnqueueOptions::default()
+    };
+    let m =
+        enqueue_message_with(&pool, "blue", &json!({"n": 1}), &urgent).await?;
+    let _n = enqueue_message(&pool, "blue", &json!({"n": 2}), 0).await?;
+    let leased = poll_messages(&pool, "blue", 1, 60_000).await?;
+    assert_eq!(leased[0].id, m.id);
+    let _p = set_paused(&pool, "blue", true).await?;
+
+    // Settings only; the copy starts unpaused
+    let (empty, copied) = clone_queue(&pool, "blue", "green", false).await?;
+    assert_eq!(copied, 0);
+    assert_ne!(empty.id, src.id);
+    assert_eq!(empty.max_attempts, 3);
+    assert_eq!(empty.backoff_base_ms, Some(250));
+    assert_eq!(empty.default_delay_ms, 10);
+    assert_eq!(empty.payload_schema, src.payload_schema);
+    assert!(!empty.paused);
+    assert!(peek_queue(&pool, "green", 10).await?.is_empty());
+
+    // With messages: the leased one is copied unleased and visible
+    let (copy, copied) = clone_queue(&pool, "blue", "staging", true).await?;
+    assert_eq!(copied, 2);
+    let polled = poll_messages(&pool, "staging", 10, 1000).await?;
+    assert_eq!(polled.len(), 2);
+    assert!(polled.iter().all(|p| p.queue_id == copy.id && p.id != m.id));
+    assert_eq!(polled[0].priority, 5);
+    assert_eq!(polled[0].payload, m.payload);
+
+    let exists = clone_queue(&pool, "blue", "green", false).await;
+    assert!(
+        matches!(exists, Err(SqewError::QueueExists(name)) if name == "green")
+    );
+    // A name in the trash stays taken for clones too
+    let _q = create_queue(&pool, "old", 1).await?;
+    assert!(trash_queue(&pool, "old").await?);
+    let trashed = clone_queue(&pool, "blue", "old", false).await;
+    assert!(
+        matches!(trashed, Err(SqewError::QueueTrashed(name)) if name == "old")
+    );
+    let missing = clone_queue(&pool, "nope", "other", false).await;
+    assert!(missing.unwrap_err().to_string().contains("not found"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn dlq_list_redrive_and_purge() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let cfg = test_config(&dir);
+    let pool = init_pool(&cfg).await?;
+    let _q = create_queue(&pool, "q5", 1).await?;
+
+    let m1 = enqueue_message(&pool, "q5", &json!({"n":1}), 0).await?;
+    let m2 = enqueue_message(&pool, "q5", &json!({"n":2}), 0).await?;
+    let leased = poll_messages(&pool, "q5", 2, 1000).await?;
+    let token = leased[0].lease_token.clone().unwrap();
+    let (_, dead) = nack_messages(&pool, &[m1.id, m2.id], &token, 0).await?;
+    assert_eq!(dead, 2);
+    assert_eq!(list_dead_letters(&pool, "q5", 10).await?.len(), 2);
+    let s = stats(&pool, "q5").await?;
+    assert_eq!(s.get("dlq").and_then(|v| v.as_i64()), Some(2));
+
+    // Redrive one: back in the queue with attempts reset
+    assert_eq!(redrive_dead_letters(&pool, "q5", &[m1.id]).await?, 1);
+    let back = get_message_by_id(&pool, m1.id).await?;
+    assert_eq!((back.attempts, back.dead_at), (0, None));
+    assert_eq!(peek_queue(&pool, "q5", 10).await?.len(), 1);
+
+    // Purging the queue keeps dead letters; purging the DLQ removes them
+    assert_eq!(purge_queue(&pool, "q5").await?, 1);
+    assert_eq!(purge_dead_letters(&pool, "q5").await?, 1);
+    assert!(list_dead_letters(&pool, "q5", 10).await?.is_empty());
+    Ok(())
+}
+
+#[tokio::test]
+async fn ack_after_lease_expiry_is_rejected() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let cfg = test_config(&dir);
+    let pool = init_pool(&cfg).await?;
+    let _q = create_queue(&pool, "q6", 5).await?;
+    let m = enqueue_message(&pool, "q6", &json!({"n":1}), 0).await?;
+
+    // First consumer's lease expires and a second consumer takes over
+    let first = poll_messages(&pool, "q6", 1, 20).await?;
+    let stale = first[0].lease_token.clone().unwrap();
+    tokio::time::sleep(std::time::Duration::from_millis(40)).await;
+    assert_eq!(ack_messages(&pool, &[m.id], &stale).await?, 0);
+    let second = poll_messages(&pool, "q6", 1, 1000).await?;
+    let fresh = second[0].lease_token.clone().unwrap();
+    assert_ne!(stale, fresh);
+
+    // The stale token cannot delete the message from under the new owner
+    assert_eq!(ack_messages(&pool, &[m.id], &stale).await?, 0);
+    assert_eq!(ack_messages(&pool, &[m.id], &fresh).await?, 1);
+    Ok(())
+}
+
+#[tokio::test]
+async fn expired_leases_count_as_attempts() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let pool = init_pool(&test_config(&dir)).await?;
+    let _q = create_queue(&pool, "reap", 2).await?;
+    let m = enqueue_message(&pool, "reap", &json!({"n":1}), 0).await?;
+
+    // A re-poll after the lease expired sees the lost attempt
+    let _first = poll_messages(&pool, "reap", 1, 20).await?;
+    tokio::time::sleep(std::time::Duration::from_millis(40)).await;
+    let second = poll_messages(&pool, "reap", 1, 20).await?;
+    assert_eq!(second[0].attempts, 1);
+
+    // The reaper dead-letters once max_attempts is reached
+    tokio::time::sleep(std::time::Duration::from_millis(40)).await;
+    assert_eq!(reap_expired_leases(&pool).await?, (0, 1));
+    assert_eq!(reap_expired_leases(&pool).await?, (0, 0));
+    let dead = list_dead_letters(&pool, "reap", 10).await?;
+    assert_eq!(dead[0].id, m.id);
+    assert_eq!(dead[0].attempts, 2);
+    Ok(())
+}
+
+#[tokio::test]
+async fn messages_whose_leases_keep_expiring_are_quarantined()
+-> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let pool = init_pool(&test_config(&dir)).await?;
+    let opts = QueueOptions {
+        max_attempts: 10,
+        max_lease_expirations: Some(2),
+        ..QueueOptions::default()
+    };
+    let q = create_queue_with(&pool, "crashy", &opts).await?;
+    assert_eq!(q.max_lease_expirations, Some(2));
+    let m = enqueue_message(&pool, "crashy", &json!({"n":1}), 0).await?;
+
+    // The first silent expiry is redelivered like any other
+    let _first = poll_messages(&pool, "crashy", 1, 20).await?;
+    tokio::time::sleep(std::time::Duration::from_millis(40)).await;
+    let second = poll_messages(&pool, "crashy", 1, 20).await?;
+    assert_eq!(second[0].lease_expirations, 1);
+
+    // The second quarantines it long before max_attempts
+    tokio::time::sleep(std::time::Duration::from_millis(40)).await;
+    assert_eq!(reap_expired_leases(&pool).await?, (0, 1));
+    let dead = list_dead_letters(&pool, "crashy", 10).await?;
+    assert_eq!(dead[0].id, m.id);
+    assert_eq!((dead[0].attempts, dead[0].lease_expirations), (2, 2));
+    assert!(dead[0].last_error.as_deref().unwrap().starts_with("quarantined"));
+    let outcomes: Vec<_> = message_attempts(&pool, m.id)
+        .await?
+        .into_iter()
+        .map(|a| a.outcome)
+        .collect();
+    assert_eq!(
+        outcomes,
+        [Some("lease_expired".to_string()), Some("quarantined".to_string())]
+    );
+
+    let update = QueueUpdate {
+        max_lease_expirations: Some(Some(0)),
+        ..QueueUpdate::default()
+    };
+    assert!(matches!(
+        update_queue(&pool, "crashy", &update).await,
+        Err(SqewError::Invalid(_))
+    ));
+    let update = QueueUpdate {
+        max_lease_expirations: Some(None),
+        ..QueueUpdate::default()
+    };
+    let q = update_queue(&pool, "crashy", &update).await?;
+    assert_eq!(q.max_lease_expirations, None);
+    Ok(())
+}
+
+#[tokio::test]
+async fn polls_respect_the_delivery_rate_limit() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let pool = init_pool(&test_config(&dir)).await?;
+    let limited = QueueOptions {
+        max_deliveries_per_second: Some(4.0),
+        ..QueueOptions::default()
+    };
+    let q = create_queue_with(&pool, "slow", &limited).await?;
+    assert_eq!(q.max_deliveries_per_second, Some(4.0));
+    for n in 0..10 {
+        let _m = enqueue_message(&pool, "slow", &json!({ "n": n }), 0).await?;
+    }
+
+    // A full bucket allows one second's worth, then refills over time
+    assert_eq!(poll_messages(&pool, "slow", 10, 5000).await?.len(), 4);
+    assert!(poll_messages(&pool, "slow", 10, 5000).await?.is_empty());
+    tokio::time::sleep(std::time::Duration::from_millis(300)).await;
+    assert_eq!(poll_messages(&pool, "slow", 10, 5000).await?.len(), 1);
+
+    // Lifting the limit drains the backlog
+    let unlimited = QueueUpdate {
+        max_deliveries_per_second: Some(None),
+        ..QueueUpdate::default()
+    };
+    let q = update_queue(&pool, "slow", &unlimited).await?;
+    assert_eq!(q.max_deliveries_per_second, None);
+    assert_eq!(poll_messages(&pool, "slow", 10, 5000).await?.len(), 5);
+    let bad = QueueUpdate {
+        max_deliveries_per_second: Some(Some(0.0)),
+        ..QueueUpdate::default()
+    };
+    assert!(update_queue(&pool, "slow", &bad).await.is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn paused_queues_lease_nothing_until_resumed() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let pool = init_pool(&test_config(&dir)).await?;
+    let _q = create_queue(&pool, "held", 5).await?;
+    let m = enqueue_message(&pool, "held", &json!({"n": 1}), 0).await?;
+    assert!(set_paused(&pool, "held", true).await?.paused);
+
+    // Enqueues and peeks still work; polls come back empty
+    let _n = enqueue_message(&pool, "held", &json!({"n": 2}), 0).await?;
+    assert_eq!(peek_queue(&pool, "held", 10).await?.len(), 2);
+    assert!(poll_messages(&pool, "held", 10, 5000).await?.is_empty());
+    assert!(show_queue(&pool, "held").await?.paused);
+
+    assert!(!set_paused(&pool, "held", false).await?.paused);
+    let leased = poll_messages(&pool, "held", 10, 5000).await?;
+    assert_eq!(leased.len(), 2);
+    assert_eq!(leased[0].id, m.id);
+
+    // Consumer groups are held back too
+    let _g = create_consumer_group(&pool, "held", "audit").await?;
+    let _o = enqueue_message(&pool, "held", &json!({"n": 3}), 0).await?;
+    let _p = set_paused(&pool, "held", true).await?;
+    assert!(
+        poll_group_messages(&pool, "held", "audit", 10, 5000).await?.is_empty()
+    );
+    let _r = set_paused(&pool, "held", false).await?;
+    assert_eq!(
+        poll_group_messages(&pool, "held", "audit", 10, 5000).await?.len(),
+        1
+    );
+    assert!(set_paused(&pool, "nope", true).await.is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn extend_visibility_keeps_lease_alive() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let cfg = test_config(&dir);
+    let pool = init_pool(&cfg).await?;
+    let _q = create_queue(&pool, "q7", 5).await?;
+    let m = enqueue_message(&pool, "q7", &json!({"n":1}), 0).await?;
+
+    let leased = poll_messages(&pool, "q7", 1, 50).await?;
+    let token = leased[0].lease_token.clone().unwrap();
+    assert_eq!(extend_visibility(&pool, &[m.id], "bogus", 1000).await?, 0);
+    assert_eq!(extend_visibility(&pool, &[m.id], &token, 1000).await?, 1);
+    let after = get_message_by_id(&pool, m.id).await?;
+    assert_eq!(after.available_at, leased[0].available_at + 1000);
+
+    // Past the original timeout the message is still leased
+    tokio::time::sleep(std::time::Duration::from_millis(80)).await;
+    assert!(poll_messages(&pool, "q7", 1, 50).await?.is_empty());
+    assert_eq!(ack_messages(&pool, &[m.id], &token).await?, 1);
+    Ok(())
+}
+
+#[tokio::test]
+async fn higher_priority_is_polled_first() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let cfg = test_config(&dir);
+    let pool = init_pool(&cfg).await?;
+    let _q = create_queue(&pool, "q8", 5).await?;
+
+    let low = enqueue_message(&pool, "q8", &json!({"n":"low"}), 0).await?;
+    let urgent = EnqueueOptions { priority: 10, ..EnqueueOptions::default() };
+    let high = enqueue_message_with(&pool, "q8", &json!({"n":"high"}), &urgent)
+        .await?;
+    assert_eq!(high.priority, 10);
+
+    let peeked = peek_queue(&pool, "q8", 10).await?;
+    assert_eq!(peeked[0].id, high.id);
+    let polled = poll_messages(&pool, "q8", 1, 1000).await?;
+    assert_eq!(polled[0].id, high.id);
+    let polled = poll_messages(&pool, "q8", 1, 1000).await?;
+    assert_eq!(polled[0].id, low.id);
+    Ok(())
+}
+
+#[tokio::test]
+async fn ttl_expires_unconsumed_messages() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let cfg = test_config(&dir);
+    let pool = init_pool(&cfg).await?;
+    let _q = create_queue(&pool, "q9", 5).await?;
+
+    let short =
+        EnqueueOptions { ttl_ms: Some(20), ..EnqueueOptions::default() };
+    let m = enqueue_message_with(&pool, "q9", &json!({"n":1}), &short).await?;
+    assert_eq!(m.expires_at, Some(m.created_at + 20));
+    let keep = enqueue_message(&pool, "q9", &json!({"n":2}), 0).await?;
+    tokio::time::sleep(std::time::Duration::from_millis(40)).await;
+
+    // Expired messages are invisible even before the sweeper runs
+    let peeked = peek_queue(&pool, "q9", 10).await?;
+    assert_eq!(peeked.len(), 1);
+    assert_eq!(peeked[0].id, keep.id);
+
+    assert_eq!(expire_messages(&pool).await?, 1);
     assert!(get_message_by_id(&pool, m.id).await.is_err());
+    let s = stats(&pool, "q9").await?;
+    assert_eq!(s.get("expired").and_then(|v| v.as_i64()), Some(1));
+    assert_eq!(s.get("ready").and_then(|v| v.as_i64()), Some(1));
     Ok(())
 }
 
 #[tokio::test]
-async fn stats_and_compact() -> anyhow::Result<()> {
+async fn dedup_key_returns_existing_message() -> anyhow::Result<()> {
     let dir = tempfile::tempdir()?;
     let cfg = test_config(&dir);
     let pool = init_pool(&cfg).await?;
-    let _q = create_queue(&pool, "q4", 5).await?;
-    let _ = enqueue_message(&pool, "q4", &json!({"n":1}), 0).await?;
-    let _ = enqueue_message(&pool, "q4", &json!({"n":2}), 1000).await?;
+    let _q = create_queue(&pool, "q10", 5).await?;
+    let opts = QueueOptions { dedup_window_ms: 30, ..QueueOptions::default() };
+    let _short = create_queue_with(&pool, "q11", &opts).await?;
 
-    // Ready should be >= 1 (first message available now)
-    let s = stats(&pool, "q4").await?;
-    assert!(s.get("ready").and_then(|v| v.as_i64()).unwrap_or(0) >= 1);
+    let keyed = EnqueueOptions {
+        dedup_key: Some("order-1".into()),
+        ..EnqueueOptions::default()
+    };
+    let first =
+        enqueue_message_with(&pool, "q10", &json!({"n":1}), &keyed).await?;
+    let dup =
+        enqueue_message_with(&pool, "q10", &json!({"n":2}), &keyed).await?;
+    assert_eq!(dup.id, first.id);
+    assert_eq!(dup.payload, first.payload);
+    assert_eq!(peek_queue(&pool, "q10", 10).await?.len(), 1);
 
-    // Compact shouldn't error
-    compact(&pool).await?;
+    // Keys are scoped per queue
+    let other =
+        enqueue_message_with(&pool, "q11", &json!({"n":3}), &keyed).await?;
+    assert_ne!(other.id, first.id);
+
+    // Once consumed, the key is free again
+    let leased = poll_messages(&pool, "q10", 1, 1000).await?;
+    let token = leased[0].lease_token.clone().unwrap();
+    ack_messages(&pool, &[first.id], &token).await?;
+    let again =
+        enqueue_message_with(&pool, "q10", &json!({"n":4}), &keyed).await?;
+    assert_ne!(again.id, first.id);
+
+    // Outside the queue's window the key no longer deduplicates
+    tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    let later =
+        enqueue_message_with(&pool, "q11", &json!({"n":5}), &keyed).await?;
+    assert_ne!(later.id, other.id);
+    assert_eq!(peek_queue(&pool, "q11", 10).await?.len(), 2);
+    Ok(())
+}
+
+#[tokio::test]
+async fn message_groups_lease_one_at_a_time_in_order() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let cfg = test_config(&dir);
+    let pool = init_pool(&cfg).await?;
+    let _q = create_queue(&pool, "q13", 5).await?;
+    let group = |g: &str| EnqueueOptions {
+        group_id: Some(g.to_string()),
+        ..EnqueueOptions::default()
+    };
+
+    let a1 = enqueue_message_with(&pool, "q13", &json!({"a":1}), &group("a"))
+        .await?;
+    let a2 = enqueue_message_with(&pool, "q13", &json!({"a":2}), &group("a"))
+        .await?;
+    let b1 = enqueue_message_with(&pool, "q13", &json!({"b":1}), &group("b"))
+        .await?;
+    let u = enqueue_message(&pool, "q13", &json!({"u":1}), 0).await?;
+
+    // One message per group, plus ungrouped messages
+    let leased = poll_messages(&pool, "q13", 10, 5000).await?;
+    let ids: Vec<i64> = leased.iter().map(|m| m.id).collect();
+    assert_eq!(ids, vec![a1.id, b1.id, u.id]);
+    assert_eq!(leased[0].group_id.as_deref(), Some("a"));
+
+    // The rest of group "a" waits until its head is acked
+    assert!(poll_messages(&pool, "q13", 10, 5000).await?.is_empty());
+    let token = leased[0].lease_token.clone().unwrap();
+    ack_messages(&pool, &[a1.id], &token).await?;
+    let next = poll_messages(&pool, "q13", 10, 5000).await?;
+    assert_eq!(next.len(), 1);
+    assert_eq!(next[0].id, a2.id);
+    Ok(())
+}
+
+#[tokio::test]
+async fn strict_fifo_leases_only_the_head() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let cfg = test_config(&dir);
+    let pool = init_pool(&cfg).await?;
+    let opts = QueueOptions {
+        max_attempts: 2,
+        strict_fifo: true,
+        ..QueueOptions::default()
+    };
+    assert!(create_queue_with(&pool, "fifo", &opts).await?.strict_fifo);
+    let urgent = EnqueueOptions {
+        delay_ms: Some(0),
+        priority: 9,
+        ..EnqueueOptions::default()
+    };
+    let grouped = EnqueueOptions {
+        group_id: Some("a".to_string()),
+        ..EnqueueOptions::default()
+    };
+    let u1 = enqueue_message(&pool, "fifo", &json!({"u":1}), 0).await?;
+    let u2 =
+        enqueue_message_with(&pool, "fifo", &json!({"u":2}), &urgent).await?;
+    let a1 =
+        enqueue_message_with(&pool, "fifo", &json!({"a":1}), &grouped).await?;
+
+    // Only the head of the ungrouped messages and of group "a", whatever
+    // their priority
+    let leased = poll_messages(&pool, "fifo", 10, 5000).await?;
+    let ids: Vec<i64> = leased.iter().map(|m| m.id).collect();
+    assert_eq!(ids, vec![u1.id, a1.id]);
+    assert!(poll_messages(&pool, "fifo", 10, 5000).await?.is_empty());
+
+    // A nacked head is redelivered before anything behind it
+    let token = leased[0].lease_token.clone().unwrap();
+    nack_messages(&pool, &[u1.id], &token, 0).await?;
+    let again = poll_messages(&pool, "fifo", 10, 5000).await?;
+    assert_eq!(again.len(), 1);
+    assert_eq!(again[0].id, u1.id);
+
+    // Once dead-lettered, the next message moves up
+    let token = again[0].lease_token.clone().unwrap();
+    assert_eq!(nack_messages(&pool, &[u1.id], &token, 0).await?, (0, 1));
+    let next = poll_messages(&pool, "fifo", 10, 5000).await?;
+    assert_eq!(next.len(), 1);
+    assert_eq!(next[0].id, u2.id);
+
+    let update =
+        QueueUpdate { strict_fifo: Some(false), ..QueueUpdate::default() };
+    assert!(!update_queue(&pool, "fifo", &update).await?.strict_fifo);
+    Ok(())
+}
+
+#[tokio::test]
+async fn fair_queue_takes_turns_between_keys() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let cfg = test_config(&dir);
+    let pool = init_pool(&cfg).await?;
+    let opts = QueueOptions { fair: true, ..QueueOptions::default() };
+    assert!(create_queue_with(&pool, "fair", &opts).await?.fair);
+    let keyed = |key: &str| EnqueueOptions {
+        fair_key: Some(key.to_string()),
+        ..EnqueueOptions::default()
+    };
+    // Tenant "a" floods the queue before "b" and unkeyed messages arrive
+    let mut a = Vec::new();
+    for i in 0..5 {
+        let m =
+            enqueue_message_with(&pool, "fair", &json!({"a":i}), &keyed("a"))
+                .await?;
+        a.push(m.id);
+    }
+    let b1 = enqueue_message_with(&pool, "fair", &json!({"b":1}), &keyed("b"))
+        .await?;
+    let b2 = enqueue_message_with(&pool, "fair", &json!({"b":2}), &keyed("b"))
+        .await?;
+    let u1 = enqueue_message(&pool, "fair", &json!({"u":1}), 0).await?;
+    assert_eq!(b1.fair_key.as_deref(), Some("b"));
+
+    // One message per key in turn, unkeyed messages first
+    let leased = poll_messages(&pool, "fair", 5, 5000).await?;
+    let ids: Vec<i64> = leased.iter().map(|m| m.id).collect();
+    assert_eq!(ids, vec![u1.id, a[0], b1.id, a[1], b2.id]);
+
+    // The next poll starts after "b", the key that got the last message
+    let more = enqueue_message(&pool, "fair", &json!({"u":2}), 0).await?;
+    let leased = poll_messages(&pool, "fair", 2, 5000).await?;
+    let ids: Vec<i64> = leased.iter().map(|m| m.id).collect();
+    assert_eq!(ids, vec![more.id, a[2]]);
+    let leased = poll_messages(&pool, "fair", 10, 5000).await?;
+    let ids: Vec<i64> = leased.iter().map(|m| m.id).collect();
+    assert_eq!(ids, vec![a[3], a[4]]);
+
+    let empty = EnqueueOptions {
+        fair_key: Some(String::new()),
+        ..EnqueueOptions::default()
+    };
+    let err = enqueue_message_with(&pool, "fair", &json!({}), &empty)
+        .await
+        .unwrap_err();
+    assert!(matches!(err, SqewError::Invalid(_)));
+
+    let update = QueueUpdate { fair: Some(false), ..QueueUpdate::default() };
+    assert!(!update_queue(&pool, "fair", &update).await?.fair);
+    Ok(())
+}
+
+#[tokio::test]
+async fn queue_ordering_picks_the_next_message() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let cfg = test_config(&dir);
+    let pool = init_pool(&cfg).await?;
+    let opts = QueueOptions {
+        ordering: QueueOrdering::SortKey,
+        ..QueueOptions::default()
+    };
+    assert_eq!(
+        create_queue_with(&pool, "sorted", &opts).await?.ordering,
+        "sort_key"
+    );
+    let keyed = |key: Option<f64>, priority: i32| EnqueueOptions {
+        sort_key: key,
+        priority,
+        ..EnqueueOptions::default()
+    };
+    let none =
+        enqueue_message_with(&pool, "sorted", &json!({}), &keyed(None, 9))
+            .await?;
+    let late =
+        enqueue_message_with(&pool, "sorted", &json!({}), &keyed(Some(2.5), 0))
+            .await?;
+    let early = enqueue_message_with(
+        &pool,
+        "sorted",
+        &json!({}),
+        &keyed(Some(-1.0), 0),
+    )
+    .await?;
+    assert_eq!(early.sort_key, Some(-1.0));
+
+    // Lowest sort key first, messages without one last whatever their priority
+    let ids = |msgs: Vec<sqew::models::Message>| -> Vec<i64> {
+        msgs.iter().map(|m| m.id).collect()
+    };
+    let order = vec![early.id, late.id, none.id];
+    assert_eq!(ids(peek_queue(&pool, "sorted", 10).await?), order);
+
+    // FIFO ignores priority and sort keys
+    let fifo = QueueUpdate {
+        ordering: Some(QueueOrdering::Fifo),
+        ..QueueUpdate::default()
+    };
+    assert_eq!(update_queue(&pool, "sorted", &fifo).await?.ordering, "fifo");
+    assert_eq!(
+        ids(peek_queue(&pool, "sorted", 10).await?),
+        vec![none.id, late.id, early.id]
+    );
+
+    let sorted = QueueUpdate {
+        ordering: Some(QueueOrdering::SortKey),
+        ..QueueUpdate::default()
+    };
+    let _q = update_queue(&pool, "sorted", &sorted).await?;
+    assert_eq!(ids(poll_messages(&pool, "sorted", 10, 5000).await?), order);
+
+    let err = enqueue_message_with(
+        &pool,
+        "sorted",
+        &json!({}),
+        &keyed(Some(f64::NAN), 0),
+    )
+    .await
+    .unwrap_err();
+    assert!(matches!(err, SqewError::Invalid(_)));
+    Ok(())
+}
+
+#[tokio::test]
+async fn acks_and_dead_letters_send_receipts_to_reply_queues()
+-> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let cfg = test_config(&dir);
+    let pool = init_pool(&cfg).await?;
+    let _q = create_queue(&pool, "jobs", 1).await?;
+    let _q = create_queue(&pool, "replies", 5).await?;
+    let reply = |to: &str| EnqueueOptions {
+        reply_to: Some(to.into()),
+        ..EnqueueOptions::default()
+    };
+    let acked = enqueue_message_with(
+        &pool,
+        "jobs",
+        &json!({"n": 1}),
+        &reply("replies"),
+    )
+    .await?;
+    assert_eq!(acked.reply_to.as_deref(), Some("replies"));
+    let token = poll_messages(&pool, "jobs", 1, 5000).await?[0]
+        .lease_token
+        .clone()
+        .unwrap();
+    assert_eq!(ack_messages(&pool, &[acked.id], &token).await?, 1);
+
+    // A receipt to a missing queue is dropped without failing the nack
+    let dead = enqueue_message_with(
+        &pool,
+        "jobs",
+        &json!({"n": 2}),
+        &reply("replies"),
+    )
+    .await?;
+    let lost =
+        enqueue_message_with(&pool, "jobs", &json!({"n": 3}), &reply("nope"))
+            .await?;
+    let token = poll_messages(&pool, "jobs", 2, 5000).await?[0]
+        .lease_token
+        .clone()
+        .unwrap();
+    assert_eq!(
+        nack_messages(&pool, &[dead.id, lost.id], &token, 0).await?,
+        (0, 2)
+    );
+
+    let receipts = peek_queue(&pool, "replies", 10).await?;
+    assert_eq!(receipts.len(), 2);
+    assert_eq!(receipts[0].trace_id, acked.trace_id);
+    let receipt: serde_json::Value =
+        serde_json::from_str(&receipts[0].payload)?;
+    assert_eq!(receipt["message_id"], acked.id);
+    assert_eq!(receipt["queue"], "jobs");
+    assert_eq!(receipt["outcome"], "acked");
+    assert_eq!(receipt["attempts"], 1);
+    assert_eq!(receipt["enqueued_at"], acked.created_at);
+    assert!(receipt["settled_at"].as_i64() >= Some(acked.created_at));
+    let receipt: serde_json::Value =
+        serde_json::from_str(&receipts[1].payload)?;
+    assert_eq!(receipt["message_id"], dead.id);
+    assert_eq!(receipt["outcome"], "dead");
+    assert_eq!(receipt["attempts"], 1);
+
+    let err = enqueue_message_with(&pool, "jobs", &json!({}), &reply(""))
+        .await
+        .unwrap_err();
+    assert!(matches!(err, SqewError::Invalid(_)));
+    Ok(())
+}
+
+#[tokio::test]
+async fn call_waits_for_the_reply_to_its_request() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let pool = init_pool(&test_config(&dir)).await?;
+    let _q = create_queue(&pool, "rpc", 1).await?;
+    let second = std::time::Duration::from_secs(1);
+
+    // A consumer answers with respond, which also acks the request
+    let responder = {
+        let pool = pool.clone();
+        tokio::spawn(async move {
+            loop {
+                if let Some(req) =
+                    poll_messages(&pool, "rpc", 1, 5000).await?.first()
+                {
+                    let n: serde_json::Value =
+                        serde_json::from_str(&req.payload).unwrap();
+                    let answer =
+                        json!({"double": n["n"].as_i64().unwrap() * 2});
+                    return respond(&pool, req, &answer).await;
+                }
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+        })
+    };
+    let reply = call(&pool, "rpc", &json!({"n": 21}), 5 * second).await?;
+    assert_eq!(reply.payload, json!({"double": 42}).to_string());
+    assert!(responder.await??);
+    assert!(peek_queue(&pool, "rpc", 10).await?.is_empty());
+    // The call's reply queue is gone with it
+    assert_eq!(list_queues(&pool).await?.len(), 1);
+
+    // A dead-lettered request fails the call without waiting out its timeout
+    let nacker = {
+        let pool = pool.clone();
+        tokio::spawn(async move {
+            loop {
+                let leased = poll_messages(&pool, "rpc", 1, 5000).await?;
+                if let Some(req) = leased.first() {
+                    let token = req.lease_token.as_deref().unwrap();
+                    return nack_messages(&pool, &[req.id], token, 0).await;
+                }
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+        })
+    };
+    let started = std::time::Instant::now();
+    let err = call(&pool, "rpc", &json!({}), 30 * second).await.unwrap_err();
+    assert!(
+        matches!(&err, SqewError::NoReply { reason, .. } if reason == "dead-lettered")
+    );
+    assert!(started.elapsed() < 5 * second);
+    assert_eq!(nacker.await??, (0, 1));
+
+    // Without a consumer the call times out; its request expires with it
+    let short = std::time::Duration::from_millis(100);
+    let err = call(&pool, "rpc", &json!({}), short).await.unwrap_err();
+    assert!(matches!(err, SqewError::NoReply { .. }));
+    assert_eq!(list_queues(&pool).await?.len(), 1);
+
+    // Only requests sent by call can be answered
+    let plain = enqueue_message(&pool, "rpc", &json!({}), 0).await?;
+    let leased = poll_messages(&pool, "rpc", 1, 5000).await?;
+    assert_eq!(leased[0].id, plain.id);
+    let err = respond(&pool, &leased[0], &json!({})).await.unwrap_err();
+    assert!(matches!(err, SqewError::Invalid(_)));
+    Ok(())
+}
+
+#[tokio::test]
+async fn schedules_enqueue_when_due() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let cfg = test_config(&dir);
+    let pool = init_pool(&cfg).await?;
+    let _q = create_queue(&pool, "q12", 5).await?;
+
+    assert!(add_schedule(&pool, "q12", "not cron", &json!({})).await.is_err());
+    assert!(
+        add_schedule(&pool, "nope", "* * * * *", &json!({})).await.is_err()
+    );
+
+    // Every second (leading seconds field), plus an hourly 5-field schedule
+    let every =
+        add_schedule(&pool, "q12", "* * * * * *", &json!({"t":1})).await?;
+    let hourly =
+        add_schedule(&pool, "q12", "0 * * * *", &json!({"t":2})).await?;
+    assert!(hourly.next_run_at > every.next_run_at);
+    assert_eq!(list_schedules(&pool, Some("q12")).await?.len(), 2);
+
+    let wait = every.next_run_at - every.created_at + 50;
+    tokio::time::sleep(std::time::Duration::from_millis(wait as u64)).await;
+    assert_eq!(run_due_schedules(&pool).await?, vec!["q12".to_string()]);
+    // Already advanced, so an immediate rerun fires nothing
+    assert!(run_due_schedules(&pool).await?.is_empty());
+    let peeked = peek_queue(&pool, "q12", 10).await?;
+    assert_eq!(peeked.len(), 1);
+    assert_eq!(peeked[0].payload, json!({"t":1}).to_string());
+
+    assert!(remove_schedule(&pool, every.id).await?);
+    assert!(!remove_schedule(&pool, every.id).await?);
+    assert_eq!(list_schedules(&pool, None).await?.len(), 1);
+    Ok(())
+}
+
+#[tokio::test]
+async fn stats_and_compact() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let cfg = test_config(&dir);
+    let pool = init_pool(&cfg).await?;
+    let _q = create_queue(&pool, "q4", 5).await?;
+    let _ = enqueue_message(&pool, "q4", &json!({"n":1}), 0).await?;
+    let _ = enqueue_message(&pool, "q4", &json!({"n":2}), 1000).await?;
+
+    // Ready should be >= 1 (first message available now)
+    let s = stats(&pool, "q4").await?;
+    assert!(s.get("ready").and_then(|v| v.as_i64()).unwrap_or(0) >= 1);
+
+    // Compact shouldn't error
+    compact(&pool).await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn stats_report_leases_delays_and_ack_latency() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let cfg = test_config(&dir);
+    let pool = init_pool(&cfg).await?;
+    let _q = create_queue(&pool, "metrics", 5).await?;
+    let s = stats(&pool, "metrics").await?;
+    assert_eq!(s["enqueued"], 0);
+    assert!(s["oldest_ready_age_ms"].is_null());
+    assert!(s["avg_ack_ms"].is_null());
+
+    for n in 0..3 {
+        let _ = enqueue_message(&pool, "metrics", &json!({"n":n}), 0).await?;
+    }
+    let _ = enqueue_message(&pool, "metrics", &json!({"n":3}), 60_000).await?;
+    tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    let s = stats(&pool, "metrics").await?;
+    assert_eq!(s["ready"], 3);
+    assert_eq!(s["delayed"], 1);
+    assert_eq!(s["enqueued"], 4);
+    assert!(s["oldest_ready_age_ms"].as_i64().unwrap() >= 20);
+
+    let leased = poll_messages(&pool, "metrics", 2, 30_000).await?;
+    let token = leased[0].lease_token.clone().unwrap();
+    let s = stats(&pool, "metrics").await?;
+    assert_eq!(s["ready"], 1);
+    assert_eq!(s["leased"], 2);
+
+    let ids: Vec<i64> = leased.iter().map(|m| m.id).collect();
+    assert_eq!(ack_messages(&pool, &ids, &token).await?, 2);
+    // A repeated ack is not counted twice
+    assert_eq!(ack_messages(&pool, &ids, &token).await?, 0);
+    let s = stats(&pool, "metrics").await?;
+    assert_eq!(s["leased"], 0);
+    assert_eq!(s["acked"], 2);
+    assert_eq!(s["enqueued"], 4);
+    assert!(s["avg_ack_ms"].as_i64().unwrap() >= 20);
+    Ok(())
+}
+
+#[tokio::test]
+async fn retention_archives_acked_messages() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let cfg = test_config(&dir);
+    let pool = init_pool(&cfg).await?;
+    let keep =
+        QueueOptions { retention_days: Some(7), ..QueueOptions::default() };
+    let _kept = create_queue_with(&pool, "q14", &keep).await?;
+    let zero =
+        QueueOptions { retention_days: Some(0), ..QueueOptions::default() };
+    let _brief = create_queue_with(&pool, "q15", &zero).await?;
+    let _plain = create_queue(&pool, "q16", 5).await?;
+
+    for name in ["q14", "q15", "q16"] {
+        let m = enqueue_message(&pool, name, &json!({"q":name}), 0).await?;
+        let token = poll_messages(&pool, name, 1, 1000).await?[0]
+            .lease_token
+            .clone()
+            .unwrap();
+        assert_eq!(ack_messages(&pool, &[m.id], &token).await?, 1);
+        assert!(get_message_by_id(&pool, m.id).await.is_err());
+    }
+
+    let history = message_history(&pool, "q14", 10).await?;
+    assert_eq!(history.len(), 1);
+    assert_eq!(history[0].payload, json!({"q":"q14"}).to_string());
+    assert_eq!(history[0].purge_at - history[0].acked_at, 7 * 86_400_000);
+    assert!(message_history(&pool, "q16", 10).await?.is_empty());
+    assert!(message_history(&pool, "nope", 10).await.is_err());
+
+    // Only the zero-day archive has expired
+    assert_eq!(purge_archives(&pool).await?, 1);
+    assert!(message_history(&pool, "q15", 10).await?.is_empty());
+    assert_eq!(message_history(&pool, "q14", 10).await?.len(), 1);
+    Ok(())
+}
+
+#[tokio::test]
+async fn replay_reenqueues_archived_messages() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let cfg = test_config(&dir);
+    let pool = init_pool(&cfg).await?;
+    let keep =
+        QueueOptions { retention_days: Some(7), ..QueueOptions::default() };
+    let _q = create_queue_with(&pool, "replay", &keep).await?;
+
+    let mut acked_at = Vec::new();
+    for kind in ["a", "b", "a"] {
+        let m =
+            enqueue_message(&pool, "replay", &json!({"kind": kind}), 0).await?;
+        let token = poll_messages(&pool, "replay", 1, 1000).await?[0]
+            .lease_token
+            .clone()
+            .unwrap();
+        ack_messages(&pool, &[m.id], &token).await?;
+        acked_at.push(message_history(&pool, "replay", 1).await?[0].acked_at);
+        tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+    }
+
+    // The range is half-open: from the second ack up to the third
+    let n = replay_messages(
+        &pool,
+        "replay",
+        Some(acked_at[1]),
+        Some(acked_at[2]),
+        None,
+    )
+    .await?;
+    assert_eq!(n, 1);
+    let msgs = poll_messages(&pool, "replay", 10, 1000).await?;
+    assert_eq!(msgs.len(), 1);
+    assert_eq!(msgs[0].payload, json!({"kind": "b"}).to_string());
+    assert_eq!(msgs[0].attempts, 0);
+    ack_messages(&pool, &[msgs[0].id], msgs[0].lease_token.as_ref().unwrap())
+        .await?;
+
+    // A payload filter, and the archive keeps what was replayed
+    let n = replay_messages(&pool, "replay", None, None, Some("\"a\"")).await?;
+    assert_eq!(n, 2);
+    assert_eq!(message_history(&pool, "replay", 10).await?.len(), 4);
+    assert_eq!(stats(&pool, "replay").await?["enqueued"], 6);
+
+    assert!(replay_messages(&pool, "nope", None, None, None).await.is_err());
+    let err = replay_messages(&pool, "replay", Some(2), Some(1), None)
+        .await
+        .unwrap_err();
+    assert!(err.to_string().starts_with("Invalid"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn stats_history_keeps_snapshots() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let pool = init_pool(&test_config(&dir)).await?;
+    let _a = create_queue(&pool, "a", 1).await?;
+    let _b = create_queue(&pool, "b", 5).await?;
+    let m = enqueue_message(&pool, "a", &json!({"n": 1}), 0).await?;
+    enqueue_message(&pool, "a", &json!({"n": 2}), 0).await?;
+    assert_eq!(record_stats_history(&pool).await?, 2);
+
+    let token = poll_messages(&pool, "a", 1, 5000).await?[0]
+        .lease_token
+        .clone()
+        .unwrap();
+    nack_messages(&pool, &[m.id], &token, 0).await?;
+    record_stats_history(&pool).await?;
+
+    let samples = stats_history(&pool, "a", parse_window("1h")?).await?;
+    assert_eq!(samples.len(), 2);
+    assert_eq!((samples[0].ready, samples[0].dlq), (2, 0));
+    assert_eq!((samples[1].ready, samples[1].dlq), (1, 1));
+    assert_eq!(samples[1].enqueued, 2);
+    assert!(samples[0].recorded_at <= samples[1].recorded_at);
+    assert_eq!(stats_history(&pool, "b", 60_000).await?.len(), 2);
+
+    assert_eq!(parse_window("90s")?, 90_000);
+    assert_eq!(parse_window("7d")?, 7 * 86_400_000);
+    for bad in ["", "h", "10", "-1h", "0m", "1w"] {
+        assert!(parse_window(bad).is_err(), "{bad}");
+    }
+    let err = stats_history(&pool, "missing", 60_000).await.unwrap_err();
+    assert!(matches!(err, SqewError::QueueNotFound(_)));
+    Ok(())
+}
+
+#[tokio::test]
+async fn batch_ack_nack_report_each_message() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let pool = init_pool(&test_config(&dir)).await?;
+    let _q = create_queue(&pool, "batch", 1).await?;
+    for n in 0..3 {
+        enqueue_message(&pool, "batch", &json!({"n": n}), 0).await?;
+    }
+    let leased = poll_messages(&pool, "batch", 3, 5000).await?;
+    let token = leased[0].lease_token.clone().unwrap();
+    let ids: Vec<i64> = leased.iter().map(|m| m.id).collect();
+
+    let results = ack_batch(&pool, &[ids[0], 999_999], &token).await?;
+    assert_eq!(
+        results,
+        vec![
+            AckResult { id: ids[0], status: AckStatus::Acked },
+            AckResult { id: 999_999, status: AckStatus::NotFound },
+        ]
+    );
+    let results = ack_batch(&pool, &[ids[1]], "other").await?;
+    assert_eq!(results[0].status, AckStatus::LeaseMismatch);
+
+    // max_attempts is 1, so a nack dead-letters
+    let results =
+        nack_batch(&pool, &[(ids[1], 0), (ids[0], 0)], &token).await?;
+    assert_eq!(
+        results,
+        vec![
+            AckResult { id: ids[1], status: AckStatus::DeadLettered },
+            AckResult { id: ids[0], status: AckStatus::NotFound },
+        ]
+    );
+
+    let too_many = vec![ids[2]; MAX_ACK_BATCH + 1];
+    let err = ack_batch(&pool, &too_many, &token).await.unwrap_err();
+    assert!(matches!(err, SqewError::Invalid(_)));
+    assert_eq!(ack_messages(&pool, &[ids[2]], &token).await?, 1);
+    Ok(())
+}
+
+#[tokio::test]
+async fn nack_backoff_grows_per_attempt() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let cfg = test_config(&dir);
+    let pool = init_pool(&cfg).await?;
+    let opts = QueueOptions {
+        max_attempts: 10,
+        backoff_base_ms: Some(20),
+        backoff_multiplier: 3.0,
+        backoff_max_ms: Some(100),
+        ..QueueOptions::default()
+    };
+    let _q = create_queue_with(&pool, "q17", &opts).await?;
+    let m = enqueue_message(&pool, "q17", &json!({"n":1}), 0).await?;
+
+    // 20ms, 60ms, then capped at 100ms; the requested delay is ignored
+    for expected in [20, 60, 100] {
+        let leased = poll_messages(&pool, "q17", 1, 5000).await?;
+        let token = leased[0].lease_token.clone().unwrap();
+        let before = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)?
+            .as_millis() as i64;
+        assert_eq!(nack_messages(&pool, &[m.id], &token, 0).await?, (1, 0));
+        let delay = get_message_by_id(&pool, m.id).await?.available_at - before;
+        assert!((expected..expected + 50).contains(&delay), "delay {delay}");
+        assert!(poll_messages(&pool, "q17", 1, 5000).await?.is_empty());
+        let wait = std::time::Duration::from_millis(expected as u64 + 10);
+        tokio::time::sleep(wait).await;
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn legacy_database_is_migrated() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let cfg = Config { force_recreate: false, ..test_config(&dir) };
+
+    // A database in the original, unversioned format with one message
+    let url = format!("sqlite://{}?mode=rwc", cfg.db_path.display());
+    let old = sqlx::SqlitePool::connect(&url).await?;
+    sqlx::raw_sql(
+        "CREATE TABLE queue (
+           id INTEGER PRIMARY KEY, name TEXT UNIQUE NOT NULL,
+           max_attempts INTEGER NOT NULL DEFAULT 5);
+         CREATE TABLE message (
+           id INTEGER PRIMARY KEY,
+           queue_id INTEGER NOT NULL REFERENCES queue(id) ON DELETE CASCADE,
+           payload TEXT NOT NULL, attempts INTEGER NOT NULL DEFAULT 0,
+           available_at INTEGER NOT NULL, created_at INTEGER NOT NULL);
+         CREATE INDEX ix_msg_visible ON message(queue_id, available_at);
+         INSERT INTO queue (name, max_attempts) VALUES ('legacy', 3);
+         INSERT INTO message (queue_id, payload, available_at, created_at)
+           VALUES (1, '{\"n\":1}', 0, 0);",
+    )
+    .execute(&old)
+    .await?;
+    old.close().await;
+
+    let pool = init_pool(&cfg).await?;
+    assert_eq!(pool.schema_version().await?, 30);
+    let q = show_queue(&pool, "legacy").await?;
+    assert_eq!(q.max_attempts, 3);
+    assert_eq!(q.dedup_window_ms, sqew::db::DEFAULT_DEDUP_WINDOW_MS);
+    assert_eq!(q.default_visibility_ms, sqew::db::DEFAULT_VISIBILITY_MS);
+    assert_eq!(q.default_delay_ms, 0);
+    let leased = poll_messages(&pool, "legacy", 10, 1000).await?;
+    assert_eq!(leased.len(), 1);
+    assert_eq!(leased[0].priority, 0);
+
+    // Reopening finds nothing left to apply
+    let one = PoolOptions { max_connections: 1, ..PoolOptions::default() };
+    let storage = SqliteStorage::open(&cfg.db_path, false, &one).await?;
+    assert!(sqew::db::sqlite::migrate(storage.pool()).await?.is_empty());
+    Ok(())
+}
+
+#[tokio::test]
+async fn headers_round_trip_and_filter_peek() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let cfg = test_config(&dir);
+    let pool = init_pool(&cfg).await?;
+    let _q = create_queue(&pool, "q18", 5).await?;
+    let with_headers = |pairs: &[(&str, &str)]| EnqueueOptions {
+        headers: Some(
+            pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(),
+        ),
+        ..EnqueueOptions::default()
+    };
+
+    let json_msg = enqueue_message_with(
+        &pool,
+        "q18",
+        &json!({"n":1}),
+        &with_headers(&[("content_type", "json"), ("correlation_id", "c-1")]),
+    )
+    .await?;
+    let _xml = enqueue_message_with(
+        &pool,
+        "q18",
+        &json!({"n":2}),
+        &with_headers(&[("content_type", "xml")]),
+    )
+    .await?;
+    let _plain = enqueue_message(&pool, "q18", &json!({"n":3}), 0).await?;
+
+    let filtered =
+        peek_queue_with(&pool, "q18", 10, Some(("content_type", "json")))
+            .await?;
+    assert_eq!(filtered.len(), 1);
+    assert_eq!(filtered[0].id, json_msg.id);
+    let headers = filtered[0].headers.clone().unwrap();
+    assert_eq!(headers["correlation_id"], "c-1");
+    assert!(
+        peek_queue_with(&pool, "q18", 10, Some(("missing", "x")))
+            .await?
+            .is_empty()
+    );
+
+    // Headers come back on poll; messages without any have none
+    let polled = poll_messages(&pool, "q18", 10, 1000).await?;
+    assert_eq!(polled[0].headers, json_msg.headers);
+    assert!(polled[2].headers.is_none());
+    Ok(())
+}
+
+#[tokio::test]
+async fn update_queue_changes_settings_in_place() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let pool = init_pool(&test_config(&dir)).await?;
+    let opts = QueueOptions {
+        retention_days: Some(7),
+        backoff_base_ms: Some(100),
+        ..QueueOptions::default()
+    };
+    let q = create_queue_with(&pool, "q19", &opts).await?;
+    let m = enqueue_message(&pool, "q19", &json!({"n":1}), 0).await?;
+
+    let update = QueueUpdate {
+        max_attempts: Some(9),
+        retention_days: Some(None),
+        default_delay_ms: Some(250),
+        ..QueueUpdate::default()
+    };
+    let updated = update_queue(&pool, "q19", &update).await?;
+    assert_eq!(updated.id, q.id);
+    assert_eq!(updated.max_attempts, 9);
+    assert_eq!(updated.retention_days, None);
+    assert_eq!(updated.default_delay_ms, 250);
+    // Untouched settings and existing messages are kept
+    assert_eq!(updated.backoff_base_ms, Some(100));
+    assert_eq!(get_message_by_id(&pool, m.id).await?.queue_id, q.id);
+
+    // Invalid values are rejected without changing anything
+    let bad = QueueUpdate {
+        max_attempts: Some(0),
+        backoff_jitter: Some(0.5),
+        ..QueueUpdate::default()
+    };
+    let err = update_queue(&pool, "q19", &bad).await.unwrap_err();
+    assert!(err.to_string().contains("max_attempts"));
+    let q = show_queue(&pool, "q19").await?;
+    assert_eq!(q.max_attempts, 9);
+    assert_eq!(q.backoff_jitter, 0.0);
+    assert!(update_queue(&pool, "missing", &update).await.is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn move_messages_between_queues() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let pool = init_pool(&test_config(&dir)).await?;
+    let src = create_queue(&pool, "src", 1).await?;
+    let dst = create_queue(&pool, "dst", 5).await?;
+    let keyed = EnqueueOptions {
+        dedup_key: Some("k".into()),
+        ..EnqueueOptions::default()
+    };
+    let dead =
+        enqueue_message_with(&pool, "src", &json!({"n":1}), &keyed).await?;
+    let token = poll_messages(&pool, "src", 1, 5000).await?[0]
+        .lease_token
+        .clone()
+        .unwrap();
+    assert_eq!(nack_messages(&pool, &[dead.id], &token, 0).await?, (0, 1));
+    let leased = enqueue_message(&pool, "src", &json!({"n":2}), 0).await?;
+    let _ = poll_messages(&pool, "src", 1, 60_000).await?;
+    let live = enqueue_message(&pool, "src", &json!({"n":3}), 0).await?;
+
+    // The leased message stays; a wrong source queue matches nothing
+    let ids = [dead.id, live.id, leased.id];
+    assert_eq!(move_messages(&pool, &ids, "dst", Some("dst"), false).await?, 0);
+    assert_eq!(move_messages(&pool, &ids, "dst", Some("src"), true).await?, 2);
+    let moved = get_message_by_id(&pool, dead.id).await?;
+    assert_eq!(moved.queue_id, dst.id);
+    assert_eq!((moved.attempts, moved.dead_at), (0, None));
+    assert_eq!(moved.dedup_key, None);
+    assert_eq!(get_message_by_id(&pool, leased.id).await?.queue_id, src.id);
+    assert!(list_dead_letters(&pool, "src", 10).await?.is_empty());
+    assert_eq!(poll_messages(&pool, "dst", 10, 5000).await?.len(), 2);
+    assert!(move_messages(&pool, &ids, "missing", None, false).await.is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn peek_pages_and_filters() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let pool = init_pool(&test_config(&dir)).await?;
+    let _q = create_queue(&pool, "q21", 5).await?;
+    let mut ids = Vec::new();
+    for n in 0..5 {
+        let kind = if n % 2 == 0 { "email" } else { "sms" };
+        let payload = json!({"n": n, "kind": kind, "to": {"id": n * 10}});
+        ids.push(enqueue_message(&pool, "q21", &payload, 0).await?.id);
+        tokio::time::sleep(std::time::Duration::from_millis(2)).await;
+    }
+    let urgent = EnqueueOptions { priority: 9, ..EnqueueOptions::default() };
+    let top =
+        enqueue_message_with(&pool, "q21", &json!({"n": 5}), &urgent).await?;
+    let peek = |filter: PeekFilter| {
+        let pool = pool.clone();
+        async move {
+            let msgs = peek_queue_filtered(&pool, "q21", 10, &filter).await?;
+            anyhow::Ok(msgs.into_iter().map(|m| m.id).collect::<Vec<_>>())
+        }
+    };
+
+    // Offset pages in delivery order; a cursor pages in id order
+    let page = peek(PeekFilter { offset: 1, ..PeekFilter::default() }).await?;
+    assert_eq!(page, ids);
+    let after = PeekFilter { after_id: Some(ids[2]), ..PeekFilter::default() };
+    assert_eq!(peek(after).await?, vec![ids[3], ids[4], top.id]);
+
+    // Creation time window
+    let first = get_message_by_id(&pool, ids[1]).await?.created_at;
+    let last = get_message_by_id(&pool, ids[3]).await?.created_at;
+    let window = PeekFilter {
+        created_after: Some(first),
+        created_before: Some(last),
+        ..PeekFilter::default()
+    };
+    assert_eq!(peek(window).await?, vec![ids[1], ids[2]]);
+
+    // Payload substring and JSON path
+    let sms = PeekFilter {
+        payload_contains: Some("\"sms\"".into()),
+        ..PeekFilter::default()
+    };
+    assert_eq!(peek(sms).await?, vec![ids[1], ids[3]]);
+    let nested = PeekFilter {
+        json_path: Some(("$.to.id".into(), "40".into())),
+        ..PeekFilter::default()
+    };
+    assert_eq!(peek(nested).await?, vec![ids[4]]);
+    let bad = PeekFilter {
+        json_path: Some(("to.id".into(), "40".into())),
+        ..PeekFilter::default()
+    };
+    assert!(peek(bad).await.is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn peek_tells_ready_delayed_and_leased_messages_apart()
+-> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let pool = init_pool(&test_config(&dir)).await?;
+    let _q = create_queue(&pool, "states", 5).await?;
+    let a = enqueue_message(&pool, "states", &json!({"n": 1}), 0).await?;
+    let b = enqueue_message(&pool, "states", &json!({"n": 2}), 60_000).await?;
+    let c = enqueue_message(&pool, "states", &json!({"n": 3}), 0).await?;
+    let leased = poll_messages(&pool, "states", 1, 60_000).await?;
+    assert_eq!(leased[0].id, a.id);
+    let peek = |state: MessageState| {
+        let pool = pool.clone();
+        async move {
+            let filter = PeekFilter { state, ..PeekFilter::default() };
+            let msgs =
+                peek_queue_filtered(&pool, "states", 10, &filter).await?;
+            anyhow::Ok(msgs.into_iter().map(|m| m.id).collect::<Vec<_>>())
+        }
+    };
+    assert_eq!(peek(MessageState::Ready).await?, vec![c.id]);
+    assert_eq!(peek(MessageState::Delayed).await?, vec![b.id]);
+    assert_eq!(peek(MessageState::Leased).await?, vec![a.id]);
+    assert_eq!(peek(MessageState::All).await?.len(), 3);
+
+    // A nacked message backing off is delayed, no longer leased
+    let token = leased[0].lease_token.clone().unwrap_or_default();
+    assert_eq!(nack_messages(&pool, &[a.id], &token, 120_000).await?, (1, 0));
+    assert_eq!(peek(MessageState::Delayed).await?, vec![b.id, a.id]);
+    assert!(peek(MessageState::Leased).await?.is_empty());
+    Ok(())
+}
+
+#[tokio::test]
+async fn public_ids_name_messages_like_their_ids() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let pool = init_pool(&test_config(&dir)).await?;
+    let _q = create_queue(&pool, "public", 5).await?;
+    let a = enqueue_message(&pool, "public", &json!({"n": 1}), 0).await?;
+    let b = enqueue_message(&pool, "public", &json!({"n": 2}), 0).await?;
+    assert_ne!(a.public_id, b.public_id);
+    let parsed: MessageRef = a.public_id.parse().map_err(anyhow::Error::msg)?;
+    assert_eq!(parsed, MessageRef::Public(a.public_id.clone()));
+    assert_eq!("42".parse::<MessageRef>(), Ok(MessageRef::Id(42)));
+    assert!("not-an-id".parse::<MessageRef>().is_err());
+
+    // Reads and leases carry the id given at enqueue
+    let leased = poll_messages(&pool, "public", 1, 60_000).await?;
+    assert_eq!(leased[0].public_id, a.public_id);
+    assert_eq!(get_message_by_id(&pool, b.id).await?.public_id, b.public_id);
+    let held = in_flight(&pool, "public", 10).await?;
+    assert_eq!(held[0].public_id, a.public_id);
+
+    // Either id resolves, in order; an upper-case public id too
+    let refs =
+        [MessageRef::Public(b.public_id.to_uppercase()), MessageRef::Id(a.id)];
+    assert_eq!(resolve_message_ids(&pool, &refs).await?, vec![b.id, a.id]);
+    let token = leased[0].lease_token.clone().unwrap_or_default();
+    let id = resolve_message_id(&pool, &parsed).await?;
+    assert_eq!(ack_messages(&pool, &[id], &token).await?, 1);
+
+    // An acked message's public id no longer names anything
+    assert!(matches!(
+        resolve_message_id(&pool, &parsed).await,
+        Err(SqewError::PublicIdNotFound(p)) if p == a.public_id
+    ));
+
+    // Copies made in SQL get ids of their own
+    let (_clone, copied) = clone_queue(&pool, "public", "copy", true).await?;
+    assert_eq!(copied, 1);
+    let copy = peek_queue(&pool, "copy", 1).await?;
+    assert!(!copy[0].public_id.is_empty());
+    assert_ne!(copy[0].public_id, b.public_id);
+    Ok(())
+}
+
+#[tokio::test]
+async fn search_finds_leased_and_dead_messages_by_path() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let pool = init_pool(&test_config(&dir)).await?;
+    let _q = create_queue(&pool, "orders", 1).await?;
+    let mut ids = Vec::new();
+    for n in 0..4 {
+        let payload = json!({"order": {"id": 100 + n % 2, "n": n}});
+        ids.push(enqueue_message(&pool, "orders", &payload, 0).await?.id);
+    }
+    let _other = enqueue_message(&pool, "orders", &json!({"k": 1}), 0).await?;
+    let search = |path: &'static str, value: Option<&'static str>, after| {
+        let pool = pool.clone();
+        async move {
+            let msgs =
+                search_messages(&pool, "orders", path, value, after, 2).await?;
+            anyhow::Ok(msgs.into_iter().map(|m| m.id).collect::<Vec<_>>())
+        }
+    };
+
+    // Leased and dead-lettered messages are still found
+    let leased = poll_messages(&pool, "orders", 1, 60_000).await?;
+    assert_eq!(leased[0].id, ids[0]);
+    let token = leased[0].lease_token.clone().unwrap();
+    nack_messages(&pool, &[ids[0]], &token, 0).await?;
+    assert!(get_message_by_id(&pool, ids[0]).await?.dead_at.is_some());
+    let _leased = poll_messages(&pool, "orders", 1, 60_000).await?;
+    assert_eq!(
+        search("$.order.id", Some("100"), None).await?,
+        [ids[0], ids[2]]
+    );
+
+    // Without a value any message with the path matches; pages by cursor
+    assert_eq!(search("$.order", None, None).await?, [ids[0], ids[1]]);
+    assert_eq!(search("$.order", None, Some(ids[1])).await?, [ids[2], ids[3]]);
+    assert!(search("$.order", None, Some(ids[3])).await?.is_empty());
+
+    // Malformed paths and unknown queues are rejected
+    let err = search("order.id", None, None).await.unwrap_err();
+    assert!(err.to_string().starts_with("Invalid JSON path"));
+    for path in ["$.[", "$.order[*]", "$.order.id ? (@ > 1)", "$.\"id"] {
+        let err = search(path, None, None).await.unwrap_err();
+        assert!(err.to_string().starts_with("Invalid JSON path"), "{err}");
+    }
+    assert_eq!(
+        search("$.\"order\".id", Some("101"), None).await?,
+        [ids[1], ids[3]]
+    );
+    let err =
+        search_messages(&pool, "nope", "$.a", None, None, 5).await.unwrap_err();
+    assert!(err.to_string().contains("not found"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn in_memory_database_is_shared_by_the_pool() -> anyhow::Result<()> {
+    let cfg = Config {
+        db_path: sqew::db::sqlite::MEMORY_PATH.into(),
+        pool_size: 4,
+        ..Config::default()
+    };
+    let pool = init_pool(&cfg).await?;
+    let _q = create_queue(&pool, "mem", 5).await?;
+    let mut tasks = tokio::task::JoinSet::new();
+    for n in 0..20 {
+        let pool = pool.clone();
+        tasks.spawn(async move {
+            enqueue_message(&pool, "mem", &json!({ "n": n }), 0).await
+        });
+    }
+    while let Some(res) = tasks.join_next().await {
+        res??;
+    }
+    assert_eq!(poll_messages(&pool, "mem", 50, 5000).await?.len(), 20);
+
+    // Every pool gets its own database
+    let other = init_pool(&cfg).await?;
+    assert!(list_queues(&other).await?.is_empty());
+    Ok(())
+}
+
+#[tokio::test]
+async fn large_payloads_are_stored_compressed() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let plain = Config { compress_threshold: 0, ..test_config(&dir) };
+    let pool = init_pool(&plain).await?;
+    let keep =
+        QueueOptions { retention_days: Some(0), ..QueueOptions::default() };
+    let _q = create_queue_with(&pool, "big", &keep).await?;
+    let payload = json!({ "pad": "x".repeat(20_000) });
+    let old = enqueue_message(&pool, "big", &payload, 0).await?;
+
+    // Reopened with compression, new large payloads are compressed on write
+    // and old ones only by recompression
+    let cfg = Config { force_recreate: false, ..test_config(&dir) };
+    let pool = init_pool(&cfg).await?;
+    let new = enqueue_message(&pool, "big", &payload, 0).await?;
+    let _small = enqueue_message(&pool, "big", &json!({"n":1}), 0).await?;
+    let raw = sqlx::SqlitePool::connect(&format!(
+        "sqlite://{}",
+        cfg.db_path.display()
+    ))
+    .await?;
+    let encodings: Vec<(i64, Option<String>, i64)> = sqlx::query_as(
+        "SELECT id, payload_encoding, length(payload) FROM message ORDER BY id",
+    )
+    .fetch_all(&raw)
+    .await?;
+    assert_eq!(encodings[0].1, None);
+    assert_eq!(encodings[1].1.as_deref(), Some("zstd"));
+    assert!(encodings[1].2 < 1_000);
+    assert_eq!(encodings[2].1, None);
+    assert_eq!(recompress_payloads(&pool).await?, 1);
+    assert_eq!(recompress_payloads(&pool).await?, 0);
+
+    // Every read path returns the original payload
+    let text = payload.to_string();
+    assert_eq!(get_message_by_id(&pool, old.id).await?.payload, text);
+    let peeked = peek_queue(&pool, "big", 10).await?;
+    assert_eq!(peeked[1].payload, text);
+    let leased = poll_messages(&pool, "big", 2, 5000).await?;
+    assert_eq!(leased[1].id, new.id);
+    assert_eq!(leased[1].payload, text);
+    let token = leased[0].lease_token.clone().unwrap();
+    assert_eq!(ack_messages(&pool, &[old.id, new.id], &token).await?, 2);
+    let history = message_history(&pool, "big", 10).await?;
+    assert_eq!(history.len(), 2);
+    assert!(history.iter().all(|m| m.payload == text));
+    compact(&pool).await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn payloads_are_encrypted_at_rest_and_keys_rotate() -> anyhow::Result<()>
+{
+    let dir = tempfile::tempdir()?;
+    let (k1, k2) = ("11".repeat(32), "22".repeat(32));
+    let keep =
+        QueueOptions { retention_days: Some(1), ..QueueOptions::default() };
+    let plain = init_pool(&test_config(&dir)).await?;
+    let _q = create_queue_with(&plain, "secret", &keep).await?;
+    let old =
+        enqueue_message(&plain, "secret", &json!({"card": "4111"}), 0).await?;
+
+    // With a key, new payloads (small, and large ones compressed first) are
+    // stored encrypted and read back transparently
+    let keyed = |spec: &str| Config {
+        force_recreate: false,
+        compress_threshold: 64,
+        encryption_keys: Some(Arc::new(Keyring::parse(spec).unwrap())),
+        ..test_config(&dir)
+    };
+    let pool = init_pool(&keyed(&k1)).await?;
+    let small = json!({"card": "5500"});
+    let large = json!({"card": "3400", "pad": "x".repeat(1_000)});
+    let a = enqueue_message(&pool, "secret", &small, 0).await?;
+    let b = enqueue_message(&pool, "secret", &large, 0).await?;
+    let raw = pool.as_sqlite().expect("sqlite backend").pool().clone();
+    let stored = |raw: sqlx::SqlitePool| async move {
+        let rows: Vec<(Option<String>, Option<String>, Vec<u8>)> =
+            sqlx::query_as(
+                "SELECT payload_encoding, payload_key_id,
+                        CAST(payload AS BLOB)
+                 FROM message ORDER BY id",
+            )
+            .fetch_all(&raw)
+            .await?;
+        anyhow::Ok(rows)
+    };
+    let rows = stored(raw.clone()).await?;
+    let k1_id = Keyring::parse(&k1)?.active_id().to_string();
+    assert_eq!(rows[0].0, None);
+    assert_eq!(rows[1].0.as_deref(), Some("aes-gcm"));
+    assert_eq!(rows[2].0.as_deref(), Some("zstd+aes-gcm"));
+    assert_eq!(rows[1].1.as_deref(), Some(k1_id.as_str()));
+    assert!(!String::from_utf8_lossy(&rows[1].2).contains("5500"));
+    assert_eq!(
+        get_message_by_id(&pool, a.id).await?.payload,
+        small.to_string()
+    );
+    let peeked = peek_queue(&pool, "secret", 10).await?;
+    assert_eq!(peeked[2].payload, large.to_string());
+    assert_eq!(
+        get_message_by_id(&pool, b.id).await?.payload,
+        large.to_string()
+    );
+
+    // Without the key encrypted payloads cannot be read
+    let cfg = Config { force_recreate: false, ..test_config(&dir) };
+    let keyless = init_pool(&cfg).await?;
+    assert!(get_message_by_id(&keyless, a.id).await.is_err());
+
+    // Rotation re-encrypts the plain payload and those under the retired
+    // key, including archived ones; afterwards the old key can be dropped
+    let leased = poll_messages(&pool, "secret", 1, 5000).await?;
+    assert_eq!(leased[0].id, old.id);
+    let token = leased[0].lease_token.clone().unwrap();
+    assert_eq!(ack_messages(&pool, &[old.id], &token).await?, 1);
+    let rotated = init_pool(&keyed(&format!("{k2},{k1}"))).await?;
+    assert_eq!(rotate_key(&rotated).await?, 3);
+    assert_eq!(rotate_key(&rotated).await?, 0);
+    let k2_id = Keyring::parse(&k2)?.active_id().to_string();
+    let rows = stored(raw).await?;
+    assert!(rows.iter().all(|r| r.1.as_deref() == Some(k2_id.as_str())));
+    let only_new = init_pool(&keyed(&k2)).await?;
+    let history = message_history(&only_new, "secret", 10).await?;
+    assert_eq!(history[0].payload, json!({"card": "4111"}).to_string());
+    assert_eq!(
+        get_message_by_id(&only_new, b.id).await?.payload,
+        large.to_string()
+    );
+    assert!(rotate_key(&keyless).await.is_err());
+
+    // Keys must be 64 hex digits and never show up in debug output
+    assert!(Keyring::parse("abc").is_err());
+    assert!(Keyring::parse(&format!("{k1},{k1}")).is_err());
+    assert!(!format!("{:?}", Keyring::parse(&k1)?).contains(&k1));
+    Ok(())
+}
+
+#[tokio::test]
+async fn backup_and_restore_round_trip() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let cfg = test_config(&dir);
+    let pool = init_pool(&cfg).await?;
+    let _q = create_queue(&pool, "snap", 5).await?;
+    let kept = enqueue_message(&pool, "snap", &json!({"n":1}), 0).await?;
+
+    // The snapshot is taken while the pool stays open
+    let backup = dir.path().join("snap.bak");
+    assert!(backup_database(&pool, &backup).await? > 0);
+    let err = backup_database(&pool, &backup).await.unwrap_err();
+    assert!(err.to_string().contains("already exists"));
+    let _later = enqueue_message(&pool, "snap", &json!({"n":2}), 0).await?;
+    drop(pool);
+
+    let version = restore_database(&cfg, &backup).await?;
+    assert_eq!(version, 30);
+    let pool =
+        init_pool(&Config { force_recreate: false, ..cfg.clone() }).await?;
+    let msgs = peek_queue(&pool, "snap", 10).await?;
+    assert_eq!(msgs.len(), 1);
+    assert_eq!(msgs[0].id, kept.id);
+
+    // Files that are not sqew databases are refused
+    let bogus = dir.path().join("bogus.bak");
+    std::fs::write(&bogus, b"not a database")?;
+    assert!(restore_database(&cfg, &bogus).await.is_err());
+    assert_eq!(peek_queue(&pool, "snap", 10).await?.len(), 1);
+    Ok(())
+}
+
+#[tokio::test]
+async fn consumer_groups_each_receive_every_message() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let pool = init_pool(&test_config(&dir)).await?;
+    let _q = create_queue(&pool, "fan", 2).await?;
+    let _a = create_consumer_group(&pool, "fan", "a").await?;
+    let _b = create_consumer_group(&pool, "fan", "b").await?;
+    let err = create_consumer_group(&pool, "fan", "a").await.unwrap_err();
+    assert!(err.to_string().contains("already exists"));
+    assert_eq!(list_consumer_groups(&pool, "fan").await?.len(), 2);
+
+    let m1 = enqueue_message(&pool, "fan", &json!({"n":1}), 0).await?;
+    let m2 = enqueue_message(&pool, "fan", &json!({"n":2}), 0).await?;
+
+    // Plain polls are refused once a queue has groups
+    let err = poll_messages(&pool, "fan", 10, 5000).await.unwrap_err();
+    assert!(err.to_string().starts_with("Invalid"));
+    assert!(poll_group_messages(&pool, "fan", "c", 1, 5000).await.is_err());
+
+    // Each group leases every message, independently of the other
+    let a = poll_group_messages(&pool, "fan", "a", 10, 5000).await?;
+    let b = poll_group_messages(&pool, "fan", "b", 10, 5000).await?;
+    let ids = |msgs: &[sqew::models::Message]| {
+        msgs.iter().map(|m| m.id).collect::<Vec<_>>()
+    };
+    assert_eq!(ids(&a), vec![m1.id, m2.id]);
+    assert_eq!(ids(&b), vec![m1.id, m2.id]);
+    assert!(poll_group_messages(&pool, "fan", "a", 10, 5000).await?.is_empty());
+    let token_a = a[0].lease_token.clone().unwrap();
+    let token_b = b[0].lease_token.clone().unwrap();
+    assert_ne!(token_a, token_b);
+    assert_eq!(extend_visibility(&pool, &[m1.id], &token_a, 1000).await?, 1);
+
+    // A message is deleted once every group has acked it
+    assert_eq!(ack_messages(&pool, &[m1.id, m2.id], &token_a).await?, 2);
+    assert_eq!(peek_queue(&pool, "fan", 10).await?.len(), 2);
+    assert_eq!(ack_messages(&pool, &[m1.id], &token_b).await?, 1);
+    assert_eq!(ids(&peek_queue(&pool, "fan", 10).await?), vec![m2.id]);
+
+    // Nacks retry per group and dead-letter at max_attempts
+    assert_eq!(nack_messages(&pool, &[m2.id], &token_b, 0).await?, (1, 0));
+    let b = poll_group_messages(&pool, "fan", "b", 10, 5000).await?;
+    assert_eq!(ids(&b), vec![m2.id]);
+    assert_eq!(b[0].attempts, 1);
+    let token_b = b[0].lease_token.clone().unwrap();
+    assert_eq!(nack_messages(&pool, &[m2.id], &token_b, 0).await?, (0, 1));
+    assert!(peek_queue(&pool, "fan", 10).await?.is_empty());
+
+    // Removing a group releases messages only it was still holding
+    let m3 = enqueue_message(&pool, "fan", &json!({"n":3}), 0).await?;
+    let a = poll_group_messages(&pool, "fan", "a", 10, 5000).await?;
+    let token_a = a[0].lease_token.clone().unwrap();
+    assert_eq!(ack_messages(&pool, &[m3.id], &token_a).await?, 1);
+    assert!(delete_consumer_group(&pool, "fan", "b").await?);
+    assert!(!delete_consumer_group(&pool, "fan", "b").await?);
+    assert!(peek_queue(&pool, "fan", 10).await?.is_empty());
+    Ok(())
+}
+
+#[tokio::test]
+async fn doctor_finds_and_fixes_inconsistent_rows() -> anyhow::Result<()> {
+    use sqlx::Connection;
+    let dir = tempfile::tempdir()?;
+    let cfg = test_config(&dir);
+    let pool = init_pool(&cfg).await?;
+    let _q = create_queue(&pool, "doc", 5).await?;
+    let future = enqueue_message(&pool, "doc", &json!({"n":1}), 0).await?;
+    let dead = enqueue_message(&pool, "doc", &json!({"n":2}), 0).await?;
+    let report = doctor(&pool, false).await?;
+    assert_eq!(report.problems(), 0);
+
+    // Edits made with foreign keys off, as a manual repair might
+    let url = format!("sqlite://{}", cfg.db_path.display());
+    let mut raw = sqlx::SqliteConnection::connect(&url).await?;
+    sqlx::raw_sql("PRAGMA foreign_keys = OFF").execute(&mut raw).await?;
+    sqlx::query(
+        "INSERT INTO message (queue_id, payload, attempts, available_at, created_at)
+         VALUES (999, '{}', 0, 0, 0)",
+    )
+    .execute(&mut raw)
+    .await?;
+    sqlx::query(
+        "UPDATE message SET created_at = created_at + 1e12 WHERE id = ?",
+    )
+    .bind(future.id)
+    .execute(&mut raw)
+    .await?;
+    sqlx::query(
+        "UPDATE message SET dead_at = available_at, lease_token = 'x' WHERE id = ?",
+    )
+    .bind(dead.id)
+    .execute(&mut raw)
+    .await?;
+    sqlx::query("UPDATE queue SET message_count = 42 WHERE name = 'doc'")
+        .execute(&mut raw)
+        .await?;
+    raw.close().await?;
+
+    let report = doctor(&pool, false).await?;
+    assert!(report.integrity_errors.is_empty());
+    assert_eq!(
+        (
+            report.orphaned_rows,
+            report.stuck_leases,
+            report.impossible_timestamps,
+            report.drifted_counters
+        ),
+        (1, 1, 1, 1)
+    );
+    assert!(!report.fixed);
+    let report = doctor(&pool, true).await?;
+    assert_eq!(report.problems(), 4);
+    assert!(report.fixed);
+    assert_eq!(doctor(&pool, false).await?.problems(), 0);
+    assert_eq!(peek_queue(&pool, "doc", 10).await?[0].id, future.id);
+    assert_eq!(list_dead_letters(&pool, "doc", 10).await?[0].id, dead.id);
+    let s = stats(&pool, "doc").await?;
+    assert_eq!((s["ready"].clone(), s["dlq"].clone()), (json!(1), json!(1)));
+    Ok(())
+}
+
+#[tokio::test]
+async fn recovery_scan_finds_stranded_leases_and_schema_gaps()
+-> anyhow::Result<()> {
+    use sqlx::Connection;
+    let dir = tempfile::tempdir()?;
+    let cfg = test_config(&dir);
+    let pool = init_pool(&cfg).await?;
+    let _q = create_queue(&pool, "rec", 5).await?;
+    let stranded = enqueue_message(&pool, "rec", &json!({"n":1}), 0).await?;
+    let held = enqueue_message(&pool, "rec", &json!({"n":2}), 0).await?;
+    let day = 24 * 3600 * 1000;
+    assert_eq!(poll_messages(&pool, "rec", 2, day).await?.len(), 2);
+    // A long lease with its attempt on record is not an anomaly
+    let report = recovery_scan(&pool, false).await?;
+    assert_eq!(report.anomalies(), 0);
+    assert!(report.wal_bytes.is_some());
+
+    // As if the server stopped before logging one delivery
+    let url = format!("sqlite://{}", cfg.db_path.display());
+    let mut raw = sqlx::SqliteConnection::connect(&url).await?;
+    sqlx::query("DELETE FROM message_attempt WHERE message_id = ?")
+        .bind(stranded.id)
+        .execute(&mut raw)
+        .await?;
+    sqlx::query("DELETE FROM schema_version WHERE version = 2")
+        .execute(&mut raw)
+        .await?;
+    raw.close().await?;
+
+    let report = recovery_scan(&pool, false).await?;
+    assert_eq!(report.stranded_leases, 1);
+    assert_eq!(report.schema_problems.len(), 1);
+    assert!(!report.repaired);
+    assert!(sample_messages(&pool, "rec", 5).await?.is_empty());
+    let report = recovery_scan(&pool, true).await?;
+    assert_eq!(report.anomalies(), 2);
+    let report = recovery_scan(&pool, false).await?;
+    assert_eq!((report.stranded_leases, report.schema_problems.len()), (0, 1));
+    let ready = poll_messages(&pool, "rec", 5, 1000).await?;
+    assert_eq!(ready.iter().map(|m| m.id).collect::<Vec<_>>(), [stranded.id]);
+    let leased = in_flight(&pool, "rec", 5).await?;
+    assert!(leased.iter().any(|m| m.message_id == held.id));
+    Ok(())
+}
+
+#[tokio::test]
+async fn db_status_reports_sizes_rows_and_when_to_compact() -> anyhow::Result<()>
+{
+    let dir = tempfile::tempdir()?;
+    let cfg = Config { compress_threshold: 0, ..test_config(&dir) };
+    let pool = init_pool(&cfg).await?;
+    let _big = create_queue(&pool, "big", 1).await?;
+    let _small = create_queue(&pool, "small", 1).await?;
+    let blob = json!("x".repeat(8000));
+    for _ in 0..300 {
+        enqueue_message(&pool, "big", &blob, 0).await?;
+    }
+    enqueue_message(&pool, "small", &json!(1), 0).await?;
+    let dead = enqueue_message(&pool, "small", &json!(2), 0).await?;
+    let token = poll_messages(&pool, "small", 2, 60_000).await?[1]
+        .lease_token
+        .clone()
+        .unwrap();
+    nack_messages(&pool, &[dead.id], &token, 0).await?;
+
+    let status = db_status(&pool).await?;
+    assert_eq!(status.file_bytes, status.page_count * status.page_size);
+    assert!(status.file_bytes > 300 * 8000);
+    assert!(status.wal_bytes.is_some());
+    assert_eq!(status.tables[0].name, "message");
+    let rows: Vec<_> = status
+        .queues
+        .iter()
+        .map(|q| (q.name.as_str(), q.messages, q.dead_letters))
+        .collect();
+    assert_eq!(rows, vec![("big", 300, 0), ("small", 1, 1)]);
+    assert!(
+        status.queues[0].estimated_bytes
+            > 100 * status.queues[1].estimated_bytes
+    );
+    assert!(!status.compact_recommended);
+
+    // Purging leaves the pages free until the database is compacted
+    purge_queue(&pool, "big").await?;
+    let status = db_status(&pool).await?;
+    assert!(status.free_pages * status.page_size > 300 * 8000);
+    assert!(status.compact_recommended);
+    compact(&pool).await?;
+    let status = db_status(&pool).await?;
+    assert_eq!((status.free_pages, status.compact_recommended), (0, false));
+    Ok(())
+}
+
+#[tokio::test]
+async fn auto_compact_runs_in_its_window_once_pages_are_free()
+-> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let cfg = Config { compress_threshold: 0, ..test_config(&dir) };
+    let pool = init_pool(&cfg).await?;
+    let _q = create_queue(&pool, "big", 1).await?;
+    let fill = || async {
+        for _ in 0..100 {
+            enqueue_message(&pool, "big", &json!("x".repeat(8000)), 0).await?;
+        }
+        purge_queue(&pool, "big").await
+    };
+    fill().await?;
+
+    let nightly = AutoCompactConfig {
+        window: "* 2-4 * * *".into(),
+        mode: CompactMode::Incremental,
+        min_free_pages: 1,
+    };
+    let at = |h: u32| {
+        Utc.with_ymd_and_hms(2025, 6, 2, h, 30, 0).unwrap().timestamp_millis()
+    };
+    assert!(nightly.in_window(at(2)) && nightly.in_window(at(4)));
+    assert!(!nightly.in_window(at(5)));
+    assert!(auto_compact(&pool, &nightly, at(5)).await?.is_none());
+
+    // The first incremental run switches the database over to it
+    for _ in 0..2 {
+        let run = auto_compact(&pool, &nightly, at(3)).await?.expect("ran");
+        assert_eq!(run.mode, "incremental");
+        assert!(run.free_pages_before > 100);
+        assert_eq!(run.free_pages_after, 0);
+        fill().await?;
+    }
+
+    let full = AutoCompactConfig {
+        mode: CompactMode::Full,
+        min_free_pages: 1_000_000,
+        ..nightly
+    };
+    assert!(auto_compact(&pool, &full, at(3)).await?.is_none());
+    let full = AutoCompactConfig { min_free_pages: 1, ..full };
+    let run = auto_compact(&pool, &full, at(3)).await?.expect("ran");
+    assert_eq!((run.mode.as_str(), run.free_pages_after), ("full", 0));
+
+    let bad = AutoCompactConfig { window: "nightly".into(), ..full };
+    assert!(bad.validate().is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn export_and_import_round_trip() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let pool = init_pool(&test_config(&dir)).await?;
+    let _src = create_queue(&pool, "src", 1).await?;
+    let dst = create_queue(&pool, "dst", 1).await?;
+    let tagged = EnqueueOptions {
+        headers: Some([("kind".to_string(), "a".to_string())].into()),
+        dedup_key: Some("k".into()),
+        priority: 3,
+        ..EnqueueOptions::default()
+    };
+    let kept =
+        enqueue_message_with(&pool, "src", &json!({"n":1}), &tagged).await?;
+    let dead = enqueue_message(&pool, "src", &json!({"n":2}), 0).await?;
+    let dead_token = poll_messages(&pool, "src", 2, 5000).await?[0]
+        .lease_token
+        .clone()
+        .unwrap();
+    assert_eq!(nack_messages(&pool, &[dead.id], &dead_token, 0).await?, (0, 1));
+
+    let mut file = Vec::new();
+    assert_eq!(export_queue(&pool, "src", &mut file).await?, 2);
+    assert_eq!(import_queue(&pool, "dst", file.as_slice()).await?, (2, 0));
+    // The leased message keeps its lease expiry, but not the lease
+    assert!(poll_messages(&pool, "dst", 10, 5000).await?.is_empty());
+    let all = {
+        let mut out = Vec::new();
+        export_queue(&pool, "dst", &mut out).await?;
+        String::from_utf8(out)?
+    };
+    let copies: Vec<serde_json::Value> =
+        all.lines().map(serde_json::from_str).collect::<Result<_, _>>()?;
+    assert_eq!(copies.len(), 2);
+    assert_eq!(copies[0]["queue_id"], dst.id);
+    assert_ne!(copies[0]["id"], kept.id);
+    assert_eq!(copies[0]["payload"], kept.payload);
+    assert_eq!(copies[0]["created_at"], kept.created_at);
+    assert_eq!(copies[0]["priority"], 3);
+    assert_eq!(copies[0]["headers"]["kind"], "a");
+    assert_eq!(copies[0]["attempts"], 0);
+    let dlq = list_dead_letters(&pool, "dst", 10).await?;
+    assert_eq!(dlq.len(), 1);
+    assert_eq!(dlq[0].attempts, 1);
+    assert!(dlq[0].dead_at.is_some());
+
+    // Held dedup keys are skipped; bad lines are rejected
+    assert_eq!(import_queue(&pool, "dst", file.as_slice()).await?, (1, 1));
+    let err = import_queue(&pool, "dst", "{}\n".as_bytes()).await.unwrap_err();
+    assert!(err.to_string().starts_with("Invalid export line 1"));
+    assert!(export_queue(&pool, "missing", &mut Vec::new()).await.is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn import_sqs_and_rabbitmq_exports() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let pool = init_pool(&test_config(&dir)).await?;
+    let _q = create_queue(&pool, "sqs", 1).await?;
+    let _q = create_queue(&pool, "rabbit", 1).await?;
+
+    let sqs = json!({"Messages": [
+        {
+            "MessageId": "a1",
+            "Body": "{\"n\":1}",
+            "Attributes": {
+                "SentTimestamp": "1700000000000",
+                "MessageGroupId": "g",
+                "MessageDeduplicationId": "d"
+            },
+            "MessageAttributes": {
+                "kind": {"DataType": "String", "StringValue": "a"}
+            }
+        },
+        {"MessageId": "a2", "Body": "plain text"}
+    ]});
+    let text = sqs.to_string();
+    let format = ImportFormat::SqsJson;
+    assert_eq!(
+        import_queue_as(&pool, "sqs", format, text.as_bytes()).await?,
+        (2, 0)
+    );
+    let msgs = peek_queue(&pool, "sqs", 10).await?;
+    assert_eq!(msgs.len(), 2);
+    assert_eq!(msgs[0].payload, r#"{"n":1}"#);
+    assert_eq!(msgs[0].created_at, 1_700_000_000_000);
+    assert_eq!(msgs[0].group_id.as_deref(), Some("g"));
+    assert_eq!(msgs[0].dedup_key.as_deref(), Some("d"));
+    assert_eq!(msgs[0].trace_id.as_deref(), Some("a1"));
+    assert_eq!(msgs[0].headers.as_ref().unwrap()["kind"], "a");
+    assert_eq!(msgs[1].payload, r#""plain text""#);
+    assert!(msgs[1].headers.is_none());
+    // Held dedup keys are skipped as for sqew exports
+    assert_eq!(
+        import_queue_as(&pool, "sqs", format, text.as_bytes()).await?,
+        (1, 1)
+    );
+
+    // {"n":2} in base64
+    let rabbit = json!([{
+        "payload": "eyJuIjoyfQ==",
+        "payload_encoding": "base64",
+        "properties": {
+            "priority": 4,
+            "message_id": "r1",
+            "timestamp": 1700000000,
+            "content_type": "application/json",
+            "headers": {"attempt": 2}
+        }
+    }]);
+    let text = rabbit.to_string();
+    let format = ImportFormat::RabbitJson;
+    assert_eq!(
+        import_queue_as(&pool, "rabbit", format, text.as_bytes()).await?,
+        (1, 0)
+    );
+    let msg = &peek_queue(&pool, "rabbit", 10).await?[0];
+    assert_eq!(msg.payload, r#"{"n":2}"#);
+    assert_eq!(msg.priority, 4);
+    assert_eq!(msg.created_at, 1_700_000_000_000);
+    assert_eq!(msg.trace_id.as_deref(), Some("r1"));
+    let headers = msg.headers.as_ref().unwrap();
+    assert_eq!(headers["attempt"], "2");
+    assert_eq!(headers["content_type"], "application/json");
+
+    let err =
+        import_queue_as(&pool, "rabbit", format, r#"[{"x":1}]"#.as_bytes())
+            .await
+            .unwrap_err();
+    assert!(err.to_string().contains("rabbit-json message 1: no payload"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn trace_id_follows_a_message_through_retries() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let pool = init_pool(&test_config(&dir)).await?;
+    let _q = create_queue(&pool, "traced", 2).await?;
+    let traced = EnqueueOptions {
+        trace_id: Some("req-42".into()),
+        ..EnqueueOptions::default()
+    };
+    let m = enqueue_message_with(&pool, "traced", &json!({}), &traced).await?;
+    assert_eq!(m.trace_id.as_deref(), Some("req-42"));
+    let other = enqueue_message(&pool, "traced", &json!({}), 0).await?;
+    assert!(other.trace_id.as_deref().is_some_and(|t| !t.is_empty()));
+    assert!(remove_message(&pool, other.id).await?);
+
+    for _ in 0..2 {
+        let leased = poll_messages(&pool, "traced", 1, 5000).await?;
+        assert_eq!(leased[0].trace_id.as_deref(), Some("req-42"));
+        let token = leased[0].lease_token.clone().unwrap();
+        nack_messages(&pool, &[m.id], &token, 0).await?;
+    }
+    let dead = list_dead_letters(&pool, "traced", 10).await?;
+    assert_eq!(dead[0].trace_id.as_deref(), Some("req-42"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn alarms_fire_after_their_duration_and_resolve() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let pool = init_pool(&test_config(&dir)).await?;
+    let _q = create_queue(&pool, "deep", 5).await?;
+    let hook = "http://127.0.0.1:9/hook";
+    assert!(add_alarm(&pool, "deep", "depth", 1, 0, hook).await.is_err());
+    assert!(add_alarm(&pool, "deep", "ready", 1, 0, "ftp://x").await.is_err());
+    assert!(add_alarm(&pool, "missing", "ready", 1, 0, hook).await.is_err());
+    let fast = add_alarm(&pool, "deep", "ready", 1, 0, hook).await?;
+    let slow = add_alarm(&pool, "deep", "ready", 1, 60_000, hook).await?;
+    let aged =
+        add_alarm(&pool, "deep", "oldest_age_ms", 60_000, 0, hook).await?;
+
+    // Below the threshold nothing happens
+    let _m = enqueue_message(&pool, "deep", &json!({"n":1}), 0).await?;
+    assert!(evaluate_alarms(&pool).await?.is_empty());
+
+    // Above it the alarm without a duration fires once; the other waits
+    let _m = enqueue_message(&pool, "deep", &json!({"n":2}), 0).await?;
+    let fired = evaluate_alarms(&pool).await?;
+    assert_eq!(fired.len(), 1);
+    assert_eq!(
+        (fired[0].alarm_id, fired[0].state, fired[0].value),
+        (fast.id, "firing", 2)
+    );
+    assert_eq!(fired[0].queue, "deep");
+    assert!(evaluate_alarms(&pool).await?.is_empty());
+    let alarms = list_alarms(&pool, Some("deep")).await?;
+    assert!(alarms[0].firing);
+    assert!(!alarms[1].firing && alarms[1].breached_since.is_some());
+    assert!(alarms[2].breached_since.is_none());
+
+    // Draining the queue resolves it
+    purge_queue(&pool, "deep").await?;
+    let resolved = evaluate_alarms(&pool).await?;
+    assert_eq!(resolved.len(), 1);
+    assert_eq!(
+        (resolved[0].alarm_id, resolved[0].state),
+        (fast.id, "resolved")
+    );
+    assert!(list_alarms(&pool, None).await?[1].breached_since.is_none());
+
+    assert!(remove_alarm(&pool, slow.id).await?);
+    assert!(!remove_alarm(&pool, slow.id).await?);
+    assert_eq!(list_alarms(&pool, None).await?.len(), 2);
+    delete_queue(&pool, "deep").await?;
+    assert!(!remove_alarm(&pool, aged.id).await?);
+    Ok(())
+}
+
+#[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+struct Job {
+    id: u32,
+    name: String,
+}
+
+#[tokio::test]
+async fn subscriptions_stream_leased_messages() -> anyhow::Result<()> {
+    use std::time::Duration;
+    use tokio::time::timeout;
+    use tokio_stream::StreamExt;
+    const WAIT: Duration = Duration::from_secs(3);
+    let dir = tempfile::tempdir()?;
+    let pool = init_pool(&test_config(&dir)).await?;
+    let _q = create_queue(&pool, "subs", 2).await?;
+    let mut sent = Vec::new();
+    for n in 0..3 {
+        sent.push(enqueue_message(&pool, "subs", &json!({"n": n}), 0).await?);
+    }
+    // Backoff long enough that only a wakeup delivers new messages in time
+    let notifier = Arc::new(QueueNotifier::new());
+    let opts = SubscribeOptions {
+        batch: 2,
+        visibility_ms: Some(60_000),
+        min_backoff: Duration::from_secs(30),
+        max_backoff: Duration::from_secs(30),
+        notifier: Some(notifier.clone()),
+        ..SubscribeOptions::default()
+    };
+    let mut sub = subscribe(&pool, "subs", opts);
+    let first = timeout(WAIT, sub.next()).await?.unwrap()?;
+    assert_eq!(first.id, sent[0].id);
+    assert!(first.ack().await?);
+    let second = timeout(WAIT, sub.next()).await?.unwrap()?;
+    assert_eq!(second.id, sent[1].id);
+    assert!(second.nack_with_reason(60_000, Some("later")).await?);
+    let third = timeout(WAIT, sub.next()).await?.unwrap()?;
+    assert_eq!(third.id, sent[2].id);
+    assert!(third.extend(1000).await?);
+    assert!(third.ack().await?);
+    assert_eq!(get_message_by_id(&pool, sent[1].id).await?.attempts, 1);
+
+    // The queue is empty now; an announced enqueue ends the wait
+    let m = enqueue_message(&pool, "subs", &json!({"n": 3}), 0).await?;
+    notifier.notify("subs");
+    let fourth = timeout(WAIT, sub.next()).await?.unwrap()?;
+    assert_eq!(fourth.id, m.id);
+    assert!(fourth.nack(0).await?);
+    // The subscription may have polled again before the nack landed
+    notifier.notify("subs");
+
+    // The nacked message comes back until it is dead-lettered
+    let again = timeout(WAIT, sub.next()).await?.unwrap()?;
+    assert_eq!(again.id, m.id);
+    assert!(again.nack(0).await?);
+    assert_eq!(list_dead_letters(&pool, "subs", 10).await?.len(), 1);
+    drop(sub);
+
+    // An unknown queue ends the stream after its error
+    let mut gone = subscribe(&pool, "nope", SubscribeOptions::default());
+    let err = timeout(WAIT, gone.next()).await?.unwrap().unwrap_err();
+    assert!(matches!(err, SqewError::QueueNotFound(_)), "{err}");
+    assert!(timeout(WAIT, gone.next()).await?.is_none());
+    Ok(())
+}
+
+#[tokio::test]
+async fn typed_messages_round_trip_and_report_bad_payloads()
+-> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let pool = init_pool(&test_config(&dir)).await?;
+    let _q = create_queue(&pool, "typed", 1).await?;
+    let job = Job { id: 7, name: "resize".into() };
+    let sent =
+        enqueue_typed(&pool, "typed", job, &EnqueueOptions::default()).await?;
+    assert_eq!(sent.payload.id, 7);
+    let bad = enqueue_message(&pool, "typed", &json!({"id": "x"}), 0).await?;
+
+    let polled = poll_typed::<Job>(&pool, "typed", 10, 5000, true).await?;
+    assert_eq!(polled.messages.len(), 1);
+    assert_eq!(polled.messages[0].message.id, sent.message.id);
+    assert_eq!(
+        polled.messages[0].payload,
+        Job { id: 7, name: "resize".into() }
+    );
+    assert_eq!(polled.failures.len(), 1);
+    assert_eq!(polled.failures[0].message.id, bad.id);
+    assert!(polled.failures[0].nacked);
+    assert!(polled.failures[0].to_string().contains("does not decode"));
+    // Nacked with max_attempts = 1, the bad message is dead-lettered
+    assert_eq!(list_dead_letters(&pool, "typed", 10).await?[0].id, bad.id);
+
+    // Without nacking, failures stay leased to the caller
+    let _bad = enqueue_message(&pool, "typed", &json!([]), 0).await?;
+    let polled = poll_typed::<Job>(&pool, "typed", 10, 5000, false).await?;
+    assert!(!polled.failures[0].nacked);
+    assert!(polled.failures[0].message.lease_token.is_some());
+    Ok(())
+}
+
+#[tokio::test]
+async fn transactional_enqueue_commits_with_domain_rows() -> anyhow::Result<()>
+{
+    let dir = tempfile::tempdir()?;
+    let cfg = test_config(&dir);
+    let pool = init_pool(&cfg).await?;
+    let _q = create_queue(&pool, "outbox", 5).await?;
+    let app = pool.as_sqlite().expect("sqlite backend").pool().clone();
+    sqlx::query("CREATE TABLE orders (id INTEGER PRIMARY KEY, item TEXT)")
+        .execute(&app)
+        .await?;
+
+    // Rolled back: neither the order nor the message exists
+    let mut tx = begin_transaction(&pool).await?;
+    sqlx::query("INSERT INTO orders (item) VALUES ('lost')")
+        .execute(&mut *tx)
+        .await?;
+    let opts = EnqueueOptions::default();
+    let lost = enqueue_message_tx(
+        &pool,
+        &mut tx,
+        "outbox",
+        &json!({"o":"lost"}),
+        &opts,
+    )
+    .await?;
+    tx.rollback().await?;
+    assert!(get_message_by_id(&pool, lost.id).await.is_err());
+
+    // Committed: both appear, and a dedup key is honoured in the transaction
+    let keyed = EnqueueOptions {
+        dedup_key: Some("o1".into()),
+        ..EnqueueOptions::default()
+    };
+    let mut tx = begin_transaction(&pool).await?;
+    sqlx::query("INSERT INTO orders (item) VALUES ('kept')")
+        .execute(&mut *tx)
+        .await?;
+    let kept = enqueue_message_tx(
+        &pool,
+        &mut tx,
+        "outbox",
+        &json!({"o":"kept"}),
+        &keyed,
+    )
+    .await?;
+    let dup = enqueue_message_tx(
+        &pool,
+        &mut tx,
+        "outbox",
+        &json!({"o":"again"}),
+        &keyed,
+    )
+    .await?;
+    assert_eq!(dup.id, kept.id);
+    // Uncommitted messages are invisible to other connections
+    assert!(peek_queue(&pool, "outbox", 10).await?.is_empty());
+    tx.commit().await?;
+
+    let orders: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM orders")
+        .fetch_one(&app)
+        .await?;
+    assert_eq!(orders, 1);
+    let polled = poll_messages(&pool, "outbox", 10, 1000).await?;
+    assert_eq!(polled.len(), 1);
+    assert_eq!(polled[0].id, kept.id);
+    assert_eq!(polled[0].payload, json!({"o":"kept"}).to_string());
+
+    let mut tx = begin_transaction(&pool).await?;
+    let missing =
+        enqueue_message_tx(&pool, &mut tx, "nope", &json!({}), &opts).await;
+    assert!(missing.unwrap_err().to_string().contains("not found"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn payload_size_limit_and_schema_are_enforced() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let pool = init_pool(&test_config(&dir)).await?;
+    let opts = QueueOptions {
+        max_payload_bytes: Some(16),
+        payload_schema: Some(json!({"type": "object", "required": ["n"]})),
+        ..QueueOptions::default()
+    };
+    let q = create_queue_with(&pool, "strict", &opts).await?;
+    assert_eq!(q.max_payload_bytes, Some(16));
+    let _ok = enqueue_message(&pool, "strict", &json!({"n": 1}), 0).await?;
+
+    let err = enqueue_message(&pool, "strict", &json!({"m": 1}), 0)
+        .await
+        .unwrap_err();
+    assert!(matches!(
+        err,
+        SqewError::PayloadRejected(PayloadRejected::SchemaViolation { .. })
+    ));
+    let err =
+        enqueue_message(&pool, "strict", &json!({"n": "x".repeat(20)}), 0)
+            .await
+            .unwrap_err();
+    assert!(matches!(
+        err,
+        SqewError::PayloadRejected(PayloadRejected::PayloadTooLarge {
+            size: 28,
+            limit: 16
+        })
+    ));
+
+    // Schemas must compile; clearing both settings lifts the checks
+    let broken = QueueUpdate {
+        payload_schema: Some(Some(json!({"type": 12}))),
+        ..QueueUpdate::default()
+    };
+    let err = update_queue(&pool, "strict", &broken).await.unwrap_err();
+    assert!(err.to_string().starts_with("Invalid"));
+    let cleared = QueueUpdate {
+        max_payload_bytes: Some(None),
+        payload_schema: Some(None),
+        ..QueueUpdate::default()
+    };
+    let q = update_queue(&pool, "strict", &cleared).await?;
+    assert_eq!((q.max_payload_bytes, q.payload_schema), (None, None));
+    let _any =
+        enqueue_message(&pool, "strict", &json!({"m": "x".repeat(20)}), 0)
+            .await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn schemas_are_versioned_and_can_tag_instead_of_reject()
+-> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let pool = init_pool(&test_config(&dir)).await?;
+    let v1 = json!({"type": "object", "required": ["n"]});
+    let opts =
+        QueueOptions { payload_schema: Some(v1.clone()), ..Default::default() };
+    let q = create_queue_with(&pool, "orders", &opts).await?;
+    assert_eq!(
+        (q.payload_schema_version, q.schema_mode.as_str()),
+        (1, "reject")
+    );
+    assert_eq!(
+        queue_schema(&pool, "orders", None).await?.payload_schema,
+        Some(v1)
+    );
+
+    // Setting a schema records the next version; `tag` accepts payloads
+    // that break it, naming the violations in headers
+    let v2 = json!({"type": "object", "required": ["id"]});
+    let q =
+        set_queue_schema(&pool, "orders", v2.clone(), Some(SchemaMode::Tag))
+            .await?;
+    assert_eq!((q.payload_schema_version, q.schema_mode.as_str()), (2, "tag"));
+    let ok = enqueue_message(&pool, "orders", &json!({"id": 1}), 0).await?;
+    assert!(ok.headers.is_none());
+    let bad = enqueue_message(&pool, "orders", &json!({"n": 1}), 0).await?;
+    let headers = bad.headers.unwrap();
+    assert!(headers[SCHEMA_VIOLATIONS_HEADER].contains("\"id\""));
+    assert_eq!(headers[SCHEMA_VERSION_HEADER], "2");
+
+    // Changing other settings, or setting the same schema, keeps the version
+    let update =
+        QueueUpdate { max_attempts: Some(3), ..QueueUpdate::default() };
+    assert_eq!(
+        update_queue(&pool, "orders", &update).await?.payload_schema_version,
+        2
+    );
+    let q = set_queue_schema(&pool, "orders", v2.clone(), None).await?;
+    assert_eq!((q.payload_schema_version, q.schema_mode.as_str()), (2, "tag"));
+
+    // Removing the schema is a version too; older ones stay readable
+    let cleared =
+        QueueUpdate { payload_schema: Some(None), ..QueueUpdate::default() };
+    assert_eq!(
+        update_queue(&pool, "orders", &cleared).await?.payload_schema_version,
+        3
+    );
+    let history = queue_schema_history(&pool, "orders").await?;
+    let versions: Vec<_> = history.iter().map(|v| v.version).collect();
+    assert_eq!(versions, [3, 2, 1]);
+    assert_eq!(history[0].payload_schema, None);
+    assert_eq!(
+        queue_schema(&pool, "orders", Some(2)).await?.payload_schema,
+        Some(v2.clone())
+    );
+    let err = queue_schema(&pool, "orders", Some(9)).await.unwrap_err();
+    assert!(
+        matches!(err, SqewError::SchemaNotFound { version: Some(9), .. }),
+        "{err}"
+    );
+
+    // Clones start their own history; queues without a schema have none
+    set_queue_schema(&pool, "orders", v2, Some(SchemaMode::Reject)).await?;
+    let (copy, _) = clone_queue(&pool, "orders", "orders-copy", false).await?;
+    assert_eq!(
+        (copy.payload_schema_version, copy.schema_mode.as_str()),
+        (1, "reject")
+    );
+    assert!(
+        enqueue_message(&pool, "orders-copy", &json!({}), 0).await.is_err()
+    );
+    let _plain = create_queue(&pool, "plain", 5).await?;
+    let err = queue_schema(&pool, "plain", None).await.unwrap_err();
+    assert_eq!(err.to_string(), "Queue 'plain' has no schema");
+    assert!(queue_schema_history(&pool, "plain").await?.is_empty());
+    Ok(())
+}
+
+#[tokio::test]
+async fn max_depth_refuses_enqueues_until_consumers_make_room()
+-> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let pool = init_pool(&test_config(&dir)).await?;
+    let opts = QueueOptions { max_depth: Some(2), ..QueueOptions::default() };
+    let q = create_queue_with(&pool, "capped", &opts).await?;
+    assert_eq!(q.max_depth, Some(2));
+    let first = enqueue_message(&pool, "capped", &json!(1), 0).await?;
+    let _second = enqueue_message(&pool, "capped", &json!(2), 0).await?;
+
+    // Leased messages still count against the depth
+    let leased = poll_messages(&pool, "capped", 1, 60_000).await?;
+    let err = enqueue_message(&pool, "capped", &json!(3), 0).await.unwrap_err();
+    assert!(matches!(err, SqewError::QueueFull { max_depth: 2, .. }));
+
+    // A waiting enqueue goes through once a consumer acks
+    let token = leased[0].lease_token.clone().unwrap();
+    let acker = {
+        let pool = pool.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(std::time::Duration::from_millis(150)).await;
+            ack_messages(&pool, &[first.id], &token).await
+        })
+    };
+    let wait = EnqueueOptions { wait_ms: Some(5_000), ..Default::default() };
+    let _third =
+        enqueue_message_with(&pool, "capped", &json!(3), &wait).await?;
+    assert_eq!(acker.await??, 1);
+    let short = EnqueueOptions { wait_ms: Some(50), ..Default::default() };
+    let err = enqueue_message_with(&pool, "capped", &json!(4), &short)
+        .await
+        .unwrap_err();
+    assert!(matches!(err, SqewError::QueueFull { .. }));
+
+    // The limit must be positive and can be lifted
+    let zero =
+        QueueUpdate { max_depth: Some(Some(0)), ..QueueUpdate::default() };
+    assert!(update_queue(&pool, "capped", &zero).await.is_err());
+    let lifted =
+        QueueUpdate { max_depth: Some(None), ..QueueUpdate::default() };
+    assert_eq!(update_queue(&pool, "capped", &lifted).await?.max_depth, None);
+    let _fourth = enqueue_message(&pool, "capped", &json!(4), 0).await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn attempt_log_records_each_delivery_and_its_outcome()
+-> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let pool = init_pool(&test_config(&dir)).await?;
+    let _q = create_queue(&pool, "logged", 5).await?;
+    let m = enqueue_message(&pool, "logged", &json!({"n": 1}), 0).await?;
+    assert!(message_attempts(&pool, m.id).await?.is_empty());
+    assert!(matches!(
+        message_attempts(&pool, m.id + 100).await,
+        Err(SqewError::MessageNotFound(_))
+    ));
+
+    // A nack keeps the consumer's reason
+    let leased =
+        poll_messages_as(&pool, "logged", 1, 60_000, Some("w1")).await?;
+    let token = leased[0].lease_token.clone().unwrap();
+    let nacks = [(m.id, 0)];
+    nack_messages_with_reason(&pool, &nacks, &token, Some("timeout")).await?;
+
+    // A lapsed lease is marked when the message is delivered again
+    let _lapsed = poll_messages(&pool, "logged", 1, 1).await?;
+    tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    let leased =
+        poll_messages_as(&pool, "logged", 1, 60_000, Some("w2")).await?;
+    let token = leased[0].lease_token.clone().unwrap();
+    assert_eq!(message_attempts(&pool, m.id).await?[2].outcome, None);
+    // The held lease is listed with its consumer
+    let held = in_flight(&pool, "logged", 10).await?;
+    assert_eq!(held.len(), 1);
+    assert_eq!(
+        (held[0].message_id, held[0].attempts),
+        (m.id, leased[0].attempts)
+    );
+    assert_eq!(held[0].consumer.as_deref(), Some("w2"));
+    assert!(held[0].held_ms >= Some(0));
+    assert_eq!(held[0].lease_expires_at, leased[0].available_at);
+    assert_eq!(ack_messages(&pool, &[m.id], &token).await?, 1);
+    assert!(in_flight(&pool, "logged", 10).await?.is_empty());
+    assert!(matches!(
+        in_flight(&pool, "nope", 10).await,
+        Err(SqewError::QueueNotFound(_))
+    ));
+
+    // The log outlives the acked message
+    let attempts = message_attempts(&pool, m.id).await?;
+    let summary: Vec<_> = attempts
+        .iter()
+        .map(|a| (a.attempt, a.consumer.as_deref(), a.outcome.as_deref()))
+        .collect();
+    assert_eq!(
+        summary,
+        vec![
+            (1, Some("w1"), Some("requeued")),
+            (2, None, Some("lease_expired")),
+            (3, Some("w2"), Some("acked")),
+        ]
+    );
+    assert_eq!(attempts[0].note.as_deref(), Some("timeout"));
+    assert!(attempts.iter().all(|a| a.settled_at >= Some(a.leased_at)));
+    Ok(())
+}
+
+#[tokio::test]
+async fn nack_reason_is_kept_on_the_message_and_its_dead_letter()
+-> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let pool = init_pool(&test_config(&dir)).await?;
+    let _q = create_queue(&pool, "flaky", 3).await?;
+    let m = enqueue_message(&pool, "flaky", &json!({"n": 1}), 0).await?;
+    assert_eq!(get_message_by_id(&pool, m.id).await?.last_error, None);
+
+    let nack = |reason: Option<&'static str>| {
+        let pool = pool.clone();
+        async move {
+            let leased = poll_messages(&pool, "flaky", 1, 60_000).await?;
+            let token = leased[0].lease_token.clone().unwrap();
+            nack_messages_with_reason(&pool, &[(m.id, 0)], &token, reason).await
+        }
+    };
+    nack(Some("connection refused")).await?;
+    let got = get_message_by_id(&pool, m.id).await?;
+    assert_eq!(got.last_error.as_deref(), Some("connection refused"));
+
+    // A nack without a reason keeps the last one given
+    nack(None).await?;
+    let got = get_message_by_id(&pool, m.id).await?;
+    assert_eq!(got.last_error.as_deref(), Some("connection refused"));
+
+    // Long reasons are cut short on a character boundary
+    let stack: &'static str = "é".repeat(MAX_NACK_REASON_BYTES).leak();
+    assert_eq!(nack(Some(stack)).await?, (0, 1));
+    let dead = list_dead_letters(&pool, "flaky", 10).await?;
+    let reason = dead[0].last_error.as_deref().unwrap();
+    assert_eq!(reason.len(), MAX_NACK_REASON_BYTES);
+    assert!(stack.starts_with(reason));
+    Ok(())
+}
+
+#[tokio::test]
+async fn transaction_enqueues_into_every_queue_or_none() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let pool = init_pool(&test_config(&dir)).await?;
+    let orders = create_queue(&pool, "orders", 5).await?;
+    let opts = QueueOptions { max_depth: Some(2), ..QueueOptions::default() };
+    let emails = create_queue_with(&pool, "emails", &opts).await?;
+    let item = |queue: &str, n: i64| {
+        (queue.to_string(), json!({"n": n}), EnqueueOptions::default())
+    };
+
+    let created = enqueue_transaction(
+        &pool,
+        &[item("orders", 1), item("emails", 2), item("orders", 3)],
+    )
+    .await?;
+    let queues: Vec<i64> = created.iter().map(|m| m.queue_id).collect();
+    assert_eq!(queues, vec![orders.id, emails.id, orders.id]);
+    assert_eq!(peek_queue(&pool, "orders", 10).await?.len(), 2);
+
+    // A missing queue rolls back the messages before it
+    let res =
+        enqueue_transaction(&pool, &[item("orders", 4), item("nope", 5)]).await;
+    assert!(matches!(res, Err(SqewError::QueueNotFound(_))));
+    // So does a queue without room for all of its messages
+    let res = enqueue_transaction(
+        &pool,
+        &[item("orders", 6), item("emails", 7), item("emails", 8)],
+    )
+    .await;
+    assert!(matches!(res, Err(SqewError::QueueFull { .. })));
+    assert_eq!(peek_queue(&pool, "orders", 10).await?.len(), 2);
+    assert_eq!(peek_queue(&pool, "emails", 10).await?.len(), 1);
+    assert!(matches!(
+        enqueue_transaction(&pool, &[]).await,
+        Err(SqewError::Invalid(_))
+    ));
+    Ok(())
+}
+
+#[tokio::test]
+async fn api_key_grants_follow_role_and_queue_pattern() -> anyhow::Result<()> {
+    use sqew::auth::{self, Grant, Permission, Role};
+    let dir = tempfile::tempdir()?;
+    let pool = init_pool(&test_config(&dir)).await?;
+
+    let (key, secret) = auth::grant_key(
+        &pool,
+        "shop",
+        Role::Producer,
+        Some("orders-*-eu"),
+        None,
+    )
+    .await?;
+    assert_eq!(key.key_hash, auth::hash_key(&secret));
+    assert_ne!(key.key_hash, secret);
+    assert!(matches!(
+        auth::grant_key(&pool, "shop", Role::Admin, None, None).await,
+        Err(SqewError::Invalid(_))
+    ));
+    assert!(matches!(
+        auth::grant_key(&pool, "short", Role::Admin, None, Some("abc")).await,
+        Err(SqewError::Invalid(_))
+    ));
+    assert_eq!(auth::list_keys(&pool).await?.len(), 1);
+
+    let shop = Grant { role: Role::Producer, queue_pattern: key.queue_pattern };
+    assert!(shop.allows(Permission::Produce, Some("orders-de-eu")));
+    assert!(shop.allows(Permission::Read, Some("orders--eu")));
+    assert!(!shop.allows(Permission::Produce, Some("orders-de-us")));
+    assert!(!shop.allows(Permission::Consume, Some("orders-de-eu")));
+    assert!(!shop.allows(Permission::Admin, Some("orders-de-eu")));
+    let reader = Grant { role: Role::ReadOnly, queue_pattern: None };
+    assert!(reader.allows(Permission::Read, Some("anything")));
+    assert!(!reader.allows(Permission::Produce, None));
+    let exact = Grant { role: Role::Admin, queue_pattern: Some("jobs".into()) };
+    assert!(exact.allows(Permission::Admin, Some("jobs")));
+    assert!(!exact.allows(Permission::Admin, Some("jobs2")));
+
+    assert!(auth::revoke_key(&pool, "shop").await?);
+    assert!(!auth::revoke_key(&pool, "shop").await?);
+    assert!(auth::list_keys(&pool).await?.is_empty());
     Ok(())
 }
@@ -0,0 +1,2305 @@
+use axum::{
+    Json, Router,
+    body::{Body, to_bytes},
+    http::{Request, StatusCode},
+    routing::post,
+};
+use serde_json::{Value, json};
+use sqew::auth::{self, Role};
+use sqew::client::{ClientError, PollRequest, SqewClient};
+use sqew::error::SqewError;
+use sqew::queue::{self, Config};
+use sqew::server::{
+    AppState, ChaosConfig, TaskIntervals, app_router, parse_cors_origin,
+    serve_until,
+};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tower::ServiceExt; // for `oneshot`
+
+fn test_config(tmp: &tempfile::TempDir) -> Config {
+    Config {
+        db_path: tmp.path().join("server.db"),
+        force_recreate: true,
+        ..Config::default()
+    }
+}
+
+// Send a request to the in-process router, returning status and JSON body
+async fn send(
+    app: &Router,
+    method: &str,
+    uri: &str,
+    body: Option<Value>,
+) -> anyhow::Result<(StatusCode, Value)> {
+    let mut req = Request::builder().method(method).uri(uri);
+    let body = match body {
+        Some(v) => {
+            req = req.header("content-type", "application/json");
+            Body::from(serde_json::to_vec(&v)?)
+        }
+        None => Body::empty(),
+    };
+    let resp = app.clone().oneshot(req.body(body)?).await?;
+    let status = resp.status();
+    let bytes = to_bytes(resp.into_body(), 1024 * 1024).await?;
+    let json = serde_json::from_slice(&bytes).unwrap_or(Value::Null);
+    Ok((status, json))
+}
+
+#[tokio::test]
+async fn dlq_routes_list_redrive_purge() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let pool = queue::init_pool(&test_config(&dir)).await?;
+    let _q = queue::create_queue(&pool, "jobs", 1).await?;
+    let m = queue::enqueue_message(&pool, "jobs", &json!({"n":1}), 0).await?;
+    let leased = queue::poll_messages(&pool, "jobs", 1, 1000).await?;
+    let token = leased[0].lease_token.clone().unwrap();
+    queue::nack_messages(&pool, &[m.id], &token, 0).await?;
+    let app = app_router(pool.clone());
+
+    let (status, body) = send(&app, "GET", "/queues/jobs/dlq", None).await?;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body.as_array().map(|a| a.len()), Some(1));
+
+    let (status, body) =
+        send(&app, "POST", "/queues/jobs/dlq/redrive", None).await?;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["redriven"], 1);
+
+    let (status, body) = send(&app, "DELETE", "/queues/jobs/dlq", None).await?;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["deleted"], 0);
+
+    let (status, _) = send(&app, "GET", "/queues/missing/dlq", None).await?;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    Ok(())
+}
+
+#[tokio::test]
+async fn soft_deleted_queues_can_be_restored() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let pool = queue::init_pool(&test_config(&dir)).await?;
+    let _q = queue::create_queue(&pool, "jobs", 1).await?;
+    queue::enqueue_message(&pool, "jobs", &json!({"n":1}), 0).await?;
+    let app = app_router(pool.clone());
+
+    let (status, _) =
+        send(&app, "DELETE", "/queues/jobs?soft=true", None).await?;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    let (status, _) = send(&app, "GET", "/queues/jobs", None).await?;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let (status, _) = send(&app, "GET", "/queues/jobs/messages", None).await?;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let body = json!({"name": "jobs"});
+    let (status, _) = send(&app, "POST", "/queues", Some(body)).await?;
+    assert_eq!(status, StatusCode::CONFLICT);
+    let (status, trash) = send(&app, "GET", "/admin/trash", None).await?;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(trash[0]["name"], "jobs");
+    assert!(trash[0]["deleted_at"].is_i64());
+
+    let (status, q) = send(&app, "POST", "/queues/jobs/restore", None).await?;
+    assert_eq!(status, StatusCode::OK);
+    assert!(q.get("deleted_at").is_none());
+    let (_, msgs) = send(&app, "GET", "/queues/jobs/messages", None).await?;
+    assert_eq!(msgs.as_array().map(|a| a.len()), Some(1));
+    let (status, _) = send(&app, "POST", "/queues/jobs/restore", None).await?;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    Ok(())
+}
+
+#[tokio::test]
+async fn purges_run_in_batches_and_in_the_background() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let pool = queue::init_pool(&test_config(&dir)).await?;
+    let _q = queue::create_queue(&pool, "jobs", 1).await?;
+    for n in 0..5 {
+        queue::enqueue_message(&pool, "jobs", &json!({"n": n}), 0).await?;
+    }
+    let app = app_router(pool.clone());
+
+    let (status, body) =
+        send(&app, "DELETE", "/queues/jobs/messages?batch_size=2", None)
+            .await?;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["deleted"], 5);
+
+    // A filter deletes only the messages it matches
+    for kind in ["spam", "mail", "spam"] {
+        let payload = json!({"type": kind});
+        queue::enqueue_message(&pool, "jobs", &payload, 0).await?;
+    }
+    let uri = "/queues/jobs/messages?filter=%24.type%20%3D%3D%20%22spam%22&older_than=1d";
+    let (status, body) = send(&app, "DELETE", uri, None).await?;
+    assert_eq!((status, body["deleted"].clone()), (StatusCode::OK, json!(0)));
+    let uri = "/queues/jobs/messages?filter=%24.type%20%3D%3D%20%22spam%22";
+    let (status, body) = send(&app, "DELETE", uri, None).await?;
+    assert_eq!((status, body["deleted"].clone()), (StatusCode::OK, json!(2)));
+    let (status, _) =
+        send(&app, "DELETE", "/queues/jobs/messages?older_than=soon", None)
+            .await?;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    queue::purge_queue(&pool, "jobs").await?;
+
+    for n in 0..3 {
+        queue::enqueue_message(&pool, "jobs", &json!({"n": n}), 0).await?;
+    }
+    let (status, job) =
+        send(&app, "POST", "/queues/jobs/purges?batch_size=2", None).await?;
+    assert_eq!(status, StatusCode::ACCEPTED);
+    assert_eq!(job["status"], "pending");
+    assert_eq!(job["batch_size"], 2);
+    // The server's admin_jobs task runs it
+    queue::run_admin_jobs(&pool).await?;
+    let uri = format!("/queues/jobs/purges/{}", job["id"]);
+    let (status, job) = send(&app, "GET", &uri, None).await?;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(job["status"], "done");
+    assert_eq!(job["progress"], 3);
+    assert!(job["finished_at"].is_i64());
+
+    let other = format!("/queues/other/purges/{}", job["id"]);
+    let (status, _) = send(&app, "GET", &other, None).await?;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let (status, _) =
+        send(&app, "POST", "/queues/jobs/purges?batch_size=0", None).await?;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let (status, _) = send(&app, "POST", "/queues/nope/purges", None).await?;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    Ok(())
+}
+
+#[tokio::test]
+async fn admin_jobs_can_be_started_followed_and_canceled() -> anyhow::Result<()>
+{
+    let dir = tempfile::tempdir()?;
+    let pool = queue::init_pool(&test_config(&dir)).await?;
+    let _q = queue::create_queue(&pool, "jobs", 1).await?;
+    queue::enqueue_message(&pool, "jobs", &json!({"n":1}), 0).await?;
+    let app = app_router(pool.clone());
+
+    let path = dir.path().join("jobs.ndjson");
+    let body = json!({"kind": "export", "queue": "jobs", "path": path});
+    let (status, export) =
+        send(&app, "POST", "/admin/jobs", Some(body)).await?;
+    assert_eq!(status, StatusCode::ACCEPTED);
+    assert_eq!(export["kind"], "export");
+    let body = json!({"kind": "compact"});
+    let (_, compact) = send(&app, "POST", "/admin/jobs", Some(body)).await?;
+    let cancel = format!("/admin/jobs/{}/cancel", compact["id"]);
+    let (status, canceled) = send(&app, "POST", &cancel, None).await?;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(canceled["status"], "canceled");
+
+    queue::run_admin_jobs(&pool).await?;
+    let uri = format!("/admin/jobs/{}", export["id"]);
+    let (status, export) = send(&app, "GET", &uri, None).await?;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(export["status"], "done");
+    assert_eq!(export["progress"], 1);
+    assert!(path.exists());
+    let (status, jobs) = send(&app, "GET", "/admin/jobs", None).await?;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(jobs[0]["id"], compact["id"]);
+    assert_eq!(jobs[1]["id"], export["id"]);
+
+    let cancel = format!("/admin/jobs/{}/cancel", export["id"]);
+    let (status, _) = send(&app, "POST", &cancel, None).await?;
+    assert_eq!(status, StatusCode::CONFLICT);
+    let (status, _) = send(&app, "GET", "/admin/jobs/999", None).await?;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let body = json!({"kind": "purge"});
+    let (status, _) = send(&app, "POST", "/admin/jobs", Some(body)).await?;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let body = json!({"kind": "redrive", "queue": "nope"});
+    let (status, _) = send(&app, "POST", "/admin/jobs", Some(body)).await?;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    Ok(())
+}
+
+#[tokio::test]
+async fn archive_replay_route() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let pool = queue::init_pool(&test_config(&dir)).await?;
+    let keep = queue::QueueOptions {
+        retention_days: Some(1),
+        ..queue::QueueOptions::default()
+    };
+    let _q = queue::create_queue_with(&pool, "kept", &keep).await?;
+    let m = queue::enqueue_message(&pool, "kept", &json!({"n":1}), 0).await?;
+    let leased = queue::poll_messages(&pool, "kept", 1, 1000).await?;
+    let token = leased[0].lease_token.clone().unwrap();
+    queue::ack_messages(&pool, &[m.id], &token).await?;
+    let app = app_router(pool.clone());
+
+    let (status, body) =
+        send(&app, "POST", "/queues/kept/archive/replay", None).await?;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["replayed"], 1);
+    let body = json!({"from": 0, "contains": "nomatch"});
+    let (status, res) =
+        send(&app, "POST", "/queues/kept/archive/replay", Some(body)).await?;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(res["replayed"], 0);
+    assert_eq!(queue::peek_queue(&pool, "kept", 10).await?.len(), 1);
+
+    let body = json!({"from": 2, "to": 1});
+    let (status, _) =
+        send(&app, "POST", "/queues/kept/archive/replay", Some(body)).await?;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let (status, _) =
+        send(&app, "POST", "/queues/missing/archive/replay", None).await?;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    Ok(())
+}
+
+#[tokio::test]
+async fn stats_history_route() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let pool = queue::init_pool(&test_config(&dir)).await?;
+    let _q = queue::create_queue(&pool, "jobs", 5).await?;
+    queue::enqueue_message(&pool, "jobs", &json!({"n":1}), 0).await?;
+    queue::record_stats_history(&pool).await?;
+    let app = app_router(pool);
+
+    let (status, body) =
+        send(&app, "GET", "/queues/jobs/stats/history?window=1h", None).await?;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body.as_array().map(Vec::len), Some(1));
+    assert_eq!(body[0]["ready"], 1);
+    assert_eq!(body[0]["enqueued"], 1);
+    let (status, _) =
+        send(&app, "GET", "/queues/jobs/stats/history?window=1y", None).await?;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let (status, _) =
+        send(&app, "GET", "/queues/missing/stats/history", None).await?;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    Ok(())
+}
+
+#[tokio::test]
+async fn poll_ack_nack_routes_require_lease_token() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let pool = queue::init_pool(&test_config(&dir)).await?;
+    let _q = queue::create_queue(&pool, "jobs", 5).await?;
+    queue::enqueue_message(&pool, "jobs", &json!({"n":1}), 0).await?;
+    queue::enqueue_message(&pool, "jobs", &json!({"n":2}), 0).await?;
+    let app = app_router(pool.clone());
+
+    let (status, body) = send(
+        &app,
+        "POST",
+        "/queues/jobs/messages/poll",
+        Some(json!({"batch": 2, "visibility_ms": 5000})),
+    )
+    .await?;
+    assert_eq!(status, StatusCode::OK);
+    let msgs = body.as_array().cloned().unwrap_or_default();
+    assert_eq!(msgs.len(), 2);
+    let token = msgs[0]["lease_token"].as_str().unwrap().to_string();
+    let (id1, id2) = (msgs[0]["id"].clone(), msgs[1]["id"].clone());
+
+    let bad = json!({"ids": [id1], "lease_token": "nope"});
+    let (status, _) = send(&app, "POST", "/messages/ack", Some(bad)).await?;
+    assert_eq!(status, StatusCode::CONFLICT);
+
+    let ack = json!({"ids": [id1], "lease_token": token});
+    let (status, body) = send(&app, "POST", "/messages/ack", Some(ack)).await?;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["acked"], 1);
+
+    let uri = format!("/messages/{}/extend", id2);
+    let ext = json!({"lease_token": token, "extra_ms": 1000});
+    let (status, body) = send(&app, "POST", &uri, Some(ext)).await?;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["extended"], 1);
+
+    let nack = json!({"ids": [id2], "lease_token": token, "delay_ms": 0});
+    let (status, body) =
+        send(&app, "POST", "/messages/nack", Some(nack)).await?;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["requeued"], 1);
+
+    // Per-message delays: a negative one is rejected, a long one hides the
+    // message from the next poll
+    let poll = json!({"batch": 2, "visibility_ms": 5000});
+    let (_, body) =
+        send(&app, "POST", "/queues/jobs/messages/poll", Some(poll.clone()))
+            .await?;
+    let token = body[0]["lease_token"].as_str().unwrap().to_string();
+    let nack = json!({
+        "lease_token": token,
+        "delays": [{"id": id2, "delay_ms": -1}],
+    });
+    let (status, _) = send(&app, "POST", "/messages/nack", Some(nack)).await?;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let nack = json!({
+        "lease_token": token,
+        "delays": [{"id": id2, "delay_ms": 60_000}],
+    });
+    let (status, body) =
+        send(&app, "POST", "/messages/nack", Some(nack)).await?;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["requeued"], 1);
+    let (_, body) =
+        send(&app, "POST", "/queues/jobs/messages/poll", Some(poll)).await?;
+    assert_eq!(body.as_array().map(Vec::len), Some(0));
+    Ok(())
+}
+
+#[tokio::test]
+async fn ack_nack_routes_report_each_message() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let pool = queue::init_pool(&test_config(&dir)).await?;
+    let _q = queue::create_queue(&pool, "jobs", 5).await?;
+    queue::enqueue_message(&pool, "jobs", &json!({"n":1}), 0).await?;
+    queue::enqueue_message(&pool, "jobs", &json!({"n":2}), 0).await?;
+    let app = app_router(pool.clone());
+    let poll = json!({"batch": 2, "visibility_ms": 5000});
+    let (_, msgs) =
+        send(&app, "POST", "/queues/jobs/messages/poll", Some(poll)).await?;
+    let token = msgs[0]["lease_token"].as_str().unwrap().to_string();
+    let (id1, id2) = (msgs[0]["id"].clone(), msgs[1]["id"].clone());
+
+    let ack = json!({"ids": [id1, 999_999], "lease_token": token});
+    let (status, body) = send(&app, "POST", "/messages/ack", Some(ack)).await?;
+    assert_eq!(status, StatusCode::CONFLICT);
+    assert_eq!(body["acked"], 1);
+    assert_eq!(
+        body["results"],
+        json!([
+            {"id": id1, "status": "acked"},
+            {"id": 999_999, "status": "not_found"},
+        ])
+    );
+    assert!(body["message"].as_str().unwrap().contains("1 of 2"));
+
+    let nack = json!({"ids": [id2], "lease_token": "nope", "delay_ms": 0});
+    let (status, body) =
+        send(&app, "POST", "/messages/nack", Some(nack)).await?;
+    assert_eq!(status, StatusCode::CONFLICT);
+    assert_eq!(body["results"][0]["status"], "lease_mismatch");
+
+    let nack = json!({"ids": [id2], "lease_token": token, "delay_ms": 0});
+    let (status, body) =
+        send(&app, "POST", "/messages/nack", Some(nack)).await?;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["requeued"], 1);
+    assert_eq!(body["results"], json!([{"id": id2, "status": "requeued"}]));
+
+    let ids = vec![id2; queue::MAX_ACK_BATCH + 1];
+    let ack = json!({"ids": ids, "lease_token": token});
+    let (status, _) = send(&app, "POST", "/messages/ack", Some(ack)).await?;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    Ok(())
+}
+
+#[tokio::test]
+async fn long_poll_wakes_on_enqueue() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let pool = queue::init_pool(&test_config(&dir)).await?;
+    let _q = queue::create_queue(&pool, "jobs", 5).await?;
+    let app = app_router(pool.clone());
+
+    // An empty queue with a short wait returns nothing after the wait
+    let started = std::time::Instant::now();
+    let (status, body) = send(
+        &app,
+        "POST",
+        "/queues/jobs/messages/poll",
+        Some(json!({"wait_ms": 100})),
+    )
+    .await?;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body.as_array().map(|a| a.len()), Some(0));
+    assert!(started.elapsed() >= std::time::Duration::from_millis(100));
+
+    // A long wait is released by an enqueue well before it elapses
+    let poller = {
+        let app = app.clone();
+        tokio::spawn(async move {
+            let body = json!({"wait_ms": 10_000});
+            send(&app, "POST", "/queues/jobs/messages/poll", Some(body)).await
+        })
+    };
+    tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    let started = std::time::Instant::now();
+    let enqueue = json!({"payload": {"n": 1}});
+    let (status, _) =
+        send(&app, "POST", "/queues/jobs/messages", Some(enqueue)).await?;
+    assert_eq!(status, StatusCode::CREATED);
+    let (status, body) = poller.await??;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body.as_array().map(|a| a.len()), Some(1));
+    assert!(started.elapsed() < std::time::Duration::from_secs(2));
+    Ok(())
+}
+
+#[tokio::test]
+async fn queue_defaults_fill_omitted_delay_and_visibility() -> anyhow::Result<()>
+{
+    let dir = tempfile::tempdir()?;
+    let pool = queue::init_pool(&test_config(&dir)).await?;
+    let app = app_router(pool.clone());
+    let create = json!({
+        "name": "defaults",
+        "default_visibility_ms": 2000,
+        "default_delay_ms": 60_000,
+    });
+    let (status, body) = send(&app, "POST", "/queues", Some(create)).await?;
+    assert_eq!(status, StatusCode::CREATED);
+    assert_eq!(body["default_visibility_ms"], 2000);
+    assert_eq!(body["default_delay_ms"], 60_000);
+
+    // No delay given: the queue's default delay holds the message back
+    let uri = "/queues/defaults/messages";
+    let (status, _) =
+        send(&app, "POST", uri, Some(json!({"payload": {"n":1}}))).await?;
+    assert_eq!(status, StatusCode::CREATED);
+    let poll = "/queues/defaults/messages/poll";
+    let (_, body) = send(&app, "POST", poll, None).await?;
+    assert_eq!(body.as_array().map(|a| a.len()), Some(0));
+
+    // An explicit delay wins; no visibility given leases for the default
+    let (_, sent) = send(
+        &app,
+        "POST",
+        uri,
+        Some(json!({"payload": {"n":2}, "delay_ms": 0})),
+    )
+    .await?;
+    let (_, body) = send(&app, "POST", poll, None).await?;
+    let leased = &body[0];
+    assert_eq!(leased["id"], sent["id"]);
+    let lease = leased["available_at"].as_i64().unwrap()
+        - sent["created_at"].as_i64().unwrap();
+    assert!((2000..30_000).contains(&lease), "lease of {lease}ms");
+    Ok(())
+}
+
+#[tokio::test]
+async fn patch_queue_updates_settings() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let pool = queue::init_pool(&test_config(&dir)).await?;
+    let _q = queue::create_queue(&pool, "jobs", 5).await?;
+    let app = app_router(pool.clone());
+
+    let patch = json!({"max_attempts": 2, "backoff_base_ms": 500});
+    let (status, body) =
+        send(&app, "PATCH", "/queues/jobs", Some(patch)).await?;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["max_attempts"], 2);
+    assert_eq!(body["backoff_base_ms"], 500);
+
+    // null switches a nullable setting off
+    let patch = json!({"backoff_base_ms": null});
+    let (status, body) =
+        send(&app, "PATCH", "/queues/jobs", Some(patch)).await?;
+    assert_eq!(status, StatusCode::OK);
+    assert!(body.get("backoff_base_ms").is_none());
+    assert_eq!(body["max_attempts"], 2);
+
+    let bad = json!({"backoff_jitter": 2.0});
+    let (status, _) = send(&app, "PATCH", "/queues/jobs", Some(bad)).await?;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let (status, _) =
+        send(&app, "PATCH", "/queues/nope", Some(json!({}))).await?;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    Ok(())
+}
+
+#[tokio::test]
+async fn move_route_shifts_messages_between_queues() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let pool = queue::init_pool(&test_config(&dir)).await?;
+    let _a = queue::create_queue(&pool, "a", 5).await?;
+    let b = queue::create_queue(&pool, "b", 5).await?;
+    let m = queue::enqueue_message(&pool, "a", &json!({"n":1}), 0).await?;
+    let app = app_router(pool.clone());
+
+    let body = json!({"ids": [m.id], "to": "b"});
+    let (status, res) =
+        send(&app, "POST", "/queues/a/messages/move", Some(body)).await?;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(res["moved"], 1);
+    assert_eq!(queue::get_message_by_id(&pool, m.id).await?.queue_id, b.id);
+
+    let body = json!({"ids": [m.id], "to": "nope"});
+    let (status, _) =
+        send(&app, "POST", "/queues/b/messages/move", Some(body)).await?;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    Ok(())
+}
+
+#[tokio::test]
+async fn clone_route_copies_settings_and_messages() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let pool = queue::init_pool(&test_config(&dir)).await?;
+    let _a = queue::create_queue(&pool, "a", 7).await?;
+    queue::enqueue_message(&pool, "a", &json!({"n":1}), 0).await?;
+    let app = app_router(pool.clone());
+
+    let body = json!({"to": "b", "with_messages": true});
+    let (status, res) =
+        send(&app, "POST", "/queues/a/clone", Some(body)).await?;
+    assert_eq!(status, StatusCode::CREATED);
+    assert_eq!(res["queue"]["max_attempts"], 7);
+    assert_eq!(res["copied"], 1);
+
+    let (status, _) =
+        send(&app, "POST", "/queues/a/clone", Some(json!({"to": "b"}))).await?;
+    assert_eq!(status, StatusCode::CONFLICT);
+    let (status, _) =
+        send(&app, "POST", "/queues/nope/clone", Some(json!({"to": "c"})))
+            .await?;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    Ok(())
+}
+
+#[tokio::test]
+async fn export_and_import_routes_copy_a_queue() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let pool = queue::init_pool(&test_config(&dir)).await?;
+    let _a = queue::create_queue(&pool, "a", 5).await?;
+    let _b = queue::create_queue(&pool, "b", 5).await?;
+    for n in 0..2 {
+        queue::enqueue_message(&pool, "a", &json!({ "n": n }), 0).await?;
+    }
+    let app = app_router(pool.clone());
+
+    let req = Request::builder().uri("/queues/a/export").body(Body::empty())?;
+    let resp = app.clone().oneshot(req).await?;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(resp.headers()["content-type"], "application/x-ndjson");
+    let file = to_bytes(resp.into_body(), 1024 * 1024).await?;
+    assert_eq!(
+        file.split(|b| *b == b'\n').filter(|l| !l.is_empty()).count(),
+        2
+    );
+
+    let req = Request::builder()
+        .method("POST")
+        .uri("/queues/b/import")
+        .body(Body::from(file))?;
+    let resp = app.clone().oneshot(req).await?;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let res: Value =
+        serde_json::from_slice(&to_bytes(resp.into_body(), 1024).await?)?;
+    assert_eq!(res, json!({"imported": 2, "skipped": 0}));
+    assert_eq!(queue::peek_queue(&pool, "b", 10).await?.len(), 2);
+
+    let req = Request::builder()
+        .method("POST")
+        .uri("/queues/b/import")
+        .body(Body::from("not json"))?;
+    let resp = app.clone().oneshot(req).await?;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    let (status, _) = send(&app, "GET", "/queues/nope/export", None).await?;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    Ok(())
+}
+
+#[tokio::test]
+async fn peek_route_accepts_paging_and_filters() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let pool = queue::init_pool(&test_config(&dir)).await?;
+    let _q = queue::create_queue(&pool, "jobs", 5).await?;
+    let a = queue::enqueue_message(&pool, "jobs", &json!({"k":"a"}), 0).await?;
+    let b = queue::enqueue_message(&pool, "jobs", &json!({"k":"b"}), 0).await?;
+    let app = app_router(pool.clone());
+
+    let uri = format!("/queues/jobs/messages?limit=10&after_id={}", a.id);
+    let (status, body) = send(&app, "GET", &uri, None).await?;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body[0]["id"], b.id);
+    assert_eq!(body.as_array().map(|v| v.len()), Some(1));
+
+    let uri = "/queues/jobs/messages?limit=10&json_path=$.k%3Da";
+    let (_, body) = send(&app, "GET", uri, None).await?;
+    assert_eq!(body[0]["id"], a.id);
+    assert_eq!(body.as_array().map(|v| v.len()), Some(1));
+
+    let uri = "/queues/jobs/messages?json_path=k%3Da";
+    let (status, _) = send(&app, "GET", uri, None).await?;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    // Leased messages apart from ready ones
+    let leased = queue::poll_messages(&pool, "jobs", 1, 60_000).await?;
+    let uri = "/v1/queues/jobs/messages?limit=10&state=leased";
+    let (_, body) = send(&app, "GET", uri, None).await?;
+    assert_eq!(body.as_array().map(|v| v.len()), Some(1));
+    assert_eq!(body[0]["id"], leased[0].id);
+    let uri = "/v1/queues/jobs/messages?limit=10&state=ready";
+    let (_, body) = send(&app, "GET", uri, None).await?;
+    assert_eq!(body.as_array().map(|v| v.len()), Some(1));
+    assert_ne!(body[0]["id"], leased[0].id);
+    let uri = "/v1/queues/jobs/messages?state=gone";
+    let (status, _) = send(&app, "GET", uri, None).await?;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    Ok(())
+}
+
+#[tokio::test]
+async fn search_route_matches_payload_paths() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let pool = queue::init_pool(&test_config(&dir)).await?;
+    let _q = queue::create_queue(&pool, "jobs", 5).await?;
+    let a = json!({"order": {"id": 123}});
+    let a = queue::enqueue_message(&pool, "jobs", &a, 0).await?;
+    let b = json!({"order": {"id": 456}});
+    let b = queue::enqueue_message(&pool, "jobs", &b, 0).await?;
+    let app = app_router(pool.clone());
+
+    let uri = "/queues/jobs/messages/search?jsonpath=$.order.id&value=123";
+    let (status, body) = send(&app, "GET", uri, None).await?;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body.as_array().map(|v| v.len()), Some(1));
+    assert_eq!(body[0]["id"], a.id);
+
+    let uri = format!(
+        "/queues/jobs/messages/search?jsonpath=$.order&limit=1&after_id={}",
+        a.id
+    );
+    let (_, body) = send(&app, "GET", &uri, None).await?;
+    assert_eq!(body[0]["id"], b.id);
+
+    let uri = "/queues/jobs/messages/search?jsonpath=order.id";
+    let (status, _) = send(&app, "GET", uri, None).await?;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let uri = "/queues/nope/messages/search?jsonpath=$.a";
+    let (status, _) = send(&app, "GET", uri, None).await?;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    Ok(())
+}
+
+#[tokio::test]
+async fn shutdown_answers_long_polls_and_stops() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let pool = queue::init_pool(&test_config(&dir)).await?;
+    let _q = queue::create_queue(&pool, "jobs", 5).await?;
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let client = SqewClient::new(format!("http://{}", listener.local_addr()?));
+    let (stop_tx, stop_rx) = tokio::sync::oneshot::channel::<()>();
+    let server = tokio::spawn(serve_until(
+        listener,
+        None,
+        AppState::new(pool),
+        Duration::from_secs(5),
+        async {
+            let _ = stop_rx.await;
+        },
+    ));
+
+    // A long poll is in flight when shutdown begins
+    let opts = PollRequest { wait_ms: Some(15_000), ..PollRequest::default() };
+    let poll = tokio::spawn(async move { client.poll("jobs", &opts).await });
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    let started = Instant::now();
+    stop_tx.send(()).ok();
+
+    let leased = tokio::time::timeout(Duration::from_secs(3), poll).await???;
+    assert!(leased.is_empty());
+    tokio::time::timeout(Duration::from_secs(3), server).await???;
+    assert!(started.elapsed() < Duration::from_secs(3));
+    Ok(())
+}
+
+#[tokio::test]
+async fn queue_schemas_are_served_by_version() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let pool = queue::init_pool(&test_config(&dir)).await?;
+    let app = app_router(pool.clone());
+    let v1 = json!({"type": "object", "required": ["n"]});
+    let body =
+        json!({"name": "orders", "payload_schema": v1, "schema_mode": "tag"});
+    let (status, created) =
+        send(&app, "POST", "/v1/queues", Some(body)).await?;
+    assert_eq!(status, StatusCode::CREATED);
+    assert_eq!(
+        (&created["schema_mode"], &created["payload_schema_version"]),
+        (&json!("tag"), &json!(1))
+    );
+
+    // A tagging queue accepts payloads that break the schema
+    let uri = "/v1/queues/orders/messages";
+    let (status, m) =
+        send(&app, "POST", uri, Some(json!({"payload": {}}))).await?;
+    assert_eq!(status, StatusCode::CREATED);
+    assert_eq!(m["headers"]["schema-version"], "1");
+
+    let v2 = json!({"type": "object", "required": ["id"]});
+    let update = json!({"payload_schema": v2, "schema_mode": "reject"});
+    let (status, q) =
+        send(&app, "PATCH", "/v1/queues/orders", Some(update)).await?;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(q["payload_schema_version"], 2);
+    let (status, _) =
+        send(&app, "POST", uri, Some(json!({"payload": {"n": 1}}))).await?;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    let (status, current) =
+        send(&app, "GET", "/v1/queues/orders/schema", None).await?;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(
+        (&current["version"], &current["payload_schema"]),
+        (&json!(2), &v2)
+    );
+    let uri = "/v1/queues/orders/schema?version=1";
+    let (status, first) = send(&app, "GET", uri, None).await?;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(first["payload_schema"], v1);
+    let uri = "/v1/queues/orders/schema/versions";
+    let (status, versions) = send(&app, "GET", uri, None).await?;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(versions.as_array().unwrap().len(), 2);
+
+    let uri = "/v1/queues/orders/schema?version=3";
+    assert_eq!(send(&app, "GET", uri, None).await?.0, StatusCode::NOT_FOUND);
+    let _plain = queue::create_queue(&pool, "plain", 5).await?;
+    let uri = "/v1/queues/plain/schema";
+    assert_eq!(send(&app, "GET", uri, None).await?.0, StatusCode::NOT_FOUND);
+    let uri = "/v1/queues/nope/schema/versions";
+    assert_eq!(send(&app, "GET", uri, None).await?.0, StatusCode::NOT_FOUND);
+    Ok(())
+}
+
+#[tokio::test]
+async fn payload_limits_answer_structured_errors() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let pool = queue::init_pool(&test_config(&dir)).await?;
+    let app = app_router(pool.clone());
+    let schema = json!({
+        "type": "object",
+        "properties": { "n": { "type": "integer" } },
+        "required": ["n"]
+    });
+    let body = json!({"name": "typed", "max_payload_bytes": 32, "payload_schema": schema});
+    let (status, created) = send(&app, "POST", "/queues", Some(body)).await?;
+    assert_eq!(status, StatusCode::CREATED);
+    assert_eq!(created["max_payload_bytes"], 32);
+    let bad = json!({"name": "bad", "payload_schema": {"type": 5}});
+    let (status, _) = send(&app, "POST", "/queues", Some(bad)).await?;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    let uri = "/queues/typed/messages";
+    let ok = json!({"payload": {"n": 1}});
+    assert_eq!(send(&app, "POST", uri, Some(ok)).await?.0, StatusCode::CREATED);
+    let wrong = json!({"payload": {"n": "one"}});
+    let (status, err) = send(&app, "POST", uri, Some(wrong)).await?;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(err["error"], "schema_violation");
+    assert!(err["violations"][0].as_str().unwrap().starts_with("/n"));
+    let big = json!({"payload": {"n": 1, "pad": "x".repeat(40)}});
+    let (status, err) = send(&app, "POST", uri, Some(big)).await?;
+    assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+    assert_eq!(
+        (err["error"].as_str(), err["limit"].as_i64()),
+        (Some("payload_too_large"), Some(32))
+    );
+
+    // The server-wide limit applies to every queue
+    let _plain = queue::create_queue(&pool, "plain", 5).await?;
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let base = format!("http://{}", listener.local_addr()?);
+    let state = AppState::new(pool).with_max_payload_bytes(Some(16));
+    let (stop_tx, stop_rx) = tokio::sync::oneshot::channel::<()>();
+    let server = tokio::spawn(serve_until(
+        listener,
+        None,
+        state,
+        Duration::from_secs(5),
+        async {
+            let _ = stop_rx.await;
+        },
+    ));
+    let http = reqwest::Client::new();
+    let resp = http
+        .post(format!("{base}/queues/plain/messages"))
+        .json(&json!({"payload": "x".repeat(20)}))
+        .send()
+        .await?;
+    assert_eq!(resp.status().as_u16(), 413);
+    assert_eq!(resp.json::<Value>().await?["size"], 22);
+    stop_tx.send(()).ok();
+    tokio::time::timeout(Duration::from_secs(3), server).await???;
+    Ok(())
+}
+
+#[tokio::test]
+async fn full_queue_answers_429_with_retry_after() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let pool = queue::init_pool(&test_config(&dir)).await?;
+    let app = app_router(pool.clone());
+    let body = json!({"name": "capped", "max_depth": 1});
+    let (status, created) = send(&app, "POST", "/queues", Some(body)).await?;
+    assert_eq!(status, StatusCode::CREATED);
+    assert_eq!(created["max_depth"], 1);
+
+    let uri = "/queues/capped/messages";
+    let (status, _) =
+        send(&app, "POST", uri, Some(json!({"payload": 1}))).await?;
+    assert_eq!(status, StatusCode::CREATED);
+    let req = Request::builder()
+        .method("POST")
+        .uri(uri)
+        .header("content-type", "application/json")
+        .body(Body::from(json!({"payload": 2, "wait_ms": 50}).to_string()))?;
+    let resp = app.clone().oneshot(req).await?;
+    assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(resp.headers()["retry-after"], "1");
+    let bytes = to_bytes(resp.into_body(), 1024 * 1024).await?;
+    let err: Value = serde_json::from_slice(&bytes)?;
+    assert_eq!(
+        (err["error"].as_str(), err["max_depth"].as_i64()),
+        (Some("queue_full"), Some(1))
+    );
+
+    // Lifting the limit over PATCH lets producers in again
+    let lift = json!({"max_depth": null});
+    let (status, q) = send(&app, "PATCH", "/queues/capped", Some(lift)).await?;
+    assert_eq!(status, StatusCode::OK);
+    assert!(q.get("max_depth").is_none());
+    let (status, _) =
+        send(&app, "POST", uri, Some(json!({"payload": 2}))).await?;
+    assert_eq!(status, StatusCode::CREATED);
+    Ok(())
+}
+
+#[tokio::test]
+async fn attempts_route_lists_deliveries() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let pool = queue::init_pool(&test_config(&dir)).await?;
+    let _q = queue::create_queue(&pool, "jobs", 1).await?;
+    let m = queue::enqueue_message(&pool, "jobs", &json!({}), 0).await?;
+    let app = app_router(pool.clone());
+
+    let poll = json!({"consumer": "worker-7"});
+    let (_, leased) =
+        send(&app, "POST", "/queues/jobs/messages/poll", Some(poll)).await?;
+    let token = leased[0]["lease_token"].clone();
+    let nack = json!({"ids": [m.id], "lease_token": token, "reason": "boom"});
+    let (status, _) = send(&app, "POST", "/messages/nack", Some(nack)).await?;
+    assert_eq!(status, StatusCode::OK);
+
+    let uri = format!("/messages/{}/attempts", m.id);
+    let (status, attempts) = send(&app, "GET", &uri, None).await?;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(attempts[0]["consumer"], "worker-7");
+    assert_eq!(attempts[0]["outcome"], "dead_lettered");
+    assert_eq!(attempts[0]["note"], "boom");
+    // The dead letter carries the reason too
+    let (_, dead) = send(&app, "GET", "/queues/jobs/dlq", None).await?;
+    assert_eq!(dead[0]["last_error"], "boom");
+    let (status, _) = send(&app, "GET", "/messages/999/attempts", None).await?;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    Ok(())
+}
+
+#[tokio::test]
+async fn routes_take_public_ids_wherever_they_take_ids() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let pool = queue::init_pool(&test_config(&dir)).await?;
+    let _q = queue::create_queue(&pool, "jobs", 5).await?;
+    let app = app_router(pool.clone());
+    let enqueue = json!({"payload": {"n": 1}});
+    let (status, m) =
+        send(&app, "POST", "/v1/queues/jobs/messages", Some(enqueue)).await?;
+    assert_eq!(status, StatusCode::CREATED);
+    let public_id = m["public_id"].as_str().unwrap().to_string();
+    queue::enqueue_message(&pool, "jobs", &json!({"n": 2}), 0).await?;
+
+    let poll = json!({"batch": 2, "visibility_ms": 5000});
+    let (_, msgs) =
+        send(&app, "POST", "/v1/queues/jobs/messages/poll", Some(poll)).await?;
+    assert_eq!(msgs[0]["public_id"], public_id.as_str());
+    let token = msgs[0]["lease_token"].clone();
+    let other = msgs[1]["public_id"].clone();
+
+    let uri = format!("/v1/messages/{public_id}/extend");
+    let extend = json!({"lease_token": token, "extra_ms": 1000});
+    let (status, _) = send(&app, "POST", &uri, Some(extend)).await?;
+    assert_eq!(status, StatusCode::OK);
+    let uri = format!("/v1/messages/{public_id}/attempts");
+    let (status, attempts) = send(&app, "GET", &uri, None).await?;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(attempts.as_array().map(Vec::len), Some(1));
+    let uri = format!("/v1/queues/jobs/messages/{public_id}/payload");
+    let (status, payload) = send(&app, "GET", &uri, None).await?;
+    assert_eq!((status, payload), (StatusCode::OK, json!({"n": 1})));
+
+    // Results name messages by their integer id
+    let nack =
+        json!({"delays": [{"id": other, "delay_ms": 0}], "lease_token": token});
+    let (status, body) =
+        send(&app, "POST", "/v1/messages/nack", Some(nack)).await?;
+    assert_eq!((status, body["requeued"].clone()), (StatusCode::OK, json!(1)));
+    let ack = json!({"ids": [public_id], "lease_token": token});
+    let (status, body) =
+        send(&app, "POST", "/v1/messages/ack", Some(ack)).await?;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["results"], json!([{"id": m["id"], "status": "acked"}]));
+
+    // A public id of no message is not found, one that is no UUID invalid
+    let ack = json!({"ids": [public_id], "lease_token": token});
+    let (status, _) = send(&app, "POST", "/v1/messages/ack", Some(ack)).await?;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let (status, _) =
+        send(&app, "GET", "/v1/messages/nonsense/attempts", None).await?;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    Ok(())
+}
+
+#[tokio::test]
+async fn admin_actions_are_recorded_in_the_audit_log() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let pool = queue::init_pool(&test_config(&dir)).await?;
+    let (_, key) =
+        auth::grant_key(&pool, "ops", Role::Admin, None, None).await?;
+    let app = app_router(pool.clone());
+    let send_as = |method: &str, uri: &str, body: Option<Value>| {
+        let mut req = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header("authorization", format!("Bearer {key}"));
+        let body = match body {
+            Some(v) => {
+                req = req.header("content-type", "application/json");
+                Body::from(v.to_string())
+            }
+            None => Body::empty(),
+        };
+        let app = app.clone();
+        async move {
+            let resp = app.oneshot(req.body(body)?).await?;
+            let status = resp.status();
+            let bytes = to_bytes(resp.into_body(), 1024 * 1024).await?;
+            let json = serde_json::from_slice(&bytes).unwrap_or(Value::Null);
+            anyhow::Ok((status, json))
+        }
+    };
+
+    let create = json!({"name": "jobs", "max_attempts": 3});
+    let (status, _) = send_as("POST", "/v1/queues", Some(create)).await?;
+    assert_eq!(status, StatusCode::CREATED);
+    let pause = json!({"paused": true});
+    let (status, _) = send_as("PATCH", "/v1/queues/jobs", Some(pause)).await?;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) =
+        send_as("DELETE", "/v1/queues/jobs/messages", None).await?;
+    assert_eq!(status, StatusCode::OK);
+    // Reads are not recorded
+    let (status, _) = send_as("GET", "/v1/queues/jobs", None).await?;
+    assert_eq!(status, StatusCode::OK);
+
+    let (status, entries) = send_as("GET", "/v1/admin/audit", None).await?;
+    assert_eq!(status, StatusCode::OK);
+    let actions: Vec<_> = entries
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|e| e["action"].clone())
+        .collect();
+    assert_eq!(
+        actions,
+        [json!("queue.purge"), "queue.pause".into(), "queue.create".into()]
+    );
+    for e in entries.as_array().unwrap() {
+        assert_eq!(
+            (&e["actor"], &e["queue"]),
+            (&json!("key:ops"), &json!("jobs"))
+        );
+    }
+    assert_eq!(entries[0]["params"]["purged"], 0);
+    assert_eq!(entries[2]["params"]["max_attempts"], 3);
+
+    let uri = "/v1/admin/audit?action=queue.pause&since=1h";
+    let (_, paused) = send_as("GET", uri, None).await?;
+    assert_eq!(paused.as_array().map(Vec::len), Some(1));
+    assert_eq!(paused[0]["params"]["paused"], true);
+    let uri = "/v1/admin/audit?actor=anonymous";
+    let (_, none) = send_as("GET", uri, None).await?;
+    assert_eq!(none, json!([]));
+    let uri = "/v1/admin/audit?since=yesterday";
+    let (status, _) = send_as("GET", uri, None).await?;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    Ok(())
+}
+
+#[tokio::test]
+async fn clones_and_moves_are_recorded_in_the_audit_log() -> anyhow::Result<()>
+{
+    let dir = tempfile::tempdir()?;
+    let pool = queue::init_pool(&test_config(&dir)).await?;
+    let _q = queue::create_queue(&pool, "jobs", 3).await?;
+    let m = queue::enqueue_message(&pool, "jobs", &json!({"n": 1}), 0).await?;
+    let app = app_router(pool.clone());
+    let post = |uri: &str, body: Value| {
+        let req = Request::builder()
+            .method("POST")
+            .uri(uri)
+            .header("content-type", "application/json")
+            .body(Body::from(body.to_string()));
+        let app = app.clone();
+        async move { anyhow::Ok(app.oneshot(req?).await?.status()) }
+    };
+
+    let clone = json!({"to": "jobs-copy", "with_messages": true});
+    let status = post("/v1/queues/jobs/clone", clone).await?;
+    assert_eq!(status, StatusCode::CREATED);
+    let moved = json!({"ids": [m.id], "to": "jobs-copy"});
+    let status = post("/v1/queues/jobs/messages/move", moved).await?;
+    assert_eq!(status, StatusCode::OK);
+
+    let req = Request::builder().uri("/v1/admin/audit").body(Body::empty())?;
+    let resp = app.clone().oneshot(req).await?;
+    let bytes = to_bytes(resp.into_body(), 1024 * 1024).await?;
+    let entries: Value = serde_json::from_slice(&bytes)?;
+    let recorded: Vec<_> = entries
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|e| (e["action"].clone(), e["queue"].clone()))
+        .collect();
+    assert_eq!(
+        recorded,
+        [
+            (json!("queue.move"), json!("jobs-copy")),
+            (json!("queue.clone"), json!("jobs-copy")),
+        ]
+    );
+    assert_eq!(entries[0]["actor"], "anonymous");
+    assert_eq!(entries[0]["params"]["from"], "jobs");
+    assert_eq!(entries[0]["params"]["moved"], 1);
+    assert_eq!(entries[1]["params"]["from"], "jobs");
+    assert_eq!(entries[1]["params"]["copied"], 1);
+    Ok(())
+}
+
+#[tokio::test]
+async fn raw_payloads_keep_their_content_type() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let pool = queue::init_pool(&test_config(&dir)).await?;
+    let _q = queue::create_queue(&pool, "proto", 1).await?;
+    let _other = queue::create_queue(&pool, "other", 1).await?;
+    let app = app_router(pool.clone());
+
+    // A non-JSON body is the payload; options come from the query
+    let raw = vec![0x0a, 0x03, b'a', b'b', 0xff, 0x00];
+    let req = Request::builder()
+        .method("POST")
+        .uri("/queues/proto/messages?priority=4&trace_id=t-1")
+        .header("content-type", "application/x-protobuf")
+        .body(Body::from(raw.clone()))?;
+    let resp = app.clone().oneshot(req).await?;
+    assert_eq!(resp.status(), StatusCode::CREATED);
+    let bytes = to_bytes(resp.into_body(), 1024 * 1024).await?;
+    let m: Value = serde_json::from_slice(&bytes)?;
+    assert_eq!(m["priority"], 4);
+    assert_eq!(m["trace_id"], "t-1");
+    assert_eq!(m["headers"]["content-type"], "application/x-protobuf");
+    let id = m["id"].as_i64().unwrap();
+
+    let payload = |accept: &'static str, queue: &'static str| {
+        let req = Request::builder()
+            .uri(format!("/queues/{queue}/messages/{id}/payload"))
+            .header("accept", accept)
+            .body(Body::empty());
+        let app = app.clone();
+        async move { anyhow::Ok(app.oneshot(req?).await?) }
+    };
+    let resp = payload("application/*", "proto").await?;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(resp.headers()["content-type"], "application/x-protobuf");
+    assert_eq!(to_bytes(resp.into_body(), 1024).await?.to_vec(), raw);
+    let resp = payload("application/json", "proto").await?;
+    assert_eq!(resp.status(), StatusCode::NOT_ACCEPTABLE);
+    // Only reachable through the message's own queue
+    let resp = payload("*/*", "other").await?;
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+    // JSON payloads are served as their JSON text
+    let body = json!({"payload": {"n": 1}});
+    let (_, m) =
+        send(&app, "POST", "/queues/proto/messages", Some(body)).await?;
+    let id = m["id"].as_i64().unwrap();
+    let uri = format!("/queues/proto/messages/{id}/payload");
+    let resp = app
+        .clone()
+        .oneshot(Request::builder().uri(uri).body(Body::empty())?)
+        .await?;
+    assert_eq!(resp.headers()["content-type"], "application/json");
+    assert_eq!(&to_bytes(resp.into_body(), 1024).await?[..], br#"{"n":1}"#);
+    Ok(())
+}
+
+#[tokio::test]
+async fn in_flight_route_lists_leases_and_their_consumers() -> anyhow::Result<()>
+{
+    let dir = tempfile::tempdir()?;
+    let pool = queue::init_pool(&test_config(&dir)).await?;
+    let _q = queue::create_queue(&pool, "jobs", 3).await?;
+    let m = queue::enqueue_message(&pool, "jobs", &json!({}), 0).await?;
+    let _ready = queue::enqueue_message(&pool, "jobs", &json!({}), 0).await?;
+    let app = app_router(pool.clone());
+
+    let (_, held) = send(&app, "GET", "/queues/jobs/in-flight", None).await?;
+    assert_eq!(held, json!([]));
+    let poll = json!({"consumer_id": "worker-3"});
+    let (_, leased) =
+        send(&app, "POST", "/queues/jobs/messages/poll", Some(poll)).await?;
+    assert_eq!(leased[0]["id"], m.id);
+
+    let (status, held) =
+        send(&app, "GET", "/queues/jobs/in-flight", None).await?;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(held.as_array().map(Vec::len), Some(1));
+    assert_eq!(held[0]["message_id"], m.id);
+    assert_eq!(held[0]["consumer"], "worker-3");
+    assert_eq!(held[0]["lease_expires_at"], leased[0]["available_at"]);
+    let (status, _) = send(&app, "GET", "/queues/nope/in-flight", None).await?;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    Ok(())
+}
+
+#[tokio::test]
+async fn transaction_route_enqueues_all_or_nothing() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let pool = queue::init_pool(&test_config(&dir)).await?;
+    let _a = queue::create_queue(&pool, "orders", 3).await?;
+    let _b = queue::create_queue(&pool, "emails", 3).await?;
+    let app = app_router(pool.clone());
+
+    let body = json!({"messages": [
+        {"queue": "orders", "payload": {"order": 1}},
+        {"queue": "emails", "payload": "receipt", "priority": 5},
+    ]});
+    let (status, created) =
+        send(&app, "POST", "/transactions/enqueue", Some(body)).await?;
+    assert_eq!(status, StatusCode::CREATED);
+    assert_eq!(created.as_array().map(Vec::len), Some(2));
+    assert_eq!(created[1]["priority"], 5);
+
+    // One unknown queue and nothing is enqueued
+    let body = json!({"messages": [
+        {"queue": "orders", "payload": {"order": 2}},
+        {"queue": "missing", "payload": {}},
+    ]});
+    let (status, _) =
+        send(&app, "POST", "/transactions/enqueue", Some(body)).await?;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert_eq!(queue::peek_queue(&pool, "orders", 10).await?.len(), 1);
+
+    let empty = json!({"messages": []});
+    let (status, _) =
+        send(&app, "POST", "/transactions/enqueue", Some(empty)).await?;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    Ok(())
+}
+
+#[tokio::test]
+async fn sample_route_returns_ready_messages() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let pool = queue::init_pool(&test_config(&dir)).await?;
+    let _q = queue::create_queue(&pool, "jobs", 3).await?;
+    for n in 0..12 {
+        queue::enqueue_message(&pool, "jobs", &json!({"n": n}), 0).await?;
+    }
+    let app = app_router(pool.clone());
+
+    let (status, sample) =
+        send(&app, "GET", "/queues/jobs/sample", None).await?;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(sample.as_array().map(Vec::len), Some(10));
+    let (_, sample) =
+        send(&app, "GET", "/queues/jobs/sample?n=50", None).await?;
+    assert_eq!(sample.as_array().map(Vec::len), Some(12));
+    // Sampling leases nothing
+    assert_eq!(queue::poll_messages(&pool, "jobs", 20, 1000).await?.len(), 12);
+    let (status, _) =
+        send(&app, "GET", "/queues/jobs/sample?n=0", None).await?;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let (status, _) = send(&app, "GET", "/queues/nope/sample", None).await?;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    Ok(())
+}
+
+#[tokio::test]
+async fn enqueue_route_schedules_at_an_absolute_time() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let pool = queue::init_pool(&test_config(&dir)).await?;
+    let _q = queue::create_queue(&pool, "later", 3).await?;
+    let app = app_router(pool.clone());
+    let uri = "/queues/later/messages";
+
+    let body = json!({"payload": 1, "deliver_at": "2030-01-01T09:00:00+02:00"});
+    let (status, m) = send(&app, "POST", uri, Some(body)).await?;
+    assert_eq!(status, StatusCode::CREATED);
+    assert_eq!(m["available_at"], 1_893_481_200_000i64);
+    assert!(queue::poll_messages(&pool, "later", 10, 1000).await?.is_empty());
+
+    for body in [
+        json!({"payload": 2, "deliver_at": "2030-01-01 09:00"}),
+        json!({"payload": 2, "deliver_at": "2030-01-01T09:00:00"}),
+        json!({"payload": 2, "deliver_at": "2030-01-01T09:00:00Z", "delay_ms": 5}),
+    ] {
+        let (status, _) = send(&app, "POST", uri, Some(body)).await?;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+    let body = json!({"messages": [
+        {"queue": "later", "payload": 3, "deliver_at": "2030-01-01T07:00:00Z"},
+    ]});
+    let (status, created) =
+        send(&app, "POST", "/transactions/enqueue", Some(body)).await?;
+    assert_eq!(status, StatusCode::CREATED);
+    assert_eq!(created[0]["available_at"], 1_893_481_200_000i64);
+    Ok(())
+}
+
+// Send raw RESP commands and read back exactly `expect_len` bytes of reply
+async fn redis(
+    conn: &mut tokio::net::TcpStream,
+    cmd: &[&str],
+    expect_len: usize,
+) -> anyhow::Result<String> {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    let mut req = format!("*{}\r\n", cmd.len());
+    for arg in cmd {
+        req.push_str(&format!("${}\r\n{}\r\n", arg.len(), arg));
+    }
+    conn.write_all(req.as_bytes()).await?;
+    let mut buf = vec![0; expect_len];
+    conn.read_exact(&mut buf).await?;
+    Ok(String::from_utf8(buf)?)
+}
+
+#[tokio::test]
+async fn redis_protocol_maps_lists_onto_queues() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let pool = queue::init_pool(&test_config(&dir)).await?;
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let redis_listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let client = SqewClient::new(format!("http://{}", listener.local_addr()?));
+    let redis_addr = redis_listener.local_addr()?;
+    let (stop_tx, stop_rx) = tokio::sync::oneshot::channel::<()>();
+    let server = tokio::spawn(serve_until(
+        listener,
+        Some(redis_listener),
+        AppState::new(pool.clone()),
+        Duration::from_secs(5),
+        async {
+            let _ = stop_rx.await;
+        },
+    ));
+    let mut conn = tokio::net::TcpStream::connect(redis_addr).await?;
+
+    assert_eq!(redis(&mut conn, &["PING"], 7).await?, "+PONG\r\n");
+    // LPUSH creates the queue; plain text and JSON values round-trip
+    let pushed = redis(&mut conn, &["LPUSH", "jobs", "hello", "{\"n\":1}"], 4);
+    assert_eq!(pushed.await?, ":2\r\n");
+    assert_eq!(queue::show_queue(&pool, "jobs").await?.name, "jobs");
+    assert_eq!(redis(&mut conn, &["LLEN", "jobs"], 4).await?, ":2\r\n");
+    assert_eq!(
+        redis(&mut conn, &["RPOP", "jobs"], 11).await?,
+        "$5\r\nhello\r\n"
+    );
+    let popped = redis(&mut conn, &["BRPOP", "jobs", "1"], 27).await?;
+    assert_eq!(popped, "*2\r\n$4\r\njobs\r\n$7\r\n{\"n\":1}\r\n");
+    assert_eq!(redis(&mut conn, &["RPOP", "jobs"], 5).await?, "$-1\r\n");
+    assert_eq!(redis(&mut conn, &["LLEN", "nope"], 4).await?, ":0\r\n");
+    let timed_out = redis(&mut conn, &["BRPOP", "nope", "jobs", "0.1"], 5);
+    assert_eq!(timed_out.await?, "*-1\r\n");
+    let unknown = redis(&mut conn, &["FLUSHALL"], 33).await?;
+    assert_eq!(unknown, "-ERR unknown command 'FLUSHALL'\r\n");
+
+    // A blocked BRPOP wakes when a message arrives over HTTP
+    let started = Instant::now();
+    let blocked = tokio::spawn(async move {
+        redis(&mut conn, &["BRPOP", "jobs", "0"], 35).await
+    });
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    client.enqueue("jobs", &json!({"via":"http"})).await?;
+    let popped =
+        tokio::time::timeout(Duration::from_secs(3), blocked).await???;
+    assert_eq!(popped, "*2\r\n$4\r\njobs\r\n$14\r\n{\"via\":\"http\"}\r\n");
+    assert!(started.elapsed() < Duration::from_millis(400));
+
+    stop_tx.send(()).ok();
+    tokio::time::timeout(Duration::from_secs(3), server).await???;
+    Ok(())
+}
+
+#[tokio::test]
+async fn readiness_reports_database_and_pending_migrations()
+-> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let pool = queue::init_pool(&test_config(&dir)).await?;
+    let app = app_router(pool.clone());
+
+    let (status, live) = send(&app, "GET", "/healthz", None).await?;
+    assert_eq!((status, live["status"].as_str()), (StatusCode::OK, Some("ok")));
+    let (status, ready) = send(&app, "GET", "/readyz", None).await?;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(ready["status"], "ready");
+    let components = &ready["components"];
+    assert_eq!(components["database"]["status"], "ok");
+    assert_eq!(components["migrations"]["pending"], 0);
+    assert_eq!(components["server"]["status"], "ok");
+
+    // A schema behind this build is not ready to serve
+    let latest = components["migrations"]["latest"].as_i64().unwrap();
+    let sqlite = pool.as_sqlite().expect("sqlite backend").pool().clone();
+    sqlx::query("DELETE FROM schema_version WHERE version = ?")
+        .bind(latest)
+        .execute(&sqlite)
+        .await?;
+    let (status, ready) = send(&app, "GET", "/readyz", None).await?;
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(ready["status"], "unavailable");
+    assert_eq!(ready["components"]["migrations"]["status"], "pending");
+    assert_eq!(ready["components"]["migrations"]["pending"], 1);
+
+    // Nor is a database that stops answering
+    sqlite.close().await;
+    let (status, ready) = send(&app, "GET", "/readyz", None).await?;
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(ready["components"]["database"]["status"], "down");
+    Ok(())
+}
+
+#[tokio::test]
+async fn api_keys_guard_http_and_redis_but_not_health() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let pool = queue::init_pool(&test_config(&dir)).await?;
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let redis_listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let base = format!("http://{}", listener.local_addr()?);
+    let redis_addr = redis_listener.local_addr()?;
+    let defaults = queue::QueueOptions {
+        max_attempts: 9,
+        ..queue::QueueOptions::default()
+    };
+    let state = AppState::new(pool.clone())
+        .with_api_keys(vec!["k1".into(), "k2".into()])
+        .with_queue_defaults(defaults);
+    let (stop_tx, stop_rx) = tokio::sync::oneshot::channel::<()>();
+    let server = tokio::spawn(serve_until(
+        listener,
+        Some(redis_listener),
+        state,
+        Duration::from_secs(5),
+        async {
+            let _ = stop_rx.await;
+        },
+    ));
+
+    // Probes and the UI's static files stay open
+    let http = reqwest::Client::new();
+    assert_eq!(http.get(format!("{base}/health")).send().await?.status(), 200);
+    assert_eq!(http.get(format!("{base}/readyz")).send().await?.status(), 200);
+    assert_eq!(http.get(format!("{base}/ui/")).send().await?.status(), 200);
+    let anon = SqewClient::new(&base);
+    assert!(matches!(
+        anon.list_queues().await,
+        Err(ClientError::Unauthorized(_))
+    ));
+    let wrong = SqewClient::new(&base).with_api_key("nope");
+    assert!(matches!(
+        wrong.list_queues().await,
+        Err(ClientError::Unauthorized(_))
+    ));
+
+    // Queues created without settings take the configured defaults
+    let created: Value = http
+        .post(format!("{base}/queues"))
+        .bearer_auth("k2")
+        .json(&json!({"name": "d"}))
+        .send()
+        .await?
+        .json()
+        .await?;
+    assert_eq!(created["max_attempts"], 9);
+    let client = SqewClient::new(&base).with_api_key("k1");
+    assert_eq!(client.list_queues().await?.len(), 1);
+
+    let mut conn = tokio::net::TcpStream::connect(redis_addr).await?;
+    let noauth = redis(&mut conn, &["LLEN", "d"], 34).await?;
+    assert_eq!(noauth, "-NOAUTH Authentication required.\r\n");
+    let wrongpass = redis(&mut conn, &["AUTH", "nope"], 28).await?;
+    assert_eq!(wrongpass, "-WRONGPASS invalid API key\r\n");
+    assert_eq!(redis(&mut conn, &["AUTH", "k1"], 5).await?, "+OK\r\n");
+    let pushed = redis(&mut conn, &["LPUSH", "r", "x"], 4).await?;
+    assert_eq!(pushed, ":1\r\n");
+    assert_eq!(queue::show_queue(&pool, "r").await?.max_attempts, 9);
+
+    stop_tx.send(()).ok();
+    tokio::time::timeout(Duration::from_secs(3), server).await???;
+    Ok(())
+}
+
+#[tokio::test]
+async fn stored_api_keys_are_limited_by_role_and_queue_pattern()
+-> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let pool = queue::init_pool(&test_config(&dir)).await?;
+    for name in ["orders-eu", "billing"] {
+        let _q = queue::create_queue(&pool, name, 5).await?;
+    }
+    let other = queue::enqueue_message(&pool, "billing", &json!({}), 0).await?;
+    let (_, admin) =
+        auth::grant_key(&pool, "ops", Role::Admin, None, None).await?;
+    let (_, producer) =
+        auth::grant_key(&pool, "shop", Role::Producer, Some("orders-*"), None)
+            .await?;
+    let (_, consumer) =
+        auth::grant_key(&pool, "worker", Role::Consumer, None, None).await?;
+    let (_, reader) =
+        auth::grant_key(&pool, "dash", Role::ReadOnly, None, None).await?;
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let redis_listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let base = format!("http://{}", listener.local_addr()?);
+    let redis_addr = redis_listener.local_addr()?;
+    let (stop_tx, stop_rx) = tokio::sync::oneshot::channel::<()>();
+    let server = tokio::spawn(serve_until(
+        listener,
+        Some(redis_listener),
+        AppState::new(pool.clone()),
+        Duration::from_secs(5),
+        async {
+            let _ = stop_rx.await;
+        },
+    ));
+    fn forbidden<T>(r: Result<T, ClientError>) -> bool {
+        matches!(r, Err(ClientError::Forbidden(_)))
+    }
+
+    // Stored keys switch authentication on
+    let anon = SqewClient::new(&base);
+    assert!(matches!(
+        anon.list_queues().await,
+        Err(ClientError::Unauthorized(_))
+    ));
+
+    // A producer enqueues to matching queues but cannot purge or delete
+    let shop = SqewClient::new(&base).with_api_key(&producer);
+    let _m = shop.enqueue("orders-eu", &json!({"n": 1})).await?;
+    assert!(forbidden(shop.enqueue("billing", &json!({"n": 2})).await));
+    assert!(forbidden(shop.delete_queue("orders-eu").await));
+    let http = reqwest::Client::new();
+    let purge = http
+        .delete(format!("{base}/queues/orders-eu/messages"))
+        .bearer_auth(&producer)
+        .send()
+        .await?;
+    assert_eq!(purge.status(), 403);
+    let tx = http
+        .post(format!("{base}/transactions/enqueue"))
+        .bearer_auth(&producer)
+        .json(&json!({"messages": [
+            {"queue": "orders-eu", "payload": 1},
+            {"queue": "billing", "payload": 2},
+        ]}))
+        .send()
+        .await?;
+    assert_eq!(tx.status(), 403);
+    let names: Vec<String> =
+        shop.list_queues().await?.into_iter().map(|q| q.name).collect();
+    assert_eq!(names, ["orders-eu"]);
+    assert!(forbidden(shop.poll("orders-eu", &PollRequest::default()).await));
+
+    // A consumer leases and acks but cannot enqueue
+    let worker = SqewClient::new(&base).with_api_key(&consumer);
+    assert!(forbidden(worker.enqueue("orders-eu", &json!({})).await));
+    let leased = worker.poll("orders-eu", &PollRequest::default()).await?;
+    let token = leased[0].lease_token.clone().unwrap_or_default();
+    assert_eq!(worker.ack(&[leased[0].id], &token).await?, 1);
+
+    // Message routes naming no queue still check the message's queue
+    let attempts = |id: i64| {
+        http.get(format!("{base}/messages/{id}/attempts"))
+            .bearer_auth(&producer)
+            .send()
+    };
+    assert_eq!(attempts(leased[0].id).await?.status(), 200);
+    assert_eq!(attempts(other.id).await?.status(), 403);
+
+    // Read-only keys only read; admins do anything
+    let dash = SqewClient::new(&base).with_api_key(&reader);
+    assert_eq!(dash.list_queues().await?.len(), 2);
+    assert!(dash.stats("billing").await.is_ok());
+    assert!(forbidden(dash.create_queue("new", 3).await));
+    let ops = SqewClient::new(&base).with_api_key(&admin);
+    let _q = ops.create_queue("new", 3).await?;
+    ops.delete_queue("new").await?;
+
+    // The Redis listener applies the same grants
+    let mut conn = tokio::net::TcpStream::connect(redis_addr).await?;
+    assert_eq!(redis(&mut conn, &["AUTH", &producer], 5).await?, "+OK\r\n");
+    let pushed = redis(&mut conn, &["LPUSH", "orders-eu", "x"], 4).await?;
+    assert_eq!(pushed, ":1\r\n");
+    let denied = redis(&mut conn, &["LPUSH", "billing", "x"], 55).await?;
+    assert_eq!(
+        denied,
+        "-NOPERM this API key may not run 'lpush' on 'billing'\r\n"
+    );
+    let denied = redis(&mut conn, &["RPOP", "orders-eu"], 56).await?;
+    assert_eq!(
+        denied,
+        "-NOPERM this API key may not run 'rpop' on 'orders-eu'\r\n"
+    );
+
+    stop_tx.send(()).ok();
+    tokio::time::timeout(Duration::from_secs(3), server).await???;
+    Ok(())
+}
+
+#[tokio::test]
+async fn admin_ui_is_served_and_can_pause_queues() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let pool = queue::init_pool(&test_config(&dir)).await?;
+    let _q = queue::create_queue(&pool, "jobs", 5).await?;
+    let _m = queue::enqueue_message(&pool, "jobs", &json!({"k": 1}), 0).await?;
+    let app = app_router(pool.clone());
+
+    // The page and its assets are compiled in
+    let get = |uri: &'static str| {
+        let req = Request::builder().uri(uri).body(Body::empty());
+        let app = app.clone();
+        async move { anyhow::Ok(app.oneshot(req?).await?) }
+    };
+    let resp = get("/ui").await?;
+    assert_eq!(resp.status(), StatusCode::PERMANENT_REDIRECT);
+    assert_eq!(resp.headers()["location"], "/ui/");
+    let resp = get("/ui/").await?;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert!(resp.headers()["content-type"].to_str()?.starts_with("text/html"));
+    let page = to_bytes(resp.into_body(), 1024 * 1024).await?;
+    assert!(String::from_utf8(page.to_vec())?.contains("/ui/app.js"));
+    for asset in ["/ui/app.js", "/ui/app.css"] {
+        assert_eq!(get(asset).await?.status(), StatusCode::OK);
+    }
+
+    // Pausing goes through the queue settings
+    let body = json!({"paused": true});
+    let (status, q) = send(&app, "PATCH", "/queues/jobs", Some(body)).await?;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(q["paused"], true);
+    let poll = "/queues/jobs/messages/poll";
+    let (_, leased) = send(&app, "POST", poll, Some(json!({}))).await?;
+    assert_eq!(leased, json!([]));
+    let body = json!({"paused": false});
+    let (_, q) = send(&app, "PATCH", "/queues/jobs", Some(body)).await?;
+    assert_eq!(q["paused"], false);
+    let (_, leased) = send(&app, "POST", poll, Some(json!({}))).await?;
+    assert_eq!(leased.as_array().map(|v| v.len()), Some(1));
+    Ok(())
+}
+
+#[tokio::test]
+async fn background_tasks_survive_failures_and_report_status()
+-> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let pool = queue::init_pool(&test_config(&dir)).await?;
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let base = format!("http://{}", listener.local_addr()?);
+    let state = AppState::new(pool);
+    let every = Duration::from_millis(20);
+    let registry = state.task_registry.clone();
+    registry.register("steady", every, || async { Ok(()) });
+    registry
+        .register("flaky", every, || async { Err(anyhow::anyhow!("boom")) });
+    registry.register("crashy", every, || async { panic!("kaboom") });
+    let (stop_tx, stop_rx) = tokio::sync::oneshot::channel::<()>();
+    let server = tokio::spawn(serve_until(
+        listener,
+        None,
+        state,
+        Duration::from_secs(5),
+        async {
+            let _ = stop_rx.await;
+        },
+    ));
+    tokio::time::sleep(Duration::from_millis(300)).await;
+
+    let tasks: Value =
+        reqwest::get(format!("{base}/admin/tasks")).await?.json().await?;
+    let tasks = tasks.as_array().cloned().unwrap_or_default();
+    let task = |name: &str| {
+        tasks.iter().find(|t| t["name"] == name).cloned().unwrap_or_default()
+    };
+    for builtin in [
+        "expiry_sweep",
+        "lease_reap",
+        "alarm_eval",
+        "schedule_tick",
+        "stats_snapshot",
+        "push_delivery",
+        "trash_purge",
+        "admin_jobs",
+    ] {
+        assert_eq!(task(builtin)["last_outcome"], "ok", "{builtin}");
+    }
+    assert_eq!(task("lease_reap")["interval_ms"], 1000);
+    let steady = task("steady");
+    assert!(steady["runs"].as_u64().unwrap_or(0) >= 3);
+    assert_eq!(steady["failures"], 0);
+    let flaky = task("flaky");
+    assert_eq!(flaky["last_outcome"], "failed");
+    assert_eq!(flaky["last_error"], "boom");
+    assert_eq!(flaky["failures"], flaky["runs"]);
+    // A panicking job is isolated and keeps being scheduled
+    let crashy = task("crashy");
+    assert_eq!(crashy["last_outcome"], "panicked");
+    assert!(crashy["runs"].as_u64().unwrap_or(0) >= 3);
+
+    let _ = stop_tx.send(());
+    server.await??;
+    Ok(())
+}
+
+#[tokio::test]
+async fn metrics_expose_operation_histograms() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let pool = queue::init_pool(&test_config(&dir)).await?;
+    let _q = queue::create_queue(&pool, "timed", 1).await?;
+    let m = queue::enqueue_message(&pool, "timed", &json!({"n":1}), 0).await?;
+    let leased = queue::poll_messages(&pool, "timed", 5, 1000).await?;
+    let token = leased[0].lease_token.clone().unwrap();
+    queue::ack_messages(&pool, &[m.id], &token).await?;
+    let app = app_router(pool);
+
+    let req = Request::builder().uri("/metrics").body(Body::empty())?;
+    let resp = app.oneshot(req).await?;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let text = String::from_utf8(
+        to_bytes(resp.into_body(), 1024 * 1024).await?.to_vec(),
+    )?;
+    assert!(text.contains("# TYPE sqew_operation_duration_seconds histogram"));
+    // Other tests share the process-wide histograms, so only lower bounds hold
+    let count = |series: &str| -> u64 {
+        text.lines()
+            .find_map(|l| l.strip_prefix(series))
+            .and_then(|v| v.trim().parse().ok())
+            .unwrap_or(0)
+    };
+    for op in ["enqueue", "poll", "ack"] {
+        let series =
+            format!("sqew_operation_duration_seconds_count{{op=\"{op}\"}}");
+        assert!(count(&series) >= 1, "{series}");
+    }
+    // The poll asked for 5 messages and leased 1
+    assert!(
+        count("sqew_operation_batch_size_bucket{op=\"poll\",le=\"5\"}") >= 1
+    );
+    assert!(count("sqew_operation_rows_bucket{op=\"poll\",le=\"1\"}") >= 1);
+    assert!(
+        text.contains("sqew_operation_rows_bucket{op=\"nack\",le=\"+Inf\"}")
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn openapi_document_covers_every_route() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let pool = queue::init_pool(&test_config(&dir)).await?;
+    let app = app_router(pool);
+
+    let (status, doc) = send(&app, "GET", "/openapi.json", None).await?;
+    assert_eq!(status, StatusCode::OK);
+    assert!(doc["openapi"].as_str().unwrap_or_default().starts_with("3."));
+    let paths = doc["paths"].as_object().expect("paths object");
+    for path in [
+        "/health",
+        "/healthz",
+        "/readyz",
+        "/v1/metrics",
+        "/v1/queues",
+        "/v1/queues/{name}",
+        "/v1/queues/{name}/stats",
+        "/v1/queues/{name}/stats/history",
+        "/v1/queues/{name}/clone",
+        "/v1/queues/{name}/restore",
+        "/v1/queues/{name}/purges",
+        "/v1/queues/{name}/purges/{id}",
+        "/v1/queues/{name}/messages",
+        "/v1/queues/{name}/messages/search",
+        "/v1/queues/{name}/messages/{id}/payload",
+        "/v1/queues/{name}/messages/poll",
+        "/v1/queues/{name}/messages/move",
+        "/v1/queues/{name}/export",
+        "/v1/queues/{name}/import",
+        "/v1/messages/ack",
+        "/v1/messages/nack",
+        "/v1/messages/{id}/extend",
+        "/v1/messages/{id}/attempts",
+        "/v1/queues/{name}/in-flight",
+        "/v1/queues/{name}/sample",
+        "/v1/transactions/enqueue",
+        "/v1/queues/{name}/dlq",
+        "/v1/queues/{name}/dlq/redrive",
+        "/v1/queues/{name}/archive/replay",
+        "/v1/queues/{name}/groups",
+        "/v1/queues/{name}/groups/{group}",
+        "/v1/queues/{name}/alarms",
+        "/v1/queues/{name}/alarms/{id}",
+        "/v1/admin/backup",
+        "/v1/admin/db",
+        "/v1/admin/tasks",
+        "/v1/admin/trash",
+        "/v1/admin/jobs",
+        "/v1/admin/jobs/{id}",
+        "/v1/admin/jobs/{id}/cancel",
+    ] {
+        assert!(paths.contains_key(path), "missing {path}");
+    }
+    assert!(paths["/v1/queues/{name}"]["patch"].is_object());
+    let params = paths["/v1/queues/{name}/messages"]["get"]["parameters"]
+        .as_array()
+        .expect("peek parameters");
+    assert!(
+        params.iter().any(|p| p["name"] == "after_id" && p["in"] == "query")
+    );
+    assert!(doc["components"]["schemas"]["Message"].is_object());
+
+    let req = Request::builder().uri("/docs/").body(Body::empty())?;
+    let resp = app.clone().oneshot(req).await?;
+    assert_eq!(resp.status(), StatusCode::OK);
+    Ok(())
+}
+
+#[tokio::test]
+async fn api_is_versioned_with_deprecated_unversioned_aliases()
+-> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let pool = queue::init_pool(&test_config(&dir)).await?;
+    let _q = queue::create_queue(&pool, "jobs", 5).await?;
+    let app = app_router(pool);
+    let get = |uri: &str, version: Option<&str>| {
+        let mut req = Request::builder().uri(uri);
+        if let Some(v) = version {
+            req = req.header("sqew-api-version", v);
+        }
+        let req = req.body(Body::empty()).unwrap();
+        app.clone().oneshot(req)
+    };
+
+    let resp = get("/v1/queues/jobs/stats", Some("1")).await?;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(resp.headers()["sqew-api-version"], "1");
+    assert!(resp.headers().get("deprecation").is_none());
+
+    // The old paths answer the same, marked deprecated
+    let resp = get("/queues/jobs/stats", None).await?;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(resp.headers()["deprecation"], "true");
+    let link = resp.headers()["link"].to_str()?;
+    assert_eq!(link, "</v1/queues/jobs/stats>; rel=\"successor-version\"");
+    assert!(resp.headers()["warning"].to_str()?.starts_with("299 "));
+
+    // Versions this server does not speak are refused
+    let resp = get("/v1/queues", Some("2")).await?;
+    assert_eq!(resp.status(), StatusCode::NOT_ACCEPTABLE);
+    assert_eq!(resp.headers()["sqew-api-version"], "1");
+    let resp = get("/queues", Some("latest")).await?;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(get("/v2/queues", None).await?.status(), StatusCode::NOT_FOUND);
+
+    // Probes stay unversioned
+    let resp = get("/healthz", None).await?;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert!(resp.headers().get("deprecation").is_none());
+    Ok(())
+}
+
+#[tokio::test]
+async fn backup_route_snapshots_the_database() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let pool = queue::init_pool(&test_config(&dir)).await?;
+    let _q = queue::create_queue(&pool, "jobs", 5).await?;
+    let app = app_router(pool);
+    let path = dir.path().join("server.bak");
+    let body = json!({ "path": path });
+
+    let (status, resp) =
+        send(&app, "POST", "/admin/backup", Some(body.clone())).await?;
+    assert_eq!(status, StatusCode::CREATED);
+    assert!(resp["bytes"].as_u64().unwrap_or(0) > 0);
+    assert!(path.exists());
+
+    let (status, _) = send(&app, "POST", "/admin/backup", Some(body)).await?;
+    assert_eq!(status, StatusCode::CONFLICT);
+    Ok(())
+}
+
+#[tokio::test]
+async fn db_route_reports_storage_use() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let pool = queue::init_pool(&test_config(&dir)).await?;
+    let _q = queue::create_queue(&pool, "jobs", 5).await?;
+    queue::enqueue_message(&pool, "jobs", &json!({"n": 1}), 0).await?;
+    let app = app_router(pool);
+
+    let (status, db) = send(&app, "GET", "/admin/db", None).await?;
+    assert_eq!(status, StatusCode::OK);
+    assert!(db["file_bytes"].as_i64().unwrap_or(0) > 0);
+    assert_eq!(db["queues"][0]["name"], "jobs");
+    assert_eq!(db["queues"][0]["messages"], 1);
+    assert!(db["queues"][0]["estimated_bytes"].as_i64().unwrap_or(0) > 0);
+    assert_eq!(db["compact_recommended"], false);
+    Ok(())
+}
+
+#[tokio::test]
+async fn db_route_reports_automatic_compaction() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let cfg = Config { compress_threshold: 0, ..test_config(&dir) };
+    let pool = queue::init_pool(&cfg).await?;
+    let _q = queue::create_queue(&pool, "big", 5).await?;
+    for _ in 0..50 {
+        queue::enqueue_message(&pool, "big", &json!("x".repeat(8000)), 0)
+            .await?;
+    }
+    queue::purge_queue(&pool, "big").await?;
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let base = format!("http://{}", listener.local_addr()?);
+    let state = AppState::new(pool)
+        .with_task_intervals(TaskIntervals {
+            auto_compact: Duration::from_millis(20),
+            ..TaskIntervals::default()
+        })
+        .with_auto_compact(Some(queue::AutoCompactConfig {
+            window: "* * * * *".into(),
+            mode: queue::CompactMode::Incremental,
+            min_free_pages: 1,
+        }));
+    let (stop_tx, stop_rx) = tokio::sync::oneshot::channel::<()>();
+    let server = tokio::spawn(serve_until(
+        listener,
+        None,
+        state,
+        Duration::from_secs(5),
+        async {
+            let _ = stop_rx.await;
+        },
+    ));
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+    let auto = loop {
+        let db: Value =
+            reqwest::get(format!("{base}/admin/db")).await?.json().await?;
+        if !db["auto_compact"]["last_run"].is_null() {
+            break db["auto_compact"].clone();
+        }
+        assert!(Instant::now() < deadline, "never compacted");
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    };
+    assert_eq!(
+        (auto["mode"].as_str(), auto["in_window"].as_bool()),
+        (Some("incremental"), Some(true))
+    );
+    assert!(auto["last_run"]["free_pages_before"].as_i64().unwrap_or(0) > 50);
+    assert_eq!(auto["last_run"]["free_pages_after"], 0);
+    let _ = stop_tx.send(());
+    server.await??;
+    Ok(())
+}
+
+#[tokio::test]
+async fn consumer_group_routes_fan_out_messages() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let pool = queue::init_pool(&test_config(&dir)).await?;
+    let _q = queue::create_queue(&pool, "events", 5).await?;
+    let app = app_router(pool);
+
+    for group in ["audit", "mail"] {
+        let body = json!({ "name": group });
+        let (status, g) =
+            send(&app, "POST", "/queues/events/groups", Some(body)).await?;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(g["name"], group);
+    }
+    let body = json!({ "name": "mail" });
+    let (status, _) =
+        send(&app, "POST", "/queues/events/groups", Some(body)).await?;
+    assert_eq!(status, StatusCode::CONFLICT);
+    let (_, groups) = send(&app, "GET", "/queues/events/groups", None).await?;
+    assert_eq!(groups.as_array().map(Vec::len), Some(2));
+
+    let body = json!({ "payload": {"n": 1} });
+    let (status, _) =
+        send(&app, "POST", "/queues/events/messages", Some(body)).await?;
+    assert_eq!(status, StatusCode::CREATED);
+    let poll = "/queues/events/messages/poll";
+    let (status, _) = send(&app, "POST", poll, Some(json!({}))).await?;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    for group in ["audit", "mail"] {
+        let body = json!({ "group": group });
+        let (status, msgs) = send(&app, "POST", poll, Some(body)).await?;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(msgs.as_array().map(Vec::len), Some(1));
+    }
+
+    let uri = "/queues/events/groups/mail";
+    let (status, _) = send(&app, "DELETE", uri, None).await?;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    let (status, _) = send(&app, "DELETE", uri, None).await?;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    Ok(())
+}
+
+#[tokio::test]
+async fn alarm_routes_and_webhook_delivery() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let pool = queue::init_pool(&test_config(&dir)).await?;
+    let _q = queue::create_queue(&pool, "jobs", 5).await?;
+    let app = app_router(pool.clone());
+
+    // A webhook receiver recording what it is sent
+    let received = Arc::new(Mutex::new(Vec::<Value>::new()));
+    let sink = received.clone();
+    let hook = Router::new().route(
+        "/hook",
+        post(move |Json(body): Json<Value>| async move {
+            sink.lock().unwrap().push(body);
+        }),
+    );
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let url = format!("http://{}/hook", listener.local_addr()?);
+    tokio::spawn(async move { axum::serve(listener, hook).await });
+
+    let body = json!({"metric": "ready", "threshold": 0, "webhook_url": url});
+    let (status, alarm) =
+        send(&app, "POST", "/queues/jobs/alarms", Some(body)).await?;
+    assert_eq!(status, StatusCode::CREATED);
+    assert_eq!(alarm["for_ms"], 0);
+    let body = json!({"metric": "size", "threshold": 0, "webhook_url": url});
+    let (status, _) =
+        send(&app, "POST", "/queues/jobs/alarms", Some(body)).await?;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let (status, list) = send(&app, "GET", "/queues/jobs/alarms", None).await?;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(list.as_array().map(|a| a.len()), Some(1));
+
+    queue::enqueue_message(&pool, "jobs", &json!({}), 0).await?;
+    let client = reqwest::Client::new();
+    assert_eq!(queue::run_alarms(&pool, &client).await?, 1);
+    let sent = received.lock().unwrap().clone();
+    assert_eq!(sent.len(), 1);
+    assert_eq!(sent[0]["alarm_id"], alarm["id"]);
+    assert_eq!(sent[0]["state"], "firing");
+    assert_eq!(sent[0]["value"], 1);
+
+    let uri = format!("/queues/jobs/alarms/{}", alarm["id"]);
+    let (status, _) = send(&app, "DELETE", &uri, None).await?;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    let (status, _) = send(&app, "DELETE", &uri, None).await?;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    Ok(())
+}
+
+#[tokio::test]
+async fn push_delivery_acks_and_retries() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let pool = queue::init_pool(&test_config(&dir)).await?;
+    let _q = queue::create_queue(&pool, "pushed", 5).await?;
+    let _q = queue::create_queue(&pool, "failing", 2).await?;
+
+    // An endpoint accepting every push, and one failing every push
+    let received = Arc::new(Mutex::new(Vec::<Value>::new()));
+    let sink = received.clone();
+    let hook = Router::new()
+        .route(
+            "/ok",
+            post(move |Json(body): Json<Value>| async move {
+                sink.lock().unwrap().push(body);
+            }),
+        )
+        .route("/fail", post(|| async { StatusCode::INTERNAL_SERVER_ERROR }));
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let base = format!("http://{}", listener.local_addr()?);
+    tokio::spawn(async move { axum::serve(listener, hook).await });
+
+    let url = format!("{base}/ok");
+    for (concurrency, timeout_ms, url) in
+        [(0, 1000, url.as_str()), (2, 0, url.as_str()), (2, 1000, "ftp://x")]
+    {
+        let err = queue::set_push_config(
+            &pool,
+            "pushed",
+            url,
+            concurrency,
+            timeout_ms,
+            0,
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(err, SqewError::Invalid(_)), "{err}");
+    }
+    let cfg = queue::set_push_config(&pool, "pushed", &url, 2, 1000, 0).await?;
+    assert_eq!(cfg.concurrency, 2);
+    for n in 0..3 {
+        queue::enqueue_message(&pool, "pushed", &json!({ "n": n }), 0).await?;
+    }
+    let client = reqwest::Client::new();
+    assert_eq!(queue::run_push(&pool, &client).await?, 3);
+    let sent = received.lock().unwrap().clone();
+    assert_eq!(sent.len(), 3);
+    assert!(sent.iter().all(|m| m["queue"] == "pushed" && m["attempts"] == 0));
+    let mut ns: Vec<i64> =
+        sent.iter().filter_map(|m| m["payload"]["n"].as_i64()).collect();
+    ns.sort();
+    assert_eq!(ns, [0, 1, 2]);
+    assert_eq!(queue::stats(&pool, "pushed").await?["ready"], 0);
+    let log = queue::push_deliveries(&pool, "pushed", 10).await?;
+    assert_eq!(log.len(), 3);
+    assert!(log.iter().all(|d| d.outcome == "acked"
+        && d.status_code == Some(200)
+        && d.attempt == 1));
+
+    // A failing endpoint nacks until the message is dead-lettered
+    queue::set_push_config(
+        &pool,
+        "failing",
+        &format!("{base}/fail"),
+        1,
+        1000,
+        0,
+    )
+    .await?;
+    let m = queue::enqueue_message(&pool, "failing", &json!({}), 0).await?;
+    assert_eq!(queue::run_push(&pool, &client).await?, 0);
+    assert_eq!(queue::run_push(&pool, &client).await?, 0);
+    let log = queue::push_deliveries(&pool, "failing", 10).await?;
+    let outcomes: Vec<_> = log.iter().map(|d| d.outcome.as_str()).collect();
+    assert_eq!(outcomes, ["dead_lettered", "requeued"]);
+    assert!(log.iter().all(|d| d.message_id == m.id
+        && d.status_code == Some(500)
+        && d.error.is_some()));
+    assert_eq!(queue::stats(&pool, "failing").await?["dlq"], 1);
+
+    assert!(queue::remove_push_config(&pool, "failing").await?);
+    assert!(!queue::remove_push_config(&pool, "failing").await?);
+    assert!(queue::push_config(&pool, "failing").await?.is_none());
+    Ok(())
+}
+
+#[tokio::test]
+async fn cors_body_limit_and_timeout_layers() -> anyhow::Result<()> {
+    for bad in ["example.com", "https://example.com/", "ftp://x", ""] {
+        assert!(parse_cors_origin(bad).is_err(), "{bad}");
+    }
+    let dir = tempfile::tempdir()?;
+    let pool = queue::init_pool(&test_config(&dir)).await?;
+    let _q = queue::create_queue(&pool, "jobs", 5).await?;
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let base = format!("http://{}", listener.local_addr()?);
+    let dash = "https://dash.example.com";
+    let state = AppState::new(pool)
+        .with_api_keys(vec!["s3cret".into()])
+        .with_cors_origins(vec![parse_cors_origin(dash).unwrap()])
+        .with_max_body_bytes(Some(256))
+        .with_request_timeout(Some(Duration::from_millis(200)));
+    let (stop_tx, stop_rx) = tokio::sync::oneshot::channel::<()>();
+    let server = tokio::spawn(serve_until(
+        listener,
+        None,
+        state,
+        Duration::from_secs(5),
+        async {
+            let _ = stop_rx.await;
+        },
+    ));
+    let http = reqwest::Client::new();
+
+    // Preflights are answered without a key, for allowed origins only
+    let preflight = |origin: &'static str| {
+        http.request(reqwest::Method::OPTIONS, format!("{base}/queues"))
+            .header("origin", origin)
+            .header("access-control-request-method", "POST")
+            .header("access-control-request-headers", "authorization")
+            .send()
+    };
+    let resp = preflight(dash).await?;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(resp.headers()["access-control-allow-origin"], dash);
+    let resp = preflight("https://evil.example.com").await?;
+    assert!(resp.headers().get("access-control-allow-origin").is_none());
+    // Errors carry CORS headers too, so the browser can read them
+    let resp = http
+        .get(format!("{base}/queues"))
+        .header("origin", dash)
+        .send()
+        .await?;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(resp.headers()["access-control-allow-origin"], dash);
+
+    let resp = http
+        .post(format!("{base}/queues/jobs/messages"))
+        .bearer_auth("s3cret")
+        .json(&json!({"payload": "x".repeat(300)}))
+        .send()
+        .await?;
+    assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+    let resp = http
+        .post(format!("{base}/queues/jobs/messages/poll"))
+        .bearer_auth("s3cret")
+        .json(&json!({"wait_ms": 2000}))
+        .send()
+        .await?;
+    assert_eq!(resp.status(), StatusCode::REQUEST_TIMEOUT);
+    stop_tx.send(()).ok();
+    tokio::time::timeout(Duration::from_secs(3), server).await???;
+    Ok(())
+}
+
+#[tokio::test]
+async fn responses_and_request_bodies_may_be_compressed() -> anyhow::Result<()>
+{
+    use flate2::{Compression, read::GzDecoder, write::GzEncoder};
+    use std::io::{Read, Write};
+    let dir = tempfile::tempdir()?;
+    let pool = queue::init_pool(&test_config(&dir)).await?;
+    let _q = queue::create_queue(&pool, "jobs", 5).await?;
+    let app = app_router(pool);
+
+    // A gzipped enqueue body
+    let messages: Vec<Value> = (0..50)
+        .map(|n| json!({"queue": "jobs", "payload": {"n": n}}))
+        .collect();
+    let mut gz = GzEncoder::new(Vec::new(), Compression::default());
+    gz.write_all(json!({"messages": messages}).to_string().as_bytes())?;
+    let req = Request::builder()
+        .method("POST")
+        .uri("/transactions/enqueue")
+        .header("content-type", "application/json")
+        .header("content-encoding", "gzip")
+        .body(Body::from(gz.finish()?))?;
+    let resp = app.clone().oneshot(req).await?;
+    assert_eq!(resp.status(), StatusCode::CREATED);
+
+    // Peeks come back compressed as the client accepts
+    let peek = |encoding: &str| {
+        let req = Request::builder()
+            .uri("/queues/jobs/messages?limit=50")
+            .header("accept-encoding", encoding)
+            .body(Body::empty());
+        let app = app.clone();
+        async move { anyhow::Ok(app.oneshot(req?).await?) }
+    };
+    let resp = peek("gzip").await?;
+    assert_eq!(resp.headers()["content-encoding"], "gzip");
+    let bytes = to_bytes(resp.into_body(), 1024 * 1024).await?;
+    let mut json = String::new();
+    GzDecoder::new(&bytes[..]).read_to_string(&mut json)?;
+    let peeked: Value = serde_json::from_str(&json)?;
+    assert_eq!(peeked.as_array().map(Vec::len), Some(50));
+    let resp = peek("zstd").await?;
+    assert_eq!(resp.headers()["content-encoding"], "zstd");
+    let bytes = to_bytes(resp.into_body(), 1024 * 1024).await?;
+    let peeked: Value = serde_json::from_slice(&zstd::decode_all(&bytes[..])?)?;
+    assert_eq!(peeked.as_array().map(Vec::len), Some(50));
+
+    // Unknown request encodings are refused
+    let req = Request::builder()
+        .method("POST")
+        .uri("/transactions/enqueue")
+        .header("content-type", "application/json")
+        .header("content-encoding", "br")
+        .body(Body::from("..."))?;
+    let resp = app.clone().oneshot(req).await?;
+    assert_eq!(resp.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    Ok(())
+}
+
+#[tokio::test]
+async fn chaos_mode_injects_faults() -> anyhow::Result<()> {
+    for bad in ["", "p=2", "p=0.1,x=1", "p=0.1,faults=boom", "delay_ms=5"] {
+        assert!(bad.parse::<ChaosConfig>().is_err(), "{bad}");
+    }
+    let spec: ChaosConfig =
+        "p=0.25, delay_ms=10".parse().map_err(anyhow::Error::msg)?;
+    assert_eq!(spec.max_delay, Duration::from_millis(10));
+    assert_eq!(spec.faults.len(), 3);
+
+    let dir = tempfile::tempdir()?;
+    let pool = queue::init_pool(&test_config(&dir)).await?;
+    let _q = queue::create_queue(&pool, "jobs", 5).await?;
+    let m = queue::enqueue_message(&pool, "jobs", &json!({"n": 1}), 0).await?;
+    let http = reqwest::Client::new();
+    for (faults, fault) in [
+        ("unavailable", "unavailable"),
+        ("delay", "delay"),
+        ("redeliver", "redeliver"),
+    ] {
+        let chaos = format!("p=1,delay_ms=0,faults={faults}")
+            .parse()
+            .map_err(anyhow::Error::msg)?;
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let base = format!("http://{}", listener.local_addr()?);
+        let state = AppState::new(pool.clone()).with_chaos(Some(chaos));
+        let (stop_tx, stop_rx) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(serve_until(
+            listener,
+            None,
+            state,
+            Duration::from_secs(5),
+            async {
+                let _ = stop_rx.await;
+            },
+        ));
+
+        // Probes are never faulted
+        let resp = http.get(format!("{base}/health")).send().await?;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(resp.headers().get("x-sqew-chaos").is_none());
+
+        let resp = http
+            .post(format!("{base}/queues/jobs/messages/poll"))
+            .json(&json!({"visibility_ms": 60_000}))
+            .send()
+            .await?;
+        assert_eq!(resp.headers()["x-sqew-chaos"], fault);
+        if fault == "unavailable" {
+            assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+            assert_eq!(resp.headers()["retry-after"], "1");
+            // The client surfaces the hint as a typed, retryable error
+            let err = SqewClient::new(&base)
+                .poll("jobs", &PollRequest::default())
+                .await
+                .unwrap_err();
+            assert!(matches!(
+                err,
+                ClientError::Unavailable {
+                    retry_after: Some(d),
+                    ..
+                } if d == Duration::from_secs(1)
+            ));
+        } else {
+            assert_eq!(resp.status(), StatusCode::OK);
+            let leased: Value = resp.json().await?;
+            assert_eq!(leased[0]["id"], m.id);
+            let token = leased[0]["lease_token"].as_str().unwrap().to_string();
+            // A redelivered message can be polled again at once, and the
+            // first lease no longer acks it
+            let again = queue::poll_messages(&pool, "jobs", 1, 60_000).await?;
+            if fault == "redeliver" {
+                assert_eq!(again[0].id, m.id);
+                assert_eq!(
+                    queue::ack_messages(&pool, &[m.id], &token).await?,
+                    0
+                );
+            } else {
+                assert!(again.is_empty());
+                assert_eq!(
+                    queue::expire_leases(&pool, &[m.id], &token).await?,
+                    1
+                );
+            }
+        }
+        let _ = stop_tx.send(());
+        server.await??;
+    }
+    Ok(())
+}
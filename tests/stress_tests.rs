@@ -11,84 +11,34 @@ use std::collections::HashSet;
 
 // Helper to build a test Config pointing to a temp DB
 fn test_config(tmp: &tempfile::TempDir) -> Config {
-    let mut cfg = Config::default();
-    cfg.db_path = tmp.path().join("stress.db");
-    cfg.force_recreate = true;
-    cfg
+    Config {
+        db_path: tmp.path().join("stress.db"),
+        force_recreate: true,
+        ..Config::default()
+    }
 }
 
-async fn enqueue_http_with_retry(
+// Enqueue over HTTP; the pool's busy_timeout absorbs lock contention, so no
+// client-side retries are needed
+async fn enqueue_http(
     app: axum::Router,
     qname: &str,
     body: serde_json::Value,
-    max_retries: usize,
 ) -> anyhow::Result<()> {
-    for attempt in 0..=max_retries {
-        let req = Request::builder()
-            .method("POST")
-            .uri(format!("/queues/{}/messages", qname))
-            .header("content-type", "application/json")
-            .body(Body::from(serde_json::to_vec(&body)?))?;
-        let resp = app.clone().oneshot(req).await?;
-        let status = resp.status();
-        if status == StatusCode::CREATED {
-            return Ok(());
-        }
-        // Extract body for diagnostics and retry on transient DB lock
+    let req = Request::builder()
+        .method("POST")
+        .uri(format!("/queues/{}/messages", qname))
+        .header("content-type", "application/json")
+        .body(Body::from(serde_json::to_vec(&body)?))?;
+    let resp = app.oneshot(req).await?;
+    let status = resp.status();
+    if status != StatusCode::CREATED {
         let bytes = to_bytes(resp.into_body(), 64 * 1024).await.unwrap_or_default();
-        let text = String::from_utf8_lossy(&bytes);
-        let is_transient = status.is_server_error() && text.contains("locked");
-        if attempt < max_retries && is_transient {
-            tokio::time::sleep(Duration::from_millis(5)).await;
-            continue;
-        }
-        anyhow::bail!("enqueue failed: {} {}", status, text);
+        anyhow::bail!("enqueue failed: {} {}", status, String::from_utf8_lossy(&bytes));
     }
     Ok(())
 }
 
-async fn poll_with_retry(
-    pool: &sqlx::SqlitePool,
-    qname: &str,
-    batch: i64,
-    vis_ms: i64,
-    max_retries: usize,
-) -> anyhow::Result<Vec<sqew::models::Message>> {
-    for attempt in 0..=max_retries {
-        match queue::poll_messages(pool, qname, batch, vis_ms).await {
-            Ok(v) => return Ok(v),
-            Err(e) => {
-                let s = format!("{e:#}");
-                if attempt < max_retries && s.contains("database is locked") {
-                    let backoff = 5 * (attempt as u64 + 1);
-                    tokio::time::sleep(Duration::from_millis(backoff.min(50))).await;
-                    continue;
-                }
-                return Err(e);
-            }
-        }
-    }
-    Ok(Vec::new())
-}
-
-async fn ack_with_retry(pool: &sqlx::SqlitePool, ids: &[i64], max_retries: usize) -> anyhow::Result<u64> {
-    for attempt in 0..=max_retries {
-        match queue::ack_messages(pool, ids).await {
-            Ok(n) => return Ok(n),
-            Err(e) => {
-                let s = format!("{e:#}");
-                if attempt < max_retries && s.contains("database is locked") {
-                    let backoff = 5 * (attempt as u64 + 1);
-                    tokio::time::sleep(Duration::from_millis(backoff.min(50))).await;
-                    continue;
-                }
-                return Err(e);
-            }
-        }
-    }
-    Ok(0)
-}
-
 #[tokio::test]
 //#[ignore]
 async fn concurrent_enqueue_no_loss() -> anyhow::Result<()> {
@@ -127,7 +77,7 @@ async fn concurrent_enqueue_no_loss() -> anyhow::Result<()> {
                     "payload": {"worker": w, "seq": i},
                     "delay_ms": 0
                 });
-                enqueue_http_with_retry(app.clone(), qname, body, 50).await?;
+                enqueue_http(app.clone(), qname, body).await?;
             }
             anyhow::Ok(())
         }));
@@ -145,7 +95,7 @@ async fn concurrent_enqueue_no_loss() -> anyhow::Result<()> {
             .duration_since(std::time::UNIX_EPOCH)?
             .as_millis() as i64;
         let q = queue::show_queue(&pool, qname).await?;
-        let ready = sqew::db::count_ready_messages(&pool, q.id, now).await?;
+        let ready = pool.count_ready_messages(q.id, now).await?;
         if ready as usize == total { break; }
         if std::time::Instant::now() > deadline {
             anyhow::bail!("Timeout waiting for ready messages: got {} expected {}", ready, total);
@@ -155,7 +105,7 @@ async fn concurrent_enqueue_no_loss() -> anyhow::Result<()> {
 
     // Additionally, verify raw total via DB to be safe
     let q = queue::show_queue(&pool, qname).await?;
-    let queued = sqew::db::count_queued_messages_by_queue(&pool, q.id).await?;
+    let queued = pool.count_queued_messages_by_queue(q.id).await?;
     assert_eq!(queued as usize, total);
 
     Ok(())
@@ -232,12 +182,12 @@ async fn concurrent_enqueue_and_drain_no_loss() -> anyhow::Result<()> {
         let qname = qname.to_string();
         consumers.push(tokio::spawn(async move {
             loop {
-                let msgs = poll_with_retry(&pool, &qname, consumer_batch, visibility_ms, 500).await?;
+                let msgs = queue::poll_messages(&pool, &qname, consumer_batch, visibility_ms).await?;
                 if msgs.is_empty() {
                     // Check if all work is done by looking at consumed counter and ready count
                     let q = queue::show_queue(&pool, &qname).await?;
                     let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)?.as_millis() as i64;
-                    let ready = sqew::db::count_ready_messages(&pool, q.id, now).await?;
+                    let ready = pool.count_ready_messages(q.id, now).await?;
                     if consumed.load(Ordering::Relaxed) >= total && ready == 0 { break; }
                     tokio::time::sleep(Duration::from_millis(5)).await;
                     continue;
@@ -251,7 +201,8 @@ async fn concurrent_enqueue_and_drain_no_loss() -> anyhow::Result<()> {
                     }
                 }
                 // Ack the polled messages
-                let n = ack_with_retry(&pool, &ids, 200).await? as usize;
+                let token = msgs[0].lease_token.clone().unwrap_or_default();
+                let n = queue::ack_messages(&pool, &ids, &token).await? as usize;
                 consumed.fetch_add(n, Ordering::Relaxed);
             }
             anyhow::Ok(())
@@ -264,7 +215,7 @@ async fn concurrent_enqueue_and_drain_no_loss() -> anyhow::Result<()> {
     let q = queue::show_queue(&pool, qname).await?;
     let total_consumed = consumed.load(Ordering::Relaxed);
     assert_eq!(total_consumed, total, "consumed != total");
-    let remaining = sqew::db::count_queued_messages_by_queue(&pool, q.id).await?;
+    let remaining = pool.count_queued_messages_by_queue(q.id).await?;
     assert_eq!(remaining, 0, "remaining queued messages should be 0");
     Ok(())
 }
@@ -323,7 +274,7 @@ async fn concurrent_mixed_produce_consume_counts_ok() -> anyhow::Result<()> {
                     if producers_done.load(Ordering::Relaxed) {
                         let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)?.as_millis() as i64;
                         let q = queue::show_queue(&pool, &qname).await?;
-                        let ready = sqew::db::count_ready_messages(&pool, q.id, now).await?;
+                        let ready = pool.count_ready_messages(q.id, now).await?;
                         if consumed.load(Ordering::Relaxed) >= produced.load(Ordering::Relaxed) && ready == 0 {
                             break;
                         }
@@ -334,7 +285,8 @@ async fn concurrent_mixed_produce_consume_counts_ok() -> anyhow::Result<()> {
                 // Track seen IDs (duplicates are acceptable under at-least-once semantics)
                 let ids: Vec<i64> = msgs.iter().map(|m| m.id).collect();
                 { let mut set = seen.lock().await; for id in &ids { set.insert(*id); } }
-                let acked = queue::ack_messages(&pool, &ids).await? as usize;
+                let token = msgs[0].lease_token.clone().unwrap_or_default();
+                let acked = queue::ack_messages(&pool, &ids, &token).await? as usize;
                 let new_total = consumed.fetch_add(acked, Ordering::Relaxed) + acked;
                 // Safety check: never consume more than produced so far
                 let p = produced.load(Ordering::Relaxed);
@@ -360,8 +312,10 @@ async fn concurrent_mixed_produce_consume_counts_ok() -> anyhow::Result<()> {
                     "payload": {"worker": w, "seq": i},
                     "delay_ms": 0
                 });
-                enqueue_http_with_retry(app.clone(), qname, body, 50).await?;
+                // Count before sending: a consumer may ack the message
+                // before the enqueue response reaches us
                 produced.fetch_add(1, Ordering::Relaxed);
+                enqueue_http(app.clone(), qname, body).await?;
             }
             anyhow::Ok(())
         }));
@@ -380,7 +334,7 @@ async fn concurrent_mixed_produce_consume_counts_ok() -> anyhow::Result<()> {
     assert_eq!(p, total, "produced != total");
     assert_eq!(c, p, "consumed != produced");
     let q = queue::show_queue(&pool, qname).await?;
-    let remaining = sqew::db::count_queued_messages_by_queue(&pool, q.id).await?;
+    let remaining = pool.count_queued_messages_by_queue(q.id).await?;
     assert_eq!(remaining, 0, "remaining queued messages should be 0");
     // Sanity: no timeouts
     assert!(std::time::Instant::now() <= deadline, "mixed test exceeded deadline");
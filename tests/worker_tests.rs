@@ -0,0 +1,58 @@
+use serde_json::json;
+use sqew::queue::{self, Config};
+use sqew::worker::{WorkerOptions, run_worker};
+use std::time::Duration;
+
+fn test_config(tmp: &tempfile::TempDir) -> Config {
+    Config {
+        db_path: tmp.path().join("worker.db"),
+        force_recreate: true,
+        ..Config::default()
+    }
+}
+
+#[tokio::test]
+async fn worker_acks_on_success_and_nacks_on_failure() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let pool = queue::init_pool(&test_config(&dir)).await?;
+    let _q = queue::create_queue(&pool, "jobs", 1).await?;
+    let ok =
+        queue::enqueue_message(&pool, "jobs", &json!({"ok":true}), 0).await?;
+    let bad =
+        queue::enqueue_message(&pool, "jobs", &json!({"ok":false}), 0).await?;
+    let slow =
+        queue::enqueue_message(&pool, "jobs", &json!({"slow":1}), 0).await?;
+
+    // Record each payload; fail on "false", hang on "slow"
+    let out = dir.path().join("out.txt");
+    let command = format!(
+        "p=$(cat); echo \"$SQEW_MESSAGE_ID $p\" >> {}; \
+         case \"$p\" in *false*) exit 3;; *slow*) exec sleep 10;; esac",
+        out.display()
+    );
+    let opts = WorkerOptions {
+        queue: "jobs".into(),
+        command,
+        concurrency: 2,
+        visibility_ms: Some(5000),
+        max_runtime: Some(300),
+        retry_delay_ms: 0,
+    };
+    run_worker(&pool, &opts, tokio::time::sleep(Duration::from_millis(1000)))
+        .await?;
+
+    let log = std::fs::read_to_string(&out)?;
+    assert_eq!(log.lines().count(), 3);
+    assert!(log.contains(&format!("{} {}", ok.id, json!({"ok":true}))));
+    // Success is acked; failure and timeout are nacked into the DLQ
+    assert!(queue::get_message_by_id(&pool, ok.id).await.is_err());
+    let dead = queue::list_dead_letters(&pool, "jobs", 10).await?;
+    let dead_ids: Vec<i64> = dead.iter().map(|m| m.id).collect();
+    assert_eq!(dead_ids.len(), 2);
+    assert!(dead_ids.contains(&bad.id) && dead_ids.contains(&slow.id));
+
+    // Unknown queues fail fast
+    let missing = WorkerOptions { queue: "nope".into(), ..opts };
+    assert!(run_worker(&pool, &missing, async {}).await.is_err());
+    Ok(())
+}